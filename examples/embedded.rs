@@ -0,0 +1,40 @@
+//! Run a collector and a sensor in one process: the sensor replays a capture file to the
+//! collector, and once it's done, both are shut down and the state rows stored are counted.
+//!
+//!     cargo run --example embedded -- capture.pcap
+
+use std::{env, net::SocketAddr, path::PathBuf, thread, time::Duration};
+
+use glosco::{ClientSettings, ServerSettings};
+
+fn main() {
+    let capture = PathBuf::from(env::args().nth(1).expect("usage: embedded CAPTURE"));
+    let database = env::temp_dir().join(format!("glosco-embedded-{}.db", std::process::id()));
+
+    let server = glosco::run_server(ServerSettings {
+        bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+        database: database.to_string_lossy().into_owned(),
+        ..Default::default()
+    }).expect("failed to start collector");
+    println!("collector listening on {:?}", server.local_addr());
+
+    let client = glosco::run_client(ClientSettings {
+        captures: vec![capture],
+        remotes: vec![server.local_addr()],
+        ident: Some("embedded".to_string()),
+        snapshot_interval: None,
+        ..Default::default()
+    }).expect("failed to start sensor");
+    // Reading a capture file, the sensor stops by itself at the end of it
+    client.join();
+
+    // Give the collector a moment to store what's still in flight
+    thread::sleep(Duration::from_secs(1));
+    server.shutdown();
+    server.join();
+
+    let db = rusqlite::Connection::open(&database).expect("failed to open database");
+    let rows: i64 = db.query_row("SELECT COUNT(*) FROM state_all", [], |row| row.get(0))
+        .expect("failed to count state rows");
+    println!("stored {} state rows", rows);
+}
@@ -0,0 +1,174 @@
+//! `#[derive(Coder)]`, for glosco's wire encoding: the same encode and decode its hand-written
+//! impls have. A struct is its fields one after another, in the order they're declared. An enum
+//! is the one-byte mark each variant is given with `#[mark = N]`, then that variant's fields the
+//! same way; a mark that doesn't belong to any variant fails to decode with `InvalidInput`.
+//!
+//! Every variant needs a mark and no two may share one; either mistake is a compile error.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, spanned::Spanned, Attribute, Data, DeriveInput, Expr, ExprLit, Fields, Lit, LitInt};
+
+#[proc_macro_derive(Coder, attributes(mark))]
+pub fn derive_coder(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if let Some(attr) = input.attrs.iter().find(|attr| attr.path().is_ident("mark")) {
+        return Err(syn::Error::new(attr.span(), "#[mark] goes on the variants of an enum"));
+    }
+    let (encode, decode) = match &input.data {
+        Data::Struct(data) => {
+            for field in data.fields.iter() {
+                no_mark(&field.attrs)?;
+            }
+            let bindings = bindings(&data.fields);
+            let pattern = pattern(quote!(Self), &data.fields, &bindings);
+            let encodes = encodes(&bindings);
+            let construct = construct(quote!(Self), &data.fields);
+            (quote! {
+                let #pattern = self;
+                #(#encodes)*
+                ::std::result::Result::Ok(())
+            }, quote! {
+                ::std::result::Result::Ok(#construct)
+            })
+        },
+        Data::Enum(data) => {
+            let mut seen: Vec<(u8, &syn::Ident)> = Vec::new();
+            let mut encode_arms = Vec::new();
+            let mut decode_arms = Vec::new();
+            for variant in data.variants.iter() {
+                let name = &variant.ident;
+                let mark = mark(&variant.attrs)?.ok_or_else(|| {
+                    syn::Error::new(variant.span(), format!("variant {} needs a #[mark = N] to be encoded by", name))
+                })?;
+                let value = mark.base10_parse::<u8>()?;
+                if let Some((_, other)) = seen.iter().find(|(earlier, _)| *earlier == value) {
+                    return Err(syn::Error::new(mark.span(), format!("variant {} already has mark {}", other, value)));
+                }
+                for field in variant.fields.iter() {
+                    no_mark(&field.attrs)?;
+                }
+                let bindings = bindings(&variant.fields);
+                let pattern = pattern(quote!(Self::#name), &variant.fields, &bindings);
+                let encodes = encodes(&bindings);
+                let construct = construct(quote!(Self::#name), &variant.fields);
+                encode_arms.push(quote! {
+                    #pattern => {
+                        ::std::io::Write::write_all(writer, &[#value])?;
+                        #(#encodes)*
+                    },
+                });
+                decode_arms.push(quote! {
+                    #value => ::std::result::Result::Ok(#construct),
+                });
+                seen.push((value, name));
+            }
+            let encode = if encode_arms.is_empty() {
+                // No value to encode; an empty match on a reference wouldn't be exhaustive
+                quote!(match *self {})
+            } else {
+                quote! {
+                    match self {
+                        #(#encode_arms)*
+                    }
+                    ::std::result::Result::Ok(())
+                }
+            };
+            (encode, quote! {
+                match <u8 as ::glosco::coding::Coder>::decode(reader)? {
+                    #(#decode_arms)*
+                    _ => ::std::result::Result::Err(::std::io::ErrorKind::InvalidInput.into()),
+                }
+            })
+        },
+        Data::Union(data) => return Err(syn::Error::new(data.union_token.span, "Coder can't be derived for a union")),
+    };
+
+    let name = &input.ident;
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::glosco::coding::Coder));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::glosco::coding::Coder for #name #ty_generics #where_clause {
+            // A type with no fields has nothing to write or read
+            #[allow(unused_variables)]
+            fn encode<__W: ::std::io::Write>(&self, writer: &mut __W) -> ::std::io::Result<()> {
+                #encode
+            }
+
+            #[allow(unused_variables)]
+            fn decode<__R: ::std::io::Read>(reader: &mut __R) -> ::std::io::Result<Self> {
+                #decode
+            }
+        }
+    })
+}
+
+/// The mark among `attrs`, if there is one.
+fn mark(attrs: &[Attribute]) -> syn::Result<Option<LitInt>> {
+    let mut found = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("mark")) {
+        if found.is_some() {
+            return Err(syn::Error::new(attr.span(), "a variant has only the one mark"));
+        }
+        match &attr.meta.require_name_value()?.value {
+            Expr::Lit(ExprLit { lit: Lit::Int(int), .. }) => found = Some(int.clone()),
+            value => return Err(syn::Error::new(value.span(), "a mark is a number from 0 to 255")),
+        }
+    }
+    Ok(found)
+}
+
+fn no_mark(attrs: &[Attribute]) -> syn::Result<()> {
+    match attrs.iter().find(|attr| attr.path().is_ident("mark")) {
+        Some(attr) => Err(syn::Error::new(attr.span(), "#[mark] goes on the variants of an enum")),
+        None => Ok(()),
+    }
+}
+
+/// A name to bind each field to when taking the value apart.
+fn bindings(fields: &Fields) -> Vec<syn::Ident> {
+    fields.iter().enumerate()
+        .map(|(idx, field)| field.ident.as_ref().map(|ident| format_ident!("__{}", ident)).unwrap_or_else(|| format_ident!("__{}", idx)))
+        .collect()
+}
+
+/// A pattern taking `path` apart into `bindings`.
+fn pattern(path: TokenStream2, fields: &Fields, bindings: &[syn::Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #bindings),* })
+        },
+        Fields::Unnamed(_) => quote!(#path(#(#bindings),*)),
+        Fields::Unit => path,
+    }
+}
+
+fn encodes(bindings: &[syn::Ident]) -> Vec<TokenStream2> {
+    bindings.iter().map(|binding| quote!(::glosco::coding::Coder::encode(#binding, writer)?;)).collect()
+}
+
+/// `path` built from fields decoded in the order they're declared, which a struct expression
+/// evaluates them in.
+fn construct(path: TokenStream2, fields: &Fields) -> TokenStream2 {
+    let decodes = fields.iter().map(|field| {
+        let ty = &field.ty;
+        quote!(<#ty as ::glosco::coding::Coder>::decode(reader)?)
+    });
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #decodes),* })
+        },
+        Fields::Unnamed(_) => quote!(#path(#(#decodes),*)),
+        Fields::Unit => path,
+    }
+}
@@ -0,0 +1,516 @@
+//! Changes an operator makes by hand to what a collector's database records, as `glosco admin`.
+
+use std::{collections::{BTreeMap, BTreeSet}, error::Error, net::{IpAddr, Ipv4Addr, Ipv6Addr}, path::{Path, PathBuf}};
+
+use rusqlite::params;
+
+use crate::{cli::{AdminArgs, AdminCommand, LabelArgs, PurgeArgs}, db, filter::Cidr, partition, query::{self, now_secs}, server, shard, sync::Hello};
+
+/// Most labels one ident may have; as many tags as a hello may carry.
+pub const MAX_LABELS: usize = Hello::MAX_TAGS;
+
+/// Entry point for `glosco admin`.
+pub fn run(args: AdminArgs) {
+    match args.command {
+        AdminCommand::Label(label) => run_label(&args.database, label),
+        AdminCommand::Purge(purge) => run_purge(&args.database, purge),
+    }
+}
+
+/// The database `ident`'s rows are kept in: `database`, or its shard if `database` is a
+/// directory of them.
+fn path_for(database: &str, ident: &str) -> PathBuf {
+    match Path::new(database).is_dir() {
+        true => Path::new(database).join(shard::file_name(ident)),
+        false => PathBuf::from(database),
+    }
+}
+
+/// Open `path`, migrated as the collector would.
+fn open(path: &Path) -> rusqlite::Connection {
+    let mut db = db::open(path).expect("failed to open database");
+    server::migrate(&mut db);
+    db
+}
+
+fn run_label(database: &str, args: LabelArgs) {
+    let mut db = open(&path_for(database, &args.ident));
+    let labels = set_labels(&mut db, &args.ident, &args.labels, &args.delete)
+        .unwrap_or_else(|e| panic!("couldn't label {}: {}", args.ident, e));
+    println!("{}", serde_json::json!({ "ident": args.ident, "labels": labels }));
+}
+
+/// Delete `ident`'s labels keyed in `delete`, then set `labels`, each replacing any with the
+/// same key; all of it or, if it would leave more than `MAX_LABELS`, none. Gives the labels as
+/// they then stand.
+///
+/// Labels are held to the rules tags are (see `Hello::check_tag`).
+pub fn set_labels(db: &mut rusqlite::Connection, ident: &str, labels: &[(String, String)], delete: &[String]) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    for (key, value) in labels {
+        Hello::check_tag(key, value)?;
+    }
+    let now = now_secs();
+    let txn = db.transaction()?;
+    let mut remove = txn.prepare_cached("DELETE FROM ident_labels WHERE ident = ? AND key = ?;")?;
+    for key in delete {
+        remove.execute(params![ident, key])?;
+    }
+    drop(remove);
+    let mut insert = txn.prepare_cached("
+        INSERT INTO ident_labels (ident, key, value, set_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT (ident, key) DO UPDATE SET value = excluded.value, set_at = excluded.set_at;
+    ")?;
+    for (key, value) in labels {
+        insert.execute(params![ident, key, value, now])?;
+    }
+    drop(insert);
+    let current = query::ident_labels(&txn, ident)?;
+    if current.len() > MAX_LABELS {
+        return Err(format!("that would leave {} labels, over the limit of {}", current.len(), MAX_LABELS).into());
+    }
+    txn.commit()?;
+    Ok(current)
+}
+
+/// What `glosco admin purge` deletes everything recorded about.
+#[derive(Debug, Clone)]
+pub enum Purge {
+    /// Every address in a block, wherever it's recorded as either end of a connection, in a
+    /// name record, or as the address a reverse lookup was for.
+    Host(Cidr),
+    /// One sensor's ident: what it reported and what's kept about it. Name records aren't kept
+    /// by ident, so they stay.
+    Ident(String),
+}
+
+/// What a purge deleted, or would have.
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct Purged {
+    /// Rows deleted from each table, with every day table counted as `state`.
+    pub deleted: BTreeMap<String, usize>,
+    /// Hours whose summaries were recomputed without the deleted state rows.
+    pub resummarized: usize,
+}
+
+/// Tables holding hosts' addresses other than the state tables, with the columns they're in.
+const HOST_COLUMNS: &[(&str, &[&str])] = &[
+    ("latest_state", &["srchost", "dsthost"]),
+    ("active_now", &["srchost", "dsthost"]),
+    ("anomalies", &["srchost", "dsthost"]),
+    ("baseline", &["dsthost"]),
+    ("scans", &["srchost", "dsthost"]),
+    ("names", &["querier", "responder", "addr", "name"]),
+];
+
+/// Tables keyed by ident other than the state tables. Deleting an ident's summaries outright
+/// is recomputing them without its rows.
+const IDENT_TABLES: &[&str] = &[
+    "latest_state", "active_now", "anomalies", "baseline", "scans", "summary_hourly",
+    "clients", "client_sessions", "client_tags", "ident_labels", "gaps", "sensor_health", "interfaces",
+];
+
+fn run_purge(database: &str, args: PurgeArgs) {
+    let (target, paths) = match (args.host, args.ident) {
+        (_, Some(ident)) => {
+            let path = path_for(database, &ident);
+            (Purge::Ident(ident), vec![path])
+        }
+        (Some(host), None) if Path::new(database).is_dir() => {
+            (Purge::Host(host), shard::list(database).expect("failed to list shards"))
+        }
+        (Some(host), None) => (Purge::Host(host), vec![PathBuf::from(database)]),
+        (None, None) => unreachable!("clap requires --host or --ident"),
+    };
+    for path in paths {
+        let mut db = open(&path);
+        let purged = purge(&mut db, &target, args.confirm)
+            .unwrap_or_else(|e| panic!("couldn't purge {}: {}", path.display(), e));
+        println!("{}", serde_json::json!({
+            "database": path,
+            "dry_run": !args.confirm,
+            "deleted": purged.deleted,
+            "resummarized": purged.resummarized,
+        }));
+    }
+}
+
+/// Delete every row about `target`, recompute the hourly summaries that counted any, and record
+/// the purge in `purges`; all in one transaction, which is rolled back instead of committed
+/// unless `confirm` is set, to count what would go.
+///
+/// Freed pages are zeroed as rows are deleted, so what's purged doesn't linger in the file.
+pub fn purge(db: &mut rusqlite::Connection, target: &Purge, confirm: bool) -> rusqlite::Result<Purged> {
+    db.pragma_update(None, "secure_delete", true)?;
+    let txn = db.transaction()?;
+    let states = match partition::is_partitioned(&txn)? {
+        true => partition::tables(&txn)?,
+        false => vec!["state".to_string()],
+    };
+    let mut purged = Purged::default();
+    match target {
+        Purge::Host(block) => {
+            // Derived tables go first, so the state tables' delete triggers don't get to them
+            for (table, columns) in HOST_COLUMNS {
+                let rows = rows_within(&txn, table, columns, block)?;
+                purged.deleted.insert(table.to_string(), delete_rows(&txn, table, &rows)?);
+            }
+            let mut hours = BTreeSet::new();
+            let mut deleted = 0;
+            for table in &states {
+                let rows = rows_within(&txn, table, &["srchost", "dsthost"], block)?;
+                let mut hour = txn.prepare_cached(&format!("
+                    SELECT CAST(instime / 3600 AS INTEGER) * 3600 FROM {} WHERE rowid = ?;
+                ", table))?;
+                for row in &rows {
+                    hours.insert(hour.query_row(params![row], |row| row.get::<_, i64>(0))?);
+                }
+                deleted += delete_rows(&txn, table, &rows)?;
+            }
+            purged.deleted.insert("state".to_string(), deleted);
+            server::resummarize(&txn, &hours)?;
+            purged.resummarized = hours.len();
+        }
+        Purge::Ident(ident) => {
+            for table in IDENT_TABLES {
+                let deleted = txn.execute(&format!("DELETE FROM {} WHERE ident = ?;", table), params![ident])?;
+                purged.deleted.insert(table.to_string(), deleted);
+            }
+            let mut deleted = 0;
+            for table in &states {
+                deleted += txn.execute(&format!("DELETE FROM {} WHERE ident = ?;", table), params![ident])?;
+            }
+            purged.deleted.insert("state".to_string(), deleted);
+        }
+    }
+    let (host, ident) = match target {
+        Purge::Host(block) => (Some(block.to_string()), None),
+        Purge::Ident(ident) => (None, Some(ident.as_str())),
+    };
+    txn.execute("
+        INSERT INTO purges (at, host, ident, deleted) VALUES (?, ?, ?, ?);
+    ", params![now_secs(), host, ident, serde_json::to_string(&purged.deleted).unwrap()])?;
+    if confirm {
+        txn.commit()?;
+    }
+    Ok(purged)
+}
+
+/// The rowids of `table`'s rows with an address in `block` in any of `columns`.
+///
+/// Blocks can't be matched in SQL, so every row is read and checked.
+fn rows_within(db: &rusqlite::Connection, table: &str, columns: &[&str], block: &Cidr) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = db.prepare(&format!("SELECT rowid, {} FROM {};", columns.join(", "), table))?;
+    let mut rows = stmt.query([])?;
+    let mut within = Vec::new();
+    while let Some(row) = rows.next()? {
+        for column in 1..=columns.len() {
+            let value: Option<String> = row.get(column)?;
+            if value.as_deref().and_then(address_of).is_some_and(|addr| block.contains(&addr)) {
+                within.push(row.get(0)?);
+                break;
+            }
+        }
+    }
+    Ok(within)
+}
+
+fn delete_rows(db: &rusqlite::Connection, table: &str, rows: &[i64]) -> rusqlite::Result<usize> {
+    let mut delete = db.prepare_cached(&format!("DELETE FROM {} WHERE rowid = ?;", table))?;
+    let mut deleted = 0;
+    for row in rows {
+        deleted += delete.execute(params![row])?;
+    }
+    Ok(deleted)
+}
+
+/// The address `value` is, or the one it asks for the name of if it's a reverse lookup name
+/// like `4.3.2.1.in-addr.arpa`.
+fn address_of(value: &str) -> Option<IpAddr> {
+    if let Ok(addr) = value.parse() {
+        return Some(addr);
+    }
+    let name = value.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(octets) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<u8> = octets.split('.').rev().map(str::parse).collect::<Result<_, _>>().ok()?;
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        return Some(Ipv4Addr::from(octets).into());
+    }
+    let nibbles = name.strip_suffix(".ip6.arpa")?.split('.').rev()
+        .map(|nibble| u8::from_str_radix(nibble, 16).ok().filter(|_| nibble.len() == 1))
+        .collect::<Option<Vec<u8>>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let bits = nibbles.iter().fold(0u128, |bits, nibble| bits << 4 | *nibble as u128);
+    Some(Ipv6Addr::from(bits).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{observe::{Closed, Connection, Endpoint, Message, Name, Problem, Protocol, Resolution, State}, scan::{Scan, ScanKind}, server::Importer, test_support::Scratch};
+
+    use super::*;
+
+    /// Midnight UTC starting 2025-06-12.
+    const MIDNIGHT: f64 = 1_749_686_400.0;
+
+    fn state(src: &str, dst: &str, protocol: Protocol, secs: f64) -> State {
+        let endpoint = |addr: &str| {
+            let addr: std::net::SocketAddr = addr.parse().unwrap();
+            Endpoint { addr: addr.ip(), port: addr.port() }
+        };
+        State {
+            as_of: SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs),
+            connection: Connection { interface: 0, src: endpoint(src), dst: endpoint(dst), protocol },
+            rtt_micros: None,
+        }
+    }
+
+    /// A database partitioned by day, with rows either side of midnight in every table a host
+    /// is recorded in: 10.9.9.9 as either end of connections, in a scan and in name records,
+    /// and 10.0.0.1 connecting to 10.0.0.2 alongside, from the idents `sensor` and `other`.
+    fn fixture(scratch: &Scratch) {
+        let mut db = open(scratch.path());
+        server::migrate(&mut db);
+        partition::Partitions::open(&mut db, true, MIDNIGHT - 3600.0).unwrap();
+        drop(db);
+
+        let (before, after) = (MIDNIGHT - 600.0, MIDNIGHT + 600.0);
+        let mut importer = Importer::open(scratch.database()).unwrap();
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state("10.0.0.1:1", "10.9.9.9:443", Protocol::Tcp, before)),
+            Message::Starting(state("10.0.0.1:2", "10.0.0.2:443", Protocol::Tcp, before)),
+            Message::Name(state("10.0.0.53:53", "10.0.0.1:5353", Protocol::Udp, before), vec![
+                Name { name: "target.example".to_string(), address: Some(Resolution::Address("10.9.9.9".parse().unwrap())) },
+                Name { name: "other.example".to_string(), address: Some(Resolution::Address("10.0.0.2".parse().unwrap())) },
+            ]),
+        ]).unwrap();
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Active(state("10.9.9.9:3", "10.0.0.2:22", Protocol::Tcp, after)),
+            Message::Ended(state("10.0.0.1:2", "10.0.0.2:443", Protocol::Tcp, after), Closed::Reset),
+            Message::Failed(state("10.0.0.1:4", "10.0.0.2:80", Protocol::Tcp, after), Problem { kind: 3, code: 3, repeats: 0 }),
+            Message::Name(state("10.0.0.53:53", "10.0.0.1:5353", Protocol::Udp, after), vec![
+                Name { name: "9.9.9.10.in-addr.arpa".to_string(), address: Some(Resolution::Alias("target.example".to_string())) },
+            ]),
+            Message::Scan(state("10.9.9.9:0", "10.0.0.2:0", Protocol::Tcp, after), Scan { kind: ScanKind::Ports, count: 40, window: Duration::from_secs(30) }),
+            Message::Scan(state("10.0.0.1:0", "0.0.0.0:22", Protocol::Tcp, after), Scan { kind: ScanKind::Hosts, count: 40, window: Duration::from_secs(30) }),
+        ]).unwrap();
+        importer.store("other", "127.0.0.1:40001", &[
+            Message::Starting(state("10.0.0.5:6", "10.9.9.9:443", Protocol::Tcp, after)),
+            Message::Starting(state("10.0.0.5:7", "10.0.0.2:443", Protocol::Tcp, after)),
+        ]).unwrap();
+        drop(importer);
+
+        let db = open(scratch.path());
+        server::summarize(&db, MIDNIGHT + 3600.0).unwrap();
+        for (ident, dsthost) in [("sensor", "10.9.9.9"), ("sensor", "10.0.0.2"), ("other", "10.0.0.2")] {
+            db.execute("INSERT INTO baseline (ident, dsthost, dstport, proto, first_seen) VALUES (?, ?, 443, 6, ?);", params![ident, dsthost, before]).unwrap();
+            db.execute("INSERT INTO anomalies (detected, ident, srchost, srcport, dsthost, dstport, proto) VALUES (?, ?, '10.0.0.1', 1, ?, 443, 6);", params![after, ident, dsthost]).unwrap();
+        }
+    }
+
+    /// Every table a purge touches, and the day tables: its rows, each written out whole.
+    fn contents(db: &rusqlite::Connection) -> BTreeMap<String, Vec<String>> {
+        let mut tables: Vec<String> = IDENT_TABLES.iter().chain(HOST_COLUMNS.iter().map(|(table, _)| table)).map(|table| table.to_string()).collect();
+        tables.extend(partition::tables(db).unwrap());
+        tables.push("purges".to_string());
+        tables.into_iter().map(|table| {
+            let mut stmt = db.prepare(&format!("SELECT * FROM {} ORDER BY rowid", table)).unwrap();
+            let columns = stmt.column_count();
+            let rows = stmt.query_map([], |row| {
+                (0 .. columns).map(|idx| row.get::<_, rusqlite::types::Value>(idx).map(|value| format!("{:?}", value)))
+                    .collect::<rusqlite::Result<Vec<_>>>().map(|values| values.join(" "))
+            }).unwrap().collect::<rusqlite::Result<Vec<_>>>().unwrap();
+            (table, rows)
+        }).collect()
+    }
+
+    /// Whether `row`, as `contents` writes it, mentions `text` anywhere.
+    fn mentions(row: &str, text: &str) -> bool {
+        row.contains(&format!("Text(\"{}\")", text))
+    }
+
+    #[test]
+    fn a_dry_run_counts_what_would_go_and_changes_nothing() {
+        let scratch = Scratch::new("dry-run");
+        fixture(&scratch);
+        let mut db = open(scratch.path());
+        let before = contents(&db);
+        let purged = purge(&mut db, &Purge::Host("10.9.9.0/24".parse().unwrap()), false).unwrap();
+        assert_eq!(contents(&db), before);
+
+        let counts: Vec<(&str, usize)> = purged.deleted.iter().map(|(table, count)| (table.as_str(), *count)).filter(|(_, count)| *count > 0).collect();
+        // Both Starting rows to it, and the Active from it; one connection of each ident's latest
+        // to it, and the Active one still open; the forward and reverse records; its port scan
+        assert_eq!(counts, [("active_now", 3), ("anomalies", 1), ("baseline", 1), ("latest_state", 3), ("names", 2), ("scans", 1), ("state", 3)]);
+        // The hour before midnight and the one after
+        assert_eq!(purged.resummarized, 2);
+    }
+
+    #[test]
+    fn purging_a_host_leaves_nothing_of_it_and_everything_else() {
+        let scratch = Scratch::new("host");
+        fixture(&scratch);
+        let mut db = open(scratch.path());
+        let before = contents(&db);
+        assert_eq!(partition::tables(&db).unwrap().len(), 2);
+        let purged = purge(&mut db, &Purge::Host("10.9.9.0/24".parse().unwrap()), true).unwrap();
+
+        let after = contents(&db);
+        let mut gone = BTreeMap::new();
+        for (table, rows) in &before {
+            if table == "summary_hourly" || table == "purges" {
+                continue;
+            }
+            let left: Vec<&String> = rows.iter()
+                .filter(|row| !mentions(row, "10.9.9.9") && !mentions(row, "9.9.9.10.in-addr.arpa"))
+                .collect();
+            assert_eq!(after[table].iter().collect::<Vec<_>>(), left, "{}", table);
+            // Every day table counts as state
+            let table = if table.starts_with("state_") { "state" } else { table.as_str() };
+            *gone.entry(table.to_string()).or_insert(0) += rows.len() - left.len();
+        }
+        gone.retain(|table, _| HOST_COLUMNS.iter().any(|(host_table, _)| host_table == table) || table == "state");
+        assert_eq!(gone, purged.deleted);
+        // The summaries are as if its rows had never been there
+        let summaries = after["summary_hourly"].clone();
+        db.execute("DELETE FROM summary_hourly;", []).unwrap();
+        db.execute("DELETE FROM watermarks;", []).unwrap();
+        server::summarize(&db, MIDNIGHT + 3600.0).unwrap();
+        assert_eq!(contents(&db)["summary_hourly"], summaries);
+        assert_ne!(before["summary_hourly"], summaries);
+
+        let (host, ident, deleted): (Option<String>, Option<String>, String) = db.query_row("SELECT host, ident, deleted FROM purges", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!((host.as_deref(), ident), (Some("10.9.9.0/24"), None));
+        assert_eq!(deleted, serde_json::to_string(&purged.deleted).unwrap());
+    }
+
+    #[test]
+    fn purging_an_ident_leaves_the_others() {
+        let scratch = Scratch::new("ident");
+        fixture(&scratch);
+        let mut db = open(scratch.path());
+        for table in IDENT_TABLES.iter().filter(|table| !["latest_state", "active_now", "anomalies", "baseline", "scans", "summary_hourly"].contains(table)) {
+            // One row of each ident, whatever else the table has
+            for ident in ["sensor", "other"] {
+                db.execute(&format!("INSERT OR IGNORE INTO {} (ident) VALUES (?);", table), params![ident]).unwrap();
+            }
+        }
+        let before = contents(&db);
+        purge(&mut db, &Purge::Ident("sensor".to_string()), true).unwrap();
+
+        let after = contents(&db);
+        for (table, rows) in &before {
+            if table == "purges" {
+                continue;
+            }
+            let left: Vec<&String> = rows.iter().filter(|row| !mentions(row, "sensor")).collect();
+            assert_eq!(after[table].iter().collect::<Vec<_>>(), left, "{}", table);
+        }
+        // Name records aren't kept by ident
+        assert_eq!(after["names"].len(), 3);
+    }
+
+    fn pairs(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn labelled(labels: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs(labels).into_iter().collect()
+    }
+
+    #[test]
+    fn labels_are_set_replaced_and_deleted_a_key_at_a_time() {
+        let scratch = Scratch::new("labels");
+        let mut db = open(scratch.path());
+        let set = set_labels(&mut db, "sensor", &pairs(&[("owner", "team-x"), ("note", "decommission next month")]), &[]).unwrap();
+        assert_eq!(set, labelled(&[("note", "decommission next month"), ("owner", "team-x")]));
+
+        // Another owner replaces the first and leaves the note be
+        let set = set_labels(&mut db, "sensor", &pairs(&[("owner", "team-y")]), &[]).unwrap();
+        assert_eq!(set, labelled(&[("note", "decommission next month"), ("owner", "team-y")]));
+
+        // Deleting comes first, so a key can be deleted and set again at once; deleting a key
+        // there isn't is nothing
+        let set = set_labels(&mut db, "sensor", &pairs(&[("owner", "team-z")]), &keys(&["note", "owner", "missing"])).unwrap();
+        assert_eq!(set, labelled(&[("owner", "team-z")]));
+        let set = set_labels(&mut db, "sensor", &[], &keys(&["owner"])).unwrap();
+        assert_eq!(set, labelled(&[]));
+
+        // Each ident's are its own
+        set_labels(&mut db, "other", &pairs(&[("owner", "team-x")]), &[]).unwrap();
+        assert_eq!(query::ident_labels(&db, "sensor").unwrap(), labelled(&[]));
+        assert_eq!(query::ident_labels(&db, "other").unwrap(), labelled(&[("owner", "team-x")]));
+    }
+
+    #[test]
+    fn labels_outside_the_limits_change_nothing() {
+        let scratch = Scratch::new("label-limits");
+        let mut db = open(scratch.path());
+        set_labels(&mut db, "sensor", &pairs(&[("owner", "team-x")]), &[]).unwrap();
+
+        let longest_key = "k".repeat(Hello::MAX_TAG_KEY);
+        let longest_value = "v".repeat(4 * Hello::MAX_TAG_KEY);
+        let too_long_key = "k".repeat(Hello::MAX_TAG_KEY + 1);
+        let too_long_value = "v".repeat(4 * Hello::MAX_TAG_KEY + 1);
+        for (key, value) in [
+            (too_long_key.as_str(), "x"),
+            ("", "x"),
+            ("has space", "x"),
+            ("owner", too_long_value.as_str()),
+            ("owner", "line\nbreak"),
+        ] {
+            // Even a deletion alongside goes undone
+            let err = set_labels(&mut db, "sensor", &pairs(&[("fine", "x"), (key, value)]), &keys(&["owner"])).unwrap_err();
+            assert!(err.to_string().contains("tag"), "{}", err);
+            assert_eq!(query::ident_labels(&db, "sensor").unwrap(), labelled(&[("owner", "team-x")]), "{:?}={:?}", key, value);
+        }
+        let set = set_labels(&mut db, "sensor", &pairs(&[(longest_key.as_str(), longest_value.as_str())]), &[]).unwrap();
+        assert_eq!(set[&longest_key], longest_value);
+
+        // Up to the limit in all, counting those already there
+        let more: Vec<(String, String)> = (set.len() .. MAX_LABELS).map(|n| (format!("key{}", n), "x".to_string())).collect();
+        assert_eq!(set_labels(&mut db, "sensor", &more, &[]).unwrap().len(), MAX_LABELS);
+        let err = set_labels(&mut db, "sensor", &pairs(&[("one-more", "x")]), &[]).unwrap_err();
+        assert!(err.to_string().contains("over the limit"), "{}", err);
+        assert_eq!(query::ident_labels(&db, "sensor").unwrap().len(), MAX_LABELS);
+        // Though one can replace another
+        assert_eq!(set_labels(&mut db, "sensor", &pairs(&[("one-more", "x")]), &keys(&["owner"])).unwrap().len(), MAX_LABELS);
+    }
+
+    #[test]
+    fn clients_are_filtered_by_every_label_asked_for() {
+        let scratch = Scratch::new("label-filter");
+        let mut db = open(scratch.path());
+        for ident in ["a", "b", "c"] {
+            db.execute("INSERT INTO clients (ident, first_seen, last_seen) VALUES (?, ?, ?);", params![ident, MIDNIGHT, MIDNIGHT]).unwrap();
+        }
+        set_labels(&mut db, "a", &pairs(&[("owner", "team-x"), ("rack", "r1")]), &[]).unwrap();
+        set_labels(&mut db, "b", &pairs(&[("owner", "team-x"), ("rack", "r2")]), &[]).unwrap();
+        set_labels(&mut db, "c", &pairs(&[("owner", "team-y")]), &[]).unwrap();
+        // Labels are the operator's, so a client tag of the same key and value doesn't count
+        db.execute("INSERT INTO client_tags (ident, key, value) VALUES ('c', 'rack', 'r1');", []).unwrap();
+
+        let idents = |db: &rusqlite::Connection, labels: &[(&str, &str)]| -> Vec<String> {
+            query::clients(db, &[], &pairs(labels)).unwrap().into_iter().map(|client| client.ident).collect()
+        };
+        assert_eq!(idents(&db, &[]), ["a", "b", "c"]);
+        assert_eq!(idents(&db, &[("owner", "team-x")]), ["a", "b"]);
+        assert_eq!(idents(&db, &[("owner", "team-x"), ("rack", "r1")]), ["a"]);
+        assert_eq!(idents(&db, &[("rack", "r1")]), ["a"]);
+        assert_eq!(idents(&db, &[("owner", "team-z")]), Vec::<String>::new());
+        // And each comes with its labels
+        let clients = query::clients(&db, &[], &[]).unwrap();
+        assert_eq!(clients[2].labels, labelled(&[("owner", "team-y")]));
+
+        // A deleted label no longer matches
+        set_labels(&mut db, "a", &[], &keys(&["rack"])).unwrap();
+        assert_eq!(idents(&db, &[("rack", "r1")]), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,638 @@
+use std::{cell::OnceCell, collections::{BTreeMap, HashMap}, fmt::{self, Display, Formatter}, panic::{self, AssertUnwindSafe}, str::FromStr, sync::{mpsc, Arc, Mutex, OnceLock, RwLock, atomic::{AtomicU64, Ordering}}, thread, time::{Duration, Instant, SystemTime}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{eventlog::Event, filter::{Cidr, Glob}, observe::{Closed, Endpoint, Message}};
+#[cfg(feature = "sqlite")]
+use crate::{changes::{Op, RowChange}, coding::TMOUT_MARK, observe::{Connection, Protocol, State}, partition};
+
+/// The kinds of message a rule can match on.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Starting,
+    Active,
+    Ended,
+    Reset,
+    Failed,
+    Name,
+    Scan,
+}
+
+impl Kind {
+    pub fn of(message: &Message) -> Self {
+        match message {
+            Message::Starting(_) => Self::Starting,
+            Message::Active(_) => Self::Active,
+            Message::Ended(_, Closed::Reset) => Self::Reset,
+            Message::Ended(_, _) => Self::Ended,
+            Message::Failed(_, _) => Self::Failed,
+            Message::Name(_, _) => Self::Name,
+            Message::Scan(_, _) => Self::Scan,
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = BadRule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starting" => Ok(Self::Starting),
+            "active" => Ok(Self::Active),
+            "ended" => Ok(Self::Ended),
+            "reset" => Ok(Self::Reset),
+            "failed" => Ok(Self::Failed),
+            "name" => Ok(Self::Name),
+            "scan" => Ok(Self::Scan),
+            _ => Err(BadRule(format!("unknown message kind {:?}", s))),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Self::Starting => "starting",
+            Self::Active => "active",
+            Self::Ended => "ended",
+            Self::Reset => "reset",
+            Self::Failed => "failed",
+            Self::Name => "name",
+            Self::Scan => "scan",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadRule(String);
+
+impl Display for BadRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "bad alert rule: {}", self.0)
+    }
+}
+
+impl std::error::Error for BadRule {}
+
+/// A predicate over accepted messages, plus how often it may fire.
+///
+/// Written on the command line as comma-separated `key=value` terms, all of which must match:
+/// `kind=failed,ident=db-*,dst=10.4.0.0/16,port=5432,window=300`. `kind` and `port` may be
+/// repeated to match any of several values; `kind` defaults to `failed`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rule {
+    kinds: Vec<Kind>,
+    ident: Option<Glob>,
+    dst: Option<Cidr>,
+    ports: Vec<u16>,
+    window: Duration,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Self {
+            kinds: vec![Kind::Failed],
+            ident: None,
+            dst: None,
+            ports: Vec::new(),
+            window: Self::WINDOW,
+        }
+    }
+}
+
+impl Rule {
+    pub const WINDOW: Duration = Duration::from_secs(300);
+
+    pub fn matches(&self, ident: &str, message: &Message) -> bool {
+        let dst = destination(message);
+        self.kinds.contains(&Kind::of(message))
+            && self.ident.as_ref().map(|pat| pat.matches(ident)).unwrap_or(true)
+            && self.dst.map(|cidr| cidr.contains(&dst.addr)).unwrap_or(true)
+            && (self.ports.is_empty() || self.ports.contains(&dst.port))
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// A rule matching messages of any of `kinds`, from any ident to anywhere, to narrow down
+    /// with the methods below: `Rule::new([Kind::Failed, Kind::Scan]).dst(cidr)` is what
+    /// `kind=failed,kind=scan,dst=...` parses to.
+    pub fn new(kinds: impl IntoIterator<Item = Kind>) -> Self {
+        Self { kinds: kinds.into_iter().collect(), ..Default::default() }
+    }
+
+    /// Only messages from idents matching `pattern`, a glob.
+    pub fn ident(mut self, pattern: &str) -> Self {
+        self.ident = Some(Glob(pattern.to_string()));
+        self
+    }
+
+    /// Only messages headed into `cidr`.
+    pub fn dst(mut self, cidr: Cidr) -> Self {
+        self.dst = Some(cidr);
+        self
+    }
+
+    /// Only messages headed to `port`, or any other port given this way.
+    pub fn port(mut self, port: u16) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    /// Fire at most once this often for an ident and destination, rather than every `WINDOW`.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = BadRule;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Rule {
+    type Err = BadRule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = Self { kinds: Vec::new(), ..Default::default() };
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (key, value) = term.split_once('=')
+                .ok_or_else(|| BadRule(format!("{:?} is not key=value", term)))?;
+            match key {
+                "kind" => rule.kinds.push(value.parse()?),
+                "ident" => rule.ident = Some(Glob(value.to_string())),
+                "dst" => rule.dst = Some(value.parse().map_err(|e| BadRule(format!("{}", e)))?),
+                "port" => rule.ports.push(value.parse().map_err(|_| BadRule(format!("bad port {:?}", value)))?),
+                "window" => rule.window = Duration::from_secs_f64(
+                    value.parse().map_err(|_| BadRule(format!("bad window {:?}", value)))?
+                ),
+                _ => return Err(BadRule(format!("unknown key {:?}", key))),
+            }
+        }
+        if rule.kinds.is_empty() {
+            rule.kinds.push(Kind::Failed);
+        }
+        Ok(rule)
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut terms: Vec<String> = self.kinds.iter().map(|k| format!("kind={}", k)).collect();
+        if let Some(ident) = &self.ident {
+            terms.push(format!("ident={}", ident));
+        }
+        if let Some(dst) = &self.dst {
+            terms.push(format!("dst={}", dst));
+        }
+        terms.extend(self.ports.iter().map(|p| format!("port={}", p)));
+        terms.push(format!("window={}", self.window.as_secs_f64()));
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+/// Where a message was headed; for names, that's the resolver, and for scans, the host or the
+/// port scanned.
+fn destination(message: &Message) -> Endpoint {
+    match message {
+        Message::Starting(state) | Message::Active(state)
+            | Message::Ended(state, _) | Message::Failed(state, _)
+            | Message::Name(state, _) | Message::Scan(state, _) => state.connection.dst,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    rule: String,
+    ident: &'a str,
+    peer: &'a str,
+    received: f64,
+    message: &'a Message,
+    /// Matches swallowed by deduplication since this rule last fired for this destination.
+    suppressed: u64,
+    /// The ident's operator labels (see `admin::set_labels`).
+    labels: &'a BTreeMap<String, String>,
+}
+
+/// Deduplication key: which rule fired, for whom, and toward where.
+type FiringKey = (usize, Arc<str>, Endpoint);
+
+#[derive(Debug)]
+struct Firing {
+    last: Instant,
+    suppressed: u64,
+}
+
+/// Matches accepted messages against rules and POSTs a JSON payload to a webhook for each hit.
+///
+/// Each rule fires at most once per window for a given ident and destination; the rest are
+/// counted and reported with the next alert. Delivery happens on a dedicated thread with a
+/// timeout so a slow endpoint never holds up ingest.
+#[derive(Debug)]
+pub struct Alerter {
+    rules: RwLock<Arc<Vec<Rule>>>,
+    url: Arc<Mutex<String>>,
+    firings: Mutex<HashMap<FiringKey, Firing>>,
+    sender: mpsc::SyncSender<Vec<u8>>,
+    dropped: AtomicU64,
+}
+
+impl Alerter {
+    pub const BACKLOG: usize = 256;
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(url: String, rules: Vec<Rule>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+        let url = Arc::new(Mutex::new(url));
+        {
+            let url = url.clone();
+            thread::spawn(move || webhook_thread(url, receiver));
+        }
+        Self {
+            rules: RwLock::new(Arc::new(rules)),
+            url,
+            firings: Default::default(),
+            sender,
+            dropped: Default::default(),
+        }
+    }
+
+    /// Swap in new rules; whatever the old ones were suppressing is forgotten.
+    pub fn set_rules(&self, rules: Vec<Rule>) {
+        let mut current = self.rules.write().unwrap();
+        self.firings.lock().unwrap().clear();
+        *current = Arc::new(rules);
+    }
+
+    /// Deliver alerts to a different webhook from now on.
+    pub fn set_webhook(&self, url: String) {
+        *self.url.lock().unwrap() = url;
+    }
+
+    /// Alert on `event` for each rule it matches that isn't holding off, with the labels
+    /// `labels` gives, which is only asked (once) if one fires.
+    pub fn check<L: Fn() -> BTreeMap<String, String>>(&self, event: &Event, labels: L) {
+        let fetched = OnceCell::new();
+        let rules = self.rules.read().unwrap().clone();
+        for (idx, rule) in rules.iter().enumerate() {
+            if !rule.matches(&event.ident, &event.message) {
+                continue;
+            }
+            let key = (idx, event.ident.clone(), destination(&event.message));
+            let suppressed = {
+                let mut firings = self.firings.lock().unwrap();
+                let now = Instant::now();
+                let suppressed = match firings.get_mut(&key) {
+                    Some(firing) if now.duration_since(firing.last) < rule.window => {
+                        firing.suppressed += 1;
+                        continue;
+                    },
+                    Some(firing) => {
+                        let suppressed = firing.suppressed;
+                        *firing = Firing { last: now, suppressed: 0 };
+                        suppressed
+                    },
+                    None => {
+                        firings.insert(key, Firing { last: now, suppressed: 0 });
+                        0
+                    },
+                };
+                // Forget quiet entries once they age out so the map stays bounded; anything
+                // with a pending suppressed count is kept to be reported on its next firing.
+                firings.retain(|(ridx, _, _), firing| {
+                    firing.suppressed > 0 || rules.get(*ridx).is_some_and(|rule| now.duration_since(firing.last) < rule.window)
+                });
+                suppressed
+            };
+            self.send(rule.to_string(), event, suppressed, fetched.get_or_init(&labels));
+        }
+    }
+
+    /// Alert on something other than a rule match, like a baseline anomaly; these aren't
+    /// deduplicated.
+    pub fn notify(&self, reason: String, event: &Event, labels: &BTreeMap<String, String>) {
+        self.send(reason, event, 0, labels);
+    }
+
+    fn send(&self, rule: String, event: &Event, suppressed: u64, labels: &BTreeMap<String, String>) {
+        let received = event.received.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        let payload = serde_json::to_vec(&Payload {
+            rule,
+            ident: &event.ident,
+            peer: &event.peer,
+            received,
+            message: &event.message,
+            suppressed,
+            labels,
+        }).expect("failed to encode alert");
+        if self.sender.try_send(payload).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of alerts dropped because the webhook thread fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Attempts `follow_timeouts` makes at reading a row the feed announced, a millisecond apart;
+/// the feed announces a row as its transaction commits, a moment before others can read it.
+#[cfg(feature = "sqlite")]
+const READ_ATTEMPTS: usize = 100;
+
+/// Alert on each timeout stored in `database` as `changes` announces it, until the collector
+/// writing it has gone. Most timeouts are written by the collector itself, by maintenance or
+/// when a sensor disconnects, so ingest never has them to `check`; it leaves the ones sensors
+/// report to this as well, so that each alerts the same way, once.
+#[cfg(feature = "sqlite")]
+pub fn follow_timeouts(alerter: &Alerter, database: &str, changes: mpsc::Receiver<RowChange>) {
+    let mut db = None;
+    while let Ok(change) = changes.recv() {
+        if change.op != Op::Insert || !partition::is_state_table(&change.table) {
+            continue;
+        }
+        if db.is_none() {
+            match crate::db::open_read_only(database) {
+                Ok(opened) => db = Some(opened),
+                Err(e) => {
+                    println!("couldn't open {} to alert on timeouts: {:?}", database, e);
+                    continue;
+                },
+            }
+        }
+        let conn = db.as_ref().expect("just opened");
+        match timed_out(conn, &change) {
+            Ok(Some(event)) => alerter.check(&event, || crate::query::ident_labels(conn, &event.ident).unwrap_or_else(|e| {
+                println!("{}: failed to look up labels for an alert: {:?}", event.ident, e);
+                BTreeMap::new()
+            })),
+            Ok(None) => (),
+            Err(e) => {
+                println!("couldn't read {} to alert on timeouts: {:?}", database, e);
+                db = None;
+            },
+        }
+    }
+}
+
+/// The timeout `change` inserted, as the message it stands for; `None` if it's some other row,
+/// or one that never turned up.
+#[cfg(feature = "sqlite")]
+fn timed_out(db: &rusqlite::Connection, change: &RowChange) -> rusqlite::Result<Option<Event>> {
+    use rusqlite::OptionalExtension;
+
+    let mut stmt = db.prepare_cached(&format!("
+        SELECT instime, ident, peer, srchost, srcport, dsthost, dstport, proto, close FROM {} WHERE rowid = ?;
+    ", change.table))?;
+    for _ in 0 .. READ_ATTEMPTS {
+        let row = stmt.query_row([change.rowid], |row| Ok((
+            row.get::<_, f64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?, row.get::<_, u16>(4)?, row.get::<_, String>(5)?, row.get::<_, u16>(6)?,
+            row.get::<_, u8>(7)?, row.get::<_, Option<u8>>(8)?,
+        ))).optional()?;
+        let Some((instime, ident, peer, srchost, srcport, dsthost, dstport, proto, close)) = row else {
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        };
+        let (Some(TMOUT_MARK), Ok(src), Ok(dst), Some(protocol)) = (close, srchost.parse(), dsthost.parse(), Protocol::from_iana_number(proto)) else {
+            return Ok(None);
+        };
+        let received = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(instime);
+        let state = State {
+            as_of: received,
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: src, port: srcport },
+                dst: Endpoint { addr: dst, port: dstport },
+                protocol,
+            },
+            rtt_micros: None,
+        };
+        return Ok(Some(Event {
+            ident: ident.into(),
+            peer: peer.into(),
+            received,
+            message: Message::Ended(state, Closed::TimedOut),
+        }));
+    }
+    Ok(None)
+}
+
+/// What `Callbacks` calls with each accepted message its rule matches.
+pub type Callback = dyn Fn(&Event) + Send + Sync;
+
+/// A callback with the rule it was registered against.
+type Registration = (Rule, Arc<Callback>);
+
+/// Rust callbacks registered against rules, for embedders of the collector to hear of
+/// accepted messages without a webhook. Each is called for every message its rule matches;
+/// unlike the webhook's, rule windows don't hold these off.
+///
+/// Calls happen in order on a dedicated thread, started with the first registration, so a
+/// slow callback never holds up ingest: once `BACKLOG` matches are waiting, further ones are
+/// dropped and counted.
+#[derive(Default)]
+pub struct Callbacks {
+    registered: RwLock<Arc<Vec<Registration>>>,
+    sender: OnceLock<mpsc::SyncSender<(Vec<Arc<Callback>>, Event)>>,
+    dropped: AtomicU64,
+}
+
+impl fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let rules: Vec<Rule> = self.registered.read().unwrap().iter().map(|(rule, _)| rule.clone()).collect();
+        f.debug_struct("Callbacks")
+            .field("rules", &rules)
+            .field("dropped", &self.dropped)
+            .finish()
+    }
+}
+
+impl Callbacks {
+    pub const BACKLOG: usize = 256;
+
+    /// Call `callback` with every accepted message `rule` matches from now on.
+    pub fn register(&self, rule: Rule, callback: Arc<Callback>) {
+        self.sender.get_or_init(|| {
+            let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+            thread::spawn(move || callback_thread(receiver));
+            sender
+        });
+        let mut registered = self.registered.write().unwrap();
+        let mut fresh = Vec::clone(&registered);
+        fresh.push((rule, callback));
+        *registered = Arc::new(fresh);
+    }
+
+    /// Whether there's anything to call, so that ingest needn't build an `Event` for nothing.
+    pub fn is_empty(&self) -> bool {
+        self.registered.read().unwrap().is_empty()
+    }
+
+    /// Queue `event` for every callback whose rule it matches.
+    pub fn check(&self, event: &Event) {
+        let registered = self.registered.read().unwrap().clone();
+        let matched: Vec<Arc<Callback>> = registered.iter()
+            .filter(|(rule, _)| rule.matches(&event.ident, &event.message))
+            .map(|(_, callback)| callback.clone())
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        let queued = self.sender.get().is_some_and(|sender| sender.try_send((matched, event.clone())).is_ok());
+        if !queued {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of matches dropped because the callbacks fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn callback_thread(receiver: mpsc::Receiver<(Vec<Arc<Callback>>, Event)>) {
+    while let Ok((callbacks, event)) = receiver.recv() {
+        for callback in callbacks {
+            // One callback panicking shouldn't take the rest down with it
+            if panic::catch_unwind(AssertUnwindSafe(|| callback(&event))).is_err() {
+                println!("alert callback panicked on a message from {}", event.ident);
+            }
+        }
+    }
+}
+
+fn webhook_thread(url: Arc<Mutex<String>>, receiver: mpsc::Receiver<Vec<u8>>) {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Alerter::TIMEOUT)
+        .build();
+    while let Ok(payload) = receiver.recv() {
+        let url = url.lock().unwrap().clone();
+        if let Err(e) = agent.post(&url)
+            .set("Content-Type", "application/json")
+            .send_bytes(&payload)
+        {
+            println!("webhook error: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::mpsc};
+
+    use crate::observe::{Connection, Problem, Protocol, State};
+
+    use super::*;
+
+    fn state(dst: &str) -> State {
+        let dst: SocketAddr = dst.parse().unwrap();
+        State {
+            as_of: SystemTime::UNIX_EPOCH,
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: "10.0.0.1".parse().unwrap(), port: 40000 },
+                dst: Endpoint { addr: dst.ip(), port: dst.port() },
+                protocol: Protocol::Tcp,
+            },
+            rtt_micros: None,
+        }
+    }
+
+    fn failed(dst: &str) -> Message {
+        Message::Failed(state(dst), Problem { kind: 3, code: 1, repeats: 0 })
+    }
+
+    fn event(ident: &str, message: Message) -> Event {
+        Event { ident: Arc::from(ident), peer: Arc::from("127.0.0.1:40000"), received: SystemTime::UNIX_EPOCH, message }
+    }
+
+    #[test]
+    fn terms_parse_to_what_the_builder_makes() {
+        let parsed: Rule = "kind=failed,kind=scan,ident=db-*,dst=10.4.0.0/16,port=5432,port=5433,window=60".parse().unwrap();
+        let built = Rule::new([Kind::Failed, Kind::Scan])
+            .ident("db-*")
+            .dst("10.4.0.0/16".parse().unwrap())
+            .port(5432)
+            .port(5433)
+            .with_window(Duration::from_secs(60));
+        assert_eq!(parsed, built);
+        assert_eq!(parsed.to_string().parse::<Rule>().unwrap(), parsed);
+    }
+
+    #[test]
+    fn kind_defaults_to_failed() {
+        let rule: Rule = "port=22".parse().unwrap();
+        assert_eq!(rule, Rule::new([Kind::Failed]).port(22));
+        assert_eq!(rule.window(), Rule::WINDOW);
+    }
+
+    #[test]
+    fn bad_terms_are_refused() {
+        for bad in ["kind=exploded", "port=http", "port=70000", "dst=10.0.0.0/33", "window=soon", "colour=red", "kind"] {
+            assert!(bad.parse::<Rule>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn every_term_has_to_match() {
+        let rule = Rule::new([Kind::Failed]).ident("db-*").dst("10.4.0.0/16".parse().unwrap()).port(5432);
+        assert!(rule.matches("db-1", &failed("10.4.1.1:5432")));
+        assert!(!rule.matches("web-1", &failed("10.4.1.1:5432")));
+        assert!(!rule.matches("db-1", &failed("10.5.1.1:5432")));
+        assert!(!rule.matches("db-1", &failed("10.4.1.1:5433")));
+        assert!(!rule.matches("db-1", &Message::Starting(state("10.4.1.1:5432"))));
+    }
+
+    #[test]
+    fn resets_are_a_kind_of_their_own() {
+        let reset = Message::Ended(state("10.0.0.2:443"), Closed::Reset);
+        let ended = Message::Ended(state("10.0.0.2:443"), Closed::Normally);
+        assert!(Rule::new([Kind::Reset]).matches("any", &reset));
+        assert!(!Rule::new([Kind::Reset]).matches("any", &ended));
+        assert!(Rule::new([Kind::Ended]).matches("any", &ended));
+        assert!(!Rule::new([Kind::Ended]).matches("any", &reset));
+    }
+
+    #[test]
+    fn callbacks_hear_of_what_their_rule_matches() {
+        let callbacks = Callbacks::default();
+        assert!(callbacks.is_empty());
+        let (sender, heard) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        callbacks.register(Rule::new([Kind::Failed]).port(5432), Arc::new(move |event: &Event| {
+            sender.lock().unwrap().send(event.message.clone()).unwrap();
+        }));
+        assert!(!callbacks.is_empty());
+
+        callbacks.check(&event("db-1", failed("10.0.0.2:22")));
+        callbacks.check(&event("db-1", failed("10.0.0.2:5432")));
+        assert_eq!(heard.recv_timeout(Duration::from_secs(5)).unwrap(), failed("10.0.0.2:5432"));
+        assert!(heard.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(callbacks.dropped(), 0);
+    }
+
+    #[test]
+    fn a_panicking_callback_leaves_the_others_be() {
+        let callbacks = Callbacks::default();
+        callbacks.register(Rule::new([Kind::Failed]), Arc::new(|_: &Event| panic!("callback failed")));
+        let (sender, heard) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        callbacks.register(Rule::new([Kind::Failed]), Arc::new(move |event: &Event| {
+            sender.lock().unwrap().send(event.ident.to_string()).unwrap();
+        }));
+        callbacks.check(&event("first", failed("10.0.0.2:22")));
+        callbacks.check(&event("second", failed("10.0.0.2:22")));
+        assert_eq!(heard.recv_timeout(Duration::from_secs(5)).unwrap(), "first");
+        assert_eq!(heard.recv_timeout(Duration::from_secs(5)).unwrap(), "second");
+    }
+}
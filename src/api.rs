@@ -0,0 +1,405 @@
+use std::{collections::HashMap, io::{self, Read}, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{db, http::{self, content_type, param, parse_query, split_url}, metrics::{Exposition, Family, Sample, Type}, observe::{Message, Protocol}, filter::Glob, query::{self, ActiveFilter, NameFilter, SessionFilter}, sync::Hello, timefmt};
+#[cfg(feature = "mesh")]
+use crate::mesh::Mesh;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// Longest a `/v1/mesh` probe waits for answers, holding up the API as it does.
+#[cfg(feature = "mesh")]
+const MAX_PROBE: f64 = 10.0;
+
+const DROP_RATE: Family = Family {
+    name: "glosco_sensor_drop_rate",
+    help: "Share of packets a sensor's interfaces dropped rather than captured, as it last reported.",
+    kind: Type::Gauge,
+};
+const LATENCY_P95: Family = Family {
+    name: "glosco_sensor_latency_p95_seconds",
+    help: "95th percentile of how long a sensor's recent messages took from timestamp to arrival.",
+    kind: Type::Gauge,
+};
+const EARLY: Family = Family {
+    name: "glosco_sensor_early_messages_total",
+    help: "Messages a sensor timestamped after they arrived, left out of its latency.",
+    kind: Type::Counter,
+};
+
+/// When the maintenance thread last finished a tick, for `/healthz`.
+#[derive(Debug)]
+pub struct Heartbeat {
+    /// The last tick, and the period it expected to tick at.
+    last: Mutex<(Instant, Duration)>,
+}
+
+impl Heartbeat {
+    /// How many periods may pass without a tick before maintenance counts as stuck.
+    pub const MISSED: u32 = 3;
+
+    /// Start counting from now, as though a tick had just happened.
+    pub fn new(period: Duration) -> Self {
+        Self {
+            last: Mutex::new((Instant::now(), period)),
+        }
+    }
+
+    pub fn beat(&self, period: Duration) {
+        *self.last.lock().unwrap() = (Instant::now(), period);
+    }
+
+    /// How long since the last tick, if that's longer than it should have been.
+    pub fn overdue(&self) -> Option<Duration> {
+        let (last, period) = *self.last.lock().unwrap();
+        let elapsed = last.elapsed();
+        (elapsed > period * Self::MISSED).then_some(elapsed)
+    }
+}
+
+/// Stores a request's messages, given the ident they're reported under and who sent them.
+type Store = dyn Fn(&str, SocketAddr, Vec<Message>) + Send + Sync;
+
+/// Takes messages posted to `/v1/ingest`, for senders that can't speak the client protocol.
+///
+/// Each request's body is newline-delimited JSON, one `Message` per line in its serde form,
+/// all reported under the one ident the request names. Every line has to parse before any of
+/// them is stored. Each source address has a budget of `rate` messages a second, which a
+/// request may overdraw; once a source is overdrawn its requests are refused until the budget
+/// has caught up again.
+#[derive(Clone)]
+pub struct Ingest {
+    store: Arc<Store>,
+    max_bytes: usize,
+    rate: f64,
+    /// Each source's remaining budget, as of when it was last charged.
+    budgets: Arc<Mutex<HashMap<IpAddr, (f64, Instant)>>>,
+}
+
+impl std::fmt::Debug for Ingest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ingest")
+            .field("max_bytes", &self.max_bytes)
+            .field("rate", &self.rate)
+            .finish()
+    }
+}
+
+impl Ingest {
+    pub const DEFAULT_MAX_BYTES: usize = 1 << 20;
+    pub const DEFAULT_RATE: f64 = 1000.0;
+
+    /// Hand each request's messages to `store`, along with the ident and the sender's address.
+    pub fn new<F: Fn(&str, SocketAddr, Vec<Message>) + Send + Sync + 'static>(store: F) -> Self {
+        Self {
+            store: Arc::new(store),
+            max_bytes: Self::DEFAULT_MAX_BYTES,
+            rate: Self::DEFAULT_RATE,
+            budgets: Arc::default(),
+        }
+    }
+
+    /// Refuse request bodies longer than this.
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+    }
+
+    /// Messages a second each source address may send.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+
+    /// Charge `source` for `messages`, or say how long until it may send again.
+    fn charge(&self, source: IpAddr, messages: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut budgets = self.budgets.lock().unwrap();
+        // A second's worth is as much as a quiet source saves up, so forget any that have
+        budgets.retain(|_, (budget, as_of)| *budget + now.duration_since(*as_of).as_secs_f64() * self.rate < self.rate);
+        let (budget, as_of) = budgets.entry(source).or_insert((self.rate, now));
+        *budget = (*budget + now.duration_since(*as_of).as_secs_f64() * self.rate).min(self.rate);
+        *as_of = now;
+        if *budget <= 0.0 {
+            return Err(Duration::from_secs_f64(-*budget / self.rate));
+        }
+        *budget -= messages as f64;
+        Ok(())
+    }
+}
+
+/// The HTTP API and dashboard served alongside the collector.
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    bind: SocketAddr,
+    database: String,
+    token: Option<String>,
+    heartbeat: Option<Arc<Heartbeat>>,
+    ingest: Option<Ingest>,
+    #[cfg(feature = "mesh")]
+    mesh: Option<Arc<Mesh>>,
+}
+
+impl ApiConfig {
+    pub const DEFAULT_LIMIT: usize = 1000;
+
+    pub fn new(bind: SocketAddr, database: String) -> Self {
+        Self {
+            bind,
+            database,
+            token: None,
+            heartbeat: None,
+            ingest: None,
+            #[cfg(feature = "mesh")]
+            mesh: None,
+        }
+    }
+
+    /// Require `Authorization: Bearer <token>` on the JSON endpoints.
+    pub fn set_token(&mut self, token: String) {
+        self.token = Some(token);
+    }
+
+    /// Have `/healthz` also require that maintenance is ticking.
+    pub fn set_heartbeat(&mut self, heartbeat: Arc<Heartbeat>) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    /// Serve `POST /v1/ingest`, behind the same token as the JSON endpoints.
+    pub fn set_ingest(&mut self, ingest: Ingest) {
+        self.ingest = Some(ingest);
+    }
+
+    /// Serve `GET /v1/mesh`: the mesh this collector joined, its peers, and with
+    /// `?probe=SECS` what a probe found reachable in that long.
+    #[cfg(feature = "mesh")]
+    pub fn set_mesh(&mut self, mesh: Arc<Mesh>) {
+        self.mesh = Some(mesh);
+    }
+
+    pub fn start(self) -> io::Result<()> {
+        let db = db::open(&self.database).map_err(io::Error::other)?;
+        http::serve(self.bind, "API", move |request| self.handle(&db, request))
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        request.headers().iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            .map(|given| given == token)
+            .unwrap_or(false)
+    }
+
+    fn handle(&self, db: &rusqlite::Connection, request: Request) -> io::Result<()> {
+        let (path, query) = split_url(request.url());
+        let params = parse_query(&query);
+        if path == "/v1/ingest" && self.ingest.is_some() {
+            return self.ingest(request, &params);
+        }
+        if *request.method() != Method::Get {
+            return request.respond(Response::from_string("method not allowed\n").with_status_code(405));
+        }
+        if path == "/" {
+            return request.respond(Response::from_string(DASHBOARD).with_header(content_type("text/html; charset=utf-8")));
+        }
+        if path == "/healthz" {
+            return match self.health(db) {
+                Ok(()) => request.respond(Response::from_string("ok\n")),
+                Err(reason) => request.respond(Response::from_string(format!("{}\n", reason)).with_status_code(503)),
+            };
+        }
+        if !self.authorized(&request) {
+            return request.respond(Response::from_string("unauthorized\n").with_status_code(401));
+        }
+        if path == "/metrics" {
+            return match Self::metrics(db) {
+                Ok(text) => request.respond(Response::from_string(text).with_header(content_type("text/plain; version=0.0.4"))),
+                Err(e) => {
+                    println!("API query error: {:?}", e);
+                    request.respond(Response::from_string("query failed\n").with_status_code(500))
+                },
+            };
+        }
+        let limit = param(&params, "limit")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(Self::DEFAULT_LIMIT);
+        let result = match path.as_str() {
+            #[cfg(feature = "mesh")]
+            "/v1/mesh" if self.mesh.is_some() => {
+                let probe = param(&params, "probe")
+                    .and_then(|p| p.parse().ok())
+                    .map(|secs: f64| Duration::from_secs_f64(secs.clamp(0.0, MAX_PROBE)));
+                Ok(self.mesh.as_ref().expect("mesh is joined").status(probe))
+            },
+            "/v1/sensors" => json(query::sensors(db)),
+            "/v1/clients" => {
+                let pairs = |name: &str| params.iter()
+                    .filter(|(key, _)| key == name)
+                    .map(|(_, pair)| Hello::parse_tag(pair))
+                    .collect::<Result<Vec<_>, _>>();
+                match (pairs("tag"), pairs("label")) {
+                    (Ok(tags), Ok(labels)) => json(query::clients(db, &tags, &labels)),
+                    (Err(e), _) | (_, Err(e)) => return request.respond(Response::from_string(format!("{}\n", e)).with_status_code(400)),
+                }
+            },
+            "/v1/active" => {
+                let filter = ActiveFilter {
+                    ident: param(&params, "ident").map(str::to_string),
+                    host: param(&params, "host").map(str::to_string),
+                    port: param(&params, "port").and_then(|p| p.parse().ok()),
+                };
+                json(query::active(db, &filter, limit))
+            },
+            "/v1/failures" => json(query::failures(db, limit)),
+            "/v1/sessions" => {
+                let until = param(&params, "until").and_then(|u| u.parse().ok()).unwrap_or_else(query::now_secs);
+                let hours: f64 = param(&params, "hours").and_then(|h| h.parse().ok()).unwrap_or(24.0);
+                let filter = SessionFilter {
+                    ident: param(&params, "ident").map(str::to_string),
+                    host: match param(&params, "host").map(str::parse).transpose() {
+                        Ok(host) => host,
+                        Err(e) => return request.respond(Response::from_string(format!("{}\n", e)).with_status_code(400)),
+                    },
+                    port: param(&params, "port").and_then(|p| p.parse().ok()),
+                    proto: match param(&params, "proto") {
+                        Some("tcp") => Some(Protocol::Tcp.iana_number()),
+                        Some("udp") => Some(Protocol::Udp.iana_number()),
+                        _ => None,
+                    },
+                    since: param(&params, "since").and_then(|s| s.parse().ok()).unwrap_or(until - hours * 3600.0),
+                    until,
+                };
+                json(query::sessions(db, &filter, limit))
+            },
+            "/v1/names" => {
+                let Some(pattern) = param(&params, "pattern") else {
+                    return request.respond(Response::from_string("no pattern given\n").with_status_code(400));
+                };
+                let until = param(&params, "until").and_then(|u| u.parse().ok()).unwrap_or_else(query::now_secs);
+                let hours: f64 = param(&params, "hours").and_then(|h| h.parse().ok()).unwrap_or(24.0);
+                let filter = NameFilter {
+                    pattern: Glob(pattern.to_string()),
+                    since: param(&params, "since").and_then(|s| s.parse().ok()).unwrap_or(until - hours * 3600.0),
+                    until,
+                    window: param(&params, "window").and_then(|w| w.parse().ok()).unwrap_or(query::NAME_WINDOW),
+                };
+                json(query::names(db, &filter, limit))
+            },
+            "/v1/summary" => {
+                let hours = param(&params, "hours").and_then(|h| h.parse().ok()).unwrap_or(24);
+                json(query::summary(db, param(&params, "ident"), hours))
+            },
+            _ => return request.respond(Response::from_string("not found\n").with_status_code(404)),
+        };
+        // Name connections' ends, where they're connections, before times are made readable
+        let resolving = param(&params, "resolve") == Some("1") && matches!(path.as_str(), "/v1/active" | "/v1/sessions" | "/v1/failures");
+        let result = result.and_then(|mut rows| {
+            if let (true, Some(rows)) = (resolving, rows.as_array_mut()) {
+                let ttl = param(&params, "window").and_then(|w| w.parse().ok()).unwrap_or(query::NAME_WINDOW);
+                query::resolve(std::slice::from_ref(db), rows, ttl)?;
+            }
+            Ok(rows)
+        });
+        // Times stay numeric unless asked for, since that's what the dashboard expects
+        let result = result.map(|mut rows| {
+            if param(&params, "times") == Some("rfc3339") {
+                timefmt::readable(&mut rows);
+            }
+            rows
+        });
+        match result {
+            Ok(body) => request.respond(Response::from_string(body.to_string()).with_header(content_type("application/json"))),
+            Err(e) => {
+                println!("API query error: {:?}", e);
+                request.respond(Response::from_string("query failed\n").with_status_code(500))
+            },
+        }
+    }
+}
+
+impl ApiConfig {
+    /// What `/metrics` reports: each sensor's drop rate, for those that have said how capture
+    /// is going, and how late its messages arrive, for those that maintenance has seen report.
+    fn metrics(db: &rusqlite::Connection) -> rusqlite::Result<String> {
+        let clients = query::clients(db, &[], &[])?;
+        let mut out = Exposition::default();
+        out.family(&DROP_RATE, clients.iter()
+            .filter_map(|client| client.drop_rate.map(|rate| Sample::new(rate).label("ident", &client.ident))));
+        out.family(&LATENCY_P95, clients.iter()
+            .filter_map(|client| client.latency_p95.map(|latency| Sample::new(latency).label("ident", &client.ident))));
+        out.family(&EARLY, clients.iter()
+            .filter_map(|client| client.early.map(|early| Sample::new(early as f64).label("ident", &client.ident))));
+        Ok(out.into_text())
+    }
+
+    /// Validate and store one `/v1/ingest` request. The ident comes from an `X-Glosco-Ident`
+    /// header or failing that an `ident` parameter.
+    fn ingest(&self, mut request: Request, params: &[(String, String)]) -> io::Result<()> {
+        let ingest = self.ingest.as_ref().expect("ingest is enabled");
+        if *request.method() != Method::Post {
+            return request.respond(Response::from_string("method not allowed\n").with_status_code(405));
+        }
+        if !self.authorized(&request) {
+            return request.respond(Response::from_string("unauthorized\n").with_status_code(401));
+        }
+        let ident = request.headers().iter()
+            .find(|h| h.field.equiv("X-Glosco-Ident"))
+            .map(|h| h.value.as_str().to_string())
+            .or_else(|| param(params, "ident").map(str::to_string));
+        let Some(ident) = ident.filter(|ident| !ident.is_empty()) else {
+            return request.respond(Response::from_string("no ident given\n").with_status_code(400));
+        };
+        let Some(&peer) = request.remote_addr() else {
+            return request.respond(Response::from_string("no peer address\n").with_status_code(400));
+        };
+        let too_large = || Response::from_string(format!("body is over {} bytes\n", ingest.max_bytes)).with_status_code(413);
+        if request.body_length().is_some_and(|length| length > ingest.max_bytes) {
+            return request.respond(too_large());
+        }
+        let mut body = Vec::new();
+        request.as_reader().take(ingest.max_bytes as u64 + 1).read_to_end(&mut body)?;
+        if body.len() > ingest.max_bytes {
+            return request.respond(too_large());
+        }
+        let Ok(body) = String::from_utf8(body) else {
+            return request.respond(Response::from_string("body isn't UTF-8\n").with_status_code(400));
+        };
+        let mut messages = Vec::new();
+        for (idx, line) in body.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Message>(line) {
+                Ok(message) => messages.push(message),
+                Err(e) => return request.respond(Response::from_string(format!("line {}: {}\n", idx + 1, e)).with_status_code(400)),
+            }
+        }
+        if let Err(wait) = ingest.charge(peer.ip(), messages.len()) {
+            let retry = Header::from_bytes(&b"Retry-After"[..], format!("{}", (wait.as_secs_f64().ceil() as u64).max(1)).as_bytes()).expect("bad header");
+            return request.respond(Response::from_string("rate limited\n").with_status_code(429).with_header(retry));
+        }
+        let received = messages.len();
+        println!("API: {} messages for {} from {:?}", received, ident, peer);
+        (ingest.store)(&ident, peer, messages);
+        let body = serde_json::json!({ "received": received });
+        request.respond(Response::from_string(body.to_string()).with_header(content_type("application/json")))
+    }
+
+    /// Whether the database takes writes and maintenance is keeping up; unauthenticated, so it
+    /// says no more than what's wrong.
+    fn health(&self, db: &rusqlite::Connection) -> Result<(), String> {
+        // Taking the write lock and letting go is enough to show a writer could get in
+        db.execute_batch("BEGIN IMMEDIATE; ROLLBACK;")
+            .map_err(|e| format!("database not writable: {}", e))?;
+        if let Some(overdue) = self.heartbeat.as_ref().and_then(|heartbeat| heartbeat.overdue()) {
+            return Err(format!("maintenance last ticked {:.0}s ago", overdue.as_secs_f64()));
+        }
+        Ok(())
+    }
+}
+
+fn json<T: Serialize>(rows: rusqlite::Result<T>) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    Ok(serde_json::to_value(rows?)?)
+}
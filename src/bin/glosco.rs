@@ -0,0 +1,22 @@
+use glosco::cli::{Cli, Command};
+
+fn main() {
+    match Cli::parse_compat().command {
+        Command::Client(args) => glosco::client::run(*args),
+        Command::Tail(args) => glosco::tail::run(args),
+        #[cfg(feature = "sqlite")]
+        Command::Server(args) => glosco::server::serve(*args),
+        #[cfg(feature = "sqlite")]
+        Command::Query(args) => glosco::query::run(args),
+        #[cfg(feature = "sqlite")]
+        Command::Healthcheck(args) => glosco::health::run(args),
+        #[cfg(feature = "sqlite")]
+        Command::Import(args) => glosco::import::run(args),
+        #[cfg(feature = "sqlite")]
+        Command::Merge(args) => glosco::merge::run(args),
+        #[cfg(feature = "sqlite")]
+        Command::Admin(args) => glosco::admin::run(args),
+        #[cfg(feature = "mesh")]
+        Command::MeshStatus(args) => glosco::mesh::run(args),
+    }
+}
@@ -0,0 +1,300 @@
+//! Several observers, each with a configuration of its own, read as one.
+
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime}};
+
+use crate::observe::{Batch, Connection, InterfaceInfo, InterfaceStats, Message, Observer, Snapshot};
+
+/// The messages of several observers, merged into one feed as if a single observer had seen
+/// every interface. Interfaces are numbered in one namespace: the first member's as it has
+/// them, the second's after those, and so on.
+///
+/// Members' snapshots each cover only their own connections, which a collector would take to
+/// mean the rest had closed, so they're dropped; the bus takes its own of everything instead.
+#[derive(Debug)]
+pub struct MessageBus {
+    feed: mpsc::Receiver<Vec<Message>>,
+    interfaces: Vec<InterfaceInfo>,
+    stats: Vec<Arc<[InterfaceStats]>>,
+    states: HashMap<Connection, Message>,
+    snapshot_every: Option<Duration>,
+    last_snapshot: Instant,
+    stop: Arc<AtomicBool>,
+    _threads: Vec<JoinHandle<()>>,
+}
+
+impl MessageBus {
+    /// How often a member with nothing to pass on checks whether the bus has stopped.
+    const POLL: Duration = Duration::from_millis(200);
+
+    /// Read `members` together; each goes on running in a thread of its own until the bus is
+    /// dropped or its packets run out.
+    pub fn new(members: Vec<Observer>) -> Self {
+        let (endpoint, feed) = mpsc::channel();
+        let stop: Arc<AtomicBool> = Arc::default();
+        let mut interfaces = Vec::new();
+        let mut stats = Vec::new();
+        let threads = members.into_iter().map(|observer| {
+            let offset = interfaces.len();
+            interfaces.extend(observer.interfaces());
+            stats.extend(observer.stats());
+            let endpoint = endpoint.clone();
+            let stop = stop.clone();
+            thread::spawn(move || pump(observer, offset, endpoint, stop))
+        }).collect();
+        Self {
+            feed, interfaces, stats,
+            states: Default::default(),
+            snapshot_every: None,
+            last_snapshot: Instant::now(),
+            stop,
+            _threads: threads,
+        }
+    }
+
+    /// Have `next_batch` produce a snapshot of every open connection, across all members, this
+    /// often.
+    pub fn set_snapshot_interval(&mut self, every: Duration) {
+        self.snapshot_every = Some(every);
+    }
+
+    /// Every member's interfaces, in the combined numbering.
+    pub fn namespace(&self) -> Vec<String> {
+        self.interfaces.iter().map(|interface| interface.name.clone()).collect()
+    }
+
+    /// Every member's interfaces as pcap knew them, in the combined numbering, for the
+    /// `Namespace` a client announces.
+    pub fn interfaces(&self) -> Vec<InterfaceInfo> {
+        self.interfaces.clone()
+    }
+
+    /// The counts of each member that was asked to keep them, in member order.
+    pub fn stats(&self) -> Vec<Arc<[InterfaceStats]>> {
+        self.stats.clone()
+    }
+
+    /// Stop every member, within `POLL` or so; the feed runs out once they have.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// The latest Starting or Active message of every connection that's open, as far as any
+    /// member has seen.
+    pub fn current_states(&self) -> Vec<Message> {
+        self.states.values()
+            .filter(|message| matches!(message, Message::Starting(_) | Message::Active(_)))
+            .cloned()
+            .collect()
+    }
+
+    /// The next messages from any member, or a snapshot if one is due; `None` once every
+    /// member's packets run out.
+    pub fn next_batch(&mut self) -> Option<Batch> {
+        self.next_batch_by(None).ok()
+    }
+
+    /// Like `next_batch`, but giving up with `Timeout` if there's nothing by `wait` from now,
+    /// and reporting every member running out as `Disconnected`.
+    pub fn next_batch_timeout(&mut self, wait: Duration) -> Result<Batch, mpsc::RecvTimeoutError> {
+        self.next_batch_by(Some(Instant::now() + wait))
+    }
+
+    fn next_batch_by(&mut self, deadline: Option<Instant>) -> Result<Batch, mpsc::RecvTimeoutError> {
+        loop {
+            let due = self.snapshot_every.map(|every| self.last_snapshot + every);
+            if due.is_some_and(|due| Instant::now() >= due) {
+                self.last_snapshot = Instant::now();
+                return Ok(Batch::Snapshot(Snapshot {
+                    as_of: SystemTime::now(),
+                    states: self.current_states(),
+                }));
+            }
+            let until = match (due, deadline) {
+                (Some(due), Some(deadline)) => Some(due.min(deadline)),
+                (due, deadline) => due.or(deadline),
+            };
+            let messages = match until {
+                Some(until) => match self.feed.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Ok(messages) => messages,
+                    Err(mpsc::RecvTimeoutError::Timeout) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                        return Err(mpsc::RecvTimeoutError::Timeout);
+                    },
+                    // A snapshot's due
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Err(mpsc::RecvTimeoutError::Disconnected),
+                },
+                None => self.feed.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)?,
+            };
+            return Ok(Batch::Messages(self.track(messages)));
+        }
+    }
+
+    /// Remember where each connection stands, for snapshots and `current_states`.
+    fn track(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        for message in messages.iter() {
+            self.states.insert(message.state().connection, message.clone());
+        }
+        messages
+    }
+}
+
+impl Drop for MessageBus {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Iterator for MessageBus {
+    type Item = Vec<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let messages = self.feed.recv().ok()?;
+        Some(self.track(messages))
+    }
+}
+
+/// Pass on what `observer` sees, renumbered to start its interfaces at `offset`, until the bus
+/// stops or goes away.
+fn pump(mut observer: Observer, offset: usize, endpoint: mpsc::Sender<Vec<Message>>, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::SeqCst) {
+        match observer.next_batch_timeout(MessageBus::POLL) {
+            Ok(Batch::Messages(mut messages)) => {
+                for message in messages.iter_mut() {
+                    message.state_mut().connection.interface += offset;
+                }
+                if endpoint.send(messages).is_err() {
+                    return;
+                }
+            },
+            // The bus takes its own
+            Ok(Batch::Snapshot(_)) => (),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, fs::{File, OpenOptions}, io::Write, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+
+    use super::*;
+    use crate::{observe::{Namespace, ObserverConfig}, test_support::{pcap_header, pcap_record, tcp_frame, write_pcap, Scratch, SYN}};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// A capture in `scratch` of one SYN from each of `sources` to 10.0.0.2:443.
+    fn capture(scratch: &Scratch, name: &str, sources: &[&str]) -> PathBuf {
+        let path = scratch.path().join(name);
+        let frames: Vec<_> = sources.iter().map(|src| (SystemTime::now(), tcp_frame(src, "10.0.0.2:443", SYN))).collect();
+        write_pcap(&path, &frames).unwrap();
+        path
+    }
+
+    /// Like `capture`, but a capture still being written, so its observer goes on waiting for
+    /// more until the writer returned is dropped. The writer's ready once the observer has
+    /// opened the capture.
+    fn open_capture(scratch: &Scratch, name: &str, sources: &[&str]) -> (PathBuf, JoinHandle<File>) {
+        let path = scratch.path().join(name);
+        let fifo = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0, "failed to create fifo");
+        let frames: Vec<Vec<u8>> = sources.iter().map(|src| pcap_record(SystemTime::now(), &tcp_frame(src, "10.0.0.2:443", SYN))).collect();
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut writer = OpenOptions::new().write(true).open(path).unwrap();
+                writer.write_all(&pcap_header()).unwrap();
+                for frame in frames {
+                    writer.write_all(&frame).unwrap();
+                }
+                writer.flush().unwrap();
+                writer
+            })
+        };
+        (path, writer)
+    }
+
+    fn observer(files: &[&Path]) -> Observer {
+        let mut config = ObserverConfig::default();
+        for file in files {
+            config.add_file(file.to_path_buf());
+        }
+        config.keep_stats();
+        config.start().unwrap()
+    }
+
+    /// Source port and interface of each connection in `messages`, in order of port.
+    fn interfaces(messages: &[Message]) -> Vec<(u16, usize)> {
+        let mut interfaces: Vec<_> = messages.iter()
+            .map(|message| (message.state().connection.src.port, message.state().connection.interface))
+            .collect();
+        interfaces.sort();
+        interfaces
+    }
+
+    #[test]
+    fn members_interfaces_are_numbered_one_after_another() {
+        let scratch = Scratch::dir("numbered");
+        let uplink = capture(&scratch, "uplink.pcap", &["10.0.0.1:40000"]);
+        let mgmt = capture(&scratch, "mgmt.pcap", &["10.0.0.1:40001", "10.0.0.1:40002"]);
+        let other = capture(&scratch, "other.pcap", &["10.0.0.3:50000"]);
+        let mut bus = MessageBus::new(vec![observer(&[&uplink, &mgmt]), observer(&[&other])]);
+
+        let names: Vec<String> = [&uplink, &mgmt, &other].iter().map(|path| path.to_string_lossy().into_owned()).collect();
+        assert_eq!(bus.namespace(), names);
+        // What a client would announce, in the same numbering as its messages
+        let announced = Namespace { generation: 0, as_of: SystemTime::now(), interfaces: bus.interfaces() };
+        assert_eq!(announced.interfaces.iter().map(|interface| interface.name.clone()).collect::<Vec<_>>(), names);
+        let stats = bus.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats.iter().flat_map(|stats| stats.iter().map(|stats| stats.name.clone())).collect::<Vec<_>>(), names);
+
+        // Both members run out, and so does the bus
+        let messages: Vec<Message> = bus.by_ref().flatten().collect();
+        assert!(messages.iter().all(|message| matches!(message, Message::Starting(_))), "{:?}", messages);
+        assert_eq!(interfaces(&messages), [(40000, 0), (40001, 1), (40002, 1), (50000, 2)]);
+        assert_eq!(interfaces(&bus.current_states()), [(40000, 0), (40001, 1), (40002, 1), (50000, 2)]);
+        assert_eq!(stats[0][1].packets.load(Ordering::Relaxed), 2);
+        assert_eq!(stats[1][0].packets.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_snapshot_covers_every_member() {
+        let scratch = Scratch::dir("snapshot");
+        let first = capture(&scratch, "first.pcap", &["10.0.0.1:40000"]);
+        // Still open, or the bus would run out before a snapshot was due
+        let (second, writer) = open_capture(&scratch, "second.pcap", &["10.0.0.3:50000"]);
+        let mut bus = MessageBus::new(vec![observer(&[&first]), observer(&[&second])]);
+        let writer = writer.join().unwrap();
+        bus.set_snapshot_interval(Duration::from_millis(100));
+
+        let deadline = Instant::now() + WAIT;
+        let snapshot = loop {
+            assert!(Instant::now() < deadline, "no snapshot of both members");
+            match bus.next_batch_timeout(WAIT) {
+                Ok(Batch::Snapshot(snapshot)) if snapshot.states.len() == 2 => break snapshot,
+                Ok(_) => (),
+                Err(e) => panic!("the bus ended early: {:?}", e),
+            }
+        };
+        assert_eq!(interfaces(&snapshot.states), [(40000, 0), (50000, 1)]);
+        drop(writer);
+    }
+
+    #[test]
+    fn stopping_the_bus_stops_every_member() {
+        let scratch = Scratch::dir("stop");
+        let done = capture(&scratch, "done.pcap", &["10.0.0.1:40000"]);
+        let (fifo, writer) = open_capture(&scratch, "open.pcap", &[]);
+        let mut bus = MessageBus::new(vec![observer(&[&done]), observer(&[&fifo])]);
+        let writer = writer.join().unwrap();
+
+        assert!(matches!(bus.next_batch_timeout(WAIT), Ok(Batch::Messages(_))));
+        assert!(matches!(bus.next_batch_timeout(MessageBus::POLL * 2), Err(mpsc::RecvTimeoutError::Timeout)));
+        bus.stop();
+        let stopped = Instant::now();
+        assert!(matches!(bus.next_batch_timeout(WAIT), Err(mpsc::RecvTimeoutError::Disconnected)));
+        assert!(stopped.elapsed() < WAIT);
+        drop(writer);
+    }
+}
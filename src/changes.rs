@@ -0,0 +1,177 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc, Mutex};
+
+use rusqlite::hooks::Action;
+
+/// What happened to a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// One row changed by a committed write on a hooked connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowChange {
+    pub table: String,
+    pub rowid: i64,
+    pub op: Op,
+}
+
+/// Fans out the row changes made through every connection it has hooked.
+///
+/// The hooks run on the writing thread in the middle of its statements, so all they do is note
+/// the change, and on commit hand it to each subscriber's bounded queue without waiting; a
+/// subscriber that can't keep up loses changes (counted in `dropped`) rather than slowing the
+/// writer down. Changes in a transaction that's rolled back are never sent. Views aren't
+/// reported, only the tables under them, and neither are `DROP TABLE`s or deletes SQLite
+/// optimizes into truncating a table.
+#[derive(Default)]
+pub struct Changes {
+    subscribers: Mutex<Vec<mpsc::SyncSender<RowChange>>>,
+    dropped: AtomicU64,
+}
+
+impl std::fmt::Debug for Changes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Changes")
+            .field("subscribers", &self.subscribers.lock().unwrap().len())
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl Changes {
+    pub const BACKLOG: usize = 4096;
+
+    /// Register a subscriber; it stays registered until the receiver is dropped.
+    pub fn subscribe(&self) -> mpsc::Receiver<RowChange> {
+        let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Report the changes `db` commits from now on.
+    pub fn hook(self: &Arc<Self>, db: &rusqlite::Connection) {
+        let pending: Arc<Mutex<Vec<RowChange>>> = Arc::default();
+        {
+            let changes = self.clone();
+            let pending = pending.clone();
+            db.update_hook(Some(move |action, _db: &str, table: &str, rowid| {
+                let op = match action {
+                    Action::SQLITE_INSERT => Op::Insert,
+                    Action::SQLITE_UPDATE => Op::Update,
+                    Action::SQLITE_DELETE => Op::Delete,
+                    _ => return,
+                };
+                if changes.subscribers() > 0 {
+                    pending.lock().unwrap().push(RowChange { table: table.to_string(), rowid, op });
+                }
+            }));
+        }
+        {
+            let changes = self.clone();
+            let pending = pending.clone();
+            db.commit_hook(Some(move || {
+                for change in pending.lock().unwrap().drain(..) {
+                    changes.publish(change);
+                }
+                false
+            }));
+        }
+        db.rollback_hook(Some(move || pending.lock().unwrap().clear()));
+    }
+
+    fn publish(&self, change: RowChange) {
+        self.subscribers.lock().unwrap().retain(|subscriber| match subscriber.try_send(change.clone()) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// Changes a subscriber missed because its queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hooked() -> (Arc<Changes>, rusqlite::Connection) {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute_batch("CREATE TABLE state (srcport); CREATE VIEW ports AS SELECT srcport FROM state;").unwrap();
+        let changes: Arc<Changes> = Arc::default();
+        changes.hook(&db);
+        (changes, db)
+    }
+
+    fn insert(db: &rusqlite::Connection, port: i64) -> i64 {
+        db.execute("INSERT INTO state (srcport) VALUES (?);", [port]).unwrap();
+        db.last_insert_rowid()
+    }
+
+    fn change(op: Op, rowid: i64) -> RowChange {
+        RowChange { table: "state".to_string(), rowid, op }
+    }
+
+    #[test]
+    fn a_committed_write_reaches_each_subscriber() {
+        let (changes, db) = hooked();
+        let (first, second) = (changes.subscribe(), changes.subscribe());
+        let rowid = insert(&db, 1);
+        db.execute("UPDATE state SET srcport = 2 WHERE rowid = ?;", [rowid]).unwrap();
+        db.execute("DELETE FROM state WHERE rowid = ?;", [rowid]).unwrap();
+        let expected = [change(Op::Insert, rowid), change(Op::Update, rowid), change(Op::Delete, rowid)];
+        for subscriber in [first, second] {
+            assert_eq!(subscriber.try_iter().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn changes_arrive_on_commit_and_never_from_a_rollback() {
+        let (changes, mut db) = hooked();
+        let subscriber = changes.subscribe();
+
+        let txn = db.transaction().unwrap();
+        let rolled_back = insert(&txn, 1);
+        txn.rollback().unwrap();
+        assert_eq!(subscriber.try_recv(), Err(mpsc::TryRecvError::Empty));
+
+        let txn = db.transaction().unwrap();
+        let first = insert(&txn, 2);
+        let second = insert(&txn, 3);
+        assert_eq!(subscriber.try_recv(), Err(mpsc::TryRecvError::Empty), "nothing before the commit");
+        txn.commit().unwrap();
+        assert_eq!(subscriber.try_iter().collect::<Vec<_>>(), [change(Op::Insert, first), change(Op::Insert, second)]);
+        assert_ne!(rolled_back, 0);
+    }
+
+    #[test]
+    fn a_slow_subscriber_loses_changes_without_holding_up_the_writer() {
+        let (changes, db) = hooked();
+        let slow = changes.subscribe();
+        let prompt = changes.subscribe();
+        for port in 0 .. Changes::BACKLOG as i64 + 10 {
+            let rowid = insert(&db, port);
+            assert_eq!(prompt.try_recv(), Ok(change(Op::Insert, rowid)));
+        }
+        assert_eq!(changes.dropped(), 10);
+        assert_eq!(slow.try_iter().count(), Changes::BACKLOG);
+
+        // Dropping a receiver unsubscribes it
+        drop(slow);
+        insert(&db, 0);
+        assert_eq!(changes.subscribers(), 1);
+        assert_eq!(changes.dropped(), 10);
+    }
+}
@@ -0,0 +1,1248 @@
+use std::ffi::OsString;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::{alert::{Kind, Rule}, client::ClientSettings, filter::{Cidr, Glob, NamePattern}, metrics::TagFormat, observe::{Filters, Recovery}, output::Output, ring::RingSettings, scan::ScanSettings, settings::{self, SettingsError}, sync::Hello};
+#[cfg(feature = "sqlite")]
+use crate::merge::Prefix;
+
+#[cfg(feature = "sqlite")]
+use crate::{forward::Target, report::{Format, Interval, Section}, server::{ServerSettings, CollisionPolicy, SkewPolicy, Granularity, BaselineSettings, EventLogSettings, RdnsSettings, AlertSettings, ApiSettings, ReportSettings}};
+
+#[derive(Debug, Parser)]
+#[command(author = "Grissess", version = "0.1",
+          about = "Track connection state globally across large networks",
+          long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Capture connection state on this host and send it to collectors
+    Client(Box<ClientArgs>),
+    /// Print the messages a collector accepts as they arrive
+    Tail(TailArgs),
+    /// Collect connection state from clients into a database
+    #[cfg(feature = "sqlite")]
+    Server(Box<ServerArgs>),
+    /// Print reports from a collector's database
+    #[cfg(feature = "sqlite")]
+    Query(QueryArgs),
+    /// Exit 0 if a collector's database has been written to recently, 1 if not
+    #[cfg(feature = "sqlite")]
+    Healthcheck(HealthcheckArgs),
+    /// Store the connections seen in a packet capture file as if a client had reported them
+    #[cfg(feature = "sqlite")]
+    Import(ImportArgs),
+    /// Copy the rows of other collectors' databases into one, skipping what's already there
+    #[cfg(feature = "sqlite")]
+    Merge(MergeArgs),
+    /// Change what a collector's database records about its sensors
+    #[cfg(feature = "sqlite")]
+    Admin(AdminArgs),
+    /// Join a sensor mesh briefly and print its peers and what a probe finds reachable
+    #[cfg(feature = "mesh")]
+    MeshStatus(MeshStatusArgs),
+}
+
+impl Cli {
+    /// Parse the process arguments, accepting the old `--mode client|server` spelling.
+    pub fn parse_compat() -> Self {
+        Self::parse_from(compat_args(std::env::args_os()))
+    }
+}
+
+/// Rewrite `--mode X` (or `--mode=X`) anywhere in `args` into a leading `X` subcommand.
+///
+/// Deprecated: this only exists so scripts written against the old flag keep working for one
+/// more release.
+pub fn compat_args<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    let mut mode = None;
+    let mut idx = 1;
+    while idx < args.len() {
+        let Some(arg) = args[idx].to_str() else {
+            idx += 1;
+            continue;
+        };
+        if arg == "--" {
+            break;
+        }
+        if arg == "--mode" && idx + 1 < args.len() {
+            mode = Some(args.remove(idx + 1));
+            args.remove(idx);
+        } else if let Some(value) = arg.strip_prefix("--mode=") {
+            mode = Some(value.into());
+            args.remove(idx);
+        } else {
+            idx += 1;
+        }
+    }
+    if let Some(mode) = mode {
+        eprintln!("warning: --mode is deprecated; use `glosco {}` instead", mode.to_string_lossy());
+        args.insert(1.min(args.len()), mode);
+    }
+    args
+}
+
+/// Arguments for running in the background, shared by the client and server.
+#[derive(Debug, Clone, clap::Args)]
+pub struct DaemonArgs {
+    /// Detach from the terminal and run in the background (Unix only)
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Write the process id here, refusing to start if it names a process that's still running;
+    /// it's removed on exit (Unix only)
+    #[arg(long)]
+    pub pidfile: Option<PathBuf>,
+
+    /// Append output here once detached, rather than discarding it
+    #[arg(long, requires = "daemon")]
+    pub log_file: Option<PathBuf>,
+}
+
+/// Arguments for `glosco client`, and the whole of `glosco_client`.
+#[derive(Debug, Clone, clap::Args)]
+pub struct ClientArgs {
+    /// TOML file of capture filters and output; flags given on the command line take
+    /// precedence over it
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Lay this [profile.<name>] section of the config file over the rest of it
+    #[arg(long, requires = "config")]
+    pub profile: Option<String>,
+
+    /// Interfaces, by name to use; if not provided, use all of them.
+    #[arg(short, long)]
+    pub interfaces: Option<Vec<String>>,
+
+    /// Read packets from this capture file instead of capturing live (repeatable; read in order)
+    #[arg(long, conflicts_with = "interfaces")]
+    pub pcap: Vec<PathBuf>,
+
+    /// Once the capture files run out, report what's still open as ended, wait for everything
+    /// to reach the collectors, and exit 0 only if it all did
+    #[arg(long, requires = "pcap", conflicts_with_all = ["tui", "daemon"])]
+    pub once: bool,
+
+    /// Seconds to wait for everything to reach the collectors when stopping, on SIGTERM or
+    /// SIGINT or at the end of --once, after reporting what's still open as ended
+    #[arg(long, default_value_t = 30.0)]
+    pub flush_timeout: f64,
+
+    /// Print the interfaces that can be captured on, with their flags and addresses, and exit
+    #[arg(long)]
+    pub list_interfaces: bool,
+
+    /// Show what's observed as a live table in the terminal rather than printing it, while
+    /// reporting as usual (needs the tui cargo feature)
+    #[arg(long, conflicts_with_all = ["output", "list_interfaces", "daemon"])]
+    pub tui: bool,
+
+    #[command(flatten)]
+    pub daemon: DaemonArgs,
+
+    /// Remote instances to which to connect
+    #[arg(short = 'R', long)]
+    pub remotes: Vec<String>,
+
+    /// Report to the collectors named by this name's SRV records, like _glosco._tcp.example.com,
+    /// falling back to --remotes while none can be found
+    #[arg(long)]
+    pub remotes_srv: Option<String>,
+
+    /// Complain loudly when a live capture has had no packets at all for this many seconds
+    /// while its interface is up
+    #[arg(long, value_parser = positive_secs)]
+    pub watchdog: Option<f64>,
+
+    /// What to do about a stalled capture besides complain: log, reopen the capture, or
+    /// reopen all of them
+    #[arg(long, value_enum, default_value_t, requires = "watchdog")]
+    pub watchdog_recovery: Recovery,
+
+    /// Serve Prometheus metrics on /metrics at this address
+    #[arg(long)]
+    pub metrics_bind: Option<SocketAddr>,
+
+    /// Send the same metrics over UDP to the statsd agent at this host:port
+    #[arg(long)]
+    pub statsd: Option<String>,
+
+    /// Put this before every metric name sent to statsd, like edge.
+    #[arg(long, default_value = "", requires = "statsd")]
+    pub statsd_prefix: String,
+
+    /// How to write metric labels for the statsd agent
+    #[arg(long, value_enum, default_value_t, requires = "statsd")]
+    pub statsd_tags: TagFormat,
+
+    /// Seconds between sends to statsd
+    #[arg(long, default_value_t = 10.0, value_parser = positive_secs, requires = "statsd")]
+    pub statsd_interval: f64,
+
+    /// Store what's observed straight into this database, as a collector would, with no
+    /// collector process (needs the sqlite feature)
+    #[arg(long)]
+    pub local_db: Option<String>,
+
+    /// Identity to advertise to server, defaults to hostname
+    #[arg(long)]
+    pub ident: Option<String>,
+
+    /// Label this sensor for the collectors, as key=value like site=ams1 (repeatable)
+    #[arg(long, value_parser = Hello::parse_tag)]
+    pub tag: Vec<(String, String)>,
+
+    /// Seconds between snapshots of every open connection, which let the server close any
+    /// whose end it missed; 0 sends none
+    #[arg(long, default_value_t = 3600)]
+    pub snapshot_interval: u64,
+
+    /// Seconds between reports to the server of packets captured and dropped on each
+    /// interface; 0 sends none
+    #[arg(long, default_value_t = 60)]
+    pub stats_interval: u64,
+
+    /// BPF expression, as tcpdump takes, for pcap to apply to every capture without one of its own
+    #[arg(long)]
+    pub filter: Option<String>,
+
+    /// BPF expression for one of the --interfaces, as name=expression, in place of --filter (repeatable)
+    #[arg(long, value_name = "IFACE=EXPR", value_parser = parse_interface_filter, requires = "interfaces")]
+    pub interface_filter: Vec<(String, String)>,
+
+    /// Only report connections with either end in this address or CIDR block (repeatable)
+    #[arg(long)]
+    pub include: Vec<Cidr>,
+
+    /// Don't report connections with either end in this address or CIDR block (repeatable)
+    #[arg(long)]
+    pub exclude: Vec<Cidr>,
+
+    /// Don't report connections with either end on this port (repeatable)
+    #[arg(long)]
+    pub ignore_port: Vec<u16>,
+
+    /// Don't capture on loopback interfaces unless named, nor report connections with a
+    /// loopback end
+    #[arg(long)]
+    pub no_loopback: bool,
+
+    /// Only report TCP packets that open or close a connection, leaving out Active messages
+    #[arg(long)]
+    pub control_only: bool,
+
+    /// Seconds after which a connection still starting or open is reported again [default: 30]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive: Option<u64>,
+
+    /// Seconds within which an ICMP error repeating one already reported, for the same
+    /// connection, isn't reported on its own: the first is, then at most one Failed message a
+    /// window counting the rest
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub failed_window: Option<u64>,
+
+    /// Time TCP handshakes from the SYN to the SYN-ACK, and report the round trip with each
+    /// connection's first Active message; collectors from before this drop those messages
+    #[arg(long)]
+    pub rtt: bool,
+
+    /// Never report this DNS name, or any under it, or names matching it if it's a glob like
+    /// *.health.example; questions and answers are left out each on its own (repeatable)
+    #[arg(long, value_name = "PATTERN")]
+    pub suppress_name: Vec<NamePattern>,
+
+    /// Never report the DNS names in this file either, one --suppress-name pattern per line;
+    /// blank lines and lines starting with # are skipped
+    #[arg(long)]
+    pub suppress_names_file: Option<PathBuf>,
+
+    /// How to print each message observed: debug, json (one object per line), compact (one
+    /// summary per line), or none [default: debug]
+    #[arg(long)]
+    pub output: Option<Output>,
+
+    /// Report port scans as one Scan message apiece; implied by the other --scan flags
+    #[arg(long)]
+    pub scan: bool,
+
+    /// Distinct ports one source may try on one host within the window before it's a scan [default: 100]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub scan_ports: Option<u32>,
+
+    /// Distinct hosts one source may try on one port within the window before it's a scan [default: 100]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub scan_hosts: Option<u32>,
+
+    /// Seconds of capture time scan attempts are counted over [default: 30]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub scan_window: Option<u64>,
+
+    /// Leave out the attempts, resets and ICMP errors of a scan once it's been reported
+    #[arg(long)]
+    pub scan_suppress: bool,
+
+    /// Keep the frames captured last on each interface, and write them out as pcap files in this
+    /// directory on SIGUSR2 or when a message sets off a --ring-trigger; implied by the other
+    /// --ring flags [default: .]
+    #[arg(long, value_name = "DIR")]
+    pub ring_dir: Option<PathBuf>,
+
+    /// Seconds of capture each interface's ring goes back [default: 30]
+    #[arg(long, value_name = "SECS")]
+    pub ring_seconds: Option<f64>,
+
+    /// Megabytes of frames each interface's ring holds [default: 64]
+    #[arg(long, value_name = "MB")]
+    pub ring_megabytes: Option<f64>,
+
+    /// Write the rings out when an observed message matches this rule, written as --alert rules
+    /// are and at most once its window, like "kind=scan,window=60" (repeatable)
+    #[arg(long, value_name = "RULE")]
+    pub ring_trigger: Vec<Rule>,
+
+    /// Publish to a sensor mesh too, listening for mesh peers here (repeatable; needs the mesh feature)
+    #[arg(long)]
+    pub mesh_listen: Vec<SocketAddr>,
+
+    /// Publish to a sensor mesh too, dialing this peer (repeatable; needs the mesh feature)
+    #[arg(long)]
+    pub mesh_peer: Vec<SocketAddr>,
+
+    /// Keep the mesh peers found or dialed in this file, to dial again after a restart
+    #[arg(long)]
+    pub mesh_peer_file: Option<PathBuf>,
+
+    /// Seconds after which a peer in the peer file that hasn't been linked to is forgotten
+    #[arg(long, default_value_t = 604800.0, requires = "mesh_peer_file")]
+    pub mesh_peer_horizon: f64,
+}
+
+/// Arguments for `glosco tail`.
+#[derive(Debug, Clone, clap::Args)]
+#[command(group(clap::ArgGroup::new("source").required(true)))]
+pub struct TailArgs {
+    /// Collector to subscribe to
+    #[arg(short = 'R', long, group = "source")]
+    pub remote: Option<String>,
+
+    /// Collector database to follow instead, polling it for newly stored rows
+    #[arg(short, long, group = "source")]
+    pub database: Option<String>,
+
+    /// Only messages from idents matching this glob (repeatable)
+    #[arg(long)]
+    pub ident: Vec<Glob>,
+
+    /// Only messages of this kind: starting, active, ended, reset, failed, name or scan (repeatable)
+    #[arg(long)]
+    pub kind: Vec<Kind>,
+
+    /// Only connections with either end in this address or CIDR block
+    #[arg(long)]
+    pub host: Option<Cidr>,
+
+    /// Only connections with either end on this port
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Print a line of text per message instead of JSON
+    #[arg(long)]
+    pub text: bool,
+
+    /// Seconds between polls of --database
+    #[arg(long, default_value_t = 1.0)]
+    pub interval: f64,
+}
+
+/// Arguments for `glosco query`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+#[command(group(clap::ArgGroup::new("report").required(true)))]
+pub struct QueryArgs {
+    /// Database file, or a directory of --shard-by-ident shards to report across them all
+    #[arg(short, long, default_value = "glosco.db")]
+    pub database: String,
+
+    /// Every sensor that has ever reported, with its agent, last report and staleness
+    #[arg(long, group = "report")]
+    pub clients: bool,
+
+    /// Hourly connection counts per ident and protocol
+    #[arg(long, group = "report")]
+    pub summary: bool,
+
+    /// Connections paired up from opening to close, with durations
+    #[arg(long, group = "report")]
+    pub sessions: bool,
+
+    /// Connections open right now
+    #[arg(long, group = "report")]
+    pub active: bool,
+
+    /// Name records matching a pattern, like corp.internal or *.example.com, over the window,
+    /// each with the connections made to its address soon after
+    #[arg(long, groups = ["report", "naming"], value_name = "PATTERN")]
+    pub names: Option<Glob>,
+
+    /// Seconds after a name record during which connections to its address are joined to it
+    /// (--names), or that a record with no TTL of its own names its address for (--resolve)
+    /// [default: 3600]
+    #[arg(long, requires = "naming")]
+    pub window: Option<f64>,
+
+    /// Name each connection's ends by the name records that held for their addresses when it
+    /// started (--active and --sessions)
+    #[arg(long, group = "naming", conflicts_with_all = ["clients", "summary", "names", "top", "remote"])]
+    pub resolve: bool,
+
+    /// The busiest values of one column (see --by) over the window, by connections
+    #[arg(long, group = "report")]
+    pub top: bool,
+
+    /// What --top ranks [default: dsthost]
+    #[arg(long, value_enum, conflicts_with_all = ["clients", "summary", "sessions", "active", "names"])]
+    pub by: Option<TopBy>,
+
+    /// Print --top as an aligned table rather than JSON
+    #[arg(long, conflicts_with_all = ["clients", "summary", "sessions", "active", "names"])]
+    pub table: bool,
+
+    /// Only report on this ident
+    #[arg(long)]
+    pub ident: Option<String>,
+
+    /// Only clients with this tag, as key=value (--clients; repeatable, and all must match)
+    #[arg(long, requires = "clients", value_parser = Hello::parse_tag)]
+    pub tag: Vec<(String, String)>,
+
+    /// Only clients with this operator label, as key=value (--clients; repeatable, and all must
+    /// match)
+    #[arg(long, requires = "clients", value_parser = Hello::parse_tag)]
+    pub label: Vec<(String, String)>,
+
+    /// Only sessions to this address or block (--sessions), or connections with either end in it (--active)
+    #[arg(long)]
+    pub host: Option<Cidr>,
+
+    /// Only sessions to this port (--sessions), or connections with either end on it (--active)
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Only sessions over this protocol (--sessions)
+    #[arg(long, value_enum)]
+    pub proto: Option<Proto>,
+
+    /// How many hours back to report
+    #[arg(long, default_value = "24")]
+    pub hours: u32,
+
+    /// Start of the window, in seconds since the epoch (--sessions, --names and --top) [default: --hours before --until]
+    #[arg(long)]
+    pub since: Option<f64>,
+
+    /// End of the window, in seconds since the epoch (--sessions, --names and --top) [default: now]
+    #[arg(long)]
+    pub until: Option<f64>,
+
+    /// Print times as seconds since the epoch rather than RFC 3339
+    #[arg(long)]
+    pub epoch: bool,
+
+    /// Ask the collector at this address over its client port instead of reading a database
+    /// (--active and --sessions only)
+    #[arg(long)]
+    pub remote: Option<SocketAddr>,
+
+    /// Token the remote collector's remote_query_token asks for
+    #[arg(long, requires = "remote")]
+    pub token: Option<String>,
+
+    /// Rows to report (--top, --names) or ask a remote collector for, at most; a remote
+    /// collector may cap this lower [default: 10 for --top, 1000 remote, else all]
+    #[arg(long)]
+    pub limit: Option<u32>,
+}
+
+/// A transport protocol, as named on the command line.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+#[cfg(feature = "sqlite")]
+impl Proto {
+    pub fn protocol(self) -> crate::observe::Protocol {
+        match self {
+            Self::Tcp => crate::observe::Protocol::Tcp,
+            Self::Udp => crate::observe::Protocol::Udp,
+        }
+    }
+}
+
+/// A column `glosco query --top` can rank.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TopBy {
+    #[default]
+    Dsthost,
+    Dstport,
+    Ident,
+    Srchost,
+}
+
+#[cfg(feature = "sqlite")]
+impl TopBy {
+    pub fn column(self) -> &'static str {
+        match self {
+            Self::Dsthost => "dsthost",
+            Self::Dstport => "dstport",
+            Self::Ident => "ident",
+            Self::Srchost => "srchost",
+        }
+    }
+}
+
+/// Arguments for `glosco mesh-status`.
+#[cfg(feature = "mesh")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct MeshStatusArgs {
+    /// Mesh instance to join the mesh by (repeatable)
+    #[arg(long, required = true)]
+    pub peer: Vec<SocketAddr>,
+
+    /// Seconds to wait for a link, and then again for answers to the probe
+    #[arg(long, default_value_t = 2.0)]
+    pub wait: f64,
+}
+
+/// Arguments for `glosco healthcheck`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct HealthcheckArgs {
+    /// Database file
+    #[arg(short, long, default_value = "glosco.db")]
+    pub database: String,
+
+    /// Seconds since the last write after which the collector counts as unhealthy
+    #[arg(long, default_value = "300")]
+    pub max_age: f64,
+}
+
+/// Arguments for `glosco import`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImportArgs {
+    /// Capture file to read (repeatable; read in the order given)
+    #[arg(long = "pcap", required = true)]
+    pub pcaps: Vec<PathBuf>,
+
+    /// Ident to store the connections under, as though that client had reported them
+    #[arg(long)]
+    pub ident: String,
+
+    /// Database file
+    #[arg(short, long, default_value = "glosco.db")]
+    pub database: String,
+
+    /// Only count the messages the capture yields, by kind, without touching the database
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for `glosco merge`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct MergeArgs {
+    /// Database to merge into; created if it doesn't exist
+    #[arg(long)]
+    pub into: String,
+
+    /// Databases to merge from, in order
+    #[arg(required = true)]
+    pub sources: Vec<String>,
+
+    /// Store the idents from one source as LABEL/ident, given as LABEL=SOURCE (repeatable)
+    #[arg(long)]
+    pub prefix: Vec<Prefix>,
+}
+
+/// Arguments for `glosco admin`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct AdminArgs {
+    /// Database file, or a directory of --shard-by-ident shards
+    #[arg(short, long, default_value = "glosco.db", global = true)]
+    pub database: String,
+
+    #[command(subcommand)]
+    pub command: AdminCommand,
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Subcommand)]
+pub enum AdminCommand {
+    /// Set or delete an ident's operator labels, then print them all
+    Label(LabelArgs),
+    /// Delete everything recorded about a host or an ident, as for an erasure request
+    Purge(PurgeArgs),
+}
+
+/// Arguments for `glosco admin label`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct LabelArgs {
+    pub ident: String,
+
+    /// Labels to set, as key=value like owner=team-x, replacing any with the same key
+    #[arg(value_parser = Hello::parse_tag)]
+    pub labels: Vec<(String, String)>,
+
+    /// Delete the label with this key (repeatable)
+    #[arg(long, value_name = "KEY")]
+    pub delete: Vec<String>,
+}
+
+/// Arguments for `glosco admin purge`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+#[command(group(clap::ArgGroup::new("target").required(true)))]
+#[command(group(clap::ArgGroup::new("mode").required(true)))]
+pub struct PurgeArgs {
+    /// Purge connections with either end in this address or block, and names of it
+    #[arg(long, group = "target", value_name = "ADDR|CIDR")]
+    pub host: Option<Cidr>,
+
+    /// Purge everything this ident's sensor reported, and what's kept about the sensor
+    #[arg(long, group = "target")]
+    pub ident: Option<String>,
+
+    /// Count what would be deleted, table by table, without deleting it
+    #[arg(long, group = "mode")]
+    pub dry_run: bool,
+
+    /// Delete it
+    #[arg(long, group = "mode")]
+    pub confirm: bool,
+}
+
+/// Arguments for `glosco server`, and the whole of `glosco_server`.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, clap::Args)]
+pub struct ServerArgs {
+    /// TOML configuration file; flags given on the command line take precedence over it
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub daemon: DaemonArgs,
+
+    /// Bind address [default: 0.0.0.0:12074]
+    #[arg(short = 'B', long)]
+    pub bind: Option<SocketAddr>,
+
+    /// Database file, directory of them with --shard-by-ident, or :memory: [default: glosco.db]
+    #[arg(short, long)]
+    pub database: Option<String>,
+
+    /// Snapshot an in-memory database to this file, and start from it if it's there
+    #[arg(long)]
+    pub snapshot: Option<PathBuf>,
+
+    /// Seconds between snapshots of an in-memory database [default: 60]
+    #[arg(long, requires = "snapshot")]
+    pub snapshot_interval: Option<f64>,
+
+    /// Timeout on TCP connections, after which we assume they closed without notice [default: 60]
+    #[arg(long)]
+    pub tcp_timeout: Option<f64>,
+
+    /// Timeout on UDP flows still open, after which we assume they ended [default: 30]
+    #[arg(long)]
+    pub udp_timeout: Option<f64>,
+
+    /// Maintenance period--how often to do periodic database tasks [default: 5]
+    #[arg(long)]
+    pub maintenance: Option<f64>,
+
+    /// Insert a new row for every keepalive instead of refreshing the open Active row
+    #[arg(long)]
+    pub append_only: bool,
+
+    /// Keep each day's rows in a table of their own, so retention can drop whole days (irreversible)
+    #[arg(long)]
+    pub partition: bool,
+
+    /// Seconds to keep state rows for before maintenance expires them [default: forever]
+    #[arg(long)]
+    pub retention: Option<f64>,
+
+    /// Seconds to keep name records for before maintenance expires them [default: forever]
+    #[arg(long)]
+    pub names_retention: Option<f64>,
+
+    /// Bytes the database may use before maintenance evicts the oldest state [default: no cap]
+    #[arg(long)]
+    pub max_db_size: Option<u64>,
+
+    /// Keep each ident's rows in a database of its own, glosco-<ident>.db in the --database directory
+    #[arg(long)]
+    pub shard_by_ident: bool,
+
+    /// Shard databases kept open between uses with --shard-by-ident [default: 64]
+    #[arg(long)]
+    pub shard_handles: Option<usize>,
+
+    /// What to do when a second address connects under an ident that's already connected [default: warn]
+    #[arg(long, value_enum)]
+    pub ident_collision: Option<CollisionPolicy>,
+
+    /// Turn away legacy clients, which send no hello, instead of accepting them and marking
+    /// them legacy in the clients listing
+    #[arg(long)]
+    pub reject_legacy: bool,
+
+    /// Threads serving clients; each serves one connection at a time [default: 256]
+    #[arg(long)]
+    pub workers: Option<usize>,
+
+    /// Connections allowed to wait for a free worker before more are refused [default: 256]
+    #[arg(long)]
+    pub pending: Option<usize>,
+
+    /// Read clients on a tokio runtime instead of a thread apiece (needs the async-server feature)
+    #[arg(long)]
+    pub async_io: bool,
+
+    /// Seconds a message's timestamp may be off from the collector's clock [default: 600]
+    #[arg(long)]
+    pub max_skew: Option<f64>,
+
+    /// What to do with a message whose timestamp is further off than --max-skew [default: clamp]
+    #[arg(long, value_enum)]
+    pub skew_policy: Option<SkewPolicy>,
+
+    /// Also append every accepted message to this file as newline-delimited JSON
+    #[arg(long)]
+    pub log_events: Option<PathBuf>,
+
+    /// Rotate the event log once it reaches this many bytes
+    #[arg(long, requires = "log_events")]
+    pub log_events_max_bytes: Option<u64>,
+
+    /// Rotate the event log once it has been open this many seconds
+    #[arg(long, requires = "log_events")]
+    pub log_events_max_age: Option<f64>,
+
+    /// Gzip rotated event logs
+    #[arg(long, requires = "log_events")]
+    pub log_events_gzip: bool,
+
+    /// Publish every accepted message as JSON to kafka://broker/topic or mqtt://host/topic
+    #[arg(long)]
+    pub forward: Vec<Target>,
+
+    /// Pass every accepted message on to this collector too, keeping each client's ident (repeatable)
+    #[arg(long)]
+    pub relay: Vec<SocketAddr>,
+
+    /// Follow this primary collector as a warm standby, storing everything it accepts
+    #[arg(long)]
+    pub replicate: Option<SocketAddr>,
+
+    /// Join a sensor mesh, listening for mesh peers here (repeatable; needs the mesh feature)
+    #[arg(long)]
+    pub mesh_listen: Vec<SocketAddr>,
+
+    /// Join a sensor mesh by dialing this peer (repeatable; needs the mesh feature)
+    #[arg(long)]
+    pub mesh_peer: Vec<SocketAddr>,
+
+    /// Keep the mesh peers found or dialed in this file, to dial again after a restart
+    #[arg(long)]
+    pub mesh_peer_file: Option<PathBuf>,
+
+    /// Answer `glosco query --remote` over the client port for queries carrying this token
+    #[arg(long)]
+    pub remote_query_token: Option<String>,
+
+    /// Most rows a remote query gets back [default: 10000]
+    #[arg(long)]
+    pub remote_query_limit: Option<usize>,
+
+    /// Look up destination country and ASN in this MaxMind database (repeatable, e.g. for Country and ASN)
+    #[arg(long)]
+    pub geoip: Vec<PathBuf>,
+
+    /// Look up the PTR name of every destination in the background and store it in the names table
+    #[arg(long)]
+    pub rdns: bool,
+
+    /// DNS server for --rdns [default: first nameserver in /etc/resolv.conf]
+    #[arg(long, requires = "rdns")]
+    pub rdns_server: Option<SocketAddr>,
+
+    /// Reverse lookups per second for --rdns [default: 20]
+    #[arg(long, requires = "rdns")]
+    pub rdns_rate: Option<f64>,
+
+    /// POST a JSON alert to this URL when an accepted message matches an alert rule
+    #[arg(long)]
+    pub webhook: Option<String>,
+
+    /// Alert rule, like "kind=failed,ident=db-*,dst=10.0.0.0/8,port=5432,window=300"; defaults to all Failed messages
+    #[arg(long = "alert", requires = "webhook")]
+    pub alerts: Vec<Rule>,
+
+    /// Learn the destinations each ident uses, by port or by host and port, and flag new ones
+    #[arg(long, value_enum)]
+    pub baseline: Option<Granularity>,
+
+    /// Seconds after an ident first appears during which new destinations are learned quietly [default: 604800]
+    #[arg(long, requires = "baseline")]
+    pub baseline_learning: Option<f64>,
+
+    /// Serve the HTTP API and dashboard on this address
+    #[arg(long)]
+    pub api_bind: Option<SocketAddr>,
+
+    /// Require this bearer token on API requests
+    #[arg(long, requires = "api_bind")]
+    pub api_token: Option<String>,
+
+    /// Take newline-delimited JSON messages on POST /v1/ingest
+    #[arg(long, requires = "api_bind")]
+    pub api_ingest: bool,
+
+    /// Longest ingest request body, in bytes [default: 1048576]
+    #[arg(long, requires = "api_ingest")]
+    pub api_ingest_max_bytes: Option<usize>,
+
+    /// Messages a second each address may post to the ingest endpoint [default: 1000]
+    #[arg(long, requires = "api_ingest")]
+    pub api_ingest_rate: Option<f64>,
+
+    /// Write a report on each day (or hour) to a file in this directory once it's over
+    #[arg(long, value_name = "DIR")]
+    pub report_path: Option<PathBuf>,
+
+    /// Period each report covers [default: daily]
+    #[arg(long, value_enum, requires = "report_path")]
+    pub report_interval: Option<Interval>,
+
+    /// Format reports are written in [default: markdown]
+    #[arg(long, value_enum, requires = "report_path")]
+    pub report_format: Option<Format>,
+
+    /// Include this section in reports, in the order given (repeatable) [default: all of them]
+    #[arg(long, value_enum, requires = "report_path")]
+    pub report_section: Vec<Section>,
+}
+
+/// A number of seconds that's more than none.
+fn parse_interface_filter(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((name, expr)) if !name.is_empty() && !expr.trim().is_empty() => Ok((name.to_string(), expr.to_string())),
+        _ => Err(format!("{:?} is not interface=expression", text)),
+    }
+}
+
+fn positive_secs(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(secs),
+        Ok(_) => Err("must be more than 0".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl ClientArgs {
+    /// Settings for `client::start`, resolving each remote to the addresses it names.
+    pub fn resolve(self) -> ClientSettings {
+        self.try_resolve().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `resolve`, but reporting a config file that can't be read or parsed, a profile it
+    /// doesn't have, or settings that conflict.
+    pub fn try_resolve(self) -> Result<ClientSettings, SettingsError> {
+        let (mut filters, output, mut scan, mut ring) = match &self.config {
+            Some(path) => settings::load_profile(path, self.profile.as_deref(), |mut keys| {
+                let output: Option<Output> = keys.remove("output").map(toml::Value::try_into).transpose()?;
+                let scan: Option<ScanSettings> = keys.remove("scan").map(toml::Value::try_into).transpose()?;
+                let ring: Option<RingSettings> = keys.remove("ring").map(toml::Value::try_into).transpose()?;
+                Ok((toml::Value::Table(keys).try_into::<Filters>()?, output, scan, ring))
+            })?,
+            None => (Filters::default(), None, None, None),
+        };
+        if let Some(filter) = self.filter {
+            filters.filter = Some(filter);
+        }
+        if !self.include.is_empty() {
+            filters.include = self.include;
+        }
+        if !self.exclude.is_empty() {
+            filters.exclude = self.exclude;
+        }
+        if !self.ignore_port.is_empty() {
+            filters.ignore_ports = self.ignore_port;
+        }
+        filters.no_loopback |= self.no_loopback;
+        filters.control_only |= self.control_only;
+        if let Some(keepalive) = self.keepalive {
+            filters.keepalive = keepalive;
+        }
+        if let Some(window) = self.failed_window {
+            filters.failed_window = Some(window);
+        }
+        filters.rtt |= self.rtt;
+        if !self.suppress_name.is_empty() {
+            filters.suppress_names = self.suppress_name;
+        }
+        if let Some(path) = self.suppress_names_file {
+            let patterns = std::fs::read_to_string(&path).map_err(|e| SettingsError::Io(path, e))?;
+            filters.suppress_names.extend(patterns.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string().into()));
+        }
+        if self.scan || self.scan_ports.is_some() || self.scan_hosts.is_some() || self.scan_window.is_some() || self.scan_suppress {
+            let scan = scan.get_or_insert_with(ScanSettings::default);
+            scan.ports = self.scan_ports.unwrap_or(scan.ports);
+            scan.hosts = self.scan_hosts.unwrap_or(scan.hosts);
+            scan.window = self.scan_window.unwrap_or(scan.window);
+            scan.suppress |= self.scan_suppress;
+        }
+        if self.ring_dir.is_some() || self.ring_seconds.is_some() || self.ring_megabytes.is_some() || !self.ring_trigger.is_empty() {
+            let ring = ring.get_or_insert_with(RingSettings::default);
+            ring.dir = self.ring_dir.unwrap_or_else(|| ring.dir.clone());
+            ring.seconds = self.ring_seconds.unwrap_or(ring.seconds);
+            ring.megabytes = self.ring_megabytes.unwrap_or(ring.megabytes);
+            if !self.ring_trigger.is_empty() {
+                ring.triggers = self.ring_trigger;
+            }
+        }
+        if let Some(both) = filters.include.iter().find(|cidr| filters.exclude.contains(cidr)) {
+            return Err(SettingsError::Conflict(format!("{} is both included and excluded", both)));
+        }
+        Ok(ClientSettings {
+            interfaces: self.interfaces.unwrap_or_default(),
+            captures: self.pcap,
+            remotes: self.remotes.iter()
+                .flat_map(|remote| remote.to_socket_addrs().expect("failed to parse as socket address"))
+                .collect(),
+            remotes_srv: self.remotes_srv,
+            local_db: self.local_db,
+            ident: self.ident,
+            tags: self.tag.into_iter().collect(),
+            snapshot_interval: (self.snapshot_interval > 0).then_some(self.snapshot_interval as f64),
+            stats_interval: (self.stats_interval > 0).then_some(self.stats_interval as f64),
+            once: self.once,
+            flush_timeout: self.flush_timeout,
+            filters,
+            interface_filters: self.interface_filter.into_iter().collect(),
+            output: self.output.or(output).unwrap_or_default(),
+            scan,
+            ring,
+            watchdog: self.watchdog,
+            watchdog_recovery: self.watchdog_recovery,
+            metrics_bind: self.metrics_bind,
+            statsd: self.statsd.map(|agent| agent.to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .unwrap_or_else(|| panic!("failed to resolve statsd agent {}", agent))),
+            statsd_prefix: self.statsd_prefix,
+            statsd_tags: self.statsd_tags,
+            statsd_interval: self.statsd_interval,
+            mesh_listen: self.mesh_listen,
+            mesh_peers: self.mesh_peer,
+            mesh_peer_file: self.mesh_peer_file,
+            mesh_peer_horizon: self.mesh_peer_horizon,
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ServerArgs {
+    /// Load the config file, if any, and lay the command line over it.
+    pub fn resolve(self) -> ServerSettings {
+        self.try_resolve().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like `resolve`, but reporting a config file that can't be read or parsed.
+    pub fn try_resolve(self) -> Result<ServerSettings, SettingsError> {
+        let mut settings = match &self.config {
+            Some(path) => ServerSettings::load(path)?,
+            None => ServerSettings::default(),
+        };
+        if let Some(bind) = self.bind {
+            settings.bind = bind;
+        }
+        if let Some(database) = self.database {
+            settings.database = database;
+        }
+        if let Some(snapshot) = self.snapshot {
+            settings.snapshot = Some(snapshot);
+        }
+        if let Some(interval) = self.snapshot_interval {
+            settings.snapshot_interval = interval;
+        }
+        if let Some(tcp_timeout) = self.tcp_timeout {
+            settings.tcp_timeout = tcp_timeout;
+        }
+        if let Some(udp_timeout) = self.udp_timeout {
+            settings.udp_timeout = udp_timeout;
+        }
+        if let Some(maintenance) = self.maintenance {
+            settings.maintenance = maintenance;
+        }
+        settings.append_only |= self.append_only;
+        settings.partition |= self.partition;
+        if let Some(retention) = self.retention {
+            settings.retention = Some(retention);
+        }
+        if let Some(retention) = self.names_retention {
+            settings.names_retention = Some(retention);
+        }
+        if let Some(max) = self.max_db_size {
+            settings.max_db_size = Some(max);
+        }
+        settings.shard_by_ident |= self.shard_by_ident;
+        if let Some(handles) = self.shard_handles {
+            settings.shard_handles = handles;
+        }
+        if let Some(policy) = self.ident_collision {
+            settings.ident_collision = policy;
+        }
+        settings.reject_legacy |= self.reject_legacy;
+        if let Some(workers) = self.workers {
+            settings.workers = workers;
+        }
+        if let Some(pending) = self.pending {
+            settings.pending = pending;
+        }
+        settings.async_io |= self.async_io;
+        if let Some(max_skew) = self.max_skew {
+            settings.max_skew = max_skew;
+        }
+        if let Some(policy) = self.skew_policy {
+            settings.skew_policy = policy;
+        }
+        if !self.forward.is_empty() {
+            settings.forward = self.forward;
+        }
+        if !self.relay.is_empty() {
+            settings.relay = self.relay;
+        }
+        if let Some(primary) = self.replicate {
+            settings.replicate = Some(primary);
+        }
+        if !self.mesh_listen.is_empty() {
+            settings.mesh_listen = self.mesh_listen;
+        }
+        if !self.mesh_peer.is_empty() {
+            settings.mesh_peers = self.mesh_peer;
+        }
+        if let Some(path) = self.mesh_peer_file {
+            settings.mesh_peer_file = Some(path);
+        }
+        if let Some(token) = self.remote_query_token {
+            settings.remote_query_token = Some(token);
+        }
+        if let Some(limit) = self.remote_query_limit {
+            settings.remote_query_limit = limit;
+        }
+        if !self.geoip.is_empty() {
+            settings.geoip = self.geoip;
+        }
+        if let Some(path) = self.log_events {
+            settings.event_log = Some(EventLogSettings {
+                path,
+                max_bytes: self.log_events_max_bytes,
+                max_age: self.log_events_max_age,
+                gzip: self.log_events_gzip,
+            });
+        }
+        if self.rdns {
+            settings.rdns = Some(RdnsSettings {
+                server: self.rdns_server,
+                workers: None,
+                rate: self.rdns_rate,
+            });
+        }
+        if let Some(webhook) = self.webhook {
+            settings.alerts = Some(AlertSettings {
+                webhook,
+                rules: self.alerts,
+            });
+        }
+        if let Some(granularity) = self.baseline {
+            let mut baseline = BaselineSettings {
+                granularity,
+                ..Default::default()
+            };
+            if let Some(learning) = self.baseline_learning {
+                baseline.learning = learning;
+            }
+            settings.baseline = Some(baseline);
+        }
+        if let Some(bind) = self.api_bind {
+            settings.api = Some(ApiSettings {
+                bind,
+                token: self.api_token,
+                ingest: self.api_ingest,
+                ingest_max_bytes: self.api_ingest_max_bytes,
+                ingest_rate: self.api_ingest_rate,
+            });
+        }
+        if let Some(path) = self.report_path {
+            settings.report = Some(ReportSettings {
+                path,
+                interval: self.report_interval.unwrap_or_default(),
+                format: self.report_format.unwrap_or_default(),
+                sections: self.report_section,
+            });
+        }
+        Ok(settings)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(compat_args(args.iter().map(OsString::from)))
+    }
+
+    fn client(args: &[&str]) -> ClientArgs {
+        let mut argv = vec!["glosco", "client"];
+        argv.extend(args);
+        match cli(&argv).unwrap().command {
+            Command::Client(args) => *args,
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    fn config(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("glosco-cli-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn the_mode_flag_becomes_the_subcommand() {
+        for argv in [
+            &["glosco", "--mode", "client", "--no-loopback"][..],
+            &["glosco", "--no-loopback", "--mode=client"][..],
+            &["glosco", "client", "--no-loopback"][..],
+        ] {
+            assert!(matches!(cli(argv).unwrap().command, Command::Client(args) if args.no_loopback), "{:?}", argv);
+        }
+        // Past `--` it's left for whatever comes after
+        assert_eq!(compat_args(["glosco", "client", "--", "--mode", "server"].map(OsString::from)),
+                   ["glosco", "client", "--", "--mode", "server"].map(OsString::from));
+    }
+
+    #[test]
+    fn filter_flags_resolve_into_filters() {
+        let settings = client(&[
+            "-R", "127.0.0.1:7193",
+            "--filter", "tcp port 443",
+            "--include", "10.0.0.0/8", "--include", "192.168.1.0/24",
+            "--exclude", "10.9.0.0/16",
+            "--ignore-port", "22", "--ignore-port", "53",
+            "--no-loopback",
+            "--control-only",
+            "--keepalive", "90",
+        ]).try_resolve().unwrap();
+        assert_eq!(settings.filters, Filters {
+            filter: Some("tcp port 443".to_string()),
+            include: vec!["10.0.0.0/8".parse().unwrap(), "192.168.1.0/24".parse().unwrap()],
+            exclude: vec!["10.9.0.0/16".parse().unwrap()],
+            ignore_ports: vec![22, 53],
+            no_loopback: true,
+            control_only: true,
+            keepalive: 90,
+            ..Filters::default()
+        });
+        assert_eq!(settings.remotes, ["127.0.0.1:7193".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn no_filter_flags_is_the_default_filters() {
+        assert_eq!(client(&[]).try_resolve().unwrap().filters, Filters::default());
+    }
+
+    #[test]
+    fn flags_are_laid_over_the_config_file() {
+        let path = config("precedence", "include = [\"10.0.0.0/8\"]\nignore_ports = [22]\nkeepalive = 60\ncontrol_only = true\n");
+        let filters = client(&["-c", path.to_str().unwrap(), "--ignore-port", "53", "--keepalive", "15"])
+            .try_resolve().unwrap().filters;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(filters, Filters {
+            include: vec!["10.0.0.0/8".parse().unwrap()],
+            ignore_ports: vec![53],
+            control_only: true,
+            keepalive: 15,
+            ..Filters::default()
+        });
+    }
+
+    #[test]
+    fn bad_filter_flags_are_refused_before_capture() {
+        for argv in [
+            &["--keepalive", "0"][..],
+            &["--keepalive", "soon"][..],
+            &["--include", "10.0.0.0/33"][..],
+            &["--exclude", "not-a-block"][..],
+            &["--ignore-port", "65536"][..],
+            &["--no-loopback=yes"][..],
+        ] {
+            let mut full = vec!["glosco", "client"];
+            full.extend(argv);
+            assert!(cli(&full).is_err(), "{:?} was accepted", argv);
+        }
+    }
+
+    #[test]
+    fn a_block_both_included_and_excluded_conflicts() {
+        let err = client(&["--include", "10.0.0.0/8", "--exclude", "10.0.0.0/8"]).try_resolve().unwrap_err();
+        assert!(matches!(err, SettingsError::Conflict(_)), "{:?}", err);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn a_purge_needs_one_target_and_one_mode() {
+        assert!(cli(&["glosco", "admin", "purge", "--dry-run"]).is_err());
+        assert!(cli(&["glosco", "admin", "purge", "--host", "10.0.0.1"]).is_err());
+        assert!(cli(&["glosco", "admin", "purge", "--host", "10.0.0.1", "--ident", "sensor", "--dry-run"]).is_err());
+        assert!(cli(&["glosco", "admin", "purge", "--host", "10.0.0.1", "--dry-run", "--confirm"]).is_err());
+        assert!(cli(&["glosco", "admin", "purge", "--ident", "sensor", "--confirm"]).is_ok());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn labels_are_checked_as_theyre_given() {
+        let Ok(Cli { command: Command::Admin(AdminArgs { command: AdminCommand::Label(label), .. }) }) =
+            cli(&["glosco", "admin", "label", "sensor", "owner=team-x", "note=due out 2026-11", "--delete", "rack"]) else {
+            panic!("admin label wasn't accepted");
+        };
+        assert_eq!(label.ident, "sensor");
+        assert_eq!(label.labels, [("owner".to_string(), "team-x".to_string()), ("note".to_string(), "due out 2026-11".to_string())]);
+        assert_eq!(label.delete, ["rack"]);
+
+        let too_long = format!("{}=x", "k".repeat(Hello::MAX_TAG_KEY + 1));
+        for label in ["owner", "=team-x", "own er=team-x", too_long.as_str()] {
+            assert!(cli(&["glosco", "admin", "label", "sensor", label]).is_err(), "{:?} was accepted", label);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::{collections::BTreeMap, io, net::SocketAddr, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc}, thread, time::{Duration, Instant, SystemTime}};
+
+use pcap::Device;
+
+use crate::{cli::ClientArgs, daemon, tui, coding::Coder, dns::UdpResolver, metrics::{Registry, Statsd, TagFormat}, observe::{self, Batch, Closed, Filters, InterfaceStats, Liveness, Message, Namespace, ObserverConfig, Recovery, StartError}, output::{Format, Output}, ring::{self, RingSettings, Triggers}, scan::ScanSettings, sync::{Client, ClientConfig, Hello, RemoteStats}};
+#[cfg(feature = "sqlite")]
+use crate::server::{LocalStore, ServerSettings};
+#[cfg(feature = "mesh")]
+use crate::mesh::{Mesh, MeshConfig};
+
+/// Everywhere observed messages go: the collectors, the local database and the mesh if there
+/// are those.
+struct Outlets {
+    client: Client,
+    #[cfg(feature = "sqlite")]
+    local: Option<LocalStore>,
+    #[cfg(feature = "mesh")]
+    ident: String,
+    #[cfg(feature = "mesh")]
+    mesh: Option<(tokio::runtime::Runtime, Arc<Mesh>)>,
+}
+
+impl Outlets {
+    fn send<C: Coder>(&mut self, object: &C) {
+        self.client.send(object);
+        #[cfg(feature = "sqlite")]
+        if let Some(local) = &mut self.local {
+            local.send(object);
+        }
+        #[cfg(feature = "mesh")]
+        if let Some((_, mesh)) = &self.mesh {
+            let mut buffer = Vec::new();
+            object.encode(&mut buffer).unwrap();
+            mesh.publish(&self.ident, buffer);
+        }
+    }
+
+    /// Send messages observed together, as one bundle to the collectors.
+    fn send_bundle(&mut self, messages: &[Message]) {
+        self.client.send_bundle(messages);
+        #[cfg(feature = "sqlite")]
+        if let Some(local) = &mut self.local {
+            for message in messages {
+                local.send(message);
+            }
+        }
+        #[cfg(feature = "mesh")]
+        if let Some((_, mesh)) = &self.mesh {
+            for message in messages {
+                let mut buffer = Vec::new();
+                message.encode(&mut buffer).unwrap();
+                mesh.publish(&self.ident, buffer);
+            }
+        }
+    }
+
+    /// Stop, after waiting up to `timeout` for everything to reach the collectors; true if it
+    /// all did.
+    fn finish(self, timeout: Duration) -> bool {
+        self.client.shutdown(timeout)
+    }
+}
+
+/// Everything the sensor needs to run, resolved from the command line.
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// Interfaces to capture on, by name; every one there is if empty.
+    pub interfaces: Vec<String>,
+    /// Capture files to read in order instead of capturing live; the sensor stops at their end.
+    pub captures: Vec<PathBuf>,
+    /// Collectors to report to.
+    pub remotes: Vec<SocketAddr>,
+    /// Report to the collectors this name's SRV records point to, like `_glosco._tcp.example.com`,
+    /// looking them up again as their TTL runs out; `remotes` is only reported to while there
+    /// aren't any to be found.
+    pub remotes_srv: Option<String>,
+    /// Database to store into directly, as a collector would, with its default settings (needs
+    /// the sqlite cargo feature).
+    pub local_db: Option<String>,
+    /// Identity to advertise to collectors; the hostname if not given.
+    pub ident: Option<String>,
+    /// Labels, like `site=ams1`, for collectors to keep with the ident.
+    pub tags: BTreeMap<String, String>,
+    /// Seconds between snapshots of every open connection; none are sent if not given.
+    pub snapshot_interval: Option<f64>,
+    /// Seconds between reports to the collectors of how capture is going on each interface;
+    /// none are sent if not given.
+    pub stats_interval: Option<f64>,
+    /// Once the capture files run out, report every connection still open as ended and wait for
+    /// everything to reach the collectors, as on shutdown.
+    pub once: bool,
+    /// On shutdown, every connection still open is reported as ended then, and the sensor waits
+    /// up to this many seconds for everything to reach the collectors before stopping.
+    pub flush_timeout: f64,
+    /// Which traffic to report on, and how often to repeat.
+    pub filters: Filters,
+    /// BPF expressions for particular interfaces, by name, in place of `filters.filter`; each
+    /// must be one of `interfaces`.
+    pub interface_filters: BTreeMap<String, String>,
+    /// How to print each message observed.
+    pub output: Output,
+    /// Report port scans as Scan messages, as these say; none are looked for if not given.
+    pub scan: Option<ScanSettings>,
+    /// Keep the frames captured last, to write out as pcap files on SIGUSR2 or when a message
+    /// sets off one of its triggers; nothing is kept if not given.
+    pub ring: Option<RingSettings>,
+    /// Report a live capture that's had no packets at all for this many seconds while its
+    /// interface is up, and recover as `watchdog_recovery` says; nothing's watched if not given.
+    pub watchdog: Option<f64>,
+    pub watchdog_recovery: Recovery,
+    /// Serve Prometheus metrics on `/metrics` here; nothing is counted for them if not given.
+    pub metrics_bind: Option<SocketAddr>,
+    /// Send the same metrics to the statsd agent here every `statsd_interval` seconds, each
+    /// name after `statsd_prefix`, with labels written as `statsd_tags` says.
+    pub statsd: Option<SocketAddr>,
+    pub statsd_prefix: String,
+    pub statsd_tags: TagFormat,
+    pub statsd_interval: f64,
+    /// Publish to a sensor mesh too (needs the mesh cargo feature), listening for mesh peers
+    /// here, dialing `mesh_peers`, and keeping the peers found in `mesh_peer_file` until they've
+    /// gone `mesh_peer_horizon` seconds without a link.
+    pub mesh_listen: Vec<SocketAddr>,
+    pub mesh_peers: Vec<SocketAddr>,
+    pub mesh_peer_file: Option<PathBuf>,
+    pub mesh_peer_horizon: f64,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            interfaces: Vec::new(),
+            captures: Vec::new(),
+            remotes: Vec::new(),
+            remotes_srv: None,
+            local_db: None,
+            ident: None,
+            tags: BTreeMap::new(),
+            snapshot_interval: Some(3600.0),
+            stats_interval: Some(60.0),
+            once: false,
+            flush_timeout: 30.0,
+            filters: Filters::default(),
+            interface_filters: BTreeMap::new(),
+            output: Output::default(),
+            scan: None,
+            ring: None,
+            watchdog: None,
+            watchdog_recovery: Recovery::default(),
+            metrics_bind: None,
+            statsd: None,
+            statsd_prefix: String::new(),
+            statsd_tags: TagFormat::default(),
+            statsd_interval: 10.0,
+            mesh_listen: Vec::new(),
+            mesh_peers: Vec::new(),
+            mesh_peer_file: None,
+            mesh_peer_horizon: 604800.0,
+        }
+    }
+}
+
+/// A sensor running in the background, from `start`.
+#[derive(Debug)]
+pub struct ClientHandle {
+    shutdown: Arc<AtomicBool>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl ClientHandle {
+    /// Stop reporting, within `POLL` or so, ending what's open and waiting out the flush timeout
+    /// for it to be delivered. Live capture threads linger until their next packet.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the sensor has stopped, so `join` won't wait.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for the sensor to stop: once shut down, or when its capture files run out. False
+    /// if it was shut down or run `once` and not everything reached the collectors.
+    pub fn join(self) -> bool {
+        self.thread.join().expect("sensor thread panicked")
+    }
+}
+
+/// How often a sensor with nothing to report checks whether it's been shut down.
+const POLL: Duration = Duration::from_millis(200);
+
+/// Join the mesh the settings ask for, if any, on a runtime of its own.
+#[cfg(feature = "mesh")]
+fn join_mesh(settings: &ClientSettings) -> io::Result<Option<(tokio::runtime::Runtime, Arc<Mesh>)>> {
+    if settings.mesh_listen.is_empty() && settings.mesh_peers.is_empty() && settings.mesh_peer_file.is_none() {
+        return Ok(None);
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    let mut config = MeshConfig::default();
+    config.set_listens(settings.mesh_listen.clone());
+    for peer in settings.mesh_peers.iter() {
+        config.add_peer(*peer);
+    }
+    if let Some(path) = &settings.mesh_peer_file {
+        config.set_peer_file(path.clone(), Duration::from_secs_f64(settings.mesh_peer_horizon));
+    }
+    let mesh = runtime.block_on(config.build())?;
+    Ok(Some((runtime, mesh)))
+}
+
+/// Start capturing on the requested interfaces (or reading the capture files) and sending
+/// everything observed to the remotes, in the background.
+pub fn start(settings: ClientSettings) -> io::Result<ClientHandle> {
+    let format = settings.output.format();
+    start_with(settings, format)
+}
+
+/// Start like `start`, but handing what's observed to `format` in place of the one
+/// `settings.output` names.
+pub fn start_with(settings: ClientSettings, format: Box<dyn Format + Send>) -> io::Result<ClientHandle> {
+    #[cfg(feature = "mesh")]
+    let mesh = join_mesh(&settings)?;
+    #[cfg(not(feature = "mesh"))]
+    assert!(settings.mesh_listen.is_empty() && settings.mesh_peers.is_empty() && settings.mesh_peer_file.is_none(), "mesh peers given, but glosco was built without the mesh feature");
+
+    let mut observer = ObserverConfig::default();
+
+    if let Some(name) = settings.interface_filters.keys().find(|name| !settings.interfaces.contains(name)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("capture filter given for {}, which isn't one of the interfaces to capture on", name)));
+    }
+    // As pcap lists them, so that their addresses can be announced
+    let mut known = Vec::new();
+    if !settings.interfaces.is_empty() {
+        known = Device::list().map_err(io::Error::other)?;
+        let names: Vec<String> = known.iter().map(|dev| dev.name.clone()).collect();
+        observe::check_interfaces(&settings.interfaces, &names).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    }
+    for devname in settings.interfaces.iter() {
+        let dev = known.iter().find(|dev| dev.name == *devname).cloned().unwrap_or_else(|| Device::from(&devname[..]));
+        match settings.interface_filters.get(devname) {
+            Some(expr) => observer.add_device_with_filter(dev, expr.clone()),
+            None => observer.add_device(dev),
+        }
+    }
+    for path in settings.captures.iter() {
+        observer.add_file(path.clone());
+    }
+    if let Some(every) = settings.snapshot_interval {
+        observer.set_snapshot_interval(Duration::from_secs_f64(every));
+    }
+    let keepalive = settings.filters.keepalive as u32;
+    observer.set_filters(settings.filters);
+    if let Some(scan) = settings.scan {
+        observer.detect_scans(scan);
+    }
+    let mut triggers = settings.ring.as_ref().map(|ring| Triggers::new(ring.triggers.clone()));
+    if let Some(ring) = settings.ring {
+        ring::catch_flush_signal();
+        observer.keep_ring(ring);
+    }
+    if settings.metrics_bind.is_some() || settings.statsd.is_some() || settings.stats_interval.is_some() {
+        observer.keep_stats();
+    }
+
+    let ident = settings.ident.unwrap_or_else(|| {
+        gethostname::gethostname().into_string().expect("couldn't encode hostname")
+    });
+    if settings.tags.len() > Hello::MAX_TAGS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} tags given, but a sensor may only have {}", settings.tags.len(), Hello::MAX_TAGS)));
+    }
+    for (key, value) in settings.tags.iter() {
+        Hello::check_tag(key, value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    let observer_ident = ident.clone();
+    let mut client = ClientConfig::new(ident.clone());
+    client.set_keepalive(keepalive);
+    #[cfg(feature = "sqlite")]
+    let tags = settings.tags.clone();
+    client.set_tags(settings.tags);
+    for addr in settings.remotes {
+        client.add(addr);
+    }
+    if let Some(name) = settings.remotes_srv {
+        let resolver = UdpResolver::new(UdpResolver::system_server()?)?;
+        client.set_srv(name, Box::new(resolver));
+    }
+
+    #[cfg(feature = "sqlite")]
+    let local = match &settings.local_db {
+        Some(database) => {
+            let mut local = LocalStore::open(&ServerSettings { database: database.clone(), ..Default::default() }, &ident)?;
+            local.send(&Hello { agent: Hello::AGENT.to_string(), keepalive: Some(keepalive), tags });
+            Some(local)
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "sqlite"))]
+    assert!(settings.local_db.is_none(), "local database given, but glosco was built without the sqlite feature");
+
+    let mut client = Outlets {
+        client: client.build()?,
+        #[cfg(feature = "sqlite")]
+        local,
+        #[cfg(feature = "mesh")]
+        ident,
+        #[cfg(feature = "mesh")]
+        mesh,
+    };
+
+    let mut observer = observer.start().map_err(|e| match e {
+        StartError::BadFilter(why) => io::Error::new(io::ErrorKind::InvalidInput, format!("bad capture filter {}", why)),
+        StartError::BadCapture(why) => io::Error::new(io::ErrorKind::InvalidInput, format!("unreadable capture file {}", why)),
+        e => io::Error::other(format!("failed to start observer: {:?}", e)),
+    })?;
+
+    if settings.metrics_bind.is_some() || settings.statsd.is_some() {
+        let registry = Registry::default();
+        if let Some(stats) = observer.stats() {
+            registry.add(move |out| InterfaceStats::collect(&stats, out));
+        }
+        let liveness = observer.liveness();
+        if !liveness.is_empty() {
+            registry.add(move |out| Liveness::collect(&liveness, out));
+        }
+        let remotes = client.client.remotes();
+        registry.add(move |out| RemoteStats::collect(&remotes.stats(), out));
+        registry.add_process();
+        if let Some(bind) = settings.metrics_bind {
+            registry.serve(bind)?;
+        }
+        if let Some(addr) = settings.statsd {
+            let statsd = Statsd::new(addr, settings.statsd_prefix, settings.statsd_tags)?;
+            registry.emit_to(statsd, Duration::from_secs_f64(settings.statsd_interval));
+        }
+    }
+
+    let once = settings.once;
+    let stats_interval = settings.stats_interval.map(Duration::from_secs_f64);
+    let watchdog = settings.watchdog.map(|quiet| (Duration::from_secs_f64(quiet), settings.watchdog_recovery));
+    let flush_timeout = Duration::from_secs_f64(settings.flush_timeout);
+    let shutdown: Arc<AtomicBool> = Arc::default();
+    let stop = shutdown.clone();
+    let thread = thread::spawn(move || {
+        // The one announcement a sensor makes; see `Namespace::generation`
+        client.send(&Namespace { generation: 0, as_of: SystemTime::now(), interfaces: observer.interfaces() });
+
+        let mut next_stats = stats_interval.map(|every| Instant::now() + every);
+        let stopped = loop {
+            if stop.load(Ordering::SeqCst) {
+                break true;
+            }
+            if let (Some(due), Some(every), Some(stats)) = (next_stats, stats_interval, observer.stats()) {
+                if Instant::now() >= due {
+                    client.send(&InterfaceStats::counts(&stats, SystemTime::now()));
+                    next_stats = Some(due + every);
+                }
+            }
+            if let Some((quiet, recovery)) = watchdog {
+                observer.watch(quiet, recovery);
+            }
+            if ring::flush_requested() {
+                observer.flush_ring("SIGUSR2");
+            }
+            match observer.next_batch_timeout(POLL) {
+                Ok(Batch::Messages(bundle)) => {
+                    for message in bundle.iter() {
+                        if let Some(line) = format.message(message) {
+                            println!("{}", line);
+                        }
+                        if let Some(rule) = triggers.as_mut().and_then(|triggers| triggers.fire(&observer_ident, message)) {
+                            observer.flush_ring(&format!("trigger {}", rule));
+                        }
+                    }
+                    client.send_bundle(&bundle);
+                },
+                Ok(Batch::Snapshot(snapshot)) => {
+                    if let Some(line) = format.snapshot(&snapshot) {
+                        println!("{}", line);
+                    }
+                    client.send(&snapshot);
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break false,
+            }
+        };
+        if !stopped && !once {
+            return true;
+        }
+        // Nothing more will be heard from whatever's still open. Shut down, that's as of now;
+        // out of capture, it's as of the last packet for it.
+        let now = SystemTime::now();
+        let ended: Vec<Message> = observer.current_states().into_iter().map(|open| {
+            let mut state = *open.state();
+            if stopped {
+                state.as_of = now;
+            }
+            let message = Message::Ended(state, Closed::TimedOut);
+            if let Some(line) = format.message(&message) {
+                println!("{}", line);
+            }
+            message
+        }).collect();
+        client.send_bundle(&ended);
+        client.finish(flush_timeout)
+    });
+    Ok(ClientHandle { shutdown, thread })
+}
+
+/// Capture on the requested interfaces and send everything observed to the remotes, forever.
+pub fn run(args: ClientArgs) {
+    if args.list_interfaces {
+        for dev in Device::list().expect("failed to list interfaces") {
+            println!("{}", observe::describe_interface(&dev));
+        }
+        return;
+    }
+    let pidfile = daemon::daemonize(&args.daemon);
+    if args.tui {
+        return tui::run(args);
+    }
+    let client = start(args.resolve()).unwrap_or_else(|e| panic!("failed to start: {}", e));
+    daemon::catch_termination();
+    while !client.is_finished() {
+        if daemon::terminating() {
+            client.shutdown();
+            break;
+        }
+        thread::sleep(POLL);
+    }
+    let delivered = client.join();
+    drop(pidfile);
+    if !delivered {
+        println!("not everything observed reached the collectors");
+        std::process::exit(1);
+    }
+}
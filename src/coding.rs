@@ -1,6 +1,70 @@
-use std::{io::{Write, Read, self, ErrorKind, Error}, net::{Ipv4Addr, Ipv6Addr, IpAddr}, array, time::{SystemTime, Duration}, marker::PhantomData};
+use std::{io::{Write, Read, self, ErrorKind, Error}, net::{Ipv4Addr, Ipv6Addr, IpAddr, SocketAddr}, array, time::{SystemTime, Duration}, marker::PhantomData};
 
-use crate::observe::{Protocol, Closed, Problem, State, Connection, Endpoint, Message, Resolution, Name};
+use crate::observe::{Protocol, Closed, Problem, State, Connection, Endpoint, Message, Resolution, Name, Snapshot, Stats, InterfaceCounts, Namespace, InterfaceInfo, InterfaceAddress};
+use crate::scan::{Scan, ScanKind};
+use crate::alert::Kind;
+use crate::filter::{Cidr, Glob};
+use crate::subscribe::{Envelope, Subscribe};
+use crate::remote::{Query, QueryActive, QueryConnections, QueryRequest, QueryResponse};
+use crate::sync::{Hello, Relayed, Sequence};
+#[cfg(feature = "mesh")]
+use crate::mesh::{Announce, Envelope as MeshEnvelope, Probe};
+
+/// `#[derive(Coder)]`: a struct's fields in the order they're declared, or an enum's variant as
+/// the mark given it with `#[mark = N]` followed by its fields.
+///
+/// ```
+/// use glosco::coding::Coder;
+///
+/// #[derive(Debug, PartialEq, Coder)]
+/// enum Shape {
+///     #[mark = 1]
+///     Dot,
+///     #[mark = 2]
+///     Line(u16),
+/// }
+///
+/// let mut bytes = Vec::new();
+/// Shape::Line(0x0102).encode(&mut bytes).unwrap();
+/// assert_eq!(bytes, [2, 1, 2]);
+/// assert_eq!(Shape::decode(&mut &bytes[..]).unwrap(), Shape::Line(0x0102));
+/// ```
+///
+/// A variant without a mark doesn't compile:
+///
+/// ```compile_fail
+/// #[derive(glosco::coding::Coder)]
+/// enum Shape {
+///     #[mark = 1]
+///     Dot,
+///     Line(u16),
+/// }
+/// ```
+///
+/// Nor do two with the same one, however it's written:
+///
+/// ```compile_fail
+/// #[derive(glosco::coding::Coder)]
+/// enum Shape {
+///     #[mark = 1]
+///     Dot,
+///     #[mark = 0x01]
+///     Line(u16),
+/// }
+/// ```
+///
+/// Nor a mark that won't fit in its byte:
+///
+/// ```compile_fail
+/// #[derive(glosco::coding::Coder)]
+/// enum Shape {
+///     #[mark = 1]
+///     Dot,
+///     #[mark = 256]
+///     Line(u16),
+/// }
+/// ```
+pub use glosco_coding_derive::Coder;
 
 pub trait Coder: Sized {
     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
@@ -25,6 +89,27 @@ pub const ACTIVE_MARK: u8 = 1;
 pub const ENDED_MARK: u8 = 2;
 pub const FAILED_MARK: u8 = 3;
 pub const NAME_MARK: u8 = 4;
+// Shares the message mark space, so older servers simply fail to decode it as a Message
+pub const HELLO_MARK: u8 = 6;
+pub const SUBSCRIBE_MARK: u8 = 7;
+pub const RELAYED_MARK: u8 = 8;
+pub const QUERY_MARK: u8 = 9;
+pub const SNAPSHOT_MARK: u8 = 10;
+pub const ANNOUNCE_MARK: u8 = 11;
+pub const PROBE_ASK_MARK: u8 = 12;
+pub const PROBE_ANSWER_MARK: u8 = 13;
+// A message, numbered after the rest; older servers fail to decode it and drop it
+pub const SCAN_MARK: u8 = 14;
+// A Failed message standing for repeats too; older servers drop it, keeping only the first
+pub const REPEATED_MARK: u8 = 15;
+// An Active message carrying its handshake's round trip; older servers drop it
+pub const RTT_MARK: u8 = 16;
+// Numbers the frames a client sends after it; older servers fail to decode it and drop it
+pub const SEQUENCE_MARK: u8 = 17;
+// How capture's going, sent on its own like a snapshot; older servers drop it
+pub const STATS_MARK: u8 = 18;
+// The interfaces a client captures on, sent on its own like stats; older servers drop it
+pub const NAMESPACE_MARK: u8 = 19;
 pub const ADDR_MARK: u8 = 1;
 pub const ALIAS_MARK: u8 = 2;
 pub const SVC_MARK: u8 = 3;
@@ -180,6 +265,29 @@ impl Coder for u64 {
     }
 }
 
+impl Coder for u128 {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        ((*self >> 64) as u64).encode(writer)?;
+        (*self as u64).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let hi = u64::decode(reader)?;
+        let lo = u64::decode(reader)?;
+        Ok(((hi as Self) << 64) | (lo as Self))
+    }
+}
+
+impl Coder for f64 {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.to_bits().encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(Self::from_bits(u64::decode(reader)?))
+    }
+}
+
 impl Protocol {
     pub fn number(&self) -> u8 {
         match self {
@@ -216,23 +324,21 @@ impl Closed {
     }
 }
 
-impl Coder for Closed {
+// Nor is a handshake's round trip part of the state; an Active message carries it after it
+impl Coder for State {
     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        writer.write_all(&[self.number()])
+        self.as_of.encode(writer)?;
+        self.connection.encode(writer)
     }
 
     fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
-        let mut mark: u8 = 0;
-        reader.read_exact(array::from_mut(&mut mark))?;
-        match mark {
-            NORMAL_MARK => Ok(Self::Normally),
-            RESET_MARK => Ok(Self::Reset),
-            CLESS_MARK => Ok(Self::Connectionless),
-            _ => Err(ErrorKind::InvalidInput.into()),
-        }
+        let as_of = SystemTime::decode(reader)?;
+        let connection = Connection::decode(reader)?;
+        Ok(Self { as_of, connection, rtt_micros: None })
     }
 }
 
+// Repeats aren't part of the problem on the wire; a Failed message carries them after it
 impl Coder for Problem {
     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         self.kind.encode(writer)?;
@@ -242,7 +348,25 @@ impl Coder for Problem {
     fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
         let kind = u8::decode(reader)?;
         let code = u8::decode(reader)?;
-        Ok(Self { kind, code })
+        Ok(Self { kind, code, repeats: 0 })
+    }
+}
+
+impl Coder for Closed {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[self.number()])
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        match mark {
+            NORMAL_MARK => Ok(Self::Normally),
+            RESET_MARK => Ok(Self::Reset),
+            TMOUT_MARK => Ok(Self::TimedOut),
+            CLESS_MARK => Ok(Self::Connectionless),
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
     }
 }
 
@@ -263,16 +387,54 @@ impl Coder for SystemTime {
     }
 }
 
-impl Coder for Endpoint {
+impl Coder for Duration {
     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        self.addr.encode(writer)?;
-        self.port.encode(writer)
+        self.as_secs().encode(writer)?;
+        self.subsec_nanos().encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let secs = u64::decode(reader)?;
+        let nanos = u32::decode(reader)?;
+        if nanos >= 1_000_000_000 {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Ok(Self::new(secs, nanos))
+    }
+}
+
+impl Coder for Scan {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[match self.kind {
+            ScanKind::Ports => 1,
+            ScanKind::Hosts => 2,
+        }])?;
+        self.count.encode(writer)?;
+        self.window.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let kind = match u8::decode(reader)? {
+            1 => ScanKind::Ports,
+            2 => ScanKind::Hosts,
+            _ => return Err(ErrorKind::InvalidInput.into()),
+        };
+        let count = u32::decode(reader)?;
+        let window = Duration::decode(reader)?;
+        Ok(Self { kind, count, window })
+    }
+}
+
+impl Coder for SocketAddr {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.ip().encode(writer)?;
+        self.port().encode(writer)
     }
 
     fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
         let addr = IpAddr::decode(reader)?;
         let port = u16::decode(reader)?;
-        Ok(Self { addr, port })
+        Ok(Self::new(addr, port))
     }
 }
 
@@ -293,19 +455,6 @@ impl Coder for Connection {
     }
 }
 
-impl Coder for State {
-    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        self.as_of.encode(writer)?;
-        self.connection.encode(writer)
-    }
-
-    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
-        let as_of = SystemTime::decode(reader)?;
-        let connection = Connection::decode(reader)?;
-        Ok(Self { as_of, connection })
-    }
-}
-
 impl Resolution {
     pub fn number(&self) -> u8 {
         match self {
@@ -374,25 +523,43 @@ impl Coder for Message {
                 writer.write_all(&[START_MARK])?;
                 state.encode(writer)
             },
-            Self::Active(state) => {
-                writer.write_all(&[ACTIVE_MARK])?;
-                state.encode(writer)
+            Self::Active(state) => match state.rtt_micros {
+                None => {
+                    writer.write_all(&[ACTIVE_MARK])?;
+                    state.encode(writer)
+                },
+                Some(rtt) => {
+                    writer.write_all(&[RTT_MARK])?;
+                    state.encode(writer)?;
+                    rtt.encode(writer)
+                },
             },
             Self::Ended(state, closed) => {
                 writer.write_all(&[ENDED_MARK])?;
                 state.encode(writer)?;
                 closed.encode(writer)
             },
-            Self::Failed(state, problem) => {
+            Self::Failed(state, problem) if problem.repeats == 0 => {
                 writer.write_all(&[FAILED_MARK])?;
                 state.encode(writer)?;
                 problem.encode(writer)
             },
+            Self::Failed(state, problem) => {
+                writer.write_all(&[REPEATED_MARK])?;
+                state.encode(writer)?;
+                problem.encode(writer)?;
+                problem.repeats.encode(writer)
+            },
             Self::Name(state, names) => {
                 writer.write_all(&[NAME_MARK])?;
                 state.encode(writer)?;
                 CodingVec::<Name, u8>::new(names.clone()).encode(writer)
-            }
+            },
+            Self::Scan(state, scan) => {
+                writer.write_all(&[SCAN_MARK])?;
+                state.encode(writer)?;
+                scan.encode(writer)
+            },
         }
     }
 
@@ -408,6 +575,11 @@ impl Coder for Message {
                 let state = State::decode(reader)?;
                 Ok(Self::Active(state))
             },
+            RTT_MARK => {
+                let state = State::decode(reader)?;
+                let rtt = u32::decode(reader)?;
+                Ok(Self::Active(State { rtt_micros: Some(rtt), ..state }))
+            },
             ENDED_MARK => {
                 let state = State::decode(reader)?;
                 let closed = Closed::decode(reader)?;
@@ -418,10 +590,445 @@ impl Coder for Message {
                 let problem = Problem::decode(reader)?;
                 Ok(Self::Failed(state, problem))
             },
+            REPEATED_MARK => {
+                let state = State::decode(reader)?;
+                let problem = Problem::decode(reader)?;
+                let repeats = u32::decode(reader)?;
+                Ok(Self::Failed(state, Problem { repeats, ..problem }))
+            },
             NAME_MARK => {
                 let state = State::decode(reader)?;
                 Ok(Self::Name(state, CodingVec::<Name, u8>::decode(reader)?.0))
             },
+            SCAN_MARK => {
+                let state = State::decode(reader)?;
+                let scan = Scan::decode(reader)?;
+                Ok(Self::Scan(state, scan))
+            },
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl Coder for Hello {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[HELLO_MARK])?;
+        self.agent.encode(writer)?;
+        self.keepalive.encode(writer)?;
+        CodingVec::<(String, String), u8>::new(self.tags.clone().into_iter().collect()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != HELLO_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let agent = String::decode(reader)?;
+        let keepalive = Option::<u32>::decode(reader)?;
+        // Hellos from before tags end here
+        let mut count: u8 = 0;
+        let tags = match reader.read(array::from_mut(&mut count))? {
+            0 => Vec::new(),
+            _ => CodingVec::<(String, String), u8>::decode(&mut (&[count][..]).chain(reader))?.0,
+        };
+        Ok(Self { agent, keepalive, tags: tags.into_iter().collect() })
+    }
+}
+
+impl Coder for Relayed {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[RELAYED_MARK])?;
+        self.ident.encode(writer)?;
+        self.message.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != RELAYED_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let ident = String::decode(reader)?;
+        let message = Message::decode(reader)?;
+        Ok(Self { ident, message })
+    }
+}
+
+impl Coder for InterfaceCounts {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.name.encode(writer)?;
+        self.packets.encode(writer)?;
+        self.dropped.encode(writer)?;
+        self.if_dropped.encode(writer)?;
+        self.unparsed.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            packets: u64::decode(reader)?,
+            dropped: u64::decode(reader)?,
+            if_dropped: u64::decode(reader)?,
+            unparsed: u64::decode(reader)?,
+        })
+    }
+}
+
+impl Coder for Stats {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[STATS_MARK])?;
+        self.as_of.encode(writer)?;
+        CodingVec::<InterfaceCounts, u16>::new(self.interfaces.clone()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != STATS_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let as_of = SystemTime::decode(reader)?;
+        let interfaces = CodingVec::<InterfaceCounts, u16>::decode(reader)?.0;
+        Ok(Self { as_of, interfaces })
+    }
+}
+
+impl Coder for InterfaceAddress {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.addr.encode(writer)?;
+        self.prefix.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            addr: IpAddr::decode(reader)?,
+            prefix: Option::<u8>::decode(reader)?,
+        })
+    }
+}
+
+impl Coder for InterfaceInfo {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.name.encode(writer)?;
+        self.flags.encode(writer)?;
+        self.mac.encode(writer)?;
+        self.mtu.encode(writer)?;
+        CodingVec::<InterfaceAddress, u16>::new(self.addresses.clone()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            flags: u32::decode(reader)?,
+            mac: Option::<String>::decode(reader)?,
+            mtu: Option::<u32>::decode(reader)?,
+            addresses: CodingVec::<InterfaceAddress, u16>::decode(reader)?.0,
+        })
+    }
+}
+
+impl Coder for Namespace {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[NAMESPACE_MARK])?;
+        self.generation.encode(writer)?;
+        self.as_of.encode(writer)?;
+        CodingVec::<InterfaceInfo, u16>::new(self.interfaces.clone()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != NAMESPACE_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let generation = u32::decode(reader)?;
+        let as_of = SystemTime::decode(reader)?;
+        let interfaces = CodingVec::<InterfaceInfo, u16>::decode(reader)?.0;
+        Ok(Self { generation, as_of, interfaces })
+    }
+}
+
+impl Coder for Sequence {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[SEQUENCE_MARK])?;
+        self.next.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != SEQUENCE_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        Ok(Self { next: u64::decode(reader)? })
+    }
+}
+
+impl Coder for Snapshot {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[SNAPSHOT_MARK])?;
+        self.as_of.encode(writer)?;
+        CodingVec::<Message, u32>::new(self.states.clone()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != SNAPSHOT_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let as_of = SystemTime::decode(reader)?;
+        let states = CodingVec::<Message, u32>::decode(reader)?.0;
+        Ok(Self { as_of, states })
+    }
+}
+
+#[cfg(feature = "mesh")]
+impl Coder for MeshEnvelope {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.id.encode(writer)?;
+        self.hops.encode(writer)?;
+        self.ident.encode(writer)?;
+        CodingVec::<u8, u32>::new(self.payload.clone()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let id = u128::decode(reader)?;
+        let hops = u8::decode(reader)?;
+        let ident = String::decode(reader)?;
+        let payload = CodingVec::<u8, u32>::decode(reader)?.0;
+        Ok(Self { id, hops, ident, payload })
+    }
+}
+
+#[cfg(feature = "mesh")]
+impl Coder for Announce {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[ANNOUNCE_MARK])?;
+        self.version.encode(writer)?;
+        self.instance.encode(writer)?;
+        CodingVec::<SocketAddr, u8>::new(self.listens.clone()).encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != ANNOUNCE_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let version = u8::decode(reader)?;
+        let instance = u128::decode(reader)?;
+        let listens = CodingVec::<SocketAddr, u8>::decode(reader)?.0;
+        Ok(Self { version, instance, listens })
+    }
+}
+
+#[cfg(feature = "mesh")]
+impl Coder for Probe {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Ask { probe, asker } => {
+                writer.write_all(&[PROBE_ASK_MARK])?;
+                probe.encode(writer)?;
+                asker.encode(writer)
+            },
+            Self::Answer { probe, asker, instance, hops } => {
+                writer.write_all(&[PROBE_ANSWER_MARK])?;
+                probe.encode(writer)?;
+                asker.encode(writer)?;
+                instance.encode(writer)?;
+                hops.encode(writer)
+            },
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        match mark {
+            PROBE_ASK_MARK => {
+                let probe = u128::decode(reader)?;
+                let asker = u128::decode(reader)?;
+                Ok(Self::Ask { probe, asker })
+            },
+            PROBE_ANSWER_MARK => {
+                let probe = u128::decode(reader)?;
+                let asker = u128::decode(reader)?;
+                let instance = u128::decode(reader)?;
+                let hops = u8::decode(reader)?;
+                Ok(Self::Answer { probe, asker, instance, hops })
+            },
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl Coder for Kind {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[match self {
+            Self::Starting => 1,
+            Self::Active => 2,
+            Self::Ended => 3,
+            Self::Reset => 4,
+            Self::Failed => 5,
+            Self::Name => 6,
+            Self::Scan => 7,
+        }])
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            1 => Ok(Self::Starting),
+            2 => Ok(Self::Active),
+            3 => Ok(Self::Ended),
+            4 => Ok(Self::Reset),
+            5 => Ok(Self::Failed),
+            6 => Ok(Self::Name),
+            7 => Ok(Self::Scan),
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl Coder for Subscribe {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[SUBSCRIBE_MARK])?;
+        CodingVec::<String, u16>::new(self.idents.iter().map(|glob| glob.0.clone()).collect()).encode(writer)?;
+        CodingVec::<Kind, u8>::new(self.kinds.clone()).encode(writer)?;
+        self.since.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != SUBSCRIBE_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let idents = CodingVec::<String, u16>::decode(reader)?.0.into_iter().map(Glob).collect();
+        let kinds = CodingVec::<Kind, u8>::decode(reader)?.0;
+        // Subscriptions from before resuming end here
+        let since = trailing(reader)?;
+        Ok(Self { idents, kinds, since })
+    }
+}
+
+impl Coder for Envelope {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.ident.encode(writer)?;
+        self.message.encode(writer)?;
+        self.seq.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let ident = String::decode(reader)?;
+        let message = Message::decode(reader)?;
+        // As do envelopes from before they were numbered
+        let seq = trailing(reader)?;
+        Ok(Self { ident, message, seq })
+    }
+}
+
+impl Coder for Cidr {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.addr.encode(writer)?;
+        self.prefix.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let addr = IpAddr::decode(reader)?;
+        let prefix = u8::decode(reader)?;
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Coder for QueryRequest {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[QUERY_MARK])?;
+        self.token.encode(writer)?;
+        self.query.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut mark: u8 = 0;
+        reader.read_exact(array::from_mut(&mut mark))?;
+        if mark != QUERY_MARK {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let token = String::decode(reader)?;
+        let query = Query::decode(reader)?;
+        Ok(Self { token, query })
+    }
+}
+
+impl Coder for Query {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Active(query) => {
+                writer.write_all(&[1])?;
+                query.ident.encode(writer)?;
+                query.host.encode(writer)?;
+                query.port.encode(writer)?;
+                query.limit.encode(writer)
+            },
+            Self::Connections(query) => {
+                writer.write_all(&[2])?;
+                query.ident.encode(writer)?;
+                query.host.encode(writer)?;
+                query.port.encode(writer)?;
+                query.proto.encode(writer)?;
+                query.since.encode(writer)?;
+                query.until.encode(writer)?;
+                query.limit.encode(writer)
+            },
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            1 => Ok(Self::Active(QueryActive {
+                ident: Option::<String>::decode(reader)?,
+                host: Option::<Cidr>::decode(reader)?,
+                port: Option::<u16>::decode(reader)?,
+                limit: u32::decode(reader)?,
+            })),
+            2 => Ok(Self::Connections(QueryConnections {
+                ident: Option::<String>::decode(reader)?,
+                host: Option::<Cidr>::decode(reader)?,
+                port: Option::<u16>::decode(reader)?,
+                proto: Option::<Protocol>::decode(reader)?,
+                since: f64::decode(reader)?,
+                until: f64::decode(reader)?,
+                limit: u32::decode(reader)?,
+            })),
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl Coder for QueryResponse {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Rows { rows, truncated } => {
+                writer.write_all(&[1])?;
+                CodingVec::<String, u32>::new(rows.clone()).encode(writer)?;
+                (*truncated as u8).encode(writer)
+            },
+            Self::Refused(reason) => {
+                writer.write_all(&[2])?;
+                reason.encode(writer)
+            },
+        }
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            1 => {
+                let rows = CodingVec::<String, u32>::decode(reader)?.0;
+                let truncated = u8::decode(reader)? != 0;
+                Ok(Self::Rows { rows, truncated })
+            },
+            2 => Ok(Self::Refused(String::decode(reader)?)),
             _ => Err(ErrorKind::InvalidInput.into()),
         }
     }
@@ -437,6 +1044,26 @@ impl Coder for String {
     }
 }
 
+impl<A: Coder, B: Coder> Coder for (A, B) {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.0.encode(writer)?;
+        self.1.encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok((A::decode(reader)?, B::decode(reader)?))
+    }
+}
+
+/// An optional field added to the end of a frame, `None` if the frame ends before it.
+fn trailing<T: Coder, R: Read>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut mark: u8 = 0;
+    match reader.read(array::from_mut(&mut mark))? {
+        0 => Ok(None),
+        _ => Option::<T>::decode(&mut (&[mark][..]).chain(reader)),
+    }
+}
+
 impl<T: Coder> Coder for Option<T> {
     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         if let Some(inner) = self {
@@ -474,3 +1101,265 @@ impl<T: Coder, Width: Length> Coder for CodingVec<T, Width> {
         })?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded<T: Coder>(value: &T) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        value.encode(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// `value` encodes as `bytes`, and `bytes` decodes back to it, leaving nothing over.
+    fn golden<T: Coder + PartialEq + std::fmt::Debug>(value: T, bytes: &[u8]) {
+        assert_eq!(encoded(&value), bytes, "{:?}", value);
+        let mut reader = bytes;
+        assert_eq!(T::decode(&mut reader).unwrap(), value);
+        assert!(reader.is_empty(), "{:?} left {:?}", value, reader);
+    }
+
+    #[test]
+    fn endpoint_bytes() {
+        golden(Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port: 443 }, &[
+            V4_MARK, 10, 0, 0, 1,
+            0x01, 0xbb,
+        ]);
+        golden(Endpoint { addr: IpAddr::V6(Ipv6Addr::LOCALHOST), port: 0xfffe }, &[
+            V6_MARK, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+            0xff, 0xfe,
+        ]);
+    }
+
+    #[test]
+    fn problem_bytes() {
+        golden(Problem { kind: 3, code: 13, repeats: 0 }, &[3, 13]);
+        // Repeats go with the Failed message, not the problem
+        assert_eq!(encoded(&Problem { kind: 3, code: 13, repeats: 7 }), [3, 13]);
+    }
+
+    #[test]
+    fn state_bytes() {
+        let state = State {
+            as_of: SystemTime::UNIX_EPOCH + Duration::new(0x0102_0304, 0x0506_0708),
+            connection: Connection {
+                interface: 2,
+                src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), port: 40000 },
+                dst: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 53)), port: 53 },
+                protocol: Protocol::Udp,
+            },
+            rtt_micros: None,
+        };
+        golden(state, &[
+            // Seconds, then nanoseconds
+            0, 0, 0, 0, 1, 2, 3, 4,
+            5, 6, 7, 8,
+            // Interface
+            0, 2,
+            V4_MARK, 192, 168, 1, 2, 0x9c, 0x40,
+            V4_MARK, 10, 0, 0, 53, 0, 53,
+            UDP_MARK,
+        ]);
+        // The round trip goes with the Active message, not the state
+        assert_eq!(encoded(&State { rtt_micros: Some(1500), ..state }), encoded(&state));
+    }
+
+    #[test]
+    fn an_active_carries_its_round_trip_under_a_mark_of_its_own() {
+        let state = State {
+            as_of: SystemTime::UNIX_EPOCH,
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port: 40000 },
+                dst: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), port: 443 },
+                protocol: Protocol::Tcp,
+            },
+            rtt_micros: None,
+        };
+        let plain = encoded(&state);
+        let mut active = vec![ACTIVE_MARK];
+        active.extend_from_slice(&plain);
+        golden(Message::Active(state), &active);
+        let mut timed = vec![RTT_MARK];
+        timed.extend_from_slice(&plain);
+        timed.extend_from_slice(&[0, 0, 0x30, 0xd4]);
+        golden(Message::Active(State { rtt_micros: Some(12500), ..state }), &timed);
+    }
+
+    #[test]
+    fn every_way_of_closing_decodes() {
+        for (closed, mark) in [(Closed::Normally, NORMAL_MARK), (Closed::Reset, RESET_MARK), (Closed::TimedOut, TMOUT_MARK), (Closed::Connectionless, CLESS_MARK)] {
+            golden(closed, &[mark]);
+        }
+    }
+
+    #[test]
+    fn sequence_bytes() {
+        golden(Sequence { next: 0x0102_0304_0506_0708 }, &[SEQUENCE_MARK, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn subscribe_bytes() {
+        golden(Subscribe { idents: vec![Glob("web*".to_string())], kinds: vec![Kind::Failed], since: Some(0x0102) }, &[
+            SUBSCRIBE_MARK,
+            0, 1, 0, 4, b'w', b'e', b'b', b'*',
+            1, 5,
+            1, 0, 0, 0, 0, 0, 0, 1, 2,
+        ]);
+        // From before resuming, and so live only
+        let mut reader = &[SUBSCRIBE_MARK, 0, 0, 0][..];
+        assert_eq!(Subscribe::decode(&mut reader).unwrap(), Subscribe::default());
+    }
+
+    #[test]
+    fn an_envelope_from_before_numbering_has_no_seq() {
+        let state = State {
+            as_of: SystemTime::UNIX_EPOCH,
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port: 40000 },
+                dst: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), port: 443 },
+                protocol: Protocol::Tcp,
+            },
+            rtt_micros: None,
+        };
+        let envelope = Envelope { ident: "web".to_string(), message: Message::Starting(state), seq: Some(7) };
+        let numbered = encoded(&envelope);
+        golden(envelope.clone(), &numbered);
+        let unnumbered = &numbered[.. numbered.len() - 9];
+        assert_eq!(Envelope::decode(&mut &unnumbered[..]).unwrap(), Envelope { seq: None, ..envelope });
+    }
+
+    #[test]
+    fn stats_bytes() {
+        golden(Stats {
+            as_of: SystemTime::UNIX_EPOCH + Duration::new(0x0102_0304, 0),
+            interfaces: vec![InterfaceCounts { name: "eth0".to_string(), packets: 1000, dropped: 5, if_dropped: 1, unparsed: 2 }],
+        }, &[
+            STATS_MARK,
+            0, 0, 0, 0, 1, 2, 3, 4,
+            0, 0, 0, 0,
+            // One interface
+            0, 1,
+            0, 4, b'e', b't', b'h', b'0',
+            0, 0, 0, 0, 0, 0, 0x03, 0xe8,
+            0, 0, 0, 0, 0, 0, 0, 5,
+            0, 0, 0, 0, 0, 0, 0, 1,
+            0, 0, 0, 0, 0, 0, 0, 2,
+        ]);
+    }
+
+    #[test]
+    fn namespace_bytes() {
+        golden(Namespace {
+            generation: 1,
+            as_of: SystemTime::UNIX_EPOCH + Duration::new(0x0102_0304, 0),
+            interfaces: vec![InterfaceInfo {
+                name: "eth0".to_string(),
+                flags: 0b110,
+                mac: Some("52:54:00:12:34:56".to_string()),
+                mtu: None,
+                addresses: vec![
+                    InterfaceAddress { addr: IpAddr::V4(Ipv4Addr::new(10, 3, 0, 9)), prefix: Some(24) },
+                    InterfaceAddress { addr: IpAddr::V6(Ipv6Addr::LOCALHOST), prefix: None },
+                ],
+            }],
+        }, &[
+            NAMESPACE_MARK,
+            0, 0, 0, 1,
+            0, 0, 0, 0, 1, 2, 3, 4,
+            0, 0, 0, 0,
+            // One interface
+            0, 1,
+            0, 4, b'e', b't', b'h', b'0',
+            0, 0, 0, 0b110,
+            // A link-layer address, but no MTU
+            1, 0, 17, b'5', b'2', b':', b'5', b'4', b':', b'0', b'0', b':', b'1', b'2', b':', b'3', b'4', b':', b'5', b'6',
+            0,
+            // Two addresses, with and without a netmask
+            0, 2,
+            V4_MARK, 10, 3, 0, 9, 1, 24,
+            V6_MARK, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0,
+        ]);
+    }
+
+    #[test]
+    fn a_namespace_with_no_interfaces_round_trips() {
+        golden(Namespace { generation: 0, as_of: SystemTime::UNIX_EPOCH, interfaces: Vec::new() }, &[
+            NAMESPACE_MARK, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn query_bytes() {
+        golden(QueryRequest {
+            token: "key".to_string(),
+            query: Query::Active(QueryActive {
+                ident: Some("web".to_string()),
+                host: Some("10.0.0.0/8".parse().unwrap()),
+                port: None,
+                limit: 100,
+            }),
+        }, &[
+            QUERY_MARK,
+            0, 3, b'k', b'e', b'y',
+            // Active, then each filter present or not
+            1,
+            1, 0, 3, b'w', b'e', b'b',
+            1, V4_MARK, 10, 0, 0, 0, 8,
+            0,
+            0, 0, 0, 100,
+        ]);
+    }
+
+    #[test]
+    fn queries_and_responses_round_trip() {
+        for query in [
+            Query::Active(QueryActive { ident: None, host: None, port: Some(443), limit: 0 }),
+            Query::Connections(QueryConnections {
+                ident: Some("web".to_string()),
+                host: Some("2001:db8::/32".parse().unwrap()),
+                port: Some(5432),
+                proto: Some(Protocol::Udp),
+                since: 1000.25,
+                until: 2000.5,
+                limit: u32::MAX,
+            }),
+            Query::Connections(QueryConnections { ident: None, host: None, port: None, proto: None, since: 0.0, until: 0.0, limit: 1 }),
+        ] {
+            let request = QueryRequest { token: String::new(), query };
+            let bytes = encoded(&request);
+            let mut reader = &bytes[..];
+            assert_eq!(QueryRequest::decode(&mut reader).unwrap(), request);
+            assert!(reader.is_empty());
+        }
+        for response in [
+            QueryResponse::Rows { rows: Vec::new(), truncated: false },
+            QueryResponse::Rows { rows: vec!["{\"srcport\":1}".to_string(), "{}".to_string()], truncated: true },
+            QueryResponse::Refused("wrong token".to_string()),
+        ] {
+            let bytes = encoded(&response);
+            let mut reader = &bytes[..];
+            assert_eq!(QueryResponse::decode(&mut reader).unwrap(), response);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn a_query_isnt_anything_else() {
+        // A hello's mark where a query's should be
+        let mut bytes = encoded(&QueryRequest { token: String::new(), query: Query::Active(QueryActive { ident: None, host: None, port: None, limit: 1 }) });
+        bytes[0] = HELLO_MARK;
+        assert_eq!(QueryRequest::decode(&mut &bytes[..]).unwrap_err().kind(), ErrorKind::InvalidInput);
+        // Neither kind of query, nor of response
+        assert_eq!(Query::decode(&mut &[3u8][..]).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(QueryResponse::decode(&mut &[0u8][..]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn an_unknown_mark_is_invalid_input() {
+        let bytes = [9, 10, 0, 0, 1, 0, 80];
+        assert_eq!(Endpoint::decode(&mut &bytes[..]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,306 @@
+use std::{fmt::{self, Display, Formatter}, fs, io, path::{Path, PathBuf}};
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::{ffi::OsStrExt, io::AsRawFd}, sync::{atomic::{AtomicBool, Ordering}, OnceLock}};
+
+use crate::cli::DaemonArgs;
+
+/// Why a pidfile couldn't be taken.
+#[derive(Debug)]
+pub enum PidfileError {
+    /// Another process by the pid it names is still running.
+    Running(PathBuf, u32),
+    Io(PathBuf, io::Error),
+}
+
+impl Display for PidfileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Running(path, pid) => write!(f, "{:?} names pid {}, which is still running", path, pid),
+            Self::Io(path, e) => write!(f, "couldn't write {:?}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for PidfileError {}
+
+/// A file holding this process's pid, removed when dropped.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+    pid: u32,
+}
+
+/// The pid a pidfile names, if it holds one.
+pub fn read_pid(path: &Path) -> io::Result<Option<u32>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text.trim().parse().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a process by `pid` exists, as far as signalling it can tell.
+#[cfg(unix)]
+pub fn alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Safety: signal 0 only checks that the process exists and may be signalled.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // It exists, but belongs to someone else
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn alive(_pid: u32) -> bool {
+    false
+}
+
+impl Pidfile {
+    /// Write this process's pid to `path`, unless it already names a running process. A
+    /// pidfile left behind by a process that's gone (or that holds no pid) is replaced.
+    pub fn create(path: &Path) -> Result<Self, PidfileError> {
+        Self::check(path)?;
+        let pid = std::process::id();
+        fs::write(path, format!("{}\n", pid)).map_err(|e| PidfileError::Io(path.to_path_buf(), e))?;
+        Ok(Self { path: path.to_path_buf(), pid })
+    }
+
+    /// Fail as `create` would for a pidfile that's in use, without writing anything.
+    pub fn check(path: &Path) -> Result<(), PidfileError> {
+        match read_pid(path) {
+            Ok(Some(pid)) if pid != std::process::id() && alive(pid) => Err(PidfileError::Running(path.to_path_buf(), pid)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(PidfileError::Io(path.to_path_buf(), e)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Leave it be if another process has taken it over since
+        if read_pid(&self.path).ok().flatten() == Some(self.pid) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Detach from the terminal the classic way: fork, start a new session, and fork again so the
+/// daemon can never reacquire a terminal, with stdin from /dev/null and stdout and stderr
+/// appended to `log_file` (or to /dev/null). The working directory is kept, since relative
+/// paths in the settings are relative to it. Only the final child returns.
+///
+/// This has to happen before any threads are started; only the calling thread survives a fork.
+#[cfg(unix)]
+pub fn detach(log_file: Option<&Path>) -> io::Result<()> {
+    let log = match log_file {
+        Some(path) => fs::OpenOptions::new().create(true).append(true).open(path)?,
+        None => fs::OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = fs::File::open("/dev/null")?;
+    fork_away()?;
+    // Lead a new session, with no controlling terminal; a fresh child can't already lead one
+    // Safety: setsid takes no pointers and only changes this process's session.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    fork_away()?;
+    // Safety: both descriptors are open files owned by this function for the duration of the calls.
+    unsafe {
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Fork, and let only the child carry on.
+#[cfg(unix)]
+fn fork_away() -> io::Result<()> {
+    // Safety: no other threads are running yet, so the child is a whole copy of this process,
+    // and the parent leaves by `_exit` without running destructors meant for the child.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// The pidfile for the termination handler to remove.
+#[cfg(unix)]
+static PIDFILE: OnceLock<CString> = OnceLock::new();
+
+#[cfg(unix)]
+extern "C" fn on_terminate(_: libc::c_int) {
+    // Safety: unlink and _exit are both async-signal-safe, and the path was set before the
+    // handler was installed.
+    unsafe {
+        if let Some(path) = PIDFILE.get() {
+            libc::unlink(path.as_ptr());
+        }
+        libc::_exit(0);
+    }
+}
+
+/// Do what the daemon arguments ask: detach if `--daemon` is given, then take the pidfile if
+/// `--pidfile` is, and see that SIGTERM and SIGINT remove it on the way out. Keep the pidfile
+/// returned for as long as the process runs. Call this first thing, before any threads are
+/// started.
+#[cfg(unix)]
+pub fn daemonize(args: &DaemonArgs) -> Option<Pidfile> {
+    if let Some(path) = &args.pidfile {
+        // While there's still a terminal to complain to
+        Pidfile::check(path).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if args.daemon {
+        detach(args.log_file.as_deref()).expect("failed to detach");
+    }
+    let pidfile = args.pidfile.as_ref().map(|path| Pidfile::create(path).unwrap_or_else(|e| panic!("{}", e)))?;
+    let path = fs::canonicalize(pidfile.path()).unwrap_or_else(|_| pidfile.path().to_path_buf());
+    PIDFILE.set(CString::new(path.as_os_str().as_bytes()).expect("pidfile path has a NUL in it")).expect("pidfile already taken");
+    // Safety: the handler only makes async-signal-safe calls.
+    unsafe {
+        libc::signal(libc::SIGTERM, on_terminate as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_terminate as *const () as libc::sighandler_t);
+    }
+    Some(pidfile)
+}
+
+/// Set by the first SIGTERM or SIGINT once `catch_termination` has been called.
+#[cfg(unix)]
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_terminate_gently(signal: libc::c_int) {
+    if TERMINATING.swap(true, Ordering::SeqCst) {
+        // Asked twice; don't wait any longer
+        on_terminate(signal);
+    }
+}
+
+/// Have SIGTERM and SIGINT ask the process to wind down rather than ending it: the first only
+/// makes `terminating` true, and a second leaves at once, removing the pidfile as without this.
+/// Call this after `daemonize`.
+#[cfg(unix)]
+pub fn catch_termination() {
+    // Safety: the handler only stores to an atomic, or makes the calls `on_terminate` does.
+    unsafe {
+        libc::signal(libc::SIGTERM, on_terminate_gently as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_terminate_gently as *const () as libc::sighandler_t);
+    }
+}
+
+/// Whether SIGTERM or SIGINT has come since `catch_termination`.
+#[cfg(unix)]
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+#[cfg(not(unix))]
+pub fn catch_termination() {}
+
+#[cfg(not(unix))]
+pub fn terminating() -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(args: &DaemonArgs) -> Option<Pidfile> {
+    assert!(!args.daemon && args.pidfile.is_none(), "--daemon and --pidfile need a Unix system");
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::{Child, Command};
+
+    use crate::test_support::Scratch;
+
+    use super::*;
+
+    /// A process that's come and gone, leaving its pid free.
+    fn dead_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    /// A process that runs until killed, killed when dropped.
+    struct Running(Child);
+
+    impl Running {
+        fn start() -> Self {
+            Self(Command::new("sleep").arg("60").spawn().unwrap())
+        }
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[test]
+    fn a_pidfile_names_this_process_until_dropped() {
+        let scratch = Scratch::file("created.pid");
+        let pidfile = Pidfile::create(scratch.path()).unwrap();
+        assert_eq!(fs::read_to_string(scratch.path()).unwrap(), format!("{}\n", std::process::id()));
+        assert_eq!(read_pid(scratch.path()).unwrap(), Some(std::process::id()));
+        // Taking it again from the same process is no conflict
+        Pidfile::check(scratch.path()).unwrap();
+        drop(pidfile);
+        assert!(!scratch.path().exists());
+        assert_eq!(read_pid(scratch.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn a_pidfile_left_by_a_dead_process_is_taken_over() {
+        let scratch = Scratch::file("stale.pid");
+        let dead = dead_pid();
+        assert!(!alive(dead));
+        fs::write(scratch.path(), format!("{}\n", dead)).unwrap();
+        let _pidfile = Pidfile::create(scratch.path()).unwrap();
+        assert_eq!(read_pid(scratch.path()).unwrap(), Some(std::process::id()));
+
+        // Nor does one that holds no pid stand in the way
+        let scratch = Scratch::file("garbage.pid");
+        fs::write(scratch.path(), "not a pid").unwrap();
+        assert_eq!(read_pid(scratch.path()).unwrap(), None);
+        let _pidfile = Pidfile::create(scratch.path()).unwrap();
+        assert_eq!(read_pid(scratch.path()).unwrap(), Some(std::process::id()));
+    }
+
+    #[test]
+    fn a_pidfile_naming_a_running_process_is_left_be() {
+        let scratch = Scratch::file("running.pid");
+        let running = Running::start();
+        assert!(alive(running.0.id()));
+        fs::write(scratch.path(), format!("{}\n", running.0.id())).unwrap();
+        for result in [Pidfile::check(scratch.path()), Pidfile::create(scratch.path()).map(drop)] {
+            match result {
+                Err(PidfileError::Running(path, pid)) => assert_eq!((path, pid), (scratch.path().to_path_buf(), running.0.id())),
+                other => panic!("expected the pidfile to be in use, got {:?}", other),
+            }
+        }
+        assert_eq!(read_pid(scratch.path()).unwrap(), Some(running.0.id()));
+    }
+
+    #[test]
+    fn dropping_a_pidfile_another_process_took_over_leaves_it() {
+        let scratch = Scratch::file("taken-over.pid");
+        let pidfile = Pidfile::create(scratch.path()).unwrap();
+        let running = Running::start();
+        fs::write(scratch.path(), format!("{}\n", running.0.id())).unwrap();
+        drop(pidfile);
+        assert_eq!(read_pid(scratch.path()).unwrap(), Some(running.0.id()));
+    }
+}
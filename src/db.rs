@@ -0,0 +1,256 @@
+use std::{fs, io, path::Path, sync::{Mutex, OnceLock}, thread, time::{Duration, Instant}};
+
+use rusqlite::{backup::{Backup, StepResult}, ffi, Connection, ErrorCode, OpenFlags};
+
+/// How long SQLite itself waits on a lock before giving up with SQLITE_BUSY.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Attempts `retry` makes at least before passing a busy error on to the caller.
+pub const RETRIES: usize = 5;
+const BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_millis(800);
+
+/// The database name that means no file at all: one database in memory, shared by every
+/// connection this process opens under that name, and gone when the process exits.
+pub const MEMORY: &str = ":memory:";
+
+/// Where every connection to `MEMORY` really goes, so the threads that open their own share
+/// one database rather than getting an empty one apiece.
+///
+/// Connections to it lock tables rather than the file, and a lock held elsewhere fails with
+/// SQLITE_LOCKED, which the busy timeout doesn't cover; they wait for it through
+/// `sqlite3_unlock_notify` instead, and only fail straight away where waiting would deadlock.
+const SHARED_MEMORY: &str = "file:glosco-memory?mode=memory&cache=shared";
+
+/// Whether `path` names the in-memory database rather than a file.
+pub fn is_memory<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new(MEMORY)
+}
+
+/// A connection held for as long as the process runs, since a shared in-memory database lasts
+/// only while something has it open. Snapshots are taken through it.
+fn keeper() -> rusqlite::Result<&'static Mutex<Connection>> {
+    static KEEPER: OnceLock<Mutex<Connection>> = OnceLock::new();
+    if let Some(keeper) = KEEPER.get() {
+        return Ok(keeper);
+    }
+    let db = open_memory()?;
+    Ok(KEEPER.get_or_init(|| Mutex::new(db)))
+}
+
+fn open_memory() -> rusqlite::Result<Connection> {
+    let db = Connection::open_with_flags(SHARED_MEMORY, OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI)?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(db)
+}
+
+/// Open the database with our standard pragmas: a busy timeout and WAL journaling. `MEMORY`
+/// opens the shared in-memory database instead, which has no journal to speak of.
+pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Connection> {
+    if is_memory(&path) {
+        keeper()?;
+        return open_memory();
+    }
+    let db = Connection::open(path)?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    let journal_mode: String = retry(|| db.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0)))?;
+    if journal_mode != "wal" {
+        println!("warning: database journal_mode is {} rather than wal", journal_mode);
+    }
+    Ok(db)
+}
+
+/// Open the database for reading only, without touching its journal mode. `MEMORY` opens the
+/// shared in-memory database, kept from writing by `query_only`.
+pub fn open_read_only<P: AsRef<Path>>(path: P) -> rusqlite::Result<Connection> {
+    if is_memory(&path) {
+        keeper()?;
+        let db = open_memory()?;
+        db.pragma_update(None, "query_only", true)?;
+        return Ok(db);
+    }
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(db)
+}
+
+/// Whether this error is SQLite telling us someone else holds the lock.
+pub fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(err.sqlite_error_code(), Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked))
+}
+
+/// Run `op`, retrying with exponential backoff while it fails with a busy or locked error: at
+/// least `RETRIES` times, and for as long as `BUSY_TIMEOUT` after the first attempt. A file
+/// waits out the busy timeout on every attempt, but the in-memory database fails at once on a
+/// lock it would deadlock waiting for, so there it's the time that bounds the retries.
+///
+/// `op` must be safe to repeat, which in practice means it runs in its own transaction.
+pub fn retry<T, F: FnMut() -> rusqlite::Result<T>>(mut op: F) -> rusqlite::Result<T> {
+    let started = Instant::now();
+    let mut backoff = BACKOFF;
+    let mut attempts = 1;
+    loop {
+        match op() {
+            Err(e) if is_busy(&e) && (attempts < RETRIES || started.elapsed() < BUSY_TIMEOUT) => {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                attempts += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Replace the in-memory database with the contents of the database file at `path`.
+pub fn restore_memory<P: AsRef<Path>>(path: P) -> rusqlite::Result<()> {
+    let src = open_read_only(path)?;
+    let mut keeper = keeper()?.lock().unwrap();
+    copy(&src, &mut keeper)
+}
+
+/// Write the in-memory database out to `path`, by way of a temporary file beside it so that
+/// `path` always holds a whole snapshot.
+pub fn snapshot_memory<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    // Left over from a snapshot that was cut short
+    let _ = fs::remove_file(&partial);
+    {
+        let mut dst = Connection::open(&partial).map_err(io::Error::other)?;
+        let keeper = keeper().map_err(io::Error::other)?;
+        copy(&keeper.lock().unwrap(), &mut dst).map_err(io::Error::other)?;
+    }
+    fs::rename(&partial, path)
+}
+
+/// Copy every page of `src` over `dst` with the backup API, in one step unless a writer gets in
+/// the way, in which case that step is retried the way `retry` retries anything else, and
+/// given up on with the busy or locked error once it runs out.
+fn copy(src: &Connection, dst: &mut Connection) -> rusqlite::Result<()> {
+    let backup = Backup::new(src, dst)?;
+    loop {
+        let step = retry(|| match backup.step(-1)? {
+            StepResult::Busy => Err(failure(ffi::SQLITE_BUSY)),
+            StepResult::Locked => Err(failure(ffi::SQLITE_LOCKED)),
+            step => Ok(step),
+        })?;
+        if step == StepResult::Done {
+            return Ok(());
+        }
+    }
+}
+
+fn failure(code: i32) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(code), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+
+    use crate::test_support::Scratch;
+
+    use super::*;
+
+    #[test]
+    fn two_threads_hammering_one_file_both_get_every_row_in() {
+        const ROWS: i64 = 500;
+        let scratch = Scratch::new("hammer");
+        open(scratch.path()).unwrap().execute_batch("CREATE TABLE rows (writer, seq);").unwrap();
+        let start = Arc::new(Barrier::new(2));
+        let writers: Vec<_> = (0 .. 2).map(|writer| {
+            let (path, start) = (scratch.path().to_path_buf(), start.clone());
+            thread::spawn(move || {
+                let db = open(path).unwrap();
+                start.wait();
+                for _ in 0 .. ROWS {
+                    // Reading before writing in one transaction is what gets a stale snapshot
+                    // turned away outright rather than waited on
+                    retry(|| {
+                        let txn = db.unchecked_transaction()?;
+                        let seq: i64 = txn.query_row("SELECT count(*) FROM rows WHERE writer = ?;", [writer], |row| row.get(0))?;
+                        txn.execute("INSERT INTO rows (writer, seq) VALUES (?, ?);", [writer, seq])?;
+                        txn.commit()
+                    }).unwrap();
+                    // And checkpoints in between, as maintenance runs them
+                    if writer == 1 {
+                        retry(|| db.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |_| Ok(()))).unwrap();
+                    }
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().expect("a writer panicked");
+        }
+
+        let db = open(scratch.path()).unwrap();
+        for writer in 0 .. 2 {
+            let seqs: Vec<i64> = db.prepare("SELECT seq FROM rows WHERE writer = ? ORDER BY seq;").unwrap()
+                .query_map([writer], |row| row.get(0)).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            assert_eq!(seqs, (0 .. ROWS).collect::<Vec<_>>(), "writer {}", writer);
+        }
+    }
+
+    #[test]
+    fn only_busy_and_locked_are_retried() {
+        for code in [ffi::SQLITE_BUSY, ffi::SQLITE_LOCKED] {
+            let mut attempts = 0;
+            let result = retry(|| {
+                attempts += 1;
+                if attempts < 3 { Err(failure(code)) } else { Ok(attempts) }
+            });
+            assert_eq!(result.unwrap(), 3);
+        }
+        let mut attempts = 0;
+        let result: rusqlite::Result<()> = retry(|| {
+            attempts += 1;
+            Err(failure(ffi::SQLITE_CONSTRAINT))
+        });
+        assert!(!is_busy(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn a_lock_held_past_every_retry_is_given_up_on() {
+        let scratch = Scratch::new("held");
+        let holder = open(scratch.path()).unwrap();
+        holder.execute_batch("CREATE TABLE rows (n); BEGIN IMMEDIATE; INSERT INTO rows VALUES (1);").unwrap();
+        let db = open(scratch.path()).unwrap();
+        // Not waiting on SQLite's own timeout, so that only the retries are measured
+        db.busy_timeout(Duration::ZERO).unwrap();
+        let started = Instant::now();
+        let mut attempts = 0;
+        let err = retry(|| {
+            attempts += 1;
+            db.execute("INSERT INTO rows VALUES (2);", [])
+        }).unwrap_err();
+        assert!(is_busy(&err), "{}", err);
+        assert!(attempts > RETRIES, "{} attempts", attempts);
+        assert!(started.elapsed() >= BUSY_TIMEOUT);
+
+        // Once it's let go of, the next try goes through
+        holder.execute_batch("COMMIT;").unwrap();
+        retry(|| db.execute("INSERT INTO rows VALUES (2);", [])).unwrap();
+    }
+
+    #[test]
+    fn a_copy_onto_a_database_held_past_every_retry_is_given_up_on() {
+        let scratch = Scratch::new("copy-held");
+        let holder = open(scratch.path()).unwrap();
+        holder.execute_batch("CREATE TABLE rows (n); BEGIN IMMEDIATE; INSERT INTO rows VALUES (1);").unwrap();
+        let src = Connection::open_in_memory().unwrap();
+        src.execute_batch("CREATE TABLE rows (n); INSERT INTO rows VALUES (2);").unwrap();
+        let mut dst = open(scratch.path()).unwrap();
+        dst.busy_timeout(Duration::ZERO).unwrap();
+        let started = Instant::now();
+        let err = copy(&src, &mut dst).unwrap_err();
+        assert!(is_busy(&err), "{}", err);
+        assert!(started.elapsed() >= BUSY_TIMEOUT);
+
+        holder.execute_batch("COMMIT;").unwrap();
+        copy(&src, &mut dst).unwrap();
+        let n: i64 = dst.query_row("SELECT n FROM rows", [], |row| row.get(0)).unwrap();
+        assert_eq!(n, 2);
+    }
+}
@@ -0,0 +1,259 @@
+//! Mirrored traffic as switches deliver it to a sensor, wrapped in ERSPAN or VXLAN, unwrapped
+//! down to the Ethernet frame that was mirrored, and that frame's VLAN tags peeled off.
+
+/// The UDP port VXLAN is sent to.
+pub const VXLAN_PORT: u16 = 4789;
+
+/// The IP protocol number of GRE, which ERSPAN is carried in.
+pub const GRE_PROTOCOL: u8 = 47;
+
+/// The encapsulation is cut short, or its headers contradict themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Malformed;
+
+// GRE flags, and the protocol types ERSPAN goes by
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_ROUTING: u16 = 0x4000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+const ERSPAN_II: u16 = 0x88be;
+const ERSPAN_III: u16 = 0x22eb;
+
+// VXLAN's one flag: the VNI is valid
+const VXLAN_VNI: u8 = 0x08;
+
+// The ethertypes a VLAN tag goes by: 802.1Q, 802.1ad (QinQ), and the old pre-standard QinQ
+const VLAN_TAGS: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+
+/// The most VLAN tags `untag` peels off one frame; providers stack two, rarely three.
+pub const MAX_TAGS: usize = 4;
+
+/// A mirrored frame, and the ERSPAN session or VXLAN network it came in on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mirrored<'a> {
+    pub session: u32,
+    pub frame: &'a [u8],
+}
+
+/// Unwrap ERSPAN (type I, II or III) from a GRE packet, less its IP header; `None` if the GRE
+/// packet carries anything else.
+pub fn erspan(gre: &[u8]) -> Result<Option<Mirrored<'_>>, Malformed> {
+    let header = gre.get(.. 4).ok_or(Malformed)?;
+    let flags = u16::from_be_bytes([header[0], header[1]]);
+    let protocol = u16::from_be_bytes([header[2], header[3]]);
+    if flags & (GRE_ROUTING | GRE_VERSION) != 0 {
+        // Source routing was deprecated long before ERSPAN; other versions are PPTP's
+        return Ok(None);
+    }
+    let mut len = 4;
+    if flags & GRE_CHECKSUM != 0 {
+        len += 4;
+    }
+    if flags & GRE_KEY != 0 {
+        len += 4;
+    }
+    let sequenced = flags & GRE_SEQUENCE != 0;
+    if sequenced {
+        len += 4;
+    }
+    let body = gre.get(len ..).ok_or(Malformed)?;
+    match protocol {
+        // Type I has no sequence number and no header of its own
+        ERSPAN_II if !sequenced => Ok(Some(Mirrored { session: 0, frame: body })),
+        ERSPAN_II => {
+            let header = body.get(.. 8).ok_or(Malformed)?;
+            if header[0] >> 4 != 1 {
+                return Err(Malformed);
+            }
+            Ok(Some(Mirrored { session: session(header), frame: &body[8 ..] }))
+        },
+        ERSPAN_III => {
+            let header = body.get(.. 12).ok_or(Malformed)?;
+            if header[0] >> 4 != 2 {
+                return Err(Malformed);
+            }
+            // A platform-specific subheader follows if the last bit says so
+            let len = if header[11] & 0x1 != 0 { 20 } else { 12 };
+            Ok(Some(Mirrored { session: session(header), frame: body.get(len ..).ok_or(Malformed)? }))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// The ten-bit session id both ERSPAN II and III keep in the same place.
+fn session(header: &[u8]) -> u32 {
+    u32::from(u16::from_be_bytes([header[2], header[3]]) & 0x3ff)
+}
+
+/// Unwrap VXLAN from a UDP payload, giving the VNI as the session.
+pub fn vxlan(udp: &[u8]) -> Result<Mirrored<'_>, Malformed> {
+    let header = udp.get(.. 8).ok_or(Malformed)?;
+    if header[0] & VXLAN_VNI == 0 {
+        return Err(Malformed);
+    }
+    Ok(Mirrored {
+        session: u32::from_be_bytes([0, header[4], header[5], header[6]]),
+        frame: &udp[8 ..],
+    })
+}
+
+/// Whether an ethertype says a VLAN tag comes next, rather than a payload.
+fn is_tag(ethertype: u16) -> bool {
+    VLAN_TAGS.contains(&ethertype)
+}
+
+/// Peel the VLAN tags off a frame, from just after the ethertype that said one follows, giving
+/// the ethertype under them and the payload. Frames with more than `MAX_TAGS` are malformed,
+/// so a frame of nothing but tags costs no more than that to turn away.
+pub fn untag(tagged: &[u8]) -> Result<(u16, &[u8]), Malformed> {
+    let mut rest = tagged;
+    for _ in 0 .. MAX_TAGS {
+        let tag = rest.get(.. 4).ok_or(Malformed)?;
+        let ethertype = u16::from_be_bytes([tag[2], tag[3]]);
+        rest = &rest[4 ..];
+        if !is_tag(ethertype) {
+            return Ok((ethertype, rest));
+        }
+    }
+    Err(Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPV4: [u8; 2] = [0x08, 0x00];
+
+    #[test]
+    fn one_tag() {
+        // VLAN 100, then IPv4
+        let tagged = [0x00, 0x64, IPV4[0], IPV4[1], 0x45, 0x00];
+        assert_eq!(untag(&tagged), Ok((0x0800, &[0x45, 0x00][..])));
+    }
+
+    #[test]
+    fn qinq() {
+        // Service VLAN 10, customer VLAN 100 under an 802.1Q tag, then IPv4
+        let tagged = [0x00, 0x0a, 0x81, 0x00, 0x00, 0x64, IPV4[0], IPV4[1], 0x45];
+        assert_eq!(untag(&tagged), Ok((0x0800, &[0x45][..])));
+        // The same under the old pre-standard ethertype
+        let tagged = [0x00, 0x0a, 0x91, 0x00, 0x00, 0x64, IPV4[0], IPV4[1]];
+        assert_eq!(untag(&tagged), Ok((0x0800, &[][..])));
+    }
+
+    #[test]
+    fn as_many_tags_as_allowed_and_one_more() {
+        let tags = |count: usize| {
+            let mut tagged = Vec::new();
+            for _ in 1 .. count {
+                tagged.extend_from_slice(&[0x00, 0x01, 0x88, 0xa8]);
+            }
+            tagged.extend_from_slice(&[0x00, 0x01, IPV4[0], IPV4[1], 0x45]);
+            tagged
+        };
+        assert_eq!(untag(&tags(MAX_TAGS)), Ok((0x0800, &[0x45][..])));
+        assert_eq!(untag(&tags(MAX_TAGS + 1)), Err(Malformed));
+        // Nothing but tags, for as long as it goes
+        assert_eq!(untag(&[0x00, 0x01, 0x81, 0x00].repeat(1000)), Err(Malformed));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(untag(&[]), Err(Malformed));
+        assert_eq!(untag(&[0x00, 0x64, IPV4[0]]), Err(Malformed));
+        // The outer tag is whole, but says another follows that isn't
+        assert_eq!(untag(&[0x00, 0x0a, 0x81, 0x00, 0x00, 0x64]), Err(Malformed));
+    }
+
+    /// The start of a mirrored Ethernet frame, as far as this module cares.
+    const FRAME: [u8; 4] = [0x02, 0x00, 0x00, 0x00];
+
+    /// A GRE header with `flags` and `protocol`, room for whatever fields the flags call for,
+    /// then `body`.
+    fn gre(flags: u16, protocol: u16, body: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&protocol.to_be_bytes());
+        for flag in [GRE_CHECKSUM, GRE_KEY, GRE_SEQUENCE] {
+            if flags & flag != 0 {
+                packet.extend_from_slice(&[0xff; 4]);
+            }
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    /// An ERSPAN header of `version`'s length, for session 42 (in the bits above it the VLAN
+    /// and CoS, set to make sure they're left out), then `FRAME`.
+    fn erspan_header(version: u8, len: usize) -> Vec<u8> {
+        let mut header = vec![0; len];
+        header[0] = version << 4 | 0x1;
+        header[2 .. 4].copy_from_slice(&(0xfc00u16 | 42).to_be_bytes());
+        header.extend_from_slice(&FRAME);
+        header
+    }
+
+    #[test]
+    fn erspan_ii() {
+        let packet = gre(GRE_SEQUENCE, ERSPAN_II, &erspan_header(1, 8));
+        assert_eq!(erspan(&packet), Ok(Some(Mirrored { session: 42, frame: &FRAME })));
+        // Checksum and key fields are skipped over too
+        let packet = gre(GRE_CHECKSUM | GRE_KEY | GRE_SEQUENCE, ERSPAN_II, &erspan_header(1, 8));
+        assert_eq!(erspan(&packet), Ok(Some(Mirrored { session: 42, frame: &FRAME })));
+    }
+
+    #[test]
+    fn erspan_i_has_no_header_of_its_own() {
+        let packet = gre(0, ERSPAN_II, &FRAME);
+        assert_eq!(erspan(&packet), Ok(Some(Mirrored { session: 0, frame: &FRAME })));
+    }
+
+    #[test]
+    fn erspan_iii_with_and_without_a_platform_subheader() {
+        let packet = gre(GRE_SEQUENCE, ERSPAN_III, &erspan_header(2, 12));
+        assert_eq!(erspan(&packet), Ok(Some(Mirrored { session: 42, frame: &FRAME })));
+        let mut header = vec![0; 20];
+        header[0] = 0x20;
+        header[3] = 7;
+        header[11] = 0x1;
+        header.extend_from_slice(&FRAME);
+        assert_eq!(erspan(&gre(GRE_SEQUENCE, ERSPAN_III, &header)), Ok(Some(Mirrored { session: 7, frame: &FRAME })));
+    }
+
+    #[test]
+    fn gre_that_isnt_erspan() {
+        // IPv4 in GRE, PPTP's enhanced GRE, and source routed GRE
+        assert_eq!(erspan(&gre(0, 0x0800, &[0x45])), Ok(None));
+        assert_eq!(erspan(&gre(GRE_KEY | 0x0001, 0x880b, &[])), Ok(None));
+        assert_eq!(erspan(&gre(GRE_ROUTING, ERSPAN_II, &FRAME)), Ok(None));
+    }
+
+    #[test]
+    fn erspan_that_doesnt_add_up() {
+        assert_eq!(erspan(&[0x10, 0x00, 0x88]), Err(Malformed));
+        // Flags promising fields that aren't there
+        assert_eq!(erspan(&[0x10, 0x00, 0x88, 0xbe, 0x00]), Err(Malformed));
+        // Headers cut short, and ones of the other type's version
+        assert_eq!(erspan(&gre(GRE_SEQUENCE, ERSPAN_II, &erspan_header(1, 8)[.. 7])), Err(Malformed));
+        assert_eq!(erspan(&gre(GRE_SEQUENCE, ERSPAN_III, &erspan_header(2, 12)[.. 11])), Err(Malformed));
+        assert_eq!(erspan(&gre(GRE_SEQUENCE, ERSPAN_II, &erspan_header(2, 8))), Err(Malformed));
+        assert_eq!(erspan(&gre(GRE_SEQUENCE, ERSPAN_III, &erspan_header(1, 12))), Err(Malformed));
+        // A subheader promised but not there
+        let mut header = erspan_header(2, 12);
+        header[11] = 0x1;
+        header.truncate(16);
+        assert_eq!(erspan(&gre(GRE_SEQUENCE, ERSPAN_III, &header)), Err(Malformed));
+    }
+
+    #[test]
+    fn vxlan_gives_the_vni() {
+        let mut packet = vec![VXLAN_VNI, 0x00, 0x00, 0x00, 0x12, 0x34, 0x56, 0x00];
+        packet.extend_from_slice(&FRAME);
+        assert_eq!(vxlan(&packet), Ok(Mirrored { session: 0x123456, frame: &FRAME }));
+        // No valid VNI, or no room for one
+        packet[0] = 0;
+        assert_eq!(vxlan(&packet), Err(Malformed));
+        assert_eq!(vxlan(&[VXLAN_VNI, 0, 0, 0, 0, 0, 1]), Err(Malformed));
+    }
+}
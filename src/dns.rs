@@ -0,0 +1,156 @@
+use std::{fs, io, net::{IpAddr, SocketAddr, UdpSocket}, time::Duration};
+
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData, ResponseCode};
+
+/// Something that can turn an address back into a name.
+pub trait Resolver: Send {
+    /// The PTR name for `addr`, or `None` if the resolver says there isn't one.
+    fn reverse(&mut self, addr: IpAddr) -> io::Result<Option<String>>;
+
+    /// What to record as the responder in the names table.
+    fn source(&self) -> String;
+}
+
+/// Something that can look up where a service is offered.
+pub trait SrvLookup: Send {
+    /// The SRV records for `name`, like `_glosco._tcp.example.com`; empty if there are none.
+    fn srv(&mut self, name: &str) -> io::Result<Vec<SrvRecord>>;
+}
+
+/// One SRV record: a host and port offering the service, and how to choose among them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower is preferred; higher priorities are only for when the lower are all gone.
+    pub priority: u16,
+    /// Relative share among records of the same priority.
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+    pub ttl: Duration,
+}
+
+/// The records to use: those of the best priority, heaviest first. A record whose target is
+/// `.` says the service isn't offered at all, so it's never chosen.
+pub fn best_srv(records: &[SrvRecord]) -> Vec<&SrvRecord> {
+    let usable = || records.iter().filter(|record| !record.target.is_empty() && record.target != ".");
+    let Some(priority) = usable().map(|record| record.priority).min() else {
+        return Vec::new();
+    };
+    let mut best: Vec<&SrvRecord> = usable().filter(|record| record.priority == priority).collect();
+    best.sort_by_key(|record| std::cmp::Reverse(record.weight));
+    best
+}
+
+/// Sends queries straight to one DNS server over UDP.
+#[derive(Debug)]
+pub struct UdpResolver {
+    server: SocketAddr,
+    socket: UdpSocket,
+    next_id: u16,
+}
+
+impl UdpResolver {
+    pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub fn new(server: SocketAddr) -> io::Result<Self> {
+        let bind: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(Self::TIMEOUT))?;
+        socket.connect(server)?;
+        Ok(Self {
+            server,
+            socket,
+            next_id: std::process::id() as u16,
+        })
+    }
+
+    /// The first nameserver in /etc/resolv.conf.
+    pub fn system_server() -> io::Result<SocketAddr> {
+        fs::read_to_string("/etc/resolv.conf")?
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+            .map(|addr| SocketAddr::new(addr, 53))
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"))
+    }
+
+    /// Ask about `name` and make what's needed of the answer with `answers`, or `None` if the
+    /// server says there's no such name.
+    fn ask<T>(&mut self, name: &str, qtype: QueryType, answers: impl FnOnce(&Packet) -> T) -> io::Result<Option<T>> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        let mut query = Builder::new_query(id, true);
+        query.add_question(name, false, qtype, QueryClass::IN);
+        let query = query.build().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "truncated query"))?;
+        self.socket.send(&query)?;
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = self.socket.recv(&mut buf)?;
+            let Ok(packet) = Packet::parse(&buf[.. len]) else {
+                continue;
+            };
+            if packet.header.id != id {
+                // A late answer to an earlier query that timed out
+                continue;
+            }
+            return match packet.header.response_code {
+                ResponseCode::NoError => Ok(Some(answers(&packet))),
+                ResponseCode::NameError => Ok(None),
+                code => Err(io::Error::other(format!("{} answered {:?}", self.server, code))),
+            };
+        }
+    }
+}
+
+/// The in-addr.arpa or ip6.arpa name to ask for.
+pub fn arpa_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        },
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(72);
+            for byte in v6.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        },
+    }
+}
+
+impl Resolver for UdpResolver {
+    fn reverse(&mut self, addr: IpAddr) -> io::Result<Option<String>> {
+        let name = self.ask(&arpa_name(addr), QueryType::PTR, |packet| packet.answers.iter().find_map(|answer| match &answer.data {
+            RData::PTR(ptr) => Some(ptr.0.to_string()),
+            _ => None,
+        }))?;
+        Ok(name.flatten())
+    }
+
+    fn source(&self) -> String {
+        self.server.ip().to_string()
+    }
+}
+
+impl SrvLookup for UdpResolver {
+    fn srv(&mut self, name: &str) -> io::Result<Vec<SrvRecord>> {
+        let records = self.ask(name, QueryType::SRV, |packet| packet.answers.iter().filter_map(|answer| match &answer.data {
+            RData::SRV(srv) => Some(SrvRecord {
+                priority: srv.priority,
+                weight: srv.weight,
+                port: srv.port,
+                target: srv.target.to_string(),
+                ttl: Duration::from_secs(answer.ttl.into()),
+            }),
+            _ => None,
+        }).collect())?;
+        Ok(records.unwrap_or_default())
+    }
+}
@@ -0,0 +1,294 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write, BufWriter}, path::{PathBuf, Path}, sync::{mpsc, Arc, atomic::{AtomicU64, Ordering}}, thread, time::{SystemTime, Duration, Instant}};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+
+use crate::observe::Message;
+
+/// Where and how to write the NDJSON event log.
+#[derive(Debug, Clone)]
+pub struct EventLogConfig {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    gzip: bool,
+}
+
+/// Handle for feeding accepted messages to the event log writer thread.
+///
+/// Cloning is cheap; all clones share the same writer and drop counter.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    sender: mpsc::SyncSender<Event>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// An accepted message along with where and when it was received.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub ident: Arc<str>,
+    pub peer: Arc<str>,
+    pub received: SystemTime,
+    pub message: Message,
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    ident: &'a str,
+    peer: &'a str,
+    received: f64,
+    message: &'a Message,
+}
+
+impl Event {
+    /// The JSON representation shared by the event log and anything else exporting events.
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        let received = self.received.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+        serde_json::to_vec(&Record {
+            ident: &self.ident,
+            peer: &self.peer,
+            received,
+            message: &self.message,
+        })
+    }
+}
+
+impl EventLogConfig {
+    pub const BACKLOG: usize = 4096;
+
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_bytes: None,
+            max_age: None,
+            gzip: false,
+        }
+    }
+
+    /// Rotate once the current file would grow past this many bytes.
+    pub fn set_max_bytes(&mut self, max_bytes: u64) {
+        self.max_bytes = Some(max_bytes);
+    }
+
+    /// Rotate once the current file has been open this long.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = Some(max_age);
+    }
+
+    /// Compress rotated files with gzip.
+    pub fn set_gzip(&mut self, gzip: bool) {
+        self.gzip = gzip;
+    }
+
+    pub fn build(self) -> io::Result<EventLog> {
+        let writer = Writer::open(self)?;
+        let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+        thread::spawn(move || writer.run(receiver));
+        Ok(EventLog {
+            sender,
+            dropped: Default::default(),
+        })
+    }
+}
+
+impl EventLog {
+    /// Queue a message for the log. Never blocks; if the writer can't keep up, the event is
+    /// dropped and counted instead.
+    pub fn log(&self, event: Event) {
+        if self.sender.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of events dropped because the writer fell behind (or died).
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct Writer {
+    config: EventLogConfig,
+    file: BufWriter<File>,
+    written: u64,
+    opened: Instant,
+}
+
+impl Writer {
+    fn open(config: EventLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file: BufWriter::new(file),
+            written,
+            opened: Instant::now(),
+        })
+    }
+
+    fn run(mut self, receiver: mpsc::Receiver<Event>) {
+        while let Ok(event) = receiver.recv() {
+            if let Err(e) = self.write(&event) {
+                println!("event log write error: {:?}", e);
+            }
+            // Drain whatever else is already queued before paying for a flush
+            while let Ok(event) = receiver.try_recv() {
+                if let Err(e) = self.write(&event) {
+                    println!("event log write error: {:?}", e);
+                }
+            }
+            if let Err(e) = self.file.flush() {
+                println!("event log flush error: {:?}", e);
+            }
+        }
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = event.to_json()?;
+        line.push(b'\n');
+        // Rotation only ever happens between lines, so a record is never split across files
+        if self.due_for_rotation(line.len() as u64) {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn due_for_rotation(&self, incoming: u64) -> bool {
+        if self.written == 0 {
+            return false;
+        }
+        self.config.max_bytes.map(|max| self.written + incoming > max).unwrap_or(false)
+            || self.config.max_age.map(|max| self.opened.elapsed() > max).unwrap_or(false)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated = self.rotated_path();
+        fs::rename(&self.config.path, &rotated)?;
+        let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.file = BufWriter::new(file);
+        self.written = 0;
+        self.opened = Instant::now();
+        if self.config.gzip {
+            // Compression can be slow on big files; don't hold up the log while it runs
+            thread::spawn(move || {
+                if let Err(e) = compress(&rotated) {
+                    println!("failed to compress {:?}: {:?}", rotated, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Where the current file goes when it's rotated: the log's path suffixed with the time in
+    /// seconds, and a count when that's taken, as it is after rotating twice in a second. A
+    /// rotated file being compressed counts as taken too.
+    fn rotated_path(&self) -> PathBuf {
+        let stamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        (0 ..).map(|count| {
+                let mut rotated = self.config.path.clone().into_os_string();
+                match count {
+                    0 => rotated.push(format!(".{}", stamp)),
+                    _ => rotated.push(format!(".{}-{}", stamp, count)),
+                }
+                PathBuf::from(rotated)
+            })
+            .find(|rotated| !rotated.exists() && !gzipped(rotated).exists())
+            .expect("some count is free")
+    }
+}
+
+/// Where `compress` puts `path`.
+fn gzipped(path: &Path) -> PathBuf {
+    let mut target = path.to_path_buf().into_os_string();
+    target.push(".gz");
+    PathBuf::from(target)
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let target = gzipped(path);
+    let mut source = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+    io::copy(&mut source, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, net::{IpAddr, Ipv4Addr}};
+
+    use crate::observe::{Connection, Endpoint, Protocol, State};
+
+    use super::*;
+
+    /// A directory of its own for each test, emptied first.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glosco-eventlog-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn event(port: u16) -> Event {
+        let endpoint = |last, port| Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), port };
+        Event {
+            ident: Arc::from("sensor"),
+            peer: Arc::from("127.0.0.1:40000"),
+            received: SystemTime::now(),
+            message: Message::Starting(State {
+                as_of: SystemTime::now(),
+                connection: Connection { interface: 0, src: endpoint(1, port), dst: endpoint(2, 443), protocol: Protocol::Tcp },
+                rtt_micros: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn rotating_often_splits_no_line_and_loses_no_file() {
+        let dir = scratch("rotate");
+        let mut config = EventLogConfig::new(dir.join("events.ndjson"));
+        // A few lines a file, so a hundred events rotate many times within the same second
+        config.set_max_bytes(1000);
+        let mut writer = Writer::open(config).unwrap();
+        for port in 0 .. 100 {
+            writer.write(&event(port)).unwrap();
+        }
+        writer.file.flush().unwrap();
+
+        let files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert!(files.len() > 10, "only {} files", files.len());
+        let mut ports = BTreeSet::new();
+        for file in &files {
+            let contents = fs::read_to_string(file).unwrap();
+            assert!(contents.len() <= 1000, "{:?} is {} bytes", file, contents.len());
+            assert!(contents.ends_with('\n'), "{:?} ends mid-line", file);
+            for line in contents.lines() {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                let port = record["message"]["Starting"]["connection"]["src"]["port"].as_u64().unwrap();
+                assert!(ports.insert(port), "port {} logged twice", port);
+            }
+        }
+        assert_eq!(ports, (0 .. 100).collect());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_rotated_name_being_compressed_is_not_reused() {
+        let dir = scratch("taken");
+        let writer = Writer::open(EventLogConfig::new(dir.join("events.ndjson"))).unwrap();
+        let first = writer.rotated_path();
+        File::create(gzipped(&first)).unwrap();
+        let second = writer.rotated_path();
+        assert_ne!(first, second);
+        File::create(&second).unwrap();
+        let third = writer.rotated_path();
+        assert!(![&first, &second].contains(&&third));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::{net::IpAddr, str::FromStr, fmt::{self, Display, Formatter}};
+
+use serde::Deserialize;
+
+/// An address block, like `10.0.0.0/8` or `fd00::/8`. A bare address is a single-host block.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadCidr(String);
+
+impl Display for BadCidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "bad address block: {}", self.0)
+    }
+}
+
+impl std::error::Error for BadCidr {}
+
+impl Cidr {
+    fn width(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn bits(addr: &IpAddr) -> u128 {
+        match addr {
+            IpAddr::V4(v4) => u32::from(*v4) as u128,
+            IpAddr::V6(v6) => u128::from(*v6),
+        }
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        if self.addr.is_ipv4() != addr.is_ipv4() {
+            return false;
+        }
+        let width = Self::width(addr);
+        let shift = (width - self.prefix) as u32;
+        Self::bits(&self.addr).checked_shr(shift).unwrap_or(0)
+            == Self::bits(addr).checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = BadCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| BadCidr(s.to_string()))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| BadCidr(s.to_string()))?,
+            None => Self::width(&addr),
+        };
+        if prefix > Self::width(&addr) {
+            return Err(BadCidr(s.to_string()));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = BadCidr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A shell-style pattern where `*` matches any run of characters and `?` any one character.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Glob(pub String);
+
+impl Glob {
+    pub fn matches(&self, text: &str) -> bool {
+        let pat: Vec<char> = self.0.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        // Classic backtracking matcher; only the most recent star ever needs revisiting
+        let (mut p, mut t) = (0usize, 0usize);
+        let mut star: Option<(usize, usize)> = None;
+        while t < text.len() {
+            if p < pat.len() && (pat[p] == '?' || pat[p] == text[t]) {
+                p += 1;
+                t += 1;
+            } else if p < pat.len() && pat[p] == '*' {
+                star = Some((p, t));
+                p += 1;
+            } else if let Some((sp, st)) = star {
+                p = sp + 1;
+                t = st + 1;
+                star = Some((sp, st + 1));
+            } else {
+                return false;
+            }
+        }
+        pat[p..].iter().all(|&c| c == '*')
+    }
+}
+
+impl FromStr for Glob {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl Display for Glob {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A DNS name to match regardless of case or a trailing dot: a plain name like `example.com`
+/// matches itself and every name under it, while one with `*` or `?` is a `Glob` over the
+/// whole name.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(from = "String")]
+pub struct NamePattern(Glob);
+
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let pattern = &self.0.0;
+        if pattern.contains(['*', '?']) {
+            self.0.matches(&name)
+        } else {
+            name == *pattern || name.strip_suffix(pattern.as_str()).is_some_and(|head| head.ends_with('.'))
+        }
+    }
+}
+
+impl From<String> for NamePattern {
+    fn from(s: String) -> Self {
+        Self(Glob(s.trim_end_matches('.').to_ascii_lowercase()))
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.to_string().into())
+    }
+}
+
+impl Display for NamePattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str) -> NamePattern {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn a_plain_name_matches_itself_and_whats_under_it() {
+        let tracker = pattern("tracker.example");
+        for name in ["tracker.example", "ads.tracker.example", "a.b.tracker.example", "tracker.example."] {
+            assert!(tracker.matches(name), "{:?}", name);
+        }
+        for name in ["nottracker.example", "tracker.example.org", "example", ""] {
+            assert!(!tracker.matches(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn names_match_whatever_their_case() {
+        assert!(pattern("Tracker.EXAMPLE.").matches("ads.tracker.example"));
+        assert!(pattern("tracker.example").matches("ADS.Tracker.Example."));
+        assert!(pattern("*.Health.example").matches("clinic.HEALTH.example"));
+    }
+
+    #[test]
+    fn a_pattern_with_wildcards_is_a_glob_over_the_whole_name() {
+        let under = pattern("*.tracker.example");
+        assert!(under.matches("ads.tracker.example"));
+        assert!(!under.matches("tracker.example"));
+        let any = pattern("ads?.*");
+        assert!(any.matches("ads1.tracker.example"));
+        assert!(!any.matches("ads.tracker.example"));
+        assert!(!any.matches("www.ads1.example"));
+    }
+}
@@ -0,0 +1,404 @@
+use std::{io, str::FromStr, fmt::{self, Display, Formatter}, sync::{mpsc, Arc, atomic::{AtomicU64, Ordering}}, thread};
+
+use serde::Deserialize;
+
+use crate::eventlog::Event;
+
+/// A streaming sink that accepted messages can be published into.
+///
+/// Implementations publish to a single destination fixed at construction; errors are reported
+/// per message and are never fatal to the forwarder.
+pub trait Producer: Send {
+    /// Publish `payload`, keyed by the ident it came from for backends that partition by key,
+    /// so each sensor's events stay in order.
+    fn publish(&mut self, key: &str, payload: &[u8]) -> io::Result<()>;
+}
+
+/// A forwarding destination, as given on the command line.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Target {
+    /// `kafka://broker[,broker...]/topic`
+    Kafka { brokers: String, topic: String },
+    /// `mqtt://host[:port]/topic`
+    Mqtt { host: String, port: u16, topic: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadTarget(String);
+
+impl Display for BadTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "bad forwarding target: {}", self.0)
+    }
+}
+
+impl std::error::Error for BadTarget {}
+
+impl Target {
+    pub const MQTT_PORT: u16 = 1883;
+}
+
+impl TryFrom<String> for Target {
+    type Error = BadTarget;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Target {
+    type Err = BadTarget;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://")
+            .ok_or_else(|| BadTarget(format!("{:?} has no scheme", s)))?;
+        let (authority, topic) = rest.split_once('/')
+            .ok_or_else(|| BadTarget(format!("{:?} has no topic", s)))?;
+        if authority.is_empty() || topic.is_empty() {
+            return Err(BadTarget(format!("{:?} needs both a host and a topic", s)));
+        }
+        match scheme {
+            "kafka" => Ok(Self::Kafka {
+                brokers: authority.to_string(),
+                topic: topic.to_string(),
+            }),
+            "mqtt" => {
+                let (host, port) = match authority.rsplit_once(':') {
+                    Some((host, port)) => (host, port.parse()
+                        .map_err(|_| BadTarget(format!("bad port in {:?}", s)))?),
+                    None => (authority, Self::MQTT_PORT),
+                };
+                Ok(Self::Mqtt {
+                    host: host.to_string(),
+                    port,
+                    topic: topic.to_string(),
+                })
+            },
+            _ => Err(BadTarget(format!("unknown scheme {:?}, try kafka or mqtt", scheme))),
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kafka { brokers, topic } => write!(f, "kafka://{}/{}", brokers, topic),
+            Self::Mqtt { host, port, topic } => write!(f, "mqtt://{}:{}/{}", host, port, topic),
+        }
+    }
+}
+
+/// Publishes accepted messages to a [Producer] from a dedicated thread.
+///
+/// Queueing is bounded and never blocks the caller, so a broker outage costs dropped events
+/// (counted) rather than stalled ingest.
+#[derive(Debug, Clone)]
+pub struct Forwarder {
+    sender: mpsc::SyncSender<Event>,
+    dropped: Arc<AtomicU64>,
+    failed: Arc<AtomicU64>,
+}
+
+impl Forwarder {
+    pub const BACKLOG: usize = 4096;
+
+    pub fn new(producer: Box<dyn Producer>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+        let failed: Arc<AtomicU64> = Default::default();
+        {
+            let failed = failed.clone();
+            thread::spawn(move || forward_thread(producer, receiver, failed));
+        }
+        Self {
+            sender,
+            dropped: Default::default(),
+            failed,
+        }
+    }
+
+    /// Connect to the given target with whichever backend was compiled in.
+    pub fn connect(target: &Target, client_id: &str) -> io::Result<Self> {
+        match target {
+            #[cfg(feature = "kafka")]
+            Target::Kafka { brokers, topic } => Ok(Self::new(Box::new(kafka::KafkaProducer::connect(brokers, topic)?))),
+            #[cfg(feature = "mqtt")]
+            Target::Mqtt { host, port, topic } => Ok(Self::new(Box::new(mqtt::MqttProducer::connect(client_id, host, *port, topic)))),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = client_id;
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("forwarding to {} is not supported by this build", target),
+                ))
+            },
+        }
+    }
+
+    pub fn forward(&self, event: Event) {
+        if self.sender.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of events dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of events the producer failed to publish.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+fn forward_thread(mut producer: Box<dyn Producer>, receiver: mpsc::Receiver<Event>, failed: Arc<AtomicU64>) {
+    while let Ok(event) = receiver.recv() {
+        let result = event.to_json()
+            .map_err(io::Error::from)
+            .and_then(|payload| producer.publish(&event.ident, &payload));
+        if let Err(e) = result {
+            let count = failed.fetch_add(1, Ordering::Relaxed) + 1;
+            println!("forward error ({} total): {:?}", count, e);
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::{io, time::Duration};
+
+    use rdkafka::{ClientConfig, ClientContext, producer::{BaseProducer, BaseRecord, Producer as _, ProducerContext, DeliveryResult}};
+
+    /// Delivery failures are only known asynchronously, so log them from the delivery callback.
+    struct LoggingContext;
+
+    impl ClientContext for LoggingContext {}
+
+    impl ProducerContext for LoggingContext {
+        type DeliveryOpaque = ();
+
+        fn delivery(&self, result: &DeliveryResult<'_>, _opaque: Self::DeliveryOpaque) {
+            if let Err((e, _)) = result {
+                println!("kafka delivery error: {:?}", e);
+            }
+        }
+    }
+
+    pub struct KafkaProducer {
+        producer: BaseProducer<LoggingContext>,
+        topic: String,
+    }
+
+    impl KafkaProducer {
+        pub fn connect(brokers: &str, topic: &str) -> io::Result<Self> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("message.timeout.ms", "10000")
+                .create_with_context(LoggingContext)
+                .map_err(io::Error::other)?;
+            Ok(Self {
+                producer,
+                topic: topic.to_string(),
+            })
+        }
+    }
+
+    impl super::Producer for KafkaProducer {
+        fn publish(&mut self, key: &str, payload: &[u8]) -> io::Result<()> {
+            let record = BaseRecord::<str, [u8]>::to(&self.topic).key(key).payload(payload);
+            let result = self.producer.send(record).map_err(|(e, _)| io::Error::other(e));
+            // Serve delivery callbacks without waiting on them
+            self.producer.poll(Duration::ZERO);
+            result
+        }
+    }
+
+    impl Drop for KafkaProducer {
+        fn drop(&mut self) {
+            let _ = self.producer.flush(Duration::from_secs(5));
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+mod mqtt {
+    use std::{io, thread, time::Duration};
+
+    use rumqttc::{Client, MqttOptions, QoS};
+
+    pub struct MqttProducer {
+        client: Client,
+        topic: String,
+    }
+
+    impl MqttProducer {
+        pub const CAPACITY: usize = 1024;
+
+        pub fn connect(client_id: &str, host: &str, port: u16, topic: &str) -> Self {
+            let mut options = MqttOptions::new(client_id, host, port);
+            options.set_keep_alive(Duration::from_secs(30));
+            let (client, mut connection) = Client::new(options, Self::CAPACITY);
+            // The event loop must be driven for anything to actually go out; it also handles
+            // reconnecting after the broker goes away.
+            thread::spawn(move || {
+                for notification in connection.iter() {
+                    if let Err(e) = notification {
+                        println!("mqtt connection error: {:?}", e);
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            });
+            Self {
+                client,
+                topic: topic.to_string(),
+            }
+        }
+    }
+
+    impl super::Producer for MqttProducer {
+        /// MQTT has no keys; everything goes to the one topic.
+        fn publish(&mut self, _key: &str, payload: &[u8]) -> io::Result<()> {
+            self.client.try_publish(self.topic.clone(), QoS::AtLeastOnce, false, payload.to_vec())
+                .map_err(io::Error::other)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::mpsc, time::{Duration, Instant, SystemTime}};
+
+    use crate::{eventlog::Event, observe::{Message, Protocol}, test_support::state};
+
+    use super::{BadTarget, Forwarder, Producer, Target};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Hands over each key and payload it's given, failing whatever `fail` picks out. While
+    /// `release` is held open, it stalls after each publish until told to go on.
+    struct Mock {
+        published: mpsc::Sender<(String, serde_json::Value)>,
+        fail: fn(&str) -> bool,
+        release: Option<mpsc::Receiver<()>>,
+    }
+
+    impl Producer for Mock {
+        fn publish(&mut self, key: &str, payload: &[u8]) -> io::Result<()> {
+            if (self.fail)(key) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "broker down"));
+            }
+            let _ = self.published.send((key.to_string(), serde_json::from_slice(payload).unwrap()));
+            if let Some(release) = &self.release {
+                if release.recv().is_err() {
+                    self.release = None;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn forwarder(fail: fn(&str) -> bool, release: Option<mpsc::Receiver<()>>) -> (Forwarder, mpsc::Receiver<(String, serde_json::Value)>) {
+        let (published, receiver) = mpsc::channel();
+        (Forwarder::new(Box::new(Mock { published, fail, release })), receiver)
+    }
+
+    fn event(ident: &str, srcport: u16) -> Event {
+        Event {
+            ident: ident.into(),
+            peer: "192.0.2.1:50000".into(),
+            received: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            message: Message::Starting(state(&format!("10.0.0.1:{}", srcport), "10.0.0.2:443", Protocol::Tcp)),
+        }
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) -> bool {
+        let started = Instant::now();
+        while !condition() {
+            if started.elapsed() > WAIT {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn targets_name_the_backend_its_hosts_and_the_topic() {
+        assert_eq!("kafka://k1:9092,k2:9092/glosco.events".parse(), Ok(Target::Kafka {
+            brokers: "k1:9092,k2:9092".to_string(),
+            topic: "glosco.events".to_string(),
+        }));
+        assert_eq!("mqtt://broker/glosco/events".parse(), Ok(Target::Mqtt {
+            host: "broker".to_string(),
+            port: Target::MQTT_PORT,
+            topic: "glosco/events".to_string(),
+        }));
+        assert_eq!("mqtt://broker:8883/glosco".parse(), Ok(Target::Mqtt {
+            host: "broker".to_string(),
+            port: 8883,
+            topic: "glosco".to_string(),
+        }));
+        for target in ["kafka://k1:9092,k2:9092/glosco.events", "mqtt://broker:1883/glosco/events"] {
+            assert_eq!(target.parse::<Target>().unwrap().to_string(), target);
+        }
+    }
+
+    #[test]
+    fn targets_missing_a_part_are_refused() {
+        for target in ["broker/topic", "kafka://broker", "kafka:///topic", "kafka://broker/", "mqtt://broker:port/topic", "amqp://broker/topic"] {
+            let parsed: Result<Target, BadTarget> = target.parse();
+            assert!(parsed.is_err(), "{} parsed as {:?}", target, parsed);
+        }
+    }
+
+    #[test]
+    fn each_event_goes_out_keyed_by_its_ident_in_the_order_it_came() {
+        let (forwarder, published) = forwarder(|_| false, None);
+        forwarder.forward(event("sensor-a", 40000));
+        forwarder.forward(event("sensor-b", 40001));
+        forwarder.forward(event("sensor-a", 40002));
+        let published: Vec<_> = (0 .. 3).map(|_| published.recv_timeout(WAIT).unwrap()).collect();
+        assert_eq!(published.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(), ["sensor-a", "sensor-b", "sensor-a"]);
+        let (_, first) = &published[0];
+        assert_eq!(first["ident"], "sensor-a");
+        assert_eq!(first["peer"], "192.0.2.1:50000");
+        assert_eq!(first["received"], 1_700_000_000.0);
+        assert_eq!(published.iter().map(|(_, payload)| payload["message"]["Starting"]["connection"]["src"]["port"].as_u64().unwrap()).collect::<Vec<_>>(), [40000, 40001, 40002]);
+        assert_eq!((forwarder.failed(), forwarder.dropped()), (0, 0));
+    }
+
+    #[test]
+    fn a_failed_publish_is_counted_and_the_next_one_still_goes() {
+        let (forwarder, published) = forwarder(|key| key == "unlucky", None);
+        forwarder.forward(event("unlucky", 40000));
+        forwarder.forward(event("sensor", 40001));
+        forwarder.forward(event("unlucky", 40002));
+        forwarder.forward(event("sensor", 40003));
+        assert_eq!(published.recv_timeout(WAIT).unwrap().0, "sensor");
+        assert_eq!(published.recv_timeout(WAIT).unwrap().0, "sensor");
+        assert!(wait_until(|| forwarder.failed() == 2), "failed {} times", forwarder.failed());
+        assert_eq!(forwarder.dropped(), 0);
+    }
+
+    #[test]
+    fn a_stalled_producer_costs_dropped_events_and_never_blocks_the_caller() {
+        let (release, waiting) = mpsc::channel();
+        let (forwarder, published) = forwarder(|_| false, Some(waiting));
+        forwarder.forward(event("sensor", 0));
+        // Held in the producer from here on, so the queue is empty and fills exactly
+        published.recv_timeout(WAIT).unwrap();
+        let started = Instant::now();
+        for port in 1 ..= Forwarder::BACKLOG + 10 {
+            forwarder.forward(event("sensor", port as u16));
+        }
+        assert!(started.elapsed() < WAIT);
+        assert_eq!(forwarder.dropped(), 10);
+        drop(release);
+        let ports: Vec<_> = (0 .. Forwarder::BACKLOG).map(|_| published.recv_timeout(WAIT).unwrap())
+            .map(|(_, payload)| payload["message"]["Starting"]["connection"]["src"]["port"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ports, (1 ..= Forwarder::BACKLOG as u64).collect::<Vec<_>>());
+        assert_eq!(forwarder.failed(), 0);
+    }
+}
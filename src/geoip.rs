@@ -0,0 +1,244 @@
+/// What the GeoIP databases know about an address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166 country code.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+#[cfg(feature = "geoip")]
+pub use self::reader::GeoIp;
+
+#[cfg(feature = "geoip")]
+mod reader {
+    use std::{collections::HashMap, fs, mem, net::IpAddr, path::PathBuf, sync::Mutex, time::{Duration, Instant, SystemTime}};
+
+    use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+    use super::Location;
+
+    struct Database {
+        path: PathBuf,
+        modified: Option<SystemTime>,
+        reader: Reader<Vec<u8>>,
+    }
+
+    impl Database {
+        fn open(path: PathBuf) -> Result<Self, MaxMindDBError> {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            let reader = Reader::open_readfile(&path)?;
+            Ok(Self { path, modified, reader })
+        }
+    }
+
+    struct Inner {
+        databases: Vec<Database>,
+        checked: Instant,
+        // Two generations make an approximate LRU: hits in `previous` are promoted, and whatever
+        // is left there when `current` fills up is dropped.
+        current: HashMap<IpAddr, Location>,
+        previous: HashMap<IpAddr, Location>,
+    }
+
+    /// Looks up country and ASN for addresses in one or more MaxMind databases, caching results
+    /// and picking up replaced database files on its own.
+    ///
+    /// Country and ASN usually come from separate files (GeoLite2-Country and GeoLite2-ASN); each
+    /// field is taken from the first database that has it.
+    pub struct GeoIp {
+        inner: Mutex<Inner>,
+    }
+
+    impl std::fmt::Debug for GeoIp {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let inner = self.inner.lock().unwrap();
+            f.debug_struct("GeoIp")
+                .field("databases", &inner.databases.iter().map(|db| &db.path).collect::<Vec<_>>())
+                .field("cached", &(inner.current.len() + inner.previous.len()))
+                .finish()
+        }
+    }
+
+    impl GeoIp {
+        /// Addresses kept in each cache generation.
+        pub const CACHE_SIZE: usize = 32768;
+        /// How often to check whether the database files have been replaced.
+        pub const RECHECK: Duration = Duration::from_secs(60);
+
+        pub fn open(paths: Vec<PathBuf>) -> Result<Self, MaxMindDBError> {
+            let databases = paths.into_iter().map(Database::open).collect::<Result<_, _>>()?;
+            Ok(Self {
+                inner: Mutex::new(Inner {
+                    databases,
+                    checked: Instant::now(),
+                    current: HashMap::new(),
+                    previous: HashMap::new(),
+                }),
+            })
+        }
+
+        pub fn lookup(&self, addr: IpAddr) -> Location {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.databases.is_empty() {
+                return Location::default();
+            }
+            if inner.checked.elapsed() > Self::RECHECK {
+                inner.checked = Instant::now();
+                inner.reload(false);
+            }
+            if let Some(location) = inner.current.get(&addr) {
+                return location.clone();
+            }
+            let location = match inner.previous.remove(&addr) {
+                Some(location) => location,
+                None => inner.resolve(addr),
+            };
+            if inner.current.len() >= Self::CACHE_SIZE {
+                inner.previous = mem::take(&mut inner.current);
+            }
+            inner.current.insert(addr, location.clone());
+            location
+        }
+
+        /// Reopen every database file, whether or not it looks changed.
+        pub fn reload(&self) {
+            self.inner.lock().unwrap().reload(true);
+        }
+
+        /// Look addresses up in `paths` from now on, or reopen the current files if they're the
+        /// same ones. If any of them can't be opened, keep the databases there are.
+        pub fn reopen(&self, paths: Vec<PathBuf>) {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.databases.iter().map(|db| &db.path).eq(paths.iter()) {
+                inner.reload(true);
+                return;
+            }
+            match paths.into_iter().map(Database::open).collect::<Result<Vec<_>, _>>() {
+                Ok(databases) => {
+                    println!("switched to GeoIP databases {:?}", databases.iter().map(|db| &db.path).collect::<Vec<_>>());
+                    inner.databases = databases;
+                    inner.current.clear();
+                    inner.previous.clear();
+                },
+                Err(e) => println!("failed to open new GeoIP databases, keeping the old ones: {:?}", e),
+            }
+        }
+    }
+
+    impl Inner {
+        fn reload(&mut self, force: bool) {
+            let mut reloaded = false;
+            for db in self.databases.iter_mut() {
+                let modified = fs::metadata(&db.path).and_then(|m| m.modified()).ok();
+                if !force && modified == db.modified {
+                    continue;
+                }
+                match Database::open(db.path.clone()) {
+                    Ok(fresh) => {
+                        println!("reloaded GeoIP database {:?}", db.path);
+                        *db = fresh;
+                        reloaded = true;
+                    },
+                    Err(e) => println!("failed to reload GeoIP database {:?}, keeping the old one: {:?}", db.path, e),
+                }
+            }
+            if reloaded {
+                self.current.clear();
+                self.previous.clear();
+            }
+        }
+
+        fn resolve(&self, addr: IpAddr) -> Location {
+            let mut location = Location::default();
+            for db in self.databases.iter() {
+                if location.country.is_none() {
+                    location.country = db.reader.lookup::<geoip2::Country>(addr).ok()
+                        .and_then(|c| c.country)
+                        .and_then(|c| c.iso_code)
+                        .map(str::to_string);
+                }
+                if location.asn.is_none() {
+                    location.asn = db.reader.lookup::<geoip2::Asn>(addr).ok()
+                        .and_then(|a| a.autonomous_system_number);
+                }
+            }
+            location
+        }
+    }
+}
+
+#[cfg(all(test, feature = "geoip"))]
+mod tests {
+    use std::{fs, net::IpAddr, path::PathBuf};
+
+    use crate::test_support::{write_mmdb, Scratch};
+
+    use super::{GeoIp, Location};
+
+    /// Write a database to `name` in `scratch`, by way of a rename as a MaxMind update would.
+    fn write(scratch: &Scratch, name: &str, networks: &[(&str, Option<&str>, Option<u32>)]) -> PathBuf {
+        let path = scratch.path().join(name);
+        let partial = scratch.path().join(format!("{}.partial", name));
+        write_mmdb(&partial, networks).unwrap();
+        fs::rename(&partial, &path).unwrap();
+        path
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn location(country: Option<&str>, asn: Option<u32>) -> Location {
+        Location { country: country.map(str::to_string), asn }
+    }
+
+    #[test]
+    fn country_and_asn_come_from_whichever_database_has_them() {
+        let scratch = Scratch::dir("lookup");
+        let country = write(&scratch, "country.mmdb", &[("192.0.2.0/24", Some("NZ"), None), ("198.51.100.0/24", Some("DE"), None)]);
+        let asn = write(&scratch, "asn.mmdb", &[("192.0.2.0/25", None, Some(64500)), ("203.0.113.0/24", None, Some(64501))]);
+        let geoip = GeoIp::open(vec![country, asn]).unwrap();
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("NZ"), Some(64500)));
+        assert_eq!(geoip.lookup(addr("192.0.2.200")), location(Some("NZ"), None));
+        assert_eq!(geoip.lookup(addr("198.51.100.7")), location(Some("DE"), None));
+        assert_eq!(geoip.lookup(addr("203.0.113.7")), location(None, Some(64501)));
+        // The second time round comes from the cache, and mustn't differ
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("NZ"), Some(64500)));
+    }
+
+    #[test]
+    fn an_address_no_database_knows_has_no_location() {
+        let scratch = Scratch::dir("miss");
+        let country = write(&scratch, "country.mmdb", &[("192.0.2.0/24", Some("NZ"), None)]);
+        let geoip = GeoIp::open(vec![country]).unwrap();
+        assert_eq!(geoip.lookup(addr("192.0.3.1")), Location::default());
+        assert_eq!(geoip.lookup(addr("2001:db8::1")), Location::default());
+        assert_eq!(GeoIp::open(Vec::new()).unwrap().lookup(addr("192.0.2.1")), Location::default());
+    }
+
+    #[test]
+    fn a_reload_swaps_in_the_replaced_file_and_forgets_what_was_cached() {
+        let scratch = Scratch::dir("reload");
+        let country = write(&scratch, "country.mmdb", &[("192.0.2.0/24", Some("NZ"), None)]);
+        let geoip = GeoIp::open(vec![country]).unwrap();
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("NZ"), None));
+        write(&scratch, "country.mmdb", &[("192.0.2.0/24", Some("AU"), None)]);
+        // Not yet time for the file to be checked on its own
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("NZ"), None));
+        geoip.reload();
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("AU"), None));
+    }
+
+    #[test]
+    fn reopening_on_other_files_switches_to_them_unless_one_is_missing() {
+        let scratch = Scratch::dir("reopen");
+        let country = write(&scratch, "country.mmdb", &[("192.0.2.0/24", Some("NZ"), None)]);
+        let asn = write(&scratch, "asn.mmdb", &[("192.0.2.0/24", None, Some(64500))]);
+        let geoip = GeoIp::open(vec![country.clone()]).unwrap();
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("NZ"), None));
+        geoip.reopen(vec![country, scratch.path().join("missing.mmdb")]);
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(Some("NZ"), None));
+        geoip.reopen(vec![asn]);
+        assert_eq!(geoip.lookup(addr("192.0.2.1")), location(None, Some(64500)));
+    }
+}
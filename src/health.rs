@@ -0,0 +1,30 @@
+use crate::{cli::HealthcheckArgs, db, query::now_secs};
+
+/// Seconds since the collector last wrote anything: a new state row, or maintenance refreshing
+/// a connected client.
+pub fn last_write_age(db: &rusqlite::Connection) -> rusqlite::Result<Option<f64>> {
+    let latest: f64 = db.query_row("
+        SELECT max(coalesce((SELECT max(instime) FROM state_all), 0), coalesce((SELECT max(last_seen) FROM clients), 0));
+    ", [], |row| row.get(0))?;
+    Ok((latest > 0.0).then(|| now_secs() - latest))
+}
+
+/// Entry point for `glosco healthcheck`; exits with 1 if the database is stale or unreadable.
+pub fn run(args: HealthcheckArgs) {
+    let age = db::open_read_only(&args.database).and_then(|db| last_write_age(&db));
+    match age {
+        Ok(Some(age)) if age <= args.max_age => println!("ok: last write {:.0}s ago", age),
+        Ok(Some(age)) => {
+            println!("stale: last write {:.0}s ago", age);
+            std::process::exit(1);
+        },
+        Ok(None) => {
+            println!("stale: nothing written yet");
+            std::process::exit(1);
+        },
+        Err(e) => {
+            println!("unreadable: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,69 @@
+use std::{io, net::SocketAddr, thread};
+
+use tiny_http::{Header, Request, Server};
+
+/// Answer HTTP requests on `bind` with `handle`, one at a time on a thread of its own, naming
+/// the service as `name` when a response fails.
+pub fn serve<F: FnMut(Request) -> io::Result<()> + Send + 'static>(bind: SocketAddr, name: &'static str, mut handle: F) -> io::Result<()> {
+    let server = Server::http(bind).map_err(io::Error::other)?;
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if let Err(e) = handle(request) {
+                println!("{} response error: {:?}", name, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+pub fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).expect("bad header")
+}
+
+pub fn split_url(url: &str) -> (String, String) {
+    match url.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (url.to_string(), String::new()),
+    }
+}
+
+pub fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| match kv.split_once('=') {
+            Some((k, v)) => (percent_decode(k), percent_decode(v)),
+            None => (percent_decode(kv), String::new()),
+        })
+        .collect()
+}
+
+pub fn param<'a>(params: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    params.iter()
+        .find(|(k, v)| k == key && !v.is_empty())
+        .map(|(_, v)| v.as_str())
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => out.push(b' '),
+            b'%' if idx + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[idx + 1 ..= idx + 2]).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => {
+                        out.push(b);
+                        idx += 2;
+                    },
+                    None => out.push(b'%'),
+                }
+            },
+            b => out.push(b),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
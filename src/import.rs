@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use crate::{alert::Kind, cli::ImportArgs, observe::ObserverConfig, server::Importer};
+
+/// Messages stored per transaction.
+const BATCH: usize = 1000;
+
+/// Stands in for the peer address on imported rows.
+const SOURCE: &str = "import";
+
+/// Entry point for `glosco import`: run the capture files through an offline observer and store
+/// what it sees under `--ident`, as of the capture's own timestamps.
+pub fn run(args: ImportArgs) {
+    let mut observer = ObserverConfig::default();
+    for path in args.pcaps.iter() {
+        observer.add_file(path.clone());
+    }
+    let observer = observer.start().expect("failed to read captures");
+
+    let mut importer = if args.dry_run {
+        None
+    } else {
+        Some(Importer::open(&args.database).expect("failed to open database"))
+    };
+    let mut counts: BTreeMap<Kind, u64> = BTreeMap::new();
+    let (mut seen, mut stored) = (0u64, 0u64);
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut progress = Instant::now();
+    for bundle in observer {
+        for message in bundle {
+            *counts.entry(Kind::of(&message)).or_default() += 1;
+            seen += 1;
+            batch.push(message);
+        }
+        if batch.len() >= BATCH {
+            if let Some(importer) = importer.as_mut() {
+                stored += importer.store(&args.ident, SOURCE, &batch).expect("failed to store messages") as u64;
+            }
+            batch.clear();
+        }
+        if progress.elapsed().as_secs() >= 5 {
+            println!("{} messages so far, {} stored", seen, stored);
+            progress = Instant::now();
+        }
+    }
+    if let Some(importer) = importer.as_mut() {
+        stored += importer.store(&args.ident, SOURCE, &batch).expect("failed to store messages") as u64;
+    }
+
+    for (kind, count) in counts.iter() {
+        println!("{}: {}", kind, count);
+    }
+    if args.dry_run {
+        println!("{} messages (dry run, nothing stored)", seen);
+    } else {
+        println!("{} messages, {} stored under {} ({} duplicates)", seen, stored, args.ident, seen - stored);
+    }
+}
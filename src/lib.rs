@@ -1,3 +1,84 @@
+//! Track connection state across large networks: sensors (`client`) observe connections and
+//! report them to collectors (`server`), which store them in SQLite.
+//!
+//! Both can run inside another process with `run_client` and `run_server`; the `glosco`
+//! binaries are thin wrappers around the same code.
+
+// So what `#[derive(Coder)]` generates names this crate the same way here as anywhere else
+extern crate self as glosco;
+
 pub mod observe;
+pub mod bus;
+pub mod radiotap;
+pub mod sll;
+pub mod decap;
+pub mod scan;
+pub mod ring;
 pub mod coding;
+pub mod view;
 pub mod sync;
+pub mod eventlog;
+pub mod forward;
+pub mod filter;
+pub mod dns;
+pub mod alert;
+pub mod subscribe;
+pub mod remote;
+pub mod tail;
+pub mod geoip;
+pub mod timefmt;
+pub mod output;
+pub mod http;
+pub mod metrics;
+pub mod settings;
+pub mod daemon;
+pub mod tui;
+#[cfg(feature = "mesh")]
+pub mod mesh;
+#[cfg(feature = "sqlite")]
+pub mod db;
+#[cfg(feature = "sqlite")]
+pub mod changes;
+#[cfg(feature = "sqlite")]
+pub mod merge;
+#[cfg(feature = "sqlite")]
+pub mod partition;
+#[cfg(feature = "sqlite")]
+pub mod shard;
+#[cfg(feature = "sqlite")]
+pub mod query;
+#[cfg(feature = "sqlite")]
+pub mod sessions;
+#[cfg(feature = "sqlite")]
+pub mod rdns;
+#[cfg(feature = "sqlite")]
+pub mod api;
+#[cfg(feature = "sqlite")]
+pub mod health;
+#[cfg(feature = "sqlite")]
+pub mod report;
+#[cfg(feature = "sqlite")]
+pub mod admin;
+#[cfg(feature = "sqlite")]
+pub mod import;
+#[cfg(feature = "sqlite")]
+pub mod server;
+#[cfg(feature = "test-util")]
+pub mod test_support;
+pub mod client;
+pub mod cli;
+
+pub use client::{ClientHandle, ClientSettings};
+#[cfg(feature = "sqlite")]
+pub use server::{ServerHandle, ServerSettings};
+
+/// Start a sensor in the background, capturing and reporting until its handle shuts it down.
+pub fn run_client(settings: ClientSettings) -> std::io::Result<ClientHandle> {
+    client::start(settings)
+}
+
+/// Start a collector in the background, accepting sensors until its handle shuts it down.
+#[cfg(feature = "sqlite")]
+pub fn run_server(settings: ServerSettings) -> std::io::Result<ServerHandle> {
+    server::start(settings)
+}
@@ -0,0 +1,355 @@
+use std::{fmt::{self, Display, Formatter}, str::FromStr, time::SystemTime};
+
+use rusqlite::named_params;
+
+use crate::{cli::MergeArgs, db, partition::{self, Partitions}, server};
+
+/// A label to put in front of the idents from one source, given as `LABEL=SOURCE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    pub label: String,
+    pub source: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadPrefix(String);
+
+impl Display for BadPrefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "expected LABEL=SOURCE, got {:?}", self.0)
+    }
+}
+
+impl std::error::Error for BadPrefix {}
+
+impl FromStr for Prefix {
+    type Err = BadPrefix;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((label, source)) if !label.is_empty() && !source.is_empty() => Ok(Self {
+                label: label.to_string(),
+                source: source.to_string(),
+            }),
+            _ => Err(BadPrefix(s.to_string())),
+        }
+    }
+}
+
+/// Rows copied from one source.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Merged {
+    pub state: usize,
+    pub names: usize,
+    pub clients: usize,
+}
+
+/// Copy `source`'s state, names and clients (with their tags and labels) into `db`, skipping rows `db` already has. Idents
+/// become `label/ident` if a label is given; otherwise idents that both databases have are taken
+/// to be the same sensor.
+///
+/// The source has to be at the same schema version as `db`; everything is copied in one
+/// transaction.
+pub fn merge(db: &mut rusqlite::Connection, partitions: &Partitions, source: &str, label: Option<&str>) -> rusqlite::Result<Merged> {
+    db.execute("ATTACH DATABASE ? AS src;", [source])?;
+    let result = copy(db, partitions, source, label);
+    db.execute_batch("DETACH DATABASE src;")?;
+    result
+}
+
+fn copy(db: &mut rusqlite::Connection, partitions: &Partitions, source: &str, label: Option<&str>) -> rusqlite::Result<Merged> {
+    let columns = partition::COLUMNS.replacen(", ident,", ", coalesce(:label || '/' || ident, ident),", 1);
+    let txn = db.transaction()?;
+    let mut merged = Merged::default();
+    if partitions.is_partitioned() {
+        let days: Vec<i64> = {
+            let mut stmt = txn.prepare("
+                SELECT DISTINCT CAST(instime / 86400 AS INTEGER) FROM src.state_all WHERE instime IS NOT NULL ORDER BY 1;
+            ")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+        for day in days {
+            let start = day as f64 * 86400.0;
+            let table = partitions.table(&txn, start)?;
+            let copied = txn.execute(&format!("
+                INSERT OR IGNORE INTO main.{} ({})
+                SELECT {} FROM src.state_all WHERE instime >= :start AND instime < :end;
+            ", table, partition::COLUMNS, columns), named_params! {
+                ":label": label,
+                ":start": start,
+                ":end": start + 86400.0,
+            })?;
+            println!("{}: {} state rows into {}", source, copied, table);
+            merged.state += copied;
+        }
+    } else {
+        merged.state = txn.execute(&format!("
+            INSERT OR IGNORE INTO main.state ({})
+            SELECT {} FROM src.state_all;
+        ", partition::COLUMNS, columns), named_params! {
+            ":label": label,
+        })?;
+    }
+    merged.names = txn.execute("
+        INSERT INTO main.names (instime, querier, responder, name, addr, port, text, source, rname, ttl)
+        SELECT instime, querier, responder, name, addr, port, text, source, rname, ttl FROM src.names n
+        WHERE NOT EXISTS (
+            SELECT 1 FROM main.names m
+            WHERE m.instime IS n.instime AND m.name IS n.name AND m.addr IS n.addr AND m.port IS n.port
+                AND m.text IS n.text AND m.querier IS n.querier AND m.responder IS n.responder AND m.source IS n.source
+        );
+    ", [])?;
+    merged.clients = txn.execute("
+        INSERT INTO main.clients (ident, agent, keepalive, first_seen, last_seen, skew, max_skew, latency_p95, early, legacy, lost_frames)
+        SELECT coalesce(:label || '/' || ident, ident), agent, keepalive, first_seen, last_seen, skew, max_skew, latency_p95, early, legacy, lost_frames
+        FROM src.clients WHERE true
+        ON CONFLICT (ident) DO UPDATE SET
+            agent = coalesce(agent, excluded.agent),
+            keepalive = coalesce(keepalive, excluded.keepalive),
+            first_seen = min(first_seen, excluded.first_seen),
+            last_seen = max(last_seen, excluded.last_seen),
+            legacy = iif(excluded.last_seen > last_seen, excluded.legacy, legacy),
+            max_skew = max(coalesce(max_skew, 0), coalesce(excluded.max_skew, 0)),
+            latency_p95 = max(coalesce(latency_p95, 0), coalesce(excluded.latency_p95, 0)),
+            early = coalesce(early, 0) + coalesce(excluded.early, 0),
+            lost_frames = coalesce(lost_frames, 0) + coalesce(excluded.lost_frames, 0);
+    ", named_params! {
+        ":label": label,
+    })?;
+    // Tags stay as the destination has them for idents it already tagged
+    txn.execute("
+        INSERT OR IGNORE INTO main.client_tags (ident, key, value)
+        SELECT coalesce(:label || '/' || ident, ident), key, value FROM src.client_tags
+        WHERE coalesce(:label || '/' || ident, ident) NOT IN (SELECT ident FROM main.client_tags);
+    ", named_params! {
+        ":label": label,
+    })?;
+    // Operator labels are kept as whichever was set last
+    txn.execute("
+        INSERT INTO main.ident_labels (ident, key, value, set_at)
+        SELECT coalesce(:label || '/' || ident, ident), key, value, set_at FROM src.ident_labels WHERE true
+        ON CONFLICT (ident, key) DO UPDATE SET value = excluded.value, set_at = excluded.set_at
+        WHERE excluded.set_at > set_at;
+    ", named_params! {
+        ":label": label,
+    })?;
+    txn.commit()?;
+    Ok(merged)
+}
+
+/// Entry point for `glosco merge`.
+pub fn run(args: MergeArgs) {
+    let mut db = db::open(&args.into).expect("failed to open destination database");
+    server::migrate(&mut db);
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .expect("time is before UNIX epoch!")
+        .as_secs_f64();
+    let partitions = Partitions::open(&mut db, false, now).expect("failed to open destination database");
+
+    let mut total = Merged::default();
+    for source in args.sources.iter() {
+        let version: i64 = db::open_read_only(source)
+            .and_then(|src| src.query_row("PRAGMA user_version;", [], |row| row.get(0)))
+            .expect("failed to open source database");
+        if version != server::schema_version() {
+            panic!("{} is at schema version {} rather than {}; run this version's collector on it once first",
+                source, version, server::schema_version());
+        }
+        let label = args.prefix.iter()
+            .find(|prefix| prefix.source == *source)
+            .map(|prefix| prefix.label.as_str());
+        println!("merging {}{}", source, label.map(|label| format!(" as {}/", label)).unwrap_or_default());
+        let merged = merge(&mut db, &partitions, source, label).expect("failed to merge database");
+        println!("{}: {} state rows, {} names, {} clients", source, merged.state, merged.names, merged.clients);
+        total.state += merged.state;
+        total.names += merged.names;
+        total.clients += merged.clients;
+    }
+
+    println!("rebuilding indexes and hourly summaries");
+    db.execute_batch("
+        REINDEX;
+        DELETE FROM summary_hourly;
+        DELETE FROM watermarks WHERE name = 'summary_hourly';
+    ").expect("failed to reset summaries");
+    let buckets = server::summarize(&db, now).expect("failed to rebuild summaries");
+    println!("merged {} state rows, {} names and {} clients; {} summary buckets", total.state, total.names, total.clients, buckets);
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use crate::{coding::{ENDED_MARK, START_MARK}, test_support::Scratch};
+
+    /// Midnight UTC starting 2025-06-12.
+    const MIDNIGHT: f64 = 1_749_686_400.0;
+    const DAY: f64 = 86400.0;
+
+    /// Open `scratch`, fully migrated and unpartitioned like an ordinary collector's database.
+    fn migrated(scratch: &Scratch) -> rusqlite::Connection {
+        let mut db = db::open(scratch.path()).unwrap();
+        server::migrate(&mut db);
+        db
+    }
+
+    fn store(db: &rusqlite::Connection, ident: &str, srcport: u16, state: u8, instime: f64) {
+        db.execute("
+            INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, last_seen)
+            VALUES (?1, ?2, ?3, '127.0.0.1:40000', '10.0.0.1', ?4, '10.0.0.2', 443, 6, ?5, ?1);
+        ", params![instime, MIDNIGHT, ident, srcport, state]).unwrap();
+    }
+
+    fn name(db: &rusqlite::Connection, name: &str, instime: f64) {
+        db.execute("
+            INSERT INTO names (instime, querier, responder, name, addr, source)
+            VALUES (?1, '10.0.0.1', '10.0.0.53', ?2, '10.0.0.2', 'sensor');
+        ", params![instime, name]).unwrap();
+    }
+
+    fn client(db: &rusqlite::Connection, ident: &str, first_seen: f64, last_seen: f64, lost_frames: i64) {
+        db.execute("
+            INSERT INTO clients (ident, first_seen, last_seen, lost_frames) VALUES (?1, ?2, ?3, ?4);
+        ", params![ident, first_seen, last_seen, lost_frames]).unwrap();
+    }
+
+    /// Two collectors that both heard from sensor `a`, the first a little earlier than the second:
+    /// port 2's start is in both, and the second also has its end, port 3, another name and
+    /// sensor `b`.
+    fn sources() -> (Scratch, Scratch) {
+        let first = Scratch::new("first");
+        let db = migrated(&first);
+        store(&db, "a", 1, START_MARK, MIDNIGHT);
+        store(&db, "a", 2, START_MARK, MIDNIGHT + 1.0);
+        name(&db, "example.com", MIDNIGHT);
+        client(&db, "a", MIDNIGHT, MIDNIGHT + 10.0, 1);
+
+        let second = Scratch::new("second");
+        let db = migrated(&second);
+        store(&db, "a", 2, START_MARK, MIDNIGHT + 1.0);
+        store(&db, "a", 2, ENDED_MARK, MIDNIGHT + DAY + 2.0);
+        store(&db, "b", 3, START_MARK, MIDNIGHT + 3.0);
+        name(&db, "example.com", MIDNIGHT);
+        name(&db, "example.org", MIDNIGHT + 3.0);
+        client(&db, "a", MIDNIGHT + 5.0, MIDNIGHT + 20.0, 2);
+        client(&db, "b", MIDNIGHT + 3.0, MIDNIGHT + 3.0, 0);
+        (first, second)
+    }
+
+    fn destination(partition: bool) -> (rusqlite::Connection, Partitions) {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        server::migrate(&mut db);
+        let partitions = Partitions::open(&mut db, partition, MIDNIGHT).unwrap();
+        (db, partitions)
+    }
+
+    fn state(db: &rusqlite::Connection, table: &str) -> Vec<(String, u16, u8)> {
+        db.prepare(&format!("SELECT ident, srcport, state FROM {} ORDER BY instime, srcport", table)).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn names(db: &rusqlite::Connection) -> Vec<String> {
+        db.prepare("SELECT name FROM names ORDER BY instime, name").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn clients(db: &rusqlite::Connection) -> Vec<(String, f64, f64, i64)> {
+        db.prepare("SELECT ident, first_seen, last_seen, lost_frames FROM clients ORDER BY ident").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn owned(rows: &[(&str, u16, u8)]) -> Vec<(String, u16, u8)> {
+        rows.iter().map(|&(ident, port, state)| (ident.to_string(), port, state)).collect()
+    }
+
+    #[test]
+    fn overlapping_sources_merge_without_duplicates() {
+        for partition in [false, true] {
+            let (first, second) = sources();
+            let (mut db, partitions) = destination(partition);
+
+            let merged = merge(&mut db, &partitions, first.database(), None).unwrap();
+            assert_eq!((merged.state, merged.names, merged.clients), (2, 1, 1), "partitioned: {}", partition);
+            // Only what the first didn't have; sensor a's inventory row is folded into the existing one
+            let merged = merge(&mut db, &partitions, second.database(), None).unwrap();
+            assert_eq!((merged.state, merged.names, merged.clients), (2, 1, 2), "partitioned: {}", partition);
+
+            let all = owned(&[
+                ("a", 1, START_MARK), ("a", 2, START_MARK), ("b", 3, START_MARK), ("a", 2, ENDED_MARK),
+            ]);
+            assert_eq!(state(&db, "state_all"), all, "partitioned: {}", partition);
+            assert_eq!(state(&db, "latest_state"), owned(&[
+                ("a", 1, START_MARK), ("b", 3, START_MARK), ("a", 2, ENDED_MARK),
+            ]), "partitioned: {}", partition);
+            assert_eq!(names(&db), ["example.com", "example.org"]);
+            assert_eq!(clients(&db), [
+                ("a".to_string(), MIDNIGHT, MIDNIGHT + 20.0, 3),
+                ("b".to_string(), MIDNIGHT + 3.0, MIDNIGHT + 3.0, 0),
+            ]);
+
+            // Again, and nothing new turns up
+            let merged = merge(&mut db, &partitions, second.database(), None).unwrap();
+            assert_eq!((merged.state, merged.names), (0, 0), "partitioned: {}", partition);
+            assert_eq!(state(&db, "state_all"), all, "partitioned: {}", partition);
+            assert_eq!(names(&db), ["example.com", "example.org"]);
+            if partition {
+                assert_eq!(partition::tables(&db).unwrap(), ["state_20250612", "state_20250613"]);
+            }
+        }
+    }
+
+    #[test]
+    fn a_label_keeps_one_sources_sensors_apart() {
+        let (first, second) = sources();
+        let (mut db, partitions) = destination(false);
+        merge(&mut db, &partitions, first.database(), None).unwrap();
+        let merged = merge(&mut db, &partitions, second.database(), Some("site2")).unwrap();
+        // Port 2's start is no longer the same row once it's site2's
+        assert_eq!((merged.state, merged.names, merged.clients), (3, 1, 2));
+
+        assert_eq!(state(&db, "state_all"), owned(&[
+            ("a", 1, START_MARK), ("a", 2, START_MARK), ("site2/a", 2, START_MARK),
+            ("site2/b", 3, START_MARK), ("site2/a", 2, ENDED_MARK),
+        ]));
+        assert_eq!(clients(&db), [
+            ("a".to_string(), MIDNIGHT, MIDNIGHT + 10.0, 1),
+            ("site2/a".to_string(), MIDNIGHT + 5.0, MIDNIGHT + 20.0, 2),
+            ("site2/b".to_string(), MIDNIGHT + 3.0, MIDNIGHT + 3.0, 0),
+        ]);
+    }
+
+    #[test]
+    fn run_labels_the_sources_named_by_prefix() {
+        let (first, second) = sources();
+        let into = Scratch::new("into");
+        run(MergeArgs {
+            into: into.database().to_string(),
+            sources: vec![first.database().to_string(), second.database().to_string()],
+            prefix: vec![format!("site2={}", second.database()).parse().unwrap()],
+        });
+
+        let db = db::open(into.path()).unwrap();
+        let idents: Vec<String> = db.prepare("SELECT DISTINCT ident FROM state_all ORDER BY ident").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(idents, ["a", "site2/a", "site2/b"]);
+        assert_eq!(names(&db), ["example.com", "example.org"]);
+        // Summaries were rebuilt up to the newest row merged (the hours themselves are past retention)
+        let watermark: f64 = db.query_row("SELECT value FROM watermarks WHERE name = 'summary_hourly'", [], |row| row.get(0)).unwrap();
+        assert_eq!(watermark, MIDNIGHT + DAY + 2.0);
+    }
+
+    #[test]
+    fn prefixes_need_a_label_and_a_source() {
+        assert_eq!("site1=a.db".parse(), Ok(Prefix { label: "site1".to_string(), source: "a.db".to_string() }));
+        assert_eq!("site1=dir/a=b.db".parse::<Prefix>().unwrap().source, "dir/a=b.db");
+        for bad in ["a.db", "=a.db", "site1=", ""] {
+            assert_eq!(bad.parse::<Prefix>(), Err(BadPrefix(bad.to_string())));
+        }
+    }
+}
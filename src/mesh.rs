@@ -1,16 +1,307 @@
-use std::{sync::Arc, net::{SocketAddr, SocketAddrV4, Ipv4Addr}, io};
+use std::{collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet}, fs, hash::{BuildHasher, Hasher}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, net::{SocketAddr, SocketAddrV4, Ipv4Addr}, io, path::PathBuf, time::{Duration, Instant, SystemTime}};
 
-use futures::future::try_join_all;
+use tokio::{io::AsyncWriteExt, net::{tcp::OwnedWriteHalf, TcpListener, TcpStream, UdpSocket}, sync::{broadcast::{self, error::RecvError}, Notify}, time};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
 
-use tokio::{sync::broadcast, net::{TcpListener, TcpStream}};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::MeshStatusArgs;
+use crate::coding::{Coder, CodingVec, PROBE_ANSWER_MARK, PROBE_ASK_MARK};
 
 type Buffer = Arc<Vec<u8>>;
 
+/// Larger frames are taken as a broken (or hostile) peer rather than buffered.
+const MAX_FRAME: usize = 16 << 20;
+/// How often the connector looks for peers to dial.
+const DIAL_TICK: Duration = Duration::from_secs(1);
+/// Waits between failed dials to a peer start here and double up to the cap.
+const BACKOFF: (Duration, Duration) = (Duration::from_secs(1), Duration::from_secs(60));
+/// How long the peer file is left after a change before it's written, so a burst of changes
+/// (a discovery sweep, say) is one write.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+/// How often the peer file is written even without changes, so peers that have stayed linked
+/// all along don't look stale to the next start.
+const SAVE_EVERY: Duration = Duration::from_secs(3600);
+
+fn epoch_secs(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+fn from_epoch_secs(secs: f64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::try_from_secs_f64(secs).unwrap_or_default()
+}
+
+/// 128 random bits, for ids that needn't be coordinated.
+fn random_id() -> u128 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    // Each RandomState is keyed at random, so two of them give 128 unpredictable bits
+    let half = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(count);
+        hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos());
+        hasher.finish() as u128
+    };
+    half() << 64 | half()
+}
+
+/// What goes over a mesh link: a frame, the ident of the sensor that first published it, and
+/// an id for it that stays the same however many instances pass it on, so that each forwards
+/// it once even where links form a cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: u128,
+    /// Links the frame has crossed, this one included.
+    pub hops: u8,
+    pub ident: String,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Wrap `payload` from `ident` under a fresh random id.
+    pub fn new(ident: String, payload: Vec<u8>) -> Self {
+        Self { id: random_id(), hops: 0, ident, payload }
+    }
+}
+
+/// Sent over UDP now and then by an instance with discovery on, so that others on the segment
+/// can add it to their peer sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    /// `Announce::VERSION` of the sender; others are ignored.
+    pub version: u8,
+    /// Picked at random when the sender started, so it can tell its own announcements apart.
+    pub instance: u128,
+    /// Where the sender listens; an unspecified address stands for wherever the announcement
+    /// came from.
+    pub listens: Vec<SocketAddr>,
+}
+
+impl Announce {
+    pub const VERSION: u8 = 1;
+}
+
+/// Flooded through the mesh to map it: every instance an `Ask` reaches sends back an `Answer`,
+/// flooded in turn, saying how many links the `Ask` crossed to get there. Neither is handed to
+/// subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    Ask { probe: u128, asker: u128 },
+    Answer { probe: u128, asker: u128, instance: u128, hops: u8 },
+}
+
+/// An instance a probe reached, and how many links away it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reachable {
+    pub instance: u128,
+    pub hops: u8,
+}
+
+/// A frame passed around the mesh, and the peer it came in from, so it isn't echoed back.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// `None` for frames published by this instance itself.
+    pub from: Option<SocketAddr>,
+    pub id: u128,
+    /// Links crossed to get here; 0 for our own.
+    pub hops: u8,
+    pub ident: Arc<str>,
+    pub data: Buffer,
+}
+
+/// What's come over one link, while it's up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LinkStats {
+    /// Every frame that came in.
+    pub received: u64,
+    /// Frames passed on to the rest of the mesh.
+    pub forwarded: u64,
+    /// Frames seen before, or that couldn't be decoded.
+    pub dropped: u64,
+    /// Frames that had crossed more than the hop limit of links.
+    pub hop_exceeded: u64,
+    /// Frames sent the other way.
+    pub sent: u64,
+}
+
+/// Ids of the frames forwarded lately, in two generations: a new one is started once the
+/// current one is full or old enough, and the one before is forgotten. So an id is remembered
+/// for at least one generation, however busy the mesh is.
+#[derive(Debug)]
+struct Seen {
+    capacity: usize,
+    age: Duration,
+    current: HashSet<u128>,
+    previous: HashSet<u128>,
+    started: Instant,
+}
+
+impl Seen {
+    fn new(capacity: usize, age: Duration) -> Self {
+        Self {
+            capacity,
+            age,
+            current: HashSet::new(),
+            previous: HashSet::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Note `id`, returning whether it's new.
+    fn insert(&mut self, id: u128) -> bool {
+        if self.current.contains(&id) || self.previous.contains(&id) {
+            return false;
+        }
+        if self.current.len() >= self.capacity || self.started.elapsed() >= self.age {
+            self.previous = std::mem::take(&mut self.current);
+            self.started = Instant::now();
+        }
+        self.current.insert(id)
+    }
+}
+
+/// What the mesh knows of a peer it can dial.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Peer {
+    /// Whether there's an outbound link to it right now.
+    connected: bool,
+    /// Dials that have failed (or links that dropped) since it was last connected.
+    failures: u32,
+    /// When it may be dialed again.
+    next_dial: Instant,
+    last_error: Option<String>,
+    /// When it was first added.
+    added: SystemTime,
+    /// When a link to it last came up.
+    last_connected: Option<SystemTime>,
+}
+
+impl Peer {
+    fn new(added: SystemTime, last_connected: Option<SystemTime>) -> Self {
+        Self {
+            connected: false,
+            failures: 0,
+            next_dial: Instant::now(),
+            last_error: None,
+            added,
+            last_connected,
+        }
+    }
+}
+
+/// Where the peer set is kept between runs, and how long a peer that's never linked since
+/// `horizon` ago stays in it.
+#[derive(Debug, Clone)]
+struct PeerFile {
+    path: PathBuf,
+    horizon: Duration,
+}
+
+/// The peer file's contents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SavedPeers {
+    peers: Vec<SavedPeer>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedPeer {
+    addr: SocketAddr,
+    /// When it was first added, in seconds since the epoch.
+    added: f64,
+    /// When a link to it last came up, in seconds since the epoch.
+    last_connected: Option<f64>,
+}
+
+impl SavedPeer {
+    /// Whether it's been added or linked to within `horizon` of `now`.
+    fn fresh(&self, now: SystemTime, horizon: Duration) -> bool {
+        let last = from_epoch_secs(self.last_connected.unwrap_or(self.added).max(self.added));
+        now.duration_since(last).map_or(true, |age| age <= horizon)
+    }
+}
+
+impl PeerFile {
+    /// The peers saved last time that are still fresh. A missing file is an empty one, and so is
+    /// one that can't be read or parsed, after saying so.
+    fn load(&self) -> Vec<SavedPeer> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                println!("Mesh couldn't read peer file {:?}, starting without it: {:?}", self.path, e);
+                return Vec::new();
+            },
+        };
+        let saved: SavedPeers = match serde_json::from_str(&text) {
+            Ok(saved) => saved,
+            Err(e) => {
+                println!("Mesh couldn't parse peer file {:?}, starting without it: {}", self.path, e);
+                return Vec::new();
+            },
+        };
+        let now = SystemTime::now();
+        saved.peers.into_iter().filter(|peer| peer.fresh(now, self.horizon)).collect()
+    }
+
+    /// Replace the file with `peers`, by way of a temporary file beside it so a crash midway
+    /// leaves the old one whole.
+    fn save(&self, peers: Vec<SavedPeer>) -> io::Result<()> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let text = serde_json::to_string_pretty(&SavedPeers { peers }).expect("failed to encode peers");
+        fs::write(&temp, text)?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A link that's up.
+#[derive(Debug, Clone)]
+struct Link {
+    direction: Direction,
+    since: SystemTime,
+    stats: LinkStats,
+}
+
+/// One peer as `Mesh::peers` reports it: a link that's up, or a peer to dial that isn't.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    /// When the link came up, in seconds since the epoch; `None` while it's down.
+    pub connected_since: Option<f64>,
+    pub stats: LinkStats,
+    /// Why the last dial failed, for a peer that isn't linked.
+    pub last_error: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Mesh {
+    instance: u128,
     degree: usize,
-    broadcast: (broadcast::Sender<Buffer>, broadcast::Receiver<Buffer>),
+    /// Links up (or being dialed), inbound and outbound alike.
+    links: AtomicUsize,
+    known: Mutex<BTreeMap<SocketAddr, Peer>>,
+    seen: Mutex<Seen>,
+    max_hops: u8,
+    linked: Mutex<BTreeMap<SocketAddr, Link>>,
+    /// Probes we've sent, and who's answered so far.
+    probes: Mutex<HashMap<u128, Vec<Reachable>>>,
+    /// Frames for subscribers: all but probes.
+    delivered: broadcast::Sender<Frame>,
+    /// Frames for links to pass on.
+    broadcast: (broadcast::Sender<Frame>, broadcast::Receiver<Frame>),
     listeners: Vec<Arc<TcpListener>>,
+    peer_file: Option<PeerFile>,
+    /// Woken when the peer set changes in a way the peer file should hear about.
+    peers_changed: Notify,
 }
 
 #[derive(Debug, Clone)]
@@ -18,6 +309,13 @@ pub struct MeshConfig {
     degree: usize,
     buffer: usize,
     listens: Vec<SocketAddr>,
+    peers: Vec<SocketAddr>,
+    seen_capacity: usize,
+    seen_age: Duration,
+    discovery: Option<SocketAddr>,
+    announce_every: Duration,
+    max_hops: u8,
+    peer_file: Option<PeerFile>,
 }
 
 impl Default for MeshConfig {
@@ -26,31 +324,268 @@ impl Default for MeshConfig {
             degree: 4,
             buffer: 1024,
             listens: vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 12074))],
+            peers: Vec::new(),
+            seen_capacity: 65536,
+            seen_age: Duration::from_secs(60),
+            discovery: None,
+            announce_every: Duration::from_secs(30),
+            max_hops: 16,
+            peer_file: None,
         }
     }
 }
 
 impl MeshConfig {
+    /// Listen on `addrs` instead of the default port.
+    pub fn set_listens(&mut self, addrs: Vec<SocketAddr>) {
+        self.listens = addrs;
+    }
+
+    /// Links to keep at once, inbound and outbound; peers past that are hung up on, and no
+    /// more are dialed.
+    pub fn set_degree(&mut self, degree: usize) {
+        self.degree = degree;
+    }
+
+    /// How many frame ids to remember in each generation of the seen-set, and how long a
+    /// generation lasts; a frame that comes back around after two generations is forwarded again.
+    pub fn set_seen(&mut self, capacity: usize, age: Duration) {
+        self.seen_capacity = capacity;
+        self.seen_age = age;
+    }
+
+    /// Most links a frame may cross; it's dropped by whoever receives it past that.
+    pub fn set_max_hops(&mut self, hops: u8) {
+        self.max_hops = hops;
+    }
+
+    /// Seed the peer set with a mesh instance to dial.
+    pub fn add_peer(&mut self, addr: SocketAddr) {
+        self.peers.push(addr);
+    }
+
+    /// Find peers by announcing to `target` (normally a broadcast address) and listening for
+    /// announcements on its port.
+    pub fn set_discovery(&mut self, target: SocketAddr) {
+        self.discovery = Some(target);
+    }
+
+    /// How often to announce, with discovery on.
+    pub fn set_announce_interval(&mut self, every: Duration) {
+        self.announce_every = every;
+    }
+
+    /// Keep the peer set in `path` (as JSON) across restarts: it's read at startup to seed the
+    /// dialer and rewritten shortly after peers are added or linked to. Peers not linked to (or
+    /// added) within `horizon` are left out. A missing or broken file is started without.
+    pub fn set_peer_file(&mut self, path: PathBuf, horizon: Duration) {
+        self.peer_file = Some(PeerFile { path, horizon });
+    }
+
     pub async fn build(self) -> io::Result<Arc<Mesh>> {
-        let listeners = try_join_all(
-            self.listens
-                .into_iter()
-                .map(|a| TcpListener::bind(a))
-        ).await?
-            .into_iter()
-            .map(Arc::new)
-            .collect();
+        let mut listeners = Vec::with_capacity(self.listens.len());
+        for addr in self.listens {
+            listeners.push(Arc::new(TcpListener::bind(addr).await?));
+        }
         let mesh = Arc::new(Mesh {
+            instance: random_id(),
             degree: self.degree,
+            links: AtomicUsize::new(0),
+            known: Mutex::default(),
+            seen: Mutex::new(Seen::new(self.seen_capacity, self.seen_age)),
+            max_hops: self.max_hops,
+            linked: Mutex::default(),
+            probes: Mutex::default(),
+            delivered: broadcast::channel(self.buffer).0,
             broadcast: broadcast::channel(self.buffer),
             listeners,
+            peer_file: self.peer_file,
+            peers_changed: Notify::new(),
         });
+        if let Some(file) = &mesh.peer_file {
+            let saved = file.load();
+            println!("Mesh loaded {} peers from {:?}", saved.len(), file.path);
+            let mut known = mesh.known.lock().unwrap();
+            for peer in saved {
+                known.insert(peer.addr, Peer::new(from_epoch_secs(peer.added), peer.last_connected.map(from_epoch_secs)));
+            }
+        }
+        for addr in self.peers {
+            mesh.add_peer(addr);
+        }
         mesh.clone().boot_listeners();
+        tokio::spawn(mesh.clone().connector());
+        if mesh.peer_file.is_some() {
+            tokio::spawn(mesh.clone().persist());
+        }
+        if let Some(target) = self.discovery {
+            let socket = Arc::new(discovery_socket(target.port())?);
+            tokio::spawn(mesh.clone().announce(socket.clone(), target, self.announce_every));
+            tokio::spawn(mesh.clone().discover(socket));
+        }
         Ok(mesh)
     }
 }
 
+/// A UDP socket on `port` that can send broadcasts and shares the port with anything else
+/// listening for announcements on this host.
+fn discovery_socket(port: u16) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Write one frame with the same length prefix `coding` gives it.
+async fn write_frame(writer: &mut OwnedWriteHalf, envelope: &Envelope) -> io::Result<()> {
+    let mut payload = Vec::with_capacity(envelope.payload.len() + envelope.ident.len() + 22);
+    envelope.encode(&mut payload)?;
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    CodingVec::<u8, u32>::new(payload).encode(&mut frame)?;
+    writer.write_all(&frame).await
+}
+
 impl Mesh {
+    /// Where the listeners ended up, for when they were bound to port 0.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|listener| listener.local_addr()).collect()
+    }
+
+    /// Add a peer to dial, returning false if it was known already.
+    pub fn add_peer(&self, addr: SocketAddr) -> bool {
+        let mut known = self.known.lock().unwrap();
+        if known.contains_key(&addr) {
+            return false;
+        }
+        known.insert(addr, Peer::new(SystemTime::now(), None));
+        self.peers_changed.notify_one();
+        true
+    }
+
+    /// Picked at random when the mesh was built; how probes tell instances apart.
+    pub fn instance(&self) -> u128 {
+        self.instance
+    }
+
+    /// Every link that's up, then every peer to dial that isn't linked.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let linked = self.linked.lock().unwrap();
+        let mut peers: Vec<PeerInfo> = linked.iter().map(|(addr, link)| PeerInfo {
+            addr: *addr,
+            direction: link.direction,
+            connected_since: Some(epoch_secs(link.since)),
+            stats: link.stats,
+            last_error: None,
+        }).collect();
+        for (addr, peer) in self.known.lock().unwrap().iter() {
+            if !linked.contains_key(addr) {
+                peers.push(PeerInfo {
+                    addr: *addr,
+                    direction: Direction::Outbound,
+                    connected_since: None,
+                    stats: LinkStats::default(),
+                    last_error: peer.last_error.clone(),
+                });
+            }
+        }
+        peers
+    }
+
+    /// Links up right now, inbound and outbound.
+    pub fn links(&self) -> usize {
+        self.links.load(Ordering::SeqCst)
+    }
+
+    /// Send a frame of our own, reported by `ident`, to every peer.
+    pub fn publish(&self, ident: &str, data: Vec<u8>) {
+        let envelope = Envelope::new(ident.to_string(), data);
+        self.seen.lock().unwrap().insert(envelope.id);
+        let frame = Frame { from: None, id: envelope.id, hops: 0, ident: envelope.ident.into(), data: Arc::new(envelope.payload) };
+        // Neither can fail for want of receivers that matters: the mesh keeps one of its own for
+        // the links, and a frame nobody's subscribed to is meant to be lost
+        let _ = self.delivered.send(frame.clone());
+        let _ = self.broadcast.0.send(frame);
+    }
+
+    /// Every frame passed around the mesh from now on, ours included, apart from probes.
+    pub fn subscribe(&self) -> broadcast::Receiver<Frame> {
+        self.delivered.subscribe()
+    }
+
+    /// Flood a probe through the mesh, returning its id for `probed`.
+    pub fn probe(&self) -> u128 {
+        let probe = random_id();
+        self.probes.lock().unwrap().insert(probe, Vec::new());
+        self.send_probe(&Probe::Ask { probe, asker: self.instance });
+        probe
+    }
+
+    /// The instances that have answered a probe so far, this one included; asking again after
+    /// that only gets an empty list.
+    pub fn probed(&self, probe: u128) -> Vec<Reachable> {
+        let mut reached = vec![Reachable { instance: self.instance, hops: 0 }];
+        reached.extend(self.probes.lock().unwrap().remove(&probe).unwrap_or_default());
+        reached.sort_by_key(|reachable| (reachable.hops, reachable.instance));
+        reached
+    }
+
+    /// Probe the mesh and wait for answers, blocking the calling thread (which mustn't be one
+    /// of the mesh runtime's).
+    pub fn survey(&self, wait: Duration) -> Vec<Reachable> {
+        let probe = self.probe();
+        std::thread::sleep(wait);
+        self.probed(probe)
+    }
+
+    /// This instance, its peers, and (if `probe` is given) what a probe answered within that
+    /// long found reachable, as JSON.
+    pub fn status(&self, probe: Option<Duration>) -> serde_json::Value {
+        let reachable: Option<Vec<serde_json::Value>> = probe.map(|wait| self.survey(wait).iter()
+            .map(|reachable| serde_json::json!({
+                "instance": format!("{:032x}", reachable.instance),
+                "hops": reachable.hops,
+            }))
+            .collect());
+        let mut status = serde_json::json!({
+            "instance": format!("{:032x}", self.instance),
+            "links": self.links(),
+            "peers": self.peers(),
+        });
+        if let Some(reachable) = reachable {
+            status["reachable"] = reachable.into();
+        }
+        status
+    }
+
+    fn send_probe(&self, probe: &Probe) {
+        let mut payload = Vec::new();
+        probe.encode(&mut payload).expect("failed to encode probe");
+        let envelope = Envelope::new(String::new(), payload);
+        self.seen.lock().unwrap().insert(envelope.id);
+        let _ = self.broadcast.0.send(Frame { from: None, id: envelope.id, hops: 0, ident: envelope.ident.into(), data: Arc::new(envelope.payload) });
+    }
+
+    /// Answer an `Ask` that reached us `hops` links from its asker, or note an `Answer` to ours.
+    fn handle_probe(&self, payload: &[u8], hops: u8) {
+        match Probe::decode(&mut &*payload) {
+            Ok(Probe::Ask { probe, asker }) if asker != self.instance => {
+                self.send_probe(&Probe::Answer { probe, asker, instance: self.instance, hops });
+            },
+            Ok(Probe::Answer { probe, asker, instance, hops }) if asker == self.instance => {
+                if let Some(reached) = self.probes.lock().unwrap().get_mut(&probe) {
+                    reached.push(Reachable { instance, hops });
+                }
+            },
+            Ok(_) => (),
+            Err(e) => println!("Mesh bad probe: {:?}", e),
+        }
+    }
+
     fn boot_listeners(self: Arc<Self>) {
         self.listeners.iter().cloned().for_each(|socket| {
             let this = self.clone();
@@ -64,7 +599,623 @@ impl Mesh {
         });
     }
 
+    /// Send an announcement to `target` every so often.
+    async fn announce(self: Arc<Self>, socket: Arc<UdpSocket>, target: SocketAddr, every: Duration) {
+        let mut announcement = Vec::new();
+        Announce {
+            version: Announce::VERSION,
+            instance: self.instance,
+            listens: self.local_addrs().unwrap_or_default(),
+        }.encode(&mut announcement).expect("failed to encode announcement");
+        loop {
+            if let Err(e) = socket.send_to(&announcement, target).await {
+                println!("Mesh announce error to {:?}: {:?}", target, e);
+            }
+            time::sleep(every).await;
+        }
+    }
+
+    /// Add the instances that announce themselves to the peer set. Of each pair that finds the
+    /// other, only the one with the lower instance id dials, so they end up with one link.
+    async fn discover(self: Arc<Self>, socket: Arc<UdpSocket>) {
+        let mut buffer = vec![0; 1500];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("Mesh discovery error: {:?}", e);
+                    time::sleep(DIAL_TICK).await;
+                    continue;
+                },
+            };
+            let announce = match Announce::decode(&mut &buffer[.. len]) {
+                Ok(announce) if announce.version == Announce::VERSION => announce,
+                Ok(announce) => {
+                    println!("Mesh ignored announcement of version {} from {:?}", announce.version, from);
+                    continue;
+                },
+                Err(_) => continue,
+            };
+            if announce.instance <= self.instance {
+                continue;
+            }
+            for mut addr in announce.listens {
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(from.ip());
+                }
+                if self.add_peer(addr) {
+                    println!("Mesh discovered {:?}", addr);
+                }
+            }
+        }
+    }
+
+    /// Take a link slot, unless there are already `degree` links.
+    fn reserve(&self) -> bool {
+        if self.links.fetch_add(1, Ordering::SeqCst) >= self.degree {
+            self.links.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// Keep dialing known peers that aren't connected, while there's room for more links.
+    async fn connector(self: Arc<Self>) {
+        loop {
+            let now = Instant::now();
+            let due: Vec<SocketAddr> = self.known.lock().unwrap().iter()
+                .filter(|(_, peer)| !peer.connected && peer.next_dial <= now)
+                .map(|(addr, _)| *addr)
+                .collect();
+            for addr in due {
+                if !self.reserve() {
+                    break;
+                }
+                self.set_connected(addr, true);
+                tokio::spawn(self.clone().dial(addr));
+            }
+            time::sleep(DIAL_TICK).await;
+        }
+    }
+
+    /// Mark a peer as connected (or being dialed), or as having failed and needing to wait.
+    fn set_connected(&self, addr: SocketAddr, connected: bool) {
+        if let Some(peer) = self.known.lock().unwrap().get_mut(&addr) {
+            peer.connected = connected;
+            if !connected {
+                peer.failures += 1;
+                let wait = BACKOFF.0.saturating_mul(2u32.saturating_pow(peer.failures - 1)).min(BACKOFF.1);
+                peer.next_dial = Instant::now() + wait;
+            }
+        }
+    }
+
+    /// Dial a peer and pass frames over the link until it drops, in a slot already reserved.
+    async fn dial(self: Arc<Self>, addr: SocketAddr) {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => {
+                println!("Mesh connected to {:?}", addr);
+                if let Some(peer) = self.known.lock().unwrap().get_mut(&addr) {
+                    peer.failures = 0;
+                    peer.last_error = None;
+                    peer.last_connected = Some(SystemTime::now());
+                }
+                self.peers_changed.notify_one();
+                self.clone().link(stream, addr, Direction::Outbound).await;
+            },
+            Err(e) => {
+                println!("Mesh connect error to {:?}: {:?}", addr, e);
+                if let Some(peer) = self.known.lock().unwrap().get_mut(&addr) {
+                    peer.last_error = Some(e.to_string());
+                }
+            },
+        }
+        self.links.fetch_sub(1, Ordering::SeqCst);
+        self.set_connected(addr, false);
+    }
+
     async fn take_client(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
-        while let Ok()
+        if !self.reserve() {
+            println!("Mesh refused {:?}, already at {} links", addr, self.degree);
+            return;
+        }
+        self.clone().link(stream, addr, Direction::Inbound).await;
+        self.links.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Pass frames between a peer and the mesh until the link drops: what the peer sends goes
+    /// to everyone else, and what anyone else sends goes to it. Inbound and outbound links are
+    /// alike here.
+    async fn link(self: Arc<Self>, stream: TcpStream, addr: SocketAddr, direction: Direction) {
+        println!("Mesh peer {:?} joined", addr);
+        self.linked.lock().unwrap().insert(addr, Link {
+            direction,
+            since: SystemTime::now(),
+            stats: LinkStats::default(),
+        });
+        let (reader, mut writer) = stream.into_split();
+        let mut outgoing = self.broadcast.0.subscribe();
+        let this = self.clone();
+        let forward = tokio::spawn(async move {
+            loop {
+                match outgoing.recv().await {
+                    Ok(frame) if frame.from == Some(addr) => (),
+                    Ok(frame) => {
+                        let envelope = Envelope {
+                            id: frame.id,
+                            hops: frame.hops.saturating_add(1),
+                            ident: frame.ident.to_string(),
+                            payload: frame.data.to_vec(),
+                        };
+                        if let Err(e) = write_frame(&mut writer, &envelope).await {
+                            println!("Mesh send error to {:?}: {:?}", addr, e);
+                            break;
+                        }
+                        this.count(addr, |stats| stats.sent += 1);
+                    },
+                    Err(RecvError::Lagged(missed)) => println!("Mesh peer {:?} missed {} frames", addr, missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        let codec = LengthDelimitedCodec::builder().max_frame_length(MAX_FRAME).new_codec();
+        let mut incoming = FramedRead::new(reader, codec);
+        while let Some(frame) = incoming.next().await {
+            match frame {
+                Ok(frame) => {
+                    self.count(addr, |stats| stats.received += 1);
+                    match Envelope::decode(&mut &*frame) {
+                        Ok(envelope) if envelope.hops > self.max_hops => self.count(addr, |stats| stats.hop_exceeded += 1),
+                        Ok(envelope) => if self.seen.lock().unwrap().insert(envelope.id) {
+                            self.count(addr, |stats| stats.forwarded += 1);
+                            let probe = matches!(envelope.payload.first(), Some(&PROBE_ASK_MARK | &PROBE_ANSWER_MARK));
+                            if probe {
+                                self.handle_probe(&envelope.payload, envelope.hops);
+                            }
+                            let frame = Frame {
+                                from: Some(addr),
+                                id: envelope.id,
+                                hops: envelope.hops,
+                                ident: envelope.ident.into(),
+                                data: Arc::new(envelope.payload),
+                            };
+                            if !probe {
+                                let _ = self.delivered.send(frame.clone());
+                            }
+                            let _ = self.broadcast.0.send(frame);
+                        } else {
+                            self.count(addr, |stats| stats.dropped += 1);
+                        },
+                        Err(e) => {
+                            println!("Mesh bad frame from {:?}: {:?}", addr, e);
+                            self.count(addr, |stats| stats.dropped += 1);
+                        },
+                    }
+                },
+                Err(e) => {
+                    println!("Mesh read error from {:?}: {:?}", addr, e);
+                    break;
+                },
+            }
+        }
+        // Dropping the forwarder's receiver is all it takes to leave the channel
+        forward.abort();
+        if let Some(link) = self.linked.lock().unwrap().remove(&addr) {
+            println!("Mesh peer {:?} left: {:?}", addr, link.stats);
+        }
+    }
+
+    /// Write the peer file shortly after each change to the peer set, and now and then besides.
+    async fn persist(self: Arc<Self>) {
+        let Some(file) = &self.peer_file else {
+            return;
+        };
+        loop {
+            let _ = time::timeout(SAVE_EVERY, self.peers_changed.notified()).await;
+            time::sleep(SAVE_DELAY).await;
+            if let Err(e) = file.save(self.saved_peers()) {
+                println!("Mesh couldn't write peer file {:?}: {:?}", file.path, e);
+            }
+        }
+    }
+
+    /// The peer set as the peer file keeps it: peers linked right now count as just connected,
+    /// and peers gone stale are dropped.
+    fn saved_peers(&self) -> Vec<SavedPeer> {
+        let horizon = self.peer_file.as_ref().map_or(Duration::MAX, |file| file.horizon);
+        let now = SystemTime::now();
+        let linked = self.linked.lock().unwrap();
+        self.known.lock().unwrap().iter()
+            .map(|(addr, peer)| SavedPeer {
+                addr: *addr,
+                added: epoch_secs(peer.added),
+                last_connected: if linked.contains_key(addr) {
+                    Some(epoch_secs(now))
+                } else {
+                    peer.last_connected.map(epoch_secs)
+                },
+            })
+            .filter(|peer| peer.fresh(now, horizon))
+            .collect()
+    }
+
+    fn count(&self, addr: SocketAddr, update: impl FnOnce(&mut LinkStats)) {
+        if let Some(link) = self.linked.lock().unwrap().get_mut(&addr) {
+            update(&mut link.stats);
+        }
+    }
+}
+
+/// Entry point for `glosco mesh-status`: join the mesh by the given peers without listening,
+/// probe it, and print what this end sees as JSON.
+pub fn run(args: MeshStatusArgs) {
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start mesh runtime");
+    let mut config = MeshConfig::default();
+    config.set_listens(Vec::new());
+    for peer in args.peer {
+        config.add_peer(peer);
+    }
+    let mesh = runtime.block_on(config.build()).expect("failed to join mesh");
+    let wait = Duration::from_secs_f64(args.wait);
+    // Until every peer has either linked or failed to
+    let deadline = Instant::now() + wait;
+    while Instant::now() < deadline && mesh.peers().iter().any(|peer| peer.connected_since.is_none() && peer.last_error.is_none()) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let status = mesh.status(Some(wait));
+    println!("{}", serde_json::to_string_pretty(&status).expect("failed to encode status"));
+}
+
+#[cfg(all(test, feature = "mesh"))]
+mod tests {
+    use super::*;
+
+    /// How long a test waits for links to come up or frames to get around.
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// A mesh listening on a port of its own on localhost, as `adjust` leaves its config.
+    async fn node(adjust: impl FnOnce(&mut MeshConfig)) -> Arc<Mesh> {
+        let mut config = MeshConfig::default();
+        config.set_listens(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 0))]);
+        adjust(&mut config);
+        config.build().await.unwrap()
+    }
+
+    /// `value` decodes from what it encodes to, leaving nothing over.
+    fn round_trip<T: Coder + PartialEq + std::fmt::Debug>(value: T) {
+        let mut bytes = Vec::new();
+        value.encode(&mut bytes).unwrap();
+        let mut reader = &bytes[..];
+        assert_eq!(T::decode(&mut reader).unwrap(), value);
+        assert!(reader.is_empty(), "{:?} left {:?}", value, reader);
+    }
+
+    fn addr(mesh: &Mesh) -> SocketAddr {
+        mesh.local_addrs().unwrap()[0]
+    }
+
+    /// Wait up to `WAIT` for `done`, giving whether it came to pass.
+    async fn until(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + WAIT;
+        while !done() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        true
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn inbound_links_past_the_degree_are_refused() {
+        let hub = node(|config| config.set_degree(1)).await;
+        let first = node(|config| config.add_peer(addr(&hub))).await;
+        assert!(until(|| hub.links() == 1 && first.peers().iter().any(|peer| peer.connected_since.is_some())).await);
+        let second = node(|config| config.add_peer(addr(&hub))).await;
+        // The second gets as far as connecting, and is hung up on straight away
+        assert!(until(|| second.known.lock().unwrap().values().any(|peer| peer.failures > 0)).await);
+        assert_eq!(hub.links(), 1);
+        let peers = hub.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].direction, Direction::Inbound);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn seeded_peers_are_dialed_up_to_the_degree() {
+        let (first, second) = (node(|_| ()).await, node(|_| ()).await);
+        let dialer = node(|config| {
+            config.set_degree(1);
+            config.add_peer(addr(&first));
+            config.add_peer(addr(&second));
+        }).await;
+        assert!(until(|| first.links() + second.links() == 1).await);
+        // Another tick of the connector, and still only the one
+        time::sleep(DIAL_TICK + Duration::from_millis(200)).await;
+        assert_eq!(dialer.links(), 1);
+        assert_eq!(first.links() + second.links(), 1);
+        let linked: Vec<Direction> = dialer.peers().iter().filter(|peer| peer.connected_since.is_some()).map(|peer| peer.direction).collect();
+        assert_eq!(linked, [Direction::Outbound]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_peer_that_cant_be_dialed_is_backed_off_from() {
+        let gone = {
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap()
+        };
+        let dialer = node(|config| config.add_peer(gone)).await;
+        assert!(until(|| dialer.peers()[0].last_error.is_some()).await);
+        assert_eq!(dialer.links(), 0);
+        let (failures, wait) = {
+            let known = dialer.known.lock().unwrap();
+            let peer = &known[&gone];
+            (peer.failures, peer.next_dial.saturating_duration_since(Instant::now()))
+        };
+        assert_eq!(failures, 1);
+        assert!(wait > Duration::from_millis(500) && wait <= BACKOFF.0, "{:?}", wait);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backoff_doubles_up_to_the_cap() {
+        // With no room for links, nothing's dialed but what the test says failed
+        let mesh = node(|config| config.set_degree(0)).await;
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        mesh.add_peer(peer);
+        let waits: Vec<u64> = (0 .. 8).map(|_| {
+            mesh.set_connected(peer, false);
+            let wait = mesh.known.lock().unwrap()[&peer].next_dial.saturating_duration_since(Instant::now());
+            // Rounded up, as a moment's gone by since it was set
+            wait.as_secs() + 1
+        }).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(mesh.links(), 0);
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        round_trip(Envelope { id: u128::MAX - 1, hops: 3, ident: "sensor".to_string(), payload: vec![1, 2, 3] });
+        round_trip(Envelope::new(String::new(), Vec::new()));
+        assert_ne!(Envelope::new(String::new(), Vec::new()).id, Envelope::new(String::new(), Vec::new()).id);
+    }
+
+    #[test]
+    fn an_id_is_seen_for_at_least_a_generation() {
+        let mut seen = Seen::new(2, Duration::from_secs(60));
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        // The third starts a generation, and the first two are still remembered in the last
+        assert!(seen.insert(3));
+        assert!(!seen.insert(1) && !seen.insert(2));
+        assert!(seen.insert(4));
+        // Two generations on, 1 and 2 are forgotten
+        assert!(seen.insert(5));
+        assert!(seen.insert(1));
+    }
+
+    /// Everything `subscriber` has been handed so far: id, hops and payload.
+    fn received(subscriber: &mut broadcast::Receiver<Frame>) -> Vec<(u128, u8, Vec<u8>)> {
+        std::iter::from_fn(|| subscriber.try_recv().ok()).map(|frame| (frame.id, frame.hops, frame.data.to_vec())).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_frame_goes_round_a_cycle_once() {
+        // a dials b, b dials c, c dials a
+        let a = node(|_| ()).await;
+        let b = node(|config| config.add_peer(addr(&a))).await;
+        let c = node(|config| config.add_peer(addr(&b))).await;
+        a.add_peer(addr(&c));
+        assert!(until(|| [&a, &b, &c].iter().all(|mesh| mesh.peers().iter().filter(|peer| peer.connected_since.is_some()).count() == 2)).await);
+        // A link's listed a moment before it starts forwarding
+        time::sleep(Duration::from_millis(50)).await;
+
+        let mut subscribers: Vec<_> = [&a, &b, &c].iter().map(|mesh| mesh.subscribe()).collect();
+        a.publish("sensor", vec![42]);
+        // Long enough for it to have gone round, and back to a, twice over
+        time::sleep(Duration::from_millis(300)).await;
+        let got: Vec<Vec<(u128, u8, Vec<u8>)>> = subscribers.iter_mut().map(received).collect();
+        let id = got[0][0].0;
+        assert_eq!(got[0], [(id, 0, vec![42])]);
+        // Once each, whichever way round it got there first
+        for got in &got[1 ..] {
+            assert!(matches!(&got[..], [(got_id, 1 | 2, payload)] if *got_id == id && payload == &[42]), "{:?}", got);
+        }
+        // Each passes it on to the neighbour it didn't come from, which makes two arrivals of
+        // something already seen, wherever they are
+        let dropped: u64 = [&a, &b, &c].iter().flat_map(|mesh| mesh.peers()).map(|peer| peer.stats.dropped).sum();
+        assert_eq!(dropped, 2);
+    }
+
+    #[test]
+    fn announcements_round_trip() {
+        round_trip(Announce { version: Announce::VERSION, instance: 7, listens: vec!["0.0.0.0:12074".parse().unwrap(), "[::1]:9".parse().unwrap()] });
+        round_trip(Announce { version: 2, instance: u128::MAX, listens: Vec::new() });
+    }
+
+    /// A UDP port nothing's bound to just now.
+    fn unused_udp_port() -> u16 {
+        std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn two_nodes_announcing_find_each_other_and_link_once() {
+        let target = SocketAddr::from((Ipv4Addr::new(127, 255, 255, 255), unused_udp_port()));
+        let announcing = |config: &mut MeshConfig| {
+            config.set_discovery(target);
+            config.set_announce_interval(Duration::from_millis(100));
+        };
+        let (first, second) = (node(announcing).await, node(announcing).await);
+        assert!(until(|| first.links() == 1 && second.links() == 1).await);
+        // Only the lower instance dials, so it alone has the other as a peer to dial
+        let (lower, higher) = if first.instance() < second.instance() { (&first, &second) } else { (&second, &first) };
+        assert_eq!(lower.known.lock().unwrap().keys().copied().collect::<Vec<_>>(), [addr(higher)]);
+        assert!(higher.known.lock().unwrap().is_empty());
+        // And hearing more announcements makes no more links
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!((first.links(), second.links()), (1, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn announcements_are_taken_at_their_word_or_ignored() {
+        let port = unused_udp_port();
+        let mesh = node(|config| {
+            config.set_discovery(SocketAddr::from((Ipv4Addr::new(127, 255, 255, 255), port)));
+            config.set_announce_interval(Duration::from_secs(3600));
+        }).await;
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let send = |announce: Announce| {
+            let mut bytes = Vec::new();
+            announce.encode(&mut bytes).unwrap();
+            socket.send_to(&bytes, (Ipv4Addr::LOCALHOST, port)).unwrap();
+        };
+        let unspecified: SocketAddr = "0.0.0.0:40001".parse().unwrap();
+        // Another version, and an instance that dials us rather than us it
+        send(Announce { version: Announce::VERSION + 1, instance: u128::MAX, listens: vec!["127.0.0.1:40002".parse().unwrap()] });
+        send(Announce { version: Announce::VERSION, instance: 0, listens: vec!["127.0.0.1:40003".parse().unwrap()] });
+        // An unspecified address is wherever it came from
+        send(Announce { version: Announce::VERSION, instance: u128::MAX, listens: vec![unspecified] });
+        assert!(until(|| !mesh.known.lock().unwrap().is_empty()).await);
+        time::sleep(Duration::from_millis(100)).await;
+        let known: Vec<SocketAddr> = mesh.known.lock().unwrap().keys().copied().collect();
+        assert_eq!(known, ["127.0.0.1:40001".parse::<SocketAddr>().unwrap()]);
+    }
+
+    /// Nodes in a line, each dialing the one before, every one with a hop limit of `max_hops`;
+    /// once every link is up.
+    async fn chain(len: usize, max_hops: u8) -> Vec<Arc<Mesh>> {
+        let mut nodes: Vec<Arc<Mesh>> = Vec::with_capacity(len);
+        for _ in 0 .. len {
+            let previous = nodes.last().map(|mesh| addr(mesh));
+            nodes.push(node(|config| {
+                config.set_max_hops(max_hops);
+                if let Some(previous) = previous {
+                    config.add_peer(previous);
+                }
+            }).await);
+        }
+        assert!(until(|| nodes.iter().enumerate().all(|(idx, mesh)| {
+            let ends = usize::from(idx > 0) + usize::from(idx + 1 < len);
+            mesh.peers().iter().filter(|peer| peer.connected_since.is_some()).count() == ends
+        })).await);
+        // A link's listed a moment before it starts forwarding
+        time::sleep(Duration::from_millis(50)).await;
+        nodes
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn frames_go_no_further_than_the_hop_limit() {
+        let nodes = chain(4, 2).await;
+        let mut subscribers: Vec<_> = nodes.iter().map(|mesh| mesh.subscribe()).collect();
+        nodes[0].publish("sensor", vec![42]);
+        time::sleep(Duration::from_millis(300)).await;
+        let hops: Vec<Vec<u8>> = subscribers.iter_mut().map(|subscriber| received(subscriber).into_iter().map(|(_, hops, _)| hops).collect()).collect();
+        // The last is three links away, one more than it may cross
+        assert_eq!(hops, [vec![0], vec![1], vec![2], vec![]]);
+
+        // Counted on the link it came in on, the one each dialed
+        let stats = |mesh: &Mesh, from: &Mesh| mesh.peers().into_iter().find(|peer| peer.addr == addr(from)).unwrap().stats;
+        let last = stats(&nodes[3], &nodes[2]);
+        assert_eq!((last.received, last.forwarded, last.hop_exceeded), (1, 0, 1));
+        let middle = stats(&nodes[2], &nodes[1]);
+        assert_eq!((middle.received, middle.forwarded, middle.hop_exceeded), (1, 1, 0));
+        let first = nodes[0].peers()[0].stats;
+        assert_eq!((first.sent, first.received), (1, 0));
+    }
+
+    #[test]
+    fn probes_round_trip() {
+        round_trip(Probe::Ask { probe: 1, asker: u128::MAX });
+        round_trip(Probe::Answer { probe: 1, asker: u128::MAX, instance: 3, hops: 2 });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_probe_finds_every_instance_and_how_far_it_is() {
+        let nodes = chain(3, 16).await;
+        let mut subscribers: Vec<_> = nodes.iter().map(|mesh| mesh.subscribe()).collect();
+        let probe = nodes[0].probe();
+        time::sleep(Duration::from_millis(300)).await;
+        let reached = nodes[0].probed(probe);
+        assert_eq!(reached, [
+            Reachable { instance: nodes[0].instance(), hops: 0 },
+            Reachable { instance: nodes[1].instance(), hops: 1 },
+            Reachable { instance: nodes[2].instance(), hops: 2 },
+        ]);
+        // Answers are only kept until they're asked for
+        assert_eq!(nodes[0].probed(probe), [Reachable { instance: nodes[0].instance(), hops: 0 }]);
+        // Nor are probes anything for subscribers
+        assert!(subscribers.iter_mut().all(|subscriber| received(subscriber).is_empty()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn status_lists_links_and_what_a_probe_reached() {
+        let nodes = chain(2, 16).await;
+        let mesh = nodes[1].clone();
+        let status = tokio::task::spawn_blocking(move || mesh.status(Some(Duration::from_millis(300)))).await.unwrap();
+        assert_eq!(status["instance"], format!("{:032x}", nodes[1].instance()));
+        assert_eq!(status["links"], 1);
+        assert_eq!(status["peers"][0]["direction"], "outbound");
+        assert_eq!(status["peers"][0]["addr"], addr(&nodes[0]).to_string());
+        let reachable: Vec<(String, u64)> = status["reachable"].as_array().unwrap().iter()
+            .map(|reached| (reached["instance"].as_str().unwrap().to_string(), reached["hops"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(reachable.len(), 2);
+        assert!(reachable.contains(&(format!("{:032x}", nodes[0].instance()), 1)), "{:?}", reachable);
+    }
+
+    /// A peer file of the test's own, not there yet.
+    fn peer_file(name: &str, horizon: Duration) -> PeerFile {
+        let path = std::env::temp_dir().join(format!("glosco-mesh-{}-{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        PeerFile { path, horizon }
+    }
+
+    fn saved(addr: &str, added: SystemTime, last_connected: Option<SystemTime>) -> SavedPeer {
+        SavedPeer { addr: addr.parse().unwrap(), added: epoch_secs(added), last_connected: last_connected.map(epoch_secs) }
+    }
+
+    #[test]
+    fn the_peer_file_keeps_peers_until_the_horizon() {
+        let day = Duration::from_secs(86400);
+        let file = peer_file("horizon", day);
+        let now = SystemTime::now();
+        file.save(vec![
+            saved("10.0.0.1:12074", now - 3 * day, Some(now - day / 2)),
+            saved("10.0.0.2:12074", now - 3 * day, Some(now - 2 * day)),
+            saved("10.0.0.3:12074", now - day / 2, None),
+            saved("10.0.0.4:12074", now - 2 * day, None),
+        ]).unwrap();
+        let loaded: Vec<String> = file.load().iter().map(|peer| peer.addr.to_string()).collect();
+        assert_eq!(loaded, ["10.0.0.1:12074", "10.0.0.3:12074"]);
+        fs::remove_file(&file.path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_or_broken_peer_file_is_an_empty_one() {
+        let file = peer_file("broken", Duration::from_secs(60));
+        assert!(file.load().is_empty());
+        fs::write(&file.path, "{not json").unwrap();
+        assert!(file.load().is_empty());
+        fs::remove_file(&file.path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peers_saved_last_time_are_dialed_and_saved_as_linked() {
+        let other = node(|_| ()).await;
+        let file = peer_file("restart", Duration::from_secs(86400));
+        let long_ago = SystemTime::now() - Duration::from_secs(86400 * 30);
+        file.save(vec![
+            saved(&addr(&other).to_string(), SystemTime::now() - Duration::from_secs(60), None),
+            saved("10.0.0.9:12074", long_ago, Some(long_ago)),
+        ]).unwrap();
+        let mesh = node(|config| config.set_peer_file(file.path.clone(), file.horizon)).await;
+        assert!(until(|| other.links() == 1).await);
+        assert_eq!(mesh.known.lock().unwrap().keys().copied().collect::<Vec<_>>(), [addr(&other)]);
+
+        // The link is up, so it's saved as just connected
+        let before = epoch_secs(SystemTime::now());
+        let peers = mesh.saved_peers();
+        assert_eq!(peers.len(), 1);
+        assert!(peers[0].last_connected.is_some_and(|at| at >= before), "{:?}", peers);
+        fs::remove_file(&file.path).unwrap();
     }
 }
@@ -0,0 +1,191 @@
+use std::{fmt::{self, Write}, fs, io, net::SocketAddr, sync::{Arc, Mutex}, time::SystemTime};
+
+use tiny_http::{Method, Response};
+
+use crate::http::{self, content_type};
+
+mod statsd;
+pub use statsd::{Statsd, TagFormat};
+
+/// How Prometheus should treat a family's samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Counter,
+    Gauge,
+}
+
+impl Type {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+/// A metric family: defined once as a constant next to what it measures, and sampled by name
+/// each scrape.
+#[derive(Debug, Clone, Copy)]
+pub struct Family {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Type,
+}
+
+/// One value of a family, told apart from the others by its labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    labels: Vec<(&'static str, String)>,
+    value: f64,
+}
+
+impl Sample {
+    pub fn new(value: f64) -> Self {
+        Self { labels: Vec::new(), value }
+    }
+
+    pub fn label(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.labels.push((name, value.to_string()));
+        self
+    }
+}
+
+/// One scrape's worth of samples, built up a family at a time, to render as the Prometheus
+/// text format or send on to statsd.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    families: Vec<(Family, Vec<Sample>)>,
+}
+
+impl Exposition {
+    /// Add a family with all of its samples, which the text format wants kept together.
+    pub fn family(&mut self, family: &Family, samples: impl IntoIterator<Item = Sample>) {
+        self.families.push((*family, samples.into_iter().collect()));
+    }
+
+    pub fn into_text(self) -> String {
+        let mut text = String::new();
+        for (family, samples) in self.families.iter() {
+            let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(text, "# TYPE {} {}", family.name, family.kind.as_str());
+            for sample in samples {
+                text.push_str(family.name);
+                if !sample.labels.is_empty() {
+                    let labels: Vec<String> = sample.labels.iter()
+                        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                        .collect();
+                    let _ = write!(text, "{{{}}}", labels.join(","));
+                }
+                let _ = writeln!(text, " {}", sample.value);
+            }
+        }
+        text
+    }
+}
+
+/// Escape a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+type Collect = dyn Fn(&mut Exposition) + Send + Sync;
+
+/// Everything a `/metrics` scrape reports, gathered from each collector added, in order.
+#[derive(Clone, Default)]
+pub struct Registry {
+    collectors: Arc<Mutex<Vec<Box<Collect>>>>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("collectors", &self.collectors.lock().unwrap().len())
+            .finish()
+    }
+}
+
+const BUILD_INFO: Family = Family {
+    name: "glosco_build_info",
+    help: "Always 1, labelled with the running version.",
+    kind: Type::Gauge,
+};
+const START_TIME: Family = Family {
+    name: "process_start_time_seconds",
+    help: "When the process started, in seconds since the epoch.",
+    kind: Type::Gauge,
+};
+const RESIDENT_MEMORY: Family = Family {
+    name: "process_resident_memory_bytes",
+    help: "Resident memory size in bytes.",
+    kind: Type::Gauge,
+};
+const OPEN_FDS: Family = Family {
+    name: "process_open_fds",
+    help: "Open file descriptors.",
+    kind: Type::Gauge,
+};
+
+impl Registry {
+    /// Have every scrape include what `collect` adds.
+    pub fn add<F: Fn(&mut Exposition) + Send + Sync + 'static>(&self, collect: F) {
+        self.collectors.lock().unwrap().push(Box::new(collect));
+    }
+
+    /// Report the version, and the process's start time, memory and descriptors where the
+    /// system says.
+    pub fn add_process(&self) {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|since| since.as_secs_f64()).unwrap_or(0.0);
+        self.add(move |out| {
+            out.family(&BUILD_INFO, [Sample::new(1.0).label("version", env!("CARGO_PKG_VERSION"))]);
+            out.family(&START_TIME, [Sample::new(started)]);
+            if let Some(bytes) = resident_memory() {
+                out.family(&RESIDENT_MEMORY, [Sample::new(bytes as f64)]);
+            }
+            if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+                out.family(&OPEN_FDS, [Sample::new(fds.count() as f64)]);
+            }
+        });
+    }
+
+    /// Sample everything, as a scrape would.
+    pub fn gather(&self) -> Exposition {
+        let mut out = Exposition::default();
+        for collect in self.collectors.lock().unwrap().iter() {
+            collect(&mut out);
+        }
+        out
+    }
+
+    pub fn render(&self) -> String {
+        self.gather().into_text()
+    }
+
+    /// Serve `GET /metrics` on `bind`, in the background.
+    pub fn serve(&self, bind: SocketAddr) -> io::Result<()> {
+        let registry = self.clone();
+        http::serve(bind, "metrics", move |request| {
+            if *request.method() != Method::Get {
+                return request.respond(Response::from_string("method not allowed\n").with_status_code(405));
+            }
+            if http::split_url(request.url()).0 != "/metrics" {
+                return request.respond(Response::from_string("not found\n").with_status_code(404));
+            }
+            request.respond(Response::from_string(registry.render()).with_header(content_type("text/plain; version=0.0.4")))
+        })
+    }
+}
+
+/// Resident memory, from `/proc/self/statm` where there is one.
+#[cfg(unix)]
+fn resident_memory() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // Safety: sysconf only reads a configuration value.
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page).ok()?)
+}
+
+#[cfg(not(unix))]
+fn resident_memory() -> Option<u64> {
+    None
+}
@@ -0,0 +1,297 @@
+use std::{collections::HashMap, fmt::Write, io, net::{SocketAddr, UdpSocket}, thread, time::Duration};
+
+use super::{Exposition, Registry, Sample, Type};
+
+/// How a statsd agent wants a sample's labels written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TagFormat {
+    /// After the value, as DogStatsD takes them: `name:1|c|#interface:eth0`.
+    #[default]
+    Dogstatsd,
+    /// After the name, as Telegraf's statsd input takes them: `name,interface=eth0:1|c`.
+    Influx,
+    /// Folded into the name for agents without tags: `name.eth0:1|c`.
+    None,
+}
+
+impl TagFormat {
+    /// Characters a label value can't carry in this format, which are sent as `_`.
+    fn reserved(self) -> &'static str {
+        match self {
+            Self::Dogstatsd => ",|#\n",
+            Self::Influx => ",= :|\n",
+            Self::None => ".:|@ \n",
+        }
+    }
+}
+
+/// Sends a registry's samples to a statsd agent over UDP: gauges as they are, counters as what
+/// they've gone up by since last sent. Sending is best-effort; whatever the agent misses, it
+/// misses.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: TagFormat,
+    /// What each counter, by `key`, stood at when last sent.
+    sent: HashMap<String, f64>,
+    // Reused from one emission to the next
+    key: String,
+    line: String,
+    datagram: String,
+}
+
+impl Statsd {
+    /// Most bytes to put in one datagram, so it fits an Ethernet frame unfragmented.
+    pub const DATAGRAM: usize = 1432;
+
+    /// Send to the agent at `addr`, putting `prefix` (like `edge.`) before every name.
+    pub fn new(addr: SocketAddr, prefix: String, tags: TagFormat) -> io::Result<Self> {
+        let bind: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket, prefix, tags,
+            sent: HashMap::new(),
+            key: String::new(),
+            line: String::new(),
+            datagram: String::with_capacity(Self::DATAGRAM),
+        })
+    }
+
+    /// Send everything in `exposition`, as many lines to a datagram as fit.
+    pub fn emit(&mut self, exposition: &Exposition) {
+        for (family, samples) in exposition.families.iter() {
+            for sample in samples.iter() {
+                self.key.clear();
+                self.key.push_str(family.name);
+                for (name, value) in sample.labels.iter() {
+                    self.key.push(',');
+                    self.key.push_str(name);
+                    self.key.push('=');
+                    self.key.push_str(value);
+                }
+                let value = match family.kind {
+                    Type::Gauge => sample.value,
+                    Type::Counter => {
+                        if !self.sent.contains_key(self.key.as_str()) {
+                            self.sent.insert(self.key.clone(), 0.0);
+                        }
+                        let last = self.sent.get_mut(self.key.as_str()).expect("just inserted");
+                        // Went down, so it was reset and all of it is new
+                        let delta = if sample.value < *last { sample.value } else { sample.value - *last };
+                        *last = sample.value;
+                        if delta == 0.0 {
+                            continue;
+                        }
+                        delta
+                    },
+                };
+                self.line.clear();
+                if family.kind == Type::Gauge && value < 0.0 {
+                    // A signed gauge is taken as a change, so go by way of zero
+                    self.write_line(family.name, sample, 0.0, "g");
+                    self.line.push('\n');
+                }
+                self.write_line(family.name, sample, value, if family.kind == Type::Gauge { "g" } else { "c" });
+                self.queue();
+            }
+        }
+        self.flush();
+    }
+
+    /// Append one line for `sample` to `line`.
+    fn write_line(&mut self, name: &str, sample: &Sample, value: f64, kind: &str) {
+        let reserved = self.tags.reserved();
+        self.line.push_str(&self.prefix);
+        self.line.push_str(name);
+        match self.tags {
+            TagFormat::Dogstatsd => {
+                let _ = write!(self.line, ":{}|{}", value, kind);
+                for (idx, (label, text)) in sample.labels.iter().enumerate() {
+                    self.line.push_str(if idx == 0 { "|#" } else { "," });
+                    self.line.push_str(label);
+                    self.line.push(':');
+                    push_clean(&mut self.line, text, reserved);
+                }
+            },
+            TagFormat::Influx => {
+                for (label, text) in sample.labels.iter() {
+                    self.line.push(',');
+                    self.line.push_str(label);
+                    self.line.push('=');
+                    push_clean(&mut self.line, text, reserved);
+                }
+                let _ = write!(self.line, ":{}|{}", value, kind);
+            },
+            TagFormat::None => {
+                for (_, text) in sample.labels.iter() {
+                    self.line.push('.');
+                    push_clean(&mut self.line, text, reserved);
+                }
+                let _ = write!(self.line, ":{}|{}", value, kind);
+            },
+        }
+    }
+
+    /// Move `line` into the datagram, sending the datagram first if it wouldn't fit.
+    fn queue(&mut self) {
+        if !self.datagram.is_empty() && self.datagram.len() + 1 + self.line.len() > Self::DATAGRAM {
+            self.flush();
+        }
+        if !self.datagram.is_empty() {
+            self.datagram.push('\n');
+        }
+        self.datagram.push_str(&self.line);
+    }
+
+    fn flush(&mut self) {
+        if !self.datagram.is_empty() {
+            // Nobody listening, or a full buffer: the agent gets the next one
+            let _ = self.socket.send(self.datagram.as_bytes());
+            self.datagram.clear();
+        }
+    }
+}
+
+/// Append `text` with any of `reserved` replaced by `_`.
+fn push_clean(out: &mut String, text: &str, reserved: &str) {
+    out.extend(text.chars().map(|c| if reserved.contains(c) { '_' } else { c }));
+}
+
+impl Registry {
+    /// Send everything to `statsd` every `every`, in the background, for as long as the process
+    /// runs.
+    pub fn emit_to(&self, mut statsd: Statsd, every: Duration) {
+        let registry = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(every);
+            statsd.emit(&registry.gather());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::metrics::Family;
+
+    const PACKETS: Family = Family { name: "packets_total", help: "Packets.", kind: Type::Counter };
+    const QUEUED: Family = Family { name: "queued", help: "Queued.", kind: Type::Gauge };
+
+    /// A local agent, and a sink sending to it.
+    fn agent(prefix: &str, tags: TagFormat) -> (UdpSocket, Statsd) {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let statsd = Statsd::new(agent.local_addr().unwrap(), prefix.to_string(), tags).unwrap();
+        (agent, statsd)
+    }
+
+    /// Every datagram the agent has been sent, until it's heard nothing for a moment.
+    fn datagrams(agent: &UdpSocket) -> Vec<String> {
+        let mut buffer = [0; 65536];
+        let mut datagrams = Vec::new();
+        while let Ok(len) = agent.recv(&mut buffer) {
+            datagrams.push(String::from_utf8(buffer[.. len].to_vec()).unwrap());
+        }
+        datagrams
+    }
+
+    fn lines(agent: &UdpSocket) -> Vec<String> {
+        datagrams(agent).iter().flat_map(|datagram| datagram.lines().map(str::to_string).collect::<Vec<_>>()).collect()
+    }
+
+    fn exposition(packets: f64, queued: f64) -> Exposition {
+        let mut out = Exposition::default();
+        out.family(&PACKETS, [Sample::new(packets).label("interface", "eth0")]);
+        out.family(&QUEUED, [Sample::new(queued).label("remote", "10.0.0.1:12074").label("site", "ams1")]);
+        out
+    }
+
+    #[test]
+    fn each_tag_format_writes_labels_its_own_way() {
+        for (tags, expected) in [
+            (TagFormat::Dogstatsd, ["edge.packets_total:3|c|#interface:eth0", "edge.queued:2|g|#remote:10.0.0.1:12074,site:ams1"]),
+            (TagFormat::Influx, ["edge.packets_total,interface=eth0:3|c", "edge.queued,remote=10.0.0.1_12074,site=ams1:2|g"]),
+            (TagFormat::None, ["edge.packets_total.eth0:3|c", "edge.queued.10_0_0_1_12074.ams1:2|g"]),
+        ] {
+            let (agent, mut statsd) = agent("edge.", tags);
+            statsd.emit(&exposition(3.0, 2.0));
+            assert_eq!(lines(&agent), expected, "{:?}", tags);
+        }
+    }
+
+    #[test]
+    fn counters_send_what_they_went_up_by_and_gauges_what_they_are() {
+        let (agent, mut statsd) = agent("", TagFormat::Dogstatsd);
+        let mut sent = Vec::new();
+        // Unchanged, so no counter line; then reset, so all of it is new
+        for (packets, queued) in [(5.0, 1.0), (5.0, 1.0), (8.0, 0.0), (2.0, 0.0)] {
+            statsd.emit(&exposition(packets, queued));
+            sent.push(lines(&agent));
+        }
+        assert_eq!(sent, [
+            vec!["packets_total:5|c|#interface:eth0", "queued:1|g|#remote:10.0.0.1:12074,site:ams1"],
+            vec!["queued:1|g|#remote:10.0.0.1:12074,site:ams1"],
+            vec!["packets_total:3|c|#interface:eth0", "queued:0|g|#remote:10.0.0.1:12074,site:ams1"],
+            vec!["packets_total:2|c|#interface:eth0", "queued:0|g|#remote:10.0.0.1:12074,site:ams1"],
+        ]);
+    }
+
+    #[test]
+    fn a_negative_gauge_goes_by_way_of_zero() {
+        let (agent, mut statsd) = agent("", TagFormat::Dogstatsd);
+        let mut out = Exposition::default();
+        out.family(&QUEUED, [Sample::new(-3.0)]);
+        statsd.emit(&out);
+        // Both in one datagram, so the agent can't take them out of order
+        assert_eq!(datagrams(&agent), ["queued:0|g\nqueued:-3|g"]);
+    }
+
+    #[test]
+    fn reserved_characters_in_labels_are_replaced() {
+        for (tags, expected) in [
+            (TagFormat::Dogstatsd, "packets_total:1|c|#interface:a_b_c_d:e"),
+            (TagFormat::Influx, "packets_total,interface=a_b_c#d_e:1|c"),
+            (TagFormat::None, "packets_total.a,b_c#d_e:1|c"),
+        ] {
+            let (agent, mut statsd) = agent("", tags);
+            let mut out = Exposition::default();
+            out.family(&PACKETS, [Sample::new(1.0).label("interface", "a,b|c#d:e")]);
+            statsd.emit(&out);
+            assert_eq!(lines(&agent), [expected], "{:?}", tags);
+        }
+    }
+
+    #[test]
+    fn lines_are_packed_into_datagrams_that_fit() {
+        let (agent, mut statsd) = agent("edge.", TagFormat::Dogstatsd);
+        let mut out = Exposition::default();
+        out.family(&QUEUED, (0 .. 200).map(|idx| Sample::new(idx as f64).label("remote", format!("10.0.0.{}:12074", idx))));
+        let started = Instant::now();
+        statsd.emit(&out);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let datagrams = datagrams(&agent);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= Statsd::DATAGRAM), "{:?}", datagrams.iter().map(String::len).collect::<Vec<_>>());
+        let lines: Vec<&str> = datagrams.iter().flat_map(|datagram| datagram.lines()).collect();
+        let expected: Vec<String> = (0 .. 200).map(|idx| format!("edge.queued:{}|g|#remote:10.0.0.{}:12074", idx, idx)).collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn nobody_listening_is_no_error() {
+        let (agent, mut statsd) = agent("", TagFormat::Dogstatsd);
+        drop(agent);
+        for _ in 0 .. 3 {
+            statsd.emit(&exposition(1.0, 1.0));
+        }
+    }
+}
@@ -1,17 +1,26 @@
-use std::{net::IpAddr, fmt::{Formatter, self, Display}, time::{self, SystemTime, Duration}, sync::mpsc, collections::HashMap, thread::{JoinHandle, self}};
+use std::{io, net::IpAddr, fmt::{Formatter, self, Display}, path::PathBuf, time::{self, SystemTime, Duration, Instant}, sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc}, collections::HashMap, thread::{JoinHandle, self}};
 
 use dns_parser::RData;
-use pcap::{Linktype, Device, Capture};
+use pcap::{Activated, Linktype, Device, Capture};
 use pktparse::{ethernet::{self, EtherType}, ipv4, ip, ipv6, tcp, udp, icmp::{self, IcmpCode}};
+use serde::{Serialize, Deserialize};
+
+use crate::{coding::Coder, filter::{Cidr, NamePattern}, metrics::{Exposition, Family, Sample, Type}, decap, radiotap::{self, Payload}, ring::{Ring, RingSettings}, scan::{expired, Scan, ScanDetector, ScanSettings}, sll::{self, Cooked}};
 
 #[derive(Debug, Clone)]
 pub struct Ingress {
     pub data: Vec<u8>,
     pub interface: usize,
-    pub link: pcap::Linktype
+    pub link: pcap::Linktype,
+    /// When it was captured, per the capture's own timestamp.
+    pub time: SystemTime,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+fn capture_time(header: &pcap::PacketHeader) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(header.ts.tv_sec as u64, header.ts.tv_usec as u32 * 1000)
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Coder)]
 pub struct Endpoint {
     pub addr: IpAddr,
     pub port: u16,
@@ -29,12 +38,31 @@ pub struct HostPair {
     pub dst: IpAddr,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Protocol {
     Tcp, Udp,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+impl Protocol {
+    /// The IANA-assigned IP protocol number, which is what the database stores; the wire
+    /// protocol has marks of its own (see `coding`).
+    pub fn iana_number(&self) -> u8 {
+        match self {
+            Self::Tcp => 6,
+            Self::Udp => 17,
+        }
+    }
+
+    pub fn from_iana_number(number: u8) -> Option<Self> {
+        match number {
+            6 => Some(Self::Tcp),
+            17 => Some(Self::Udp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Connection {
     pub interface: usize,
     pub src: Endpoint,
@@ -42,19 +70,29 @@ pub struct Connection {
     pub protocol: Protocol,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct State {
     pub as_of: time::SystemTime,
     pub connection: Connection,
+    /// Microseconds from the SYN to the SYN-ACK, on the first Active message of a TCP
+    /// connection whose handshake was seen whole (see `Filters::rtt`); `None` otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_micros: Option<u32>,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+/// An ICMP error about a connection. Only `kind` and `code` are the problem itself; a Failed
+/// message that stands for several alike carries how many more in `repeats`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Problem {
     pub kind: u8,
     pub code: u8,
+    /// Failures just like it, for the same connection, that went unreported since the last
+    /// message about it (see `Filters::failed_window`); 0 normally.
+    #[serde(default)]
+    pub repeats: u32,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Closed {
     Normally,
     Reset,
@@ -62,7 +100,7 @@ pub enum Closed {
     Connectionless,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Resolution {
     Address(IpAddr),
     Alias(String),
@@ -70,29 +108,512 @@ pub enum Resolution {
     Text(Vec<Vec<u8>>),
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Name {
     pub name: String,
     pub address: Option<Resolution>,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Message {
     Starting(State),
     Active(State),
     Ended(State, Closed),
     Failed(State, Problem),
     Name(State, Vec<Name>),
+    /// A port scan; the connection runs from the scanner to what it scanned, as `Scan::kind`
+    /// says.
+    Scan(State, Scan),
+}
+
+impl Message {
+    pub fn state(&self) -> &State {
+        match self {
+            Self::Starting(state) | Self::Active(state) | Self::Ended(state, _) | Self::Failed(state, _) | Self::Name(state, _) | Self::Scan(state, _) => state,
+        }
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        match self {
+            Self::Starting(state) | Self::Active(state) | Self::Ended(state, _) | Self::Failed(state, _) | Self::Name(state, _) | Self::Scan(state, _) => state,
+        }
+    }
+}
+
+/// Every connection a client believes is open, sent now and then so that the collector can
+/// close out any whose end it missed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// When the client took it, by its own clock.
+    pub as_of: SystemTime,
+    /// The latest Starting or Active message of each open connection.
+    pub states: Vec<Message>,
+}
+
+/// How capture is going on every interface, sent now and then so that the collector can tell
+/// a sensor that's losing packets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// When the client counted, by its own clock.
+    pub as_of: SystemTime,
+    pub interfaces: Vec<InterfaceCounts>,
+}
+
+/// One interface's counts, since the sensor started, as `InterfaceStats` has them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceCounts {
+    pub name: String,
+    pub packets: u64,
+    pub dropped: u64,
+    pub if_dropped: u64,
+    pub unparsed: u64,
+}
+
+/// The interfaces a client captures on, in the order its connections number them, sent when
+/// it starts so that the collector can tell which sensor interface owns an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+    /// Which announcement this is of the client's, from 0. The collector keeps each generation
+    /// apart, replacing one announced again. Sensors only announce generation 0, when they
+    /// start: nothing watches for interfaces changing yet to announce the next.
+    pub generation: u32,
+    /// When the client looked, by its own clock.
+    pub as_of: SystemTime,
+    pub interfaces: Vec<InterfaceInfo>,
+}
+
+/// One interface as pcap knew it when capture started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceInfo {
+    pub name: String,
+    /// pcap's `PCAP_IF_*` flags: up, running, loopback and so on.
+    pub flags: u32,
+    /// The link-layer address, as the kernel prints it, like `52:54:00:12:34:56`. pcap's device
+    /// list has neither this nor the MTU, so they're read from `/sys/class/net` on Linux, and
+    /// are `None` elsewhere or for a capture file.
+    pub mac: Option<String>,
+    pub mtu: Option<u32>,
+    pub addresses: Vec<InterfaceAddress>,
+}
+
+/// An address configured on an interface, and the length of its netmask if it has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub addr: IpAddr,
+    pub prefix: Option<u8>,
+}
+
+/// Where Linux lists network interfaces, each a directory of its attributes.
+#[cfg(target_os = "linux")]
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+impl From<&Device> for InterfaceInfo {
+    fn from(dev: &Device) -> Self {
+        #[cfg(target_os = "linux")]
+        let (mac, mtu) = link_attributes(std::path::Path::new(SYS_CLASS_NET), &dev.name);
+        #[cfg(not(target_os = "linux"))]
+        let (mac, mtu) = (None, None);
+        Self {
+            name: dev.name.clone(),
+            flags: dev.flags.if_flags.bits(),
+            mac,
+            mtu,
+            addresses: dev.addresses.iter().map(|address| InterfaceAddress {
+                addr: address.addr,
+                prefix: address.netmask.map(|mask| match mask {
+                    IpAddr::V4(mask) => u32::from(mask).count_ones() as u8,
+                    IpAddr::V6(mask) => u128::from(mask).count_ones() as u8,
+                }),
+            }).collect(),
+        }
+    }
+}
+
+/// The link-layer address and MTU of the interface `name`, from its directory under `root`;
+/// neither if there's no such interface, as for a capture file.
+#[cfg(target_os = "linux")]
+fn link_attributes(root: &std::path::Path, name: &str) -> (Option<String>, Option<u32>) {
+    // A capture file's path is no interface, and mustn't lead anywhere else
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return (None, None);
+    }
+    let read = |attribute: &str| std::fs::read_to_string(root.join(name).join(attribute)).ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+    (read("address"), read("mtu").and_then(|mtu| mtu.parse().ok()))
+}
+
+/// As `10.0.0.5/24`, or bare if there's no netmask.
+impl Display for InterfaceAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "{}/{}", self.addr, prefix),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// What an observer produces: messages as packets call for them, and snapshots when they're due.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Batch {
+    Messages(Vec<Message>),
+    Snapshot(Snapshot),
+}
+
+/// Every bundle of messages an observer produces, shared with whatever else subscribed, from
+/// when it subscribed. It holds a bounded backlog; bundles that come while that's full are
+/// counted and dropped, so a slow subscriber never holds up the observer or anyone else.
+///
+/// Dropping it unsubscribes; it ends once the observer's gone.
+#[derive(Debug)]
+pub struct MessageReceiver {
+    bundles: mpsc::Receiver<Arc<[Message]>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl MessageReceiver {
+    /// The next bundle, waiting for one; `None` once the observer's gone.
+    pub fn recv(&self) -> Option<Arc<[Message]>> {
+        self.bundles.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, wait: Duration) -> Result<Arc<[Message]>, mpsc::RecvTimeoutError> {
+        self.bundles.recv_timeout(wait)
+    }
+
+    pub fn try_recv(&self) -> Result<Arc<[Message]>, mpsc::TryRecvError> {
+        self.bundles.try_recv()
+    }
+
+    /// Bundles left out because this fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Iterator for MessageReceiver {
+    type Item = Arc<[Message]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+/// A subscriber's end of its backlog, and its count of what didn't fit.
+type Subscriber = (mpsc::SyncSender<Arc<[Message]>>, Arc<AtomicU64>);
+
+/// Where an observer's bundles go besides whoever's iterating it.
+#[derive(Debug, Default)]
+struct Subscribers(Vec<Subscriber>);
+
+impl Subscribers {
+    fn subscribe(&mut self, backlog: usize) -> MessageReceiver {
+        let (sender, bundles) = mpsc::sync_channel(backlog);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.0.push((sender, dropped.clone()));
+        MessageReceiver { bundles, dropped }
+    }
+
+    /// Pass a copy of `messages` to every subscriber with room for it, forgetting those that
+    /// have unsubscribed. Nothing's copied if there are none.
+    fn publish(&mut self, messages: &[Message]) {
+        if self.0.is_empty() {
+            return;
+        }
+        let bundle: Arc<[Message]> = messages.into();
+        self.0.retain(|(sender, dropped)| match sender.try_send(bundle.clone()) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Which traffic an observer reports on, and how often it repeats itself about a connection
+/// that's still open; by default, everything, every 30 seconds. Field names double as the
+/// client config file's keys.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// A BPF expression, as tcpdump takes, for pcap to apply before anything else.
+    pub filter: Option<String>,
+    /// Only connections with either end in one of these blocks, if any are given.
+    pub include: Vec<Cidr>,
+    /// No connections with either end in one of these blocks.
+    pub exclude: Vec<Cidr>,
+    /// No connections with either end on one of these ports.
+    pub ignore_ports: Vec<u16>,
+    /// Neither capture on loopback interfaces by default nor report connections with a
+    /// loopback end.
+    pub no_loopback: bool,
+    /// Only TCP packets that open or close a connection (SYN, FIN or RST), leaving out the
+    /// Active messages the rest would bring.
+    pub control_only: bool,
+    /// Seconds after which a connection still starting or open is reported again.
+    pub keepalive: u64,
+    /// DNS names never to report: questions for them, and answers about them or pointing to
+    /// them, are left out of Name messages, each on its own.
+    pub suppress_names: Vec<NamePattern>,
+    /// Seconds within which ICMP errors repeating one already reported, for the same connection
+    /// and problem, aren't reported each on their own: the first is, then at most one Failed
+    /// message a window carrying how many there were since. Every one is reported if `None`.
+    pub failed_window: Option<u64>,
+    /// Time TCP handshakes, SYN to SYN-ACK, and carry the round trip on each connection's first
+    /// Active message.
+    pub rtt: bool,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self {
+            filter: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_ports: Vec::new(),
+            no_loopback: false,
+            control_only: false,
+            keepalive: Observer::KEEPALIVE_SECS,
+            suppress_names: Vec::new(),
+            failed_window: None,
+            rtt: false,
+        }
+    }
+}
+
+impl Filters {
+    /// Check that pcap can compile the BPF expression, if there is one.
+    pub fn check(&self) -> Result<(), pcap::Error> {
+        match &self.filter {
+            Some(filter) => check_bpf(filter),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a connection should be reported on at all.
+    pub fn wants(&self, conn: &Connection) -> bool {
+        let ends = [conn.src, conn.dst];
+        (self.include.is_empty() || ends.iter().any(|end| self.include.iter().any(|cidr| cidr.contains(&end.addr))))
+            && !ends.iter().any(|end| self.exclude.iter().any(|cidr| cidr.contains(&end.addr)))
+            && !ends.iter().any(|end| self.ignore_ports.contains(&end.port))
+            && !(self.no_loopback && ends.iter().any(|end| end.addr.is_loopback()))
+    }
+
+    /// Whether a question or answer should be left out of what's reported, for its own name
+    /// or the one an alias or service record points to.
+    pub fn suppresses(&self, name: &Name) -> bool {
+        let target = match &name.address {
+            Some(Resolution::Alias(target) | Resolution::Service(target, _)) => Some(target),
+            _ => None,
+        };
+        self.suppress_names.iter().any(|pattern| pattern.matches(&name.name) || target.is_some_and(|target| pattern.matches(target)))
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct ObserverConfig {
-    devices: Vec<Device>
+    devices: Vec<Device>,
+    files: Vec<PathBuf>,
+    /// BPF expressions for particular devices, by name, in place of `Filters::filter`.
+    device_filters: HashMap<String, String>,
+    snapshot_every: Option<Duration>,
+    filters: Filters,
+    keep_stats: bool,
+    scans: Option<ScanSettings>,
+    ring: Option<RingSettings>,
 }
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+/// What an observer has counted on one interface or capture file, when asked to keep count.
+#[derive(Debug, Default)]
+pub struct InterfaceStats {
+    pub name: String,
+    pub packets: AtomicU64,
+    /// Dropped by the kernel for want of buffer space, per pcap; live captures only.
+    pub dropped: AtomicU64,
+    /// Dropped by the interface or its driver, per pcap; live captures only.
+    pub if_dropped: AtomicU64,
+    /// Packets that were cut short or otherwise couldn't be parsed.
+    pub unparsed: AtomicU64,
+    /// DNS questions and answers left out for names `Filters::suppress_names` matches.
+    pub suppressed: AtomicU64,
+}
+
+const PACKETS: Family = Family {
+    name: "glosco_observer_packets_total",
+    help: "Packets captured.",
+    kind: Type::Counter,
+};
+const DROPPED: Family = Family {
+    name: "glosco_observer_dropped_total",
+    help: "Packets the kernel dropped before they could be captured.",
+    kind: Type::Counter,
+};
+const IF_DROPPED: Family = Family {
+    name: "glosco_observer_interface_dropped_total",
+    help: "Packets the interface or its driver dropped.",
+    kind: Type::Counter,
+};
+const UNPARSED: Family = Family {
+    name: "glosco_observer_unparsed_total",
+    help: "Packets that couldn't be parsed.",
+    kind: Type::Counter,
+};
+const SUPPRESSED: Family = Family {
+    name: "glosco_observer_suppressed_names_total",
+    help: "DNS questions and answers left out for suppressed names.",
+    kind: Type::Counter,
+};
+
+impl InterfaceStats {
+    /// Pcap's drop counts are refreshed this often, in packets, rather than on every one.
+    const REFRESH: u64 = 256;
+
+    fn new(name: String) -> Self {
+        Self { name, ..Default::default() }
+    }
+
+    /// Count a packet captured by `cap`.
+    fn packet<T: Activated + ?Sized>(&self, cap: &mut Capture<T>) {
+        if self.packets.fetch_add(1, Ordering::Relaxed).is_multiple_of(Self::REFRESH) {
+            // Capture files have no drop counts to give
+            if let Ok(stat) = cap.stats() {
+                self.dropped.store(stat.dropped.into(), Ordering::Relaxed);
+                self.if_dropped.store(stat.if_dropped.into(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Every interface's counts as of `as_of`, to send on.
+    pub fn counts(stats: &[Self], as_of: SystemTime) -> Stats {
+        let interfaces = stats.iter().map(|stat| InterfaceCounts {
+            name: stat.name.clone(),
+            packets: stat.packets.load(Ordering::Relaxed),
+            dropped: stat.dropped.load(Ordering::Relaxed),
+            if_dropped: stat.if_dropped.load(Ordering::Relaxed),
+            unparsed: stat.unparsed.load(Ordering::Relaxed),
+        }).collect();
+        Stats { as_of, interfaces }
+    }
+
+    /// Add every interface's counts to a scrape.
+    pub fn collect(stats: &[Self], out: &mut Exposition) {
+        let sample = |count: fn(&Self) -> &AtomicU64| -> Vec<Sample> {
+            stats.iter().map(|stat| Sample::new(count(stat).load(Ordering::Relaxed) as f64).label("interface", &stat.name)).collect()
+        };
+        out.family(&PACKETS, sample(|stat| &stat.packets));
+        out.family(&DROPPED, sample(|stat| &stat.dropped));
+        out.family(&IF_DROPPED, sample(|stat| &stat.if_dropped));
+        out.family(&UNPARSED, sample(|stat| &stat.unparsed));
+        out.family(&SUPPRESSED, sample(|stat| &stat.suppressed));
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum StartError {
     NoDevices,
+    /// A BPF expression pcap couldn't compile, and why.
+    BadFilter(String),
+    /// A capture file pcap couldn't open, and why.
+    BadCapture(String),
+}
+
+/// Check that pcap can compile `expr` as a BPF expression.
+fn check_bpf(expr: &str) -> Result<(), pcap::Error> {
+    Capture::dead(Linktype::ETHERNET)?.compile(expr, true).map(|_| ())
+}
+
+/// One line describing a capturable interface: its name, pcap's description, flags, and
+/// addresses, like `eth0 (Ethernet) [up, running] 10.0.0.5/24 fe80::1/64`.
+pub fn describe_interface(dev: &Device) -> String {
+    let mut line = dev.name.clone();
+    if let Some(desc) = &dev.desc {
+        line.push_str(&format!(" ({})", desc));
+    }
+    let flags: Vec<&str> = [
+        (dev.flags.is_up(), "up"),
+        (dev.flags.is_running(), "running"),
+        (dev.flags.is_loopback(), "loopback"),
+        (dev.flags.is_wireless(), "wireless"),
+    ].into_iter().filter(|(set, _)| *set).map(|(_, name)| name).collect();
+    line.push_str(&format!(" [{}]", flags.join(", ")));
+    for address in InterfaceInfo::from(dev).addresses {
+        line.push_str(&format!(" {}", address));
+    }
+    line
+}
+
+/// An interface asked for that pcap doesn't know of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownInterface {
+    pub name: String,
+    /// Known interfaces with names close to it, closest first.
+    pub suggestions: Vec<String>,
+}
+
+impl Display for UnknownInterface {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "no interface named {:?}", self.name)?;
+        if !self.suggestions.is_empty() {
+            write!(f, "; did you mean {}?", self.suggestions.join(" or "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnknownInterface {}
+
+/// Check that every name in `names` is one of `known`, so a typo is caught before capturing
+/// rather than inside a capture thread.
+pub fn check_interfaces<S: AsRef<str>>(names: &[S], known: &[String]) -> Result<(), UnknownInterface> {
+    for name in names.iter().map(AsRef::as_ref) {
+        if !known.iter().any(|known| known == name) {
+            return Err(UnknownInterface {
+                name: name.to_string(),
+                suggestions: suggest_interfaces(name, known),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The names in `known` that `name` could be a typo of: within an edit or so for every three
+/// characters, or differing only in case. Closest first, then by name.
+pub fn suggest_interfaces(name: &str, known: &[String]) -> Vec<String> {
+    let limit = (name.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &String)> = known.iter()
+        .filter_map(|known| {
+            let distance = if known.eq_ignore_ascii_case(name) { 0 } else { edit_distance(name, known) };
+            (distance <= limit).then_some((distance, known))
+        })
+        .collect();
+    close.sort();
+    close.into_iter().map(|(_, known)| known.clone()).collect()
+}
+
+/// Edit distance between `a` and `b` by characters, counting a swap of neighbours as one edit
+/// since that's the usual typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1 ..= a.len() {
+        for j in 1 ..= b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1).min(rows[i][j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
 }
 
 // This implementation reversed from the source code of pktparse with love
@@ -151,7 +672,7 @@ impl From<IcmpCode> for Problem {
             }),
             IcmpCode::Other(raw) => ((raw >> 8) as u8, raw as u8),
         };
-        Problem { kind, code }
+        Problem { kind, code, repeats: 0 }
     }
 }
 
@@ -160,88 +681,760 @@ impl ObserverConfig {
         self.devices.push(dev);
     }
 
+    /// Capture on `dev` like `add_device`, with `expr` as its BPF expression in place of the
+    /// one the filters have.
+    pub fn add_device_with_filter(&mut self, dev: Device, expr: String) {
+        self.device_filters.insert(dev.name.clone(), expr);
+        self.devices.push(dev);
+    }
+
+    /// Read packets from a capture file instead of capturing live; files are read one after
+    /// another, and the observer ends once the last one runs out.
+    pub fn add_file(&mut self, path: PathBuf) {
+        self.files.push(path);
+    }
+
+    /// Have `next_batch` produce a snapshot of every open connection this often.
+    pub fn set_snapshot_interval(&mut self, every: Duration) {
+        self.snapshot_every = Some(every);
+    }
+
+    /// Report only on the traffic `filters` let through.
+    pub fn set_filters(&mut self, filters: Filters) {
+        self.filters = filters;
+    }
+
+    /// Have pcap pass along only what the BPF expression `expr` matches, on every capture
+    /// without one of its own; this is the filters' `filter`, so `set_filters` replaces it.
+    pub fn set_filter(&mut self, expr: String) {
+        self.filters.filter = Some(expr);
+    }
+
+    /// Count packets, drops and parse failures per interface, for `Observer::stats`. Nothing is
+    /// counted unless asked for.
+    pub fn keep_stats(&mut self) {
+        self.keep_stats = true;
+    }
+
+    /// Look for port scans, reporting each as a Scan message.
+    pub fn detect_scans(&mut self, settings: ScanSettings) {
+        self.scans = Some(settings);
+    }
+
+    /// Keep the frames captured last, for `Observer::flush_ring` to write out.
+    pub fn keep_ring(&mut self, settings: RingSettings) {
+        self.ring = Some(settings);
+    }
+
     pub fn start(mut self) -> Result<Observer, StartError> {
+        // Better found here than by every capture thread on its own
+        for expr in self.filters.filter.iter().chain(self.device_filters.values()) {
+            check_bpf(expr).map_err(|e| StartError::BadFilter(format!("{:?}: {}", expr, e)))?;
+        }
         let (endpoint, packets) = mpsc::channel();
+        if !self.files.is_empty() {
+            // Opened and filtered here too, so a file that can't be read fails the start rather
+            // than quietly adding nothing
+            let mut captures = Vec::with_capacity(self.files.len());
+            for path in self.files.iter() {
+                let mut cap = Capture::from_file(path).map_err(|e| StartError::BadCapture(format!("{:?}: {}", path, e)))?;
+                if let Some(bpf) = &self.filters.filter {
+                    cap.filter(bpf, true).map_err(|e| StartError::BadFilter(format!("{:?} on {:?}: {}", bpf, path, e)))?;
+                }
+                captures.push(cap);
+            }
+            let stats: Option<Arc<[InterfaceStats]>> = self.keep_stats
+                .then(|| self.files.iter().map(|path| InterfaceStats::new(path.to_string_lossy().into_owned())).collect());
+            let counts = stats.clone();
+            let thread = thread::spawn(move || {
+                for (idx, mut cap) in captures.into_iter().enumerate() {
+                    let link = cap.get_datalink();
+                    while let Ok(pkt) = cap.next_packet() {
+                        let ingress = Ingress {
+                            data: pkt.data.to_vec(),
+                            interface: idx,
+                            link,
+                            time: capture_time(pkt.header),
+                        };
+                        if let Some(counts) = &counts {
+                            counts[idx].packet(&mut cap);
+                        }
+                        if endpoint.send(ingress).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+            return Ok(Observer {
+                packets, live: None, threads: vec![thread],
+                devices: self.files.iter().map(|path| Device::from(&*path.to_string_lossy())).collect(),
+                states: Default::default(),
+                now: SystemTime::UNIX_EPOCH,
+                snapshot_every: self.snapshot_every,
+                last_snapshot: Instant::now(),
+                failures: self.filters.failed_window.map(FailureLimiter::new),
+                handshakes: self.filters.rtt.then(Handshakes::default),
+            filters: self.filters,
+                stats,
+                depth: 0,
+                scans: self.scans.map(ScanDetector::new),
+                ring: self.ring.as_ref().map(Ring::new),
+                ifindexes: HashMap::new(),
+            interface_name: sll::interface_name,
+            interfaces_up,
+                subscribers: Subscribers::default(),
+            });
+        }
         if self.devices.is_empty() {
             self.devices = Device::list().unwrap();
+            if self.filters.no_loopback {
+                self.devices.retain(|dev| !dev.flags.is_loopback());
+            }
         }
         if self.devices.is_empty() {
             return Err(StartError::NoDevices);
         }
-        let threads = self.devices.iter().enumerate().map(|(idx, dev)| {
-            let ep = endpoint.clone();
-            let dev = dev.clone();
-            thread::spawn(move || {
-                let mut cap = Capture::from_device(dev).unwrap().immediate_mode(true).open().unwrap();
-                let link = cap.get_datalink();
-                while let Ok(pkt) = cap.next_packet() {
-                    ep.send(Ingress {
-                        data: pkt.data.to_vec(),
-                        interface: idx,
-                        link,
-                    }).unwrap();
-                }
-            })
-        }).collect();
+        let stats: Option<Arc<[InterfaceStats]>> = self.keep_stats
+            .then(|| self.devices.iter().map(|dev| InterfaceStats::new(dev.name.clone())).collect());
+        let started = Instant::now();
+        let live = LiveCaptures {
+            endpoint,
+            bpf: self.devices.iter()
+                .map(|dev| self.device_filters.get(&dev.name).or(self.filters.filter.as_ref()).cloned())
+                .collect(),
+            stats: stats.clone(),
+            liveness: self.devices.iter().map(|dev| Liveness::new(dev.name.clone(), started)).collect(),
+            read: LiveCaptures::pcap,
+        };
+        let threads = self.devices.iter().enumerate().map(|(idx, dev)| live.spawn(idx, dev.clone())).collect();
         Ok(Observer {
-            packets, _endpoint: endpoint, _threads: threads,
+            packets, live: Some(live), threads,
             devices: self.devices,
             states: Default::default(),
+            now: SystemTime::UNIX_EPOCH,
+            snapshot_every: self.snapshot_every,
+            last_snapshot: Instant::now(),
+            failures: self.filters.failed_window.map(FailureLimiter::new),
+            handshakes: self.filters.rtt.then(Handshakes::default),
+            filters: self.filters,
+            stats,
+            depth: 0,
+            scans: self.scans.map(ScanDetector::new),
+            ring: self.ring.as_ref().map(Ring::new),
+            ifindexes: HashMap::new(),
+            interface_name: sll::interface_name,
+            interfaces_up,
+            subscribers: Subscribers::default(),
         })
     }
 }
 
+/// When a live capture last had a packet, and how often it's been found stalled, so that a
+/// wedged capture can be told from a quiet one.
+#[derive(Debug)]
+pub struct Liveness {
+    pub name: String,
+    started: Instant,
+    /// Milliseconds after `started` of the last packet, or of the capture (re)opening.
+    last_packet: AtomicU64,
+    /// Which capture thread is the current one; any other stops at its next chance.
+    generation: AtomicU64,
+    pub stalls: AtomicU64,
+    pub restarts: AtomicU64,
+}
+
+const STALLS: Family = Family {
+    name: "glosco_observer_stalls_total",
+    help: "Times a capture had no packets for the watchdog interval while its interface was up.",
+    kind: Type::Counter,
+};
+const RESTARTS: Family = Family {
+    name: "glosco_observer_capture_restarts_total",
+    help: "Times a capture was torn down and opened again.",
+    kind: Type::Counter,
+};
+const PACKET_AGE: Family = Family {
+    name: "glosco_observer_last_packet_age_seconds",
+    help: "Seconds since a capture last had a packet, or was opened.",
+    kind: Type::Gauge,
+};
+
+impl Liveness {
+    fn new(name: String, started: Instant) -> Self {
+        Self {
+            name, started,
+            last_packet: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_packet.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// How long since the last packet, or since the capture (re)opened.
+    pub fn quiet(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.last_packet.load(Ordering::Relaxed)))
+    }
+
+    /// Add every live capture's stalls, restarts and quiet to a scrape.
+    pub fn collect(lives: &[Self], out: &mut Exposition) {
+        let sample = |value: fn(&Self) -> f64| -> Vec<Sample> {
+            lives.iter().map(|live| Sample::new(value(live)).label("interface", &live.name)).collect()
+        };
+        out.family(&STALLS, sample(|live| live.stalls.load(Ordering::Relaxed) as f64));
+        out.family(&RESTARTS, sample(|live| live.restarts.load(Ordering::Relaxed) as f64));
+        out.family(&PACKET_AGE, sample(|live| live.quiet().as_secs_f64()));
+    }
+}
+
+/// What to do about a live capture that's stalled, once it's been reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Recovery {
+    /// Nothing more.
+    #[default]
+    Log,
+    /// Tear down the stalled capture and open it again.
+    Capture,
+    /// Tear down every capture and open them all again.
+    All,
+}
+
+/// What live capture threads share, and what it takes to start another.
+#[derive(Debug, Clone)]
+struct LiveCaptures {
+    /// Keeps the channel open even if every capture thread dies, unlike capture files, which
+    /// leave it to end with them.
+    endpoint: mpsc::Sender<Ingress>,
+    /// Each interface's BPF expression, if it has one, by index.
+    bpf: Arc<[Option<String>]>,
+    stats: Option<Arc<[InterfaceStats]>>,
+    liveness: Arc<[Liveness]>,
+    /// How a capture thread reads its interface; `LiveCaptures::pcap` but in tests.
+    read: fn(LiveCaptures, usize, Device, u64),
+}
+
+impl LiveCaptures {
+    /// How long a capture blocks for a packet before checking whether it's been replaced.
+    const READ_TIMEOUT_MS: i32 = 1000;
+
+    /// Capture on `dev`, interface `idx`, in a thread of its own, until the observer's gone
+    /// or a newer capture of the same interface takes over.
+    fn spawn(&self, idx: usize, dev: Device) -> JoinHandle<()> {
+        let live = self.clone();
+        let liveness = &self.liveness[idx];
+        let generation = liveness.generation.load(Ordering::SeqCst);
+        liveness.touch();
+        thread::spawn(move || (live.read)(live, idx, dev, generation))
+    }
+
+    /// Read `dev`, interface `idx`, with pcap until the observer's gone or the capture's
+    /// generation moves on from `generation`.
+    fn pcap(live: LiveCaptures, idx: usize, dev: Device, generation: u64) {
+        let name = dev.name.clone();
+        let opened = Capture::from_device(dev).and_then(|cap| cap.immediate_mode(true).timeout(Self::READ_TIMEOUT_MS).open());
+        let mut cap = match opened {
+            Ok(cap) => cap,
+            Err(e) => return println!("couldn't capture on {}: {:?}", name, e),
+        };
+        if let Some(bpf) = &live.bpf[idx] {
+            if let Err(e) = cap.filter(bpf, true) {
+                return println!("couldn't filter capture on {}: {:?}", name, e);
+            }
+        }
+        let link = cap.get_datalink();
+        let liveness = &live.liveness[idx];
+        loop {
+            match cap.next_packet() {
+                Ok(pkt) => {
+                    let ingress = Ingress {
+                        data: pkt.data.to_vec(),
+                        interface: idx,
+                        link,
+                        time: capture_time(pkt.header),
+                    };
+                    liveness.touch();
+                    if let Some(counts) = &live.stats {
+                        counts[idx].packet(&mut cap);
+                    }
+                    // The observer's gone
+                    if live.endpoint.send(ingress).is_err() {
+                        return;
+                    }
+                },
+                Err(pcap::Error::TimeoutExpired) => (),
+                Err(_) => return,
+            }
+            if liveness.generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Observer {
     packets: mpsc::Receiver<Ingress>,
-    _endpoint: mpsc::Sender<Ingress>,
+    /// What it takes to capture live; capture files have none.
+    live: Option<LiveCaptures>,
     devices: Vec<Device>,
-    _threads: Vec<JoinHandle<()>>,
+    threads: Vec<JoinHandle<()>>,
     states: HashMap<Connection, Message>,
+    /// Capture time of the packet being handled, which messages are stamped with.
+    now: SystemTime,
+    snapshot_every: Option<Duration>,
+    last_snapshot: Instant,
+    filters: Filters,
+    stats: Option<Arc<[InterfaceStats]>>,
+    /// How many tunnels deep the packet being handled is.
+    depth: u8,
+    scans: Option<ScanDetector>,
+    failures: Option<FailureLimiter>,
+    handshakes: Option<Handshakes>,
+    ring: Option<Ring>,
+    /// Kernel interface indexes cooked captures have given, and the devices they are.
+    ifindexes: HashMap<u32, Option<usize>>,
+    /// How an interface is named from its kernel index; `sll::interface_name` but in tests.
+    interface_name: fn(u32) -> Option<String>,
+    /// The interfaces that are up and running, by name; `interfaces_up` but in tests.
+    interfaces_up: fn() -> Vec<String>,
+    subscribers: Subscribers,
+}
+
+/// The interfaces pcap lists as up and running, by name; none if it can't list them.
+fn interfaces_up() -> Vec<String> {
+    match Device::list() {
+        Ok(devices) => devices.into_iter().filter(|dev| dev.flags.is_up() && dev.flags.is_running()).map(|dev| dev.name).collect(),
+        Err(e) => {
+            println!("watchdog: couldn't list interfaces: {:?}", e);
+            Vec::new()
+        },
+    }
+}
+
+/// Repeats of one connection's problem since the last Failed message about it.
+#[derive(Debug)]
+struct Repeats {
+    /// When its window started, with the last Failed message about it.
+    sent: SystemTime,
+    /// When the latest repeat was seen.
+    last: SystemTime,
+    /// How many there have been since that message.
+    count: u32,
+}
+
+/// Holds back Failed messages repeating one sent within a window of capture time, so that a
+/// retry storm against a host that's down is a message a window rather than one an attempt.
+/// Every failure is still counted: each message stands for itself and its `repeats`.
+#[derive(Debug)]
+struct FailureLimiter {
+    window: Duration,
+    repeats: HashMap<(Connection, u8, u8), Repeats>,
+    last_pruned: SystemTime,
+}
+
+impl FailureLimiter {
+    /// How much capture time passes between sweeps for problems that have gone quiet.
+    const PRUNE_EVERY: Duration = Duration::from_secs(1);
+
+    fn new(window: u64) -> Self {
+        Self {
+            window: Duration::from_secs(window),
+            repeats: HashMap::new(),
+            last_pruned: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Note a failure at `now`, giving the problem to report it with (counting the repeats held
+    /// back since the last one), or `None` if it's held back itself.
+    fn fail(&mut self, conn: Connection, problem: Problem, now: SystemTime) -> Option<Problem> {
+        let key = (conn, problem.kind, problem.code);
+        match self.repeats.get_mut(&key) {
+            Some(held) if !expired(held.sent, now, self.window) => {
+                held.count += 1;
+                held.last = now;
+                None
+            },
+            held => {
+                let repeats = held.map_or(0, |held| held.count);
+                self.repeats.insert(key, Repeats { sent: now, last: now, count: 0 });
+                Some(Problem { repeats, ..problem })
+            },
+        }
+    }
+
+    /// Sweep problems whose window has passed, giving a Failed message for the repeats of each
+    /// that had any (stamped with the latest, and standing for it and the rest) and starting it
+    /// a new window, or forgetting it if it had none.
+    fn prune(&mut self, now: SystemTime) -> Vec<Message> {
+        if !expired(self.last_pruned, now, Self::PRUNE_EVERY) {
+            return Vec::new();
+        }
+        self.last_pruned = now;
+        let mut messages = Vec::new();
+        self.repeats.retain(|&(connection, kind, code), held| {
+            if !expired(held.sent, now, self.window) {
+                return true;
+            }
+            if held.count == 0 {
+                return false;
+            }
+            messages.push(Message::Failed(
+                State { as_of: held.last, connection, rtt_micros: None },
+                Problem { kind, code, repeats: held.count - 1 },
+            ));
+            *held = Repeats { sent: now, last: held.last, count: 0 };
+            true
+        });
+        messages.sort_by_key(|message| message.state().as_of);
+        messages
+    }
+}
+
+/// TCP handshakes under way, for timing them: when each SYN was seen, and the round trips
+/// measured when their SYN-ACKs were, until the connection's first Active message takes them.
+#[derive(Debug)]
+struct Handshakes {
+    /// By the tuple as the SYN has it: when it was first seen, and whether it's been sent
+    /// again since, after which a SYN-ACK can't be told apart as answering which.
+    syns: HashMap<Connection, (SystemTime, bool)>,
+    /// Round trips in microseconds, by the tuple as the SYN had it, and when they were measured.
+    measured: HashMap<Connection, (u32, SystemTime)>,
+    last_pruned: SystemTime,
 }
 
-impl From<dns_parser::ResourceRecord<'_>> for Name {
-    fn from(value: dns_parser::ResourceRecord) -> Self {
-        Self {
-            name: value.name.to_string(),
-            address: match value.data {
-                RData::A(arec) => Some(Resolution::Address(IpAddr::V4(arec.0))),
-                RData::AAAA(arec) => Some(Resolution::Address(IpAddr::V6(arec.0))),
-                RData::CNAME(crec) => Some(Resolution::Alias(crec.0.to_string())),
-                RData::MX(mrec) => Some(Resolution::Service(mrec.exchange.to_string(), None)),
-                RData::SRV(srec) => Some(Resolution::Service(srec.target.to_string(), Some(srec.port))),
-                RData::TXT(trec) => Some(Resolution::Text(trec.iter().map(|txt| txt.to_vec()).collect())),
-                _ => None
-            },
+impl Default for Handshakes {
+    fn default() -> Self {
+        Self {
+            syns: HashMap::new(),
+            measured: HashMap::new(),
+            last_pruned: SystemTime::UNIX_EPOCH,
+        }
+    }
+}
+
+impl Handshakes {
+    /// How much capture time passes between sweeps for handshakes that went nowhere.
+    const PRUNE_EVERY: Duration = Duration::from_secs(1);
+    /// How long a SYN waits for its SYN-ACK, and a round trip for the Active it goes on.
+    const PATIENCE: Duration = Duration::from_secs(30);
+
+    fn syn(&mut self, conn: Connection, now: SystemTime) {
+        match self.syns.get_mut(&conn) {
+            Some((seen, again)) if !expired(*seen, now, Self::PATIENCE) => *again = true,
+            _ => {
+                self.syns.insert(conn, (now, false));
+            },
+        }
+        self.measured.remove(&conn);
+    }
+
+    /// Note a SYN-ACK, `conn` being its tuple as it has it: the other way around from the SYN.
+    fn syn_ack(&mut self, conn: Connection, now: SystemTime) {
+        let conn = Connection { src: conn.dst, dst: conn.src, ..conn };
+        if let Some((seen, false)) = self.syns.remove(&conn) {
+            if let Ok(rtt) = now.duration_since(seen) {
+                if rtt <= Self::PATIENCE {
+                    self.measured.insert(conn, (rtt.as_micros() as u32, now));
+                }
+            }
+        }
+    }
+
+    /// The round trip measured for `conn`'s handshake, if there's one waiting.
+    fn take(&mut self, conn: &Connection) -> Option<u32> {
+        self.measured.remove(conn).map(|(rtt, _)| rtt)
+    }
+
+    fn prune(&mut self, now: SystemTime) {
+        if !expired(self.last_pruned, now, Self::PRUNE_EVERY) {
+            return;
+        }
+        self.last_pruned = now;
+        self.syns.retain(|_, (seen, _)| !expired(*seen, now, Self::PATIENCE));
+        self.measured.retain(|_, (_, at)| !expired(*at, now, Self::PATIENCE));
+    }
+}
+
+impl From<dns_parser::ResourceRecord<'_>> for Name {
+    fn from(value: dns_parser::ResourceRecord) -> Self {
+        Self {
+            name: value.name.to_string(),
+            address: match value.data {
+                RData::A(arec) => Some(Resolution::Address(IpAddr::V4(arec.0))),
+                RData::AAAA(arec) => Some(Resolution::Address(IpAddr::V6(arec.0))),
+                RData::CNAME(crec) => Some(Resolution::Alias(crec.0.to_string())),
+                RData::MX(mrec) => Some(Resolution::Service(mrec.exchange.to_string(), None)),
+                RData::SRV(srec) => Some(Resolution::Service(srec.target.to_string(), Some(srec.port))),
+                RData::TXT(trec) => Some(Resolution::Text(trec.iter().map(|txt| txt.to_vec()).collect())),
+                _ => None
+            },
+        }
+    }
+}
+
+impl From<dns_parser::Question<'_>> for Name {
+    fn from(value: dns_parser::Question) -> Self {
+        Self {
+            name: value.qname.to_string(),
+            address: None,
+        }
+    }
+}
+
+impl Observer {
+    pub const KEEPALIVE_SECS: u64 = 30u64;
+    /// Bundles a subscriber can fall behind by before they're dropped.
+    pub const SUBSCRIBER_BACKLOG: usize = 1024;
+    /// Most tunnels deep a mirrored frame is looked for.
+    const MAX_DEPTH: u8 = 2;
+
+    /// Counts per interface, in the order of `namespace`, if they were asked to be kept.
+    pub fn stats(&self) -> Option<Arc<[InterfaceStats]>> {
+        self.stats.clone()
+    }
+
+    /// When each live capture, in the order of `namespace`, last had a packet; empty for
+    /// capture files.
+    pub fn liveness(&self) -> Arc<[Liveness]> {
+        self.live.as_ref().map_or_else(|| Arc::from([]), |live| live.liveness.clone())
+    }
+
+    /// Look for live captures that have had no packets at all for `quiet`, and report each one
+    /// whose interface is up, recovering as `recovery` says. The wait starts again for every
+    /// capture found quiet, up or not, so each is looked at once per `quiet` at most.
+    pub fn watch(&mut self, quiet: Duration, recovery: Recovery) {
+        let Some(live) = &self.live else {
+            return;
+        };
+        let stalled: Vec<usize> = live.liveness.iter().enumerate()
+            .filter(|(_, liveness)| liveness.quiet() >= quiet)
+            .map(|(idx, _)| idx)
+            .collect();
+        if stalled.is_empty() {
+            return;
+        }
+        let up = (self.interfaces_up)();
+        let mut fired = Vec::new();
+        for idx in stalled {
+            let liveness = &live.liveness[idx];
+            liveness.touch();
+            if !up.contains(&liveness.name) {
+                continue;
+            }
+            liveness.stalls.fetch_add(1, Ordering::Relaxed);
+            println!("WATCHDOG: no packets captured on {} for {:.0}s, though it's up", liveness.name, quiet.as_secs_f64());
+            fired.push(idx);
+        }
+        let restart = match recovery {
+            Recovery::Log => Vec::new(),
+            Recovery::Capture => fired,
+            Recovery::All if fired.is_empty() => Vec::new(),
+            Recovery::All => (0 .. self.devices.len()).collect(),
+        };
+        for idx in restart {
+            self.restart_capture(idx);
+        }
+    }
+
+    /// Tear down the live capture on interface `idx` and open it again. The old capture thread
+    /// stops within a second, unless it's wedged, when it's left behind.
+    pub fn restart_capture(&mut self, idx: usize) {
+        let Some(live) = &self.live else {
+            return;
+        };
+        let liveness = &live.liveness[idx];
+        liveness.generation.fetch_add(1, Ordering::SeqCst);
+        liveness.restarts.fetch_add(1, Ordering::Relaxed);
+        println!("restarting capture on {}", liveness.name);
+        let thread = live.spawn(idx, self.devices[idx].clone());
+        self.threads.retain(|thread| !thread.is_finished());
+        self.threads.push(thread);
+    }
+
+    /// Get every bundle of messages from here on, alongside whoever's iterating this (or
+    /// taking its batches), which carries on as it would have. Snapshots aren't included.
+    pub fn subscribe(&mut self) -> MessageReceiver {
+        self.subscribers.subscribe(Self::SUBSCRIBER_BACKLOG)
+    }
+
+    pub fn namespace(&mut self) -> Vec<String> {
+        self.devices.iter().map(|dev| dev.name.clone()).collect()
+    }
+
+    /// Every interface in `namespace`, with its flags and addresses as pcap gave them; none
+    /// for capture files.
+    pub fn interfaces(&self) -> Vec<InterfaceInfo> {
+        self.devices.iter().map(InterfaceInfo::from).collect()
+    }
+
+    /// Write the frames captured last out as pcap files, if asked to keep them, saying `why`
+    /// when it's done; see `Ring::flush`.
+    pub fn flush_ring(&self, why: &str) -> Option<JoinHandle<io::Result<Vec<PathBuf>>>> {
+        let names: Vec<String> = self.devices.iter().map(|dev| dev.name.clone()).collect();
+        self.ring.as_ref().map(|ring| ring.flush(&names, why, SystemTime::now()))
+    }
+
+    /// The latest Starting or Active message of every connection that's open, as far as this
+    /// observer has seen.
+    pub fn current_states(&self) -> Vec<Message> {
+        self.states.values()
+            .filter(|message| matches!(message, Message::Starting(_) | Message::Active(_)))
+            .cloned()
+            .collect()
+    }
+
+    /// The next messages, or a snapshot if one is due; `None` once the packets run out. Unlike
+    /// iterating, this doesn't wait for packets past when a snapshot is due.
+    pub fn next_batch(&mut self) -> Option<Batch> {
+        self.next_batch_by(None).ok()
+    }
+
+    /// Like `next_batch`, but giving up with `Timeout` if there's nothing by `wait` from now,
+    /// and reporting the packets running out as `Disconnected`.
+    pub fn next_batch_timeout(&mut self, wait: Duration) -> Result<Batch, mpsc::RecvTimeoutError> {
+        self.next_batch_by(Some(Instant::now() + wait))
+    }
+
+    fn next_batch_by(&mut self, deadline: Option<Instant>) -> Result<Batch, mpsc::RecvTimeoutError> {
+        loop {
+            let due = self.snapshot_every.map(|every| self.last_snapshot + every);
+            if due.is_some_and(|due| Instant::now() >= due) {
+                self.last_snapshot = Instant::now();
+                return Ok(Batch::Snapshot(Snapshot {
+                    as_of: SystemTime::now(),
+                    states: self.current_states(),
+                }));
+            }
+            let until = match (due, deadline) {
+                (Some(due), Some(deadline)) => Some(due.min(deadline)),
+                (due, deadline) => due.or(deadline),
+            };
+            let ingress = match until {
+                Some(until) => match self.packets.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Ok(ingress) => ingress,
+                    Err(mpsc::RecvTimeoutError::Timeout) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                        return Err(mpsc::RecvTimeoutError::Timeout);
+                    },
+                    // A snapshot's due
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Err(mpsc::RecvTimeoutError::Disconnected),
+                },
+                None => self.packets.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)?,
+            };
+            let messages = self.handle(ingress);
+            if !messages.is_empty() {
+                return Ok(Batch::Messages(messages));
+            }
+        }
+    }
+
+    fn handle(&mut self, ingress: Ingress) -> Vec<Message> {
+        self.now = ingress.time;
+        let messages = if ingress.link == Linktype::ETHERNET {
+            self.handle_ether(ingress.interface, &ingress.data)
+        } else if ingress.link == Linktype::IEEE802_11_RADIOTAP {
+            self.handle_radiotap(ingress.interface, &ingress.data)
+        } else if ingress.link == Linktype::LINUX_SLL {
+            self.handle_sll(ingress.interface, sll::parse(&ingress.data))
+        } else if ingress.link == Linktype::LINUX_SLL2 {
+            self.handle_sll(ingress.interface, sll::parse_v2(&ingress.data))
+        } else {
+            self.unparsed(ingress.interface)
+        };
+        let messages = match &mut self.failures {
+            Some(failures) => failures.prune(self.now).into_iter().chain(messages).collect(),
+            None => messages,
+        };
+        if let Some(handshakes) = &mut self.handshakes {
+            handshakes.prune(self.now);
+        }
+        let messages = self.detect_scans(messages);
+        if !messages.is_empty() {
+            self.subscribers.publish(&messages);
+        }
+        if let Some(ring) = &mut self.ring {
+            ring.push(ingress);
+        }
+        messages
+    }
+
+    /// Pass `messages` by the scan detector, if there is one, adding the scans it finds and
+    /// leaving out what it suppresses.
+    fn detect_scans(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let Some(scans) = &mut self.scans else {
+            return messages;
+        };
+        let mut out = Vec::with_capacity(messages.len());
+        for message in messages {
+            if let Message::Starting(state) = &message {
+                out.extend(scans.attempt(state));
+            }
+            if !scans.suppresses(&message) {
+                out.push(message);
+            } else if let Message::Starting(state) = &message {
+                // Nor is it in snapshots
+                self.states.remove(&state.connection);
+            }
         }
+        out
     }
-}
 
-impl From<dns_parser::Question<'_>> for Name {
-    fn from(value: dns_parser::Question) -> Self {
-        Self {
-            name: value.qname.to_string(),
-            address: None,
+    fn handle_radiotap(&mut self, interface: usize, bytes: &[u8]) -> Vec<Message> {
+        match radiotap::parse(bytes) {
+            Ok(Payload::Packet(ethertype, rest)) => self.handle_ethertype(interface, EtherType::from(ethertype), rest),
+            Ok(Payload::Skipped) => Vec::new(),
+            Err(_) => self.unparsed(interface),
         }
     }
-}
 
-impl Observer {
-    pub const KEEPALIVE_SECS: u64 = 30u64;
+    /// Handle a Linux cooked capture's packet. Which way it went is in the header too, but
+    /// nothing's made of it yet. SLL2 says which interface it was really on; if that's one
+    /// this observer captures on as well, as when capturing on `any` and everything else, the
+    /// connection is put down to it rather than to `any`.
+    fn handle_sll(&mut self, interface: usize, cooked: Result<Cooked, sll::Malformed>) -> Vec<Message> {
+        match cooked {
+            Ok(cooked) => {
+                let interface = cooked.ifindex.and_then(|ifindex| self.device_index(ifindex)).unwrap_or(interface);
+                self.handle_ethertype(interface, EtherType::from(cooked.protocol), cooked.packet)
+            },
+            Err(_) => self.unparsed(interface),
+        }
+    }
 
-    pub fn namespace(&mut self) -> Vec<String> {
-        self.devices.iter().map(|dev| dev.name.clone()).collect()
+    /// Which of this observer's devices the kernel knows by `ifindex`, if any. Looked up once
+    /// an index; an interface that comes and goes keeps the index it was first seen with.
+    fn device_index(&mut self, ifindex: u32) -> Option<usize> {
+        let (devices, interface_name) = (&self.devices, self.interface_name);
+        *self.ifindexes.entry(ifindex).or_insert_with(|| {
+            let name = interface_name(ifindex)?;
+            devices.iter().position(|dev| dev.name == name)
+        })
     }
 
     fn handle_ether(&mut self, interface: usize, bytes: impl AsRef<[u8]>) -> Vec<Message> {
         if let Ok((rest, pkt)) = ethernet::parse_ethernet_frame(bytes.as_ref()) {
-            match pkt.ethertype {
-                EtherType::IPv4 => self.handle_ipv4(interface, rest),
-                EtherType::IPv6 => self.handle_ipv6(interface, rest),
-                _ => Vec::new()
-            }
+            self.handle_ethertype(interface, pkt.ethertype, rest)
         } else {
-            Vec::new()
+            self.unparsed(interface)
+        }
+    }
+
+    /// Handle what a link layer carries by its ethertype, under whatever VLAN tags it has.
+    fn handle_ethertype(&mut self, interface: usize, ethertype: EtherType, bytes: &[u8]) -> Vec<Message> {
+        let (ethertype, rest) = match ethertype {
+            EtherType::VLAN | EtherType::QinQ | EtherType::VLANdouble => match decap::untag(bytes) {
+                Ok((ethertype, rest)) => (EtherType::from(ethertype), rest),
+                Err(_) => return self.unparsed(interface),
+            },
+            ethertype => (ethertype, bytes),
+        };
+        match ethertype {
+            EtherType::IPv4 => self.handle_ipv4(interface, rest),
+            EtherType::IPv6 => self.handle_ipv6(interface, rest),
+            _ => Vec::new()
         }
     }
 
@@ -255,10 +1448,11 @@ impl Observer {
                 ip::IPProtocol::TCP => self.handle_tcp(interface, rest, pair),
                 ip::IPProtocol::UDP => self.handle_udp(interface, rest, pair),
                 ip::IPProtocol::ICMP => self.handle_icmp(interface, rest, pair),
+                ip::IPProtocol::Other(decap::GRE_PROTOCOL) => self.handle_gre(interface, rest),
                 _ => Vec::new()
             }
         } else {
-            Vec::new()
+            self.unparsed(interface)
         }
     }
 
@@ -272,10 +1466,11 @@ impl Observer {
                 ip::IPProtocol::TCP => self.handle_tcp(interface, rest, pair),
                 ip::IPProtocol::UDP => self.handle_udp(interface, rest, pair),
                 ip::IPProtocol::ICMP6 => self.handle_icmp(interface, rest, pair),
+                ip::IPProtocol::Other(decap::GRE_PROTOCOL) => self.handle_gre(interface, rest),
                 _ => Vec::new(),
             }
         } else {
-            Vec::new()
+            self.unparsed(interface)
         }
     }
 
@@ -287,6 +1482,15 @@ impl Observer {
                 dst: Endpoint { addr: hosts.dst, port: pkt.dest_port },
                 protocol: Protocol::Tcp,
             };
+            if !self.filters.wants(&conn) || self.filters.control_only && !(pkt.flag_syn | pkt.flag_fin | pkt.flag_rst) {
+                return Vec::new();
+            }
+            if let Some(handshakes) = self.handshakes.as_mut().filter(|_| pkt.flag_syn) {
+                match pkt.flag_ack {
+                    false => handshakes.syn(conn, self.now),
+                    true => handshakes.syn_ack(conn, self.now),
+                }
+            }
             if pkt.flag_rst | pkt.flag_fin {
                 self.connection_closed(conn, if pkt.flag_rst {
                     Closed::Reset
@@ -301,18 +1505,48 @@ impl Observer {
                 }
             }
         } else {
-            Vec::new()
+            self.unparsed(interface)
+        }
+    }
+
+    fn handle_gre(&mut self, interface: usize, bytes: &[u8]) -> Vec<Message> {
+        match decap::erspan(bytes) {
+            Ok(Some(mirrored)) => self.handle_mirrored(interface, mirrored.frame),
+            // Some other tunnel, which is reported on no more than other IP protocols are
+            Ok(None) => Vec::new(),
+            Err(_) => self.unparsed(interface),
         }
     }
 
+    /// Handle a frame mirrored to the sensor inside a tunnel, in place of the tunnel itself,
+    /// unless tunnels are already nested too deep.
+    fn handle_mirrored(&mut self, interface: usize, frame: &[u8]) -> Vec<Message> {
+        if self.depth >= Self::MAX_DEPTH {
+            return self.unparsed(interface);
+        }
+        self.depth += 1;
+        let messages = self.handle_ether(interface, frame);
+        self.depth -= 1;
+        messages
+    }
+
     fn handle_udp(&mut self, interface: usize, bytes: impl AsRef<[u8]>, hosts: HostPair) -> Vec<Message> {
         if let Ok((rest, pkt)) = udp::parse_udp_header(bytes.as_ref()) {
+            if pkt.dest_port == decap::VXLAN_PORT {
+                return match decap::vxlan(rest) {
+                    Ok(mirrored) => self.handle_mirrored(interface, mirrored.frame),
+                    Err(_) => self.unparsed(interface),
+                };
+            }
             let conn = Connection {
                 interface,
                 src: Endpoint { addr: hosts.src, port: pkt.source_port },
                 dst: Endpoint { addr: hosts.dst, port: pkt.dest_port },
                 protocol: Protocol::Udp,
             };
+            if !self.filters.wants(&conn) {
+                return Vec::new();
+            }
             if pkt.dest_port == 53 || pkt.source_port == 53 {
                 self.handle_dns(rest, conn)
             } else {
@@ -320,7 +1554,7 @@ impl Observer {
                 self.connection_closed(conn, Closed::Connectionless)
             }
         } else {
-            Vec::new()
+            self.unparsed(interface)
         }
     }
 
@@ -338,10 +1572,19 @@ impl Observer {
                 for resp in dns.answers {
                     names.push(resp.into());
                 }
+                let found = names.len();
+                names.retain(|name| !self.filters.suppresses(name));
+                if let (Some(stats), suppressed @ 1 ..) = (&self.stats, found - names.len()) {
+                    stats[conn.interface].suppressed.fetch_add(suppressed as u64, Ordering::Relaxed);
+                }
+                // Not even that the lookup happened, if it was all suppressed
+                if names.is_empty() {
+                    return Vec::new();
+                }
                 self.send_names(conn, names)
             }
         } else {
-            Vec::new()
+            self.unparsed(conn.interface)
         }
     }
 
@@ -362,7 +1605,7 @@ impl Observer {
                     protocol: Protocol::Tcp,
                 })
             } else { None };
-            if let Some(conn) = conn {
+            if let Some(conn) = conn.filter(|conn| self.filters.wants(conn)) {
                 // TODO
                 let problem: Problem = pkt.code.into();
                 self.connection_unavail(conn, problem)
@@ -370,25 +1613,33 @@ impl Observer {
                 Vec::new()
             }
         } else {
-            Vec::new()
+            self.unparsed(interface)
+        }
+    }
+
+    /// Count a packet that couldn't be parsed, and report nothing for it.
+    fn unparsed(&self, interface: usize) -> Vec<Message> {
+        if let Some(stats) = &self.stats {
+            stats[interface].unparsed.fetch_add(1, Ordering::Relaxed);
         }
+        Vec::new()
     }
 
     fn send_names(&mut self, conn: Connection, names: Vec<Name>) -> Vec<Message> {
         let mut messages = self.connection_closed(conn, Closed::Connectionless);
-        messages.push(Message::Name(State { as_of: SystemTime::now(), connection: conn }, names));
+        messages.push(Message::Name(State { as_of: self.now, connection: conn, rtt_micros: None }, names));
         messages
     }
 
     fn connection_open(&mut self, conn: Connection) -> Vec<Message> {
         if let Some(Message::Active(state)) = self.states.get(&conn) {
             // Ensure we heartbeat some of these connections somewhat regularly
-            if SystemTime::now().duration_since(state.as_of)
-                .map(|d| d > Duration::from_secs(Self::KEEPALIVE_SECS))
+            if self.now.duration_since(state.as_of)
+                .map(|d| d > Duration::from_secs(self.filters.keepalive))
                 .unwrap_or(false)
             {
                 let message = Message::Active(
-                    State { as_of: SystemTime::now(), connection: conn }
+                    State { as_of: self.now, connection: conn, rtt_micros: None }
                 );
                 self.states.insert(conn, message.clone());
                 vec![message]
@@ -396,11 +1647,11 @@ impl Observer {
                 Vec::new()
             }
         } else {
-            let message = Message::Active(
-                State { as_of: SystemTime::now(), connection: conn }
-            );
-            self.states.insert(conn, message.clone());
-            vec![message]
+            let state = State { as_of: self.now, connection: conn, rtt_micros: None };
+            self.states.insert(conn, Message::Active(state));
+            // Only the message itself carries the round trip, not what snapshots repeat
+            let rtt_micros = self.handshakes.as_mut().and_then(|handshakes| handshakes.take(&conn));
+            vec![Message::Active(State { rtt_micros, ..state })]
         }
     }
 
@@ -408,12 +1659,12 @@ impl Observer {
     fn connection_starting(&mut self, conn: Connection) -> Vec<Message> {
         if let Some(Message::Starting(state)) = self.states.get(&conn) {
             // Ensure we heartbeat some of these connections somewhat regularly
-            if SystemTime::now().duration_since(state.as_of)
-                .map(|d| d > Duration::from_secs(Self::KEEPALIVE_SECS))
+            if self.now.duration_since(state.as_of)
+                .map(|d| d > Duration::from_secs(self.filters.keepalive))
                 .unwrap_or(false)
             {
                 let message = Message::Starting(
-                    State { as_of: SystemTime::now(), connection: conn }
+                    State { as_of: self.now, connection: conn, rtt_micros: None }
                 );
                 self.states.insert(conn, message.clone());
                 vec![message]
@@ -422,7 +1673,7 @@ impl Observer {
             }
         } else {
             let message = Message::Starting(
-                State { as_of: SystemTime::now(), connection: conn }
+                State { as_of: self.now, connection: conn, rtt_micros: None }
             );
             self.states.insert(conn, message.clone());
             vec![message]
@@ -432,7 +1683,7 @@ impl Observer {
     fn connection_closed(&mut self, conn: Connection, how: Closed) -> Vec<Message> {
         // Due to connectionless protocols, don't rate-limit this
         let message = Message::Ended(
-            State { as_of: SystemTime::now(), connection: conn },
+            State { as_of: self.now, connection: conn, rtt_micros: None },
             how,
         );
         self.states.insert(conn, message.clone());
@@ -440,8 +1691,15 @@ impl Observer {
     }
 
     fn connection_unavail(&mut self, conn: Connection, problem: Problem) -> Vec<Message> {
+        let problem = match &mut self.failures {
+            Some(failures) => match failures.fail(conn, problem, self.now) {
+                Some(problem) => problem,
+                None => return Vec::new(),
+            },
+            None => problem,
+        };
         let message = Message::Failed(
-            State { as_of: SystemTime::now(), connection: conn },
+            State { as_of: self.now, connection: conn, rtt_micros: None },
             problem,
         );
         self.states.insert(conn, message.clone());
@@ -449,25 +1707,918 @@ impl Observer {
     }
 }
 
+/// Each item is the messages one packet gave rise to, never empty. To send one, hand the whole
+/// bundle to `Client::send_bundle`; a bundle isn't a `Coder`, so `Client::send` won't take it.
 impl Iterator for Observer {
     type Item = Vec<Message>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            match self.packets.recv() {
-                Err(_) => return None,
-                Ok(ingress) => {
-                    match ingress.link {
-                        Linktype::ETHERNET => {
-                            let msgs = self.handle_ether(ingress.interface, &ingress.data);
-                            if !msgs.is_empty() {
-                                return Some(msgs);
-                            }
-                        },
-                        _ => (),
-                    }
+            let messages = self.handle(self.packets.recv().ok()?);
+            if !messages.is_empty() {
+                return Some(messages);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::atomic::AtomicUsize};
+
+    use crate::scan::ScanKind;
+
+    use super::*;
+
+    /// An observer of capture files on `devices`, fed by the sender, with nothing filtered.
+    fn observer(devices: &[&str]) -> (mpsc::Sender<Ingress>, Observer) {
+        let (sender, packets) = mpsc::channel();
+        (sender, Observer {
+            packets,
+            live: None,
+            devices: devices.iter().map(|&name| Device::from(name)).collect(),
+            threads: Vec::new(),
+            states: HashMap::new(),
+            now: SystemTime::UNIX_EPOCH,
+            snapshot_every: None,
+            last_snapshot: Instant::now(),
+            filters: Filters::default(),
+            stats: None,
+            depth: 0,
+            scans: None,
+            failures: None,
+            handshakes: None,
+            ring: None,
+            ifindexes: HashMap::new(),
+            interface_name: kernel_names,
+            interfaces_up: Vec::new,
+            subscribers: Subscribers::default(),
+        })
+    }
+
+    /// The kernel's interfaces, as far as the tests go.
+    fn kernel_names(ifindex: u32) -> Option<String> {
+        match ifindex {
+            1 => Some("lo".to_string()),
+            2 => Some("eth0".to_string()),
+            3 => Some("wlan0".to_string()),
+            _ => None,
+        }
+    }
+
+    /// An IPv4 packet from `src` to `dst` carrying `protocol`'s header and payload.
+    fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, body: &[u8]) -> Vec<u8> {
+        let len = (20 + body.len()) as u16;
+        let mut packet = vec![0x45, 0x00];
+        packet.extend_from_slice(&len.to_be_bytes());
+        // Identification, flags and fragment offset, TTL, protocol, checksum
+        packet.extend_from_slice(&[0x00, 0x01, 0x40, 0x00, 0x40, protocol, 0x00, 0x00]);
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    /// A UDP datagram from port `src` to `dst` carrying `payload`.
+    fn udp(src: u16, dst: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&src.to_be_bytes());
+        datagram.extend_from_slice(&dst.to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0x00, 0x00]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    /// `packet` behind an SLL2 header saying it was captured on `ifindex`.
+    fn sll2(ifindex: u32, packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x08, 0x00, 0x00, 0x00];
+        frame.extend_from_slice(&ifindex.to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x01, 0x00, 0x06, 0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00]);
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    fn cooked(interface: usize, ifindex: u32) -> Ingress {
+        Ingress {
+            data: sll2(ifindex, &ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 17, &udp(5000, 6000, b"hi"))),
+            interface,
+            link: Linktype::LINUX_SLL2,
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    fn interfaces(messages: &[Message]) -> Vec<usize> {
+        messages.iter().map(|message| message.state().connection.interface).collect()
+    }
+
+    /// A datagram from 10.0.0.1:`srcport` captured on the first device, new to the observer.
+    fn datagram(srcport: u16) -> Ingress {
+        Ingress {
+            data: sll2(2, &ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2), 17, &udp(srcport, 6000, b"hi"))),
+            interface: 0,
+            link: Linktype::LINUX_SLL2,
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    fn batch(observer: &mut Observer) -> Vec<Message> {
+        match observer.next_batch_timeout(Duration::from_secs(1)) {
+            Ok(Batch::Messages(messages)) => messages,
+            other => panic!("expected messages, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn subscribers_see_every_bundle_and_a_slow_one_drops_instead_of_stalling() {
+        const BUNDLES: usize = Observer::SUBSCRIBER_BACKLOG + 50;
+        let (sender, mut observer) = observer(&["eth0"]);
+        let (first, second, slow) = (observer.subscribe(), observer.subscribe(), observer.subscribe());
+        let mut emitted = Vec::new();
+        for srcport in 0 .. BUNDLES {
+            sender.send(datagram(1000 + srcport as u16)).unwrap();
+            let messages = batch(&mut observer);
+            // Each keeping up as they go, and seeing what the observer's iterator did
+            for subscriber in [&first, &second] {
+                assert_eq!(&*subscriber.recv_timeout(Duration::from_secs(1)).unwrap(), &messages[..]);
+            }
+            emitted.push(messages);
+        }
+        assert_eq!((first.dropped(), second.dropped()), (0, 0));
+
+        // The slow one has what fitted, from the start, and a count of the rest
+        assert_eq!(slow.dropped(), (BUNDLES - Observer::SUBSCRIBER_BACKLOG) as u64);
+        let kept: Vec<_> = slow.bundles.try_iter().map(|bundle| bundle.to_vec()).collect();
+        assert_eq!(kept, emitted[.. Observer::SUBSCRIBER_BACKLOG]);
+
+        // Room again, it picks up with what comes next
+        sender.send(datagram(60000)).unwrap();
+        let messages = batch(&mut observer);
+        assert_eq!(&*slow.try_recv().unwrap(), &messages[..]);
+        assert_eq!(slow.dropped(), (BUNDLES - Observer::SUBSCRIBER_BACKLOG) as u64);
+    }
+
+    #[test]
+    fn dropping_a_receiver_unsubscribes_it_and_the_observer_going_ends_the_rest() {
+        let (sender, mut observer) = observer(&["eth0"]);
+        let kept = observer.subscribe();
+        let gone = observer.subscribe();
+        drop(gone);
+        sender.send(datagram(1000)).unwrap();
+        let messages = batch(&mut observer);
+        assert_eq!(observer.subscribers.0.len(), 1, "the dropped receiver is still subscribed");
+        assert_eq!(&*kept.recv().unwrap(), &messages[..]);
+        drop(observer);
+        assert!(kept.recv().is_none());
+    }
+
+    #[test]
+    fn cooked_packets_go_down_to_the_device_they_were_on() {
+        let (_sender, mut observer) = observer(&["any", "eth0", "wlan0"]);
+        assert_eq!(interfaces(&observer.handle(cooked(0, 2))), [1]);
+        assert_eq!(interfaces(&observer.handle(cooked(0, 3))), [2]);
+    }
+
+    #[test]
+    fn cooked_packets_from_elsewhere_stay_where_they_were_captured() {
+        let (_sender, mut observer) = observer(&["any", "eth0"]);
+        // Not captured on, and no longer there at all
+        assert_eq!(interfaces(&observer.handle(cooked(0, 1))), [0]);
+        assert_eq!(interfaces(&observer.handle(cooked(0, 99))), [0]);
+    }
+
+    #[test]
+    fn an_ifindex_is_looked_up_once() {
+        static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+        fn counted(ifindex: u32) -> Option<String> {
+            LOOKUPS.fetch_add(1, Ordering::Relaxed);
+            kernel_names(ifindex)
+        }
+        let (_sender, mut observer) = observer(&["any", "eth0"]);
+        observer.interface_name = counted;
+        for _ in 0 .. 3 {
+            assert_eq!(observer.device_index(2), Some(1));
+            assert_eq!(observer.device_index(3), None);
+        }
+        assert_eq!(LOOKUPS.load(Ordering::Relaxed), 2);
+        // Even once the kernel has given the index to another interface
+        observer.interface_name = |_| Some("wlan0".to_string());
+        assert_eq!(observer.device_index(2), Some(1));
+    }
+
+    /// `packet` as IPv4 in an Ethernet frame.
+    fn ether(packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00];
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+    const FIN: u8 = 0x01;
+    const RST: u8 = 0x04;
+
+    /// A TCP segment from port `src` to `dst` with `flags` and no payload.
+    fn tcp(src: u16, dst: u16, flags: u8) -> Vec<u8> {
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&src.to_be_bytes());
+        segment.extend_from_slice(&dst.to_be_bytes());
+        // Sequence and acknowledgement numbers
+        segment.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+        // Data offset, flags, window, checksum, urgent pointer
+        segment.extend_from_slice(&[0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        segment
+    }
+
+    /// A DNS response saying example.com has the address 93.184.216.34.
+    const EXAMPLE_COM: [u8; 45] = [
+        // ID, flags, one question, one answer, nothing else
+        0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        // example.com, type A, class IN
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01,
+        // The question's name, type A, class IN, TTL 60, the address
+        0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 93, 184, 216, 34,
+    ];
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 53);
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// `packet`, as IPv4 on Ethernet captured at `secs` on the first device.
+    fn captured(secs: u64, packet: Vec<u8>) -> Ingress {
+        Ingress { data: ether(&packet), interface: 0, link: Linktype::ETHERNET, time: at(secs) }
+    }
+
+    fn segment(secs: u64, src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), flags: u8) -> Ingress {
+        captured(secs, ipv4(src.0, dst.0, 6, &tcp(src.1, dst.1, flags)))
+    }
+
+    fn connection(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), protocol: Protocol) -> Connection {
+        let endpoint = |(addr, port): (Ipv4Addr, u16)| Endpoint { addr: IpAddr::V4(addr), port };
+        Connection { interface: 0, src: endpoint(src), dst: endpoint(dst), protocol }
+    }
+
+    fn state(secs: u64, connection: Connection) -> State {
+        State { as_of: at(secs), connection, rtt_micros: None }
+    }
+
+    #[test]
+    fn tcp_from_handshake_to_close() {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        let (client, server) = ((CLIENT, 40000), (SERVER, 443));
+        let outbound = connection(client, server, Protocol::Tcp);
+        let inbound = connection(server, client, Protocol::Tcp);
+        assert_eq!(observer.handle(segment(100, client, server, SYN)), [Message::Starting(state(100, outbound))]);
+        // A retransmitted SYN says nothing new
+        assert_eq!(observer.handle(segment(101, client, server, SYN)), []);
+        assert_eq!(observer.handle(segment(102, server, client, SYN | ACK)), [Message::Active(state(102, inbound))]);
+        assert_eq!(observer.handle(segment(102, client, server, ACK)), [Message::Active(state(102, outbound))]);
+        assert_eq!(observer.handle(segment(103, client, server, ACK)), []);
+        assert_eq!(observer.handle(segment(110, client, server, FIN | ACK)), [Message::Ended(state(110, outbound), Closed::Normally)]);
+        assert_eq!(observer.handle(segment(111, server, client, RST)), [Message::Ended(state(111, inbound), Closed::Reset)]);
+    }
+
+    #[test]
+    fn an_open_connection_is_repeated_once_keepalive_passes() {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        let (client, server) = ((CLIENT, 40000), (SERVER, 443));
+        let keepalive = Observer::KEEPALIVE_SECS;
+        assert_eq!(observer.handle(segment(100, client, server, ACK)).len(), 1);
+        assert_eq!(observer.handle(segment(100 + keepalive, client, server, ACK)), []);
+        assert_eq!(observer.handle(segment(101 + keepalive, client, server, ACK)), [Message::Active(state(101 + keepalive, connection(client, server, Protocol::Tcp)))]);
+    }
+
+    #[test]
+    fn udp_is_ended_as_soon_as_its_seen() {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        let datagram = captured(100, ipv4(CLIENT, SERVER, 17, &udp(5000, 123, b"time?")));
+        let conn = connection((CLIENT, 5000), (SERVER, 123), Protocol::Udp);
+        assert_eq!(observer.handle(datagram), [Message::Ended(state(100, conn), Closed::Connectionless)]);
+    }
+
+    #[test]
+    fn dns_gives_the_names_asked_and_answered() {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        let response = captured(100, ipv4(SERVER, CLIENT, 17, &udp(53, 5353, &EXAMPLE_COM)));
+        let conn = connection((SERVER, 53), (CLIENT, 5353), Protocol::Udp);
+        assert_eq!(observer.handle(response), [
+            Message::Ended(state(100, conn), Closed::Connectionless),
+            Message::Name(state(100, conn), vec![
+                Name { name: "example.com".to_string(), address: None },
+                Name { name: "example.com".to_string(), address: Some(Resolution::Address(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)))) },
+            ]),
+        ]);
+    }
+
+    /// One answer to a lookup: its owner's name, then an address or the name it's an alias for.
+    enum Answer<'a> {
+        A(&'a str, Ipv4Addr),
+        Cname(&'a str, &'a str),
+    }
+
+    /// `name` as DNS labels, uncompressed.
+    fn labels(name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for label in name.split('.') {
+            bytes.push(label.len() as u8);
+            bytes.extend_from_slice(label.as_bytes());
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    /// A response to an A lookup of `qname`, answering with `answers`.
+    fn dns_response(qname: &str, answers: &[Answer]) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, answers.len() as u8, 0x00, 0x00, 0x00, 0x00];
+        bytes.extend(labels(qname));
+        bytes.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        for answer in answers {
+            let (owner, kind, data) = match answer {
+                Answer::A(owner, addr) => (owner, 1u8, addr.octets().to_vec()),
+                Answer::Cname(owner, target) => (owner, 5u8, labels(target)),
+            };
+            bytes.extend(labels(owner));
+            // Type, class IN, TTL 60, then the data
+            bytes.extend_from_slice(&[0x00, kind, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c]);
+            bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            bytes.extend(data);
+        }
+        bytes
+    }
+
+    /// An observer suppressing `patterns`, counting into stats of its own, and a subscriber to it.
+    fn suppressing(patterns: &[&str]) -> (Observer, Arc<[InterfaceStats]>, MessageReceiver) {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        observer.filters.suppress_names = patterns.iter().map(|pattern| pattern.parse().unwrap()).collect();
+        let stats: Arc<[InterfaceStats]> = Arc::from([InterfaceStats::new("eth0".to_string())]);
+        observer.stats = Some(stats.clone());
+        let subscriber = observer.subscribe();
+        (observer, stats, subscriber)
+    }
+
+    /// A CNAME chain through a tracker's domain to a CDN's address.
+    fn through_a_tracker() -> Ingress {
+        captured(100, ipv4(SERVER, CLIENT, 17, &udp(53, 5353, &dns_response("www.shop.example", &[
+            Answer::Cname("www.shop.example", "shop.cdn.Tracker.example"),
+            Answer::Cname("shop.cdn.Tracker.example", "edge.cdnhost.example"),
+            Answer::A("edge.cdnhost.example", Ipv4Addr::new(192, 0, 2, 7)),
+        ]))))
+    }
+
+    #[test]
+    fn suppressed_names_are_left_out_of_a_chain_each_on_its_own() {
+        let (mut observer, stats, subscriber) = suppressing(&["tracker.EXAMPLE"]);
+        let conn = connection((SERVER, 53), (CLIENT, 5353), Protocol::Udp);
+        let expected = [
+            Message::Ended(state(100, conn), Closed::Connectionless),
+            // The question and the end of the chain; the links to and from the tracker go
+            Message::Name(state(100, conn), vec![
+                Name { name: "www.shop.example".to_string(), address: None },
+                Name { name: "edge.cdnhost.example".to_string(), address: Some(Resolution::Address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)))) },
+            ]),
+        ];
+        assert_eq!(observer.handle(through_a_tracker()), expected);
+        assert_eq!(stats[0].suppressed.load(Ordering::Relaxed), 2);
+        // What's handed on for sending is already without them
+        assert_eq!(&*subscriber.try_recv().unwrap(), &expected[..]);
+    }
+
+    #[test]
+    fn a_question_and_its_answers_are_judged_apart() {
+        let (mut observer, stats, _subscriber) = suppressing(&["www.shop.example"]);
+        let names = observer.handle(through_a_tracker()).into_iter()
+            .filter_map(|message| match message {
+                Message::Name(_, names) => Some(names),
+                _ => None,
+            })
+            .flatten()
+            .map(|name| name.name)
+            .collect::<Vec<_>>();
+        // The answer about the name asked after goes along with the question; the rest stay
+        assert_eq!(names, ["shop.cdn.Tracker.example", "edge.cdnhost.example"]);
+        assert_eq!(stats[0].suppressed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn a_lookup_thats_all_suppressed_isnt_reported_at_all() {
+        let (mut observer, stats, subscriber) = suppressing(&["*.example"]);
+        assert_eq!(observer.handle(through_a_tracker()), []);
+        assert_eq!(stats[0].suppressed.load(Ordering::Relaxed), 4);
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[test]
+    fn dns_that_doesnt_parse_is_counted_and_nothing_more() {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        let stats: Arc<[InterfaceStats]> = Arc::from([InterfaceStats::new("eth0".to_string())]);
+        observer.stats = Some(stats.clone());
+        let garbled = captured(100, ipv4(SERVER, CLIENT, 17, &udp(53, 5353, &EXAMPLE_COM[.. 20])));
+        assert_eq!(observer.handle(garbled), []);
+        assert_eq!(stats[0].unparsed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn replay_is_stamped_with_capture_times_and_ends_with_its_packets() {
+        let (sender, mut observer) = observer(&["capture.pcap"]);
+        let (client, server) = ((CLIENT, 40000), (SERVER, 443));
+        // Years ago, as a saved capture would be
+        sender.send(segment(1_000_000_000, client, server, SYN)).unwrap();
+        sender.send(captured(1_000_000_005, ipv4(CLIENT, SERVER, 17, &udp(5000, 123, b"")))).unwrap();
+        drop(sender);
+        let times: Vec<SystemTime> = observer.by_ref().flatten().map(|message| message.state().as_of).collect();
+        assert_eq!(times, [at(1_000_000_000), at(1_000_000_005)]);
+        assert_eq!(observer.next(), None);
+        assert_eq!(observer.next_batch_timeout(Duration::from_secs(5)).err(), Some(mpsc::RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn interfaces_carry_every_address_pcap_gave() {
+        let (_sender, mut observer) = observer(&["eth0", "capture.pcap"]);
+        let address = |addr: &str, netmask: Option<&str>| pcap::Address {
+            addr: addr.parse().unwrap(),
+            netmask: netmask.map(|mask| mask.parse().unwrap()),
+            broadcast_addr: None,
+            dst_addr: None,
+        };
+        observer.devices[0] = Device {
+            name: "eth0".to_string(),
+            desc: None,
+            addresses: vec![
+                address("10.3.0.9", Some("255.255.255.0")),
+                address("fe80::1", Some("ffff:ffff:ffff:ffff::")),
+                address("192.0.2.1", None),
+            ],
+            flags: pcap::DeviceFlags::from(pcap::IfFlags::UP.bits() | pcap::IfFlags::RUNNING.bits()),
+        };
+        let interfaces = observer.interfaces();
+        assert_eq!(interfaces.iter().map(|interface| &interface.name[..]).collect::<Vec<_>>(), ["eth0", "capture.pcap"]);
+        assert_eq!(interfaces[0].flags, (pcap::IfFlags::UP | pcap::IfFlags::RUNNING).bits());
+        let addresses: Vec<String> = interfaces[0].addresses.iter().map(ToString::to_string).collect();
+        assert_eq!(addresses, ["10.3.0.9/24", "fe80::1/64", "192.0.2.1"]);
+        // A capture file is no interface pcap knows of
+        assert_eq!(interfaces[1].flags, 0);
+        assert!(interfaces[1].addresses.is_empty());
+    }
+
+    /// `segment`, captured `micros` microseconds into second 100.
+    fn segment_at(micros: u64, src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), flags: u8) -> Ingress {
+        Ingress { time: at(100) + Duration::from_micros(micros), ..segment(0, src, dst, flags) }
+    }
+
+    /// The round trip on every Active `packets` give, with handshakes timed.
+    fn round_trips(packets: Vec<Ingress>) -> Vec<Option<u32>> {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        observer.filters.rtt = true;
+        observer.handshakes = Some(Handshakes::default());
+        packets.into_iter().flat_map(|packet| observer.handle(packet)).filter_map(|message| match message {
+            Message::Active(state) => Some(state.rtt_micros),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn a_handshake_seen_whole_is_timed_syn_to_syn_ack() {
+        let (client, server) = ((CLIENT, 40000), (SERVER, 443));
+        for gap in [12_500, 250] {
+            // The SYN-ACK's Active is the server's side; the client's first ACK carries the time,
+            // and only that once
+            assert_eq!(round_trips(vec![
+                segment_at(0, client, server, SYN),
+                segment_at(gap, server, client, SYN | ACK),
+                segment_at(gap + 100, client, server, ACK),
+                segment_at(gap + 200, client, server, ACK),
+                segment_at(gap + 1_000_000 * (Observer::KEEPALIVE_SECS + 1), client, server, ACK),
+            ]), [None, Some(gap as u32), None]);
+        }
+    }
+
+    #[test]
+    fn a_handshake_not_seen_whole_isnt_timed() {
+        let (client, server) = ((CLIENT, 40000), (SERVER, 443));
+        // Retransmitted, so the SYN-ACK could answer either
+        assert_eq!(round_trips(vec![
+            segment_at(0, client, server, SYN),
+            segment_at(1_000, client, server, SYN),
+            segment_at(12_500, server, client, SYN | ACK),
+            segment_at(12_600, client, server, ACK),
+        ]), [None, None]);
+        // Picked up midstream
+        assert_eq!(round_trips(vec![
+            segment_at(0, server, client, SYN | ACK),
+            segment_at(100, client, server, ACK),
+        ]), [None, None]);
+        // Answered too late
+        assert_eq!(round_trips(vec![
+            segment_at(0, client, server, SYN),
+            segment_at(31_000_000, server, client, SYN | ACK),
+            segment_at(31_000_100, client, server, ACK),
+        ]), [None, None]);
+    }
+
+    /// Every message an observer looking for scans with `settings` gives for a SYN from
+    /// the client to each of `ports` on the server, one a second.
+    fn swept(settings: ScanSettings, ports: std::ops::Range<u16>) -> Vec<Message> {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        observer.scans = Some(ScanDetector::new(settings));
+        ports.enumerate().flat_map(|(n, port)| observer.handle(segment(100 + n as u64, (CLIENT, 40000), (SERVER, port), SYN))).collect()
+    }
+
+    fn scans(messages: &[Message]) -> Vec<(State, Scan)> {
+        messages.iter().filter_map(|message| match message {
+            Message::Scan(state, scan) => Some((*state, *scan)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn a_syn_scan_is_one_scan_message() {
+        let settings = ScanSettings { ports: 20, hosts: 100, window: 30, suppress: false };
+        let messages = swept(settings, 1 .. 26);
+        let scanned = Endpoint { addr: IpAddr::V4(SERVER), port: 0 };
+        // Found with the twentieth port, nineteen seconds in
+        assert_eq!(scans(&messages), [(
+            State { as_of: at(119), connection: Connection { dst: scanned, ..connection((CLIENT, 0), (SERVER, 0), Protocol::Tcp) }, rtt_micros: None },
+            Scan { kind: ScanKind::Ports, count: 20, window: Duration::from_secs(30) },
+        )]);
+        // Every attempt is still reported, as suppression's off
+        assert_eq!(messages.iter().filter(|message| matches!(message, Message::Starting(_))).count(), 25);
+    }
+
+    #[test]
+    fn fewer_ports_than_the_threshold_are_no_scan() {
+        let settings = ScanSettings { ports: 20, hosts: 100, window: 30, suppress: false };
+        assert_eq!(scans(&swept(settings.clone(), 1 .. 20)), []);
+        // Nor are as many, once the first have left the window
+        assert_eq!(scans(&swept(ScanSettings { window: 10, ..settings }, 1 .. 40)), []);
+    }
+
+    #[test]
+    fn a_scan_found_suppresses_its_attempts_from_then_on() {
+        let settings = ScanSettings { ports: 20, hosts: 100, window: 30, suppress: true };
+        let messages = swept(settings, 1 .. 26);
+        assert_eq!(scans(&messages).len(), 1);
+        let attempted: Vec<u16> = messages.iter().filter_map(|message| match message {
+            Message::Starting(state) => Some(state.connection.dst.port),
+            _ => None,
+        }).collect();
+        assert_eq!(attempted, (1 .. 20).collect::<Vec<_>>());
+    }
+
+    /// What a limiter with a 10s window lets out of failures at each of `times`, swept before
+    /// each as the observer does and once more at `end`: (as_of, repeats) of each message.
+    fn limited(times: &[u64], end: u64) -> Vec<(u64, u32)> {
+        let mut limiter = FailureLimiter::new(10);
+        let conn = connection((CLIENT, 40000), (SERVER, 443), Protocol::Tcp);
+        let problem = Problem { kind: 3, code: 1, repeats: 0 };
+        let secs = |time: SystemTime| time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let mut out = Vec::new();
+        for &time in times {
+            out.extend(limiter.prune(at(time)).iter().map(|message| match message {
+                Message::Failed(state, problem) => (secs(state.as_of), problem.repeats),
+                other => panic!("{:?}", other),
+            }));
+            out.extend(limiter.fail(conn, problem, at(time)).map(|problem| (time, problem.repeats)));
+        }
+        out.extend(limiter.prune(at(end)).iter().map(|message| match message {
+            Message::Failed(state, problem) => (secs(state.as_of), problem.repeats),
+            other => panic!("{:?}", other),
+        }));
+        out
+    }
+
+    #[test]
+    fn a_retry_storm_is_a_message_a_window() {
+        // A SYN retried every second for a minute, each drawing an unreachable, then one more
+        // long after
+        let times: Vec<u64> = (0 .. 60).chain([100]).collect();
+        let messages = limited(&times, 200);
+        // The first at once, the rest once a window's passed as of a sweep, stamped with the
+        // last they stand for
+        assert_eq!(messages, [(0, 0), (11, 10), (21, 9), (33, 11), (43, 9), (55, 11), (59, 3), (100, 0)]);
+        // Every failure is accounted for
+        assert_eq!(messages.iter().map(|(_, repeats)| 1 + *repeats as usize).sum::<usize>(), times.len());
+    }
+
+    #[test]
+    fn a_failure_on_its_own_is_reported_at_once_and_then_forgotten() {
+        assert_eq!(limited(&[5], 100), [(5, 0)]);
+        let mut limiter = FailureLimiter::new(10);
+        let conn = connection((CLIENT, 40000), (SERVER, 443), Protocol::Tcp);
+        limiter.fail(conn, Problem { kind: 3, code: 1, repeats: 0 }, at(5));
+        assert!(limiter.prune(at(100)).is_empty());
+        assert!(limiter.repeats.is_empty());
+    }
+
+    /// How long the watchdog tests give a capture to go quiet.
+    const QUIET: Duration = Duration::from_millis(300);
+    /// How often a synthetic capture that's flowing has a packet.
+    const FEED_EVERY: Duration = Duration::from_millis(20);
+
+    /// An observer capturing live on `devices`, each read by `read` in place of pcap, with `up`
+    /// as the interfaces that are up.
+    fn live_observer(devices: &[&str], read: fn(LiveCaptures, usize, Device, u64), up: fn() -> Vec<String>) -> Observer {
+        let (endpoint, packets) = mpsc::channel();
+        let started = Instant::now();
+        let live = LiveCaptures {
+            endpoint,
+            bpf: devices.iter().map(|_| None).collect(),
+            stats: None,
+            liveness: devices.iter().map(|&name| Liveness::new(name.to_string(), started)).collect(),
+            read,
+        };
+        let (_, mut observer) = observer(devices);
+        observer.threads = observer.devices.iter().enumerate().map(|(idx, dev)| live.spawn(idx, dev.clone())).collect();
+        observer.packets = packets;
+        observer.live = Some(live);
+        observer.interfaces_up = up;
+        observer
+    }
+
+    /// A live capture as the watchdog tests have it: interface 0 is wedged when first opened,
+    /// seeing nothing, and fine once reopened; any other is always fine. Fine is a SYN from
+    /// another port every `FEED_EVERY`.
+    fn wedged_until_reopened(live: LiveCaptures, idx: usize, _: Device, generation: u64) {
+        let liveness = &live.liveness[idx];
+        let opened = Instant::now();
+        let mut port = 40000;
+        // Left wedged, it gives up in time rather than outlive the test
+        while liveness.generation.load(Ordering::SeqCst) == generation && opened.elapsed() < QUIET * 10 {
+            if idx > 0 || generation > 0 {
+                let ingress = Ingress {
+                    data: crate::test_support::tcp_frame(&format!("10.0.{}.1:{}", idx, port), "10.0.0.2:443", crate::test_support::SYN),
+                    interface: idx,
+                    link: Linktype::ETHERNET,
+                    time: SystemTime::now(),
+                };
+                liveness.touch();
+                if live.endpoint.send(ingress).is_err() {
+                    return;
                 }
+                port += 1;
             }
+            thread::sleep(FEED_EVERY);
+        }
+    }
+
+    fn eth0_and_eth1_up() -> Vec<String> {
+        vec!["eth0".to_string(), "eth1".to_string()]
+    }
+
+    /// Take batches for `running`, watching for quiet captures between them as the client
+    /// does, and return the interfaces messages came from after `QUIET` and a bit, by which
+    /// time anything reopened has had time to flow.
+    fn watch(observer: &mut Observer, recovery: Recovery, running: Duration) -> Vec<usize> {
+        let started = Instant::now();
+        let mut interfaces = Vec::new();
+        while started.elapsed() < running {
+            observer.watch(QUIET, recovery);
+            if let Ok(Batch::Messages(messages)) = observer.next_batch_timeout(FEED_EVERY) {
+                if started.elapsed() > QUIET + FEED_EVERY * 5 {
+                    interfaces.extend(messages.iter().map(|message| message.state().connection.interface));
+                }
+            }
+        }
+        interfaces.sort();
+        interfaces.dedup();
+        interfaces
+    }
+
+    /// Each capture's stalls and restarts, in order.
+    fn counts(observer: &Observer) -> Vec<(u64, u64)> {
+        observer.liveness().iter().map(|live| (live.stalls.load(Ordering::Relaxed), live.restarts.load(Ordering::Relaxed))).collect()
+    }
+
+    #[test]
+    fn a_stalled_capture_is_reported_and_reopened() {
+        let mut observer = live_observer(&["eth0", "eth1"], wedged_until_reopened, eth0_and_eth1_up);
+        assert_eq!(watch(&mut observer, Recovery::Capture, QUIET * 3), [0, 1]);
+        // Only eth0 went quiet, and once reopened it stayed busy
+        assert_eq!(counts(&observer), [(1, 1), (0, 0)]);
+        assert!(observer.liveness()[0].quiet() < QUIET);
+
+        let mut out = Exposition::default();
+        Liveness::collect(&observer.liveness(), &mut out);
+        let text = out.into_text();
+        assert!(text.contains("glosco_observer_stalls_total{interface=\"eth0\"} 1\n"), "{}", text);
+        assert!(text.contains("glosco_observer_capture_restarts_total{interface=\"eth0\"} 1\n"), "{}", text);
+        assert!(text.contains("glosco_observer_stalls_total{interface=\"eth1\"} 0\n"), "{}", text);
+    }
+
+    #[test]
+    fn a_stalled_capture_is_only_reported_when_asked_to_log() {
+        let mut observer = live_observer(&["eth0", "eth1"], wedged_until_reopened, eth0_and_eth1_up);
+        assert_eq!(watch(&mut observer, Recovery::Log, QUIET * 3 / 2), [1]);
+        assert_eq!(counts(&observer), [(1, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn recovering_all_reopens_every_capture() {
+        let mut observer = live_observer(&["eth0", "eth1"], wedged_until_reopened, eth0_and_eth1_up);
+        assert_eq!(watch(&mut observer, Recovery::All, QUIET * 3), [0, 1]);
+        assert_eq!(counts(&observer), [(1, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn a_quiet_capture_on_an_interface_thats_down_isnt_a_stall() {
+        let mut observer = live_observer(&["eth0", "eth1"], wedged_until_reopened, || vec!["eth1".to_string()]);
+        assert_eq!(watch(&mut observer, Recovery::Capture, QUIET * 3), [1]);
+        assert_eq!(counts(&observer), [(0, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn capture_files_arent_watched() {
+        let (_sender, mut observer) = observer(&["trace.pcap"]);
+        observer.interfaces_up = || vec!["trace.pcap".to_string()];
+        observer.watch(Duration::ZERO, Recovery::All);
+        assert!(observer.liveness().is_empty());
+    }
+
+    /// `packet` as a wireless monitor interface captures it: radiotap, then an 802.11 data
+    /// frame to the AP with frame control flags `fc`, then LLC/SNAP.
+    fn radiotap(fc: u8, packet: &[u8]) -> Ingress {
+        let mut frame = vec![0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
+        frame.extend_from_slice(&[0x08, fc, 0x00, 0x00]);
+        frame.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x03]);
+        frame.extend_from_slice(&[0x10, 0x00]);
+        frame.extend_from_slice(&[0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00]);
+        frame.extend_from_slice(packet);
+        Ingress { data: frame, interface: 0, link: Linktype::IEEE802_11_RADIOTAP, time: at(100) }
+    }
+
+    #[test]
+    fn monitor_mode_frames_are_handled_like_any_other_packet() {
+        let (_sender, mut observer) = observer(&["wlan0mon"]);
+        let stats: Arc<[InterfaceStats]> = Arc::from([InterfaceStats::new("wlan0mon".to_string())]);
+        observer.stats = Some(stats.clone());
+
+        let syn = ipv4(CLIENT, SERVER, 6, &tcp(40000, 443, SYN));
+        let messages = observer.handle(radiotap(0x01, &syn));
+        let [Message::Starting(state)] = &messages[..] else {
+            panic!("{:?}", messages);
+        };
+        assert_eq!(state.connection.src, Endpoint { addr: CLIENT.into(), port: 40000 });
+        assert_eq!(state.connection.dst, Endpoint { addr: SERVER.into(), port: 443 });
+        assert_eq!(state.connection.protocol, Protocol::Tcp);
+
+        // Encrypted, there's nothing to see, which isn't a failure to parse
+        assert_eq!(observer.handle(radiotap(0x41, &ipv4(CLIENT, SERVER, 6, &tcp(40001, 443, SYN)))), []);
+        assert_eq!(stats[0].unparsed.load(Ordering::Relaxed), 0);
+        let mut truncated = radiotap(0x01, &syn);
+        truncated.data.truncate(20);
+        assert_eq!(observer.handle(truncated), []);
+        assert_eq!(stats[0].unparsed.load(Ordering::Relaxed), 1);
+    }
+
+    /// The switch mirroring traffic to the sensor, and the sensor.
+    const SWITCH: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 1);
+    const SENSOR: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 2);
+
+    /// `frame` mirrored in ERSPAN type II, session 42.
+    fn erspan_ii(frame: Vec<u8>) -> Vec<u8> {
+        let mut gre = vec![0x10, 0x00, 0x88, 0xbe, 0x00, 0x00, 0x00, 0x01];
+        gre.extend_from_slice(&[0x10, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00]);
+        gre.extend_from_slice(&frame);
+        ether(&ipv4(SWITCH, SENSOR, decap::GRE_PROTOCOL, &gre))
+    }
+
+    /// `frame` mirrored in ERSPAN type III, session 42.
+    fn erspan_iii(frame: Vec<u8>) -> Vec<u8> {
+        let mut gre = vec![0x10, 0x00, 0x22, 0xeb, 0x00, 0x00, 0x00, 0x01];
+        gre.extend_from_slice(&[0x20, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        gre.extend_from_slice(&frame);
+        ether(&ipv4(SWITCH, SENSOR, decap::GRE_PROTOCOL, &gre))
+    }
+
+    /// `frame` mirrored in VXLAN, VNI 42.
+    fn vxlan(frame: Vec<u8>) -> Vec<u8> {
+        let mut payload = vec![0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x00];
+        payload.extend_from_slice(&frame);
+        ether(&ipv4(SWITCH, SENSOR, 17, &udp(50000, decap::VXLAN_PORT, &payload)))
+    }
+
+    #[test]
+    fn mirrored_handshakes_are_the_conversation_not_the_tunnel() {
+        let (client, server) = ((CLIENT, 40000), (SERVER, 443));
+        let outbound = connection(client, server, Protocol::Tcp);
+        let inbound = connection(server, client, Protocol::Tcp);
+        for (what, wrap) in [("ERSPAN II", erspan_ii as fn(Vec<u8>) -> Vec<u8>), ("ERSPAN III", erspan_iii), ("VXLAN", vxlan)] {
+            let (_sender, mut observer) = observer(&["mirror0"]);
+            let mut mirrored = |secs: u64, src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16), flags: u8| {
+                let frame = ether(&ipv4(src.0, dst.0, 6, &tcp(src.1, dst.1, flags)));
+                observer.handle(Ingress { data: wrap(frame), interface: 0, link: Linktype::ETHERNET, time: at(secs) })
+            };
+            assert_eq!(mirrored(100, client, server, SYN), [Message::Starting(state(100, outbound))], "{}", what);
+            assert_eq!(mirrored(101, server, client, SYN | ACK), [Message::Active(state(101, inbound))], "{}", what);
+            assert_eq!(mirrored(101, client, server, ACK), [Message::Active(state(101, outbound))], "{}", what);
+            assert_eq!(mirrored(110, client, server, FIN | ACK), [Message::Ended(state(110, outbound), Closed::Normally)], "{}", what);
         }
     }
+
+    #[test]
+    fn mirrors_are_unwrapped_only_so_deep() {
+        let (_sender, mut observer) = observer(&["mirror0"]);
+        let stats: Arc<[InterfaceStats]> = Arc::from([InterfaceStats::new("mirror0".to_string())]);
+        observer.stats = Some(stats.clone());
+        let syn = |port| ether(&ipv4(CLIENT, SERVER, 6, &tcp(port, 443, SYN)));
+        let mirrored = |data| Ingress { data, interface: 0, link: Linktype::ETHERNET, time: at(100) };
+
+        let twice = observer.handle(mirrored(vxlan(erspan_ii(syn(40000)))));
+        assert!(matches!(&twice[..], [Message::Starting(state)] if state.connection.src.port == 40000), "{:?}", twice);
+        assert_eq!(observer.handle(mirrored(vxlan(erspan_ii(vxlan(syn(40001)))))), []);
+        assert_eq!(stats[0].unparsed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn gre_that_isnt_erspan_is_no_connection_and_no_failure() {
+        let (_sender, mut observer) = observer(&["eth0"]);
+        let stats: Arc<[InterfaceStats]> = Arc::from([InterfaceStats::new("eth0".to_string())]);
+        observer.stats = Some(stats.clone());
+        let gre = [0x00, 0x00, 0x08, 0x00, 0x45, 0x00];
+        assert_eq!(observer.handle(captured(100, ipv4(SWITCH, SENSOR, decap::GRE_PROTOCOL, &gre))), []);
+        // VXLAN without its flag doesn't parse
+        assert_eq!(observer.handle(captured(100, ipv4(SWITCH, SENSOR, 17, &udp(50000, decap::VXLAN_PORT, &[0; 8])))), []);
+        assert_eq!(stats[0].unparsed.load(Ordering::Relaxed), 1);
+    }
+
+    /// An interface address with `netmask`, if any, and nothing else.
+    fn address(addr: &str, netmask: Option<&str>) -> pcap::Address {
+        pcap::Address { addr: addr.parse().unwrap(), netmask: netmask.map(|mask| mask.parse().unwrap()), broadcast_addr: None, dst_addr: None }
+    }
+
+    #[test]
+    fn an_interface_is_described_with_its_flags_and_prefix_lengths() {
+        let eth0 = Device {
+            name: "eth0".to_string(),
+            desc: Some("Ethernet".to_string()),
+            addresses: vec![address("10.0.0.5", Some("255.255.255.0")), address("fe80::1", Some("ffff:ffff:ffff:ffff::")), address("10.0.0.6", None)],
+            flags: pcap::DeviceFlags { if_flags: pcap::IfFlags::UP | pcap::IfFlags::RUNNING, connection_status: pcap::ConnectionStatus::Connected },
+        };
+        assert_eq!(describe_interface(&eth0), "eth0 (Ethernet) [up, running] 10.0.0.5/24 fe80::1/64 10.0.0.6");
+        let lo = Device {
+            name: "lo".to_string(),
+            desc: None,
+            addresses: vec![address("127.0.0.1", Some("255.0.0.0"))],
+            flags: pcap::DeviceFlags { if_flags: pcap::IfFlags::UP | pcap::IfFlags::RUNNING | pcap::IfFlags::LOOPBACK, connection_status: pcap::ConnectionStatus::NotApplicable },
+        };
+        assert_eq!(describe_interface(&lo), "lo [up, running, loopback] 127.0.0.1/8");
+        let wlan0 = Device {
+            name: "wlan0".to_string(),
+            desc: None,
+            addresses: Vec::new(),
+            flags: pcap::DeviceFlags { if_flags: pcap::IfFlags::WIRELESS, connection_status: pcap::ConnectionStatus::Disconnected },
+        };
+        assert_eq!(describe_interface(&wlan0), "wlan0 [wireless]");
+    }
+
+    #[test]
+    fn an_unknown_interface_is_caught_with_the_names_it_could_be_a_typo_of() {
+        let known: Vec<String> = ["eth0", "eth1", "enp2s0", "enp3s0", "wlan0", "lo"].map(String::from).to_vec();
+        assert_eq!(check_interfaces(&["eth0", "lo"], &known), Ok(()));
+        assert_eq!(check_interfaces::<&str>(&[], &known), Ok(()));
+
+        let unknown = |names: &[&str]| check_interfaces(names, &known).unwrap_err();
+        // The first that isn't known, after those that are
+        let swapped = unknown(&["lo", "eht0", "eht1"]);
+        assert_eq!(swapped, UnknownInterface { name: "eht0".to_string(), suggestions: vec!["eth0".to_string()] });
+        assert_eq!(swapped.to_string(), "no interface named \"eht0\"; did you mean eth0?");
+        // Equally close ones by name
+        let between = unknown(&["eth2"]);
+        assert_eq!(between.to_string(), "no interface named \"eth2\"; did you mean eth0 or eth1?");
+        assert_eq!(unknown(&["ETH0"]).suggestions, ["eth0"]);
+        assert_eq!(unknown(&["WLAN0"]).suggestions, ["wlan0"]);
+        // Longer names allow more edits, closest first
+        assert_eq!(unknown(&["enp3s0f"]).suggestions, ["enp3s0", "enp2s0"]);
+        // But a short one only so many
+        assert_eq!(unknown(&["l0"]).suggestions, ["lo"]);
+        assert_eq!(unknown(&["ab"]).suggestions, Vec::<String>::new());
+        let far = unknown(&["docker0"]);
+        assert_eq!(far.suggestions, Vec::<String>::new());
+        assert_eq!(far.to_string(), "no interface named \"docker0\"");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn an_interfaces_link_attributes_are_read_from_its_directory() {
+        let root = crate::test_support::Scratch::dir("sys-class-net");
+        let eth0 = root.path().join("eth0");
+        std::fs::create_dir(&eth0).unwrap();
+        std::fs::write(eth0.join("address"), "52:54:00:12:34:56\n").unwrap();
+        std::fs::write(eth0.join("mtu"), "9000\n").unwrap();
+        // A tunnel has no link-layer address to speak of
+        let tun0 = root.path().join("tun0");
+        std::fs::create_dir(&tun0).unwrap();
+        std::fs::write(tun0.join("address"), "\n").unwrap();
+        std::fs::write(tun0.join("mtu"), "1420\n").unwrap();
+
+        assert_eq!(link_attributes(root.path(), "eth0"), (Some("52:54:00:12:34:56".to_string()), Some(9000)));
+        assert_eq!(link_attributes(root.path(), "tun0"), (None, Some(1420)));
+        assert_eq!(link_attributes(root.path(), "wlan0"), (None, None));
+        // Capture files, by whatever path they were given
+        assert_eq!(link_attributes(root.path(), "capture.pcap"), (None, None));
+        assert_eq!(link_attributes(root.path(), "../eth0"), (None, None));
+        assert_eq!(link_attributes(root.path(), "./eth0"), (None, None));
+    }
 }
@@ -0,0 +1,210 @@
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::{alert::Kind, observe::{Message, Protocol, Snapshot, State}, timefmt};
+
+/// How the sensor prints what it observes to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// Each message as Rust's debug formatting shows it.
+    #[default]
+    Debug,
+    /// Each message as a line of JSON, for piping into other tools.
+    Json,
+    /// Each message as a one-line summary: time, kind, protocol, source and destination.
+    Compact,
+    /// Nothing per message.
+    None,
+}
+
+impl Output {
+    pub fn format(self) -> Box<dyn Format + Send> {
+        match self {
+            Self::Debug => Box::new(DebugFormat),
+            Self::Json => Box::new(JsonFormat),
+            Self::Compact => Box::new(CompactFormat),
+            Self::None => Box::new(NoFormat),
+        }
+    }
+}
+
+/// Turns what the sensor observes into lines to print; `None` prints nothing. A format can
+/// also pass what it's given on elsewhere, as the TUI does.
+pub trait Format {
+    fn message(&self, message: &Message) -> Option<String>;
+
+    fn snapshot(&self, _snapshot: &Snapshot) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DebugFormat;
+
+impl Format for DebugFormat {
+    fn message(&self, message: &Message) -> Option<String> {
+        Some(format!("{:?}", message))
+    }
+
+    fn snapshot(&self, snapshot: &Snapshot) -> Option<String> {
+        Some(format!("Snapshot of {} open connections", snapshot.states.len()))
+    }
+}
+
+/// One JSON object per message; snapshots are left out so every line is a message.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+impl Format for JsonFormat {
+    fn message(&self, message: &Message) -> Option<String> {
+        Some(serde_json::to_string(message).expect("failed to encode message"))
+    }
+}
+
+/// Lines like `2025-06-12T14:12:14.220000Z starting tcp 10.0.0.5:40000 -> 10.0.0.1:443`, the
+/// way `glosco tail --text` prints them less the ident.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactFormat;
+
+impl Format for CompactFormat {
+    fn message(&self, message: &Message) -> Option<String> {
+        let state = message.state();
+        let conn = state.connection;
+        let time = state.as_of.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| timefmt::rfc3339(since.as_secs_f64()))
+            .unwrap_or_default();
+        let proto = match conn.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        };
+        let mut line = format!("{} {} {} {} -> {}", time, Kind::of(message), proto, conn.src, conn.dst);
+        match message {
+            Message::Failed(_, problem) => {
+                line.push_str(&format!(" icmp {}/{}", problem.kind, problem.code));
+                if problem.repeats > 0 {
+                    line.push_str(&format!(" +{} repeats", problem.repeats));
+                }
+            },
+            Message::Name(_, names) => for name in names.iter() {
+                line.push(' ');
+                line.push_str(&name.name);
+            },
+            Message::Scan(_, scan) => line.push_str(&format!(" {} {} in {}s", scan.count, scan.kind.name(), scan.window.as_secs_f64())),
+            Message::Active(State { rtt_micros: Some(rtt), .. }) => line.push_str(&format!(" rtt {:.3}ms", *rtt as f64 / 1000.0)),
+            _ => (),
+        }
+        Some(line)
+    }
+
+    fn snapshot(&self, snapshot: &Snapshot) -> Option<String> {
+        Some(format!("snapshot of {} open connections", snapshot.states.len()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFormat;
+
+impl Format for NoFormat {
+    fn message(&self, _message: &Message) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::{IpAddr, Ipv4Addr}, time::Duration};
+
+    use crate::{observe::{Closed, Connection, Endpoint, Name, Problem, Resolution}, scan::{Scan, ScanKind}};
+
+    use super::*;
+
+    fn state(rtt_micros: Option<u32>) -> State {
+        State {
+            as_of: SystemTime::UNIX_EPOCH + Duration::from_millis(1_749_737_534_220),
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5)), port: 40000 },
+                dst: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port: 443 },
+                protocol: Protocol::Tcp,
+            },
+            rtt_micros,
+        }
+    }
+
+    fn fixtures() -> Vec<Message> {
+        vec![
+            Message::Starting(state(None)),
+            Message::Active(state(Some(12_345))),
+            Message::Ended(state(None), Closed::Reset),
+            Message::Failed(state(None), Problem { kind: 3, code: 1, repeats: 4 }),
+            Message::Name(state(None), vec![Name { name: "example.com".to_string(), address: Some(Resolution::Address(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)))) }]),
+            Message::Scan(state(None), Scan { kind: ScanKind::Ports, count: 40, window: Duration::from_millis(1500) }),
+        ]
+    }
+
+    fn lines(output: Output) -> Vec<Option<String>> {
+        let format = output.format();
+        fixtures().iter().map(|message| format.message(message)).collect()
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot { as_of: SystemTime::UNIX_EPOCH, states: vec![Message::Starting(state(None)), Message::Active(state(None))] }
+    }
+
+    #[test]
+    fn json_is_one_object_per_message() {
+        // Left open, as an Active's round trip goes inside it
+        const STATE: &str = r#"{"as_of":{"secs_since_epoch":1749737534,"nanos_since_epoch":220000000},"connection":{"interface":0,"src":{"addr":"10.0.0.5","port":40000},"dst":{"addr":"10.0.0.1","port":443},"protocol":"Tcp"}"#;
+        let expected = [
+            format!(r#"{{"Starting":{}}}}}"#, STATE),
+            format!(r#"{{"Active":{},"rtt_micros":12345}}}}"#, STATE),
+            format!(r#"{{"Ended":[{}}},"Reset"]}}"#, STATE),
+            format!(r#"{{"Failed":[{}}},{{"kind":3,"code":1,"repeats":4}}]}}"#, STATE),
+            format!(r#"{{"Name":[{}}},[{{"name":"example.com","address":{{"Address":"93.184.216.34"}}}}]]}}"#, STATE),
+            format!(r#"{{"Scan":[{}}},{{"kind":"Ports","count":40,"window":{{"secs":1,"nanos":500000000}}}}]}}"#, STATE),
+        ];
+        assert_eq!(lines(Output::Json), expected.clone().map(Some));
+        // Each line reads back as the message it was
+        for (line, message) in expected.iter().zip(fixtures()) {
+            assert_eq!(serde_json::from_str::<Message>(line).unwrap(), message);
+        }
+        assert_eq!(JsonFormat.snapshot(&snapshot()), None);
+    }
+
+    #[test]
+    fn compact_is_a_summary_per_message() {
+        assert_eq!(lines(Output::Compact), [
+            "2025-06-12T14:12:14.220000Z starting tcp 10.0.0.5:40000 -> 10.0.0.1:443",
+            "2025-06-12T14:12:14.220000Z active tcp 10.0.0.5:40000 -> 10.0.0.1:443 rtt 12.345ms",
+            "2025-06-12T14:12:14.220000Z reset tcp 10.0.0.5:40000 -> 10.0.0.1:443",
+            "2025-06-12T14:12:14.220000Z failed tcp 10.0.0.5:40000 -> 10.0.0.1:443 icmp 3/1 +4 repeats",
+            "2025-06-12T14:12:14.220000Z name tcp 10.0.0.5:40000 -> 10.0.0.1:443 example.com",
+            "2025-06-12T14:12:14.220000Z scan tcp 10.0.0.5:40000 -> 10.0.0.1:443 40 ports in 1.5s",
+        ].map(|line| Some(line.to_string())));
+        // Without a round trip or repeats, nothing's said of them
+        assert_eq!(CompactFormat.message(&Message::Active(state(None))).unwrap(), "2025-06-12T14:12:14.220000Z active tcp 10.0.0.5:40000 -> 10.0.0.1:443");
+        assert_eq!(
+            CompactFormat.message(&Message::Failed(state(None), Problem { kind: 3, code: 1, repeats: 0 })).unwrap(),
+            "2025-06-12T14:12:14.220000Z failed tcp 10.0.0.5:40000 -> 10.0.0.1:443 icmp 3/1",
+        );
+        assert_eq!(CompactFormat.snapshot(&snapshot()).unwrap(), "snapshot of 2 open connections");
+    }
+
+    #[test]
+    fn none_prints_nothing() {
+        assert!(lines(Output::None).iter().all(Option::is_none));
+        assert_eq!(NoFormat.snapshot(&snapshot()), None);
+    }
+
+    #[test]
+    fn debug_is_as_rust_shows_it() {
+        let debug = lines(Output::Debug);
+        for (line, message) in debug.iter().zip(fixtures()) {
+            assert_eq!(line.as_deref(), Some(&*format!("{:?}", message)));
+        }
+        assert_eq!(debug[0].as_deref(), Some("Starting(State { as_of: SystemTime { tv_sec: 1749737534, tv_nsec: 220000000 }, connection: Connection { interface: 0, src: Endpoint { addr: 10.0.0.5, port: 40000 }, dst: Endpoint { addr: 10.0.0.1, port: 443 }, protocol: Tcp }, rtt_micros: None })"));
+        assert_eq!(DebugFormat.snapshot(&snapshot()).unwrap(), "Snapshot of 2 open connections");
+    }
+}
@@ -0,0 +1,534 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use rusqlite::{params, OptionalExtension};
+
+use crate::timefmt;
+
+/// Columns of every state table, in order; day tables are created with exactly these.
+pub(crate) const COLUMNS: &str = "instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode, \
+    last_seen, dstcountry, dstasn, reported_conntime, repeats, opened_at, rtt_micros";
+
+/// Triggers keeping `latest_state` up to date with a state table, `{state}`: every insert that's
+/// at least as recent as what's there replaces it, keepalives refreshing `last_seen` in place
+/// are followed, and deleting the latest row forgets the connection, which holds as long as
+/// rows are deleted oldest first. Dropping a whole day table fires none of these; `drop_day`
+/// does the forgetting then.
+pub(crate) const LATEST_TRIGGERS: &str = "
+    CREATE TRIGGER IF NOT EXISTS {state}_latest_insert AFTER INSERT ON {state} BEGIN
+        INSERT INTO latest_state
+        (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+        last_seen, dstcountry, dstasn, reported_conntime)
+        VALUES (NEW.instime, NEW.conntime, NEW.ident, NEW.peer, NEW.srchost, NEW.srcport, NEW.dsthost, NEW.dstport,
+        NEW.proto, NEW.state, NEW.close, NEW.pkind, NEW.pcode, NEW.last_seen, NEW.dstcountry, NEW.dstasn,
+        NEW.reported_conntime)
+        ON CONFLICT (ident, srchost, srcport, dsthost, dstport, proto) DO UPDATE SET
+            instime = excluded.instime, conntime = excluded.conntime, peer = excluded.peer,
+            state = excluded.state, close = excluded.close, pkind = excluded.pkind, pcode = excluded.pcode,
+            last_seen = excluded.last_seen, dstcountry = excluded.dstcountry, dstasn = excluded.dstasn,
+            reported_conntime = excluded.reported_conntime
+        WHERE excluded.instime >= latest_state.instime;
+    END;
+    CREATE TRIGGER IF NOT EXISTS {state}_latest_refresh AFTER UPDATE OF last_seen ON {state} BEGIN
+        UPDATE latest_state SET last_seen = NEW.last_seen
+        WHERE ident = NEW.ident AND srchost = NEW.srchost AND srcport = NEW.srcport AND dsthost = NEW.dsthost
+            AND dstport = NEW.dstport AND proto = NEW.proto AND instime = NEW.instime;
+    END;
+    CREATE TRIGGER IF NOT EXISTS {state}_latest_delete AFTER DELETE ON {state} BEGIN
+        DELETE FROM latest_state
+        WHERE ident = OLD.ident AND srchost = OLD.srchost AND srcport = OLD.srcport AND dsthost = OLD.dsthost
+            AND dstport = OLD.dstport AND proto = OLD.proto AND instime = OLD.instime;
+    END;
+";
+
+const DAY: f64 = 24.0 * 3600.0;
+
+/// The UTC day `time` (seconds since the epoch) falls in, as days since the epoch.
+pub fn day_of(time: f64) -> i64 {
+    (time / DAY).floor() as i64
+}
+
+/// The table holding state rows stored on `day`, like `state_20250612`.
+pub fn table_name(day: i64) -> String {
+    let (y, m, d) = timefmt::civil(day);
+    format!("state_{:04}{:02}{:02}", y, m, d)
+}
+
+/// Whether `name` is a table state rows are stored in: `state`, or a day table.
+pub fn is_state_table(name: &str) -> bool {
+    name == "state" || name.strip_prefix("state_").is_some_and(|day| day.len() == 8 && day.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Whether state rows live in day tables rather than the single `state` table.
+pub fn is_partitioned(db: &rusqlite::Connection) -> rusqlite::Result<bool> {
+    let monolithic: Option<i64> = db.query_row("
+        SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'state';
+    ", [], |row| row.get(0)).optional()?;
+    Ok(monolithic.is_none())
+}
+
+/// Every day table, oldest first.
+pub fn tables(db: &rusqlite::Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare("
+        SELECT name FROM sqlite_master
+        WHERE type = 'table' AND name GLOB 'state_[0-9][0-9][0-9][0-9][0-9][0-9][0-9][0-9]'
+        ORDER BY name;
+    ")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Create a day table with the indexes the monolithic table has, unless it exists already.
+/// Returns whether it was created.
+///
+/// Uniqueness is only enforced within a day, so a message replayed on a later day than it was
+/// first stored is stored again.
+fn create(db: &rusqlite::Connection, table: &str) -> rusqlite::Result<bool> {
+    let exists: Option<i64> = db.query_row("
+        SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?;
+    ", params![table], |row| row.get(0)).optional()?;
+    if exists.is_some() {
+        return Ok(false);
+    }
+    db.execute_batch(&format!("
+        CREATE TABLE IF NOT EXISTS {t} ({columns});
+        CREATE INDEX IF NOT EXISTS {t}_instime ON {t} (instime);
+        CREATE INDEX IF NOT EXISTS {t}_conntime ON {t} (conntime);
+        CREATE INDEX IF NOT EXISTS {t}_ident ON {t} (ident);
+        CREATE INDEX IF NOT EXISTS {t}_src ON {t} (srchost, srcport);
+        CREATE INDEX IF NOT EXISTS {t}_dst ON {t} (dsthost, dstport);
+        CREATE INDEX IF NOT EXISTS {t}_latest ON {t} (ident, srchost, srcport, dsthost, dstport, proto);
+        CREATE UNIQUE INDEX IF NOT EXISTS {t}_unique ON {t}
+        (ident, srchost, srcport, dsthost, dstport, proto, conntime, state, coalesce(close, 0), coalesce(pkind, -1), coalesce(pcode, -1));
+    ", t = table, columns = COLUMNS))?;
+    db.execute_batch(&LATEST_TRIGGERS.replace("{state}", table))?;
+    Ok(true)
+}
+
+/// Drop a day table, the oldest there is, and forget the connections last heard of in it.
+fn drop_day(db: &rusqlite::Connection, table: &str) -> rusqlite::Result<()> {
+    db.execute_batch(&format!("
+        DELETE FROM latest_state WHERE instime <= (SELECT max(instime) FROM {t});
+        DROP TABLE {t};
+    ", t = table))
+}
+
+/// Point `state_all` at exactly the day tables that exist, with whatever columns they have;
+/// migrations that add one rebuild it after adding it to every table.
+pub(crate) fn rebuild_view(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let union = tables(db)?.iter()
+        .map(|table| format!("SELECT * FROM {}", table))
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+    db.execute_batch(&format!("
+        DROP VIEW IF EXISTS state_all;
+        CREATE VIEW state_all AS {};
+    ", union))
+}
+
+/// Split the monolithic state table into day tables by `instime`, dropping it afterwards. It
+/// all happens in one transaction, so an interrupted conversion leaves the database as it was.
+/// Returns how many days of rows there were.
+fn convert(db: &mut rusqlite::Connection, today: i64) -> rusqlite::Result<usize> {
+    let txn = db.transaction()?;
+    let days: Vec<i64> = {
+        let mut stmt = txn.prepare("
+            SELECT DISTINCT CAST(instime / 86400 AS INTEGER) FROM state WHERE instime IS NOT NULL ORDER BY 1;
+        ")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    for day in days.iter().copied().chain(std::iter::once(today)) {
+        create(&txn, &table_name(day))?;
+        txn.execute(&format!("
+            INSERT OR IGNORE INTO {t} ({columns})
+            SELECT {columns} FROM state WHERE instime >= ? AND instime < ?;
+        ", t = table_name(day), columns = COLUMNS), params![day as f64 * DAY, (day + 1) as f64 * DAY])?;
+    }
+    txn.execute_batch("DROP TABLE state;")?;
+    rebuild_view(&txn)?;
+    txn.commit()?;
+    Ok(days.len())
+}
+
+/// Which table state rows go into, and how old ones are expired.
+///
+/// Partitioned databases keep each UTC day's rows in a table of its own, created when the first
+/// row of the day is stored; `state_all` is a view over whichever exist, and expiring a day is a
+/// `DROP TABLE` rather than a `DELETE` of every row in it. Monolithic databases keep everything
+/// in `state` (and `state_all` is just that).
+#[derive(Debug, Default)]
+pub struct Partitions {
+    partitioned: bool,
+    /// Day tables known to exist already, so the common case doesn't touch sqlite_master.
+    known: Mutex<HashSet<i64>>,
+}
+
+impl Partitions {
+    /// Work out how `db` is laid out, converting a monolithic database if `partition` asks for
+    /// it. There's no converting back: a partitioned database stays partitioned.
+    pub fn open(db: &mut rusqlite::Connection, partition: bool, now: f64) -> rusqlite::Result<Self> {
+        let partitioned = is_partitioned(db)?;
+        if partition && !partitioned {
+            println!("partitioning state by day, this may take a while...");
+            let days = convert(db, day_of(now))?;
+            println!("moved {} days of state into day tables", days);
+        } else if !partition && partitioned {
+            println!("database is already partitioned by day, keeping it that way");
+        }
+        Ok(Self {
+            partitioned: partition || partitioned,
+            known: Mutex::default(),
+        })
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.partitioned
+    }
+
+    /// The table to store rows at `time` in, creating it (and repointing `state_all`) if this is
+    /// the first row of its day.
+    pub fn table(&self, db: &rusqlite::Connection, time: f64) -> rusqlite::Result<String> {
+        if !self.partitioned {
+            return Ok("state".to_string());
+        }
+        let day = day_of(time);
+        let table = table_name(day);
+        if self.known.lock().unwrap().contains(&day) {
+            return Ok(table);
+        }
+        if create(db, &table)? {
+            rebuild_view(db)?;
+            println!("started day table {}", table);
+        }
+        self.known.lock().unwrap().insert(day);
+        Ok(table)
+    }
+
+    /// The table a row stored at `time` would already be in, without creating anything.
+    pub fn existing(&self, time: f64) -> String {
+        if self.partitioned {
+            table_name(day_of(time))
+        } else {
+            "state".to_string()
+        }
+    }
+
+    /// Get rid of state rows stored before `cutoff`, returning how many rows (monolithic) or
+    /// tables (partitioned) went. Partitioned, only whole days go, so up to a day more is kept.
+    pub fn expire(&self, db: &mut rusqlite::Connection, cutoff: f64) -> rusqlite::Result<usize> {
+        if !self.partitioned {
+            return db.execute("DELETE FROM state WHERE instime < ?;", params![cutoff]);
+        }
+        let keep = table_name(day_of(cutoff));
+        let txn = db.transaction()?;
+        let expired: Vec<String> = tables(&txn)?.into_iter().filter(|table| *table < keep).collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        for table in expired.iter() {
+            drop_day(&txn, table)?;
+        }
+        // The view needs at least one table behind it
+        create(&txn, &keep)?;
+        rebuild_view(&txn)?;
+        txn.commit()?;
+        self.known.lock().unwrap().clear();
+        Ok(expired.len())
+    }
+
+    /// Get rid of the oldest state: the oldest day table if there's more than one, otherwise
+    /// the oldest `chunk` rows. Names older than whatever state is left go too. Returns what was
+    /// evicted, or `None` if there's no state left to evict.
+    pub fn evict_oldest(&self, db: &mut rusqlite::Connection, chunk: usize) -> rusqlite::Result<Option<String>> {
+        let txn = db.transaction()?;
+        let tables = if self.partitioned { tables(&txn)? } else { Vec::new() };
+        let evicted = if tables.len() > 1 {
+            drop_day(&txn, &tables[0])?;
+            rebuild_view(&txn)?;
+            format!("day table {}", tables[0])
+        } else {
+            let table = tables.first().map(String::as_str).unwrap_or("state");
+            let rows = txn.execute(&format!("
+                DELETE FROM {t} WHERE rowid IN (SELECT rowid FROM {t} ORDER BY instime LIMIT ?);
+            ", t = table), params![chunk as i64])?;
+            if rows == 0 {
+                return Ok(None);
+            }
+            format!("{} rows from {}", rows, table)
+        };
+        let names = txn.execute("
+            DELETE FROM names WHERE instime < (SELECT min(instime) FROM state_all);
+        ", [])?;
+        txn.commit()?;
+        if self.partitioned {
+            self.known.lock().unwrap().clear();
+        }
+        Ok(Some(format!("{} and {} names", evicted, names)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::Random;
+
+    use super::*;
+
+    /// Midnight UTC starting 2025-06-12.
+    const MIDNIGHT: f64 = 1_749_686_400.0;
+
+    /// A fully migrated database of the test's own, partitioned by day.
+    fn partitioned() -> (rusqlite::Connection, Partitions) {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        crate::server::migrate(&mut db);
+        let partitions = Partitions::open(&mut db, true, MIDNIGHT - 3600.0).unwrap();
+        (db, partitions)
+    }
+
+    /// Store a Starting row from `srcport`, at `instime`, wherever `partitions` puts it.
+    fn store(db: &rusqlite::Connection, partitions: &Partitions, srcport: u16, instime: f64) {
+        let table = partitions.table(db, instime).unwrap();
+        db.execute(&format!("
+            INSERT INTO {} (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, last_seen)
+            VALUES (?1, ?1, 'sensor', '127.0.0.1:40000', '10.0.0.1', ?2, '10.0.0.2', 443, 6, 5, ?1);
+        ", table), params![instime, srcport]).unwrap();
+    }
+
+    fn rows(db: &rusqlite::Connection, table: &str) -> Vec<(u16, f64)> {
+        db.prepare(&format!("SELECT srcport, instime FROM {} ORDER BY instime, srcport", table)).unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    /// Rows either side of midnight: port 1 only before, port 2 after, port 3 both.
+    fn across_midnight() -> (rusqlite::Connection, Partitions) {
+        let (db, partitions) = partitioned();
+        store(&db, &partitions, 1, MIDNIGHT - 1.0);
+        store(&db, &partitions, 3, MIDNIGHT - 0.5);
+        store(&db, &partitions, 2, MIDNIGHT);
+        store(&db, &partitions, 3, MIDNIGHT + 1.0);
+        (db, partitions)
+    }
+
+    #[test]
+    fn day_tables_are_named_for_utc_days() {
+        assert_eq!(day_of(MIDNIGHT - 0.001), day_of(MIDNIGHT) - 1);
+        assert_eq!(table_name(day_of(MIDNIGHT - 0.001)), "state_20250611");
+        assert_eq!(table_name(day_of(MIDNIGHT)), "state_20250612");
+        assert_eq!(table_name(day_of(MIDNIGHT + 86399.9)), "state_20250612");
+    }
+
+    #[test]
+    fn rows_land_in_the_day_they_were_stored_on() {
+        let (db, _partitions) = across_midnight();
+        assert_eq!(tables(&db).unwrap(), ["state_20250611", "state_20250612"]);
+        assert_eq!(rows(&db, "state_20250611"), [(1, MIDNIGHT - 1.0), (3, MIDNIGHT - 0.5)]);
+        assert_eq!(rows(&db, "state_20250612"), [(2, MIDNIGHT), (3, MIDNIGHT + 1.0)]);
+        // The view sees both days, and the latest row of each connection whichever day it's in
+        assert_eq!(rows(&db, "state_all").len(), 4);
+        assert_eq!(rows(&db, "latest_state"), [(1, MIDNIGHT - 1.0), (2, MIDNIGHT), (3, MIDNIGHT + 1.0)]);
+    }
+
+    #[test]
+    fn dropping_a_day_forgets_only_what_was_last_heard_of_then() {
+        let (db, _partitions) = across_midnight();
+        drop_day(&db, "state_20250611").unwrap();
+        rebuild_view(&db).unwrap();
+        assert_eq!(tables(&db).unwrap(), ["state_20250612"]);
+        assert_eq!(rows(&db, "state_all"), [(2, MIDNIGHT), (3, MIDNIGHT + 1.0)]);
+        assert_eq!(rows(&db, "latest_state"), [(2, MIDNIGHT), (3, MIDNIGHT + 1.0)]);
+    }
+
+    #[test]
+    fn expiring_goes_by_whole_days() {
+        let (mut db, partitions) = across_midnight();
+        // A cutoff before midnight leaves that day, cut short or not
+        assert_eq!(partitions.expire(&mut db, MIDNIGHT - 0.75).unwrap(), 0);
+        assert_eq!(rows(&db, "state_all").len(), 4);
+
+        // Just after, and the day before goes whole, rows after the cutoff or not
+        assert_eq!(partitions.expire(&mut db, MIDNIGHT + 0.5).unwrap(), 1);
+        assert_eq!(tables(&db).unwrap(), ["state_20250612"]);
+        assert_eq!(rows(&db, "state_all"), [(2, MIDNIGHT), (3, MIDNIGHT + 1.0)]);
+        assert_eq!(rows(&db, "latest_state"), [(2, MIDNIGHT), (3, MIDNIGHT + 1.0)]);
+
+        // The day table's made again once a row for it turns up, late as it is
+        store(&db, &partitions, 4, MIDNIGHT - 10.0);
+        assert_eq!(tables(&db).unwrap(), ["state_20250611", "state_20250612"]);
+        assert_eq!(rows(&db, "state_all").len(), 3);
+    }
+
+    #[test]
+    fn expiring_everything_leaves_a_table_behind_the_view() {
+        let (mut db, partitions) = across_midnight();
+        assert_eq!(partitions.expire(&mut db, MIDNIGHT + 2.0 * DAY).unwrap(), 2);
+        assert_eq!(tables(&db).unwrap(), ["state_20250614"]);
+        assert!(rows(&db, "state_all").is_empty());
+        assert!(rows(&db, "latest_state").is_empty());
+        store(&db, &partitions, 5, MIDNIGHT + 2.0 * DAY);
+        assert_eq!(rows(&db, "state_all"), [(5, MIDNIGHT + 2.0 * DAY)]);
+    }
+
+    fn names(db: &rusqlite::Connection) -> Vec<f64> {
+        db.prepare("SELECT instime FROM names ORDER BY instime").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn evicting_drops_the_oldest_day_then_the_oldest_rows_of_the_last() {
+        let (mut db, partitions) = across_midnight();
+        store(&db, &partitions, 4, MIDNIGHT + DAY);
+        store(&db, &partitions, 5, MIDNIGHT + DAY + 1.0);
+        for instime in [MIDNIGHT - 2.0, MIDNIGHT + 0.5, MIDNIGHT + DAY + 0.5] {
+            db.execute("INSERT INTO names (instime, name, addr) VALUES (?, 'example.com', '10.0.0.2');", params![instime]).unwrap();
+        }
+
+        // Whole days while there's more than one, oldest first, with names older than what's left
+        assert_eq!(partitions.evict_oldest(&mut db, 1).unwrap().unwrap(), "day table state_20250611 and 1 names");
+        assert_eq!(tables(&db).unwrap(), ["state_20250612", "state_20250613"]);
+        assert_eq!(names(&db), [MIDNIGHT + 0.5, MIDNIGHT + DAY + 0.5]);
+        assert_eq!(partitions.evict_oldest(&mut db, 1).unwrap().unwrap(), "day table state_20250612 and 1 names");
+        assert_eq!(rows(&db, "state_all"), [(4, MIDNIGHT + DAY), (5, MIDNIGHT + DAY + 1.0)]);
+        assert_eq!(rows(&db, "latest_state"), [(4, MIDNIGHT + DAY), (5, MIDNIGHT + DAY + 1.0)]);
+
+        // Then the last day a chunk at a time, so the newest rows are the last to go
+        assert_eq!(partitions.evict_oldest(&mut db, 1).unwrap().unwrap(), "1 rows from state_20250613 and 1 names");
+        assert_eq!(rows(&db, "state_all"), [(5, MIDNIGHT + DAY + 1.0)]);
+        assert!(names(&db).is_empty());
+        assert_eq!(partitions.evict_oldest(&mut db, 1).unwrap().unwrap(), "1 rows from state_20250613 and 0 names");
+        assert_eq!(partitions.evict_oldest(&mut db, 1).unwrap(), None);
+        assert_eq!(tables(&db).unwrap(), ["state_20250613"]);
+    }
+
+    /// Columns `latest_state` keeps of each connection's latest row.
+    const LATEST: &str = "instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, \
+        pkind, pcode, last_seen, dstcountry, dstasn, reported_conntime";
+
+    /// `rows` rows over the three days from `start`, for 300 connections of two sensors, in
+    /// every state there is, stored out of order so that rows turn up after later ones.
+    fn churn(db: &rusqlite::Connection, partitions: &Partitions, random: &mut Random, start: f64, rows: usize) {
+        let mut order: Vec<usize> = (0 .. rows).collect();
+        for idx in (1 .. order.len()).rev() {
+            order.swap(idx, random.below(idx as u64 + 1) as usize);
+        }
+        for idx in order {
+            let instime = start + idx as f64 * 3.0 * DAY / rows as f64;
+            let conn = random.below(300);
+            let state = [5, 1, 2, 3][random.below(4) as usize];
+            let close = (state == 2).then(|| random.below(4) + 1);
+            let problem = (state == 3).then(|| (3, random.below(16)));
+            db.execute(&format!("
+                INSERT INTO {} ({}) VALUES (?, ?, ?, '127.0.0.1:40000', '10.0.0.1', ?, '10.0.0.2', 443, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+            ", partitions.table(db, instime).unwrap(), LATEST), params![
+                instime, instime,
+                if conn.is_multiple_of(2) { "a" } else { "b" },
+                40000 + conn,
+                if conn.is_multiple_of(3) { 17 } else { 6 },
+                state, close,
+                problem.map(|(kind, _)| kind), problem.map(|(_, code)| code),
+                instime + random.below(60) as f64,
+                (random.below(2) == 0).then_some("NL"),
+                (random.below(2) == 0).then_some(1136),
+                instime - random.below(5) as f64,
+            ]).unwrap();
+        }
+    }
+
+    /// Connections, as `ident/srcport@instime`, that `latest_state` has and recomputing the
+    /// view wouldn't give, then the other way round.
+    fn disagreements(db: &rusqlite::Connection) -> (Vec<String>, Vec<String>) {
+        let except = |from: &str, less: &str| -> Vec<String> {
+            db.prepare(&format!("SELECT ident, srcport, instime FROM (SELECT {c} FROM {} EXCEPT SELECT {c} FROM {})", from, less, c = LATEST)).unwrap()
+                .query_map([], |row| Ok(format!("{}/{}@{}", row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, f64>(2)?))).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap()
+        };
+        (except("latest_state", "latest_ins"), except("latest_ins", "latest_state"))
+    }
+
+    fn assert_agrees(db: &rusqlite::Connection, when: &str) {
+        assert_eq!(disagreements(db), (Vec::new(), Vec::new()), "{}", when);
+        let count = |table: &str| db.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("latest_state"), count("latest_ins"), "{}", when);
+    }
+
+    #[test]
+    fn latest_state_is_what_the_view_recomputes() {
+        for partition in [false, true] {
+            let mut db = rusqlite::Connection::open_in_memory().unwrap();
+            crate::server::migrate(&mut db);
+            let partitions = Partitions::open(&mut db, partition, MIDNIGHT - 3600.0).unwrap();
+            let mut random = Random::new(0x2545f4914f6cdd1d);
+            churn(&db, &partitions, &mut random, MIDNIGHT - DAY, 6000);
+            assert_agrees(&db, &format!("after the load, partitioned {}", partition));
+            assert_eq!(db.query_row("SELECT count(*) FROM latest_state", [], |row| row.get::<_, i64>(0)).unwrap(), 300);
+
+            // Keepalives refresh the latest row of a connection in place
+            let latest: Vec<(String, i64, f64)> = db.prepare("SELECT ident, srcport, instime FROM latest_state WHERE srcport % 7 = 0").unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            for (ident, srcport, instime) in latest {
+                db.execute(&format!("
+                    UPDATE {} SET last_seen = last_seen + 30 WHERE ident = ? AND srcport = ? AND instime = ?;
+                ", partitions.existing(instime)), params![ident, srcport, instime]).unwrap();
+            }
+            assert_agrees(&db, &format!("after keepalives, partitioned {}", partition));
+
+            // Retention and eviction both go oldest first
+            partitions.expire(&mut db, MIDNIGHT + 0.5 * DAY).unwrap();
+            assert_agrees(&db, &format!("after expiry, partitioned {}", partition));
+            for _ in 0 .. 3 {
+                partitions.evict_oldest(&mut db, 500).unwrap();
+                assert_agrees(&db, &format!("after eviction, partitioned {}", partition));
+            }
+            assert!(db.query_row("SELECT count(*) FROM state_all", [], |row| row.get::<_, i64>(0)).unwrap() > 0);
+
+            // Then more, some of it older than what's left
+            churn(&db, &partitions, &mut random, MIDNIGHT - DAY + 1.0, 2000);
+            assert_agrees(&db, &format!("after more rows, partitioned {}", partition));
+        }
+    }
+
+    /// How `latest_state` and the view compare, timed on millions of rows; run it with
+    /// `cargo test --release -- --ignored latest_state_outpaces`.
+    #[test]
+    #[ignore]
+    fn latest_state_outpaces_the_view_on_millions_of_rows() {
+        const ROWS: i64 = 2_000_000;
+        const CONNECTIONS: i64 = 600_000;
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        crate::server::migrate(&mut db);
+        let loading = std::time::Instant::now();
+        // Connection n is sensor n % 20's, from 10.0.(n / 60000).1:n % 60000; every third row
+        // starts a connection, every third is a keepalive and every third ends one
+        db.execute("
+            INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, last_seen)
+            WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i < :rows - 1)
+            SELECT :start + i * 0.01, :start + i * 0.01, 'sensor' || (i % :conns % 20), '127.0.0.1:40000',
+                '10.0.' || (i % :conns / 60000) || '.1', i % :conns % 60000, '10.1.0.2', 443, 6,
+                CASE i / :conns % 3 WHEN 0 THEN 5 WHEN 1 THEN 1 ELSE 2 END,
+                CASE WHEN i / :conns % 3 = 2 THEN 1 END,
+                :start + i * 0.01
+            FROM n;
+        ", rusqlite::named_params! { ":rows": ROWS, ":conns": CONNECTIONS, ":start": MIDNIGHT }).unwrap();
+        println!("{} rows, {} connections, loaded in {:?}", ROWS, CONNECTIONS, loading.elapsed());
+        assert_agrees(&db, "after the load");
+
+        let threshold = MIDNIGHT + ROWS as f64 * 0.01 * 0.75;
+        for (what, query) in [
+            ("timeout candidates", format!("SELECT count(*) FROM {{}} WHERE close IS NOT 4 AND proto = 6 AND coalesce(last_seen, instime) < {}", threshold)),
+            ("one sensor's open connections", "SELECT count(*) FROM {} WHERE ident = 'sensor7' AND state IN (5, 1)".to_string()),
+            ("one connection", "SELECT count(*) FROM {} WHERE ident = 'sensor7' AND srchost = '10.0.3.1' AND srcport = 7 AND dsthost = '10.1.0.2' AND dstport = 443 AND proto = 6".to_string()),
+        ] {
+            let time = |table: &str| {
+                let started = std::time::Instant::now();
+                let count: i64 = db.query_row(&query.replace("{}", table), [], |row| row.get(0)).unwrap();
+                (count, started.elapsed())
+            };
+            let (from_view, view) = time("latest_ins");
+            let (from_table, table) = time("latest_state");
+            println!("{}: {} rows, {:?} from the view, {:?} from latest_state", what, from_table, view, table);
+            assert_eq!(from_table, from_view, "{}", what);
+            assert!(table < view, "{}: {:?} from latest_state, {:?} from the view", what, table, view);
+        }
+    }
+}
@@ -0,0 +1,1112 @@
+use std::{cmp::Ordering, collections::{BTreeMap, HashMap}, net::SocketAddr, path::Path, time::SystemTime};
+
+use rusqlite::{named_params, OptionalExtension};
+use serde::Serialize;
+
+use crate::{cli::{QueryArgs, TopBy}, coding::FAILED_MARK, db, filter::{Cidr, Glob}, observe::Protocol, remote::{self, Query, QueryActive, QueryConnections, QueryRequest, QueryResponse}, sessions::{self, Key, Row, Session}, shard, timefmt};
+
+/// A sensor with an open sync connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sensor {
+    pub ident: String,
+    pub peer: String,
+    pub connected: f64,
+    /// Most recent time anything was stored for this ident.
+    pub last_seen: Option<f64>,
+    /// Seconds since `last_seen`.
+    pub staleness: Option<f64>,
+}
+
+/// A sensor that has reported at some point, connected or not.
+#[derive(Debug, Clone, Serialize)]
+pub struct Client {
+    pub ident: String,
+    /// Software and version from the client's hello; absent for clients too old to send one.
+    pub agent: Option<String>,
+    /// Whether its latest session was a legacy client's, which sends no hello; for a client not
+    /// seen since that was kept track of, whether it never sent one.
+    pub legacy: bool,
+    /// Seconds between keepalives the client announced.
+    pub keepalive: Option<u32>,
+    pub first_seen: f64,
+    pub last_seen: f64,
+    /// Seconds since `last_seen`.
+    pub staleness: f64,
+    pub connected: bool,
+    /// Seconds the client's clock was ahead (or, if negative, behind) when it last reported.
+    pub skew: Option<f64>,
+    /// The furthest off the client's clock has been seen, in either direction.
+    pub max_skew: Option<f64>,
+    /// Seconds from timestamp to arrival that 95% of the client's recent messages beat.
+    pub latency_p95: Option<f64>,
+    /// Messages the client timestamped after they arrived, left out of `latency_p95`.
+    pub early: Option<u64>,
+    /// Frames the client numbered that never arrived, over every session.
+    pub lost_frames: u64,
+    /// How capture was going on each of its interfaces when it last said.
+    pub health: Vec<InterfaceHealth>,
+    /// The share of packets its interfaces saw that were dropped rather than captured, over
+    /// every interface, as of its last report; absent if it never sent one.
+    pub drop_rate: Option<f64>,
+    /// Labels from the client's latest hello.
+    pub tags: BTreeMap<String, String>,
+    /// Labels operators gave it on the collector's side, with `glosco admin label`.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// One interface's counts, since its sensor started, as the sensor last reported them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InterfaceHealth {
+    pub interface: String,
+    /// Packets captured.
+    pub received: u64,
+    /// Dropped by the kernel for want of buffer space.
+    pub dropped: u64,
+    /// Dropped by the interface or its driver.
+    pub if_dropped: u64,
+    /// Captured but cut short or otherwise unparseable.
+    pub unparsed: u64,
+    /// When the report arrived, by the collector's clock.
+    pub reported_at: f64,
+}
+
+/// The share of packets `health`'s interfaces saw that were dropped, if they saw any.
+pub fn drop_rate(health: &[InterfaceHealth]) -> Option<f64> {
+    let dropped: u64 = health.iter().map(|interface| interface.dropped + interface.if_dropped).sum();
+    let seen = dropped + health.iter().map(|interface| interface.received).sum::<u64>();
+    (!health.is_empty()).then(|| if seen == 0 { 0.0 } else { dropped as f64 / seen as f64 })
+}
+
+/// Every ident's latest capture counts, by interface.
+fn health(db: &rusqlite::Connection) -> rusqlite::Result<HashMap<String, Vec<InterfaceHealth>>> {
+    let mut health: HashMap<String, Vec<InterfaceHealth>> = HashMap::new();
+    let mut stmt = db.prepare_cached("
+        SELECT ident, interface, received, dropped, if_dropped, unparsed, reported_at
+        FROM sensor_health
+        ORDER BY ident, interface;
+    ")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        health.entry(row.get(0)?).or_default().push(InterfaceHealth {
+            interface: row.get(1)?,
+            received: row.get(2)?,
+            dropped: row.get(3)?,
+            if_dropped: row.get(4)?,
+            unparsed: row.get(5)?,
+            reported_at: row.get(6)?,
+        });
+    }
+    Ok(health)
+}
+
+/// One hour of activity for one ident and protocol.
+#[derive(Debug, Clone, Serialize)]
+pub struct HourlySummary {
+    /// Start of the hour, in seconds since the epoch.
+    pub hour: f64,
+    pub ident: String,
+    pub proto: &'static str,
+    /// Connections seen starting (a SYN, for TCP).
+    pub opened: u64,
+    /// Distinct connections reported open at any point in the hour.
+    pub active: u64,
+    pub ended: u64,
+    pub failed: u64,
+    /// Distinct destination addresses.
+    pub dsthosts: u64,
+}
+
+/// A connection that's open as far as the collector knows, from `active_now`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveConnection {
+    pub ident: String,
+    pub srchost: String,
+    pub srcport: u16,
+    pub dsthost: String,
+    pub dstport: u16,
+    pub proto: &'static str,
+    pub conntime: f64,
+    pub last_seen: f64,
+    /// Seconds since the connection was first reported.
+    pub age: f64,
+}
+
+/// A Failed row, newest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub instime: f64,
+    pub conntime: f64,
+    pub ident: String,
+    pub srchost: String,
+    pub srcport: u16,
+    pub dsthost: String,
+    pub dstport: u16,
+    pub proto: &'static str,
+    pub pkind: u8,
+    pub pcode: u8,
+    /// How many more failures like it the row stands for, held back by the sensor.
+    pub repeats: u32,
+}
+
+/// Restricts which active connections are returned; `None` fields match anything.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveFilter {
+    pub ident: Option<String>,
+    /// Matches either end of the connection.
+    pub host: Option<String>,
+    /// Matches either end of the connection.
+    pub port: Option<u16>,
+}
+
+/// Restricts which sessions are returned; `None` fields match anything. Host and port are the
+/// destination's.
+#[derive(Debug, Clone, Default)]
+pub struct SessionFilter {
+    pub ident: Option<String>,
+    pub host: Option<Cidr>,
+    pub port: Option<u16>,
+    /// An IANA protocol number, as stored.
+    pub proto: Option<u8>,
+    /// Only sessions open at some point between these, in seconds since the epoch.
+    pub since: f64,
+    pub until: f64,
+}
+
+/// The name of a protocol stored by its IANA number.
+pub fn protocol_name(number: u8) -> &'static str {
+    match Protocol::from_iana_number(number) {
+        Some(Protocol::Tcp) => "tcp",
+        Some(Protocol::Udp) => "udp",
+        None => "unknown",
+    }
+}
+
+pub(crate) fn now_secs() -> f64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .expect("time is before UNIX epoch!")
+        .as_secs_f64()
+}
+
+pub fn sensors(db: &rusqlite::Connection) -> rusqlite::Result<Vec<Sensor>> {
+    let now = now_secs();
+    let mut stmt = db.prepare_cached("
+        SELECT ident, peer, connected,
+            (SELECT max(coalesce(last_seen, instime)) FROM state_all WHERE state_all.ident = client_sessions.ident)
+        FROM client_sessions
+        WHERE disconnected IS NULL
+        ORDER BY ident;
+    ")?;
+    let rows = stmt.query_map([], |row| {
+        let last_seen: Option<f64> = row.get(3)?;
+        Ok(Sensor {
+            ident: row.get(0)?,
+            peer: row.get(1)?,
+            connected: row.get(2)?,
+            last_seen,
+            staleness: last_seen.map(|t| now - t),
+        })
+    })?;
+    rows.collect()
+}
+
+/// Every ident's keys and values from `table`, `client_tags` or `ident_labels`.
+fn keyed(db: &rusqlite::Connection, table: &str) -> rusqlite::Result<HashMap<String, BTreeMap<String, String>>> {
+    let mut keyed: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+    let mut stmt = db.prepare_cached(&format!("SELECT ident, key, value FROM {};", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        keyed.entry(row.get(0)?).or_default().insert(row.get(1)?, row.get(2)?);
+    }
+    Ok(keyed)
+}
+
+/// The labels operators have given `ident`.
+pub fn ident_labels(db: &rusqlite::Connection, ident: &str) -> rusqlite::Result<BTreeMap<String, String>> {
+    let mut stmt = db.prepare_cached("SELECT key, value FROM ident_labels WHERE ident = ?;")?;
+    let labels = stmt.query_map([ident], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    labels.collect()
+}
+
+/// Every client that has all of `tags` and all of `labels`, which is every client if there are
+/// none.
+pub fn clients(db: &rusqlite::Connection, tags: &[(String, String)], labels: &[(String, String)]) -> rusqlite::Result<Vec<Client>> {
+    let now = now_secs();
+    let mut tagged = keyed(db, "client_tags")?;
+    let mut labelled = keyed(db, "ident_labels")?;
+    let mut healths = health(db)?;
+    let mut stmt = db.prepare_cached("
+        SELECT ident, agent, keepalive, first_seen, last_seen,
+            EXISTS (SELECT 1 FROM client_sessions
+                WHERE client_sessions.ident = clients.ident AND disconnected IS NULL),
+            skew, max_skew, latency_p95, early, coalesce(legacy, agent IS NULL), coalesce(lost_frames, 0)
+        FROM clients
+        ORDER BY ident;
+    ")?;
+    let rows = stmt.query_map([], |row| {
+        let last_seen: f64 = row.get(4)?;
+        Ok(Client {
+            ident: row.get(0)?,
+            agent: row.get(1)?,
+            keepalive: row.get(2)?,
+            first_seen: row.get(3)?,
+            last_seen,
+            staleness: now - last_seen,
+            connected: row.get(5)?,
+            skew: row.get(6)?,
+            max_skew: row.get(7)?,
+            latency_p95: row.get(8)?,
+            early: row.get(9)?,
+            legacy: row.get(10)?,
+            lost_frames: row.get(11)?,
+            health: Vec::new(),
+            drop_rate: None,
+            tags: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        })
+    })?;
+    let mut clients = Vec::new();
+    for client in rows {
+        let mut client = client?;
+        client.tags = tagged.remove(&client.ident).unwrap_or_default();
+        client.labels = labelled.remove(&client.ident).unwrap_or_default();
+        client.health = healths.remove(&client.ident).unwrap_or_default();
+        client.drop_rate = drop_rate(&client.health);
+        if tags.iter().all(|(key, value)| client.tags.get(key) == Some(value))
+            && labels.iter().all(|(key, value)| client.labels.get(key) == Some(value)) {
+            clients.push(client);
+        }
+    }
+    Ok(clients)
+}
+
+pub fn active(db: &rusqlite::Connection, filter: &ActiveFilter, limit: usize) -> rusqlite::Result<Vec<ActiveConnection>> {
+    let now = now_secs();
+    let mut stmt = db.prepare_cached("
+        SELECT ident, srchost, srcport, dsthost, dstport, proto, conntime, last_seen
+        FROM active_now
+        WHERE (:ident IS NULL OR ident = :ident)
+            AND (:host IS NULL OR srchost = :host OR dsthost = :host)
+            AND (:port IS NULL OR srcport = :port OR dstport = :port)
+        ORDER BY ident, conntime
+        LIMIT :limit;
+    ")?;
+    let rows = stmt.query_map(named_params! {
+        ":ident": filter.ident,
+        ":host": filter.host,
+        ":port": filter.port,
+        ":limit": limit as i64,
+    }, |row| {
+        let conntime: f64 = row.get(6)?;
+        Ok(ActiveConnection {
+            ident: row.get(0)?,
+            srchost: row.get(1)?,
+            srcport: row.get(2)?,
+            dsthost: row.get(3)?,
+            dstport: row.get(4)?,
+            proto: protocol_name(row.get(5)?),
+            conntime,
+            last_seen: row.get(7)?,
+            age: now - conntime,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Active connections with either end in `host`, which may be a whole block rather than the
+/// single address `active` matches.
+pub fn active_within(db: &rusqlite::Connection, ident: Option<String>, host: Option<Cidr>, port: Option<u16>, limit: usize) -> rusqlite::Result<Vec<ActiveConnection>> {
+    // A single address can be matched in SQL; wider blocks are checked row by row
+    let filter = ActiveFilter {
+        ident,
+        host: host.filter(|cidr| cidr.prefix == if cidr.addr.is_ipv4() { 32 } else { 128 }).map(|cidr| cidr.addr.to_string()),
+        port,
+    };
+    let matches = |addr: &str| match host {
+        Some(cidr) => addr.parse().map(|addr| cidr.contains(&addr)).unwrap_or(false),
+        None => true,
+    };
+    let limit = if host.is_some() { usize::MAX } else { limit };
+    Ok(active(db, &filter, limit)?.into_iter()
+        .filter(|row| matches(&row.srchost) || matches(&row.dsthost))
+        .take(limit)
+        .collect())
+}
+
+/// Hourly summaries from the last `hours` hours, oldest first.
+pub fn summary(db: &rusqlite::Connection, ident: Option<&str>, hours: u32) -> rusqlite::Result<Vec<HourlySummary>> {
+    let mut stmt = db.prepare_cached("
+        SELECT hour, ident, proto, opened, active, ended, failed, dsthosts
+        FROM summary_hourly
+        WHERE hour >= :since AND (:ident IS NULL OR ident = :ident)
+        ORDER BY hour, ident, proto;
+    ")?;
+    let rows = stmt.query_map(named_params! {
+        ":since": now_secs() - hours as f64 * 3600.0,
+        ":ident": ident,
+    }, |row| {
+        Ok(HourlySummary {
+            hour: row.get(0)?,
+            ident: row.get(1)?,
+            proto: protocol_name(row.get(2)?),
+            opened: row.get(3)?,
+            active: row.get(4)?,
+            ended: row.get(5)?,
+            failed: row.get(6)?,
+            dsthosts: row.get(7)?,
+        })
+    })?;
+    rows.collect()
+}
+
+pub fn failures(db: &rusqlite::Connection, limit: usize) -> rusqlite::Result<Vec<Failure>> {
+    let mut stmt = db.prepare_cached("
+        SELECT instime, conntime, ident, srchost, srcport, dsthost, dstport, proto, pkind, pcode, coalesce(repeats, 0)
+        FROM state_all
+        WHERE state = :failed
+        ORDER BY instime DESC
+        LIMIT :limit;
+    ")?;
+    let rows = stmt.query_map(named_params! {
+        ":failed": FAILED_MARK,
+        ":limit": limit as i64,
+    }, |row| {
+        Ok(Failure {
+            instime: row.get(0)?,
+            conntime: row.get(1)?,
+            ident: row.get(2)?,
+            srchost: row.get(3)?,
+            srcport: row.get(4)?,
+            dsthost: row.get(5)?,
+            dstport: row.get(6)?,
+            proto: protocol_name(row.get(7)?),
+            pkind: row.get(8)?,
+            pcode: row.get(9)?,
+            repeats: row.get(10)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Connections reconstructed into sessions, in ident and tuple order.
+pub fn sessions(db: &rusqlite::Connection, filter: &SessionFilter, limit: usize) -> rusqlite::Result<Vec<Session>> {
+    // A single address can be matched in SQL; wider blocks are checked row by row
+    let exact = filter.host
+        .filter(|cidr| cidr.prefix == if cidr.addr.is_ipv4() { 32 } else { 128 })
+        .map(|cidr| cidr.addr.to_string());
+    let mut stmt = db.prepare_cached("
+        SELECT ident, srchost, srcport, dsthost, dstport, proto, conntime, coalesce(last_seen, instime), state, close, opened_at
+        FROM state_all
+        WHERE conntime <= :until
+            AND (:ident IS NULL OR ident = :ident)
+            AND (:host IS NULL OR dsthost = :host)
+            AND (:port IS NULL OR dstport = :port)
+            AND (:proto IS NULL OR proto = :proto)
+        ORDER BY ident, srchost, srcport, dsthost, dstport, proto, instime;
+    ")?;
+    let rows = stmt.query_map(named_params! {
+        ":until": filter.until,
+        ":ident": filter.ident,
+        ":host": exact,
+        ":port": filter.port,
+        ":proto": filter.proto,
+    }, |row| {
+        Ok((Key {
+            ident: row.get(0)?,
+            srchost: row.get(1)?,
+            srcport: row.get(2)?,
+            dsthost: row.get(3)?,
+            dstport: row.get(4)?,
+            proto: row.get(5)?,
+        }, Row {
+            conntime: row.get(6)?,
+            last_seen: row.get(7)?,
+            state: row.get(8)?,
+            close: row.get(9)?,
+            opened_at: row.get(10)?,
+        }))
+    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+    let rows = rows.into_iter().filter(|(key, _)| match &filter.host {
+        Some(cidr) => key.dsthost.parse().map(|addr| cidr.contains(&addr)).unwrap_or(false),
+        None => true,
+    });
+    Ok(sessions::reconstruct(rows).into_iter()
+        .filter(|session| session.overlaps(filter.since, filter.until))
+        .take(limit)
+        .collect())
+}
+
+/// One of the busiest values of a column over a window.
+#[derive(Debug, Clone, Serialize)]
+pub struct Talker {
+    pub key: String,
+    /// Distinct connections (ident and tuple) with rows stored in the window.
+    pub connections: u64,
+}
+
+/// The `limit` values of `by` with the most connections stored between `since` and `until`,
+/// busiest first (ties in key order). The window is found through the instime index.
+pub fn top(db: &rusqlite::Connection, by: TopBy, since: f64, until: f64, limit: usize) -> rusqlite::Result<Vec<Talker>> {
+    let mut stmt = db.prepare_cached(&format!("
+        SELECT CAST({column} AS TEXT) AS key,
+            count(DISTINCT ident || ' ' || srchost || ' ' || srcport || ' ' || dsthost || ' ' || dstport || ' ' || proto) AS connections
+        FROM state_all
+        WHERE instime >= :since AND instime < :until
+        GROUP BY key
+        ORDER BY connections DESC, key
+        LIMIT :limit;
+    ", column = by.column()))?;
+    let rows = stmt.query_map(named_params! {
+        ":since": since,
+        ":until": until,
+        ":limit": limit.min(i64::MAX as usize) as i64,
+    }, |row| Ok(Talker {
+        key: row.get(0)?,
+        connections: row.get(1)?,
+    }))?;
+    rows.collect()
+}
+
+/// How a name is kept in `names.rname`: lowercased, without a trailing dot, and reversed, so
+/// that everything under one domain sorts together.
+pub(crate) fn reversed_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase().chars().rev().collect()
+}
+
+/// Restricts which name records `names` returns.
+#[derive(Debug, Clone)]
+pub struct NameFilter {
+    /// Matched against the name without regard to case or a trailing dot. `*.example.com` and
+    /// plain names are looked up through the `rname` index; other patterns read every record in
+    /// the window.
+    pub pattern: Glob,
+    /// Only records stored between these, in seconds since the epoch.
+    pub since: f64,
+    pub until: f64,
+    /// Seconds after a record during which connections to its address are joined to it. The
+    /// records' own TTLs aren't stored, so this stands in for them.
+    pub window: f64,
+}
+
+/// A name record matching a search, with the connections made to what it resolved to.
+#[derive(Debug, Clone, Serialize)]
+pub struct NameMatch {
+    pub instime: f64,
+    pub querier: Option<String>,
+    pub responder: Option<String>,
+    pub name: String,
+    pub addr: Option<String>,
+    /// Absent for observed DNS, `rdns` for the collector's own reverse lookups.
+    pub source: Option<String>,
+    pub connections: Vec<NameConnection>,
+}
+
+/// A connection to a matched record's address within the filter's window after it.
+#[derive(Debug, Clone, Serialize)]
+pub struct NameConnection {
+    pub ident: String,
+    pub srchost: String,
+    pub srcport: u16,
+    pub dstport: u16,
+    pub proto: &'static str,
+    /// The first row of it stored in the window.
+    pub instime: f64,
+}
+
+/// Name records matching `filter`, oldest first, each with the connections to its address.
+pub fn names(db: &rusqlite::Connection, filter: &NameFilter, limit: usize) -> rusqlite::Result<Vec<NameMatch>> {
+    let pattern = Glob(filter.pattern.0.trim_end_matches('.').to_lowercase());
+    let literal = |text: &str| !text.contains(['*', '?']);
+    // Narrow the records down by rname where the pattern allows; the glob has the last word
+    let (narrow, lo, hi) = if literal(&pattern.0) {
+        ("AND rname = :lo", Some(reversed_name(&pattern.0)), None)
+    } else if let Some(suffix) = pattern.0.strip_prefix('*').filter(|suffix| !suffix.is_empty() && literal(suffix)) {
+        let lo = reversed_name(suffix);
+        let mut hi = lo.clone();
+        let last = hi.pop().expect("suffix isn't empty");
+        hi.push(char::from_u32(last as u32 + 1).unwrap_or(char::MAX));
+        ("AND rname >= :lo AND rname < :hi", Some(lo), Some(hi))
+    } else {
+        ("", None, None)
+    };
+    let mut params: Vec<(&str, &dyn rusqlite::ToSql)> = vec![(":since", &filter.since), (":until", &filter.until)];
+    if let Some(lo) = &lo {
+        params.push((":lo", lo));
+    }
+    if let Some(hi) = &hi {
+        params.push((":hi", hi));
+    }
+    let mut stmt = db.prepare_cached(&format!("
+        SELECT instime, querier, responder, name, addr, source
+        FROM names
+        WHERE instime >= :since AND instime <= :until {}
+        ORDER BY instime;
+    ", narrow))?;
+    let found = stmt.query_map(params.as_slice(), |row| Ok(NameMatch {
+        instime: row.get(0)?,
+        querier: row.get(1)?,
+        responder: row.get(2)?,
+        name: row.get(3)?,
+        addr: row.get(4)?,
+        source: row.get(5)?,
+        connections: Vec::new(),
+    }))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut matches: Vec<NameMatch> = found.into_iter()
+        .filter(|found| pattern.matches(found.name.trim_end_matches('.').to_lowercase().as_str()))
+        .take(limit)
+        .collect();
+    let mut stmt = db.prepare_cached("
+        SELECT ident, srchost, srcport, dstport, proto, min(instime) AS first
+        FROM state_all
+        WHERE dsthost = :addr AND instime >= :from AND instime <= :to
+        GROUP BY ident, srchost, srcport, dstport, proto
+        ORDER BY first;
+    ")?;
+    for found in matches.iter_mut() {
+        let Some(addr) = &found.addr else {
+            continue;
+        };
+        found.connections = stmt.query_map(named_params! {
+            ":addr": addr,
+            ":from": found.instime,
+            ":to": found.instime + filter.window,
+        }, |row| Ok(NameConnection {
+            ident: row.get(0)?,
+            srchost: row.get(1)?,
+            srcport: row.get(2)?,
+            dstport: row.get(3)?,
+            proto: protocol_name(row.get(4)?),
+            instime: row.get(5)?,
+        }))?.collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(matches)
+}
+
+/// The name a record gave an address.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HostName {
+    pub name: String,
+    /// When the record was stored.
+    pub instime: f64,
+    /// Absent for observed DNS, `rdns` for the collector's own reverse lookups.
+    pub source: Option<String>,
+}
+
+/// The name `addr` went by at `at`: of the records for it stored by then, the newest that
+/// hadn't run out. A record holds for its own TTL, or `ttl` seconds if it has none (as
+/// observed DNS doesn't), and ties go to the one stored last.
+pub fn name_for_host(db: &rusqlite::Connection, addr: &str, at: f64, ttl: f64) -> rusqlite::Result<Option<HostName>> {
+    db.prepare_cached("
+        SELECT name, instime, source
+        FROM names
+        WHERE addr = :addr AND instime <= :at AND instime + coalesce(ttl, :ttl) >= :at
+        ORDER BY instime DESC, rowid DESC
+        LIMIT 1;
+    ")?.query_row(named_params! {
+        ":addr": addr,
+        ":at": at,
+        ":ttl": ttl,
+    }, |row| Ok(HostName {
+        name: row.get(0)?,
+        instime: row.get(1)?,
+        source: row.get(2)?,
+    })).optional()
+}
+
+/// Add `srcname` and `dstname` to each connection row, naming its ends as of when it was
+/// first reported (or, failing that, last seen), per `name_for_host` across `dbs`; null where
+/// there's no name.
+pub fn resolve(dbs: &[rusqlite::Connection], rows: &mut [serde_json::Value], ttl: f64) -> rusqlite::Result<()> {
+    for row in rows.iter_mut() {
+        let Some(fields) = row.as_object_mut() else {
+            continue;
+        };
+        let Some(at) = ["conntime", "start", "end"].iter().find_map(|key| fields.get(*key).and_then(serde_json::Value::as_f64)) else {
+            continue;
+        };
+        for (host, name) in [("srchost", "srcname"), ("dsthost", "dstname")] {
+            let Some(addr) = fields.get(host).and_then(serde_json::Value::as_str) else {
+                continue;
+            };
+            let mut found: Option<HostName> = None;
+            for db in dbs {
+                if let Some(record) = name_for_host(db, addr, at, ttl)? {
+                    if found.as_ref().is_none_or(|found| record.instime > found.instime) {
+                        found = Some(record);
+                    }
+                }
+            }
+            fields.insert(name.to_string(), found.map(|found| found.name).into());
+        }
+    }
+    Ok(())
+}
+
+/// Open `database`, or every shard in it if it's a directory of them.
+pub fn open_all(database: &str) -> Result<Vec<rusqlite::Connection>, Box<dyn std::error::Error + Send + Sync>> {
+    if !Path::new(database).is_dir() {
+        return Ok(vec![db::open(database)?]);
+    }
+    let shards = shard::list(database)?;
+    Ok(shards.iter().map(db::open).collect::<rusqlite::Result<_>>()?)
+}
+
+/// Run `query` against every database and put the results together in `order`.
+pub(crate) fn union<T, F, O>(dbs: &[rusqlite::Connection], query: F, order: O) -> rusqlite::Result<Vec<T>>
+where F: Fn(&rusqlite::Connection) -> rusqlite::Result<Vec<T>>, O: FnMut(&T, &T) -> Ordering {
+    let mut rows = Vec::new();
+    for db in dbs {
+        rows.extend(query(db)?);
+    }
+    rows.sort_by(order);
+    Ok(rows)
+}
+
+/// The order `active` returns rows in, for putting shards' results together.
+pub(crate) fn active_order(a: &ActiveConnection, b: &ActiveConnection) -> Ordering {
+    a.ident.cmp(&b.ident).then_with(|| a.conntime.total_cmp(&b.conntime))
+}
+
+/// The order `sessions` returns rows in, for putting shards' results together. Each ident's
+/// sessions come out of one shard in tuple order already, which a stable sort keeps.
+pub(crate) fn session_order(a: &Session, b: &Session) -> Ordering {
+    a.ident.cmp(&b.ident)
+}
+
+/// Rows asked of a remote collector when `--limit` isn't given.
+const REMOTE_LIMIT: u32 = 1000;
+/// Rows `--top` reports when `--limit` isn't given.
+const TOP_LIMIT: u32 = 10;
+/// Seconds after a name record that `--names` joins connections to it, and that `--resolve`
+/// takes a record with no TTL of its own to hold for, when `--window` isn't given.
+pub const NAME_WINDOW: f64 = 3600.0;
+
+/// Put the requested report to a collector over its client port and print what it answers,
+/// as `run` would have.
+fn ask(addr: SocketAddr, args: QueryArgs) {
+    let query = if args.active {
+        Query::Active(QueryActive {
+            ident: args.ident.clone(),
+            host: args.host,
+            port: args.port,
+            limit: args.limit.unwrap_or(REMOTE_LIMIT),
+        })
+    } else if args.sessions {
+        let until = args.until.unwrap_or_else(now_secs);
+        Query::Connections(QueryConnections {
+            ident: args.ident.clone(),
+            host: args.host,
+            port: args.port,
+            proto: args.proto.map(|proto| proto.protocol()),
+            since: args.since.unwrap_or(until - args.hours as f64 * 3600.0),
+            until,
+            limit: args.limit.unwrap_or(REMOTE_LIMIT),
+        })
+    } else {
+        panic!("only --active and --sessions can be asked of a remote collector");
+    };
+    let request = QueryRequest {
+        token: args.token.clone().unwrap_or_default(),
+        query,
+    };
+    let ident = format!("{}-query", gethostname::gethostname().to_string_lossy());
+    match remote::ask(addr, &ident, &request).expect("failed to query collector") {
+        QueryResponse::Rows { rows, truncated } => {
+            for row in rows {
+                let mut row: serde_json::Value = serde_json::from_str(&row).expect("collector sent a bad row");
+                if !args.epoch {
+                    timefmt::readable(&mut row);
+                }
+                println!("{}", row);
+            }
+            if truncated {
+                eprintln!("warning: more rows matched than the limit; raise --limit or narrow the query");
+            }
+        },
+        QueryResponse::Refused(reason) => panic!("collector refused the query: {}", reason),
+    }
+}
+
+/// Print the requested report from the database (or shards) as one JSON object per line.
+pub fn run(args: QueryArgs) {
+    if let Some(addr) = args.remote {
+        return ask(addr, args);
+    }
+    let dbs = open_all(&args.database).expect("failed to open database");
+    if args.top {
+        return print_top(&dbs, &args);
+    }
+    let mut rows: Vec<serde_json::Value> = if args.clients {
+        union(&dbs, |db| clients(db, &args.tag, &args.label), |a, b| a.ident.cmp(&b.ident)).expect("failed to query clients").into_iter()
+            .map(|row| serde_json::to_value(row).expect("failed to encode row"))
+            .collect()
+    } else if args.summary {
+        let query = |db: &rusqlite::Connection| summary(db, args.ident.as_deref(), args.hours);
+        let order = |a: &HourlySummary, b: &HourlySummary| a.hour.total_cmp(&b.hour).then_with(|| a.ident.cmp(&b.ident)).then_with(|| a.proto.cmp(b.proto));
+        union(&dbs, query, order).expect("failed to query summary").into_iter()
+            .map(|row| serde_json::to_value(row).expect("failed to encode row"))
+            .collect()
+    } else if args.active {
+        let query = |db: &rusqlite::Connection| active_within(db, args.ident.clone(), args.host, args.port, usize::MAX);
+        union(&dbs, query, active_order).expect("failed to query active connections").into_iter()
+            .map(|row| serde_json::to_value(row).expect("failed to encode row"))
+            .collect()
+    } else if let Some(pattern) = &args.names {
+        let until = args.until.unwrap_or_else(now_secs);
+        let filter = NameFilter {
+            pattern: pattern.clone(),
+            since: args.since.unwrap_or(until - args.hours as f64 * 3600.0),
+            until,
+            window: args.window.unwrap_or(NAME_WINDOW),
+        };
+        let limit = args.limit.map_or(usize::MAX, |limit| limit as usize);
+        let mut found = union(&dbs, |db| names(db, &filter, limit), |a, b| a.instime.total_cmp(&b.instime))
+            .expect("failed to search names");
+        found.truncate(limit);
+        found.into_iter()
+            .map(|row| serde_json::to_value(row).expect("failed to encode row"))
+            .collect()
+    } else if args.sessions {
+        let until = args.until.unwrap_or_else(now_secs);
+        let filter = SessionFilter {
+            ident: args.ident.clone(),
+            host: args.host,
+            port: args.port,
+            proto: args.proto.map(|proto| proto.protocol().iana_number()),
+            since: args.since.unwrap_or(until - args.hours as f64 * 3600.0),
+            until,
+        };
+        let query = |db: &rusqlite::Connection| sessions(db, &filter, usize::MAX);
+        union(&dbs, query, session_order).expect("failed to query sessions").into_iter()
+            .map(|row| serde_json::to_value(row).expect("failed to encode row"))
+            .collect()
+    } else {
+        unreachable!("clap requires a report")
+    };
+    if args.resolve {
+        resolve(&dbs, &mut rows, args.window.unwrap_or(NAME_WINDOW)).expect("failed to resolve names");
+    }
+    for mut row in rows {
+        if !args.epoch {
+            timefmt::readable(&mut row);
+        }
+        println!("{}", row);
+    }
+}
+
+/// `top` over every database, adding up shards' counts (each ident is only in one, so no
+/// connection is counted twice).
+pub fn top_all(dbs: &[rusqlite::Connection], by: TopBy, since: f64, until: f64, limit: usize) -> rusqlite::Result<Vec<Talker>> {
+    // A single database can rank for itself; shards' tails could add up to make the cut
+    let each = if dbs.len() == 1 { limit } else { usize::MAX };
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    for db in dbs {
+        for talker in top(db, by, since, until, each)? {
+            *totals.entry(talker.key).or_default() += talker.connections;
+        }
+    }
+    let mut talkers: Vec<Talker> = totals.into_iter().map(|(key, connections)| Talker { key, connections }).collect();
+    talkers.sort_by(|a, b| b.connections.cmp(&a.connections).then_with(|| a.key.cmp(&b.key)));
+    talkers.truncate(limit);
+    Ok(talkers)
+}
+
+/// Print the `--top` report.
+fn print_top(dbs: &[rusqlite::Connection], args: &QueryArgs) {
+    let by = args.by.unwrap_or_default();
+    let until = args.until.unwrap_or_else(now_secs);
+    let since = args.since.unwrap_or(until - args.hours as f64 * 3600.0);
+    let limit = args.limit.unwrap_or(TOP_LIMIT) as usize;
+    let talkers = top_all(dbs, by, since, until, limit).expect("failed to query top talkers");
+    if args.table {
+        let width = talkers.iter().map(|talker| talker.key.len()).chain([by.column().len()]).max().unwrap_or(0);
+        println!("{:<width$}  connections", by.column(), width = width);
+        for talker in talkers {
+            println!("{:<width$}  {:>11}", talker.key, talker.connections, width = width);
+        }
+        return;
+    }
+    for talker in talkers {
+        let key = match by {
+            TopBy::Dstport => talker.key.parse::<u16>().map(serde_json::Value::from).unwrap_or(talker.key.into()),
+            _ => talker.key.into(),
+        };
+        println!("{}", serde_json::json!({ by.column(): key, "connections": talker.connections }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+
+    const T: f64 = 1_000_000.0;
+    const WINDOW: f64 = 60.0;
+
+    fn db() -> rusqlite::Connection {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        crate::server::migrate(&mut db);
+        db
+    }
+
+    /// A record of `name` resolving to `addr`, stored at `instime`, as the ingest path stores it.
+    fn name(db: &rusqlite::Connection, instime: f64, name: &str, addr: &str) {
+        db.execute("
+            INSERT INTO names (instime, querier, responder, name, addr, rname)
+            VALUES (?, '10.0.0.1', '10.0.0.53', ?, ?, ?);
+        ", params![instime, name, addr, reversed_name(name)]).unwrap();
+    }
+
+    /// A Starting row from `srcport` to `dsthost`:443, stored at `instime`.
+    fn connect(db: &rusqlite::Connection, instime: f64, srcport: u16, dsthost: &str) {
+        db.execute("
+            INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, last_seen)
+            VALUES (?1, ?1, 'sensor', '127.0.0.1:40000', '10.0.0.1', ?2, ?3, 443, 6, 5, ?1);
+        ", params![instime, srcport, dsthost]).unwrap();
+    }
+
+    fn search(db: &rusqlite::Connection, pattern: &str) -> Vec<NameMatch> {
+        let filter = NameFilter { pattern: pattern.parse().unwrap(), since: 0.0, until: f64::MAX, window: WINDOW };
+        names(db, &filter, usize::MAX).unwrap()
+    }
+
+    fn found(db: &rusqlite::Connection, pattern: &str) -> Vec<String> {
+        search(db, pattern).into_iter().map(|found| found.name).collect()
+    }
+
+    #[test]
+    fn names_are_reversed_for_the_index() {
+        assert_eq!(reversed_name("Www.Example.COM."), "moc.elpmaxe.www");
+        assert_eq!(reversed_name("example.com"), "moc.elpmaxe");
+    }
+
+    #[test]
+    fn suffixes_match_the_names_under_them() {
+        let db = db();
+        for (idx, record) in ["a.evilcdn.example", "b.c.evilcdn.example", "evilcdn.example", "x.notevilcdn.example", "A.EvilCDN.Example.", "evilcdn.example.org"].iter().enumerate() {
+            name(&db, T + idx as f64, record, "192.0.2.1");
+        }
+        assert_eq!(found(&db, "*.evilcdn.example"), ["a.evilcdn.example", "b.c.evilcdn.example", "A.EvilCDN.Example."]);
+        // Case and a trailing dot don't matter in the pattern either
+        assert_eq!(found(&db, "*.EVILCDN.example."), found(&db, "*.evilcdn.example"));
+        // A plain name is only itself
+        assert_eq!(found(&db, "evilcdn.example"), ["evilcdn.example"]);
+        // Other globs go by the whole name
+        assert_eq!(found(&db, "?.evilcdn.*"), ["a.evilcdn.example", "A.EvilCDN.Example."]);
+        assert_eq!(found(&db, "*evilcdn.example"), ["a.evilcdn.example", "b.c.evilcdn.example", "evilcdn.example", "x.notevilcdn.example", "A.EvilCDN.Example."]);
+    }
+
+    #[test]
+    fn connections_are_joined_within_the_window_after_the_record() {
+        let db = db();
+        name(&db, T, "a.evilcdn.example", "192.0.2.1");
+        // Just before the record, and just after the window: not because of it
+        connect(&db, T - 0.5, 1, "192.0.2.1");
+        connect(&db, T + WINDOW + 0.5, 2, "192.0.2.1");
+        // At the record, inside and right at the end of the window
+        connect(&db, T, 3, "192.0.2.1");
+        connect(&db, T + WINDOW - 0.5, 4, "192.0.2.1");
+        connect(&db, T + WINDOW, 5, "192.0.2.1");
+        // Within the window, but somewhere else
+        connect(&db, T + 1.0, 6, "192.0.2.2");
+        // Seen twice in the window, joined once as of the first
+        connect(&db, T + 2.0, 7, "192.0.2.1");
+        connect(&db, T + 3.0, 7, "192.0.2.1");
+
+        let matched = search(&db, "*.evilcdn.example");
+        assert_eq!(matched.len(), 1);
+        let joined: Vec<(u16, f64)> = matched[0].connections.iter().map(|conn| (conn.srcport, conn.instime)).collect();
+        assert_eq!(joined, [(3, T), (7, T + 2.0), (4, T + WINDOW - 0.5), (5, T + WINDOW)]);
+        assert!(matched[0].connections.iter().all(|conn| conn.ident == "sensor" && conn.dstport == 443 && conn.proto == "tcp"));
+    }
+
+    #[test]
+    fn each_record_has_a_window_of_its_own() {
+        let db = db();
+        name(&db, T, "a.evilcdn.example", "192.0.2.1");
+        name(&db, T + 2.0 * WINDOW, "a.evilcdn.example", "192.0.2.1");
+        // A record without an address joins nothing
+        db.execute("INSERT INTO names (instime, name, rname) VALUES (?, 'b.evilcdn.example', ?);", params![T, reversed_name("b.evilcdn.example")]).unwrap();
+        connect(&db, T + 10.0, 1, "192.0.2.1");
+        connect(&db, T + 1.5 * WINDOW, 2, "192.0.2.1");
+        connect(&db, T + 2.0 * WINDOW + 10.0, 3, "192.0.2.1");
+
+        let joined: Vec<(Option<String>, Vec<u16>)> = search(&db, "*.evilcdn.example").into_iter()
+            .map(|found| (found.addr, found.connections.iter().map(|conn| conn.srcport).collect()))
+            .collect();
+        assert_eq!(joined, [
+            (Some("192.0.2.1".to_string()), vec![1]),
+            (None, vec![]),
+            (Some("192.0.2.1".to_string()), vec![3]),
+        ]);
+    }
+
+    #[test]
+    fn only_records_stored_in_the_span_are_searched() {
+        let db = db();
+        for idx in 0 .. 5 {
+            name(&db, T + idx as f64 * 10.0, "a.evilcdn.example", "192.0.2.1");
+        }
+        let filter = NameFilter { pattern: "*.evilcdn.example".parse().unwrap(), since: T + 10.0, until: T + 30.0, window: WINDOW };
+        let times: Vec<f64> = names(&db, &filter, usize::MAX).unwrap().iter().map(|found| found.instime).collect();
+        assert_eq!(times, [T + 10.0, T + 20.0, T + 30.0]);
+        assert_eq!(names(&db, &filter, 2).unwrap().len(), 2);
+    }
+
+    /// A record of `name` for `addr` that holds for `ttl`, or the default if it has none.
+    fn named(db: &rusqlite::Connection, instime: f64, name: &str, addr: &str, ttl: Option<f64>, source: Option<&str>) {
+        db.execute("
+            INSERT INTO names (instime, name, addr, rname, ttl, source) VALUES (?, ?, ?, ?, ?, ?);
+        ", params![instime, name, addr, reversed_name(name), ttl, source]).unwrap();
+    }
+
+    /// What `name_for_host` finds for each of `cases`, as (address, time, name).
+    fn assert_names(db: &rusqlite::Connection, ttl: f64, cases: &[(&str, f64, Option<&str>)]) {
+        for (addr, at, expected) in cases {
+            let found = name_for_host(db, addr, *at, ttl).unwrap().map(|found| found.name);
+            assert_eq!(found.as_deref(), *expected, "{} at T{:+}", addr, at - T);
+        }
+    }
+
+    #[test]
+    fn a_host_goes_by_the_newest_name_that_still_holds() {
+        let db = db();
+        named(&db, T, "old.example", "192.0.2.1", None, None);
+        named(&db, T + 100.0, "short.example", "192.0.2.1", Some(30.0), None);
+        named(&db, T + 120.0, "cdn-a.example", "192.0.2.1", Some(600.0), None);
+        // Stored at the same time, but after
+        named(&db, T + 120.0, "cdn-b.example", "192.0.2.1", Some(600.0), None);
+        named(&db, T + 1000.0, "late.example", "192.0.2.1", Some(60.0), None);
+        named(&db, T + 50.0, "neighbour.example", "192.0.2.2", None, None);
+        assert_names(&db, 300.0, &[
+            // Overlapping: the newest goes, even while older ones hold
+            ("192.0.2.1", T, Some("old.example")),
+            ("192.0.2.1", T + 99.0, Some("old.example")),
+            ("192.0.2.1", T + 100.0, Some("short.example")),
+            ("192.0.2.1", T + 119.0, Some("short.example")),
+            ("192.0.2.1", T + 120.0, Some("cdn-b.example")),
+            // The default TTL old.example holds for, and short.example's own, have run out
+            ("192.0.2.1", T + 500.0, Some("cdn-b.example")),
+            ("192.0.2.1", T + 720.0, Some("cdn-b.example")),
+            ("192.0.2.1", T + 721.0, None),
+            // Only what was stored by then
+            ("192.0.2.1", T - 1.0, None),
+            ("192.0.2.1", T + 999.0, None),
+            ("192.0.2.1", T + 1000.0, Some("late.example")),
+            ("192.0.2.1", T + 1060.0, Some("late.example")),
+            ("192.0.2.1", T + 1061.0, None),
+            // Addresses don't borrow each other's names
+            ("192.0.2.2", T + 100.0, Some("neighbour.example")),
+            ("192.0.2.2", T + 351.0, None),
+            ("192.0.2.3", T + 100.0, None),
+        ]);
+    }
+
+    #[test]
+    fn records_without_a_ttl_hold_for_the_one_given() {
+        let db = db();
+        named(&db, T, "observed.example", "192.0.2.1", None, None);
+        named(&db, T + 10.0, "resolved.example", "192.0.2.1", Some(3600.0), Some("rdns"));
+        named(&db, T + 20.0, "observed-again.example", "192.0.2.1", None, None);
+        assert_names(&db, 5.0, &[
+            ("192.0.2.1", T + 5.0, Some("observed.example")),
+            ("192.0.2.1", T + 6.0, None),
+            ("192.0.2.1", T + 25.0, Some("observed-again.example")),
+            ("192.0.2.1", T + 26.0, Some("resolved.example")),
+        ]);
+        assert_names(&db, 60.0, &[
+            ("192.0.2.1", T + 6.0, Some("observed.example")),
+            ("192.0.2.1", T + 26.0, Some("observed-again.example")),
+        ]);
+        assert_eq!(name_for_host(&db, "192.0.2.1", T + 100.0, 5.0).unwrap(), Some(HostName {
+            name: "resolved.example".to_string(),
+            instime: T + 10.0,
+            source: Some("rdns".to_string()),
+        }));
+    }
+
+    #[test]
+    fn resolving_names_both_ends_from_whichever_database_knows_newest() {
+        let (older, newer) = (db(), db());
+        named(&older, T, "old.example", "192.0.2.1", None, None);
+        named(&newer, T + 10.0, "new.example", "192.0.2.1", None, None);
+        named(&older, T + 20.0, "laptop.example", "10.0.0.1", None, None);
+        let mut rows = vec![
+            serde_json::json!({"srchost": "10.0.0.1", "dsthost": "192.0.2.1", "conntime": T + 30.0}),
+            serde_json::json!({"srchost": "10.0.0.1", "dsthost": "192.0.2.1", "conntime": T + 5.0}),
+            serde_json::json!({"srchost": "10.0.0.9", "dsthost": "192.0.2.9", "start": T + 30.0}),
+        ];
+        resolve(&[older, newer], &mut rows, 300.0).unwrap();
+        let names: Vec<_> = rows.iter().map(|row| (row["srcname"].as_str(), row["dstname"].as_str())).collect();
+        assert_eq!(names, [
+            (Some("laptop.example"), Some("new.example")),
+            (None, Some("old.example")),
+            (None, None),
+        ]);
+    }
+
+    const HOUR: f64 = 3600.0;
+
+    /// Connections from `srchost` to `dsthost:dstport`, one per source port from `ports`, each
+    /// stored as a Starting at `at` and an Ended a second later.
+    fn talk(db: &rusqlite::Connection, at: f64, ident: &str, srchost: &str, ports: std::ops::Range<u16>, dsthost: &str, dstport: u16) {
+        for srcport in ports {
+            for (instime, state, close) in [(at, 5, None), (at + 1.0, 2, Some(1))] {
+                db.execute("
+                    INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, last_seen)
+                    VALUES (?1, ?1, ?2, '127.0.0.1:40000', ?3, ?4, ?5, ?6, 6, ?7, ?8, ?1);
+                ", params![instime, ident, srchost, srcport, dsthost, dstport, state, close]).unwrap();
+            }
+        }
+    }
+
+    /// An hour of traffic from two sensors, `web` into `web` and `db` into `db` (which may be
+    /// the same database), and some either side of it that doesn't count.
+    fn talkers(web: &rusqlite::Connection, db: &rusqlite::Connection) {
+        talk(web, T + 10.0, "web", "10.0.0.1", 1 .. 4, "192.0.2.1", 443);
+        talk(web, T + 20.0, "web", "10.0.0.1", 4 .. 6, "192.0.2.2", 53);
+        talk(db, T + 30.0, "db", "10.0.0.2", 6 .. 8, "192.0.2.2", 5432);
+        talk(db, T + 40.0, "db", "10.0.0.3", 8 .. 11, "192.0.2.3", 443);
+        talk(db, T - 10.0, "old", "10.0.0.9", 1 .. 11, "192.0.2.9", 22);
+        talk(web, T + HOUR, "web", "10.0.0.1", 11 .. 21, "192.0.2.9", 22);
+    }
+
+    fn ranked(talkers: Vec<Talker>) -> Vec<(String, u64)> {
+        talkers.into_iter().map(|talker| (talker.key, talker.connections)).collect()
+    }
+
+    #[test]
+    fn top_ranks_each_column_by_connections_in_the_window() {
+        let db = db();
+        talkers(&db, &db);
+        let rank = |by, since, until, limit| ranked(top(&db, by, since, until, limit).unwrap());
+        let expect = |ranking: &[(&str, u64)]| ranking.iter().map(|(key, n)| (key.to_string(), *n)).collect::<Vec<_>>();
+        // Each connection once, however many rows it has; ties in key order
+        assert_eq!(rank(TopBy::Dsthost, T, T + HOUR, 10), expect(&[("192.0.2.2", 4), ("192.0.2.1", 3), ("192.0.2.3", 3)]));
+        assert_eq!(rank(TopBy::Dstport, T, T + HOUR, 10), expect(&[("443", 6), ("53", 2), ("5432", 2)]));
+        assert_eq!(rank(TopBy::Ident, T, T + HOUR, 10), expect(&[("db", 5), ("web", 5)]));
+        assert_eq!(rank(TopBy::Srchost, T, T + HOUR, 10), expect(&[("10.0.0.1", 5), ("10.0.0.3", 3), ("10.0.0.2", 2)]));
+
+        assert_eq!(rank(TopBy::Dsthost, T, T + HOUR, 2), expect(&[("192.0.2.2", 4), ("192.0.2.1", 3)]));
+        // Windows of their own: just the first connections, and just what came before
+        assert_eq!(rank(TopBy::Dsthost, T, T + 15.0, 10), expect(&[("192.0.2.1", 3)]));
+        assert_eq!(rank(TopBy::Ident, 0.0, T, 10), expect(&[("old", 10)]));
+    }
+
+    #[test]
+    fn top_across_shards_adds_up_what_none_ranks_first_alone() {
+        let (web, db) = (self::db(), self::db());
+        talkers(&web, &db);
+        // 192.0.2.2 is second in each, and first once they're added up
+        assert_eq!(ranked(top(&web, TopBy::Dsthost, T, T + HOUR, 1).unwrap()), [("192.0.2.1".to_string(), 3)]);
+        assert_eq!(ranked(top(&db, TopBy::Dsthost, T, T + HOUR, 1).unwrap()), [("192.0.2.3".to_string(), 3)]);
+        let shards = [web, db];
+        assert_eq!(ranked(top_all(&shards, TopBy::Dsthost, T, T + HOUR, 1).unwrap()), [("192.0.2.2".to_string(), 4)]);
+
+        let whole = self::db();
+        talkers(&whole, &whole);
+        for by in [TopBy::Dsthost, TopBy::Dstport, TopBy::Ident, TopBy::Srchost] {
+            assert_eq!(ranked(top_all(&shards, by, T, T + HOUR, 10).unwrap()), ranked(top(&whole, by, T, T + HOUR, 10).unwrap()), "{:?}", by);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! 802.11 frames as wireless monitor interfaces capture them, behind a radiotap header, taken
+//! apart just far enough to find the packet a data frame carries.
+
+/// What a radiotap-framed capture carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload<'a> {
+    /// A data frame's packet, LLC/SNAP encapsulated, with the ethertype SNAP gave it.
+    Packet(u16, &'a [u8]),
+    /// A frame with no packet to give: management and control frames, null data, and data
+    /// that's encrypted, fragmented, aggregated or not SNAP encapsulated.
+    Skipped,
+}
+
+/// The frame is cut short, or its headers contradict themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Malformed;
+
+// Radiotap present-bitmap fields before the flags, and what the flags say
+const PRESENT_TSFT: u32 = 1 << 0;
+const PRESENT_FLAGS: u32 = 1 << 1;
+const PRESENT_EXT: u32 = 1 << 31;
+const FLAG_FCS: u8 = 0x10;
+const FLAG_BAD_FCS: u8 = 0x40;
+
+// 802.11 frame control
+const TYPE_DATA: u8 = 2;
+const SUBTYPE_NO_DATA: u8 = 0x4;
+const SUBTYPE_QOS: u8 = 0x8;
+const FC_TO_DS: u8 = 0x01;
+const FC_FROM_DS: u8 = 0x02;
+const FC_MORE_FRAGMENTS: u8 = 0x04;
+const FC_PROTECTED: u8 = 0x40;
+const FC_ORDER: u8 = 0x80;
+const QOS_AMSDU: u8 = 0x80;
+
+/// RFC 1042 and 802.1H (bridge tunnel) encapsulation, before the ethertype.
+const SNAP_RFC1042: [u8; 6] = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00];
+const SNAP_BRIDGE_TUNNEL: [u8; 6] = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0xf8];
+
+/// Find the packet in a frame captured with link type `IEEE802_11_RADIOTAP`.
+pub fn parse(frame: &[u8]) -> Result<Payload<'_>, Malformed> {
+    let (flags, rest) = radiotap(frame)?;
+    if flags & FLAG_BAD_FCS != 0 {
+        return Ok(Payload::Skipped);
+    }
+    let rest = if flags & FLAG_FCS != 0 {
+        rest.get(.. rest.len().checked_sub(4).ok_or(Malformed)?).ok_or(Malformed)?
+    } else {
+        rest
+    };
+    dot11(rest)
+}
+
+/// Skip the radiotap header, by its own length, giving back its flags (zero if it has none)
+/// and what follows it.
+fn radiotap(frame: &[u8]) -> Result<(u8, &[u8]), Malformed> {
+    let header = frame.get(.. 8).ok_or(Malformed)?;
+    if header[0] != 0 {
+        // Only version 0 has ever been defined
+        return Err(Malformed);
+    }
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if len < 8 || len > frame.len() {
+        return Err(Malformed);
+    }
+    let (header, rest) = frame.split_at(len);
+    // The first present word is all the flags depend on, but fields start after every word
+    let word = |at: usize| header.get(at .. at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or(Malformed);
+    let present = word(4)?;
+    let mut offset = 8;
+    let mut last = present;
+    while last & PRESENT_EXT != 0 {
+        last = word(offset)?;
+        offset += 4;
+    }
+    if present & PRESENT_FLAGS == 0 {
+        return Ok((0, rest));
+    }
+    if present & PRESENT_TSFT != 0 {
+        // Eight bytes, aligned to eight
+        offset = offset.next_multiple_of(8) + 8;
+    }
+    let flags = *header.get(offset).ok_or(Malformed)?;
+    Ok((flags, rest))
+}
+
+/// Take apart an 802.11 frame, less any FCS.
+fn dot11(frame: &[u8]) -> Result<Payload<'_>, Malformed> {
+    let control = frame.get(.. 2).ok_or(Malformed)?;
+    if control[0] & 0x3 != 0 {
+        // Protocol version 0 is the only one there is
+        return Err(Malformed);
+    }
+    let (kind, subtype, fc) = ((control[0] >> 2) & 0x3, control[0] >> 4, control[1]);
+    if kind != TYPE_DATA || subtype & SUBTYPE_NO_DATA != 0 {
+        return Ok(Payload::Skipped);
+    }
+    // Frame control, duration, three addresses and sequence control
+    let mut len = 24;
+    if fc & FC_TO_DS != 0 && fc & FC_FROM_DS != 0 {
+        len += 6;
+    }
+    let mut amsdu = false;
+    if subtype & SUBTYPE_QOS != 0 {
+        amsdu = frame.get(len).ok_or(Malformed)? & QOS_AMSDU != 0;
+        len += 2;
+        if fc & FC_ORDER != 0 {
+            // HT control
+            len += 4;
+        }
+    }
+    let header = frame.get(.. len).ok_or(Malformed)?;
+    let fragment = header[22] & 0xf;
+    if fc & FC_PROTECTED != 0 || fc & FC_MORE_FRAGMENTS != 0 || fragment != 0 || amsdu {
+        return Ok(Payload::Skipped);
+    }
+    let body = &frame[len ..];
+    let Some(snap) = body.get(.. 8) else {
+        return Ok(Payload::Skipped);
+    };
+    if snap[.. 6] != SNAP_RFC1042 && snap[.. 6] != SNAP_BRIDGE_TUNNEL {
+        return Ok(Payload::Skipped);
+    }
+    Ok(Payload::Packet(u16::from_be_bytes([snap[6], snap[7]]), &body[8 ..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A radiotap header with flags and a rate, as most monitor interfaces give.
+    const RADIOTAP: [u8; 10] = [
+        // Version, pad, length
+        0x00, 0x00, 0x0a, 0x00,
+        // Present: flags and rate
+        0x06, 0x00, 0x00, 0x00,
+        // Flags (filled in), rate
+        0x00, 0x02,
+    ];
+
+    /// An 802.11 data frame header to the AP: frame control, duration, BSSID, source,
+    /// destination and sequence control.
+    const DATA: [u8; 24] = [
+        0x08, 0x01, 0x00, 0x00,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x03,
+        0x10, 0x00,
+    ];
+
+    const SNAP_IPV4: [u8; 8] = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00];
+    /// The start of an IPv4 packet, as far as this module cares.
+    const PACKET: [u8; 4] = [0x45, 0x00, 0x00, 0x14];
+
+    /// `RADIOTAP` with `flags`, then `dot11` and `body`.
+    fn frame(flags: u8, dot11: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut frame = RADIOTAP.to_vec();
+        frame[8] = flags;
+        frame.extend_from_slice(dot11);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn snap(ethertype: [u8; 2]) -> Vec<u8> {
+        let mut body = SNAP_IPV4.to_vec();
+        body[6 ..].copy_from_slice(&ethertype);
+        body.extend_from_slice(&PACKET);
+        body
+    }
+
+    /// `DATA` with frame control `fc`.
+    fn data(fc: [u8; 2]) -> Vec<u8> {
+        let mut header = DATA.to_vec();
+        header[.. 2].copy_from_slice(&fc);
+        header
+    }
+
+    #[test]
+    fn a_data_frame_gives_its_packet() {
+        assert_eq!(parse(&frame(0, &DATA, &snap([0x08, 0x00]))), Ok(Payload::Packet(0x0800, &PACKET)));
+    }
+
+    #[test]
+    fn a_frame_check_sequence_is_trimmed_and_a_bad_one_skipped() {
+        let mut body = snap([0x08, 0x00]);
+        body.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse(&frame(FLAG_FCS, &DATA, &body)), Ok(Payload::Packet(0x0800, &PACKET)));
+        assert_eq!(parse(&frame(FLAG_FCS | FLAG_BAD_FCS, &DATA, &body)), Ok(Payload::Skipped));
+        // Without the flag, those four bytes are the packet's
+        assert_eq!(parse(&frame(0, &DATA, &body)), Ok(Payload::Packet(0x0800, &body[8 ..])));
+    }
+
+    #[test]
+    fn flags_are_found_past_extended_present_words_and_the_tsft() {
+        let mut header = vec![
+            0x00, 0x00, 0x1a, 0x00,
+            // Present: TSFT, flags and another word; the other word has nothing
+            0x03, 0x00, 0x00, 0x80,
+            0x00, 0x00, 0x00, 0x00,
+            // Padding to the TSFT's alignment, then the TSFT
+            0x00, 0x00, 0x00, 0x00,
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+        ];
+        // Flags, then a pad byte
+        header.extend_from_slice(&[FLAG_FCS, 0x00]);
+        header.extend_from_slice(&DATA);
+        header.extend_from_slice(&snap([0x08, 0x00]));
+        header.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(parse(&header), Ok(Payload::Packet(0x0800, &PACKET)));
+    }
+
+    #[test]
+    fn no_flags_field_means_no_flags() {
+        let mut frame = frame(0, &DATA, &snap([0x08, 0x00]));
+        // Present: only the rate, which now sits where the flags were
+        frame[4] = 0x04;
+        frame[8] = FLAG_FCS;
+        assert_eq!(parse(&frame), Ok(Payload::Packet(0x0800, &PACKET)));
+    }
+
+    #[test]
+    fn the_header_is_as_long_as_its_addresses_and_controls() {
+        // QoS data between APs: a fourth address, QoS control and (with the order bit) HT control
+        let mut header = data([0x88, FC_TO_DS | FC_FROM_DS | FC_ORDER]);
+        header.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x04]);
+        header.extend_from_slice(&[0x00, 0x00]);
+        header.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(parse(&frame(0, &header, &snap([0x86, 0xdd]))), Ok(Payload::Packet(0x86dd, &PACKET)));
+
+        // Plain QoS data, bridge tunnel encapsulated
+        let mut header = data([0x88, FC_FROM_DS]);
+        header.extend_from_slice(&[0x00, 0x00]);
+        let mut body = snap([0x08, 0x00]);
+        body[.. 6].copy_from_slice(&SNAP_BRIDGE_TUNNEL);
+        assert_eq!(parse(&frame(0, &header, &body)), Ok(Payload::Packet(0x0800, &PACKET)));
+    }
+
+    #[test]
+    fn frames_without_a_packet_to_give_are_skipped() {
+        let body = snap([0x08, 0x00]);
+        let mut fragment = DATA.to_vec();
+        fragment[22] = 0x11;
+        let mut amsdu = data([0x88, FC_TO_DS]);
+        amsdu.extend_from_slice(&[QOS_AMSDU, 0x00]);
+        let mut llc = body.clone();
+        llc[5] = 0x01;
+        for (what, frame) in [
+            ("beacon", frame(0, &data([0x80, 0x00]), &body)),
+            ("ack", frame(0, &data([0xd4, 0x00]), &body)),
+            ("null data", frame(0, &data([0x48, FC_TO_DS]), &[])),
+            ("QoS null", frame(0, &data([0xc8, FC_TO_DS]), &body)),
+            // Ciphertext that happens to look like SNAP is still ciphertext
+            ("protected", frame(0, &data([0x08, FC_TO_DS | FC_PROTECTED]), &body)),
+            ("more fragments", frame(0, &data([0x08, FC_TO_DS | FC_MORE_FRAGMENTS]), &body)),
+            ("a later fragment", frame(0, &fragment, &body)),
+            ("A-MSDU", frame(0, &amsdu, &body)),
+            ("not SNAP", frame(0, &DATA, &llc)),
+            ("too short for SNAP", frame(0, &DATA, &body[.. 7])),
+        ] {
+            assert_eq!(parse(&frame), Ok(Payload::Skipped), "{}", what);
+        }
+    }
+
+    #[test]
+    fn headers_that_dont_add_up_are_malformed() {
+        let good = frame(0, &DATA, &snap([0x08, 0x00]));
+        let mut version = good.clone();
+        version[0] = 1;
+        let mut short = good.clone();
+        short[2] = 0x04;
+        let mut long = good.clone();
+        long[2 .. 4].copy_from_slice(&(good.len() as u16 + 1).to_le_bytes());
+        let mut dot11_version = good.clone();
+        dot11_version[RADIOTAP.len()] |= 0x01;
+        let mut qos = data([0x88, FC_TO_DS]);
+        qos.truncate(24);
+        for (what, frame) in [
+            ("empty", Vec::new()),
+            ("cut short in the radiotap header", good[.. 6].to_vec()),
+            ("a radiotap version that isn't", version),
+            ("radiotap shorter than its own header", short),
+            ("radiotap longer than the frame", long),
+            ("an 802.11 version that isn't", dot11_version),
+            ("cut short in the 802.11 header", good[.. RADIOTAP.len() + 20].to_vec()),
+            ("QoS with no QoS control", frame(0, &qos, &[])),
+            ("an FCS with no room for it", frame(FLAG_FCS, &[], &[0x08, 0x01])),
+        ] {
+            assert_eq!(parse(&frame), Err(Malformed), "{}", what);
+        }
+    }
+}
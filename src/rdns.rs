@@ -0,0 +1,401 @@
+use std::{collections::HashMap, io, net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc, Mutex}, thread, time::{Duration, Instant, SystemTime}};
+
+use rusqlite::params;
+
+use crate::{db, query::reversed_name};
+
+pub use crate::dns::{arpa_name, Resolver, UdpResolver};
+
+/// Remembers what was looked up recently, so each address is resolved at most once per TTL.
+#[derive(Debug, Default)]
+struct Recent {
+    until: HashMap<IpAddr, Instant>,
+}
+
+impl Recent {
+    const PRUNE_AT: usize = 65536;
+
+    /// Mark `addr` as handled for `ttl`, returning false if it already was.
+    fn claim(&mut self, addr: IpAddr, ttl: Duration) -> bool {
+        let now = Instant::now();
+        if self.until.get(&addr).map(|until| *until > now).unwrap_or(false) {
+            return false;
+        }
+        if self.until.len() >= Self::PRUNE_AT {
+            self.until.retain(|_, until| *until > now);
+        }
+        self.until.insert(addr, now + ttl);
+        true
+    }
+
+    fn extend(&mut self, addr: IpAddr, ttl: Duration) {
+        self.until.insert(addr, Instant::now() + ttl);
+    }
+}
+
+/// Allows one lookup every `interval` across all workers.
+#[derive(Debug)]
+struct RateLimit {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Background reverse lookups of destination addresses, stored in the names table with
+/// `source = 'rdns'`.
+pub struct ReverseDnsConfig {
+    database: String,
+    resolver: Box<dyn Fn() -> io::Result<Box<dyn Resolver>> + Send + Sync>,
+    workers: usize,
+    rate: f64,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl std::fmt::Debug for ReverseDnsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReverseDnsConfig")
+            .field("database", &self.database)
+            .field("workers", &self.workers)
+            .field("rate", &self.rate)
+            .field("ttl", &self.ttl)
+            .field("negative_ttl", &self.negative_ttl)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReverseDns {
+    sender: mpsc::SyncSender<IpAddr>,
+    recent: Arc<Mutex<Recent>>,
+    ttl: Duration,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ReverseDnsConfig {
+    pub const BACKLOG: usize = 4096;
+    pub const WORKERS: usize = 4;
+    /// Lookups per second, across all workers.
+    pub const RATE: f64 = 20.0;
+    pub const TTL: Duration = Duration::from_secs(6 * 3600);
+    pub const NEGATIVE_TTL: Duration = Duration::from_secs(24 * 3600);
+    /// How soon to try again after a lookup failed outright (as opposed to NXDOMAIN).
+    pub const RETRY: Duration = Duration::from_secs(300);
+
+    /// Resolve through `server`, or the system's first nameserver if not given.
+    pub fn new(database: String, server: Option<SocketAddr>) -> Self {
+        Self::with_resolver(database, move || {
+            let server = match server {
+                Some(server) => server,
+                None => UdpResolver::system_server()?,
+            };
+            Ok(Box::new(UdpResolver::new(server)?) as Box<dyn Resolver>)
+        })
+    }
+
+    /// Resolve through whatever `resolver` makes; it's called once per worker.
+    pub fn with_resolver<F: Fn() -> io::Result<Box<dyn Resolver>> + Send + Sync + 'static>(database: String, resolver: F) -> Self {
+        Self {
+            database,
+            resolver: Box::new(resolver),
+            workers: Self::WORKERS,
+            rate: Self::RATE,
+            ttl: Self::TTL,
+            negative_ttl: Self::NEGATIVE_TTL,
+        }
+    }
+
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.rate = rate;
+    }
+
+    /// How long before a resolved address is looked up again.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// How long before an address without a PTR record is looked up again.
+    pub fn set_negative_ttl(&mut self, ttl: Duration) {
+        self.negative_ttl = ttl;
+    }
+
+    pub fn start(self) -> io::Result<ReverseDns> {
+        let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let recent: Arc<Mutex<Recent>> = Arc::default();
+        let limit = Arc::new(RateLimit {
+            interval: Duration::from_secs_f64(1.0 / self.rate.max(0.001)),
+            next: Mutex::new(Instant::now()),
+        });
+        for _ in 0 .. self.workers {
+            let resolver = (self.resolver)()?;
+            let db = db::open(&self.database).map_err(io::Error::other)?;
+            let receiver = receiver.clone();
+            let recent = recent.clone();
+            let limit = limit.clone();
+            let (ttl, negative_ttl) = (self.ttl, self.negative_ttl);
+            thread::spawn(move || worker(resolver, db, receiver, recent, limit, ttl, negative_ttl));
+        }
+        Ok(ReverseDns {
+            sender,
+            recent,
+            ttl: self.ttl,
+            dropped: Arc::default(),
+        })
+    }
+}
+
+impl ReverseDns {
+    /// Queue `addr` for lookup unless it was looked up recently.
+    pub fn submit(&self, addr: IpAddr) {
+        if !self.recent.lock().unwrap().claim(addr, self.ttl) {
+            return;
+        }
+        if self.sender.try_send(addr).is_err() {
+            // Forget it so a later sighting gets another chance
+            self.recent.lock().unwrap().extend(addr, Duration::ZERO);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Addresses not looked up because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn worker(mut resolver: Box<dyn Resolver>, db: rusqlite::Connection, receiver: Arc<Mutex<mpsc::Receiver<IpAddr>>>, recent: Arc<Mutex<Recent>>, limit: Arc<RateLimit>, ttl: Duration, negative_ttl: Duration) {
+    loop {
+        let Ok(addr) = receiver.lock().unwrap().recv() else {
+            return;
+        };
+        limit.wait();
+        match resolver.reverse(addr) {
+            Ok(Some(name)) => {
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("time is before UNIX epoch!")
+                    .as_secs_f64();
+                let result = db::retry(|| db.execute("
+                    INSERT INTO names
+                    (instime, querier, responder, name, addr, port, text, source, rname, ttl)
+                    VALUES (?, NULL, ?, ?, ?, NULL, NULL, 'rdns', ?, ?);
+                ", params![now, resolver.source(), name.trim_end_matches('.'), addr.to_string(), reversed_name(&name), ttl.as_secs_f64()]));
+                if let Err(e) = result {
+                    println!("failed to store reverse lookup of {}: {:?}", addr, e);
+                }
+            },
+            Ok(None) => recent.lock().unwrap().extend(addr, negative_ttl),
+            Err(e) => {
+                println!("reverse lookup of {} failed: {:?}", addr, e);
+                recent.lock().unwrap().extend(addr, ReverseDnsConfig::RETRY);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::Scratch;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// What the mock resolver says about an address.
+    #[derive(Debug, Clone)]
+    enum Answer {
+        Name(&'static str),
+        Nxdomain,
+        Fails,
+    }
+
+    /// Answers from a table, noting each address asked after, and waiting to be let go of
+    /// before each answer if given a receiver to wait on.
+    struct Mock {
+        answers: Arc<HashMap<IpAddr, Answer>>,
+        asked: mpsc::Sender<(IpAddr, Instant)>,
+        release: Option<Arc<Mutex<mpsc::Receiver<()>>>>,
+    }
+
+    impl Resolver for Mock {
+        fn reverse(&mut self, addr: IpAddr) -> io::Result<Option<String>> {
+            self.asked.send((addr, Instant::now())).unwrap();
+            if let Some(release) = &self.release {
+                let _ = release.lock().unwrap().recv();
+            }
+            match self.answers.get(&addr).cloned().unwrap_or(Answer::Nxdomain) {
+                Answer::Name(name) => Ok(Some(name.to_string())),
+                Answer::Nxdomain => Ok(None),
+                Answer::Fails => Err(io::Error::new(io::ErrorKind::TimedOut, "no answer")),
+            }
+        }
+
+        fn source(&self) -> String {
+            "mock".to_string()
+        }
+    }
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    /// Every address asked after, until none has been for a while.
+    fn asked(asking: &mpsc::Receiver<(IpAddr, Instant)>) -> Vec<IpAddr> {
+        let mut asked = Vec::new();
+        while let Ok((addr, _)) = asking.recv_timeout(Duration::from_millis(200)) {
+            asked.push(addr);
+        }
+        asked
+    }
+
+    /// A database of the test's own, migrated.
+    fn migrated(name: &str) -> Scratch {
+        let scratch = Scratch::new(name);
+        crate::server::migrate(&mut db::open(scratch.path()).unwrap());
+        scratch
+    }
+
+    /// Reverse lookups through a mock answering with `answers`, and what it's asked.
+    fn config(scratch: &Scratch, answers: &[(&str, Answer)], release: Option<mpsc::Receiver<()>>) -> (ReverseDnsConfig, mpsc::Receiver<(IpAddr, Instant)>) {
+        let answers: Arc<HashMap<IpAddr, Answer>> = Arc::new(answers.iter().map(|(addr, answer)| (addr.parse().unwrap(), answer.clone())).collect());
+        let release = release.map(|release| Arc::new(Mutex::new(release)));
+        let (asked, asking) = mpsc::channel();
+        let config = ReverseDnsConfig::with_resolver(scratch.database().to_string(), move || Ok(Box::new(Mock {
+            answers: answers.clone(),
+            asked: asked.clone(),
+            release: release.clone(),
+        }) as Box<dyn Resolver>));
+        (config, asking)
+    }
+
+    /// The reverse lookups stored, as (addr, name, rname, responder, ttl), once there are `count`.
+    fn stored(scratch: &Scratch, count: usize) -> Vec<(String, String, String, String, f64)> {
+        let db = db::open(scratch.path()).unwrap();
+        let deadline = Instant::now() + WAIT;
+        loop {
+            let rows: Vec<(String, String, String, String, f64)> = db
+                .prepare("SELECT addr, name, rname, responder, ttl FROM names WHERE source = 'rdns' ORDER BY addr").unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            if rows.len() >= count || Instant::now() >= deadline {
+                return rows;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn names_found_are_stored_and_not_looked_up_again_for_their_ttl() {
+        let scratch = migrated("found");
+        let (mut config, asking) = config(&scratch, &[
+            ("10.0.0.1", Answer::Name("one.example.")),
+            ("2001:db8::2", Answer::Name("Two.Example")),
+        ], None);
+        config.set_rate(1000.0);
+        let rdns = config.start().unwrap();
+        for _ in 0 .. 3 {
+            rdns.submit(addr("10.0.0.1"));
+            rdns.submit(addr("2001:db8::2"));
+        }
+        assert_eq!(stored(&scratch, 2), [
+            ("10.0.0.1".to_string(), "one.example".to_string(), "elpmaxe.eno".to_string(), "mock".to_string(), ReverseDnsConfig::TTL.as_secs_f64()),
+            ("2001:db8::2".to_string(), "Two.Example".to_string(), "elpmaxe.owt".to_string(), "mock".to_string(), ReverseDnsConfig::TTL.as_secs_f64()),
+        ]);
+        let mut asked = asked(&asking);
+        asked.sort();
+        assert_eq!(asked, [addr("10.0.0.1"), addr("2001:db8::2")]);
+        assert_eq!(rdns.dropped(), 0);
+    }
+
+    #[test]
+    fn no_name_is_remembered_for_the_negative_ttl_and_a_failure_for_the_retry() {
+        let scratch = migrated("negative");
+        let (mut config, asking) = config(&scratch, &[("10.0.0.3", Answer::Fails)], None);
+        config.set_rate(1000.0);
+        config.set_ttl(Duration::from_millis(10));
+        config.set_negative_ttl(Duration::from_millis(500));
+        let rdns = config.start().unwrap();
+        rdns.submit(addr("10.0.0.2"));
+        rdns.submit(addr("10.0.0.3"));
+        let mut first = asked(&asking);
+        first.sort();
+        assert_eq!(first, [addr("10.0.0.2"), addr("10.0.0.3")]);
+
+        // Well past the TTL of a name found, but within the negative TTL and the retry
+        thread::sleep(Duration::from_millis(100));
+        rdns.submit(addr("10.0.0.2"));
+        rdns.submit(addr("10.0.0.3"));
+        assert!(asked(&asking).is_empty());
+
+        // Past the negative TTL, though still not the retry after a failure
+        thread::sleep(Duration::from_millis(500));
+        rdns.submit(addr("10.0.0.2"));
+        rdns.submit(addr("10.0.0.3"));
+        assert_eq!(asked(&asking), [addr("10.0.0.2")]);
+        assert!(stored(&scratch, 0).is_empty());
+    }
+
+    #[test]
+    fn lookups_are_held_to_the_rate_across_workers() {
+        let scratch = migrated("rate");
+        let (mut config, asking) = config(&scratch, &[], None);
+        config.set_workers(4);
+        config.set_rate(20.0);
+        let rdns = config.start().unwrap();
+        for n in 1 ..= 10 {
+            rdns.submit(IpAddr::from([10, 0, 1, n]));
+        }
+        let mut times: Vec<Instant> = (0 .. 10).map(|_| asking.recv_timeout(WAIT).unwrap().1).collect();
+        times.sort();
+        // A twentieth of a second apart, give or take a sleep's waking up early
+        for pair in times.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(gap >= Duration::from_millis(45), "{:?} between lookups", gap);
+        }
+    }
+
+    #[test]
+    fn addresses_past_a_full_backlog_are_dropped_and_counted_and_get_another_chance() {
+        let scratch = migrated("backlog");
+        let (release, stalled) = mpsc::channel();
+        let (mut config, asking) = config(&scratch, &[], Some(stalled));
+        config.set_workers(1);
+        config.set_rate(1_000_000.0);
+        let rdns = config.start().unwrap();
+        let addrs: Vec<IpAddr> = (0 .. ReverseDnsConfig::BACKLOG as u32 + 100).map(|n| IpAddr::from((0x0a00_0000 + n).to_be_bytes())).collect();
+        // The one worker takes the first and waits on it, and the backlog fills behind it
+        rdns.submit(addrs[0]);
+        assert_eq!(asking.recv_timeout(WAIT).unwrap().0, addrs[0]);
+        for addr in &addrs[1 ..] {
+            rdns.submit(*addr);
+        }
+        assert_eq!(rdns.dropped(), 99);
+
+        // One dropped is let in when it's seen again, once there's room
+        for _ in 0 ..= ReverseDnsConfig::BACKLOG {
+            release.send(()).unwrap();
+        }
+        for addr in &addrs[1 ..= ReverseDnsConfig::BACKLOG] {
+            assert_eq!(asking.recv_timeout(WAIT).unwrap().0, *addr);
+        }
+        let last = *addrs.last().unwrap();
+        rdns.submit(last);
+        assert_eq!(asking.recv_timeout(WAIT).unwrap().0, last);
+        assert_eq!(rdns.dropped(), 99);
+    }
+}
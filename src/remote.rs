@@ -0,0 +1,66 @@
+use std::{io::{self, Write}, net::{SocketAddr, TcpStream}};
+
+use crate::{coding::{Coder, CodingVec}, filter::Cidr, observe::Protocol};
+
+/// Sent as the first frame, in place of a `Hello`, by a connection that wants to ask the
+/// collector a question rather than report. The collector answers with one `QueryResponse`
+/// frame and hangs up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryRequest {
+    /// Has to match the collector's `remote_query_token`; with none set, every query is refused.
+    pub token: String,
+    pub query: Query,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Active(QueryActive),
+    Connections(QueryConnections),
+}
+
+/// Connections open right now, as in `glosco query --active`; `None` fields match anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryActive {
+    pub ident: Option<String>,
+    /// Matches either end of the connection.
+    pub host: Option<Cidr>,
+    /// Matches either end of the connection.
+    pub port: Option<u16>,
+    pub limit: u32,
+}
+
+/// Connections reconstructed into sessions, as in `glosco query --sessions`; `None` fields
+/// match anything. Host and port are the destination's.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryConnections {
+    pub ident: Option<String>,
+    pub host: Option<Cidr>,
+    pub port: Option<u16>,
+    pub proto: Option<Protocol>,
+    /// Only sessions open at some point between these, in seconds since the epoch.
+    pub since: f64,
+    pub until: f64,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryResponse {
+    /// The matching rows as JSON objects, times in seconds since the epoch. `truncated` if there
+    /// were more than the query's limit, or the collector's, whichever is lower.
+    Rows { rows: Vec<String>, truncated: bool },
+    /// Why the collector wouldn't or couldn't answer.
+    Refused(String),
+}
+
+/// Ask the collector at `addr` one question, connecting under `ident`.
+pub fn ask(addr: SocketAddr, ident: &str, request: &QueryRequest) -> io::Result<QueryResponse> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut hello = Vec::new();
+    ident.to_string().encode(&mut hello)?;
+    let mut payload = Vec::new();
+    request.encode(&mut payload)?;
+    CodingVec::<u8, u32>::new(payload).encode(&mut hello)?;
+    stream.write_all(&hello)?;
+    let frame = CodingVec::<u8, u32>::decode(&mut stream)?.0;
+    QueryResponse::decode(&mut frame.as_slice())
+}
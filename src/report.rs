@@ -0,0 +1,689 @@
+//! Reports the collector writes to files on a schedule, summing up a day or an hour for people
+//! who don't run queries: the busiest destinations, idents seen for the first time, failures,
+//! sessions, and how each sensor is doing.
+//!
+//! Maintenance checks after every tick whether the last whole period has a report yet and, if
+//! not, has one written on a thread of its own, through connections of its own.
+
+use std::{collections::BTreeMap, error::Error, fs, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread};
+
+use rusqlite::named_params;
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::TopBy, query::{self, Client, HostName, SessionFilter}, server::ReportSettings, sessions::Ending, timefmt};
+
+/// How much each report covers. A report is written once its period is over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Hourly,
+    /// UTC days.
+    #[default]
+    Daily,
+}
+
+impl Interval {
+    pub fn secs(self) -> f64 {
+        match self {
+            Self::Hourly => 3600.0,
+            Self::Daily => 24.0 * 3600.0,
+        }
+    }
+
+    /// The last whole period before `now`, as (start, end).
+    pub fn last(self, now: f64) -> (f64, f64) {
+        let end = (now / self.secs()).floor() * self.secs();
+        (end - self.secs(), end)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Plain text, with columns lined up.
+    Text,
+    #[default]
+    Markdown,
+    /// One JSON object, with times as RFC 3339 like query results.
+    Json,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Markdown => "md",
+            Self::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Section {
+    /// The destination addresses with the most connections, named where a name is on record.
+    Destinations,
+    /// Idents first seen in the period.
+    NewIdents,
+    /// Connections each ident opened and saw fail, from the hourly summaries.
+    Failures,
+    /// Sessions each ident had open in the period, by how they ended.
+    Sessions,
+    /// Whether each sensor is connected, when it last reported, and its clock skew and latency.
+    Health,
+}
+
+impl Section {
+    /// Every section, in the order a report has them unless told otherwise.
+    pub const ALL: &'static [Section] = &[
+        Self::Destinations, Self::NewIdents, Self::Failures, Self::Sessions, Self::Health,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Destinations => "Top destinations",
+            Self::NewIdents => "New idents",
+            Self::Failures => "Failures",
+            Self::Sessions => "Sessions",
+            Self::Health => "Sensor health",
+        }
+    }
+}
+
+/// Destinations a report lists.
+const DESTINATIONS: usize = 10;
+
+/// A destination address and the connections made to it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Destination {
+    pub dsthost: String,
+    /// The newest name on record for it at the end of the period, if there is one.
+    pub name: Option<String>,
+    pub connections: u64,
+}
+
+/// Connections an ident opened and saw fail.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failures {
+    pub ident: String,
+    pub opened: u64,
+    pub failed: u64,
+}
+
+/// An ident's sessions, by how they ended (see `sessions::Ending`).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Sessions {
+    pub ident: String,
+    pub sessions: u64,
+    pub ended: u64,
+    pub reset: u64,
+    pub failed: u64,
+    pub timeout: u64,
+    pub reopened: u64,
+    pub open: u64,
+    /// Median seconds of the sessions with a start on record.
+    pub median_duration: Option<f64>,
+}
+
+/// One section of a report, or why it couldn't be put together.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Part<T> {
+    Rows(Vec<T>),
+    Unavailable { error: String },
+}
+
+impl<T> From<rusqlite::Result<Vec<T>>> for Part<T> {
+    fn from(result: rusqlite::Result<Vec<T>>) -> Self {
+        match result {
+            Ok(rows) => Self::Rows(rows),
+            Err(e) => Self::Unavailable { error: e.to_string() },
+        }
+    }
+}
+
+/// A report on `start .. end`, with the sections it was asked for.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub start: f64,
+    pub end: f64,
+    pub generated: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destinations: Option<Part<Destination>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_idents: Option<Part<Client>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failures: Option<Part<Failures>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<Part<Sessions>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<Part<Client>>,
+}
+
+/// Put together the `sections` of a report on `start .. end` from every database. A section
+/// that can't be is marked unavailable and the rest go ahead.
+pub fn generate(dbs: &[rusqlite::Connection], sections: &[Section], start: f64, end: f64, now: f64) -> Report {
+    let wants = |section| sections.contains(&section);
+    Report {
+        start,
+        end,
+        generated: now,
+        destinations: wants(Section::Destinations).then(|| destinations(dbs, start, end).into()),
+        new_idents: wants(Section::NewIdents).then(|| new_idents(dbs, start, end).into()),
+        failures: wants(Section::Failures).then(|| failures(dbs, start, end).into()),
+        sessions: wants(Section::Sessions).then(|| sessions(dbs, start, end).into()),
+        health: wants(Section::Health).then(|| clients(dbs).into()),
+    }
+}
+
+fn destinations(dbs: &[rusqlite::Connection], start: f64, end: f64) -> rusqlite::Result<Vec<Destination>> {
+    query::top_all(dbs, TopBy::Dsthost, start, end, DESTINATIONS)?.into_iter().map(|talker| {
+        // Names without a TTL of their own hold for the whole period
+        let mut newest: Option<HostName> = None;
+        for db in dbs {
+            if let Some(found) = query::name_for_host(db, &talker.key, end, end - start)? {
+                if newest.as_ref().is_none_or(|newest| found.instime > newest.instime) {
+                    newest = Some(found);
+                }
+            }
+        }
+        Ok(Destination {
+            dsthost: talker.key,
+            name: newest.map(|newest| newest.name),
+            connections: talker.connections,
+        })
+    }).collect()
+}
+
+fn clients(dbs: &[rusqlite::Connection]) -> rusqlite::Result<Vec<Client>> {
+    query::union(dbs, |db| query::clients(db, &[], &[]), |a, b| a.ident.cmp(&b.ident))
+}
+
+fn new_idents(dbs: &[rusqlite::Connection], start: f64, end: f64) -> rusqlite::Result<Vec<Client>> {
+    Ok(clients(dbs)?.into_iter()
+        .filter(|client| client.first_seen >= start && client.first_seen < end)
+        .collect())
+}
+
+fn failures(dbs: &[rusqlite::Connection], start: f64, end: f64) -> rusqlite::Result<Vec<Failures>> {
+    let query = |db: &rusqlite::Connection| {
+        let mut stmt = db.prepare_cached("
+            SELECT ident, sum(opened), sum(failed)
+            FROM summary_hourly
+            WHERE hour >= :start AND hour < :end
+            GROUP BY ident;
+        ")?;
+        let rows = stmt.query_map(named_params! {
+            ":start": start,
+            ":end": end,
+        }, |row| Ok(Failures {
+            ident: row.get(0)?,
+            opened: row.get(1)?,
+            failed: row.get(2)?,
+        }))?;
+        rows.collect()
+    };
+    query::union(dbs, query, |a: &Failures, b: &Failures| b.failed.cmp(&a.failed).then_with(|| a.ident.cmp(&b.ident)))
+}
+
+fn sessions(dbs: &[rusqlite::Connection], start: f64, end: f64) -> rusqlite::Result<Vec<Sessions>> {
+    let filter = SessionFilter {
+        since: start,
+        until: end,
+        ..Default::default()
+    };
+    let mut by_ident: BTreeMap<String, (Sessions, Vec<f64>)> = BTreeMap::new();
+    for db in dbs {
+        for session in query::sessions(db, &filter, usize::MAX)? {
+            let (counts, durations) = by_ident.entry(session.ident.clone()).or_default();
+            counts.sessions += 1;
+            *match session.ending {
+                Ending::Ended => &mut counts.ended,
+                Ending::Reset => &mut counts.reset,
+                Ending::Failed => &mut counts.failed,
+                Ending::Timeout => &mut counts.timeout,
+                Ending::Reopened => &mut counts.reopened,
+                Ending::Open => &mut counts.open,
+            } += 1;
+            durations.extend(session.duration);
+        }
+    }
+    Ok(by_ident.into_iter().map(|(ident, (counts, mut durations))| {
+        durations.sort_by(f64::total_cmp);
+        Sessions {
+            ident,
+            median_duration: durations.get(durations.len() / 2).copied(),
+            ..counts
+        }
+    }).collect())
+}
+
+/// A time as RFC 3339 to the second, for reading rather than parsing.
+fn time(secs: f64) -> String {
+    format!("{}Z", &timefmt::rfc3339(secs)[..19])
+}
+
+fn secs(secs: Option<f64>) -> String {
+    secs.map(|secs| format!("{:.1}", secs)).unwrap_or_default()
+}
+
+/// A section's column headings and rows, or why it's missing; `None` if the report doesn't
+/// have it.
+type Table = Option<Result<(&'static [&'static str], Vec<Vec<String>>), String>>;
+
+fn table<T>(part: &Option<Part<T>>, columns: &'static [&'static str], row: impl Fn(&T) -> Vec<String>) -> Table {
+    match part.as_ref()? {
+        Part::Rows(rows) => Some(Ok((columns, rows.iter().map(row).collect()))),
+        Part::Unavailable { error } => Some(Err(error.clone())),
+    }
+}
+
+fn section(report: &Report, section: Section) -> Table {
+    match section {
+        Section::Destinations => table(&report.destinations, &["dsthost", "name", "connections"], |row| vec![
+            row.dsthost.clone(), row.name.clone().unwrap_or_default(), row.connections.to_string(),
+        ]),
+        Section::NewIdents => table(&report.new_idents, &["ident", "agent", "first_seen"], |row| vec![
+            row.ident.clone(), row.agent.clone().unwrap_or_default(), time(row.first_seen),
+        ]),
+        Section::Failures => table(&report.failures, &["ident", "opened", "failed"], |row| vec![
+            row.ident.clone(), row.opened.to_string(), row.failed.to_string(),
+        ]),
+        Section::Sessions => table(&report.sessions, &["ident", "sessions", "ended", "reset", "failed", "timeout", "reopened", "open", "median_duration"], |row| vec![
+            row.ident.clone(), row.sessions.to_string(), row.ended.to_string(), row.reset.to_string(),
+            row.failed.to_string(), row.timeout.to_string(), row.reopened.to_string(), row.open.to_string(),
+            secs(row.median_duration),
+        ]),
+        Section::Health => table(&report.health, &["ident", "connected", "last_seen", "staleness", "skew", "latency_p95", "agent"], |row| vec![
+            row.ident.clone(), row.connected.to_string(), time(row.last_seen), secs(Some(row.staleness)),
+            secs(row.skew), secs(row.latency_p95), row.agent.clone().unwrap_or_default(),
+        ]),
+    }
+}
+
+/// `report` as the contents of a file in `format`, with its sections in the order of `sections`.
+pub fn render(report: &Report, sections: &[Section], format: Format) -> String {
+    if format == Format::Json {
+        let mut value = serde_json::to_value(report).expect("failed to encode report");
+        timefmt::readable(&mut value);
+        return serde_json::to_string_pretty(&value).expect("failed to encode report") + "\n";
+    }
+    let markdown = format == Format::Markdown;
+    let title = format!("glosco report, {} to {}", time(report.start), time(report.end));
+    let mut out = match markdown {
+        true => format!("# {}\n\nGenerated {}.\n", title, time(report.generated)),
+        false => format!("{}\n{}\nGenerated {}.\n", title, "=".repeat(title.len()), time(report.generated)),
+    };
+    for &part in sections {
+        let Some(table) = self::section(report, part) else {
+            continue;
+        };
+        out += &match markdown {
+            true => format!("\n## {}\n\n", part.title()),
+            false => format!("\n{}\n{}\n", part.title(), "-".repeat(part.title().len())),
+        };
+        let (columns, rows) = match table {
+            Ok((_, rows)) if rows.is_empty() => {
+                out += "None.\n";
+                continue;
+            },
+            Ok(table) => table,
+            Err(error) => {
+                out += &format!("Unavailable: {}\n", error);
+                continue;
+            },
+        };
+        if markdown {
+            let cells = |row: &[String]| format!("| {} |\n", row.iter().map(|cell| cell.replace('|', "\\|")).collect::<Vec<_>>().join(" | "));
+            out += &cells(&columns.iter().map(|column| column.to_string()).collect::<Vec<_>>());
+            out += &format!("|{}\n", " --- |".repeat(columns.len()));
+            for row in &rows {
+                out += &cells(row);
+            }
+        } else {
+            let widths: Vec<usize> = (0..columns.len())
+                .map(|i| rows.iter().map(|row| row[i].len()).chain([columns[i].len()]).max().unwrap_or(0))
+                .collect();
+            let line = |row: &[&str]| row.iter().zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>().join("  ").trim_end().to_string() + "\n";
+            out += &line(columns);
+            for row in &rows {
+                out += &line(&row.iter().map(String::as_str).collect::<Vec<_>>());
+            }
+        }
+    }
+    out
+}
+
+/// Where the report on the period starting at `start` goes: `glosco-report-2025-06-12.md`,
+/// or `glosco-report-2025-06-12T14.md` for hourly reports.
+pub fn file_name(settings: &ReportSettings, start: f64) -> PathBuf {
+    let stamp = timefmt::rfc3339(start);
+    let stamp = match settings.interval {
+        Interval::Daily => &stamp[..10],
+        Interval::Hourly => &stamp[..13],
+    };
+    settings.path.join(format!("glosco-report-{}.{}", stamp, settings.format.extension()))
+}
+
+/// Write the report on `start .. end` from `database` (or its shards) to `path`.
+pub fn write(settings: &ReportSettings, database: &str, path: &Path, start: f64, end: f64, now: f64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dbs = query::open_all(database)?;
+    let sections = match settings.sections.is_empty() {
+        true => Section::ALL,
+        false => &settings.sections,
+    };
+    let report = generate(&dbs, sections, start, end, now);
+    fs::create_dir_all(&settings.path)?;
+    // Written aside and renamed into place, so a report that's there is whole
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, render(&report, sections, settings.format))?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Keeps track, for maintenance, of the report being written and which it has tried.
+#[derive(Debug, Default)]
+pub struct Reporter {
+    writing: Arc<AtomicBool>,
+    /// Start of the last period a report was started for, so one that fails isn't retried
+    /// every tick.
+    tried: Option<f64>,
+}
+
+impl Reporter {
+    /// Start writing the report on the last whole period before `now` if there isn't one yet,
+    /// unless a report is being written already.
+    pub fn tick(&mut self, settings: &ReportSettings, database: &str, now: f64) {
+        let (start, end) = settings.interval.last(now);
+        let path = file_name(settings, start);
+        if self.tried == Some(start) || path.exists() || self.writing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        self.tried = Some(start);
+        let settings = settings.clone();
+        let database = database.to_string();
+        let writing = self.writing.clone();
+        thread::spawn(move || {
+            match write(&settings, &database, &path, start, end, now) {
+                Ok(()) => println!("report written to {}", path.display()),
+                Err(e) => println!("failed to write report {}: {}", path.display(), e),
+            }
+            writing.store(false, Ordering::Release);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::{BTreeSet, HashMap}, time::{Duration, SystemTime}};
+
+    use crate::{db, observe::{Closed, Message, Name, Problem, Protocol, Resolution}, server::{self, Importer}, test_support::{state, Random, Scratch}};
+
+    use super::*;
+
+    /// The day the reports are on, from midnight UTC starting 2025-06-12.
+    const START: f64 = 1_749_686_400.0;
+    const END: f64 = START + 24.0 * 3600.0;
+
+    /// One connection of the fixture: opened at `at` and closed some time later, or failed
+    /// then, or still open.
+    struct Fixture {
+        ident: &'static str,
+        srcport: u16,
+        dsthost: &'static str,
+        at: f64,
+        fate: Fate,
+    }
+
+    #[derive(Clone, Copy)]
+    enum Fate {
+        Open,
+        Closed(f64, Closed),
+        Failed,
+    }
+
+    impl Fixture {
+        fn messages(&self) -> Vec<Message> {
+            let at = |secs: f64| {
+                let mut state = state(&format!("10.1.0.1:{}", self.srcport), &format!("{}:443", self.dsthost), Protocol::Tcp);
+                state.as_of = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs);
+                state
+            };
+            match self.fate {
+                Fate::Open => vec![Message::Starting(at(self.at))],
+                Fate::Closed(closed, how) => vec![Message::Starting(at(self.at)), Message::Ended(at(closed), how)],
+                Fate::Failed => vec![Message::Failed(at(self.at), Problem { kind: 3, code: 1, repeats: 0 })],
+            }
+        }
+
+        /// When each of its rows is stored.
+        fn times(&self) -> Vec<f64> {
+            match self.fate {
+                Fate::Closed(closed, _) => vec![self.at, closed],
+                _ => vec![self.at],
+            }
+        }
+    }
+
+    const DSTHOSTS: [&str; 6] = ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4", "10.0.0.5", "10.0.0.6"];
+
+    /// 400 connections of `north` and `south` over the day and two hours either side, each of
+    /// which was seen earlier still; `newcomer`'s first ones during it; and `later`'s after it.
+    fn connections() -> Vec<Fixture> {
+        let mut random = Random::new(0x5eed_1242);
+        let mut fixtures = Vec::new();
+        for srcport in 10000 .. 10400 {
+            let ident = ["north", "south"][random.below(2) as usize];
+            // Skewed, so some destinations are busier than others
+            let dsthost = DSTHOSTS[(random.below(6) * random.below(6) / 5) as usize];
+            let at = START - 2.0 * 3600.0 + random.below(28 * 3600) as f64 + 0.25;
+            let fate = match random.below(4) {
+                0 => Fate::Open,
+                1 => Fate::Closed(at + 1.0 + random.below(7200) as f64, Closed::Normally),
+                2 => Fate::Closed(at + 1.0 + random.below(7200) as f64, Closed::Reset),
+                _ => Fate::Failed,
+            };
+            fixtures.push(Fixture { ident, srcport, dsthost, at, fate });
+        }
+        for (srcport, ident, at) in [(20000, "north", START - 3.0 * 3600.0), (20001, "south", START - 3.0 * 3600.0)] {
+            fixtures.push(Fixture { ident, srcport, dsthost: "10.0.0.6", at, fate: Fate::Open });
+        }
+        for srcport in 20100 .. 20110 {
+            let at = START + 5.0 * 3600.0 + srcport as f64;
+            fixtures.push(Fixture { ident: "newcomer", srcport, dsthost: "10.0.0.5", at, fate: Fate::Closed(at + 30.0, Closed::Normally) });
+        }
+        fixtures.push(Fixture { ident: "later", srcport: 20200, dsthost: "10.0.0.1", at: END + 3600.0, fate: Fate::Open });
+        fixtures
+    }
+
+    /// A name record for `addr` stored at `at`, as a sensor's lookup would leave.
+    fn name(name: &str, addr: &str, at: f64) -> Message {
+        let mut state = state("10.0.0.53:53", "10.1.0.1:5353", Protocol::Udp);
+        state.as_of = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(at);
+        Message::Name(state, vec![Name { name: name.to_string(), address: Some(Resolution::Address(addr.parse().unwrap())) }])
+    }
+
+    /// `fixtures` stored as a collector would have, with hourly summaries brought up to date.
+    fn store(scratch: &Scratch, fixtures: &[Fixture], names: &[Message]) -> rusqlite::Connection {
+        let mut importer = Importer::open(scratch.database()).unwrap();
+        for fixture in fixtures {
+            importer.store(fixture.ident, "127.0.0.1:40000", &fixture.messages()).unwrap();
+        }
+        for name in names {
+            importer.store("north", "127.0.0.1:40000", std::slice::from_ref(name)).unwrap();
+        }
+        drop(importer);
+        let db = db::open(scratch.database()).unwrap();
+        server::summarize(&db, END).unwrap();
+        db
+    }
+
+    fn within(at: f64) -> bool {
+        (START .. END).contains(&at)
+    }
+
+    #[test]
+    fn a_days_numbers_are_what_the_connections_add_up_to() {
+        let scratch = Scratch::new("numbers");
+        let fixtures = connections();
+        let db = store(&scratch, &fixtures, &[
+            // The newer of two that hold at the end of the day
+            name("old.one.example", "10.0.0.1", START + 100.0),
+            name("one.example", "10.0.0.1", START + 5000.0),
+            // Gone stale by the end of the day, and not stored until after it
+            name("two.example", "10.0.0.2", START - 100.0),
+            name("three.example", "10.0.0.3", END + 50.0),
+        ]);
+        let report = generate(&[db], Section::ALL, START, END, END + 60.0);
+
+        // Each destination's connections with a row stored in the day, busiest first
+        let mut counted: HashMap<&str, BTreeSet<u16>> = HashMap::new();
+        for fixture in fixtures.iter().filter(|fixture| fixture.times().into_iter().any(within)) {
+            counted.entry(fixture.dsthost).or_default().insert(fixture.srcport);
+        }
+        let mut expected: Vec<(String, u64)> = counted.into_iter().map(|(host, ports)| (host.to_string(), ports.len() as u64)).collect();
+        expected.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let Some(Part::Rows(destinations)) = &report.destinations else {
+            panic!("no destinations: {:?}", report.destinations);
+        };
+        assert_eq!(destinations.iter().map(|row| (row.dsthost.clone(), row.connections)).collect::<Vec<_>>(), expected);
+        let names: BTreeMap<&str, Option<&str>> = destinations.iter().map(|row| (row.dsthost.as_str(), row.name.as_deref())).collect();
+        assert_eq!(names["10.0.0.1"], Some("one.example"));
+        assert_eq!((names["10.0.0.2"], names["10.0.0.3"], names["10.0.0.4"]), (None, None, None));
+
+        let Some(Part::Rows(new_idents)) = &report.new_idents else {
+            panic!("no new idents: {:?}", report.new_idents);
+        };
+        assert_eq!(new_idents.iter().map(|client| client.ident.as_str()).collect::<Vec<_>>(), ["newcomer"]);
+        let Some(Part::Rows(health)) = &report.health else {
+            panic!("no health: {:?}", report.health);
+        };
+        assert_eq!(health.iter().map(|client| client.ident.as_str()).collect::<Vec<_>>(), ["later", "newcomer", "north", "south"]);
+
+        // Starts and failures stored in the day, of each ident with anything stored in it
+        let mut failures: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+        for fixture in fixtures.iter() {
+            if !fixture.times().into_iter().any(within) {
+                continue;
+            }
+            let (opened, failed) = failures.entry(fixture.ident).or_default();
+            match fixture.fate {
+                Fate::Failed => *failed += 1,
+                _ if within(fixture.at) => *opened += 1,
+                _ => (),
+            }
+        }
+        let mut expected: Vec<(String, u64, u64)> = failures.into_iter().map(|(ident, (opened, failed))| (ident.to_string(), opened, failed)).collect();
+        expected.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        let Some(Part::Rows(rows)) = &report.failures else {
+            panic!("no failures: {:?}", report.failures);
+        };
+        assert_eq!(rows.iter().map(|row| (row.ident.clone(), row.opened, row.failed)).collect::<Vec<_>>(), expected);
+
+        // Sessions overlapping the day, as far as rows up to its end tell: a close stored after
+        // it leaves its session open as of its start
+        let mut sessions: BTreeMap<&str, (Sessions, Vec<f64>)> = BTreeMap::new();
+        for fixture in fixtures.iter().filter(|fixture| fixture.at <= END) {
+            let (ending, end) = match fixture.fate {
+                Fate::Closed(closed, how) if closed <= END => (if how == Closed::Reset { Ending::Reset } else { Ending::Ended }, closed),
+                Fate::Failed => (Ending::Failed, fixture.at),
+                _ => (Ending::Open, fixture.at),
+            };
+            if end < START {
+                continue;
+            }
+            let (counts, durations) = sessions.entry(fixture.ident).or_default();
+            counts.sessions += 1;
+            *match ending {
+                Ending::Ended => &mut counts.ended,
+                Ending::Reset => &mut counts.reset,
+                Ending::Failed => &mut counts.failed,
+                _ => &mut counts.open,
+            } += 1;
+            durations.push(end - fixture.at);
+        }
+        let Some(Part::Rows(rows)) = &report.sessions else {
+            panic!("no sessions: {:?}", report.sessions);
+        };
+        assert_eq!(rows.len(), sessions.len());
+        for (row, (ident, (counts, mut durations))) in rows.iter().zip(sessions) {
+            durations.sort_by(f64::total_cmp);
+            assert_eq!(
+                (row.ident.as_str(), row.sessions, row.ended, row.reset, row.failed, row.timeout, row.reopened, row.open),
+                (ident, counts.sessions, counts.ended, counts.reset, counts.failed, 0, 0, counts.open),
+            );
+            assert_eq!(row.median_duration, Some(durations[durations.len() / 2]), "{}", ident);
+        }
+        // The fixture has enough of everything for the numbers to mean something
+        assert!(rows.iter().all(|row| row.ended > 5 && row.reset > 5 && row.failed > 5 && row.open > 5 || row.ident == "newcomer"), "{:?}", rows);
+
+        // And they're what the file says
+        let text = render(&report, Section::ALL, Format::Markdown);
+        for row in destinations {
+            let line = format!("| {} | {} | {} |\n", row.dsthost, row.name.clone().unwrap_or_default(), row.connections);
+            assert!(text.contains(&line), "{}", text);
+        }
+    }
+
+    #[test]
+    fn a_report_goes_ahead_without_names_or_summaries() {
+        let scratch = Scratch::new("bare");
+        let fixtures = connections();
+        let mut importer = Importer::open(scratch.database()).unwrap();
+        for fixture in &fixtures {
+            importer.store(fixture.ident, "127.0.0.1:40000", &fixture.messages()).unwrap();
+        }
+        drop(importer);
+        let db = db::open(scratch.database()).unwrap();
+        let report = generate(&[db], Section::ALL, START, END, END + 60.0);
+
+        // No names on record for anything, and the hours not summed up yet
+        let Some(Part::Rows(destinations)) = &report.destinations else {
+            panic!("no destinations: {:?}", report.destinations);
+        };
+        assert_eq!(destinations.len(), DSTHOSTS.len());
+        assert!(destinations.iter().all(|row| row.name.is_none()), "{:?}", destinations);
+        assert!(matches!(&report.failures, Some(Part::Rows(rows)) if rows.is_empty()), "{:?}", report.failures);
+        for format in [Format::Text, Format::Markdown] {
+            let text = render(&report, Section::ALL, format);
+            assert!(text.contains("Failures\n") && text.contains("\nNone.\n"), "{}", text);
+            assert!(!text.contains("Unavailable"), "{}", text);
+        }
+
+        // A database without the tables at all has each section say so, and still gets a report
+        let empty = Scratch::new("empty");
+        let report = generate(&[db::open(empty.database()).unwrap()], Section::ALL, START, END, END + 60.0);
+        assert!(matches!(report.destinations, Some(Part::Unavailable { .. })), "{:?}", report.destinations);
+        assert!(matches!(report.failures, Some(Part::Unavailable { .. })), "{:?}", report.failures);
+        let text = render(&report, &[Section::Failures], Format::Markdown);
+        assert!(text.starts_with("# glosco report, 2025-06-12T00:00:00Z to 2025-06-13T00:00:00Z\n"), "{}", text);
+        assert!(text.contains("## Failures\n\nUnavailable: no such table: summary_hourly\n"), "{}", text);
+    }
+
+    #[test]
+    fn only_the_sections_asked_for_are_written_and_the_file_is_named_for_its_period() {
+        let scratch = Scratch::new("write");
+        drop(store(&scratch, &connections(), &[]));
+        let dir = Scratch::dir("reports");
+        let settings = ReportSettings {
+            path: dir.path().to_path_buf(),
+            interval: Interval::Daily,
+            format: Format::Json,
+            sections: vec![Section::NewIdents, Section::Failures],
+        };
+        let path = file_name(&settings, START);
+        assert_eq!(path, dir.path().join("glosco-report-2025-06-12.json"));
+        write(&settings, scratch.database(), &path, START, END, END + 60.0).unwrap();
+
+        let written: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let keys: Vec<&str> = written.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(keys, ["end", "failures", "generated", "new_idents", "start"]);
+        assert_eq!(written["start"], "2025-06-12T00:00:00.000000Z");
+        assert_eq!(written["new_idents"][0]["ident"], "newcomer");
+        // Nothing left aside
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
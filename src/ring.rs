@@ -0,0 +1,327 @@
+//! The raw frames a sensor captured last, kept per interface for a while so that when something
+//! suspicious turns up, the packets behind it can be written out as pcap files without saving
+//! every capture all the time.
+//!
+//! The rings belong to the observer and are only touched by the thread handling packets, so
+//! keeping them takes no locks; a frame's buffer moves in as it came, and a flush shares them
+//! with a thread of its own that writes them out while capture carries on.
+
+use std::{collections::VecDeque, fs::{self, File}, io::{self, BufWriter, Write}, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::{Duration, Instant, SystemTime}};
+
+use pcap::Linktype;
+use serde::Deserialize;
+
+use crate::{alert::Rule, observe::{Ingress, Message}, timefmt};
+
+/// How much recent traffic to keep, where it's written, and what has it written. Field names
+/// double as the keys of the client config file's `[ring]` table.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RingSettings {
+    /// Directory flushes write their pcap files to, one per interface with frames to write.
+    pub dir: PathBuf,
+    /// Seconds of capture time each interface's ring goes back at most.
+    pub seconds: f64,
+    /// Megabytes of frames each interface's ring holds at most.
+    pub megabytes: f64,
+    /// Flush whenever an observed message matches one of these, written as alert rules are,
+    /// each at most once a window. SIGUSR2 flushes too, whatever these say.
+    pub triggers: Vec<Rule>,
+}
+
+impl Default for RingSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("."),
+            seconds: 30.0,
+            megabytes: 64.0,
+            triggers: Vec::new(),
+        }
+    }
+}
+
+/// One captured frame, shared with whatever flush is writing it out.
+#[derive(Debug, Clone)]
+struct Frame {
+    time: SystemTime,
+    data: Arc<Vec<u8>>,
+}
+
+/// One interface's ring, oldest frame first.
+#[derive(Debug)]
+struct Frames {
+    link: Linktype,
+    frames: VecDeque<Frame>,
+    bytes: usize,
+}
+
+/// The recent frames of every interface, bounded by time and size.
+#[derive(Debug)]
+pub struct Ring {
+    dir: PathBuf,
+    window: Duration,
+    capacity: usize,
+    /// By interface index; an interface has none until its first frame.
+    interfaces: Vec<Option<Frames>>,
+}
+
+impl Ring {
+    pub fn new(settings: &RingSettings) -> Self {
+        Self {
+            dir: settings.dir.clone(),
+            window: Duration::from_secs_f64(settings.seconds),
+            capacity: (settings.megabytes * 1e6) as usize,
+            interfaces: Vec::new(),
+        }
+    }
+
+    /// Keep a frame handled, dropping whatever it pushes past the ring's bounds.
+    pub fn push(&mut self, ingress: Ingress) {
+        if self.interfaces.len() <= ingress.interface {
+            self.interfaces.resize_with(ingress.interface + 1, || None);
+        }
+        let ring = self.interfaces[ingress.interface].get_or_insert_with(|| Frames {
+            link: ingress.link,
+            frames: VecDeque::new(),
+            bytes: 0,
+        });
+        ring.bytes += ingress.data.len();
+        ring.frames.push_back(Frame { time: ingress.time, data: Arc::new(ingress.data) });
+        while let Some(oldest) = ring.frames.front() {
+            let stale = ingress.time.duration_since(oldest.time).is_ok_and(|age| age > self.window);
+            if !stale && ring.bytes <= self.capacity {
+                break;
+            }
+            ring.bytes -= oldest.data.len();
+            ring.frames.pop_front();
+        }
+    }
+
+    /// Write every interface's ring out as it is now, one pcap file apiece named for `now` and
+    /// the interface (`names`, by index), on a thread of its own. The rings are left as they
+    /// were. The thread says how it went and gives the files written.
+    pub fn flush(&self, names: &[String], why: &str, now: SystemTime) -> thread::JoinHandle<io::Result<Vec<PathBuf>>> {
+        let stamp = now.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| timefmt::rfc3339(since.as_secs_f64()).replace(':', ""))
+            .unwrap_or_default();
+        let rings: Vec<(PathBuf, Linktype, Vec<Frame>)> = self.interfaces.iter().enumerate()
+            .filter_map(|(idx, ring)| ring.as_ref().map(|ring| (idx, ring)))
+            .filter(|(_, ring)| !ring.frames.is_empty())
+            .map(|(idx, ring)| {
+                let name = names.get(idx).map(|name| file_safe(name)).unwrap_or_else(|| idx.to_string());
+                let path = self.dir.join(format!("glosco-ring-{}-{}.pcap", stamp, name));
+                (path, ring.link, ring.frames.iter().cloned().collect())
+            })
+            .collect();
+        let dir = self.dir.clone();
+        let why = why.to_string();
+        thread::spawn(move || {
+            let written = fs::create_dir_all(&dir).and_then(|()| rings.iter()
+                .map(|(path, link, frames)| write_pcap(path, *link, frames).map(|()| path.clone()))
+                .collect::<io::Result<Vec<_>>>());
+            match &written {
+                Ok(paths) => println!("ring: flushed for {} to {:?}", why, paths),
+                Err(e) => println!("ring: failed to flush for {}: {}", why, e),
+            }
+            written
+        })
+    }
+}
+
+/// An interface's name as it can go in a file name; capture files are named by their paths.
+fn file_safe(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect()
+}
+
+/// Write `frames` as a classic pcap file, with microsecond timestamps. Written aside and
+/// renamed into place, so a file that's there is whole.
+fn write_pcap(path: &PathBuf, link: Linktype, frames: &[Frame]) -> io::Result<()> {
+    const MAGIC: u32 = 0xa1b2c3d4;
+    const SNAPLEN: u32 = 262144;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    out.write_all(&MAGIC.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    // Timezone offset and timestamp accuracy, both always 0
+    out.write_all(&[0; 8])?;
+    out.write_all(&SNAPLEN.to_le_bytes())?;
+    out.write_all(&(link.0 as u32).to_le_bytes())?;
+    for frame in frames {
+        let since = frame.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        out.write_all(&(since.as_secs() as u32).to_le_bytes())?;
+        out.write_all(&since.subsec_micros().to_le_bytes())?;
+        // Only what was captured is kept, so that's the length on the wire as far as we know
+        let len = frame.data.len() as u32;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&frame.data)?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&partial, path)
+}
+
+/// Which of `RingSettings::triggers` a message sets off, holding each off for its window
+/// after it fires.
+#[derive(Debug)]
+pub struct Triggers {
+    rules: Vec<Rule>,
+    fired: Vec<Option<Instant>>,
+}
+
+impl Triggers {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let fired = vec![None; rules.len()];
+        Self { rules, fired }
+    }
+
+    /// The first rule `message`, observed by `ident`, sets off, if any isn't holding off.
+    pub fn fire(&mut self, ident: &str, message: &Message) -> Option<&Rule> {
+        let now = Instant::now();
+        let idx = self.rules.iter().zip(self.fired.iter())
+            .position(|(rule, fired)| rule.matches(ident, message)
+                && fired.is_none_or(|fired| now.duration_since(fired) >= rule.window()))?;
+        self.fired[idx] = Some(now);
+        Some(&self.rules[idx])
+    }
+}
+
+/// Set by the SIGUSR2 handler, cleared by `flush_requested`.
+static FLUSH: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_flush(_: libc::c_int) {
+    FLUSH.store(true, Ordering::Relaxed);
+}
+
+/// Have SIGUSR2 ask for a flush, rather than end the process.
+pub fn catch_flush_signal() {
+    #[cfg(unix)]
+    // Safety: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGUSR2, on_flush as *const () as libc::sighandler_t);
+    }
+}
+
+/// Whether SIGUSR2 has come since this was last asked.
+pub fn flush_requested() -> bool {
+    FLUSH.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use pcap::Capture;
+
+    use crate::{alert::Kind, observe::{Closed, Connection, Endpoint, Problem, Protocol, State}};
+
+    use super::*;
+
+    fn at(millis: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000) + Duration::from_millis(millis)
+    }
+
+    /// Frame `n` of `interface`: `len` bytes of `n`, captured `n` tenths of a second in.
+    fn frame(interface: usize, n: u8, len: usize) -> Ingress {
+        Ingress { data: vec![n; len], interface, link: Linktype::ETHERNET, time: at(n as u64 * 100) }
+    }
+
+    /// A scratch directory of the test's own, emptied first.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("glosco-ring-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn ring(dir: &Path, seconds: f64, megabytes: f64) -> Ring {
+        Ring::new(&RingSettings { dir: dir.to_path_buf(), seconds, megabytes, triggers: Vec::new() })
+    }
+
+    /// Every frame of the pcap file at `path`: its time and what it held.
+    fn read(path: &Path) -> Vec<(SystemTime, Vec<u8>)> {
+        let mut cap = Capture::from_file(path).unwrap();
+        assert_eq!(cap.get_datalink(), Linktype::ETHERNET);
+        let mut frames = Vec::new();
+        while let Ok(packet) = cap.next_packet() {
+            let time = SystemTime::UNIX_EPOCH + Duration::new(packet.header.ts.tv_sec as u64, packet.header.ts.tv_usec as u32 * 1000);
+            frames.push((time, packet.data.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn a_ring_filled_past_capacity_flushes_its_newest_frames_in_order() {
+        let dir = scratch("capacity");
+        // Room for three 400 byte frames
+        let mut ring = ring(&dir, 60.0, 0.0013);
+        for n in 0 .. 10 {
+            ring.push(frame(0, n, 400));
+        }
+        let written = ring.flush(&["eth0".to_string()], "a test", at(5000)).join().unwrap().unwrap();
+        assert_eq!(written.len(), 1);
+        assert!(written[0].file_name().unwrap().to_string_lossy().ends_with("-eth0.pcap"), "{:?}", written);
+        assert_eq!(read(&written[0]), (7 .. 10).map(|n| (at(n as u64 * 100), vec![n; 400])).collect::<Vec<_>>());
+        // Nothing's left half written
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frames_older_than_the_window_are_dropped() {
+        let dir = scratch("window");
+        let mut ring = ring(&dir, 0.25, 64.0);
+        for n in 0 .. 10 {
+            ring.push(frame(0, n, 60));
+        }
+        let written = ring.flush(&["eth0".to_string()], "a test", at(5000)).join().unwrap().unwrap();
+        // 700ms and 800ms are within a quarter second of 900ms; 600ms isn't
+        let kept: Vec<u8> = read(&written[0]).into_iter().map(|(_, data)| data[0]).collect();
+        assert_eq!(kept, [7, 8, 9]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn each_interface_is_a_file_of_its_own_and_flushing_leaves_the_ring_be() {
+        let dir = scratch("interfaces");
+        let mut ring = ring(&dir, 60.0, 64.0);
+        ring.push(frame(0, 1, 60));
+        ring.push(frame(2, 2, 60));
+        ring.push(frame(0, 3, 60));
+        let names = ["eth0".to_string(), "eth1".to_string(), "/tmp/odd name.pcap".to_string()];
+        let mut written = ring.flush(&names, "a test", at(5000)).join().unwrap().unwrap();
+        written.sort();
+        let files: Vec<String> = written.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        // Nothing captured on eth1, so no file for it; a capture file's path is made safe
+        assert!(files[0].ends_with("-_tmp_odd_name.pcap.pcap") && files[1].ends_with("-eth0.pcap"), "{:?}", files);
+        assert_eq!(read(&written[1]).into_iter().map(|(_, data)| data[0]).collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(read(&written[0]).into_iter().map(|(_, data)| data[0]).collect::<Vec<_>>(), [2]);
+
+        // A second flush later writes the same frames again
+        let again = ring.flush(&names, "a test", at(6000)).join().unwrap().unwrap();
+        assert_eq!(again.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_trigger_fires_once_a_window() {
+        let state = State {
+            as_of: at(0),
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: "10.0.0.1".parse().unwrap(), port: 40000 },
+                dst: Endpoint { addr: "10.0.0.2".parse().unwrap(), port: 443 },
+                protocol: Protocol::Tcp,
+            },
+            rtt_micros: None,
+        };
+        let failed = Message::Failed(state, Problem { kind: 3, code: 1, repeats: 0 });
+        let mut triggers = Triggers::new(vec![Rule::new([Kind::Failed]).port(443), Rule::new([Kind::Failed]).with_window(Duration::ZERO)]);
+        assert!(triggers.fire("sensor", &Message::Ended(state, Closed::Reset)).is_none());
+        assert_eq!(triggers.fire("sensor", &failed).map(Rule::window), Some(Rule::WINDOW));
+        // The first is holding off, so the second has it
+        assert_eq!(triggers.fire("sensor", &failed).map(Rule::window), Some(Duration::ZERO));
+        assert_eq!(triggers.fire("sensor", &failed).map(Rule::window), Some(Duration::ZERO));
+    }
+}
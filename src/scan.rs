@@ -0,0 +1,197 @@
+//! Port scans, told apart from ordinary traffic by how many distinct ports or hosts one source
+//! tries in a short while, and reported as one message instead of a connection apiece.
+
+use std::{collections::HashMap, hash::Hash, net::{IpAddr, Ipv4Addr, Ipv6Addr}, time::{Duration, SystemTime}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::observe::{Closed, Connection, Endpoint, Message, Protocol, State};
+
+/// Which way a scan went, which says what its message's destination stands for.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ScanKind {
+    /// Many ports on one host: the destination is that host, with port 0.
+    Ports,
+    /// One port on many hosts: the destination is that port, on the unspecified address.
+    Hosts,
+}
+
+impl ScanKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ports => "ports",
+            Self::Hosts => "hosts",
+        }
+    }
+}
+
+/// A scan found: how many distinct ports or hosts the source tried within `window`.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Scan {
+    pub kind: ScanKind,
+    pub count: u32,
+    pub window: Duration,
+}
+
+/// When a source's connection attempts make a scan, and what's done about the messages that
+/// make it up. Field names double as the keys of the client config file's `[scan]` table.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanSettings {
+    /// Distinct ports one source may try on one host within the window before it's a scan.
+    pub ports: u32,
+    /// Distinct hosts one source may try on one port within the window before it's a scan.
+    pub hosts: u32,
+    /// Seconds the window covers; a scan that goes on is reported again once per window.
+    pub window: u64,
+    /// Leave out the attempts, resets and ICMP errors of a scan once it's been reported.
+    pub suppress: bool,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        Self {
+            ports: 100,
+            hosts: 100,
+            window: 30,
+            suppress: false,
+        }
+    }
+}
+
+/// What one source has tried of one host's ports, or of one port's hosts.
+#[derive(Debug)]
+struct Tried<T> {
+    /// When each was last tried.
+    seen: HashMap<T, SystemTime>,
+    /// When it was last reported as a scan; until it goes quiet for a window, if it has been.
+    reported: Option<SystemTime>,
+}
+
+impl<T: Hash + Eq> Tried<T> {
+    fn new() -> Self {
+        Self { seen: HashMap::new(), reported: None }
+    }
+
+    /// Note `item` tried at `now`, giving how many were tried within `window` if that's
+    /// `threshold` or more and it's not been reported within the window.
+    fn note(&mut self, item: T, now: SystemTime, window: Duration, threshold: u32) -> Option<u32> {
+        self.seen.insert(item, now);
+        if self.seen.len() < threshold as usize || self.reported.is_some_and(|at| !expired(at, now, window)) {
+            return None;
+        }
+        self.seen.retain(|_, at| !expired(*at, now, window));
+        let count = self.seen.len() as u32;
+        if count < threshold {
+            return None;
+        }
+        self.reported = Some(now);
+        Some(count)
+    }
+
+    /// Forget what was tried too long before `now`, returning whether anything's left.
+    fn prune(&mut self, now: SystemTime, window: Duration) -> bool {
+        self.seen.retain(|_, at| !expired(*at, now, window));
+        !self.seen.is_empty()
+    }
+}
+
+/// Whether more than `window` has passed since `at`; never, if `at` is after `now`.
+pub(crate) fn expired(at: SystemTime, now: SystemTime, window: Duration) -> bool {
+    now.duration_since(at).is_ok_and(|since| since > window)
+}
+
+/// Counts the distinct ports each source tries per host and hosts it tries per port, over a
+/// sliding window of capture time, and reports each that passes its threshold as a scan.
+///
+/// A TCP SYN is an attempt. The messages a scan is made of from before it's found are reported
+/// as usual; suppression only starts with the Scan message.
+#[derive(Debug)]
+pub struct ScanDetector {
+    settings: ScanSettings,
+    window: Duration,
+    by_host: HashMap<(IpAddr, IpAddr), Tried<u16>>,
+    by_port: HashMap<(IpAddr, u16), Tried<IpAddr>>,
+    last_pruned: SystemTime,
+}
+
+impl ScanDetector {
+    /// How much capture time passes between sweeps for sources that have gone quiet.
+    const PRUNE_EVERY: Duration = Duration::from_secs(1);
+
+    pub fn new(settings: ScanSettings) -> Self {
+        Self {
+            window: Duration::from_secs(settings.window),
+            settings,
+            by_host: HashMap::new(),
+            by_port: HashMap::new(),
+            last_pruned: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    /// Note the attempt `state` opens, giving a Scan message for each scan it makes, or makes
+    /// again after a window.
+    pub fn attempt(&mut self, state: &State) -> Vec<Message> {
+        let conn = state.connection;
+        let now = state.as_of;
+        self.prune(now);
+        let (src, dst) = (conn.src.addr, conn.dst.addr);
+        let mut found = Vec::new();
+        let by_host = self.by_host.entry((src, dst)).or_insert_with(Tried::new);
+        if let Some(count) = by_host.note(conn.dst.port, now, self.window, self.settings.ports) {
+            found.push(self.message(&conn, now, Endpoint { addr: dst, port: 0 }, ScanKind::Ports, count));
+        }
+        let by_port = self.by_port.entry((src, conn.dst.port)).or_insert_with(Tried::new);
+        if let Some(count) = by_port.note(dst, now, self.window, self.settings.hosts) {
+            let anywhere = match dst {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            found.push(self.message(&conn, now, Endpoint { addr: anywhere, port: conn.dst.port }, ScanKind::Hosts, count));
+        }
+        found
+    }
+
+    fn message(&self, attempt: &Connection, now: SystemTime, dst: Endpoint, kind: ScanKind, count: u32) -> Message {
+        let connection = Connection {
+            interface: attempt.interface,
+            src: Endpoint { addr: attempt.src.addr, port: 0 },
+            dst,
+            protocol: Protocol::Tcp,
+        };
+        Message::Scan(State { as_of: now, connection, rtt_micros: None }, Scan { kind, count, window: self.window })
+    }
+
+    /// Whether `message` belongs to a scan that's been reported, if those are to be suppressed:
+    /// the scanner's attempts, resets either way, and ICMP errors sent back to it.
+    pub fn suppresses(&self, message: &Message) -> bool {
+        if !self.settings.suppress {
+            return false;
+        }
+        let conn = message.state().connection;
+        let (src, dst) = (conn.src, conn.dst);
+        match message {
+            Message::Starting(_) => self.scanning(src.addr, dst.addr, dst.port),
+            Message::Ended(_, Closed::Reset) => self.scanning(src.addr, dst.addr, dst.port) || self.scanning(dst.addr, src.addr, src.port),
+            // The error comes from the target (or a router) with the attempt's own ports
+            Message::Failed(_, _) => self.scanning(dst.addr, src.addr, dst.port),
+            _ => false,
+        }
+    }
+
+    fn scanning(&self, src: IpAddr, dst: IpAddr, port: u16) -> bool {
+        self.by_host.get(&(src, dst)).is_some_and(|tried| tried.reported.is_some())
+            || self.by_port.get(&(src, port)).is_some_and(|tried| tried.reported.is_some())
+    }
+
+    /// Forget sources that have tried nothing within the window, and their scans with them.
+    fn prune(&mut self, now: SystemTime) {
+        if !expired(self.last_pruned, now, Self::PRUNE_EVERY) {
+            return;
+        }
+        self.last_pruned = now;
+        let window = self.window;
+        self.by_host.retain(|_, tried| tried.prune(now, window));
+        self.by_port.retain(|_, tried| tried.prune(now, window));
+    }
+}
@@ -0,0 +1,3617 @@
+use std::{cell::OnceCell, collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}, fs, io, net::{IpAddr, SocketAddr, SocketAddrV4, Ipv4Addr, TcpListener, TcpStream}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc, Arc, Mutex, RwLock}, thread, time::{Duration, SystemTime}};
+
+use rusqlite::{params, types::Null, named_params, OptionalExtension, TransactionBehavior};
+use serde::Deserialize;
+
+use crate::alert::{self, Alerter, Callback, Callbacks, Rule};
+use crate::api::{ApiConfig, Heartbeat, Ingest};
+use crate::changes::Changes;
+use crate::cli::ServerArgs;
+use crate::daemon;
+use crate::db;
+use crate::coding::{Coder, HELLO_MARK, SUBSCRIBE_MARK, RELAYED_MARK, SNAPSHOT_MARK, SEQUENCE_MARK, STATS_MARK, NAMESPACE_MARK, TMOUT_MARK, CodingVec, START_MARK, ACTIVE_MARK, ENDED_MARK, FAILED_MARK};
+use crate::eventlog::{Event, EventLog, EventLogConfig};
+use crate::forward::{Forwarder, Target};
+use crate::observe::{Closed, Connection, Message, Namespace, Protocol, Snapshot, Stats};
+use crate::partition::{self, Partitions};
+use crate::query::{self, protocol_name, reversed_name};
+use crate::shard::{self, Shards};
+use crate::rdns::{ReverseDns, ReverseDnsConfig};
+use crate::report::{self, Reporter};
+use crate::settings;
+use crate::scan::ScanKind;
+use crate::subscribe::{self, Broadcast, Subscribe};
+use crate::sync::{Client, ClientConfig, Hello, Relayed, Sequence};
+use crate::view::{MessageRef, ResolutionRef};
+use crate::geoip::Location;
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+
+#[cfg(feature = "async-server")]
+mod async_io;
+mod ingest;
+mod local;
+#[cfg(feature = "mesh")]
+mod mesh;
+mod remote;
+mod replica;
+
+pub use local::LocalStore;
+pub use crate::settings::SettingsError;
+
+/// Everything the collector needs to run, resolved from the config file and command line.
+///
+/// Field names double as the config file's keys; unknown keys are rejected so typos don't
+/// silently fall back to defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind: SocketAddr,
+    /// Database file, directory of them when sharding by ident, or `:memory:` for a database
+    /// that lasts only as long as the process.
+    pub database: String,
+    /// Keep a copy of the in-memory database here, written every `snapshot_interval` seconds
+    /// and on shutdown, and start from it if it's there.
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: f64,
+    /// Seconds without news after which an open TCP connection is assumed closed.
+    pub tcp_timeout: f64,
+    /// Seconds without news after which a UDP flow still open is assumed closed.
+    pub udp_timeout: f64,
+    /// Seconds between maintenance ticks.
+    pub maintenance: f64,
+    /// Insert a row for every keepalive rather than refreshing the open Active row.
+    pub append_only: bool,
+    /// Store each UTC day's state rows in a table of its own (`state_20250612`), with `state_all`
+    /// unioning them. A database converted once stays partitioned.
+    pub partition: bool,
+    /// Seconds state rows are kept before maintenance expires them; forever if not given.
+    pub retention: Option<f64>,
+    /// Seconds name records are kept before maintenance expires them; forever if not given.
+    pub names_retention: Option<f64>,
+    /// Bytes the database (in use, plus its WAL) may take up before maintenance evicts the
+    /// oldest state to get back under it.
+    pub max_db_size: Option<u64>,
+    /// Treat `database` as a directory and keep each ident's rows in a file of its own there
+    /// (`glosco-<ident>.db`), so one site can be archived or deleted on its own. Retention and
+    /// the size cap then apply to each file separately.
+    pub shard_by_ident: bool,
+    /// Shard files kept open between uses when sharding by ident; the least recently used are
+    /// closed past this.
+    pub shard_handles: usize,
+    /// What to do when a second peer connects under an ident that's already connected.
+    pub ident_collision: CollisionPolicy,
+    /// Turn away legacy clients, from before hellos, rather than accepting them and marking them
+    /// legacy in the clients table.
+    pub reject_legacy: bool,
+    /// Threads handling client connections; each holds one connection at a time, for as long as
+    /// it stays connected.
+    pub workers: usize,
+    /// Accepted connections allowed to wait for a free worker before more are refused.
+    pub pending: usize,
+    /// Read clients on a tokio runtime rather than a worker thread apiece (needs the async-server
+    /// cargo feature); `workers` and `pending` then don't apply.
+    pub async_io: bool,
+    /// Seconds a message's timestamp may differ from the time it arrived before `skew_policy` applies.
+    pub max_skew: f64,
+    pub skew_policy: SkewPolicy,
+    pub forward: Vec<Target>,
+    /// Collectors to pass every accepted message on to, under the ident that reported it.
+    pub relay: Vec<SocketAddr>,
+    /// Primary collector to follow as a warm standby, storing everything it accepts as well.
+    pub replicate: Option<SocketAddr>,
+    /// Join a mesh of sensors (needs the mesh cargo feature), listening for mesh peers here and
+    /// dialing `mesh_peers`, and store every frame passed around it as though its sensor had
+    /// connected here.
+    pub mesh_listen: Vec<SocketAddr>,
+    pub mesh_peers: Vec<SocketAddr>,
+    /// Keep the mesh peers found or dialed here, to dial again after a restart, forgetting any
+    /// not linked to for `mesh_peer_horizon` seconds.
+    pub mesh_peer_file: Option<PathBuf>,
+    pub mesh_peer_horizon: f64,
+    /// Answer queries sent over the client port by connections presenting this token; remote
+    /// queries are refused if it isn't set.
+    pub remote_query_token: Option<String>,
+    /// Most rows a remote query gets back, whatever limit it asked for.
+    pub remote_query_limit: usize,
+    /// MaxMind databases to look up destination country and ASN in (needs the geoip cargo feature).
+    /// Reopened on SIGHUP, and within a minute of a file being replaced.
+    pub geoip: Vec<PathBuf>,
+    pub event_log: Option<EventLogSettings>,
+    pub rdns: Option<RdnsSettings>,
+    pub alerts: Option<AlertSettings>,
+    /// Learn where each ident connects to and flag destinations it hasn't used before.
+    pub baseline: Option<BaselineSettings>,
+    pub api: Option<ApiSettings>,
+    pub report: Option<ReportSettings>,
+}
+
+/// How to handle a client claiming an ident that another address already holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CollisionPolicy {
+    /// Drop the newcomer's connection.
+    Reject,
+    /// Accept it anyway, logging that the rows will interleave.
+    #[default]
+    Warn,
+    /// Accept it as `ident#2` (or `#3`, ...), whichever is free.
+    Suffix,
+}
+
+/// What to do with a message whose timestamp is too far from the collector's clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SkewPolicy {
+    /// Store it as of its arrival, keeping the reported time in `reported_conntime`.
+    #[default]
+    Clamp,
+    /// Drop it.
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EventLogSettings {
+    pub path: PathBuf,
+    pub max_bytes: Option<u64>,
+    /// Seconds.
+    pub max_age: Option<f64>,
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+/// Reverse lookups of destination addresses into the names table.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RdnsSettings {
+    /// DNS server to query; defaults to the first in /etc/resolv.conf.
+    pub server: Option<SocketAddr>,
+    pub workers: Option<usize>,
+    /// Lookups per second.
+    pub rate: Option<f64>,
+}
+
+/// How finely the baseline tells destinations apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// Destination port and protocol, whatever the address.
+    #[default]
+    Port,
+    /// Destination address, port and protocol.
+    Host,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BaselineSettings {
+    pub granularity: Granularity,
+    /// Seconds after an ident's first connection during which new destinations are learned
+    /// quietly.
+    pub learning: f64,
+}
+
+impl Default for BaselineSettings {
+    fn default() -> Self {
+        Self {
+            granularity: Granularity::default(),
+            learning: 7.0 * 24.0 * 3600.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertSettings {
+    pub webhook: String,
+    /// If empty, every Failed message alerts.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiSettings {
+    pub bind: SocketAddr,
+    pub token: Option<String>,
+    /// Take newline-delimited JSON messages on `POST /v1/ingest`.
+    #[serde(default)]
+    pub ingest: bool,
+    /// Longest ingest request body, in bytes.
+    pub ingest_max_bytes: Option<usize>,
+    /// Messages a second each address may post.
+    pub ingest_rate: Option<f64>,
+}
+
+/// Reports written to a directory on a schedule (see `report`).
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReportSettings {
+    /// Directory the reports go in, a file for each period named for its start.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub interval: report::Interval,
+    #[serde(default)]
+    pub format: report::Format,
+    /// Sections each report has, in this order; all of them if empty.
+    #[serde(default)]
+    pub sections: Vec<report::Section>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 12074)),
+            database: "glosco.db".to_string(),
+            snapshot: None,
+            snapshot_interval: 60.0,
+            tcp_timeout: 60.0,
+            udp_timeout: 30.0,
+            maintenance: 5.0,
+            append_only: false,
+            partition: false,
+            retention: None,
+            names_retention: None,
+            max_db_size: None,
+            shard_by_ident: false,
+            shard_handles: 64,
+            ident_collision: CollisionPolicy::default(),
+            reject_legacy: false,
+            workers: 256,
+            pending: 256,
+            async_io: false,
+            max_skew: 600.0,
+            skew_policy: SkewPolicy::default(),
+            forward: Vec::new(),
+            relay: Vec::new(),
+            replicate: None,
+            mesh_listen: Vec::new(),
+            mesh_peers: Vec::new(),
+            mesh_peer_file: None,
+            mesh_peer_horizon: 604800.0,
+            remote_query_token: None,
+            remote_query_limit: 10000,
+            geoip: Vec::new(),
+            event_log: None,
+            rdns: None,
+            alerts: None,
+            baseline: None,
+            api: None,
+            report: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Load settings from a TOML file; anything it doesn't mention keeps its default.
+    pub fn load(path: &Path) -> Result<Self, SettingsError> {
+        settings::load(path)
+    }
+}
+
+/// Produces fresh settings when the collector is told to reload, e.g. by reading the config file
+/// and command line again.
+pub type Reload = Box<dyn Fn() -> Result<ServerSettings, SettingsError> + Send>;
+
+/// The collector's current settings, swapped wholesale on reload. Loops take a snapshot each time
+/// around rather than holding on to one.
+#[derive(Debug)]
+struct Live(RwLock<Arc<ServerSettings>>);
+
+impl Live {
+    fn get(&self) -> Arc<ServerSettings> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// Set by the SIGHUP handler, cleared by `reload_thread` once it has reloaded.
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::Relaxed);
+}
+
+/// Reload settings whenever the process gets SIGHUP, and reopen the GeoIP databases they name.
+fn reload_thread(live: Arc<Live>, reload: Option<Reload>, alerter: Option<Arc<Alerter>>, #[cfg(feature = "geoip")] geoip: Option<Arc<GeoIp>>, shutdown: Arc<AtomicBool>) {
+    #[cfg(unix)]
+    // Safety: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGHUP, on_hangup as *const () as libc::sighandler_t);
+    }
+    loop {
+        thread::sleep(Duration::from_millis(500));
+        if shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        if !HANGUP.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        match reload.as_ref().map(|reload| reload()) {
+            Some(Ok(fresh)) => apply(&live, fresh, alerter.as_deref()),
+            Some(Err(e)) => println!("reload failed, keeping the current settings: {}", e),
+            None => println!("reload: nothing to reload settings from, keeping the current settings"),
+        }
+        // MaxMind publishes new databases weekly; SIGHUP is how an update gets picked up
+        // without waiting for the next mtime check
+        #[cfg(feature = "geoip")]
+        if let Some(geoip) = &geoip {
+            geoip.reopen(live.get().geoip.clone());
+        }
+    }
+}
+
+/// Swap in reloaded settings, keeping the current value of anything that can't change while
+/// running.
+fn apply(live: &Live, mut fresh: ServerSettings, alerter: Option<&Alerter>) {
+    let current = live.get();
+    fn fixed<T: PartialEq + Clone>(name: &str, current: &T, fresh: &mut T) {
+        if current != fresh {
+            println!("reload: {} can't change without a restart, ignoring the new value", name);
+            *fresh = current.clone();
+        }
+    }
+    fixed("bind", &current.bind, &mut fresh.bind);
+    fixed("database", &current.database, &mut fresh.database);
+    fixed("snapshot", &current.snapshot, &mut fresh.snapshot);
+    fixed("snapshot_interval", &current.snapshot_interval, &mut fresh.snapshot_interval);
+    fixed("workers", &current.workers, &mut fresh.workers);
+    fixed("pending", &current.pending, &mut fresh.pending);
+    fixed("async_io", &current.async_io, &mut fresh.async_io);
+    fixed("partition", &current.partition, &mut fresh.partition);
+    fixed("shard_by_ident", &current.shard_by_ident, &mut fresh.shard_by_ident);
+    fixed("shard_handles", &current.shard_handles, &mut fresh.shard_handles);
+    fixed("forward", &current.forward, &mut fresh.forward);
+    fixed("relay", &current.relay, &mut fresh.relay);
+    fixed("replicate", &current.replicate, &mut fresh.replicate);
+    fixed("mesh_listen", &current.mesh_listen, &mut fresh.mesh_listen);
+    fixed("mesh_peers", &current.mesh_peers, &mut fresh.mesh_peers);
+    fixed("mesh_peer_file", &current.mesh_peer_file, &mut fresh.mesh_peer_file);
+    fixed("mesh_peer_horizon", &current.mesh_peer_horizon, &mut fresh.mesh_peer_horizon);
+    fixed("remote_query_token", &current.remote_query_token, &mut fresh.remote_query_token);
+    #[cfg(not(feature = "geoip"))]
+    fixed("geoip", &current.geoip, &mut fresh.geoip);
+    fixed("event_log", &current.event_log, &mut fresh.event_log);
+    fixed("rdns", &current.rdns, &mut fresh.rdns);
+    fixed("api", &current.api, &mut fresh.api);
+    match (alerter, &fresh.alerts) {
+        (Some(alerter), Some(alerts)) => {
+            alerter.set_webhook(alerts.webhook.clone());
+            alerter.set_rules(if alerts.rules.is_empty() {
+                vec![Rule::default()]
+            } else {
+                alerts.rules.clone()
+            });
+        },
+        (None, None) => (),
+        _ => fixed("alerts", &current.alerts, &mut fresh.alerts),
+    }
+    *live.0.write().unwrap() = Arc::new(fresh);
+    println!("reloaded settings");
+}
+
+/// The addresses currently connected under each ident, shared by every client thread.
+#[derive(Debug, Default)]
+struct Idents {
+    held: Mutex<HashMap<String, Vec<IpAddr>>>,
+    collisions: AtomicU64,
+}
+
+/// Clock skew seen from each ident since maintenance last recorded it in the clients table,
+/// and how late each ident's recent messages arrived.
+#[derive(Debug, Default)]
+struct Skews {
+    seen: Mutex<HashMap<String, Skew>>,
+    skewed: AtomicU64,
+    /// Seconds from timestamp to arrival of each ident's last `LATENCY_WINDOW` messages that
+    /// weren't early, oldest first; kept across maintenance ticks.
+    latencies: Mutex<HashMap<String, VecDeque<f64>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Skew {
+    /// Seconds the latest message's timestamp was ahead of (or, if negative, behind) its arrival.
+    latest: f64,
+    /// The largest such difference, in either direction.
+    worst: f64,
+    /// Messages timestamped after they arrived, which say nothing about latency.
+    early: u64,
+    /// The 95th percentile of the ident's recent latencies, once it has any.
+    latency_p95: Option<f64>,
+}
+
+impl Skews {
+    /// How many of each ident's messages the latency percentile is taken over.
+    const LATENCY_WINDOW: usize = 1000;
+
+    fn observe(&self, ident: &str, skew: f64) {
+        {
+            let mut seen = self.seen.lock().unwrap();
+            let entry = seen.entry(ident.to_string()).or_insert(Skew { latest: skew, worst: 0.0, early: 0, latency_p95: None });
+            entry.latest = skew;
+            entry.worst = entry.worst.max(skew.abs());
+            if skew > 0.0 {
+                entry.early += 1;
+                return;
+            }
+        }
+        let mut latencies = self.latencies.lock().unwrap();
+        let recent = latencies.entry(ident.to_string()).or_default();
+        if recent.len() == Self::LATENCY_WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(-skew);
+    }
+
+    fn take(&self) -> HashMap<String, Skew> {
+        let mut seen = std::mem::take(&mut *self.seen.lock().unwrap());
+        let latencies = self.latencies.lock().unwrap();
+        for (ident, skew) in seen.iter_mut() {
+            skew.latency_p95 = latencies.get(ident).and_then(|recent| percentile(recent.iter().copied(), 0.95));
+        }
+        seen
+    }
+}
+
+/// The `p`th quantile of `values` (nearest rank), if there are any.
+fn percentile(values: impl Iterator<Item = f64>, p: f64) -> Option<f64> {
+    let mut sorted: Vec<f64> = values.collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(f64::total_cmp);
+    let rank = ((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    Some(sorted[rank - 1])
+}
+
+/// The outcome of `Idents::claim`.
+#[derive(Debug)]
+enum Claim {
+    /// Nobody else at another address holds the ident.
+    Clear(String),
+    /// Somebody else does, and the policy let this client in as the given ident anyway.
+    Collided(String),
+    Rejected,
+}
+
+impl Idents {
+    fn claim(&self, ident: &str, addr: IpAddr, policy: CollisionPolicy) -> Claim {
+        let mut held = self.held.lock().unwrap();
+        let collides = |held: &HashMap<String, Vec<IpAddr>>, ident: &str| {
+            held.get(ident).map(|addrs| addrs.iter().any(|a| *a != addr)).unwrap_or(false)
+        };
+        if !collides(&held, ident) {
+            held.entry(ident.to_string()).or_default().push(addr);
+            return Claim::Clear(ident.to_string());
+        }
+        self.collisions.fetch_add(1, Ordering::Relaxed);
+        let granted = match policy {
+            CollisionPolicy::Reject => return Claim::Rejected,
+            CollisionPolicy::Warn => ident.to_string(),
+            CollisionPolicy::Suffix => (2..)
+                .map(|n| format!("{}#{}", ident, n))
+                .find(|candidate| !collides(&held, candidate))
+                .expect("ran out of suffixes"),
+        };
+        held.entry(granted.clone()).or_default().push(addr);
+        Claim::Collided(granted)
+    }
+
+    /// Give up one claim on `ident` by `addr`, returning whether anyone else still holds it.
+    fn release(&self, ident: &str, addr: IpAddr) -> bool {
+        let mut held = self.held.lock().unwrap();
+        let Some(addrs) = held.get_mut(ident) else {
+            return false;
+        };
+        if let Some(idx) = addrs.iter().position(|a| *a == addr) {
+            addrs.swap_remove(idx);
+        }
+        if addrs.is_empty() {
+            held.remove(ident);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// How many connections have claimed an ident held elsewhere since startup.
+    fn collisions(&self) -> u64 {
+        self.collisions.load(Ordering::Relaxed)
+    }
+}
+
+/// Per-connection settings shared by every client thread.
+#[derive(Debug, Clone)]
+struct ClientOptions {
+    settings: Arc<Live>,
+    idents: Arc<Idents>,
+    skews: Arc<Skews>,
+    partitions: Arc<Partitions>,
+    /// Per-ident databases, when sharding by ident.
+    shards: Option<Arc<Shards>>,
+    events: Option<EventLog>,
+    forwarders: Vec<Forwarder>,
+    relay: Option<Arc<Client>>,
+    alerter: Option<Arc<Alerter>>,
+    /// What embedders registered with `ServerHandle::on_match`.
+    callbacks: Arc<Callbacks>,
+    /// Messages dropped because the database stayed busy (or broke) through every retry.
+    write_failures: Arc<AtomicU64>,
+    /// Messages ignored because an identical row was already stored.
+    duplicates: Arc<AtomicU64>,
+    /// Frames clients numbered that never arrived.
+    lost_frames: Arc<AtomicU64>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<GeoIp>>,
+    rdns: Option<ReverseDns>,
+    broadcast: Arc<Broadcast>,
+    changes: Arc<Changes>,
+}
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how many have been run,
+/// so only append to this list--never edit an entry that has shipped.
+///
+/// Later entries may run against a partitioned database, which has day tables in place of
+/// `state`; anything that changes state has to change those too (and `partition::COLUMNS`).
+/// An entry that mentions `{state}` is run once for each state table, with it standing in for
+/// the table's name, and `state_all` is rebuilt over the day tables afterwards.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE IF NOT EXISTS state
+    (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode);
+    CREATE INDEX IF NOT EXISTS state_instime ON state (instime);
+    CREATE INDEX IF NOT EXISTS state_conntime ON state (conntime);
+    CREATE INDEX IF NOT EXISTS state_ident ON state (ident);
+    CREATE INDEX IF NOT EXISTS state_src ON state (srchost, srcport);
+    CREATE INDEX IF NOT EXISTS state_dst ON state (dsthost, dstport);
+
+    CREATE VIEW IF NOT EXISTS latest_ins AS
+    SELECT max(instime), * FROM state
+    GROUP BY ident, srchost, srcport, dsthost, dstport, proto;
+    CREATE INDEX IF NOT EXISTS latest_ins_idx
+    ON STATE (ident, srchost, srcport, dsthost, dstport, proto);
+
+    CREATE TABLE IF NOT EXISTS names
+    (instime, querier, responder, name, addr, port, text);
+
+    CREATE TABLE IF NOT EXISTS client_sessions
+    (ident, peer, connected, disconnected);
+    CREATE INDEX IF NOT EXISTS client_sessions_ident ON client_sessions (ident);
+    ",
+    // Keepalives refresh the open Active row instead of appending a new one
+    "
+    ALTER TABLE state ADD COLUMN last_seen;
+    UPDATE state SET last_seen = instime;
+    ",
+    // The ident a client asked for, when it differs or collided with another peer's
+    "
+    ALTER TABLE client_sessions ADD COLUMN claimed;
+    ALTER TABLE client_sessions ADD COLUMN collision;
+    ",
+    // Replayed messages are ignored; NULLs are coalesced since a unique index treats them as distinct
+    "
+    DELETE FROM state WHERE rowid NOT IN (
+        SELECT min(rowid) FROM state
+        GROUP BY ident, srchost, srcport, dsthost, dstport, proto, conntime, state, close, pkind, pcode
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS state_unique ON state
+    (ident, srchost, srcport, dsthost, dstport, proto, conntime, state, coalesce(close, 0), coalesce(pkind, -1), coalesce(pcode, -1));
+    ",
+    // Inventory of every sensor that has ever reported, seeded from the sessions we already have
+    "
+    CREATE TABLE IF NOT EXISTS clients
+    (ident PRIMARY KEY, agent, keepalive, first_seen, last_seen);
+    INSERT OR IGNORE INTO clients (ident, first_seen, last_seen)
+    SELECT ident, min(connected), max(coalesce(disconnected, connected))
+    FROM client_sessions WHERE ident IS NOT NULL GROUP BY ident;
+    ALTER TABLE client_sessions ADD COLUMN frames;
+    ",
+    // Hourly rollups kept up to date by maintenance, so long-range dashboards needn't scan state
+    "
+    CREATE TABLE IF NOT EXISTS summary_hourly
+    (hour, ident, proto, opened, active, ended, failed, dsthosts, PRIMARY KEY (hour, ident, proto));
+    CREATE TABLE IF NOT EXISTS watermarks
+    (name PRIMARY KEY, value);
+    ",
+    // GeoIP enrichment of the destination, when the collector has databases to look it up in
+    "
+    ALTER TABLE state ADD COLUMN dstcountry;
+    ALTER TABLE state ADD COLUMN dstasn;
+    ",
+    // Where a name came from: NULL for observed DNS, 'rdns' for the collector's own PTR lookups
+    "
+    ALTER TABLE names ADD COLUMN source;
+    ",
+    // Clock skew: what a sensor claimed when its timestamp was clamped, and how far off each
+    // sensor's clock has been seen to be
+    "
+    ALTER TABLE state ADD COLUMN reported_conntime;
+    ALTER TABLE clients ADD COLUMN skew;
+    ALTER TABLE clients ADD COLUMN max_skew;
+    ",
+    // Destinations each ident has used (dsthost is '' at port granularity), and the first uses
+    // that came after its learning period
+    "
+    CREATE TABLE IF NOT EXISTS baseline
+    (ident, dsthost, dstport, proto, first_seen, PRIMARY KEY (ident, dsthost, dstport, proto));
+    CREATE TABLE IF NOT EXISTS anomalies
+    (detected, ident, srchost, srcport, dsthost, dstport, proto);
+    CREATE INDEX IF NOT EXISTS anomalies_detected ON anomalies (detected);
+    ",
+    // What queries read state through, whether or not it's partitioned into day tables
+    "
+    CREATE VIEW IF NOT EXISTS state_all AS SELECT * FROM state;
+    DROP VIEW IF EXISTS latest_ins;
+    CREATE VIEW latest_ins AS
+    SELECT max(instime), * FROM state_all
+    GROUP BY ident, srchost, srcport, dsthost, dstport, proto;
+    ",
+    // Names have no unique key, so merging databases matches them up by time first
+    "
+    CREATE INDEX IF NOT EXISTS names_instime ON names (instime);
+    ",
+    // For people reading the database by hand: times as ISO 8601 UTC, to the millisecond
+    "
+    CREATE VIEW IF NOT EXISTS state_readable AS
+    SELECT strftime('%Y-%m-%dT%H:%M:%fZ', instime, 'unixepoch') AS instime,
+        strftime('%Y-%m-%dT%H:%M:%fZ', conntime, 'unixepoch') AS conntime,
+        ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+        strftime('%Y-%m-%dT%H:%M:%fZ', last_seen, 'unixepoch') AS last_seen,
+        dstcountry, dstasn,
+        strftime('%Y-%m-%dT%H:%M:%fZ', reported_conntime, 'unixepoch') AS reported_conntime
+    FROM state_all;
+    CREATE VIEW IF NOT EXISTS names_readable AS
+    SELECT strftime('%Y-%m-%dT%H:%M:%fZ', instime, 'unixepoch') AS instime,
+        querier, responder, name, addr, port, text, source
+    FROM names;
+    ",
+    // Connections open right now, kept up to date on ingest so nobody has to ask latest_ins;
+    // seeded from it with the Starting (5) and Active (1) marks
+    "
+    CREATE TABLE IF NOT EXISTS active_now
+    (ident, peer, srchost, srcport, dsthost, dstport, proto, state, conntime, last_seen,
+    PRIMARY KEY (ident, srchost, srcport, dsthost, dstport, proto));
+    INSERT OR IGNORE INTO active_now
+    SELECT ident, peer, srchost, srcport, dsthost, dstport, proto, state, conntime, coalesce(last_seen, instime)
+    FROM latest_ins WHERE state IN (5, 1) AND close IS NULL;
+    ",
+    // Ingest latency: the 95th percentile of how late each client's recent messages arrived,
+    // and how many were timestamped after they arrived instead
+    "
+    ALTER TABLE clients ADD COLUMN latency_p95;
+    ALTER TABLE clients ADD COLUMN early;
+    ",
+    // Protocols as IANA numbers, TCP 6 and UDP 17, rather than the wire marks 1 and 2 (which
+    // are ICMP and IGMP to anyone expecting IANA's)
+    "
+    UPDATE active_now SET proto = CASE proto WHEN 1 THEN 6 WHEN 2 THEN 17 END WHERE proto IN (1, 2);
+    UPDATE summary_hourly SET proto = CASE proto WHEN 1 THEN 6 WHEN 2 THEN 17 END WHERE proto IN (1, 2);
+    UPDATE baseline SET proto = CASE proto WHEN 1 THEN 6 WHEN 2 THEN 17 END WHERE proto IN (1, 2);
+    UPDATE anomalies SET proto = CASE proto WHEN 1 THEN 6 WHEN 2 THEN 17 END WHERE proto IN (1, 2);
+    ",
+    "
+    UPDATE {state} SET proto = CASE proto WHEN 1 THEN 6 WHEN 2 THEN 17 END WHERE proto IN (1, 2);
+    ",
+    // Names lowercased, without the trailing dot, and reversed (see `query::reversed_name`), so
+    // a search for everything under a domain is a range of the index
+    "
+    ALTER TABLE names ADD COLUMN rname;
+    UPDATE names SET rname = (
+        WITH RECURSIVE r (idx, out) AS (
+            SELECT length(rtrim(lower(names.name), '.')), ''
+            UNION ALL
+            SELECT idx - 1, out || substr(rtrim(lower(names.name), '.'), idx, 1) FROM r WHERE idx > 0
+        )
+        SELECT out FROM r WHERE idx = 0
+    );
+    CREATE INDEX IF NOT EXISTS names_rname ON names (rname);
+    ",
+    // Labels like site and environment, as each client's latest hello gave them
+    "
+    CREATE TABLE IF NOT EXISTS client_tags
+    (ident, key, value, PRIMARY KEY (ident, key));
+    ",
+    // Port scans sensors reported: 'ports' scans of dsthost (dstport NULL) and 'hosts' sweeps of
+    // dstport (dsthost NULL), with how many were tried within `window` seconds
+    "
+    CREATE TABLE IF NOT EXISTS scans
+    (instime, scantime, ident, peer, srchost, dsthost, dstport, proto, kind, count, window);
+    CREATE UNIQUE INDEX IF NOT EXISTS scans_unique ON scans
+    (ident, srchost, coalesce(dsthost, ''), coalesce(dstport, -1), kind, scantime);
+    CREATE INDEX IF NOT EXISTS scans_instime ON scans (instime);
+    ",
+    // The latest row of each connection, as latest_ins has it but kept up to date by triggers
+    // (see `partition::LATEST_TRIGGERS`) rather than grouped over all of state on every read.
+    // Maintenance only looks for timeouts among rows that haven't timed out (close 4) yet
+    "
+    CREATE TABLE IF NOT EXISTS latest_state
+    (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+    last_seen, dstcountry, dstasn, reported_conntime,
+    PRIMARY KEY (ident, srchost, srcport, dsthost, dstport, proto));
+    CREATE INDEX IF NOT EXISTS latest_state_pending ON latest_state (proto, coalesce(last_seen, instime))
+    WHERE close IS NOT 4;
+    INSERT OR REPLACE INTO latest_state
+    (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+    last_seen, dstcountry, dstasn, reported_conntime)
+    SELECT instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+    last_seen, dstcountry, dstasn, reported_conntime
+    FROM latest_ins;
+    ",
+    partition::LATEST_TRIGGERS,
+    // Seconds a name record holds for where that's known (the collector's own lookups); NULL
+    // for observed DNS, whose TTLs sensors don't send. Names are looked up by address, newest
+    // first, to name the ends of connections
+    "
+    ALTER TABLE names ADD COLUMN ttl;
+    CREATE INDEX IF NOT EXISTS names_addr ON names (addr, instime);
+    ",
+    // Whether the client's latest session opened without a hello, as clients from before hellos
+    // do; NULL for clients last seen before this was kept
+    "
+    ALTER TABLE clients ADD COLUMN legacy;
+    ",
+    // Labels operators give idents on the collector's side with `glosco admin label`, like
+    // owner=team-x, apart from the tags clients give themselves
+    "
+    CREATE TABLE IF NOT EXISTS ident_labels
+    (ident, key, value, set_at, PRIMARY KEY (ident, key));
+    ",
+    // Purges run with `glosco admin purge`: when, of which host block or ident, and how many
+    // rows went from each table (a JSON object)
+    "
+    CREATE TABLE IF NOT EXISTS purges
+    (at, host, ident, deleted);
+    ",
+    // How many more failures a Failed row stands for, held back by the sensor as repeats of it;
+    // NULL for none
+    "
+    ALTER TABLE {state} ADD COLUMN repeats;
+    DROP VIEW IF EXISTS state_readable;
+    CREATE VIEW state_readable AS
+    SELECT strftime('%Y-%m-%dT%H:%M:%fZ', instime, 'unixepoch') AS instime,
+        strftime('%Y-%m-%dT%H:%M:%fZ', conntime, 'unixepoch') AS conntime,
+        ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+        strftime('%Y-%m-%dT%H:%M:%fZ', last_seen, 'unixepoch') AS last_seen,
+        dstcountry, dstasn,
+        strftime('%Y-%m-%dT%H:%M:%fZ', reported_conntime, 'unixepoch') AS reported_conntime,
+        repeats
+    FROM state_all;
+    ",
+    // When the session a synthesized close (a timeout, or a client going away) ends was first
+    // reported, as active_now had it; NULL on every other row
+    "
+    ALTER TABLE {state} ADD COLUMN opened_at;
+    DROP VIEW IF EXISTS state_readable;
+    CREATE VIEW state_readable AS
+    SELECT strftime('%Y-%m-%dT%H:%M:%fZ', instime, 'unixepoch') AS instime,
+        strftime('%Y-%m-%dT%H:%M:%fZ', conntime, 'unixepoch') AS conntime,
+        ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+        strftime('%Y-%m-%dT%H:%M:%fZ', last_seen, 'unixepoch') AS last_seen,
+        dstcountry, dstasn,
+        strftime('%Y-%m-%dT%H:%M:%fZ', reported_conntime, 'unixepoch') AS reported_conntime,
+        repeats,
+        strftime('%Y-%m-%dT%H:%M:%fZ', opened_at, 'unixepoch') AS opened_at
+    FROM state_all;
+    ",
+    // The TCP handshake's round trip, SYN to SYN-ACK in microseconds, on a connection's first
+    // Active row when its sensor timed it; NULL otherwise
+    "
+    ALTER TABLE {state} ADD COLUMN rtt_micros;
+    DROP VIEW IF EXISTS state_readable;
+    CREATE VIEW state_readable AS
+    SELECT strftime('%Y-%m-%dT%H:%M:%fZ', instime, 'unixepoch') AS instime,
+        strftime('%Y-%m-%dT%H:%M:%fZ', conntime, 'unixepoch') AS conntime,
+        ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode,
+        strftime('%Y-%m-%dT%H:%M:%fZ', last_seen, 'unixepoch') AS last_seen,
+        dstcountry, dstasn,
+        strftime('%Y-%m-%dT%H:%M:%fZ', reported_conntime, 'unixepoch') AS reported_conntime,
+        repeats,
+        strftime('%Y-%m-%dT%H:%M:%fZ', opened_at, 'unixepoch') AS opened_at,
+        rtt_micros
+    FROM state_all;
+    ",
+    // Frames a client numbered that never arrived, from_seq to to_seq inclusive, and clients
+    // that started numbering over (reset 1), expected to go on from from_seq but going on from
+    // to_seq instead. next_seq is the number the client's next frame should have, as of its
+    // last session; lost_frames counts every frame its gaps left out
+    "
+    CREATE TABLE IF NOT EXISTS gaps
+    (ident, from_seq, to_seq, detected_at, reset);
+    CREATE INDEX IF NOT EXISTS gaps_ident ON gaps (ident, detected_at);
+    ALTER TABLE clients ADD COLUMN next_seq;
+    ALTER TABLE clients ADD COLUMN lost_frames;
+    ",
+    // Each sensor's latest counts of packets captured, dropped by the kernel or the interface,
+    // and left unparsed on each interface, since it started; replaced whole by every report
+    "
+    CREATE TABLE IF NOT EXISTS sensor_health
+    (ident, interface, received, dropped, if_dropped, unparsed, reported_at, PRIMARY KEY (ident, interface));
+    ",
+    // The interfaces each client captures on, by the announcement (generation) that listed them:
+    // idx is the number its connections give the interface, flags pcap's PCAP_IF_* bits, and
+    // addresses every address configured on it, space-separated, like "10.0.0.5/24 fe80::1/64"
+    "
+    CREATE TABLE IF NOT EXISTS interfaces
+    (ident, generation, idx, name, flags, addresses, announced_at, PRIMARY KEY (ident, generation, idx));
+    ",
+    // When, by its client's clock, the latest Starting or Active of each open connection was;
+    // a keepalive no later than that is a replay. NULL for connections open before this was kept
+    "
+    ALTER TABLE active_now ADD COLUMN reported_at;
+    ",
+    // Each interface's link-layer address and MTU, where the sensor could find them out
+    "
+    ALTER TABLE interfaces ADD COLUMN mac;
+    ALTER TABLE interfaces ADD COLUMN mtu;
+    ",
+];
+
+/// How long hourly summaries are kept.
+const SUMMARY_RETENTION: Duration = Duration::from_secs(366 * 24 * 3600);
+
+/// The schema version a fully migrated database has.
+pub(crate) fn schema_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+/// Apply whatever migrations `db` hasn't had yet.
+///
+/// Each one takes the write lock before checking the version, so connections racing to migrate
+/// a new database (as with shards opened by several workers at once) apply every step once.
+pub(crate) fn migrate(db: &mut rusqlite::Connection) {
+    loop {
+        let txn = db.transaction_with_behavior(TransactionBehavior::Immediate).expect("failed to start migration");
+        let version: i64 = txn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .expect("failed to query schema version");
+        let Some(sql) = MIGRATIONS.get(version as usize) else {
+            return;
+        };
+        if sql.contains("{state}") {
+            let partitioned = partition::is_partitioned(&txn).expect("failed to check for partitions");
+            let tables = match partitioned {
+                true => partition::tables(&txn).expect("failed to list day tables"),
+                false => vec!["state".to_string()],
+            };
+            for table in tables {
+                txn.execute_batch(&sql.replace("{state}", &table)).expect("failed to migrate database");
+            }
+            if partitioned {
+                partition::rebuild_view(&txn).expect("failed to rebuild state_all");
+            }
+        } else {
+            txn.execute_batch(sql).expect("failed to migrate database");
+        }
+        txn.pragma_update(None, "user_version", version + 1).expect("failed to update schema version");
+        txn.commit().expect("failed to commit migration");
+        println!("migrated database to schema version {}", version + 1);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn maint_thread(path: String, live: Arc<Live>, skews: Arc<Skews>, partitions: Arc<Partitions>, shards: Option<Arc<Shards>>, changes: Arc<Changes>, heartbeat: Arc<Heartbeat>, shutdown: Arc<AtomicBool>) {
+    // Kept from one tick to the next, along with the statements it has prepared
+    let mut conn = None;
+    let mut reporter = Reporter::default();
+    loop {
+        let settings = live.get();
+        thread::sleep(Duration::from_secs_f64(settings.maintenance));
+        if shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .expect("time is before UNIX epoch!")
+            .as_secs_f64();
+        let skews = skews.take();
+        match &shards {
+            Some(shards) => {
+                let paths = match shards.list() {
+                    Ok(paths) => paths,
+                    Err(e) => {
+                        println!("maintenance skipped, couldn't list shards: {:?}", e);
+                        continue;
+                    },
+                };
+                for path in paths {
+                    // Only the shard's own ident has rows in it to record skew against
+                    let ident = path.file_name().and_then(|name| name.to_str()).and_then(shard::ident_of);
+                    let skews: Vec<_> = skews.iter().filter(|(skewed, _)| Some(*skewed) == ident.as_ref()).collect();
+                    match shards.take_path(&path) {
+                        Ok(mut db) => {
+                            if maintain(&mut db, &path, &settings, &skews, &partitions, now) {
+                                shards.give(path, db);
+                            }
+                        },
+                        Err(e) => println!("maintenance skipped {}, couldn't open it: {:?}", path.display(), e),
+                    }
+                }
+            },
+            None => {
+                maintain_on(&mut conn, &path, &changes, &settings, &skews.iter().collect::<Vec<_>>(), &partitions, now);
+            },
+        }
+        // After the tick, so the summaries it brought up to date are in the report
+        if let Some(report) = &settings.report {
+            reporter.tick(report, &path, now);
+        }
+        heartbeat.beat(Duration::from_secs_f64(settings.maintenance));
+    }
+}
+
+/// Write the in-memory database to `path` every `every`, until shut down; the last snapshot is
+/// `ServerHandle::join`'s to take.
+fn snapshot_thread(path: PathBuf, every: Duration, shutdown: Arc<AtomicBool>) {
+    loop {
+        thread::sleep(every);
+        if shutdown.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = db::snapshot_memory(&path) {
+            println!("failed to snapshot the in-memory database to {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// For a close synthesized from a `latest_state` row: when the session it ends was first
+/// reported, which `active_now` keeps as long as it's open. Closes have to be inserted before
+/// the connection leaves `active_now`.
+const OPENED_AT: &str = "(
+    SELECT active_now.conntime FROM active_now
+    WHERE active_now.ident = latest_state.ident AND active_now.srchost = latest_state.srchost
+        AND active_now.srcport = latest_state.srcport AND active_now.dsthost = latest_state.dsthost
+        AND active_now.dstport = latest_state.dstport AND active_now.proto = latest_state.proto
+)";
+
+/// Run a tick on `conn`, opening it first if there's none. A tick that fails leaves none, so
+/// the next starts over on a new connection.
+fn maintain_on(conn: &mut Option<rusqlite::Connection>, path: &str, changes: &Arc<Changes>, settings: &ServerSettings, skews: &[(&String, &Skew)], partitions: &Partitions, now: f64) -> bool {
+    let db = match conn {
+        Some(db) => db,
+        None => match db::open(path) {
+            Ok(db) => {
+                changes.hook(&db);
+                conn.insert(db)
+            },
+            Err(e) => {
+                println!("maintenance skipped, couldn't open database: {:?}", e);
+                return false;
+            },
+        },
+    };
+    let ok = maintain(db, Path::new(path), settings, skews, partitions, now);
+    if !ok {
+        // Whatever went wrong may be the connection's own
+        *conn = None;
+    }
+    ok
+}
+
+/// One maintenance tick's work on one database: time out quiet connections, bring the
+/// summaries up to date, and enforce retention and the size cap. A step that fails is reported
+/// and the rest go ahead; returns whether they all succeeded.
+fn maintain(db: &mut rusqlite::Connection, path: &Path, settings: &ServerSettings, skews: &[(&String, &Skew)], partitions: &Partitions, now: f64) -> bool {
+    let mut ok = true;
+    let mut closed = Vec::new();
+    for (protocol, timeout) in [(Protocol::Tcp, settings.tcp_timeout), (Protocol::Udp, settings.udp_timeout)] {
+        // Only what's still open times out; what ended, and most UDP rows, which are datagrams
+        // closed as they're reported, are left be
+        let result = db::retry(|| db.prepare_cached(&format!("
+            INSERT INTO {}
+            (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode, last_seen, opened_at)
+            SELECT :now, :now, ident, peer, srchost, srcport, dsthost, dstport, :proto, state, :timeout, pkind, pcode, :now, {opened_at}
+            FROM latest_state
+            WHERE close IS NOT {timeout} AND proto = :proto AND coalesce(last_seen, instime) < :threshold
+                AND state IN (:start, :active);
+        ", partitions.table(db, now)?, timeout = TMOUT_MARK, opened_at = OPENED_AT))?.execute(named_params! {
+            ":now": now,
+            ":threshold": now - timeout,
+            ":proto": protocol.iana_number(),
+            ":start": START_MARK,
+            ":active": ACTIVE_MARK,
+            ":timeout": TMOUT_MARK,
+        }));
+        match result {
+            Ok(changed) => closed.push((protocol_name(protocol.iana_number()), changed)),
+            Err(e) => {
+                println!("maintenance tick failed: {:?}", e);
+                ok = false;
+            },
+        }
+        let result = db::retry(|| db.prepare_cached("
+            DELETE FROM active_now WHERE proto = :proto AND last_seen < :threshold;
+        ")?.execute(named_params! {
+            ":threshold": now - timeout,
+            ":proto": protocol.iana_number(),
+        }));
+        if let Err(e) = result {
+            println!("maintenance failed to expire active connections: {:?}", e);
+            ok = false;
+        }
+    }
+    if !closed.is_empty() {
+        let by_protocol: Vec<String> = closed.iter().map(|(name, changed)| format!("{} {}", name, changed)).collect();
+        println!("maintenance tick: {} rows changed ({})", closed.iter().map(|(_, changed)| changed).sum::<usize>(), by_protocol.join(", "));
+    }
+    let result = db::retry(|| db.prepare_cached("
+        UPDATE clients SET last_seen = :now
+        WHERE ident IN (SELECT ident FROM client_sessions WHERE disconnected IS NULL);
+    ")?.execute(named_params! {
+        ":now": now,
+    }));
+    if let Err(e) = result {
+        println!("maintenance failed to refresh clients: {:?}", e);
+        ok = false;
+    }
+    for (ident, skew) in skews.iter() {
+        let result = db::retry(|| db.prepare_cached("
+            UPDATE clients SET skew = :skew, max_skew = max(coalesce(max_skew, 0), :worst),
+                latency_p95 = coalesce(:latency_p95, latency_p95), early = coalesce(early, 0) + :early
+            WHERE ident = :ident;
+        ")?.execute(named_params! {
+            ":skew": skew.latest,
+            ":worst": skew.worst,
+            ":latency_p95": skew.latency_p95,
+            ":early": skew.early,
+            ":ident": ident,
+        }));
+        if let Err(e) = result {
+            println!("maintenance failed to record clock skew and latency for {}: {:?}", ident, e);
+            ok = false;
+        }
+    }
+    match db::retry(|| summarize(db, now)) {
+        Ok(hours) => println!("maintenance tick: {} summary buckets updated", hours),
+        Err(e) => {
+            println!("maintenance failed to summarize: {:?}", e);
+            ok = false;
+        },
+    }
+    if let Some(retention) = settings.retention {
+        match db::retry(|| partitions.expire(db, now - retention)) {
+            Ok(0) => (),
+            Ok(expired) if partitions.is_partitioned() => println!("maintenance tick: {} day tables expired", expired),
+            Ok(expired) => println!("maintenance tick: {} rows expired", expired),
+            Err(e) => {
+                println!("maintenance failed to expire old rows: {:?}", e);
+                ok = false;
+            },
+        }
+    }
+    if let Some(retention) = settings.names_retention {
+        let result = db::retry(|| db.prepare_cached("
+            DELETE FROM names WHERE instime < :threshold;
+        ")?.execute(named_params! {
+            ":threshold": now - retention,
+        }));
+        match result {
+            Ok(0) => (),
+            Ok(expired) => println!("maintenance tick: {} name records expired", expired),
+            Err(e) => {
+                println!("maintenance failed to expire old names: {:?}", e);
+                ok = false;
+            },
+        }
+    }
+    if let Some(max) = settings.max_db_size {
+        if let Err(e) = enforce_size(db, path, partitions, max) {
+            println!("maintenance failed to enforce the database size cap: {:?}", e);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Rows deleted at a time when evicting from a single table to get under the size cap.
+const EVICTION_CHUNK: usize = 10000;
+
+/// Bytes the database is using: its pages that hold data, plus the WAL. Pages freed by deletes
+/// stay in the file but are reused before it grows, so this is what the cap is checked against.
+fn db_size(db: &rusqlite::Connection, path: &Path) -> rusqlite::Result<u64> {
+    let (pages, free, page_size): (u64, u64, u64) = db.query_row("
+        SELECT (SELECT page_count FROM pragma_page_count()), (SELECT freelist_count FROM pragma_freelist_count()),
+            (SELECT page_size FROM pragma_page_size());
+    ", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    let wal = fs::metadata(wal).map(|m| m.len()).unwrap_or(0);
+    Ok((pages - free) * page_size + wal)
+}
+
+/// If the database is over `max` bytes, evict the oldest state until it's back under nine
+/// tenths of that, so it isn't over again a tick later.
+fn enforce_size(db: &mut rusqlite::Connection, path: &Path, partitions: &Partitions, max: u64) -> rusqlite::Result<()> {
+    let size = db_size(db, path)?;
+    if size <= max {
+        return Ok(());
+    }
+    let low_water = max / 10 * 9;
+    println!("database is {} bytes, over the {} byte cap; evicting down to {}", size, max, low_water);
+    loop {
+        match db::retry(|| partitions.evict_oldest(db, EVICTION_CHUNK))? {
+            Some(evicted) => println!("size cap: evicted {}", evicted),
+            None => {
+                println!("size cap: no state left to evict, database is still {} bytes", db_size(db, path)?);
+                return Ok(());
+            },
+        }
+        // The WAL holds every page the eviction touched until it's checkpointed
+        db.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |_| Ok(()))?;
+        let size = db_size(db, path)?;
+        if size <= low_water {
+            println!("size cap: database is down to {} bytes", size);
+            return Ok(());
+        }
+    }
+}
+
+/// Write the hourly summaries of the state rows in `{range}`, a condition on `state_all`,
+/// replacing those already there.
+const SUMMARIZE: &str = "
+    INSERT INTO summary_hourly (hour, ident, proto, opened, active, ended, failed, dsthosts)
+    SELECT CAST(instime / 3600 AS INTEGER) * 3600 AS bucket, ident, proto,
+        sum(state = :start),
+        count(DISTINCT CASE WHEN state IN (:start, :active) THEN srchost || ' ' || srcport || ' ' || dsthost || ' ' || dstport END),
+        sum(state = :ended),
+        sum(state = :failed) + coalesce(sum(repeats), 0),
+        count(DISTINCT dsthost)
+    FROM state_all
+    WHERE {range}
+    GROUP BY bucket, ident, proto
+    ON CONFLICT (hour, ident, proto) DO UPDATE SET
+        opened = excluded.opened, active = excluded.active, ended = excluded.ended,
+        failed = excluded.failed, dsthosts = excluded.dsthosts;
+";
+
+/// Bring `summary_hourly` up to date with everything inserted since the last call.
+///
+/// Every hour that gained rows since the watermark is recomputed from scratch rather than
+/// incremented, which keeps distinct counts exact and makes a repeated or interrupted run
+/// harmless. Returns the number of buckets written.
+pub(crate) fn summarize(db: &rusqlite::Connection, now: f64) -> rusqlite::Result<usize> {
+    let txn = db.unchecked_transaction()?;
+    let watermark: f64 = txn.prepare_cached("
+        SELECT coalesce((SELECT value FROM watermarks WHERE name = 'summary_hourly'), 0);
+    ")?.query_row([], |row| row.get(0))?;
+    let latest: Option<f64> = txn.prepare_cached("
+        SELECT max(instime) FROM state_all WHERE instime > ?;
+    ")?.query_row(params![watermark], |row| row.get(0))?;
+    let Some(latest) = latest else {
+        return Ok(0);
+    };
+    let buckets = txn.prepare_cached(&SUMMARIZE.replace("{range}", "
+        instime >= (SELECT CAST(min(instime) / 3600 AS INTEGER) * 3600 FROM state_all WHERE instime > :watermark)
+    "))?.execute(named_params! {
+        ":watermark": watermark,
+        ":start": START_MARK,
+        ":active": ACTIVE_MARK,
+        ":ended": ENDED_MARK,
+        ":failed": FAILED_MARK,
+    })?;
+    txn.prepare_cached("
+        INSERT INTO watermarks (name, value) VALUES ('summary_hourly', ?)
+        ON CONFLICT (name) DO UPDATE SET value = excluded.value;
+    ")?.execute(params![latest])?;
+    txn.prepare_cached("
+        DELETE FROM summary_hourly WHERE hour < ?;
+    ")?.execute(params![now - SUMMARY_RETENTION.as_secs_f64()])?;
+    txn.commit()?;
+    Ok(buckets)
+}
+
+/// Recompute the summaries of each hour in `hours` (as `summary_hourly.hour`) from the state
+/// rows left in it, as after rows are deleted; buckets left with no rows go. Returns the number
+/// of buckets written. Run it in the transaction that deleted them.
+pub(crate) fn resummarize(txn: &rusqlite::Connection, hours: &BTreeSet<i64>) -> rusqlite::Result<usize> {
+    let mut buckets = 0;
+    for hour in hours {
+        txn.prepare_cached("
+            DELETE FROM summary_hourly WHERE hour = ?;
+        ")?.execute(params![hour])?;
+        buckets += txn.prepare_cached(&SUMMARIZE.replace("{range}", "
+            instime >= :hour AND instime < :hour + 3600
+        "))?.execute(named_params! {
+            ":hour": hour,
+            ":start": START_MARK,
+            ":active": ACTIVE_MARK,
+            ":ended": ENDED_MARK,
+            ":failed": FAILED_MARK,
+        })?;
+    }
+    Ok(buckets)
+}
+
+/// Close out any session a previous run left open; it didn't get to clean up after itself.
+fn close_stale_sessions(db: &rusqlite::Connection) {
+    let closed = db.execute("
+        UPDATE client_sessions SET disconnected = ? WHERE disconnected IS NULL;
+    ", params![to_float_secs(SystemTime::now())]).expect("failed to close stale sessions");
+    if closed > 0 {
+        println!("closed {} sessions left open by a previous run", closed);
+    }
+}
+
+/// A collector running in the background, from `start`.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+    /// Where the in-memory database's last snapshot goes, if it's kept one.
+    snapshot: Option<PathBuf>,
+    callbacks: Arc<Callbacks>,
+}
+
+impl ServerHandle {
+    /// Where the client port ended up, for when it was bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Call `callback` with every message accepted from now on that `rule` matches, whoever
+    /// it's from; see `Callbacks` for how and when.
+    pub fn on_match<F: Fn(&Event) + Send + Sync + 'static>(&self, rule: Rule, callback: F) {
+        self.callbacks.register(rule, Arc::new(callback) as Arc<Callback>);
+    }
+
+    /// Number of matches dropped because `on_match` callbacks fell behind.
+    pub fn callbacks_dropped(&self) -> u64 {
+        self.callbacks.dropped()
+    }
+
+    /// Stop accepting clients, and stop maintenance after the tick in progress. Clients already
+    /// connected are seen through to the end (with async I/O, they're hung up on); the API,
+    /// mesh, replication and reverse DNS keep running until the process exits.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // The accept loop only looks at the flag between connections, so give it one
+        let mut wake = self.local_addr;
+        if wake.ip().is_unspecified() {
+            wake.set_ip(match wake {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake);
+    }
+
+    /// Wait for the collector to stop accepting clients, which it does only once shut down, then
+    /// take a last snapshot of the in-memory database if it's kept one.
+    pub fn join(self) {
+        self.thread.join().expect("collector thread panicked");
+        if let Some(snapshot) = self.snapshot {
+            db::snapshot_memory(&snapshot).expect("failed to snapshot the in-memory database");
+        }
+    }
+}
+
+/// Run the collector `args` describe until the process exits, the way both binaries do: detached
+/// and holding a pidfile if asked to, and reading `args` again for new settings on SIGHUP.
+pub fn serve(args: ServerArgs) {
+    let settings = args.clone().resolve();
+    let _pidfile = daemon::daemonize(&args.daemon);
+    run_with_reload(settings, Some(Box::new(move || args.clone().try_resolve())));
+}
+
+/// Run the collector until the process exits.
+pub fn run(settings: ServerSettings) {
+    run_with_reload(settings, None);
+}
+
+/// Run the collector until the process exits, calling `reload` for new settings on SIGHUP.
+pub fn run_with_reload(settings: ServerSettings, reload: Option<Reload>) {
+    run_with_changes(settings, reload, Arc::default());
+}
+
+/// Run the collector like `run_with_reload`, reporting every row it writes to `changes`; subscribe
+/// to it before calling this to see them all.
+pub fn run_with_changes(settings: ServerSettings, reload: Option<Reload>, changes: Arc<Changes>) {
+    let handle = start_with(settings, reload, changes).expect("failed to bind socket");
+    println!("listening for clients on {:?}", handle.local_addr());
+    handle.join();
+}
+
+/// Start the collector in the background and return once it's accepting clients.
+pub fn start(settings: ServerSettings) -> io::Result<ServerHandle> {
+    start_with(settings, None, Arc::default())
+}
+
+/// Start the collector like `start`, with `reload` and `changes` as `run_with_changes` takes
+/// them. Only failing to bind the client port is returned; the rest of setup panics on failure,
+/// as it does when running in the foreground.
+pub fn start_with(settings: ServerSettings, reload: Option<Reload>, changes: Arc<Changes>) -> io::Result<ServerHandle> {
+    let sock = TcpListener::bind(settings.bind)?;
+    let local_addr = sock.local_addr()?;
+    let shutdown: Arc<AtomicBool> = Arc::default();
+    let options = collector(&settings, reload, changes, shutdown.clone());
+    let callbacks = options.callbacks.clone();
+
+    #[cfg(feature = "async-server")]
+    if settings.async_io {
+        let dbname = settings.database.clone();
+        let stop = shutdown.clone();
+        let thread = thread::spawn(move || async_io::serve(sock, &dbname, options, stop));
+        return Ok(ServerHandle { local_addr, shutdown, thread, snapshot: settings.snapshot, callbacks });
+    }
+    #[cfg(not(feature = "async-server"))]
+    assert!(!settings.async_io, "async I/O requested, but glosco was built without the async-server feature");
+
+    let (queue, pending) = mpsc::sync_channel::<(TcpStream, SocketAddr)>(settings.pending);
+    let pending = Arc::new(Mutex::new(pending));
+    for _ in 0 .. settings.workers.max(1) {
+        let dbname = settings.database.clone();
+        let pending = pending.clone();
+        let options = options.clone();
+        thread::spawn(move || worker_thread(dbname, pending, options));
+    }
+
+    let stop = shutdown.clone();
+    let thread = thread::spawn(move || accept_thread(sock, queue, stop));
+    Ok(ServerHandle { local_addr, shutdown, thread, snapshot: settings.snapshot, callbacks })
+}
+
+/// Everything the collector runs apart from the client port: open and migrate the database,
+/// start maintenance, reloads, and whatever else the settings ask for (the API, replication,
+/// the mesh), and return what storing what clients send takes. Maintenance and reloads stop once
+/// `shutdown` is set.
+fn collector(settings: &ServerSettings, reload: Option<Reload>, changes: Arc<Changes>, shutdown: Arc<AtomicBool>) -> ClientOptions {
+    if db::is_memory(&settings.database) {
+        assert!(!settings.shard_by_ident, "an in-memory database can't be sharded by ident");
+        if let Some(snapshot) = settings.snapshot.as_ref().filter(|snapshot| snapshot.exists()) {
+            db::restore_memory(snapshot).expect("failed to restore the in-memory database from its snapshot");
+            println!("restored the in-memory database from {}", snapshot.display());
+        }
+    } else {
+        assert!(settings.snapshot.is_none(), "snapshots are only taken of an in-memory database");
+    }
+
+    let shards = settings.shard_by_ident.then(|| {
+        assert!(!settings.partition, "partition can't be combined with shard_by_ident");
+        assert!(settings.api.is_none(), "the API can't serve a database sharded by ident");
+        assert!(settings.rdns.is_none(), "reverse DNS can't write to a database sharded by ident");
+        let shards = Shards::open(&settings.database, settings.shard_handles, changes.clone()).expect("failed to open shard directory");
+        for path in shards.list().expect("failed to list shards") {
+            let mut db = db::open(&path).expect("failed to open shard");
+            migrate(&mut db);
+            close_stale_sessions(&db);
+        }
+        Arc::new(shards)
+    });
+
+    let partitions = if shards.is_some() {
+        Arc::default()
+    } else {
+        let mut db = db::open(&settings.database).expect("failed to open database");
+        migrate(&mut db);
+        close_stale_sessions(&db);
+        let partitions = Partitions::open(&mut db, settings.partition, to_float_secs(SystemTime::now()))
+            .expect("failed to partition database");
+        Arc::new(partitions)
+    };
+
+    let heartbeat = Arc::new(Heartbeat::new(Duration::from_secs_f64(settings.maintenance)));
+
+    let live = Arc::new(Live(RwLock::new(Arc::new(settings.clone()))));
+    let skews: Arc<Skews> = Arc::default();
+    {
+        let dbname = settings.database.clone();
+        let live = live.clone();
+        let skews = skews.clone();
+        let partitions = partitions.clone();
+        let shards = shards.clone();
+        let changes = changes.clone();
+        let heartbeat = heartbeat.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || maint_thread(dbname, live, skews, partitions, shards, changes, heartbeat, shutdown));
+    }
+    if let Some(snapshot) = settings.snapshot.clone() {
+        let every = Duration::from_secs_f64(settings.snapshot_interval);
+        let shutdown = shutdown.clone();
+        thread::spawn(move || snapshot_thread(snapshot, every, shutdown));
+    }
+
+    let events = settings.event_log.as_ref().map(|log| {
+        let mut config = EventLogConfig::new(log.path.clone());
+        if let Some(max_bytes) = log.max_bytes {
+            config.set_max_bytes(max_bytes);
+        }
+        if let Some(max_age) = log.max_age {
+            config.set_max_age(Duration::from_secs_f64(max_age));
+        }
+        config.set_gzip(log.gzip);
+        config.build().expect("failed to open event log")
+    });
+
+    let client_id = format!("glosco-{}", gethostname::gethostname().to_string_lossy());
+    let forwarders = settings.forward.iter().map(|target| {
+        Forwarder::connect(target, &client_id).expect("failed to set up forwarding")
+    }).collect();
+
+    let relay = if settings.relay.is_empty() {
+        None
+    } else {
+        let mut config = ClientConfig::new(gethostname::gethostname().into_string().expect("couldn't encode hostname"));
+        for addr in settings.relay.iter() {
+            config.add(*addr);
+        }
+        Some(Arc::new(config.build().expect("failed to start relay")))
+    };
+
+    let alerter = settings.alerts.as_ref().map(|alerts| {
+        let rules = if alerts.rules.is_empty() {
+            vec![Rule::default()]
+        } else {
+            alerts.rules.clone()
+        };
+        Arc::new(Alerter::new(alerts.webhook.clone(), rules))
+    });
+    // A rowid in the feed doesn't say which shard it's in, so sharded collectors alert on the
+    // timeouts sensors report as they're ingested, and not on their own
+    if let Some(alerter) = alerter.as_ref().filter(|_| shards.is_none()) {
+        let alerter = alerter.clone();
+        let dbname = settings.database.clone();
+        let feed = changes.subscribe();
+        thread::spawn(move || alert::follow_timeouts(&alerter, &dbname, feed));
+    }
+
+    // Opened even with no databases to look in, so a reload can name some
+    #[cfg(feature = "geoip")]
+    let geoip = Some(Arc::new(GeoIp::open(settings.geoip.clone()).expect("failed to open GeoIP databases")));
+    #[cfg(not(feature = "geoip"))]
+    assert!(settings.geoip.is_empty(), "GeoIP databases given, but glosco was built without the geoip feature");
+
+    {
+        let live = live.clone();
+        let alerter = alerter.clone();
+        #[cfg(feature = "geoip")]
+        let geoip = geoip.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || reload_thread(live, reload, alerter, #[cfg(feature = "geoip")] geoip, shutdown));
+    }
+
+    let rdns = settings.rdns.as_ref().map(|rdns| {
+        let mut config = ReverseDnsConfig::new(settings.database.clone(), rdns.server);
+        if let Some(workers) = rdns.workers {
+            config.set_workers(workers);
+        }
+        if let Some(rate) = rdns.rate {
+            config.set_rate(rate);
+        }
+        config.start().expect("failed to start reverse DNS workers")
+    });
+
+    let options = ClientOptions {
+        settings: live.clone(),
+        idents: Arc::default(),
+        skews,
+        partitions,
+        shards,
+        events,
+        forwarders,
+        relay,
+        alerter,
+        callbacks: Arc::default(),
+        write_failures: Arc::default(),
+        duplicates: Arc::default(),
+        lost_frames: Arc::default(),
+        #[cfg(feature = "geoip")]
+        geoip,
+        rdns,
+        broadcast: Arc::default(),
+        changes,
+    };
+
+    let meshed = !settings.mesh_listen.is_empty() || !settings.mesh_peers.is_empty() || settings.mesh_peer_file.is_some();
+    #[cfg(feature = "mesh")]
+    let mesh = meshed.then(|| mesh::start(settings));
+    #[cfg(not(feature = "mesh"))]
+    assert!(!meshed, "mesh peers given, but glosco was built without the mesh feature");
+
+    if let Some(api_settings) = &settings.api {
+        let mut api = ApiConfig::new(api_settings.bind, settings.database.clone());
+        if let Some(token) = api_settings.token.clone() {
+            api.set_token(token);
+        }
+        api.set_heartbeat(heartbeat);
+        if api_settings.ingest {
+            let mut ingest = Ingest::new(ingest::store(settings.database.clone(), options.clone()));
+            if let Some(max_bytes) = api_settings.ingest_max_bytes {
+                ingest.set_max_bytes(max_bytes);
+            }
+            if let Some(rate) = api_settings.ingest_rate {
+                ingest.set_rate(rate);
+            }
+            api.set_ingest(ingest);
+        }
+        #[cfg(feature = "mesh")]
+        if let Some((_, mesh)) = &mesh {
+            api.set_mesh(mesh.clone());
+        }
+        api.start().expect("failed to start API listener");
+    }
+
+    if let Some(primary) = settings.replicate {
+        let dbname = settings.database.clone();
+        let options = options.clone();
+        thread::spawn(move || replica::follow(primary, dbname, options));
+    }
+
+    #[cfg(feature = "mesh")]
+    if let Some((runtime, mesh)) = mesh {
+        let dbname = settings.database.clone();
+        let options = options.clone();
+        thread::spawn(move || mesh::store(runtime, mesh, dbname, options));
+    }
+
+    options
+}
+
+/// Hand accepted connections to the workers until shut down; dropping the queue then lets each
+/// worker go once it's done with its client.
+fn accept_thread(sock: TcpListener, queue: mpsc::SyncSender<(TcpStream, SocketAddr)>, shutdown: Arc<AtomicBool>) {
+    let mut refused = 0u64;
+    loop {
+        let accepted = sock.accept();
+        if shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Ok((client, peer)) = accepted {
+            println!("Connection from {:?}", peer);
+            if let Err(e) = queue.try_send((client, peer)) {
+                refused += 1;
+                let reason = match e {
+                    mpsc::TrySendError::Full(_) => "every worker is busy and the queue is full",
+                    mpsc::TrySendError::Disconnected(_) => "no workers are left",
+                };
+                println!("refused connection from {:?}: {} ({} refused so far)", peer, reason, refused);
+            }
+        }
+    }
+}
+
+/// Take accepted connections off the queue and see each one through to the end, reusing one
+/// database handle for all of them.
+fn worker_thread(dbname: String, pending: Arc<Mutex<mpsc::Receiver<(TcpStream, SocketAddr)>>>, options: ClientOptions) {
+    let mut store = None;
+    loop {
+        let Ok((client, peer)) = pending.lock().unwrap().recv() else {
+            return;
+        };
+        if store.is_none() {
+            match Store::open(&dbname, &options) {
+                Ok(opened) => store = Some(opened),
+                Err(e) => {
+                    println!("dropping connection from {:?}, couldn't open database: {:?}", peer, e);
+                    continue;
+                },
+            }
+        }
+        client_thread(client, peer, store.as_mut().unwrap(), &options);
+    }
+}
+
+/// Where a thread stores what its clients send: its own handle on the database, or when
+/// sharding by ident, the shared pool of per-ident files.
+#[derive(Debug)]
+enum Store {
+    Single(rusqlite::Connection),
+    Sharded(Arc<Shards>),
+}
+
+impl Store {
+    fn open(dbname: &str, options: &ClientOptions) -> rusqlite::Result<Self> {
+        match &options.shards {
+            Some(shards) => Ok(Self::Sharded(shards.clone())),
+            None => {
+                let db = db::open(dbname)?;
+                options.changes.hook(&db);
+                Ok(Self::Single(db))
+            },
+        }
+    }
+
+    /// Run `op` against the database holding `ident`'s rows.
+    fn with<T, F: FnOnce(&mut rusqlite::Connection) -> rusqlite::Result<T>>(&mut self, ident: &str, op: F) -> rusqlite::Result<T> {
+        match self {
+            Self::Single(db) => op(db),
+            Self::Sharded(shards) => shards.with(ident, op),
+        }
+    }
+}
+
+fn to_float_secs(st: SystemTime) -> f64 {
+    let dur = st.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+    dur.as_secs_f64()
+}
+
+/// Make sure an ident that reports other than by connecting (replicated, or posted to the API)
+/// shows up in the clients inventory.
+fn client_seen(db: &rusqlite::Connection, ident: &str) -> rusqlite::Result<()> {
+    let now = to_float_secs(SystemTime::now());
+    db.execute("
+        INSERT INTO clients (ident, first_seen, last_seen) VALUES (?1, ?2, ?2)
+        ON CONFLICT (ident) DO UPDATE SET last_seen = excluded.last_seen;
+    ", params![ident, now])?;
+    Ok(())
+}
+
+/// Record the start of a client's session, returning the rowid for `session_ended`.
+///
+/// `claimed` is the ident the client announced, if it collided with a connection from another
+/// peer; `ident` is what it was granted.
+fn session_started(db: &rusqlite::Connection, ident: &str, peername: &str, claimed: Option<&str>, legacy: bool) -> rusqlite::Result<i64> {
+    let now = to_float_secs(SystemTime::now());
+    let txn = db.unchecked_transaction()?;
+    txn.execute("
+        INSERT INTO clients (ident, first_seen, last_seen, legacy) VALUES (?1, ?2, ?2, ?3)
+        ON CONFLICT (ident) DO UPDATE SET last_seen = excluded.last_seen, legacy = excluded.legacy;
+    ", params![ident, now, legacy])?;
+    txn.execute("
+        INSERT INTO client_sessions
+        (ident, peer, connected, disconnected, claimed, collision, frames)
+        VALUES (?, ?, ?, NULL, ?, ?, 0);
+    ", params![
+        ident, peername, now, claimed, claimed.is_some(),
+    ])?;
+    let session = txn.last_insert_rowid();
+    txn.commit()?;
+    Ok(session)
+}
+
+/// Record what the client said about itself in its hello, its tags replacing any it had.
+fn client_hello(db: &rusqlite::Connection, ident: &str, hello: &Hello) -> rusqlite::Result<()> {
+    let txn = db.unchecked_transaction()?;
+    txn.execute("
+        UPDATE clients SET agent = ?, keepalive = ? WHERE ident = ?;
+    ", params![hello.agent, hello.keepalive, ident])?;
+    txn.execute("DELETE FROM client_tags WHERE ident = ?;", params![ident])?;
+    let mut insert = txn.prepare_cached("INSERT INTO client_tags (ident, key, value) VALUES (?, ?, ?);")?;
+    for (key, value) in hello.tags.iter() {
+        insert.execute(params![ident, key, value])?;
+    }
+    drop(insert);
+    txn.commit()
+}
+
+/// Record what a sensor said of its capture, in place of what it said before.
+fn sensor_stats(db: &rusqlite::Connection, ident: &str, stats: &Stats, now: f64) -> rusqlite::Result<()> {
+    let txn = db.unchecked_transaction()?;
+    txn.prepare_cached("DELETE FROM sensor_health WHERE ident = ?;")?.execute(params![ident])?;
+    let mut insert = txn.prepare_cached("
+        INSERT OR REPLACE INTO sensor_health (ident, interface, received, dropped, if_dropped, unparsed, reported_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?);
+    ")?;
+    for counts in stats.interfaces.iter() {
+        insert.execute(params![
+            ident, counts.name,
+            counts.packets as i64, counts.dropped as i64, counts.if_dropped as i64, counts.unparsed as i64,
+            now,
+        ])?;
+    }
+    drop(insert);
+    txn.commit()
+}
+
+/// Record the interfaces `ident` announced, replacing any it announced before under the same
+/// generation.
+fn announced(db: &rusqlite::Connection, ident: &str, namespace: &Namespace, now: f64) -> rusqlite::Result<()> {
+    let txn = db.unchecked_transaction()?;
+    txn.prepare_cached("DELETE FROM interfaces WHERE ident = ? AND generation = ?;")?.execute(params![ident, namespace.generation])?;
+    let mut insert = txn.prepare_cached("
+        INSERT INTO interfaces (ident, generation, idx, name, flags, mac, mtu, addresses, announced_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
+    ")?;
+    for (idx, interface) in namespace.interfaces.iter().enumerate() {
+        let addresses: Vec<String> = interface.addresses.iter().map(ToString::to_string).collect();
+        insert.execute(params![ident, namespace.generation, idx, interface.name, interface.flags, interface.mac, interface.mtu, addresses.join(" "), now])?;
+    }
+    drop(insert);
+    txn.commit()
+}
+
+/// Where a client's numbering jumped to, from what was expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Jump {
+    /// Frames `from` to `to` never arrived.
+    Gap { from: u64, to: u64 },
+    /// The client numbered from `expected` on but started over, as it does when restarted.
+    Reset { expected: u64 },
+}
+
+/// Check the number a client says its next frame has against `expected`, or what its last
+/// session left off at if this session has no expectations yet, and record any gap or reset.
+fn sequenced(db: &rusqlite::Connection, ident: &str, expected: Option<u64>, next: u64, now: f64) -> rusqlite::Result<Option<Jump>> {
+    let expected = match expected {
+        Some(expected) => Some(expected),
+        None => db.prepare_cached("SELECT next_seq FROM clients WHERE ident = ?;")?
+            .query_row(params![ident], |row| row.get::<_, Option<i64>>(0))
+            .optional()?
+            .flatten()
+            .map(|next| next as u64),
+    };
+    let Some(expected) = expected else {
+        return Ok(None);
+    };
+    let (jump, to, lost) = match next.cmp(&expected) {
+        std::cmp::Ordering::Equal => return Ok(None),
+        std::cmp::Ordering::Greater => (Jump::Gap { from: expected, to: next - 1 }, next - 1, next - expected),
+        std::cmp::Ordering::Less => (Jump::Reset { expected }, next, 0),
+    };
+    let txn = db.unchecked_transaction()?;
+    txn.prepare_cached("
+        INSERT INTO gaps (ident, from_seq, to_seq, detected_at, reset) VALUES (?, ?, ?, ?, ?);
+    ")?.execute(params![ident, expected as i64, to as i64, now, matches!(jump, Jump::Reset { .. })])?;
+    txn.prepare_cached("
+        UPDATE clients SET lost_frames = coalesce(lost_frames, 0) + ? WHERE ident = ?;
+    ")?.execute(params![lost as i64, ident])?;
+    txn.commit()?;
+    Ok(Some(jump))
+}
+
+/// Record a client turned away because its ident was already held by another peer.
+fn session_rejected(db: &rusqlite::Connection, claimed: &str, peername: &str) -> rusqlite::Result<()> {
+    let now = to_float_secs(SystemTime::now());
+    db.execute("
+        INSERT INTO client_sessions
+        (ident, peer, connected, disconnected, claimed, collision)
+        VALUES (NULL, ?, ?, ?, ?, 1);
+    ", params![peername, now, now, claimed])?;
+    Ok(())
+}
+
+/// Close out a client's session after its sync connection dropped.
+///
+/// Every connection whose latest row for this ident is still open gets a synthesized timeout
+/// row stamped with the disconnect time, written the way maintenance writes its own: the state
+/// it had, closed as timed out. Otherwise they would linger until the TCP or UDP timeout in
+/// maintenance caught up; those it already has aren't closed again. If another peer is still connected under the same ident,
+/// only this peer's connections are closed. Returns how many were.
+/// `next_seq` is the number the client's next frame should have, if it numbers them.
+fn session_ended(db: &mut rusqlite::Connection, partitions: &Partitions, ident: &str, peername: Option<&str>, session: Option<i64>, frames: u64, next_seq: Option<u64>) -> rusqlite::Result<usize> {
+    let now = to_float_secs(SystemTime::now());
+    let txn = db.transaction()?;
+    let table = partitions.table(&txn, now)?;
+    txn.execute(&format!("
+        INSERT INTO {}
+        (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode, last_seen, opened_at)
+        SELECT :now, :now, ident, peer, srchost, srcport, dsthost, dstport, proto, state, :timeout, pkind, pcode, :now, {}
+        FROM latest_state
+        WHERE ident = :ident AND (:peer IS NULL OR peer = :peer) AND close IS NOT :timeout
+            AND state IN (:start, :active);
+    ", table, OPENED_AT), named_params! {
+        ":now": now,
+        ":ident": ident,
+        ":peer": peername,
+        ":start": START_MARK,
+        ":active": ACTIVE_MARK,
+        ":timeout": TMOUT_MARK,
+    })?;
+    let closed = txn.changes() as usize;
+    txn.execute("
+        DELETE FROM active_now WHERE ident = :ident AND (:peer IS NULL OR peer = :peer);
+    ", named_params! {
+        ":ident": ident,
+        ":peer": peername,
+    })?;
+    txn.execute("
+        UPDATE client_sessions SET disconnected = ?, frames = ? WHERE rowid = ?;
+    ", params![now, frames, session])?;
+    txn.execute("
+        UPDATE clients SET last_seen = ?, next_seq = coalesce(?, next_seq) WHERE ident = ?;
+    ", params![now, next_seq.map(|next| next as i64), ident])?;
+    txn.commit()?;
+    Ok(closed)
+}
+
+/// Bring `ident`'s open connections in line with a snapshot of them: any the snapshot doesn't
+/// have are closed as timed out the way maintenance closes them, unless they were opened after
+/// it was taken or maintenance got to them first. Returns how many
+/// were closed, and the snapshot's states that weren't known to be open, for storing as usual;
+/// leaving out those the sensor has reported closing since the snapshot was taken.
+fn reconcile(db: &mut rusqlite::Connection, partitions: &Partitions, ident: &str, snapshot: &Snapshot) -> rusqlite::Result<(usize, Vec<Message>)> {
+    let now = to_float_secs(SystemTime::now());
+    let key = |conn: &Connection| (
+        conn.src.addr.to_string(), conn.src.port,
+        conn.dst.addr.to_string(), conn.dst.port,
+        conn.protocol.iana_number(),
+    );
+    let reported: HashSet<_> = snapshot.states.iter().map(|message| key(&message.state().connection)).collect();
+    let txn = db.transaction()?;
+    let open = txn.prepare_cached("
+        SELECT srchost, srcport, dsthost, dstport, proto, conntime FROM active_now WHERE ident = ?;
+    ")?.query_map(params![ident], |row| Ok((
+        (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?),
+        row.get::<_, f64>(5)?,
+    )))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    let table = partitions.table(&txn, now)?;
+    let as_of = to_float_secs(snapshot.as_of);
+    let mut closed = 0;
+    for (conn, conntime) in open.iter() {
+        if reported.contains(conn) || *conntime > as_of {
+            continue;
+        }
+        let (srchost, srcport, dsthost, dstport, proto) = conn;
+        txn.execute(&format!("
+            INSERT INTO {}
+            (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode, last_seen, opened_at)
+            SELECT ?1, ?1, ident, peer, srchost, srcport, dsthost, dstport, proto, state, ?2, pkind, pcode, ?1, {}
+            FROM latest_state
+            WHERE ident = ?3 AND srchost = ?4 AND srcport = ?5 AND dsthost = ?6 AND dstport = ?7 AND proto = ?8
+                AND close IS NOT ?2 AND state IN (?9, ?10);
+        ", table, OPENED_AT), params![
+            now, TMOUT_MARK,
+            ident, srchost, srcport, dsthost, dstport, proto,
+            START_MARK, ACTIVE_MARK,
+        ])?;
+        closed += txn.prepare_cached("
+            DELETE FROM active_now
+            WHERE ident = ? AND srchost = ? AND srcport = ? AND dsthost = ? AND dstport = ? AND proto = ?;
+        ")?.execute(params![ident, srchost, srcport, dsthost, dstport, proto])?;
+    }
+    // Nor is one the sensor has since said closed opened again, if the snapshot comes late
+    let known: HashSet<_> = open.into_iter().map(|(conn, _)| conn).collect();
+    let mut unknown = Vec::new();
+    for message in snapshot.states.iter().filter(|message| !known.contains(&key(&message.state().connection))) {
+        let (srchost, srcport, dsthost, dstport, proto) = key(&message.state().connection);
+        let closed_since: bool = txn.prepare_cached("
+            SELECT EXISTS (SELECT 1 FROM latest_state
+                WHERE ident = ? AND srchost = ? AND srcport = ? AND dsthost = ? AND dstport = ? AND proto = ?
+                    AND close IS NOT ? AND conntime > ?);
+        ")?.query_row(params![
+            ident, srchost, srcport, dsthost, dstport, proto,
+            TMOUT_MARK, to_float_secs(message.state().as_of),
+        ], |row| row.get(0))?;
+        if !closed_since {
+            unknown.push(message.clone());
+        }
+    }
+    txn.commit()?;
+    Ok((closed, unknown))
+}
+
+fn client_thread(mut client: TcpStream, peer: SocketAddr, store: &mut Store, options: &ClientOptions) {
+    let claimed = if let Ok(frame) = String::decode(&mut client) {
+        frame
+    } else {
+        println!("failed to read initial ident");
+        return;
+    };
+    let first = if let Ok(frame) = CodingVec::<u8, u32>::decode(&mut client) {
+        frame.0
+    } else {
+        println!("{}@{:?}: disconnected before sending anything", claimed, peer);
+        return;
+    };
+    if let Some(filter) = subscription(&claimed, peer, &first) {
+        let (replay, receiver) = options.broadcast.subscribe(filter);
+        if let Err(e) = subscribe::serve(client, replay, receiver) {
+            println!("{}@{:?}: subscriber went away: {:?}", claimed, peer, e);
+        }
+        return;
+    }
+    if let Some(request) = remote::request(&claimed, peer, &first) {
+        let response = remote::answer(&claimed, peer, request, options);
+        if let Err(e) = remote::reply(&mut client, &response) {
+            println!("{}@{:?}: failed to send query response: {:?}", claimed, peer, e);
+        }
+        return;
+    }
+    let Some(legacy) = legacy(&claimed, peer, &first, options) else {
+        return;
+    };
+    let Some(mut session) = Session::open(store, &claimed, peer, legacy, options) else {
+        return;
+    };
+    let mut pending = Some(first);
+    while let Some(frame) = pending.take().or_else(|| CodingVec::<u8, u32>::decode(&mut client).ok().map(|frame| frame.0)) {
+        session.receive(store, &frame, options);
+    }
+    session.close(store, options);
+}
+
+/// If `first` asks to subscribe, the filter it asked for.
+fn subscription(claimed: &str, peer: SocketAddr, first: &[u8]) -> Option<Subscribe> {
+    if first.first() != Some(&SUBSCRIBE_MARK) {
+        return None;
+    }
+    match Subscribe::decode(&mut &*first) {
+        Ok(filter) => {
+            println!("{}@{:?}: subscribed with {:?}", claimed, peer, filter);
+            Some(filter)
+        },
+        Err(e) => {
+            println!("{}@{:?}: bad subscribe request: {:?}", claimed, peer, e);
+            None
+        },
+    }
+}
+
+/// Whether a reporting client is legacy, from before hellos, which start straight in on
+/// messages rather than saying what they are first; `None` if it is and legacy clients are
+/// being turned away.
+fn legacy(claimed: &str, peer: SocketAddr, first: &[u8], options: &ClientOptions) -> Option<bool> {
+    if first.first() == Some(&HELLO_MARK) {
+        return Some(false);
+    }
+    if options.settings.get().reject_legacy {
+        println!("{}@{:?}: legacy client, sent no hello; rejected", claimed, peer);
+        return None;
+    }
+    println!("{}@{:?}: legacy client, sent no hello", claimed, peer);
+    Some(true)
+}
+
+/// A reporting client's connection, from claiming its ident to releasing it. The protocol
+/// handling lives here so that either server implementation can drive it.
+#[derive(Debug)]
+struct Session {
+    ident: Arc<str>,
+    peer: SocketAddr,
+    peername: Arc<str>,
+    /// The `client_sessions` rowid, if recording the start worked.
+    id: Option<i64>,
+    frames: u64,
+    /// The number the client's next frame should have, once it's said what it numbers them from.
+    next_seq: Option<u64>,
+}
+
+impl Session {
+    /// Claim an ident for a newly connected client, or `None` if it was turned away.
+    fn open(store: &mut Store, claimed: &str, peer: SocketAddr, legacy: bool, options: &ClientOptions) -> Option<Self> {
+        let peername: Arc<str> = format!("{:?}", peer).into();
+        let started = |store: &mut Store, ident: &str, claimed: Option<&str>| {
+            store.with(ident, |db| db::retry(|| session_started(db, ident, &peername, claimed, legacy)))
+                .map_err(|e| println!("{}@{:?}: failed to record session start: {:?}", ident, peer, e))
+                .ok()
+        };
+        let (ident, id): (Arc<str>, Option<i64>) = match options.idents.claim(claimed, peer.ip(), options.settings.get().ident_collision) {
+            Claim::Clear(ident) => {
+                let id = started(store, &ident, None);
+                (ident.into(), id)
+            },
+            Claim::Collided(ident) => {
+                println!("ident collision: {:?} claimed {:?}, already connected from another address; accepted as {:?} ({} collisions so far)",
+                         peer, claimed, ident, options.idents.collisions());
+                let id = started(store, &ident, Some(claimed));
+                (ident.into(), id)
+            },
+            Claim::Rejected => {
+                println!("ident collision: {:?} claimed {:?}, already connected from another address; rejected ({} collisions so far)",
+                         peer, claimed, options.idents.collisions());
+                if let Err(e) = store.with(claimed, |db| db::retry(|| session_rejected(db, claimed, &peername))) {
+                    println!("failed to record rejected session: {:?}", e);
+                }
+                return None;
+            },
+        };
+        Some(Self {
+            ident,
+            peer,
+            peername,
+            id,
+            frames: 0,
+            next_seq: None,
+        })
+    }
+
+    /// A session for an ident reporting over the mesh. It holds no claim on the ident and isn't
+    /// recorded in `client_sessions`, having no connection of its own to begin or end.
+    #[cfg(feature = "mesh")]
+    fn meshed(ident: Arc<str>, peer: SocketAddr) -> Self {
+        Self {
+            ident,
+            peer,
+            peername: format!("{:?}", peer).into(),
+            id: None,
+            frames: 0,
+            next_seq: None,
+        }
+    }
+
+    /// Handle one frame from the client.
+    fn receive(&mut self, store: &mut Store, frame: &[u8], options: &ClientOptions) {
+        let (ident, peer) = (&self.ident, self.peer);
+        self.frames += 1;
+        if frame.first() == Some(&HELLO_MARK) {
+            match Hello::decode(&mut &*frame) {
+                Ok(mut hello) => {
+                    println!("{}@{:?}: {:?}", ident, peer, hello);
+                    hello.tags.retain(|key, value| Hello::check_tag(key, value)
+                        .map_err(|e| println!("{}@{:?}: dropping tag: {}", ident, peer, e))
+                        .is_ok());
+                    if hello.tags.len() > Hello::MAX_TAGS {
+                        println!("{}@{:?}: keeping only the first {} of {} tags", ident, peer, Hello::MAX_TAGS, hello.tags.len());
+                        hello.tags = hello.tags.into_iter().take(Hello::MAX_TAGS).collect();
+                    }
+                    if let Err(e) = store.with(ident, |db| db::retry(|| client_hello(db, ident, &hello))) {
+                        println!("{}@{:?}: failed to record hello: {:?}", ident, peer, e);
+                    }
+                },
+                Err(e) => println!("{}@{:?}: bad hello: {:?}", ident, peer, e),
+            }
+            return;
+        }
+        if frame.first() == Some(&SEQUENCE_MARK) {
+            match Sequence::decode(&mut &*frame) {
+                Ok(sequence) => {
+                    let now = to_float_secs(SystemTime::now());
+                    match store.with(ident, |db| db::retry(|| sequenced(db, ident, self.next_seq, sequence.next, now))) {
+                        Ok(Some(Jump::Gap { from, to })) => {
+                            let lost = to - from + 1;
+                            let so_far = options.lost_frames.fetch_add(lost, Ordering::Relaxed) + lost;
+                            println!("{}@{:?}: {} frames lost, {} to {} ({} so far)", ident, peer, lost, from, to, so_far);
+                        },
+                        Ok(Some(Jump::Reset { expected })) => {
+                            println!("{}@{:?}: numbering started over at {}, was at {}", ident, peer, sequence.next, expected);
+                        },
+                        Ok(None) => (),
+                        Err(e) => println!("{}@{:?}: failed to record sequence: {:?}", ident, peer, e),
+                    }
+                    self.next_seq = Some(sequence.next);
+                },
+                Err(e) => println!("{}@{:?}: bad sequence: {:?}", ident, peer, e),
+            }
+            return;
+        }
+        // Every frame from here on is one the client numbered
+        if let Some(next) = self.next_seq.as_mut() {
+            *next += 1;
+        }
+        if frame.first() == Some(&STATS_MARK) {
+            match Stats::decode(&mut &*frame) {
+                Ok(stats) => {
+                    let now = to_float_secs(SystemTime::now());
+                    if let Err(e) = store.with(ident, |db| db::retry(|| sensor_stats(db, ident, &stats, now))) {
+                        println!("{}@{:?}: failed to record stats: {:?}", ident, peer, e);
+                    }
+                },
+                Err(e) => println!("{}@{:?}: bad stats: {:?}", ident, peer, e),
+            }
+            return;
+        }
+        if frame.first() == Some(&NAMESPACE_MARK) {
+            match Namespace::decode(&mut &*frame) {
+                Ok(namespace) => {
+                    let now = to_float_secs(SystemTime::now());
+                    if let Err(e) = store.with(ident, |db| db::retry(|| announced(db, ident, &namespace, now))) {
+                        println!("{}@{:?}: failed to record interfaces: {:?}", ident, peer, e);
+                    }
+                },
+                Err(e) => println!("{}@{:?}: bad namespace: {:?}", ident, peer, e),
+            }
+            return;
+        }
+        if frame.first() == Some(&RELAYED_MARK) {
+            match Relayed::decode(&mut &*frame) {
+                Ok(relayed) => {
+                    let origin: Arc<str> = relayed.ident.into();
+                    accept_into(store, relayed.message.view(), &origin, peer, &self.peername, options);
+                },
+                Err(e) => println!("{}@{:?}: bad relayed message: {:?}", ident, peer, e),
+            }
+            return;
+        }
+        if frame.first() == Some(&SNAPSHOT_MARK) {
+            match Snapshot::decode(&mut &*frame) {
+                Ok(snapshot) => {
+                    let reconciled = store.with(ident, |db| db::retry(|| reconcile(db, &options.partitions, ident, &snapshot)));
+                    match reconciled {
+                        Ok((closed, unknown)) => {
+                            println!("{}@{:?}: snapshot of {} open connections, {} closed, {} new",
+                                ident, peer, snapshot.states.len(), closed, unknown.len());
+                            for message in unknown {
+                                accept_into(store, message.view(), ident, peer, &self.peername, options);
+                            }
+                        },
+                        Err(e) => println!("{}@{:?}: failed to reconcile snapshot: {:?}", ident, peer, e),
+                    }
+                },
+                Err(e) => println!("{}@{:?}: bad snapshot: {:?}", ident, peer, e),
+            }
+            return;
+        }
+        if let Ok(message) = MessageRef::decode(&mut &*frame) {
+            accept_into(store, message, ident, peer, &self.peername, options);
+        }
+    }
+
+    /// Release the ident and close out the session after the connection dropped.
+    fn close(self, store: &mut Store, options: &ClientOptions) {
+        let ident = &self.ident;
+        println!("Lost connection from {}@{:?}", ident, self.peer);
+        let shared = options.idents.release(ident, self.peer.ip());
+        let closed = store.with(ident, |db| {
+            db::retry(|| session_ended(db, &options.partitions, ident, shared.then_some(&*self.peername), self.id, self.frames, self.next_seq))
+        });
+        match closed {
+            Ok(closed) => println!("{}: session ended, {} connections closed", ident, closed),
+            Err(e) => println!("{}: failed to record session end: {:?}", ident, e),
+        }
+    }
+}
+
+/// Add a connection's destination to its ident's baseline, returning true if it's an anomaly: a
+/// destination the ident hadn't used before, seen after its learning period.
+fn learn(db: &rusqlite::Connection, ident: &str, conn: &Connection, now: SystemTime, baseline: &BaselineSettings) -> rusqlite::Result<bool> {
+    let now = to_float_secs(now);
+    let dsthost = match baseline.granularity {
+        Granularity::Port => String::new(),
+        Granularity::Host => conn.dst.addr.to_string(),
+    };
+    let txn = db.unchecked_transaction()?;
+    let added = txn.prepare_cached("
+        INSERT OR IGNORE INTO baseline (ident, dsthost, dstport, proto, first_seen)
+        VALUES (?, ?, ?, ?, ?);
+    ")?.execute(params![ident, dsthost, conn.dst.port, conn.protocol.iana_number(), now])? > 0;
+    if !added {
+        return Ok(false);
+    }
+    let learning_since: f64 = txn.prepare_cached("
+        SELECT min(first_seen) FROM baseline WHERE ident = ?;
+    ")?.query_row(params![ident], |row| row.get(0))?;
+    let anomaly = now - learning_since > baseline.learning;
+    if anomaly {
+        txn.prepare_cached("
+            INSERT INTO anomalies (detected, ident, srchost, srcport, dsthost, dstport, proto)
+            VALUES (?, ?, ?, ?, ?, ?, ?);
+        ")?.execute(params![
+            now, ident,
+            conn.src.addr.to_string(), conn.src.port,
+            conn.dst.addr.to_string(), conn.dst.port,
+            conn.protocol.iana_number(),
+        ])?;
+    }
+    txn.commit()?;
+    Ok(anomaly)
+}
+
+/// Whether a keepalive for `conn` taken at `as_of` is no later than one already reported for it
+/// while it's been open, as when a client sends again what it sent before a reconnect. Such a
+/// keepalive is a duplicate, neither refreshing the row nor going any further.
+fn replayed_keepalive(db: &rusqlite::Connection, ident: &str, conn: &Connection, as_of: SystemTime) -> rusqlite::Result<bool> {
+    let reported: Option<Option<f64>> = db.prepare_cached("
+        SELECT reported_at FROM active_now
+        WHERE ident = ? AND srchost = ? AND srcport = ? AND dsthost = ? AND dstport = ? AND proto = ?;
+    ")?.query_row(params![
+        ident,
+        conn.src.addr.to_string(), conn.src.port,
+        conn.dst.addr.to_string(), conn.dst.port,
+        conn.protocol.iana_number(),
+    ], |row| row.get(0)).optional()?;
+    Ok(reported.flatten().is_some_and(|reported| to_float_secs(as_of) <= reported))
+}
+
+/// If the latest row for this connection is an open Active, bump its `last_seen` and return
+/// true; a keepalive then costs an update rather than a whole new row.
+///
+/// The row is found again by its tuple and `instime`, since rows seen through `state_all` have
+/// no usable rowid; `instime` also says which day table it's in.
+fn refresh_active(db: &rusqlite::Connection, partitions: &Partitions, ident: &str, conn: &Connection, now: SystemTime) -> rusqlite::Result<bool> {
+    let mut stmt = db.prepare_cached("
+        SELECT instime, state, close FROM latest_state
+        WHERE ident = ? AND srchost = ? AND srcport = ? AND dsthost = ? AND dstport = ? AND proto = ?;
+    ")?;
+    let latest: Option<(f64, i64, Option<i64>)> = stmt.query_row(params![
+        ident,
+        conn.src.addr.to_string(), conn.src.port,
+        conn.dst.addr.to_string(), conn.dst.port,
+        conn.protocol.iana_number(),
+    ], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .optional()?;
+    match latest {
+        Some((instime, state, None)) if state == ACTIVE_MARK as i64 => {
+            db.prepare_cached(&format!("
+                UPDATE {} SET last_seen = ?
+                WHERE ident = ? AND srchost = ? AND srcport = ? AND dsthost = ? AND dstport = ? AND proto = ? AND instime = ?;
+            ", partitions.existing(instime)))?
+                .execute(params![
+                    to_float_secs(now),
+                    ident,
+                    conn.src.addr.to_string(), conn.src.port,
+                    conn.dst.addr.to_string(), conn.dst.port,
+                    conn.protocol.iana_number(),
+                    instime,
+                ])?;
+            Ok(true)
+        },
+        _ => Ok(false),
+    }
+}
+
+/// `accept` a message into whichever database holds `ident`'s rows.
+fn accept_into(store: &mut Store, message: MessageRef<'_>, ident: &Arc<str>, peer: SocketAddr, peername: &Arc<str>, options: &ClientOptions) {
+    let result = store.with(ident, |db| {
+        accept(message, ident, peer, db, peername, options);
+        Ok(())
+    });
+    if let Err(e) = result {
+        let dropped = options.write_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        println!("{}@{:?}: dropped message, couldn't open its database ({} dropped so far): {:?}", ident, peer, dropped, e);
+    }
+}
+
+/// Store one message reported under `ident`, then fan it out and relay it upstream; a message
+/// the database ignores as a replay, or fails to write, goes nowhere.
+fn accept(mut message: MessageRef<'_>, ident: &Arc<str>, peer: SocketAddr, db: &rusqlite::Connection, peername: &Arc<str>, options: &ClientOptions) {
+    println!("{}@{:?}: {:?}", ident, peer, message);
+    let now = SystemTime::now();
+    let skew = to_float_secs(message.state().as_of) - to_float_secs(now);
+    options.skews.observe(ident, skew);
+    let mut reported = None;
+    let settings = options.settings.get();
+    if skew.abs() > settings.max_skew {
+        let skewed = options.skews.skewed.fetch_add(1, Ordering::Relaxed) + 1;
+        match settings.skew_policy {
+            SkewPolicy::Clamp => {
+                println!("{}@{:?}: timestamp is {:.0}s off, storing it as received ({} skewed so far)", ident, peer, skew, skewed);
+                reported = Some(message.state().as_of);
+                message.state_mut().as_of = now;
+            },
+            SkewPolicy::Reject => {
+                println!("{}@{:?}: timestamp is {:.0}s off, dropped message ({} skewed so far)", ident, peer, skew, skewed);
+                return;
+            },
+        }
+    }
+    // Only what keeps or passes the message on gets an owned copy; storing it needs none
+    let owned = OnceCell::new();
+    let owned = || owned.get_or_init(|| message.to_message());
+    let labels = || query::ident_labels(db, ident).unwrap_or_else(|e| {
+        println!("{}@{:?}: failed to look up labels for an alert: {:?}", ident, peer, e);
+        BTreeMap::new()
+    });
+    let event = || Event {
+        ident: ident.clone(),
+        peer: peername.clone(),
+        received: now,
+        message: owned().clone(),
+    };
+    match db::retry(|| store(db, ident, peername, &message, now, reported, options)) {
+        Ok(true) => {
+            // Only what was stored is fanned out, so nothing the database ignored goes out
+            if options.events.is_some() || !options.forwarders.is_empty() || options.alerter.is_some() || !options.callbacks.is_empty() {
+                let event = event();
+                // Timeouts alert as the change feed reports them stored (see `alert::follow_timeouts`)
+                let followed = options.shards.is_none() && matches!(event.message, Message::Ended(_, Closed::TimedOut));
+                if let Some(alerter) = options.alerter.as_ref().filter(|_| !followed) {
+                    alerter.check(&event, labels);
+                }
+                options.callbacks.check(&event);
+                for forwarder in options.forwarders.iter() {
+                    forwarder.forward(event.clone());
+                }
+                if let Some(events) = &options.events {
+                    events.log(event);
+                }
+            }
+            if options.broadcast.wanted() {
+                options.broadcast.publish(ident, owned());
+            }
+            if let Some(relay) = &options.relay {
+                relay.send(&Relayed {
+                    ident: ident.to_string(),
+                    message: owned().clone(),
+                });
+            }
+            if let (Some(rdns), MessageRef::Starting(state) | MessageRef::Active(state) | MessageRef::Ended(state, _) | MessageRef::Failed(state, _)) = (&options.rdns, &message) {
+                rdns.submit(state.connection.dst.addr);
+            }
+            if let (Some(baseline), MessageRef::Starting(state) | MessageRef::Active(state) | MessageRef::Ended(state, _) | MessageRef::Failed(state, _)) = (&settings.baseline, &message) {
+                let conn = state.connection;
+                match db::retry(|| learn(db, ident, &conn, now, baseline)) {
+                    Ok(false) => (),
+                    Ok(true) => {
+                        let reason = format!("baseline: first connection to {}:{}/{}", conn.dst.addr, conn.dst.port, protocol_name(conn.protocol.iana_number()));
+                        println!("{}@{:?}: anomaly, {}", ident, peer, reason);
+                        if let Some(alerter) = &options.alerter {
+                            alerter.notify(reason, &event(), &labels());
+                        }
+                    },
+                    Err(e) => println!("{}@{:?}: failed to update baseline: {:?}", ident, peer, e),
+                }
+            }
+        },
+        Ok(false) => {
+            let duplicates = options.duplicates.fetch_add(1, Ordering::Relaxed) + 1;
+            println!("{}@{:?}: ignored duplicate message ({} so far)", ident, peer, duplicates);
+        },
+        Err(e) => {
+            let dropped = options.write_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            println!("{}@{:?}: dropped message after database error ({} dropped so far): {:?}", ident, peer, dropped, e);
+        },
+    }
+}
+
+#[cfg(feature = "geoip")]
+fn locate(options: &ClientOptions, addr: IpAddr) -> Location {
+    options.geoip.as_ref().map(|geoip| geoip.lookup(addr)).unwrap_or_default()
+}
+
+#[cfg(not(feature = "geoip"))]
+fn locate(_options: &ClientOptions, _addr: IpAddr) -> Location {
+    Location::default()
+}
+
+/// Write one message's rows in a single transaction, so a retry never half-applies it.
+///
+/// Returns false if the message was a duplicate of one already stored.
+fn store(db: &rusqlite::Connection, ident: &str, peername: &str, message: &MessageRef<'_>, now: SystemTime, reported: Option<SystemTime>, options: &ClientOptions) -> rusqlite::Result<bool> {
+    let txn = db.unchecked_transaction()?;
+    let stored = store_message(&txn, ident, peername, message, now, reported, options)?;
+    if stored {
+        track_active(&txn, ident, peername, message, now)?;
+    }
+    txn.commit()?;
+    Ok(stored)
+}
+
+/// Keep `active_now` in step with a stored message: opening and keepalive messages add or
+/// refresh the connection's row, closing ones remove it. Its `conntime` is when the session
+/// was first reported; a Starting message for a connection that's open starts a new one, as
+/// `sessions::reconstruct` has it.
+fn track_active(db: &rusqlite::Connection, ident: &str, peername: &str, message: &MessageRef<'_>, now: SystemTime) -> rusqlite::Result<()> {
+    let (state, mark) = match message {
+        MessageRef::Starting(state) => (state, Some(START_MARK)),
+        MessageRef::Active(state) => (state, Some(ACTIVE_MARK)),
+        MessageRef::Ended(state, _) | MessageRef::Failed(state, _) => (state, None),
+        MessageRef::Name(_, _) | MessageRef::Scan(_, _) => return Ok(()),
+    };
+    let conn = state.connection;
+    let (src, dst) = (conn.src, conn.dst);
+    match mark {
+        Some(mark) => db.prepare_cached("
+            INSERT INTO active_now (ident, peer, srchost, srcport, dsthost, dstport, proto, state, conntime, last_seen, reported_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?9)
+            ON CONFLICT (ident, srchost, srcport, dsthost, dstport, proto) DO UPDATE SET
+                peer = excluded.peer, state = excluded.state, last_seen = excluded.last_seen,
+                conntime = CASE excluded.state WHEN ?11 THEN excluded.conntime ELSE active_now.conntime END,
+                reported_at = CASE excluded.state WHEN ?11 THEN excluded.reported_at
+                    ELSE max(coalesce(active_now.reported_at, excluded.reported_at), excluded.reported_at) END;
+        ")?.execute(params![
+            ident, peername,
+            src.addr.to_string(), src.port,
+            dst.addr.to_string(), dst.port,
+            conn.protocol.iana_number(),
+            mark, to_float_secs(state.as_of), to_float_secs(now), START_MARK,
+        ])?,
+        None => db.prepare_cached("
+            DELETE FROM active_now
+            WHERE ident = ? AND srchost = ? AND srcport = ? AND dsthost = ? AND dstport = ? AND proto = ?;
+        ")?.execute(params![
+            ident,
+            src.addr.to_string(), src.port,
+            dst.addr.to_string(), dst.port,
+            conn.protocol.iana_number(),
+        ])?,
+    };
+    Ok(())
+}
+
+/// Writes messages that didn't come over a connection, like those read back from a capture
+/// file, through the same path as `accept`. Each is stored as of its own timestamp, and nothing
+/// is fanned out.
+pub(crate) struct Importer {
+    db: rusqlite::Connection,
+    options: ClientOptions,
+}
+
+impl Importer {
+    /// Open (and migrate) `database`, storing into whatever layout it already has.
+    pub(crate) fn open(database: &str) -> rusqlite::Result<Self> {
+        let mut db = db::open(database)?;
+        migrate(&mut db);
+        let partitions = Partitions::open(&mut db, false, to_float_secs(SystemTime::now()))?;
+        let options = ClientOptions {
+            settings: Arc::new(Live(RwLock::new(Arc::default()))),
+            idents: Arc::default(),
+            skews: Arc::default(),
+            partitions: Arc::new(partitions),
+            shards: None,
+            events: None,
+            forwarders: Vec::new(),
+            relay: None,
+            alerter: None,
+            callbacks: Arc::default(),
+            write_failures: Arc::default(),
+            duplicates: Arc::default(),
+            lost_frames: Arc::default(),
+            #[cfg(feature = "geoip")]
+            geoip: None,
+            rdns: None,
+            broadcast: Arc::default(),
+            changes: Arc::default(),
+        };
+        Ok(Self { db, options })
+    }
+
+    /// Store a batch of messages reported under `ident` in one transaction, returning how many
+    /// weren't duplicates of rows already there. `source` stands in for the peer address.
+    pub(crate) fn store(&mut self, ident: &str, source: &str, messages: &[Message]) -> rusqlite::Result<usize> {
+        let Some(last) = messages.last() else {
+            return Ok(0);
+        };
+        let txn = self.db.transaction()?;
+        let mut stored = 0;
+        for message in messages {
+            let message = &message.view();
+            let as_of = message.state().as_of;
+            if store_message(&txn, ident, source, message, as_of, None, &self.options)? {
+                track_active(&txn, ident, source, message, as_of)?;
+                stored += 1;
+            }
+        }
+        let (first, last) = (to_float_secs(messages[0].state().as_of), to_float_secs(last.state().as_of));
+        txn.execute("
+            INSERT INTO clients (ident, first_seen, last_seen) VALUES (?1, ?2, ?3)
+            ON CONFLICT (ident) DO UPDATE SET
+                first_seen = min(first_seen, excluded.first_seen), last_seen = max(last_seen, excluded.last_seen);
+        ", params![ident, first, last])?;
+        txn.commit()?;
+        Ok(stored)
+    }
+}
+
+/// Store one message; `reported` is the timestamp the sensor sent, if it was clamped.
+fn store_message(db: &rusqlite::Connection, ident: &str, peername: &str, message: &MessageRef<'_>, now: SystemTime, reported: Option<SystemTime>, options: &ClientOptions) -> rusqlite::Result<bool> {
+    let table = options.partitions.table(db, to_float_secs(now))?;
+    let mut stmt = db.prepare_cached(&format!(
+        "INSERT OR IGNORE INTO {}
+        (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, close, pkind, pcode, last_seen, dstcountry, dstasn, reported_conntime, repeats, rtt_micros)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+        ", table
+    ))?;
+    let reported = reported.map(to_float_secs);
+    let stored = match message {
+        MessageRef::Starting(state) => {
+            let conn = state.connection;
+            let (src, dst) = (conn.src, conn.dst);
+            let location = locate(options, dst.addr);
+            stmt.execute(params![
+                to_float_secs(now), to_float_secs(state.as_of),
+                ident, peername,
+                src.addr.to_string(), src.port,
+                dst.addr.to_string(), dst.port,
+                conn.protocol.iana_number(),
+                START_MARK, Null, Null, Null, to_float_secs(now),
+                location.country, location.asn, reported, Null, Null,
+            ])? > 0
+        },
+        MessageRef::Active(state) => {
+            let conn = state.connection;
+            // A handshake's round trip goes on a row of its own rather than into a refresh
+            if state.rtt_micros.is_none() && !options.settings.get().append_only {
+                if replayed_keepalive(db, ident, &conn, state.as_of)? {
+                    return Ok(false);
+                }
+                if refresh_active(db, &options.partitions, ident, &conn, now)? {
+                    return Ok(true);
+                }
+            }
+            let (src, dst) = (conn.src, conn.dst);
+            let location = locate(options, dst.addr);
+            stmt.execute(params![
+                to_float_secs(now), to_float_secs(state.as_of),
+                ident, peername,
+                src.addr.to_string(), src.port,
+                dst.addr.to_string(), dst.port,
+                conn.protocol.iana_number(),
+                ACTIVE_MARK, Null, Null, Null, to_float_secs(now),
+                location.country, location.asn, reported, Null, state.rtt_micros,
+            ])? > 0
+        },
+        MessageRef::Ended(state, closed) => {
+            let conn = state.connection;
+            let (src, dst) = (conn.src, conn.dst);
+            let location = locate(options, dst.addr);
+            stmt.execute(params![
+                to_float_secs(now), to_float_secs(state.as_of),
+                ident, peername,
+                src.addr.to_string(), src.port,
+                dst.addr.to_string(), dst.port,
+                conn.protocol.iana_number(),
+                ENDED_MARK, closed.number(), Null, Null, to_float_secs(now),
+                location.country, location.asn, reported, Null, Null,
+            ])? > 0
+        },
+        MessageRef::Failed(state, problem) => {
+            let conn = state.connection;
+            let (src, dst) = (conn.src, conn.dst);
+            let location = locate(options, dst.addr);
+            stmt.execute(params![
+                to_float_secs(now), to_float_secs(state.as_of),
+                ident, peername,
+                src.addr.to_string(), src.port,
+                dst.addr.to_string(), dst.port,
+                conn.protocol.iana_number(),
+                FAILED_MARK, Null, problem.kind, problem.code, to_float_secs(now),
+                location.country, location.asn, reported, (problem.repeats > 0).then_some(problem.repeats), Null,
+            ])? > 0
+        },
+        MessageRef::Name(state, names) => {
+            let mut name_stmt = db.prepare_cached("
+                INSERT INTO names
+                (instime, querier, responder, name, addr, port, text, rname)
+                VALUES
+                (?, ?, ?, ?, ?, ?, ?, ?);
+            ")?;
+            let (querier, responder) = if state.connection.src.port == 53 {
+                (state.connection.dst.addr, state.connection.src.addr)
+            } else {
+                (state.connection.src.addr, state.connection.dst.addr)
+            };
+            for name in names.iter() {
+                let nm = name.name;
+                let (addr, port, text) = match name.address {
+                    Some(ResolutionRef::Address(addr)) => (Some(addr.to_string()), None, None),
+                    Some(ResolutionRef::Alias(name)) => (Some(name.to_string()), None, None),
+                    Some(ResolutionRef::Service(name, port)) => (Some(name.to_string()), port, None),
+                    Some(ResolutionRef::Text(texts)) => (None, None, Some(texts.rdata())),
+                    None => (None, None, None),
+                };
+                name_stmt.execute(params![
+                    to_float_secs(now),
+                    querier.to_string(),
+                    responder.to_string(),
+                    nm, addr, port, text, reversed_name(nm),
+                ])?;
+            }
+            true
+        },
+        MessageRef::Scan(state, scan) => {
+            let conn = state.connection;
+            let (dsthost, dstport) = match scan.kind {
+                ScanKind::Ports => (Some(conn.dst.addr.to_string()), None),
+                ScanKind::Hosts => (None, Some(conn.dst.port)),
+            };
+            db.prepare_cached("
+                INSERT OR IGNORE INTO scans
+                (instime, scantime, ident, peer, srchost, dsthost, dstport, proto, kind, count, window)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);
+            ")?.execute(params![
+                to_float_secs(now), to_float_secs(state.as_of),
+                ident, peername,
+                conn.src.addr.to_string(), dsthost, dstport,
+                conn.protocol.iana_number(),
+                scan.kind.name(), scan.count, scan.window.as_secs_f64(),
+            ])? > 0
+        },
+    };
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{observe::{Closed, Endpoint, Problem, State}, query::{self, SessionFilter}, sessions::Ending, test_support::{Random, Scratch}};
+
+    use super::*;
+
+    /// An importer on a database of the test's own; in-memory ones are shared by the process.
+    fn open_importer(scratch: &Scratch) -> Importer {
+        Importer::open(scratch.database()).unwrap()
+    }
+
+    fn at(secs: f64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs)
+    }
+
+    fn state(srcport: u16, protocol: Protocol, as_of: f64) -> State {
+        State {
+            as_of: at(as_of),
+            connection: Connection {
+                interface: 0,
+                src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port: srcport },
+                dst: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), port: 443 },
+                protocol,
+            },
+            rtt_micros: None,
+        }
+    }
+
+    /// Rows of the synthesized closes there are, as (srcport, proto, conntime, opened_at).
+    fn timeouts(db: &rusqlite::Connection) -> Vec<(u16, u8, f64, Option<f64>)> {
+        db.prepare("SELECT srcport, proto, conntime, opened_at FROM state_all WHERE close = ? ORDER BY srcport, proto").unwrap()
+            .query_map(params![TMOUT_MARK], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn open_ports(db: &rusqlite::Connection) -> Vec<u16> {
+        db.prepare("SELECT srcport FROM active_now ORDER BY srcport").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn all_sessions(db: &rusqlite::Connection) -> Vec<(u16, Option<f64>, f64, Ending)> {
+        let filter = SessionFilter { since: 0.0, until: f64::MAX, ..Default::default() };
+        query::sessions(db, &filter, usize::MAX).unwrap().into_iter()
+            .map(|session| (session.srcport, session.start, session.end, session.ending))
+            .collect()
+    }
+
+    #[test]
+    fn the_example_config_sets_every_field() {
+        let settings = ServerSettings::load(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/glosco-server.example.toml"))).unwrap();
+        // Taken apart whole, so a field added without a line here doesn't build
+        let ServerSettings {
+            bind, database, snapshot, snapshot_interval, tcp_timeout, udp_timeout, maintenance, append_only, partition,
+            retention, names_retention, max_db_size, shard_by_ident, shard_handles, ident_collision, reject_legacy,
+            workers, pending, async_io, max_skew, skew_policy, forward, relay, replicate, mesh_listen, mesh_peers,
+            mesh_peer_file, mesh_peer_horizon, remote_query_token, remote_query_limit, geoip, event_log, rdns, alerts,
+            baseline, api, report,
+        } = settings;
+        assert_eq!(bind, "0.0.0.0:12074".parse().unwrap());
+        assert_eq!(database, "glosco.db");
+        // Commented out, so as the defaults have them
+        assert_eq!((snapshot, snapshot_interval), (None, ServerSettings::default().snapshot_interval));
+        assert_eq!(names_retention, None);
+        assert_eq!((tcp_timeout, udp_timeout, maintenance), (60.0, 30.0, 5.0));
+        assert!(!append_only && !partition && !shard_by_ident && !reject_legacy && !async_io);
+        assert_eq!((retention, max_db_size), (Some(2592000.0), Some(5000000000)));
+        assert_eq!(shard_handles, 64);
+        assert_eq!(ident_collision, CollisionPolicy::Warn);
+        assert_eq!((workers, pending), (256, 256));
+        assert_eq!((max_skew, skew_policy), (600.0, SkewPolicy::Clamp));
+        assert_eq!(forward, [Target::Mqtt { host: "broker.example.com".to_string(), port: 1883, topic: "glosco".to_string() }]);
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        assert_eq!(relay, [addr("192.0.2.10:12074")]);
+        assert_eq!(replicate, Some(addr("192.0.2.20:12074")));
+        assert_eq!(mesh_listen, [addr("0.0.0.0:12076")]);
+        assert_eq!(mesh_peers, [addr("192.0.2.31:12076")]);
+        assert_eq!((mesh_peer_file, mesh_peer_horizon), (Some(PathBuf::from("glosco-mesh-peers.json")), 604800.0));
+        assert_eq!((remote_query_token.as_deref(), remote_query_limit), (Some("change-me"), 10000));
+        assert_eq!(geoip, [PathBuf::from("/var/lib/GeoIP/GeoLite2-Country.mmdb"), PathBuf::from("/var/lib/GeoIP/GeoLite2-ASN.mmdb")]);
+        assert_eq!(event_log, Some(EventLogSettings {
+            path: PathBuf::from("glosco-events.ndjson"),
+            max_bytes: Some(104857600),
+            max_age: Some(86400.0),
+            gzip: true,
+        }));
+        assert_eq!(rdns, Some(RdnsSettings { server: Some("127.0.0.53:53".parse().unwrap()), workers: Some(4), rate: Some(20.0) }));
+        assert_eq!(alerts, Some(AlertSettings {
+            webhook: "https://hooks.example.com/glosco".to_string(),
+            rules: ["kind=failed,dst=10.0.0.0/8,port=5432,window=300", "kind=failed,kind=reset,ident=db-*", "kind=scan,window=3600"]
+                .into_iter().map(|rule| rule.parse().unwrap()).collect(),
+        }));
+        assert_eq!(baseline, Some(BaselineSettings { granularity: Granularity::Port, learning: 604800.0 }));
+        assert_eq!(api, Some(ApiSettings {
+            bind: "127.0.0.1:12080".parse().unwrap(),
+            token: Some("change-me".to_string()),
+            ingest: false,
+            ingest_max_bytes: Some(1048576),
+            ingest_rate: Some(1000.0),
+        }));
+        assert_eq!(report, Some(ReportSettings {
+            path: PathBuf::from("/var/lib/glosco/reports"),
+            interval: report::Interval::Daily,
+            format: report::Format::Markdown,
+            sections: report::Section::ALL.to_vec(),
+        }));
+    }
+
+    #[test]
+    fn a_reload_keeps_what_cant_change_while_running() {
+        let live = Live(RwLock::new(Arc::new(ServerSettings {
+            database: db::MEMORY.to_string(),
+            snapshot: Some(PathBuf::from("before.db")),
+            remote_query_token: Some("before".to_string()),
+            ..Default::default()
+        })));
+        apply(&live, ServerSettings {
+            database: db::MEMORY.to_string(),
+            snapshot: Some(PathBuf::from("after.db")),
+            snapshot_interval: 1.0,
+            remote_query_token: Some("after".to_string()),
+            retention: Some(3600.0),
+            ..Default::default()
+        }, None);
+        let settings = live.get();
+        assert_eq!(settings.snapshot, Some(PathBuf::from("before.db")));
+        assert_eq!(settings.snapshot_interval, ServerSettings::default().snapshot_interval);
+        assert_eq!(settings.remote_query_token.as_deref(), Some("before"));
+        assert_eq!(settings.retention, Some(3600.0), "what can change does");
+    }
+
+    #[test]
+    fn maintenance_times_out_quiet_connections_only() {
+        let scratch = Scratch::new("maintenance");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Active(state(1, Protocol::Tcp, 1030.0)),
+            Message::Starting(state(2, Protocol::Tcp, 1090.0)),
+            Message::Starting(state(3, Protocol::Udp, 1000.0)),
+            // Closed as it was reported; nothing to time out
+            Message::Ended(state(4, Protocol::Udp, 1000.0), Closed::Connectionless),
+            // Ended long ago, as TCP does; nothing to time out either
+            Message::Starting(state(5, Protocol::Tcp, 1000.0)),
+            Message::Ended(state(5, Protocol::Tcp, 1010.0), Closed::Normally),
+            // Quiet for longer than the UDP timeout but not the TCP one: each goes by its own
+            Message::Starting(state(6, Protocol::Udp, 1050.0)),
+            Message::Starting(state(7, Protocol::Tcp, 1050.0)),
+        ]).unwrap();
+        let settings = ServerSettings { tcp_timeout: 60.0, udp_timeout: 30.0, ..Default::default() };
+        let partitions = importer.options.partitions.clone();
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1100.0));
+
+        assert_eq!(timeouts(&importer.db), [(1, 6, 1100.0, Some(1000.0)), (3, 17, 1100.0, Some(1000.0)), (6, 17, 1100.0, Some(1050.0))]);
+        assert_eq!(open_ports(&importer.db), [2, 7]);
+        assert_eq!(all_sessions(&importer.db), [
+            (1, Some(1000.0), 1030.0, Ending::Timeout),
+            (2, Some(1090.0), 1090.0, Ending::Open),
+            (3, Some(1000.0), 1000.0, Ending::Timeout),
+            (4, None, 1000.0, Ending::Ended),
+            (5, Some(1000.0), 1010.0, Ending::Ended),
+            (6, Some(1050.0), 1050.0, Ending::Timeout),
+            (7, Some(1050.0), 1050.0, Ending::Open),
+        ]);
+
+        // A tick later, what's timed out already isn't again
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1101.0));
+        assert_eq!(timeouts(&importer.db).len(), 3);
+    }
+
+    #[test]
+    fn maintenance_starts_over_on_a_new_connection_after_an_error() {
+        let scratch = Scratch::new("maintenance-reopen");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[Message::Starting(state(1, Protocol::Tcp, 1000.0))]).unwrap();
+        let settings = ServerSettings { tcp_timeout: 60.0, ..Default::default() };
+        let partitions = importer.options.partitions.clone();
+        let path = scratch.database();
+        let changes: Arc<Changes> = Arc::default();
+        let mut conn = None;
+        assert!(maintain_on(&mut conn, path, &changes, &settings, &[], &partitions, 1010.0));
+        assert!(conn.is_some(), "kept for the next tick");
+
+        // Nothing that tick can write now goes through, on this connection alone
+        conn.as_ref().unwrap().pragma_update(None, "query_only", true).unwrap();
+        assert!(!maintain_on(&mut conn, path, &changes, &settings, &[], &partitions, 1100.0));
+        assert!(conn.is_none(), "a failed tick gives up its connection");
+        assert!(timeouts(&importer.db).is_empty());
+
+        assert!(maintain_on(&mut conn, path, &changes, &settings, &[], &partitions, 1100.0));
+        assert!(conn.is_some());
+        assert_eq!(timeouts(&importer.db), [(1, 6, 1100.0, Some(1000.0))]);
+    }
+
+    /// A tick on a kept connection against one opened for it, as every tick once was, on a few
+    /// hundred thousand rows; run it with `cargo test --release -- --ignored maintenance_on_a_kept`.
+    #[test]
+    #[ignore]
+    fn maintenance_on_a_kept_connection_outpaces_opening_one_each_tick() {
+        const ROWS: i64 = 500_000;
+        const TICKS: usize = 20;
+        let scratch = Scratch::new("maintenance-timing");
+        let importer = open_importer(&scratch);
+        // Open connections from 20 sensors, all seen within the last minute so none time out
+        importer.db.execute("
+            INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, last_seen)
+            WITH RECURSIVE n(i) AS (SELECT 0 UNION ALL SELECT i + 1 FROM n WHERE i < :rows - 1)
+            SELECT :start, :start, 'sensor' || (i % 20), '127.0.0.1:40000', '10.0.' || (i / 60000) || '.1', i % 60000,
+                '10.1.0.2', 443, 6, :start_mark, :start
+            FROM n;
+        ", named_params! { ":rows": ROWS, ":start": 1000.0, ":start_mark": START_MARK }).unwrap();
+        let settings = ServerSettings { tcp_timeout: 600.0, udp_timeout: 600.0, ..Default::default() };
+        let partitions = importer.options.partitions.clone();
+        let path = scratch.database();
+        let changes: Arc<Changes> = Arc::default();
+        let time = |keep: bool| {
+            let mut conn = None;
+            let started = std::time::Instant::now();
+            for tick in 0 .. TICKS {
+                if !keep {
+                    conn = None;
+                }
+                assert!(maintain_on(&mut conn, path, &changes, &settings, &[], &partitions, 1010.0 + tick as f64));
+            }
+            started.elapsed() / TICKS as u32
+        };
+        let opened = time(false);
+        let kept = time(true);
+        println!("{} rows: {:?} a tick opening a connection, {:?} on a kept one", ROWS, opened, kept);
+        assert!(kept < opened, "{:?} on a kept connection, {:?} opening one", kept, opened);
+    }
+
+    /// Each connection's messages, and when the close synthesized for it should say it opened:
+    /// the earliest open since it last closed.
+    fn reopened_connections() -> Vec<(u16, Vec<Message>, Option<f64>)> {
+        vec![
+            (1, vec![
+                Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+                Message::Active(state(1, Protocol::Tcp, 1010.0)),
+                Message::Active(state(1, Protocol::Tcp, 1020.0)),
+            ], Some(1000.0)),
+            // Closed and opened again: only the second time counts
+            (2, vec![
+                Message::Starting(state(2, Protocol::Tcp, 1000.0)),
+                Message::Ended(state(2, Protocol::Tcp, 1005.0), Closed::Normally),
+                Message::Starting(state(2, Protocol::Tcp, 1020.0)),
+                Message::Active(state(2, Protocol::Tcp, 1030.0)),
+            ], Some(1020.0)),
+            // Twice over, the last time without a keepalive since
+            (3, vec![
+                Message::Starting(state(3, Protocol::Tcp, 1000.0)),
+                Message::Ended(state(3, Protocol::Tcp, 1005.0), Closed::Normally),
+                Message::Starting(state(3, Protocol::Tcp, 1010.0)),
+                Message::Ended(state(3, Protocol::Tcp, 1015.0), Closed::Reset),
+                Message::Starting(state(3, Protocol::Tcp, 1025.0)),
+            ], Some(1025.0)),
+            // First seen part way through, by a keepalive
+            (4, vec![
+                Message::Active(state(4, Protocol::Tcp, 1010.0)),
+                Message::Active(state(4, Protocol::Tcp, 1020.0)),
+            ], Some(1010.0)),
+            // Open again after a failure
+            (5, vec![
+                Message::Starting(state(5, Protocol::Tcp, 1000.0)),
+                Message::Failed(state(5, Protocol::Tcp, 1005.0), Problem { kind: 3, code: 1, repeats: 0 }),
+                Message::Starting(state(5, Protocol::Tcp, 1020.0)),
+            ], Some(1020.0)),
+        ]
+    }
+
+    fn opened_at(db: &rusqlite::Connection) -> Vec<(u16, Option<f64>)> {
+        timeouts(db).into_iter().map(|(port, _, _, opened)| (port, opened)).collect()
+    }
+
+    #[test]
+    fn a_timeout_says_when_its_connection_last_opened() {
+        let scratch = Scratch::new("opened-timeout");
+        let mut importer = open_importer(&scratch);
+        let cases = reopened_connections();
+        for (_, messages, _) in cases.iter() {
+            importer.store("sensor", "127.0.0.1:40000", messages).unwrap();
+        }
+        let settings = ServerSettings { tcp_timeout: 60.0, ..Default::default() };
+        let partitions = importer.options.partitions.clone();
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1100.0));
+        let expected: Vec<_> = cases.iter().map(|(port, _, opened)| (*port, *opened)).collect();
+        assert_eq!(opened_at(&importer.db), expected);
+
+        // Timed out, then opened again and timed out again: the second close goes by the reopen
+        importer.store("sensor", "127.0.0.1:40000", &[Message::Starting(state(1, Protocol::Tcp, 1200.0))]).unwrap();
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1300.0));
+        let closes = opened_at(&importer.db);
+        assert_eq!(closes.iter().filter(|(port, _)| *port == 1).collect::<Vec<_>>(), [&(1, Some(1000.0)), &(1, Some(1200.0))]);
+        let sessions = all_sessions(&importer.db);
+        assert_eq!(sessions.iter().filter(|session| session.0 == 1).map(|session| (session.1, session.3)).collect::<Vec<_>>(), [
+            (Some(1000.0), Ending::Timeout),
+            (Some(1200.0), Ending::Timeout),
+        ]);
+    }
+
+    #[test]
+    fn a_disconnect_says_when_each_connection_it_closes_last_opened() {
+        let scratch = Scratch::new("opened-disconnect");
+        let mut importer = open_importer(&scratch);
+        let cases = reopened_connections();
+        for (_, messages, _) in cases.iter() {
+            importer.store("sensor", "127.0.0.1:40000", messages).unwrap();
+        }
+        let partitions = importer.options.partitions.clone();
+        let session = session_started(&importer.db, "sensor", "127.0.0.1:40000", None, false).unwrap();
+        assert_eq!(session_ended(&mut importer.db, &partitions, "sensor", Some("127.0.0.1:40000"), Some(session), 0, None).unwrap(), cases.len());
+        let expected: Vec<_> = cases.iter().map(|(port, _, opened)| (*port, *opened)).collect();
+        assert_eq!(opened_at(&importer.db), expected);
+    }
+
+    #[test]
+    fn a_disconnect_closes_that_peers_connections() {
+        let scratch = Scratch::new("disconnect");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Active(state(1, Protocol::Tcp, 1010.0)),
+            Message::Starting(state(2, Protocol::Tcp, 1000.0)),
+            Message::Ended(state(2, Protocol::Tcp, 1005.0), Closed::Normally),
+        ]).unwrap();
+        importer.store("sensor", "127.0.0.1:40001", &[Message::Starting(state(3, Protocol::Tcp, 1000.0))]).unwrap();
+        let partitions = importer.options.partitions.clone();
+        let first = session_started(&importer.db, "sensor", "127.0.0.1:40000", None, false).unwrap();
+        let second = session_started(&importer.db, "sensor", "127.0.0.1:40001", None, false).unwrap();
+        let session = |db: &rusqlite::Connection, id: i64| -> (String, f64, Option<f64>, i64) {
+            db.query_row("SELECT peer, connected, disconnected, frames FROM client_sessions WHERE rowid = ?", params![id], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            }).unwrap()
+        };
+
+        let before = to_float_secs(SystemTime::now());
+        assert_eq!(session_ended(&mut importer.db, &partitions, "sensor", Some("127.0.0.1:40000"), Some(first), 5, Some(4)).unwrap(), 1);
+        let after = to_float_secs(SystemTime::now());
+        let closes = timeouts(&importer.db);
+        assert_eq!(closes.iter().map(|(port, _, _, opened)| (*port, *opened)).collect::<Vec<_>>(), [(1, Some(1000.0))]);
+        // Stamped with when the peer went
+        assert!((before ..= after).contains(&closes[0].2), "{} not in {} ..= {}", closes[0].2, before, after);
+        assert_eq!(open_ports(&importer.db), [3]);
+        let sessions = all_sessions(&importer.db);
+        assert_eq!(sessions[0], (1, Some(1000.0), 1010.0, Ending::Timeout));
+        assert_eq!(sessions[1], (2, Some(1000.0), 1005.0, Ending::Ended));
+
+        // The session that ended is recorded as ending then, with what it sent; the other's open
+        let (peer, connected, disconnected, frames) = session(&importer.db, first);
+        assert_eq!((peer.as_str(), frames), ("127.0.0.1:40000", 5));
+        let disconnected = disconnected.expect("the session wasn't ended");
+        assert!(connected <= disconnected && (before ..= after).contains(&disconnected), "{} .. {}", connected, disconnected);
+        assert_eq!(session(&importer.db, second).2, None);
+        let (last_seen, next_seq): (f64, Option<i64>) = importer.db.query_row("SELECT last_seen, next_seq FROM clients WHERE ident = 'sensor'", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((last_seen, next_seq), (disconnected, Some(4)));
+
+        // Without a peer, whatever the ident has open goes
+        assert_eq!(session_ended(&mut importer.db, &partitions, "sensor", None, Some(second), 1, None).unwrap(), 1);
+        assert!(open_ports(&importer.db).is_empty());
+        assert!(session(&importer.db, second).2.is_some());
+        // Ending without a sequence to resume from leaves the one recorded before
+        assert_eq!(importer.db.query_row("SELECT next_seq FROM clients WHERE ident = 'sensor'", [], |row| row.get::<_, Option<i64>>(0)).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn a_disconnect_leaves_what_maintenance_timed_out_closed_once() {
+        let scratch = Scratch::new("disconnect-timed-out");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Starting(state(2, Protocol::Tcp, 1000.0)),
+            Message::Active(state(2, Protocol::Tcp, 1090.0)),
+        ]).unwrap();
+        let settings = ServerSettings { tcp_timeout: 60.0, ..Default::default() };
+        let partitions = importer.options.partitions.clone();
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1100.0));
+        let session = session_started(&importer.db, "sensor", "127.0.0.1:40000", None, false).unwrap();
+
+        assert_eq!(session_ended(&mut importer.db, &partitions, "sensor", Some("127.0.0.1:40000"), Some(session), 0, None).unwrap(), 1);
+        // One close each, both kept in the state they timed out in
+        let closes: Vec<(u16, u8)> = importer.db.prepare("SELECT srcport, state FROM state_all WHERE close = ? ORDER BY srcport").unwrap()
+            .query_map(params![TMOUT_MARK], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(closes, [(1, START_MARK), (2, ACTIVE_MARK)]);
+        let sessions = all_sessions(&importer.db);
+        assert_eq!(sessions.iter().map(|session| (session.0, session.1, session.3)).collect::<Vec<_>>(), [
+            (1, Some(1000.0), Ending::Timeout),
+            (2, Some(1000.0), Ending::Timeout),
+        ]);
+    }
+
+    #[test]
+    fn a_snapshot_closes_what_it_no_longer_has() {
+        let scratch = Scratch::new("snapshot");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Starting(state(2, Protocol::Tcp, 1000.0)),
+            // Opened after the snapshot was taken, so its absence says nothing
+            Message::Starting(state(3, Protocol::Tcp, 2000.0)),
+        ]).unwrap();
+        let partitions = importer.options.partitions.clone();
+        let snapshot = Snapshot {
+            as_of: at(1500.0),
+            states: vec![
+                Message::Active(state(1, Protocol::Tcp, 1500.0)),
+                Message::Active(state(4, Protocol::Tcp, 1500.0)),
+            ],
+        };
+
+        let (closed, unknown) = reconcile(&mut importer.db, &partitions, "sensor", &snapshot).unwrap();
+        assert_eq!(closed, 1);
+        assert_eq!(unknown, [Message::Active(state(4, Protocol::Tcp, 1500.0))]);
+        assert_eq!(timeouts(&importer.db).iter().map(|(port, _, _, opened)| (*port, *opened)).collect::<Vec<_>>(), [(2, Some(1000.0))]);
+        assert_eq!(open_ports(&importer.db), [1, 3]);
+    }
+
+    #[test]
+    fn a_snapshot_leaves_what_maintenance_timed_out_closed_once() {
+        let scratch = Scratch::new("snapshot-timed-out");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Starting(state(2, Protocol::Tcp, 1000.0)),
+            Message::Active(state(2, Protocol::Tcp, 1090.0)),
+        ]).unwrap();
+        let settings = ServerSettings { tcp_timeout: 60.0, ..Default::default() };
+        let partitions = importer.options.partitions.clone();
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1100.0));
+
+        let (closed, unknown) = reconcile(&mut importer.db, &partitions, "sensor", &Snapshot { as_of: at(1100.0), states: Vec::new() }).unwrap();
+        assert_eq!((closed, unknown), (1, Vec::new()));
+        let closes: Vec<(u16, u8)> = importer.db.prepare("SELECT srcport, state FROM state_all WHERE close = ? ORDER BY srcport").unwrap()
+            .query_map(params![TMOUT_MARK], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(closes, [(1, START_MARK), (2, ACTIVE_MARK)]);
+        assert!(open_ports(&importer.db).is_empty());
+    }
+
+    #[test]
+    fn a_connection_reopened_on_the_same_tuple_is_open_once() {
+        let scratch = Scratch::new("reopen");
+        let mut importer = open_importer(&scratch);
+        importer.store("sensor", "127.0.0.1:40000", &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Active(state(1, Protocol::Tcp, 1010.0)),
+            Message::Ended(state(1, Protocol::Tcp, 1020.0), Closed::Normally),
+            Message::Starting(state(1, Protocol::Tcp, 1030.0)),
+            Message::Active(state(1, Protocol::Tcp, 1040.0)),
+        ]).unwrap();
+
+        let open: Vec<(u16, f64, f64)> = importer.db.prepare("SELECT srcport, conntime, last_seen FROM active_now").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(open, [(1, 1030.0, 1040.0)]);
+        // The keepalive after the reopen refreshed nothing from before the close
+        let actives: Vec<(f64, f64)> = importer.db.prepare("SELECT conntime, last_seen FROM state_all WHERE state = ? ORDER BY conntime").unwrap()
+            .query_map(params![ACTIVE_MARK], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(actives, [(1010.0, 1010.0), (1040.0, 1040.0)]);
+        let sessions = all_sessions(&importer.db);
+        assert_eq!(sessions, [(1, Some(1000.0), 1020.0, Ending::Ended), (1, Some(1030.0), 1040.0, Ending::Open)]);
+    }
+
+    /// What the log has open, as (srcport, proto): the connections whose last row is a Starting
+    /// or an Active that wasn't timed out. Checks `active_now` has exactly the same.
+    fn open_as_logged(db: &rusqlite::Connection) -> Vec<(u16, u8)> {
+        let mut last: BTreeMap<(u16, u8), (u8, Option<u8>)> = BTreeMap::new();
+        let rows = db.prepare("SELECT srcport, proto, state, close FROM state_all ORDER BY instime, conntime").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+            .collect::<rusqlite::Result<Vec<(u16, u8, u8, Option<u8>)>>>().unwrap();
+        for (srcport, proto, state, close) in rows {
+            last.insert((srcport, proto), (state, close));
+        }
+        let logged: Vec<_> = last.into_iter()
+            .filter(|(_, (state, close))| [START_MARK, ACTIVE_MARK].contains(state) && close.is_none())
+            .map(|(conn, _)| conn)
+            .collect();
+        let materialized: Vec<(u16, u8)> = db.prepare("SELECT srcport, proto FROM active_now ORDER BY srcport, proto").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(materialized, logged, "active_now and the log disagree");
+        logged
+    }
+
+    #[test]
+    fn active_now_keeps_up_with_the_log_through_keepalives_reopens_and_a_lost_sensor() {
+        let scratch = Scratch::new("active-now");
+        let mut importer = open_importer(&scratch);
+        let partitions = importer.options.partitions.clone();
+        let session = session_started(&importer.db, "sensor", "127.0.0.1:40000", None, false).unwrap();
+        let store = |importer: &mut Importer, messages: &[Message]| {
+            importer.store("sensor", "127.0.0.1:40000", messages).unwrap();
+        };
+
+        // Keepalives, for connections opened here and for one first seen by its keepalive
+        store(&mut importer, &[
+            Message::Starting(state(1, Protocol::Tcp, 1000.0)),
+            Message::Active(state(1, Protocol::Tcp, 1010.0)),
+            Message::Active(state(1, Protocol::Tcp, 1020.0)),
+            Message::Active(state(2, Protocol::Tcp, 1010.0)),
+            Message::Starting(state(3, Protocol::Udp, 1000.0)),
+        ]);
+        assert_eq!(open_as_logged(&importer.db), [(1, 6), (2, 6), (3, 17)]);
+        let reported = |db: &rusqlite::Connection| -> Vec<(u16, f64)> {
+            query::active(db, &Default::default(), usize::MAX).unwrap().into_iter().map(|active| (active.srcport, active.conntime)).collect()
+        };
+        assert_eq!(reported(&importer.db), [(1, 1000.0), (3, 1000.0), (2, 1010.0)]);
+
+        // Closed and reopened on the same tuple, once normally and once after failing
+        store(&mut importer, &[
+            Message::Ended(state(1, Protocol::Tcp, 1030.0), Closed::Normally),
+            Message::Failed(state(2, Protocol::Tcp, 1030.0), Problem { kind: 3, code: 1, repeats: 0 }),
+        ]);
+        assert_eq!(open_as_logged(&importer.db), [(3, 17)]);
+        store(&mut importer, &[
+            Message::Starting(state(1, Protocol::Tcp, 1040.0)),
+            Message::Active(state(1, Protocol::Tcp, 1050.0)),
+            Message::Starting(state(2, Protocol::Tcp, 1040.0)),
+            Message::Ended(state(3, Protocol::Udp, 1040.0), Closed::Connectionless),
+        ]);
+        assert_eq!(open_as_logged(&importer.db), [(1, 6), (2, 6)]);
+        assert_eq!(reported(&importer.db), [(1, 1040.0), (2, 1040.0)]);
+
+        // Gone quiet past the timeout, one of them goes; a keepalive after brings it back
+        let settings = ServerSettings { tcp_timeout: 60.0, ..Default::default() };
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, 1105.0));
+        assert_eq!(open_as_logged(&importer.db), [(1, 6)]);
+        store(&mut importer, &[Message::Active(state(2, Protocol::Tcp, 1110.0))]);
+        assert_eq!(open_as_logged(&importer.db), [(1, 6), (2, 6)]);
+
+        // And once the sensor's lost, it has nothing open anywhere
+        assert_eq!(session_ended(&mut importer.db, &partitions, "sensor", Some("127.0.0.1:40000"), Some(session), 0, None).unwrap(), 2);
+        assert!(open_as_logged(&importer.db).is_empty());
+        assert!(reported(&importer.db).is_empty());
+    }
+
+    #[test]
+    fn replayed_keepalives_are_duplicates() {
+        let scratch = Scratch::new("replay");
+        let importer = open_importer(&scratch);
+        let (_, subscriber) = importer.options.broadcast.subscribe(Subscribe::default());
+        let (ident, peer): (Arc<str>, SocketAddr) = (Arc::from("sensor"), "127.0.0.1:40000".parse().unwrap());
+        let peername: Arc<str> = Arc::from("127.0.0.1:40000");
+        // Timestamped as of now, so that none of them are skewed
+        let base = to_float_secs(SystemTime::now()).floor();
+        let send = |message: Message| accept(message.view(), &ident, peer, &importer.db, &peername, &importer.options);
+
+        send(Message::Starting(state(1, Protocol::Tcp, base)));
+        send(Message::Active(state(1, Protocol::Tcp, base + 1.0)));
+        send(Message::Active(state(1, Protocol::Tcp, base + 2.0)));
+        // A reconnecting client sending them all again
+        send(Message::Starting(state(1, Protocol::Tcp, base)));
+        send(Message::Active(state(1, Protocol::Tcp, base + 1.0)));
+        send(Message::Active(state(1, Protocol::Tcp, base + 2.0)));
+
+        assert_eq!(importer.options.duplicates.load(Ordering::Relaxed), 3);
+        assert_eq!(subscriber.try_iter().count(), 3);
+        let reported: f64 = importer.db.query_row("SELECT reported_at FROM active_now", [], |row| row.get(0)).unwrap();
+        assert_eq!(reported, base + 2.0);
+        // One Active row, refreshed by the second keepalive and nothing after
+        let actives: i64 = importer.db.query_row("SELECT COUNT(*) FROM state_all WHERE state = ?", params![ACTIVE_MARK], |row| row.get(0)).unwrap();
+        assert_eq!(actives, 1);
+
+        // Whereas a later one is news
+        send(Message::Active(state(1, Protocol::Tcp, base + 3.0)));
+        assert_eq!(importer.options.duplicates.load(Ordering::Relaxed), 3);
+        assert_eq!(subscriber.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn a_timed_handshake_is_stored_and_an_untimed_one_is_null() {
+        let scratch = Scratch::new("rtt");
+        let mut importer = open_importer(&scratch);
+        let timed = State { rtt_micros: Some(12500), ..state(1, Protocol::Tcp, 1000.0) };
+        // Both on the wire and back, as a sensor from before round trips would send the second
+        let decoded: Vec<Message> = [Message::Active(timed), Message::Active(state(2, Protocol::Tcp, 1000.0))].iter().map(|message| {
+            let mut bytes = Vec::new();
+            message.encode(&mut bytes).unwrap();
+            Message::decode(&mut &bytes[..]).unwrap()
+        }).collect();
+        assert_eq!(decoded[0], Message::Active(timed));
+        importer.store("sensor", "127.0.0.1:40000", &decoded).unwrap();
+        // A keepalive after it refreshes that row, round trip and all
+        importer.store("sensor", "127.0.0.1:40000", &[Message::Active(state(1, Protocol::Tcp, 1030.0))]).unwrap();
+
+        let rtts: Vec<(u16, Option<u32>, f64)> = importer.db.prepare("SELECT srcport, rtt_micros, last_seen FROM state_all ORDER BY srcport").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(rtts, [(1, Some(12500), 1030.0), (2, None, 1000.0)]);
+    }
+
+    /// Send a Starting message for each (srcport, as_of) of `cases` through `accept`, with a
+    /// skew limit of 600s and `policy`.
+    fn accept_skewed(importer: &Importer, policy: SkewPolicy, cases: &[(u16, f64)]) {
+        *importer.options.settings.0.write().unwrap() = Arc::new(ServerSettings { max_skew: 600.0, skew_policy: policy, ..Default::default() });
+        let ident: Arc<str> = Arc::from("sensor");
+        let peername: Arc<str> = Arc::from("127.0.0.1:40000");
+        for &(port, as_of) in cases {
+            let message = Message::Starting(state(port, Protocol::Tcp, as_of));
+            accept(message.view(), &ident, "127.0.0.1:40000".parse().unwrap(), &importer.db, &peername, &importer.options);
+        }
+    }
+
+    /// The rows stored, as (srcport, conntime, reported_conntime).
+    fn conntimes(db: &rusqlite::Connection) -> Vec<(u16, f64, Option<f64>)> {
+        db.prepare("SELECT srcport, conntime, reported_conntime FROM state_all ORDER BY srcport").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    /// Two hours ahead, the epoch itself, and either side of the limit in both directions.
+    fn skewed_cases(now: f64) -> [(u16, f64); 6] {
+        [(1, now + 7200.0), (2, 0.0), (3, now + 590.0), (4, now - 590.0), (5, now + 610.0), (6, now - 610.0)]
+    }
+
+    #[test]
+    fn skewed_timestamps_are_clamped_to_arrival() {
+        let scratch = Scratch::new("skew-clamp");
+        let importer = open_importer(&scratch);
+        let before = to_float_secs(SystemTime::now());
+        let cases = skewed_cases(before);
+        accept_skewed(&importer, SkewPolicy::Clamp, &cases);
+        let after = to_float_secs(SystemTime::now());
+
+        let rows = conntimes(&importer.db);
+        assert_eq!(rows.iter().map(|(port, _, _)| *port).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6]);
+        for ((port, conntime, reported), (_, sent)) in rows.into_iter().zip(cases) {
+            if matches!(port, 3 | 4) {
+                // Within the limit, stored as sent
+                assert_eq!((conntime, reported), (sent, None), "port {}", port);
+            } else {
+                assert!((before ..= after).contains(&conntime), "port {}: {} not clamped", port, conntime);
+                assert_eq!(reported, Some(sent), "port {}", port);
+            }
+        }
+        assert_eq!(importer.options.skews.skewed.load(Ordering::Relaxed), 4);
+        let skews = importer.options.skews.take();
+        assert!(skews["sensor"].worst >= before, "{:?}", skews["sensor"]);
+    }
+
+    #[test]
+    fn skewed_timestamps_are_rejected() {
+        let scratch = Scratch::new("skew-reject");
+        let importer = open_importer(&scratch);
+        let now = to_float_secs(SystemTime::now());
+        let cases = skewed_cases(now);
+        accept_skewed(&importer, SkewPolicy::Reject, &cases);
+
+        assert_eq!(conntimes(&importer.db), [(3, cases[2].1, None), (4, cases[3].1, None)]);
+        assert_eq!(importer.options.skews.skewed.load(Ordering::Relaxed), 4);
+        assert_eq!(open_ports(&importer.db), [3, 4]);
+        // Rejected or not, every timestamp counts towards the ident's skew
+        let skews = importer.options.skews.take();
+        assert!(skews["sensor"].worst >= now, "{:?}", skews["sensor"]);
+    }
+
+    /// A connection from port 40000 to `dst`, written `addr:port`.
+    fn to(dst: &str) -> Connection {
+        let dst: SocketAddr = dst.parse().unwrap();
+        Connection { dst: Endpoint { addr: dst.ip(), port: dst.port() }, ..state(40000, Protocol::Tcp, 0.0).connection }
+    }
+
+    fn anomalies(db: &rusqlite::Connection) -> Vec<(String, String, u16)> {
+        db.prepare("SELECT ident, dsthost, dstport FROM anomalies ORDER BY rowid").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn a_novel_destination_after_learning_is_one_anomaly() {
+        let scratch = Scratch::new("baseline");
+        let importer = open_importer(&scratch);
+        let db = &importer.db;
+        let baseline = BaselineSettings { granularity: Granularity::Port, learning: 100.0 };
+
+        // Learning, from the first connection on
+        assert!(!learn(db, "sensor", &to("10.0.0.2:443"), at(1000.0), &baseline).unwrap());
+        assert!(!learn(db, "sensor", &to("10.0.0.2:22"), at(1090.0), &baseline).unwrap());
+        assert!(anomalies(db).is_empty());
+
+        // Learned: what was seen then is nothing new, on any address
+        assert!(!learn(db, "sensor", &to("10.0.0.2:443"), at(1200.0), &baseline).unwrap());
+        assert!(!learn(db, "sensor", &to("10.0.0.9:22"), at(1200.0), &baseline).unwrap());
+        // Whereas a port it never used is, once
+        assert!(learn(db, "sensor", &to("10.0.0.2:4444"), at(1200.0), &baseline).unwrap());
+        assert!(!learn(db, "sensor", &to("10.0.0.2:4444"), at(1300.0), &baseline).unwrap());
+        assert_eq!(anomalies(db), [("sensor".to_string(), "10.0.0.2".to_string(), 4444)]);
+
+        // Another ident learns on a clock of its own
+        assert!(!learn(db, "other", &to("10.0.0.2:4444"), at(1300.0), &baseline).unwrap());
+        assert_eq!(anomalies(db).len(), 1);
+    }
+
+    #[test]
+    fn by_host_a_known_port_on_a_new_address_is_an_anomaly() {
+        let scratch = Scratch::new("baseline-host");
+        let importer = open_importer(&scratch);
+        let db = &importer.db;
+        let baseline = BaselineSettings { granularity: Granularity::Host, learning: 100.0 };
+
+        assert!(!learn(db, "sensor", &to("10.0.0.2:443"), at(1000.0), &baseline).unwrap());
+        assert!(!learn(db, "sensor", &to("10.0.0.2:443"), at(1200.0), &baseline).unwrap());
+        assert!(learn(db, "sensor", &to("10.0.0.3:443"), at(1200.0), &baseline).unwrap());
+        assert!(!learn(db, "sensor", &to("10.0.0.3:443"), at(1300.0), &baseline).unwrap());
+        assert_eq!(anomalies(db), [("sensor".to_string(), "10.0.0.3".to_string(), 443)]);
+    }
+
+    /// Apply the first `version` migrations, as a collector that old would have left it.
+    fn migrate_to(db: &rusqlite::Connection, version: usize) {
+        for sql in &MIGRATIONS[.. version] {
+            db.execute_batch(&sql.replace("{state}", "state")).unwrap();
+        }
+        db.pragma_update(None, "user_version", version as i64).unwrap();
+    }
+
+    fn names_of(db: &rusqlite::Connection, kind: &str) -> BTreeSet<String> {
+        db.prepare("SELECT name FROM sqlite_master WHERE type = ? AND name NOT LIKE 'sqlite_%'").unwrap()
+            .query_map(params![kind], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn a_new_database_gets_the_whole_schema() {
+        let scratch = Scratch::new("fresh");
+        let mut db = db::open(scratch.path()).unwrap();
+        migrate(&mut db);
+        assert_eq!(db.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0)).unwrap(), schema_version());
+        let tables = names_of(&db, "table");
+        for table in ["state", "names", "client_sessions", "clients", "summary_hourly", "watermarks", "baseline", "anomalies",
+                      "active_now", "client_tags", "scans", "latest_state", "ident_labels", "purges"] {
+            assert!(tables.contains(table), "no {} table", table);
+        }
+        let views = names_of(&db, "view");
+        for view in ["state_all", "state_readable", "names_readable", "latest_ins"] {
+            assert!(views.contains(view), "no {} view", view);
+        }
+        // Migrating what's up to date changes nothing
+        migrate(&mut db);
+        assert_eq!(db.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0)).unwrap(), schema_version());
+    }
+
+    #[test]
+    fn the_readable_views_give_times_as_rfc3339_does_to_the_millisecond() {
+        let scratch = Scratch::new("readable");
+        let mut importer = open_importer(&scratch);
+        let times = [1718201534.22, 1718201534.001, 1718201534.999, 951782399.5, 0.0];
+        let messages: Vec<Message> = times.iter().enumerate()
+            .map(|(n, at)| Message::Starting(state(n as u16 + 1, Protocol::Tcp, *at)))
+            .collect();
+        importer.store("sensor", "127.0.0.1:40000", &messages).unwrap();
+        for (n, at) in times.iter().enumerate() {
+            importer.db.execute("INSERT INTO names (instime, name, source) VALUES (?, ?, 'dns')", params![at, format!("{}.example.com", n)]).unwrap();
+        }
+        let millis = |at: f64| format!("{}Z", &crate::timefmt::rfc3339(at)[..23]);
+        let state: Vec<(String, String)> = importer.db.prepare("SELECT instime, conntime FROM state_readable ORDER BY srcport").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(state, times.map(|at| (millis(at), millis(at))));
+        let names: Vec<String> = importer.db.prepare("SELECT instime FROM names_readable ORDER BY name").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(names, times.map(millis));
+    }
+
+    #[test]
+    fn rows_stored_with_wire_marks_get_iana_numbers() {
+        let scratch = Scratch::new("marks");
+        let mut db = db::open(scratch.path()).unwrap();
+        let iana = MIGRATIONS.iter().position(|sql| sql.contains("UPDATE active_now SET proto")).unwrap();
+        migrate_to(&db, iana);
+        // TCP and UDP as the wire marks them, and something already stored by number
+        for (srcport, proto) in [(1, 1), (2, 2), (3, 6)] {
+            db.execute("
+                INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state)
+                VALUES (1000, 1000, 'sensor', 'peer', '10.0.0.1', ?, '10.0.0.2', 443, ?, ?);
+            ", params![srcport, proto, START_MARK]).unwrap();
+            db.execute("
+                INSERT INTO active_now (ident, peer, srchost, srcport, dsthost, dstport, proto, state, conntime, last_seen)
+                VALUES ('sensor', 'peer', '10.0.0.1', ?, '10.0.0.2', 443, ?, ?, 1000, 1000);
+            ", params![srcport, proto, START_MARK]).unwrap();
+        }
+
+        migrate(&mut db);
+        assert_eq!(db.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0)).unwrap(), schema_version());
+        for table in ["state", "active_now", "latest_state"] {
+            let protos: Vec<(u16, u8)> = db.prepare(&format!("SELECT srcport, proto FROM {} ORDER BY srcport", table)).unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            assert_eq!(protos, [(1, 6), (2, 17), (3, 6)], "in {}", table);
+        }
+        // Columns added after it are there, and empty
+        let added: (Option<u32>, Option<f64>, Option<u32>) = db.query_row("SELECT repeats, opened_at, rtt_micros FROM state_all WHERE srcport = 1", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(added, (None, None, None));
+    }
+
+    /// Store `days` days of Starting rows, `per_day` a day from a new source port each, the
+    /// first at `start`; ports count up with time.
+    fn ingest(importer: &mut Importer, start: f64, days: u16, per_day: u16) {
+        for day in 0 .. days {
+            let messages: Vec<Message> = (0 .. per_day)
+                .map(|idx| {
+                    let port = day * per_day + idx;
+                    Message::Starting(state(port, Protocol::Tcp, start + day as f64 * DAY + idx as f64))
+                })
+                .collect();
+            importer.store("sensor", "127.0.0.1:40000", &messages).unwrap();
+        }
+    }
+
+    fn ports(db: &rusqlite::Connection) -> Vec<u16> {
+        db.prepare("SELECT srcport FROM state_all ORDER BY srcport").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    /// Midnight UTC starting 2025-06-12.
+    const MIDNIGHT: f64 = 1_749_686_400.0;
+    const DAY: f64 = 86400.0;
+
+    #[test]
+    fn over_the_size_cap_the_oldest_days_go_first() {
+        let scratch = Scratch::new("size-cap-days");
+        let mut importer = open_importer(&scratch);
+        importer.options.partitions = Arc::new(Partitions::open(&mut importer.db, true, MIDNIGHT).unwrap());
+        ingest(&mut importer, MIDNIGHT, 5, 2000);
+        let partitions = importer.options.partitions.clone();
+        assert_eq!(partition::tables(&importer.db).unwrap().len(), 5);
+
+        // Well under the cap, nothing goes
+        let now = MIDNIGHT + 5.0 * DAY;
+        let size = db_size(&importer.db, scratch.path()).unwrap();
+        let mut settings = ServerSettings { tcp_timeout: 30.0 * DAY, max_db_size: Some(2 * size), ..Default::default() };
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, now));
+        assert_eq!(ports(&importer.db).len(), 10000);
+
+        // Retention's stricter than the cap here, so it's what goes by
+        settings.retention = Some(4.0 * DAY);
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, now));
+        assert_eq!(ports(&importer.db).first(), Some(&2000));
+
+        // And the cap's stricter than retention: days go, oldest first, to under nine tenths of it
+        let max = db_size(&importer.db, scratch.path()).unwrap() / 2;
+        settings.max_db_size = Some(max);
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, now));
+        assert!(db_size(&importer.db, scratch.path()).unwrap() <= max / 10 * 9);
+        let tables = partition::tables(&importer.db).unwrap();
+        // The day maintenance ran on has a table of its own by now, empty as it is
+        assert!(tables.len() < 5, "{:?}", tables);
+        assert_eq!(tables[tables.len() - 2 ..], ["state_20250616", "state_20250617"]);
+        let left = ports(&importer.db);
+        assert_eq!(left, (10000 - left.len() as u16 .. 10000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn over_the_size_cap_unpartitioned_the_oldest_rows_go_first() {
+        let scratch = Scratch::new("size-cap-rows");
+        let mut importer = open_importer(&scratch);
+        ingest(&mut importer, MIDNIGHT, 1, 25000);
+        let partitions = importer.options.partitions.clone();
+        let max = db_size(&importer.db, scratch.path()).unwrap() * 7 / 10;
+        let settings = ServerSettings { tcp_timeout: 30.0 * DAY, max_db_size: Some(max), ..Default::default() };
+        assert!(maintain(&mut importer.db, scratch.path(), &settings, &[], &partitions, MIDNIGHT + DAY));
+
+        assert!(db_size(&importer.db, scratch.path()).unwrap() <= max / 10 * 9);
+        // A chunk at a time, so the newest rows are still there, all of them
+        let left = ports(&importer.db);
+        assert_eq!(left.len() % EVICTION_CHUNK, 25000 % EVICTION_CHUNK);
+        assert!(!left.is_empty());
+        assert_eq!(left, (25000 - left.len() as u16 .. 25000).collect::<Vec<_>>());
+    }
+
+    /// One stored row, as far as summaries care.
+    #[derive(Debug, Clone)]
+    struct Stored {
+        instime: f64,
+        ident: String,
+        srcport: u16,
+        dsthost: String,
+        proto: u8,
+        state: u8,
+        repeats: Option<u32>,
+    }
+
+    /// `count` rows over the `hours` hours from `start`, in order of instime: three idents,
+    /// TCP and UDP, 40 ports to 8 hosts, in every state, with some failures standing for more.
+    fn stored(random: &mut Random, start: f64, hours: u64, count: usize) -> Vec<Stored> {
+        let mut rows: Vec<Stored> = (0 .. count).map(|_| {
+            let state = [START_MARK, ACTIVE_MARK, ENDED_MARK, FAILED_MARK][random.below(4) as usize];
+            Stored {
+                instime: start + random.below(hours * 3600) as f64 + 0.5,
+                ident: format!("sensor{}", random.below(3)),
+                srcport: 40000 + random.below(40) as u16,
+                dsthost: format!("10.0.0.{}", random.below(8)),
+                proto: [6, 17][random.below(2) as usize],
+                state,
+                repeats: (state == FAILED_MARK && random.below(3) == 0).then(|| random.below(5) as u32 + 1),
+            }
+        }).collect();
+        rows.sort_by(|a, b| a.instime.total_cmp(&b.instime));
+        rows
+    }
+
+    fn insert(db: &rusqlite::Connection, rows: &[Stored]) {
+        for row in rows {
+            db.execute("
+                INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, repeats)
+                VALUES (?, ?, ?, '127.0.0.1:40000', '10.1.0.1', ?, ?, 443, ?, ?, ?);
+            ", params![row.instime, row.instime, row.ident, row.srcport, row.dsthost, row.proto, row.state, row.repeats]).unwrap();
+        }
+    }
+
+    /// A bucket's hour, ident and proto, then its opened, active, ended, failed and dsthosts.
+    type Bucket = (i64, String, u8, i64, i64, i64, i64, i64);
+
+    /// Each bucket, worked out from the rows one at a time.
+    fn brute_force(rows: &[Stored]) -> Vec<Bucket> {
+        #[derive(Default)]
+        struct Counts {
+            opened: i64,
+            active: BTreeSet<(u16, String)>,
+            ended: i64,
+            failed: i64,
+            dsthosts: BTreeSet<String>,
+        }
+        let mut buckets: BTreeMap<(i64, String, u8), Counts> = BTreeMap::new();
+        for row in rows {
+            let hour = (row.instime / 3600.0).floor() as i64 * 3600;
+            let bucket = buckets.entry((hour, row.ident.clone(), row.proto)).or_default();
+            match row.state {
+                START_MARK => {
+                    bucket.opened += 1;
+                    bucket.active.insert((row.srcport, row.dsthost.clone()));
+                },
+                ACTIVE_MARK => {
+                    bucket.active.insert((row.srcport, row.dsthost.clone()));
+                },
+                ENDED_MARK => bucket.ended += 1,
+                _ => bucket.failed += 1 + row.repeats.unwrap_or(0) as i64,
+            }
+            bucket.dsthosts.insert(row.dsthost.clone());
+        }
+        buckets.into_iter().map(|((hour, ident, proto), bucket)| (
+            hour, ident, proto, bucket.opened, bucket.active.len() as i64, bucket.ended, bucket.failed, bucket.dsthosts.len() as i64,
+        )).collect()
+    }
+
+    fn summaries(db: &rusqlite::Connection) -> Vec<Bucket> {
+        db.prepare("SELECT hour, ident, proto, opened, active, ended, failed, dsthosts FROM summary_hourly ORDER BY hour, ident, proto").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn watermark(db: &rusqlite::Connection) -> Option<f64> {
+        db.query_row("SELECT value FROM watermarks WHERE name = 'summary_hourly'", [], |row| row.get(0)).optional().unwrap()
+    }
+
+    #[test]
+    fn summaries_kept_up_tick_by_tick_match_a_brute_force_count() {
+        let scratch = Scratch::new("summaries");
+        let db = open_importer(&scratch).db;
+        let mut random = Random::new(0x1164);
+        let rows = stored(&mut random, MIDNIGHT, 12, 3000);
+        assert_eq!(summarize(&db, MIDNIGHT).unwrap(), 0);
+        assert_eq!(watermark(&db), None);
+
+        // A tick every 20 minutes or so, each finding part of an hour an earlier one summed up
+        let mut stored_so_far = 0;
+        let mut tick = MIDNIGHT;
+        while stored_so_far < rows.len() {
+            tick += 1200.0 + random.below(600) as f64;
+            let upto = rows.partition_point(|row| row.instime < tick);
+            insert(&db, &rows[stored_so_far .. upto]);
+            stored_so_far = upto;
+            summarize(&db, tick).unwrap();
+            assert_eq!(summaries(&db), brute_force(&rows[.. upto]), "at {}", tick);
+            if upto > 0 {
+                // Up to the newest row there is, not the tick
+                assert_eq!(watermark(&db), Some(rows[upto - 1].instime));
+            }
+        }
+        // The fixture runs every bucket through a few ticks and has some of everything
+        let all = summaries(&db);
+        assert_eq!(all.len(), 12 * 3 * 2);
+        assert!(all.iter().all(|(_, _, _, opened, active, ended, failed, _)| [opened, active, ended, failed].iter().all(|n| **n > 0)), "{:?}", all);
+
+        // Nothing new is nothing to do
+        assert_eq!(summarize(&db, tick + 60.0).unwrap(), 0);
+        assert_eq!(summaries(&db), all);
+    }
+
+    #[test]
+    fn summarizing_again_from_any_watermark_comes_out_the_same() {
+        let scratch = Scratch::new("summaries-again");
+        let db = open_importer(&scratch).db;
+        let rows = stored(&mut Random::new(0x4611), MIDNIGHT, 6, 1000);
+        insert(&db, &rows);
+        let now = MIDNIGHT + 7.0 * 3600.0;
+        summarize(&db, now).unwrap();
+        let expected = brute_force(&rows);
+        assert_eq!(summaries(&db), expected);
+
+        // As after a restart that lost track of how far it got, or never got to record it:
+        // whatever's summed up again is summed up from scratch, not added to
+        for lost in [None, Some(MIDNIGHT + 3.0 * 3600.0 + 17.0), Some(MIDNIGHT + 2.5)] {
+            match lost {
+                None => db.execute("DELETE FROM watermarks WHERE name = 'summary_hourly'", []).unwrap(),
+                Some(at) => db.execute("UPDATE watermarks SET value = ? WHERE name = 'summary_hourly'", [at]).unwrap(),
+            };
+            assert!(summarize(&db, now).unwrap() > 0, "{:?}", lost);
+            assert_eq!(summaries(&db), expected, "{:?}", lost);
+            assert_eq!(watermark(&db), Some(rows.last().unwrap().instime));
+        }
+    }
+
+    #[test]
+    fn summaries_are_kept_for_a_year_whatever_the_rows_retention() {
+        let scratch = Scratch::new("summaries-retention");
+        let db = open_importer(&scratch).db;
+        let year = SUMMARY_RETENTION.as_secs_f64();
+        let old = stored(&mut Random::new(0x1641), MIDNIGHT - year - 2.0 * 3600.0, 1, 50);
+        let recent = stored(&mut Random::new(0x1614), MIDNIGHT - year + 3600.0, 1, 50);
+        insert(&db, &old);
+        insert(&db, &recent);
+        summarize(&db, MIDNIGHT - year + 2.0 * 3600.0).unwrap();
+        assert_eq!(summaries(&db), brute_force(&[old.clone(), recent.clone()].concat()));
+
+        // The rows themselves go, and the summaries outlast them until they're a year old
+        db.execute("DELETE FROM state", []).unwrap();
+        summarize(&db, MIDNIGHT - 3600.0).unwrap();
+        assert_eq!(summaries(&db), brute_force(&[old.clone(), recent.clone()].concat()));
+        insert(&db, &stored(&mut Random::new(0x6114), MIDNIGHT, 1, 1));
+        summarize(&db, MIDNIGHT).unwrap();
+        let kept: Vec<_> = summaries(&db).into_iter().filter(|bucket| (bucket.0 as f64) < MIDNIGHT - 3600.0).collect();
+        assert_eq!(kept, brute_force(&recent));
+    }
+}
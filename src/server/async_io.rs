@@ -0,0 +1,187 @@
+use std::{collections::HashMap, io::{self, ErrorKind}, net::{SocketAddr, TcpListener as StdListener}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread};
+
+use tokio::{io::AsyncReadExt, net::{TcpListener, TcpStream}, runtime, sync::{mpsc, oneshot}};
+use tokio_stream::StreamExt;
+use tokio_util::{bytes::{Buf, BytesMut}, codec::{Decoder, FramedRead}};
+
+use crate::subscribe;
+
+use super::{legacy, remote, subscription, ClientOptions, Session, Store};
+
+/// Threads doing the blocking database work for every connection; each connection sticks to one
+/// so its frames are stored in order.
+const STORAGE_THREADS: usize = 4;
+/// Frames queued for a storage thread before connections feeding it stop being read.
+const BACKLOG: usize = 1024;
+
+/// Splits the sync protocol's frames out of a stream: a big-endian u32 length, then the frame.
+#[derive(Debug, Default)]
+struct FrameCodec;
+
+impl FrameCodec {
+    /// Larger frames are taken as a broken (or hostile) client rather than buffered.
+    const MAX_FRAME: usize = 16 << 20;
+}
+
+impl Decoder for FrameCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > Self::MAX_FRAME {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        Ok(Some(src.split_to(len).to_vec()))
+    }
+}
+
+/// Work handed from a connection's task to its storage thread.
+enum Job {
+    Open {
+        conn: u64,
+        claimed: String,
+        peer: SocketAddr,
+        legacy: bool,
+        opened: oneshot::Sender<bool>,
+    },
+    Frame {
+        conn: u64,
+        frame: Vec<u8>,
+    },
+    Close {
+        conn: u64,
+    },
+}
+
+/// Accept and read clients on a tokio runtime instead of a thread apiece, handing what they send
+/// to a few storage threads. Never returns.
+pub(super) fn serve(sock: StdListener, dbname: &str, options: ClientOptions, shutdown: Arc<AtomicBool>) {
+    let storage: Vec<mpsc::Sender<Job>> = (0 .. STORAGE_THREADS).map(|_| {
+        let (sender, receiver) = mpsc::channel(BACKLOG);
+        let dbname = dbname.to_string();
+        let options = options.clone();
+        thread::spawn(move || storage_thread(dbname, receiver, options));
+        sender
+    }).collect();
+    let runtime = runtime::Builder::new_multi_thread()
+        .enable_io()
+        .build()
+        .expect("failed to start async runtime");
+    runtime.block_on(async move {
+        sock.set_nonblocking(true).expect("failed to make socket nonblocking");
+        let listener = TcpListener::from_std(sock).expect("failed to register socket");
+        let mut next = 0u64;
+        loop {
+            let accepted = listener.accept().await;
+            if shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+            match accepted {
+                Ok((stream, peer)) => {
+                    println!("Connection from {:?}", peer);
+                    let conn = next;
+                    next += 1;
+                    let storage = storage[conn as usize % storage.len()].clone();
+                    let options = options.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = connection(stream, peer, conn, storage, options).await {
+                            println!("{:?}: connection failed: {:?}", peer, e);
+                        }
+                    });
+                },
+                Err(e) => println!("failed to accept connection: {:?}", e),
+            }
+        }
+    });
+}
+
+async fn connection(mut stream: TcpStream, peer: SocketAddr, conn: u64, storage: mpsc::Sender<Job>, options: ClientOptions) -> io::Result<()> {
+    let mut claimed = vec![0u8; stream.read_u16().await? as usize];
+    stream.read_exact(&mut claimed).await?;
+    let claimed = String::from_utf8(claimed).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let mut frames = FramedRead::new(stream, FrameCodec);
+    let Some(first) = frames.next().await.transpose()? else {
+        println!("{}@{:?}: disconnected before sending anything", claimed, peer);
+        return Ok(());
+    };
+    if let Some(filter) = subscription(&claimed, peer, &first) {
+        let (replay, receiver) = options.broadcast.subscribe(filter);
+        let stream = frames.into_inner().into_std()?;
+        stream.set_nonblocking(false)?;
+        if let Err(e) = tokio::task::spawn_blocking(move || subscribe::serve(stream, replay, receiver)).await? {
+            println!("{}@{:?}: subscriber went away: {:?}", claimed, peer, e);
+        }
+        return Ok(());
+    }
+    if let Some(request) = remote::request(&claimed, peer, &first) {
+        let mut stream = frames.into_inner().into_std()?;
+        stream.set_nonblocking(false)?;
+        let replied = tokio::task::spawn_blocking(move || {
+            let response = remote::answer(&claimed, peer, request, &options);
+            remote::reply(&mut stream, &response)
+        }).await?;
+        if let Err(e) = replied {
+            println!("{:?}: failed to send query response: {:?}", peer, e);
+        }
+        return Ok(());
+    }
+    let Some(legacy) = legacy(&claimed, peer, &first, &options) else {
+        return Ok(());
+    };
+    let gone = || io::Error::new(ErrorKind::BrokenPipe, "storage thread exited");
+    let (opened, reply) = oneshot::channel();
+    storage.send(Job::Open { conn, claimed, peer, legacy, opened }).await.map_err(|_| gone())?;
+    if !reply.await.unwrap_or(false) {
+        return Ok(());
+    }
+    let mut pending = Some(first);
+    loop {
+        let frame = match pending.take() {
+            Some(frame) => frame,
+            None => match frames.next().await {
+                Some(Ok(frame)) => frame,
+                _ => break,
+            },
+        };
+        storage.send(Job::Frame { conn, frame }).await.map_err(|_| gone())?;
+    }
+    storage.send(Job::Close { conn }).await.map_err(|_| gone())
+}
+
+/// Run every connection's database work, keeping each one's session between jobs.
+fn storage_thread(dbname: String, mut jobs: mpsc::Receiver<Job>, options: ClientOptions) {
+    let mut store = match Store::open(&dbname, &options) {
+        Ok(store) => store,
+        Err(e) => {
+            println!("storage thread exiting, couldn't open database: {:?}", e);
+            return;
+        },
+    };
+    let mut sessions: HashMap<u64, Session> = HashMap::new();
+    while let Some(job) = jobs.blocking_recv() {
+        match job {
+            Job::Open { conn, claimed, peer, legacy, opened } => {
+                let session = Session::open(&mut store, &claimed, peer, legacy, &options);
+                let _ = opened.send(session.is_some());
+                if let Some(session) = session {
+                    sessions.insert(conn, session);
+                }
+            },
+            Job::Frame { conn, frame } => if let Some(session) = sessions.get_mut(&conn) {
+                session.receive(&mut store, &frame, &options);
+            },
+            Job::Close { conn } => if let Some(session) = sessions.remove(&conn) {
+                session.close(&mut store, &options);
+            },
+        }
+    }
+}
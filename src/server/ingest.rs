@@ -0,0 +1,33 @@
+use std::{net::SocketAddr, sync::{atomic::Ordering, Arc, Mutex}};
+
+use crate::{db, observe::Message};
+
+use super::{accept_into, client_seen, ClientOptions, Store};
+
+/// Store messages posted to the API's ingest endpoint just as though their sender had connected
+/// to the client port, through one database handle shared by every request.
+pub(super) fn store(dbname: String, options: ClientOptions) -> impl Fn(&str, SocketAddr, Vec<Message>) + Send + Sync {
+    let store: Mutex<Option<Store>> = Mutex::default();
+    move |ident, peer, messages| {
+        let mut store = store.lock().unwrap();
+        if store.is_none() {
+            match Store::open(&dbname, &options) {
+                Ok(opened) => *store = Some(opened),
+                Err(e) => {
+                    let dropped = options.write_failures.fetch_add(messages.len() as u64, Ordering::Relaxed) + messages.len() as u64;
+                    println!("{}@{:?}: dropped posted messages, couldn't open database ({} dropped so far): {:?}", ident, peer, dropped, e);
+                    return;
+                },
+            }
+        }
+        let store = store.as_mut().expect("just opened");
+        let ident: Arc<str> = Arc::from(ident);
+        let peername: Arc<str> = format!("{:?}", peer).into();
+        if let Err(e) = store.with(&ident, |db| db::retry(|| client_seen(db, &ident))) {
+            println!("{}@{:?}: failed to record client: {:?}", ident, peer, e);
+        }
+        for message in messages {
+            accept_into(store, message.view(), &ident, peer, &peername, &options);
+        }
+    }
+}
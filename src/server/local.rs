@@ -0,0 +1,48 @@
+use std::{io, net::{Ipv4Addr, SocketAddr}, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use crate::coding::Coder;
+
+use super::{collector, ClientOptions, ServerSettings, Session, Store};
+
+/// A collector with no client port, for a sensor to store what it observes straight into a
+/// database on the same host. Frames take the same path through the session, storage and
+/// maintenance that a connected sensor's would; only the connection is left out.
+#[derive(Debug)]
+pub struct LocalStore {
+    store: Store,
+    session: Option<Session>,
+    options: ClientOptions,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl LocalStore {
+    /// Set up the collector `settings` describe, less its client port, and open a session for
+    /// the sensor `ident`.
+    pub fn open(settings: &ServerSettings, ident: &str) -> io::Result<Self> {
+        let shutdown: Arc<AtomicBool> = Arc::default();
+        let options = collector(settings, None, Arc::default(), shutdown.clone());
+        let mut store = Store::open(&settings.database, &options).map_err(io::Error::other)?;
+        let peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let session = Session::open(&mut store, ident, peer, false, &options)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, format!("{} was turned away", ident)))?;
+        Ok(Self { store, session: Some(session), options, shutdown })
+    }
+
+    /// Store a message, snapshot or hello as if a connected sensor had sent it.
+    pub fn send<C: Coder>(&mut self, object: &C) {
+        let mut frame = Vec::new();
+        object.encode(&mut frame).unwrap();
+        if let Some(session) = &mut self.session {
+            session.receive(&mut self.store, &frame, &self.options);
+        }
+    }
+}
+
+impl Drop for LocalStore {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            session.close(&mut self.store, &self.options);
+        }
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
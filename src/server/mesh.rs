@@ -0,0 +1,64 @@
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr}, sync::Arc, thread, time::Duration};
+
+use tokio::{runtime::Runtime, sync::broadcast::error::RecvError};
+
+use crate::{db, mesh::{Mesh, MeshConfig}};
+
+use super::{client_seen, ClientOptions, ServerSettings, Session, Store};
+
+/// Wait before trying again after failing to open the database.
+const RETRY: Duration = Duration::from_secs(10);
+
+/// Join the mesh, on a runtime of its own.
+pub(super) fn start(settings: &ServerSettings) -> (Runtime, Arc<Mesh>) {
+    let runtime = Runtime::new().expect("failed to start mesh runtime");
+    let mut config = MeshConfig::default();
+    config.set_listens(settings.mesh_listen.clone());
+    for peer in settings.mesh_peers.iter() {
+        config.add_peer(*peer);
+    }
+    if let Some(path) = &settings.mesh_peer_file {
+        config.set_peer_file(path.clone(), Duration::from_secs_f64(settings.mesh_peer_horizon));
+    }
+    let mesh = runtime.block_on(config.build()).expect("failed to join mesh");
+    println!("mesh: joined as {:032x}, {} peers to dial", mesh.instance(), mesh.peers().len());
+    (runtime, mesh)
+}
+
+/// Store every frame passed around the mesh just as though the sensor that published it had
+/// connected here, for as long as the process runs.
+pub(super) fn store(runtime: Runtime, mesh: Arc<Mesh>, dbname: String, options: ClientOptions) {
+    // The runtime drives the mesh, so it has to live as long as this does
+    let _runtime = runtime;
+    let mut frames = mesh.subscribe();
+    let mut store = loop {
+        match Store::open(&dbname, &options) {
+            Ok(store) => break store,
+            Err(e) => {
+                println!("mesh: couldn't open database, retrying: {:?}", e);
+                thread::sleep(RETRY);
+            },
+        }
+    };
+    let mut sessions: HashMap<Arc<str>, Session> = HashMap::new();
+    loop {
+        let frame = match frames.blocking_recv() {
+            Ok(frame) => frame,
+            Err(RecvError::Lagged(missed)) => {
+                println!("mesh: fell behind, {} frames dropped", missed);
+                continue;
+            },
+            Err(RecvError::Closed) => return,
+        };
+        let session = sessions.entry(frame.ident.clone()).or_insert_with(|| {
+            let ident = frame.ident.clone();
+            let peer = frame.from.unwrap_or(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+            if let Err(e) = store.with(&ident, |db| db::retry(|| client_seen(db, &ident))) {
+                println!("{}@{:?}: failed to record client: {:?}", ident, peer, e);
+            }
+            println!("mesh: first frame from {} by way of {:?}", ident, peer);
+            Session::meshed(ident, peer)
+        });
+        session.receive(&mut store, &frame.data, &options);
+    }
+}
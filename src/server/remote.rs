@@ -0,0 +1,85 @@
+use std::{io::{self, Write}, net::SocketAddr};
+
+use serde::Serialize;
+
+use crate::{coding::{Coder, CodingVec, QUERY_MARK}, query, remote::{Query, QueryRequest, QueryResponse}};
+
+use super::ClientOptions;
+
+/// If `first` asks a query, the query it asked.
+pub(super) fn request(claimed: &str, peer: SocketAddr, first: &[u8]) -> Option<QueryRequest> {
+    if first.first() != Some(&QUERY_MARK) {
+        return None;
+    }
+    match QueryRequest::decode(&mut &*first) {
+        Ok(request) => Some(request),
+        Err(e) => {
+            println!("{}@{:?}: bad query: {:?}", claimed, peer, e);
+            None
+        },
+    }
+}
+
+/// Answer a query sent over the client port from the database the collector writes to (or
+/// every shard of it), returning no more rows than the collector's limit allows.
+pub(super) fn answer(claimed: &str, peer: SocketAddr, request: QueryRequest, options: &ClientOptions) -> QueryResponse {
+    let settings = options.settings.get();
+    match &settings.remote_query_token {
+        None => {
+            println!("{}@{:?}: refused query, remote queries aren't enabled", claimed, peer);
+            return QueryResponse::Refused("remote queries aren't enabled".to_string());
+        },
+        Some(token) if *token != request.token => {
+            println!("{}@{:?}: refused query with the wrong token", claimed, peer);
+            return QueryResponse::Refused("wrong token".to_string());
+        },
+        Some(_) => (),
+    }
+    println!("{}@{:?}: query {:?}", claimed, peer, request.query);
+    let dbs = match query::open_all(&settings.database) {
+        Ok(dbs) => dbs,
+        Err(e) => return QueryResponse::Refused(format!("couldn't open database: {}", e)),
+    };
+    match request.query {
+        Query::Active(asked) => {
+            let limit = (asked.limit as usize).min(settings.remote_query_limit);
+            let found = query::union(&dbs, |db| {
+                query::active_within(db, asked.ident.clone(), asked.host, asked.port, limit + 1)
+            }, query::active_order);
+            rows(found, limit)
+        },
+        Query::Connections(asked) => {
+            let limit = (asked.limit as usize).min(settings.remote_query_limit);
+            let filter = query::SessionFilter {
+                ident: asked.ident,
+                host: asked.host,
+                port: asked.port,
+                proto: asked.proto.map(|proto| proto.iana_number()),
+                since: asked.since,
+                until: asked.until,
+            };
+            let found = query::union(&dbs, |db| query::sessions(db, &filter, limit + 1), query::session_order);
+            rows(found, limit)
+        },
+    }
+}
+
+/// The first `limit` of `found` as a response, noting whether there were more.
+fn rows<T: Serialize>(found: rusqlite::Result<Vec<T>>, limit: usize) -> QueryResponse {
+    match found {
+        Ok(found) => QueryResponse::Rows {
+            truncated: found.len() > limit,
+            rows: found.iter().take(limit).map(|row| serde_json::to_string(row).expect("failed to encode row")).collect(),
+        },
+        Err(e) => QueryResponse::Refused(format!("query failed: {}", e)),
+    }
+}
+
+/// Send the answer back as one frame.
+pub(super) fn reply<W: Write>(stream: &mut W, response: &QueryResponse) -> io::Result<()> {
+    let mut payload = Vec::new();
+    response.encode(&mut payload)?;
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    CodingVec::<u8, u32>::new(payload).encode(&mut frame)?;
+    stream.write_all(&frame)
+}
@@ -0,0 +1,94 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, thread, time::{Duration, Instant, SystemTime}};
+
+use crate::{db, subscribe::{Subscribe, Subscription}};
+
+use super::{accept_into, client_seen, to_float_secs, ClientOptions, Store};
+
+/// Longest wait between attempts to reach the primary.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often to log how far behind the primary we are.
+const REPORT: Duration = Duration::from_secs(60);
+
+/// How far behind the primary replicated messages arrive, over one reporting period.
+#[derive(Debug, Default)]
+struct Lag {
+    messages: u64,
+    latest: f64,
+    worst: f64,
+}
+
+impl Lag {
+    fn observe(&mut self, lag: f64) {
+        self.messages += 1;
+        self.latest = lag;
+        self.worst = self.worst.max(lag);
+    }
+}
+
+/// Follow `primary` as a warm standby: subscribe to everything it accepts and store each
+/// message as though its sensor had connected here, for as long as the process runs.
+///
+/// Each reconnection resumes from the last message received, so what the primary accepted
+/// while this end was disconnected is caught up, as far back as the primary still remembers;
+/// what overflowed the subscriber queue while connected isn't.
+pub(super) fn follow(primary: SocketAddr, dbname: String, options: ClientOptions) {
+    let name = format!("{}-standby", gethostname::gethostname().to_string_lossy());
+    let peername: Arc<str> = Arc::from(primary.to_string());
+    let mut backoff = Duration::from_secs(1);
+    // Zero the first time, for the primary to start remembering from
+    let mut last = 0;
+    loop {
+        let mut store = match Store::open(&dbname, &options) {
+            Ok(store) => store,
+            Err(e) => {
+                println!("replication: couldn't open database: {:?}", e);
+                thread::sleep(backoff);
+                continue;
+            },
+        };
+        let subscription = match Subscription::connect(primary, &name, &Subscribe { since: Some(last), ..Subscribe::default() }) {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                println!("replication: couldn't reach primary {}, retrying in {:?}: {}", primary, backoff, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            },
+        };
+        println!("replication: following primary {}", primary);
+        let mut seen: HashSet<Arc<str>> = HashSet::new();
+        let mut lag = Lag::default();
+        let mut reported = Instant::now();
+        for envelope in subscription {
+            let envelope = match envelope {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    println!("replication: lost primary {}: {}", primary, e);
+                    break;
+                },
+            };
+            backoff = Duration::from_secs(1);
+            if let Some(seq) = envelope.seq {
+                last = last.max(seq);
+            }
+            let ident: Arc<str> = Arc::from(envelope.ident);
+            if !seen.contains(&ident) {
+                if let Err(e) = store.with(&ident, |db| db::retry(|| client_seen(db, &ident))) {
+                    println!("replication: failed to record client {}: {:?}", ident, e);
+                }
+                seen.insert(ident.clone());
+            }
+            lag.observe(to_float_secs(SystemTime::now()) - to_float_secs(envelope.message.state().as_of));
+            accept_into(&mut store, envelope.message.view(), &ident, primary, &peername, &options);
+            if reported.elapsed() >= REPORT {
+                println!("replication: {} messages from {} in the last {:?}, lag {:.1}s (worst {:.1}s)",
+                    lag.messages, primary, REPORT, lag.latest, lag.worst);
+                lag = Lag::default();
+                reported = Instant::now();
+            }
+        }
+        println!("replication: primary {} hung up, reconnecting in {:?}", primary, backoff);
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
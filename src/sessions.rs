@@ -0,0 +1,323 @@
+use serde::Serialize;
+
+use crate::coding::{ACTIVE_MARK, ENDED_MARK, FAILED_MARK, RESET_MARK, START_MARK, TMOUT_MARK};
+
+/// The identity of one connection as stored: who reported it and its tuple.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    pub ident: String,
+    pub srchost: String,
+    pub srcport: u16,
+    pub dsthost: String,
+    pub dstport: u16,
+    pub proto: u8,
+}
+
+/// What session reconstruction needs from one stored row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub conntime: f64,
+    /// The most recent time the row was confirmed, for refreshed Active rows.
+    pub last_seen: f64,
+    pub state: u8,
+    pub close: Option<u8>,
+    /// When the session a synthesized close ends was first reported, if the row says.
+    pub opened_at: Option<f64>,
+}
+
+/// How a session came to an end, as far as the rows say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ending {
+    /// Closed normally.
+    Ended,
+    Reset,
+    Failed,
+    /// Went quiet and was closed by maintenance, or its client went away; the end is when it
+    /// was last seen.
+    Timeout,
+    /// The same tuple started again before this one was seen to close.
+    Reopened,
+    /// No close on record (yet).
+    Open,
+}
+
+impl Ending {
+    /// Whether the session's close is on record, rather than only when it was last seen.
+    pub fn seen_closing(self) -> bool {
+        matches!(self, Self::Ended | Self::Reset | Self::Failed)
+    }
+}
+
+/// One connection from its opening to its close, reconstructed from the rows between.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Session {
+    pub ident: String,
+    pub srchost: String,
+    pub srcport: u16,
+    pub dsthost: String,
+    pub dstport: u16,
+    pub proto: &'static str,
+    /// When it was first reported; absent if only its close is on record.
+    pub start: Option<f64>,
+    /// When it closed, or was last seen if it's still open.
+    pub end: f64,
+    /// Seconds from `start` to `end`; absent without a start. Keepalives only move `end` for
+    /// sessions with no close on record: an open session's duration is as of its last
+    /// keepalive, and a timed-out one's stops at the last keepalive before maintenance gave up
+    /// on it, not at the timeout.
+    pub duration: Option<f64>,
+    /// Whether `duration` only runs to when the connection was last seen (the session is open,
+    /// timed out or was reopened), so it may really have lasted longer.
+    pub duration_lower_bound: bool,
+    pub ending: Ending,
+}
+
+impl Session {
+    fn new(key: &Key, start: Option<f64>, end: f64, ending: Ending) -> Self {
+        Self {
+            ident: key.ident.clone(),
+            srchost: key.srchost.clone(),
+            srcport: key.srcport,
+            dsthost: key.dsthost.clone(),
+            dstport: key.dstport,
+            proto: crate::query::protocol_name(key.proto),
+            start,
+            end,
+            duration: start.map(|start| (end - start).max(0.0)),
+            duration_lower_bound: start.is_some() && !ending.seen_closing(),
+            ending,
+        }
+    }
+
+    /// Whether any part of the session falls within `since ..= until`.
+    pub fn overlaps(&self, since: f64, until: f64) -> bool {
+        self.start.unwrap_or(self.end) <= until && self.end >= since
+    }
+}
+
+/// Pair up openings with their closes. `rows` must be grouped by key and, within each key, in
+/// the order they were stored. A timeout that says when its session opened is believed over
+/// the rows before it, which retention may have thinned.
+pub fn reconstruct<I: IntoIterator<Item = (Key, Row)>>(rows: I) -> Vec<Session> {
+    let mut sessions = Vec::new();
+    // The key being walked, and the (start, last seen) of its open session if it has one
+    let mut current: Option<(Key, Option<(f64, f64)>)> = None;
+    for (key, row) in rows {
+        if current.as_ref().map(|(k, _)| *k != key).unwrap_or(true) {
+            if let Some((key, Some((start, last)))) = current.take() {
+                sessions.push(Session::new(&key, Some(start), last, Ending::Open));
+            }
+            current = Some((key, None));
+        }
+        let (key, open) = current.as_mut().unwrap();
+        let state = row.state;
+        if row.close == Some(TMOUT_MARK) {
+            if let Some((start, last)) = open.take() {
+                let start = row.opened_at.map_or(start, |opened| opened.min(last));
+                sessions.push(Session::new(key, Some(start), last, Ending::Timeout));
+            }
+        } else if state == ENDED_MARK || state == FAILED_MARK {
+            let ending = match (state, row.close) {
+                (FAILED_MARK, _) => Ending::Failed,
+                (_, Some(RESET_MARK)) => Ending::Reset,
+                _ => Ending::Ended,
+            };
+            let start = open.take().map(|(start, _)| start);
+            let start = if state == FAILED_MARK { start.or(Some(row.conntime)) } else { start };
+            sessions.push(Session::new(key, start, row.conntime, ending));
+        } else if state == START_MARK || state == ACTIVE_MARK {
+            match open {
+                Some((start, last)) if state == START_MARK => {
+                    sessions.push(Session::new(key, Some(*start), *last, Ending::Reopened));
+                    *open = Some((row.conntime, row.last_seen.max(row.conntime)));
+                },
+                Some((_, last)) => *last = last.max(row.last_seen).max(row.conntime),
+                None => *open = Some((row.conntime, row.last_seen.max(row.conntime))),
+            }
+        }
+    }
+    if let Some((key, Some((start, last)))) = current {
+        sessions.push(Session::new(&key, Some(start), last, Ending::Open));
+    }
+    sessions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coding::NORMAL_MARK;
+
+    use super::*;
+
+    fn key(srcport: u16) -> Key {
+        Key {
+            ident: "sensor".to_string(),
+            srchost: "10.0.0.1".to_string(),
+            srcport,
+            dsthost: "10.0.0.2".to_string(),
+            dstport: 443,
+            proto: 6,
+        }
+    }
+
+    fn row(conntime: f64, state: u8, close: Option<u8>) -> Row {
+        Row { conntime, last_seen: conntime, state, close, opened_at: None }
+    }
+
+    /// A keepalive refreshing the Active row it follows up to `last_seen`.
+    fn refreshed(conntime: f64, last_seen: f64) -> Row {
+        Row { last_seen, ..row(conntime, ACTIVE_MARK, None) }
+    }
+
+    /// A close maintenance or a disconnect synthesized, saying when the session opened.
+    fn timeout(conntime: f64, opened_at: Option<f64>) -> Row {
+        Row { opened_at, ..row(conntime, ACTIVE_MARK, Some(TMOUT_MARK)) }
+    }
+
+    fn ends(sessions: &[Session]) -> Vec<(Option<f64>, f64, Ending)> {
+        sessions.iter().map(|session| (session.start, session.end, session.ending)).collect()
+    }
+
+    #[test]
+    fn an_opening_pairs_with_its_close() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(11.0, 15.0)),
+            (key(1), row(20.0, ENDED_MARK, Some(NORMAL_MARK))),
+            (key(2), row(12.0, START_MARK, None)),
+            (key(2), row(13.0, ENDED_MARK, Some(RESET_MARK))),
+        ]);
+        assert_eq!(ends(&sessions), [(Some(10.0), 20.0, Ending::Ended), (Some(12.0), 13.0, Ending::Reset)]);
+        assert_eq!((sessions[0].srcport, sessions[0].proto), (1, "tcp"));
+        assert_eq!((sessions[1].srcport, sessions[1].dsthost.as_str()), (2, "10.0.0.2"));
+    }
+
+    #[test]
+    fn a_tuple_started_again_reopens() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 14.0)),
+            (key(1), row(30.0, START_MARK, None)),
+            (key(1), row(35.0, ENDED_MARK, Some(NORMAL_MARK))),
+        ]);
+        assert_eq!(ends(&sessions), [(Some(10.0), 14.0, Ending::Reopened), (Some(30.0), 35.0, Ending::Ended)]);
+    }
+
+    #[test]
+    fn a_missing_close_leaves_the_session_open() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 40.0)),
+            (key(2), row(50.0, ACTIVE_MARK, None)),
+        ]);
+        assert_eq!(ends(&sessions), [(Some(10.0), 40.0, Ending::Open), (Some(50.0), 50.0, Ending::Open)]);
+    }
+
+    #[test]
+    fn a_missing_opening_leaves_only_the_close() {
+        let sessions = reconstruct([
+            (key(1), row(20.0, ENDED_MARK, Some(NORMAL_MARK))),
+            (key(2), row(25.0, FAILED_MARK, None)),
+        ]);
+        // A failure is its own opening, since there's nothing before it to wait for
+        assert_eq!(ends(&sessions), [(None, 20.0, Ending::Ended), (Some(25.0), 25.0, Ending::Failed)]);
+    }
+
+    #[test]
+    fn a_timeout_ends_at_the_last_keepalive() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 40.0)),
+            (key(1), timeout(400.0, Some(10.0))),
+        ]);
+        assert_eq!(ends(&sessions), [(Some(10.0), 40.0, Ending::Timeout)]);
+    }
+
+    #[test]
+    fn a_timeout_knows_an_opening_retention_took() {
+        // The Starting row is gone; the Active row left only says when it was refreshed
+        let sessions = reconstruct([
+            (key(1), refreshed(30.0, 40.0)),
+            (key(1), timeout(400.0, Some(10.0))),
+        ]);
+        assert_eq!(ends(&sessions), [(Some(10.0), 40.0, Ending::Timeout)]);
+    }
+
+    #[test]
+    fn a_timeout_with_nothing_open_is_no_session() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), row(20.0, ENDED_MARK, Some(NORMAL_MARK))),
+            (key(1), timeout(400.0, Some(10.0))),
+        ]);
+        assert_eq!(ends(&sessions), [(Some(10.0), 20.0, Ending::Ended)]);
+    }
+
+    fn durations(sessions: &[Session]) -> Vec<(Option<f64>, bool)> {
+        sessions.iter().map(|session| (session.duration, session.duration_lower_bound)).collect()
+    }
+
+    #[test]
+    fn a_closed_session_lasts_from_its_opening_to_its_close() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 18.0)),
+            (key(1), row(25.0, ENDED_MARK, Some(NORMAL_MARK))),
+            (key(2), row(10.0, START_MARK, None)),
+            (key(2), row(12.5, ENDED_MARK, Some(RESET_MARK))),
+        ]);
+        // Keepalives don't count once the close is on record
+        assert_eq!(durations(&sessions), [(Some(15.0), false), (Some(2.5), false)]);
+    }
+
+    #[test]
+    fn an_open_session_lasts_until_its_last_keepalive_so_far() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 70.0)),
+        ]);
+        assert_eq!(durations(&sessions), [(Some(60.0), true)]);
+    }
+
+    #[test]
+    fn a_timed_out_session_stops_at_its_last_keepalive_not_the_timeout() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 70.0)),
+            (key(1), timeout(400.0, Some(10.0))),
+        ]);
+        assert_eq!(durations(&sessions), [(Some(60.0), true)]);
+    }
+
+    #[test]
+    fn a_reopened_session_lasts_until_it_was_last_seen() {
+        let sessions = reconstruct([
+            (key(1), row(10.0, START_MARK, None)),
+            (key(1), refreshed(10.0, 20.0)),
+            (key(1), row(30.0, START_MARK, None)),
+        ]);
+        assert_eq!(durations(&sessions), [(Some(10.0), true), (Some(0.0), true)]);
+    }
+
+    #[test]
+    fn a_session_without_an_opening_has_no_duration() {
+        let sessions = reconstruct([(key(1), row(20.0, ENDED_MARK, Some(NORMAL_MARK)))]);
+        assert_eq!(durations(&sessions), [(None, false)]);
+    }
+
+    #[test]
+    fn a_failure_lasts_no_time() {
+        let sessions = reconstruct([(key(1), row(20.0, FAILED_MARK, None))]);
+        assert_eq!(durations(&sessions), [(Some(0.0), false)]);
+    }
+
+    #[test]
+    fn a_close_stamped_before_its_opening_lasts_no_time() {
+        // Clocks disagree, as a sensor's and a collector's can
+        let sessions = reconstruct([
+            (key(1), row(20.0, START_MARK, None)),
+            (key(1), row(19.0, ENDED_MARK, Some(NORMAL_MARK))),
+        ]);
+        assert_eq!(durations(&sessions), [(Some(0.0), false)]);
+    }
+}
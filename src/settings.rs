@@ -0,0 +1,184 @@
+use std::{fmt::{self, Display, Formatter}, fs, io, path::{Path, PathBuf}};
+
+use serde::de::DeserializeOwned;
+
+/// Why a config file couldn't be loaded.
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A `[profile.<name>]` section that doesn't make valid settings laid over the file.
+    Profile(PathBuf, String, String),
+    /// A profile was asked for that the file doesn't have; the ones it does follow.
+    UnknownProfile(PathBuf, String, Vec<String>),
+    /// Settings that each parse but can't both hold, like a block both included and excluded.
+    Conflict(String),
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "couldn't read {:?}: {}", path, e),
+            Self::Parse(path, e) => write!(f, "couldn't parse {:?}: {}", path, e),
+            Self::Profile(path, name, e) => write!(f, "couldn't parse [profile.{}] of {:?}: {}", name, path, e),
+            Self::UnknownProfile(path, name, known) if known.is_empty() => write!(f, "no profile {:?} in {:?}, which has no profiles", name, path),
+            Self::UnknownProfile(path, name, known) => write!(f, "no profile {:?} in {:?}; it has {}", name, path, known.join(", ")),
+            Self::Conflict(why) => write!(f, "conflicting settings: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// Load settings from a TOML file; anything it doesn't mention keeps its default.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, SettingsError> {
+    let text = fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| SettingsError::Parse(path.to_path_buf(), e))
+}
+
+/// Load settings from a TOML file whose `[profile.<name>]` sections are presets over the rest
+/// of it, laying the one named `profile`, if any, over the rest: each key the profile gives
+/// replaces the file's, lists included, and anything neither mentions keeps its default.
+///
+/// `parse` makes settings of the keys; every profile is put through it, chosen or not, so a
+/// mistake in one is found before a sensor is switched to it.
+pub fn load_profile<T, F>(path: &Path, profile: Option<&str>, parse: F) -> Result<T, SettingsError>
+where
+    F: Fn(toml::Table) -> Result<T, toml::de::Error>,
+{
+    let text = fs::read_to_string(path).map_err(|e| SettingsError::Io(path.to_path_buf(), e))?;
+    let mut base: toml::Table = text.parse().map_err(|e| SettingsError::Parse(path.to_path_buf(), e))?;
+    let profiles = match base.remove("profile") {
+        None => toml::Table::new(),
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(SettingsError::Parse(path.to_path_buf(), serde::de::Error::custom("profile must be [profile.<name>] sections"))),
+    };
+    let layered = |name: &str| -> Result<toml::Table, SettingsError> {
+        let Some(toml::Value::Table(keys)) = profiles.get(name) else {
+            return Err(SettingsError::Profile(path.to_path_buf(), name.to_string(), "not a section".to_string()));
+        };
+        let mut table = base.clone();
+        table.extend(keys.clone());
+        Ok(table)
+    };
+    for name in profiles.keys() {
+        parse(layered(name)?).map_err(|e| SettingsError::Profile(path.to_path_buf(), name.clone(), e.to_string()))?;
+    }
+    match profile {
+        Some(name) if !profiles.contains_key(name) => Err(SettingsError::UnknownProfile(path.to_path_buf(), name.to_string(), profiles.keys().cloned().collect())),
+        Some(name) => parse(layered(name)?).map_err(|e| SettingsError::Profile(path.to_path_buf(), name.to_string(), e.to_string())),
+        None => parse(base).map_err(|e| SettingsError::Parse(path.to_path_buf(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::{cli::ClientArgs, client::ClientSettings, filter::Cidr, output::Output};
+
+    use super::*;
+
+    /// The client's flags, as `glosco_client` takes them.
+    #[derive(Debug, Parser)]
+    struct Args {
+        #[command(flatten)]
+        client: ClientArgs,
+    }
+
+    const ROLES: &str = r#"
+keepalive = 60
+ignore_ports = [22]
+include = ["10.0.0.0/8"]
+
+[profile.uplink]
+keepalive = 300
+control_only = true
+
+[profile.lab]
+include = []
+output = "compact"
+"#;
+
+    fn config(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("glosco-settings-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    /// Settings from `text` as the config file, if any, and `flags` after it.
+    fn resolved(name: &str, text: Option<&str>, flags: &[&str]) -> Result<ClientSettings, SettingsError> {
+        let path = text.map(|text| config(name, text));
+        let mut argv = vec!["glosco_client".to_string()];
+        if let Some(path) = &path {
+            argv.extend(["--config".to_string(), path.to_str().unwrap().to_string()]);
+        }
+        argv.extend(flags.iter().map(|flag| flag.to_string()));
+        let settings = Args::try_parse_from(argv).unwrap().client.try_resolve();
+        if let Some(path) = path {
+            let _ = fs::remove_file(path);
+        }
+        settings
+    }
+
+    fn cidrs(blocks: &[&str]) -> Vec<Cidr> {
+        blocks.iter().map(|block| block.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn flags_over_the_profile_over_the_file_over_the_defaults() {
+        // keepalive, ignore_ports, include, control_only, output
+        type Expected<'a> = (u64, &'a [u16], &'a [&'a str], bool, Output);
+        let cases: &[(Option<&str>, &[&str], Expected)] = &[
+            // Defaults alone
+            (None, &[], (30, &[], &[], false, Output::Debug)),
+            (None, &["--keepalive", "15", "--ignore-port", "53"], (15, &[53], &[], false, Output::Debug)),
+            // The file over the defaults
+            (Some(ROLES), &[], (60, &[22], &["10.0.0.0/8"], false, Output::Debug)),
+            // A profile over the file: what it gives replaces, the rest is the file's
+            (Some(ROLES), &["--profile", "uplink"], (300, &[22], &["10.0.0.0/8"], true, Output::Debug)),
+            // Lists included, even when the profile's is empty
+            (Some(ROLES), &["--profile", "lab"], (60, &[22], &[], false, Output::Compact)),
+            // Flags over all of it, lists replacing rather than adding to them
+            (Some(ROLES), &["--profile", "uplink", "--keepalive", "15"], (15, &[22], &["10.0.0.0/8"], true, Output::Debug)),
+            (Some(ROLES), &["--ignore-port", "53", "--include", "192.168.0.0/16"], (60, &[53], &["192.168.0.0/16"], false, Output::Debug)),
+            (Some(ROLES), &["--profile", "lab", "--output", "json"], (60, &[22], &[], false, Output::Json)),
+            // A switch can only be turned on by a flag, not off
+            (Some(ROLES), &["--profile", "uplink", "--control-only"], (300, &[22], &["10.0.0.0/8"], true, Output::Debug)),
+        ];
+        for (idx, (text, flags, (keepalive, ignore_ports, include, control_only, output))) in cases.iter().enumerate() {
+            let settings = resolved(&format!("precedence-{}", idx), *text, flags).unwrap();
+            let case = format!("case {}: {:?}", idx, flags);
+            assert_eq!(settings.filters.keepalive, *keepalive, "{}", case);
+            assert_eq!(settings.filters.ignore_ports, *ignore_ports, "{}", case);
+            assert_eq!(settings.filters.include, cidrs(include), "{}", case);
+            assert_eq!(settings.filters.control_only, *control_only, "{}", case);
+            assert_eq!(settings.output, *output, "{}", case);
+        }
+    }
+
+    #[test]
+    fn bad_profiles_are_refused_with_what_was_wrong() {
+        let cases: &[(&str, &str, &[&str], &str)] = &[
+            ("unknown", ROLES, &["--profile", "edge"], "no profile \"edge\" in"),
+            ("unknown-listed", ROLES, &["--profile", "edge"], "it has lab, uplink"),
+            ("none", "keepalive = 60\n", &["--profile", "edge"], "which has no profiles"),
+            // Checked whether it's chosen or not
+            ("broken", "[profile.uplink]\nkeepalive = \"soon\"\n", &[], "couldn't parse [profile.uplink]"),
+            ("misspelt", "[profile.uplink]\nkeeplive = 300\n", &["--profile", "uplink"], "unknown field `keeplive`"),
+            ("not-a-section", "profile = \"uplink\"\n", &[], "must be [profile.<name>] sections"),
+            // Keys that only conflict once laid over each other, or over the flags
+            ("conflict", "include = [\"10.0.0.0/8\"]\n[profile.lab]\nexclude = [\"10.0.0.0/8\"]\n", &["--profile", "lab"], "10.0.0.0/8 is both included and excluded"),
+            ("conflict-flag", ROLES, &["--exclude", "10.0.0.0/8"], "10.0.0.0/8 is both included and excluded"),
+        ];
+        for (name, text, flags, complaint) in cases {
+            let e = resolved(name, Some(text), flags).unwrap_err().to_string();
+            assert!(e.contains(complaint), "{}: {}", name, e);
+        }
+    }
+
+    #[test]
+    fn a_profile_needs_a_config_file() {
+        assert!(Args::try_parse_from(["glosco_client", "--profile", "uplink"]).is_err());
+    }
+}
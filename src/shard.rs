@@ -0,0 +1,276 @@
+use std::{collections::VecDeque, fs, io, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use crate::{changes::Changes, db, server};
+
+const PREFIX: &str = "glosco-";
+const SUFFIX: &str = ".db";
+
+/// The file name an ident's shard is stored under, like `glosco-web-1.db`.
+///
+/// Letters, digits, `-`, `_` and `.` are kept as they are; every other byte (including `/`,
+/// and `%` itself) is written as `%XX`, so distinct idents never share a file and none can
+/// name a path outside the shard directory.
+pub fn file_name(ident: &str) -> String {
+    let mut name = String::with_capacity(PREFIX.len() + ident.len() + SUFFIX.len());
+    name.push_str(PREFIX);
+    for byte in ident.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.') {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    name.push_str(SUFFIX);
+    name
+}
+
+/// The ident a shard file name was made from, or `None` if it isn't one.
+pub fn ident_of(file_name: &str) -> Option<String> {
+    let escaped = file_name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(.. 2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2 ..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Every shard file in `dir`, in file name order.
+pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
+    let mut shards = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name().and_then(|name| name.to_str()).and_then(ident_of).is_some() {
+            shards.push(path);
+        }
+    }
+    shards.sort();
+    Ok(shards)
+}
+
+/// One SQLite file per ident, in a directory, opened as they're needed.
+///
+/// Handles are taken out for as long as they're used and given back afterwards. Of those given
+/// back, the `handles` most recently used are kept open for next time and older ones are
+/// closed; a handle that's taken out doesn't count against that.
+#[derive(Debug)]
+pub struct Shards {
+    dir: PathBuf,
+    handles: usize,
+    idle: Mutex<VecDeque<(PathBuf, rusqlite::Connection)>>,
+    changes: Arc<Changes>,
+}
+
+impl Shards {
+    /// Keep shards in `dir`, creating it if it doesn't exist yet, and report what's written to
+    /// them to `changes`.
+    pub fn open<P: Into<PathBuf>>(dir: P, handles: usize, changes: Arc<Changes>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            handles: handles.max(1),
+            idle: Mutex::default(),
+            changes,
+        })
+    }
+
+    /// Where `ident`'s shard is (or will be).
+    pub fn path(&self, ident: &str) -> PathBuf {
+        self.dir.join(file_name(ident))
+    }
+
+    /// Every shard that exists so far.
+    pub fn list(&self) -> io::Result<Vec<PathBuf>> {
+        list(&self.dir)
+    }
+
+    /// A handle on `ident`'s shard, creating and migrating it if it's new.
+    pub fn take(&self, ident: &str) -> rusqlite::Result<rusqlite::Connection> {
+        self.take_path(&self.path(ident))
+    }
+
+    /// A handle on the shard at `path`: an idle one if there is one, else a fresh one.
+    pub fn take_path(&self, path: &Path) -> rusqlite::Result<rusqlite::Connection> {
+        {
+            let mut idle = self.idle.lock().unwrap();
+            if let Some(idx) = idle.iter().rposition(|(open, _)| open == path) {
+                return Ok(idle.remove(idx).expect("index in bounds").1);
+            }
+        }
+        let mut db = db::open(path)?;
+        server::migrate(&mut db);
+        self.changes.hook(&db);
+        Ok(db)
+    }
+
+    /// Hand back a handle from `take_path`, closing the least recently used if that's too many.
+    pub fn give(&self, path: PathBuf, db: rusqlite::Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        idle.push_back((path, db));
+        while idle.len() > self.handles {
+            idle.pop_front();
+        }
+    }
+
+    /// Run `op` against `ident`'s shard.
+    pub fn with<T, F: FnOnce(&mut rusqlite::Connection) -> rusqlite::Result<T>>(&self, ident: &str, op: F) -> rusqlite::Result<T> {
+        let path = self.path(ident);
+        let mut db = self.take_path(&path)?;
+        let result = op(&mut db);
+        self.give(path, db);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cli::TopBy, query::{self, SessionFilter}, test_support::Scratch};
+
+    use super::*;
+
+    /// Shards in a directory of the test's own.
+    fn shards(scratch: &Scratch, handles: usize) -> Shards {
+        Shards::open(scratch.path(), handles, Arc::default()).unwrap()
+    }
+
+    /// Store a Starting row for `ident` from `srcport` to `dstport` at `instime`.
+    fn store(shards: &Shards, ident: &str, srcport: u16, dstport: u16, instime: f64) {
+        shards.with(ident, |db| db.execute("
+            INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state, last_seen)
+            VALUES (?1, ?1, ?2, '127.0.0.1:40000', '10.0.0.1', ?3, '10.0.0.2', ?4, 6, 5, ?1);
+        ", rusqlite::params![instime, ident, srcport, dstport])).unwrap();
+    }
+
+    fn idle(shards: &Shards) -> Vec<PathBuf> {
+        shards.idle.lock().unwrap().iter().map(|(path, _)| path.clone()).collect()
+    }
+
+    #[test]
+    fn plain_idents_are_their_own_file_names() {
+        assert_eq!(file_name("web-1"), "glosco-web-1.db");
+        assert_eq!(file_name("ams1.rack_2"), "glosco-ams1.rack_2.db");
+        assert_eq!(file_name(""), "glosco-.db");
+    }
+
+    #[test]
+    fn idents_cant_name_a_path_outside_the_directory() {
+        for (ident, name) in [
+            ("../../etc/passwd", "glosco-..%2F..%2Fetc%2Fpasswd.db"),
+            ("/var/lib/glosco", "glosco-%2Fvar%2Flib%2Fglosco.db"),
+            ("..\\sensor", "glosco-..%5Csensor.db"),
+            ("sensor\0.db", "glosco-sensor%00.db.db"),
+        ] {
+            assert_eq!(file_name(ident), name);
+            assert_eq!(Path::new(&file_name(ident)).components().count(), 1, "{:?}", ident);
+        }
+        let scratch = Scratch::dir("traversal");
+        let shards = shards(&scratch, 4);
+        assert_eq!(shards.path("../../etc/passwd").parent(), Some(scratch.path()));
+    }
+
+    #[test]
+    fn odd_characters_are_escaped_and_come_back() {
+        for (ident, name) in [
+            ("web 1", "glosco-web%201.db"),
+            ("100%", "glosco-100%25.db"),
+            ("sïte", "glosco-s%C3%AFte.db"),
+            ("a:b*c?", "glosco-a%3Ab%2Ac%3F.db"),
+        ] {
+            assert_eq!(file_name(ident), name);
+            assert_eq!(ident_of(name).as_deref(), Some(ident));
+        }
+        // Escaping `%` too keeps an ident spelling out an escape apart from what it escapes
+        assert_ne!(file_name("a/b"), file_name("a%2Fb"));
+        assert_eq!(ident_of(&file_name("a%2Fb")).as_deref(), Some("a%2Fb"));
+    }
+
+    #[test]
+    fn other_files_arent_shards() {
+        for name in ["glosco.db", "other-web-1.db", "glosco-web-1.db-wal", "glosco-%zz.db", "glosco-%4.db", "glosco-%FF.db"] {
+            assert_eq!(ident_of(name), None, "{:?}", name);
+        }
+        let scratch = Scratch::dir("list");
+        let shards = shards(&scratch, 4);
+        store(&shards, "web-2", 1, 443, 1000.0);
+        store(&shards, "web-1", 1, 443, 1000.0);
+        fs::write(scratch.path().join("notes.txt"), "").unwrap();
+        fs::write(scratch.path().join("glosco.db"), "").unwrap();
+        assert_eq!(shards.list().unwrap(), [shards.path("web-1"), shards.path("web-2")]);
+    }
+
+    #[test]
+    fn each_ident_gets_a_file_of_its_own() {
+        let scratch = Scratch::dir("separate");
+        let shards = shards(&scratch, 4);
+        store(&shards, "web-1", 1, 443, 1000.0);
+        store(&shards, "web-1", 2, 443, 1001.0);
+        store(&shards, "../db 2", 3, 443, 1002.0);
+        for (ident, rows) in [("web-1", 2), ("../db 2", 1)] {
+            let db = db::open(shards.path(ident)).unwrap();
+            let stored: Vec<String> = db.prepare("SELECT DISTINCT ident FROM state").unwrap()
+                .query_map([], |row| row.get(0)).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            assert_eq!(stored, [ident]);
+            assert_eq!(db.query_row("SELECT COUNT(*) FROM state", [], |row| row.get::<_, i64>(0)).unwrap(), rows);
+        }
+        assert!(!scratch.path().join("..").join("db 2").exists());
+    }
+
+    #[test]
+    fn only_the_most_recently_used_handles_stay_open() {
+        let scratch = Scratch::dir("handles");
+        let shards = shards(&scratch, 2);
+        for ident in ["a", "b", "c"] {
+            store(&shards, ident, 1, 443, 1000.0);
+        }
+        assert_eq!(idle(&shards), [shards.path("b"), shards.path("c")]);
+        // Using one again makes it the most recent
+        store(&shards, "b", 2, 443, 1001.0);
+        assert_eq!(idle(&shards), [shards.path("c"), shards.path("b")]);
+
+        // A closed one is opened again as it was left
+        store(&shards, "a", 2, 443, 1001.0);
+        assert_eq!(idle(&shards), [shards.path("b"), shards.path("a")]);
+        let rows = shards.with("a", |db| db.query_row("SELECT COUNT(*) FROM state", [], |row| row.get::<_, i64>(0))).unwrap();
+        assert_eq!(rows, 2);
+
+        // One taken out doesn't count until it's given back
+        let path = shards.path("c");
+        let taken = shards.take_path(&path).unwrap();
+        store(&shards, "d", 1, 443, 1000.0);
+        assert_eq!(idle(&shards), [shards.path("a"), shards.path("d")]);
+        shards.give(path, taken);
+        assert_eq!(idle(&shards), [shards.path("d"), shards.path("c")]);
+    }
+
+    #[test]
+    fn queries_take_in_every_shard() {
+        let scratch = Scratch::dir("query");
+        let shards = shards(&scratch, 1);
+        store(&shards, "web-2", 1, 443, 1003.0);
+        store(&shards, "web-1", 1, 443, 1001.0);
+        store(&shards, "web-1", 2, 22, 1002.0);
+        store(&shards, "db", 1, 5432, 1000.0);
+        drop(shards);
+
+        let dbs = query::open_all(scratch.database()).unwrap();
+        assert_eq!(dbs.len(), 3);
+        let filter = SessionFilter { since: 0.0, until: f64::MAX, ..Default::default() };
+        let sessions = query::union(&dbs, |db| query::sessions(db, &filter, usize::MAX), query::session_order).unwrap();
+        let found: Vec<(&str, u16)> = sessions.iter().map(|session| (session.ident.as_str(), session.srcport)).collect();
+        assert_eq!(found, [("db", 1), ("web-1", 1), ("web-1", 2), ("web-2", 1)]);
+
+        // Counts from each shard add up
+        let top = query::top_all(&dbs, TopBy::Dstport, 0.0, f64::MAX, 2).unwrap();
+        let top: Vec<(&str, u64)> = top.iter().map(|talker| (talker.key.as_str(), talker.connections)).collect();
+        assert_eq!(top, [("443", 2), ("22", 1)]);
+    }
+}
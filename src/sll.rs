@@ -0,0 +1,175 @@
+//! Packets as Linux "cooked" captures give them, behind an SLL or SLL2 header in place of the
+//! link layer's own: what capturing on the `any` pseudo-device gets, and on interfaces with no
+//! link-layer header to speak of, like tun devices and PPP.
+
+/// Who a cooked packet was to or from, as the kernel saw it.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum PacketType {
+    /// To this host.
+    Host,
+    Broadcast,
+    Multicast,
+    /// To some other host, seen by an interface in promiscuous mode.
+    OtherHost,
+    /// Sent by this host.
+    Outgoing,
+    Other(u16),
+}
+
+impl From<u16> for PacketType {
+    fn from(raw: u16) -> Self {
+        match raw {
+            0 => Self::Host,
+            1 => Self::Broadcast,
+            2 => Self::Multicast,
+            3 => Self::OtherHost,
+            4 => Self::Outgoing,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// What a cooked capture carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cooked<'a> {
+    pub packet_type: PacketType,
+    /// The packet's ethertype, for the link types that have one.
+    pub protocol: u16,
+    /// The kernel's index of the interface it was captured on; SLL2 only.
+    pub ifindex: Option<u32>,
+    pub packet: &'a [u8],
+}
+
+/// The header is cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Malformed;
+
+/// Take the header off a packet captured with link type `LINUX_SLL`.
+pub fn parse(frame: &[u8]) -> Result<Cooked<'_>, Malformed> {
+    // Packet type, ARPHRD type, address length, the address padded to 8 bytes, protocol
+    let header = frame.get(.. 16).ok_or(Malformed)?;
+    Ok(Cooked {
+        packet_type: PacketType::from(u16::from_be_bytes([header[0], header[1]])),
+        protocol: u16::from_be_bytes([header[14], header[15]]),
+        ifindex: None,
+        packet: &frame[16 ..],
+    })
+}
+
+/// Take the header off a packet captured with link type `LINUX_SLL2`.
+pub fn parse_v2(frame: &[u8]) -> Result<Cooked<'_>, Malformed> {
+    // Protocol, reserved, interface index, ARPHRD type, packet type, address length, the
+    // address padded to 8 bytes
+    let header = frame.get(.. 20).ok_or(Malformed)?;
+    Ok(Cooked {
+        packet_type: PacketType::from(u16::from(header[10])),
+        protocol: u16::from_be_bytes([header[0], header[1]]),
+        ifindex: Some(u32::from_be_bytes([header[4], header[5], header[6], header[7]])),
+        packet: &frame[20 ..],
+    })
+}
+
+/// The name of the interface the kernel knows by `ifindex`, if there still is one.
+#[cfg(unix)]
+pub fn interface_name(ifindex: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    // Safety: the buffer is IF_NAMESIZE long, as if_indextoname requires, and NUL-terminated
+    // whenever it returns non-null.
+    unsafe {
+        if libc::if_indextoname(ifindex, name.as_mut_ptr()).is_null() {
+            return None;
+        }
+        Some(std::ffi::CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(not(unix))]
+pub fn interface_name(_ifindex: u32) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An SLL header for an IPv4 packet sent by this host over Ethernet, then the packet.
+    const SENT: [u8; 18] = [
+        // Packet type, ARPHRD type
+        0x00, 0x04, 0x00, 0x01,
+        // Address length, the address padded to 8 bytes
+        0x00, 0x06, 0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00,
+        // Protocol
+        0x08, 0x00,
+        0x45, 0x00,
+    ];
+
+    #[test]
+    fn header() {
+        assert_eq!(parse(&SENT), Ok(Cooked {
+            packet_type: PacketType::Outgoing,
+            protocol: 0x0800,
+            ifindex: None,
+            packet: &[0x45, 0x00],
+        }));
+    }
+
+    #[test]
+    fn a_header_and_nothing_else() {
+        let cooked = parse(&SENT[.. 16]).unwrap();
+        assert_eq!(cooked.protocol, 0x0800);
+        assert!(cooked.packet.is_empty());
+    }
+
+    #[test]
+    fn packet_types() {
+        let mut frame = SENT;
+        for (raw, packet_type) in [(0, PacketType::Host), (1, PacketType::Broadcast), (2, PacketType::Multicast), (3, PacketType::OtherHost), (0x0107, PacketType::Other(0x0107))] {
+            frame[.. 2].copy_from_slice(&u16::to_be_bytes(raw));
+            assert_eq!(parse(&frame).unwrap().packet_type, packet_type);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(parse(&SENT[.. 15]), Err(Malformed));
+        assert_eq!(parse(&[]), Err(Malformed));
+    }
+
+    /// The same packet behind an SLL2 header, captured on interface 3.
+    const SENT_V2: [u8; 22] = [
+        // Protocol, reserved
+        0x08, 0x00, 0x00, 0x00,
+        // Interface index
+        0x00, 0x00, 0x00, 0x03,
+        // ARPHRD type, packet type, address length, the address padded to 8 bytes
+        0x00, 0x01, 0x04, 0x06, 0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00,
+        0x45, 0x00,
+    ];
+
+    #[test]
+    fn header_v2() {
+        assert_eq!(parse_v2(&SENT_V2), Ok(Cooked {
+            packet_type: PacketType::Outgoing,
+            protocol: 0x0800,
+            ifindex: Some(3),
+            packet: &[0x45, 0x00],
+        }));
+    }
+
+    #[test]
+    fn packet_types_v2() {
+        let mut frame = SENT_V2;
+        for (raw, packet_type) in [(0, PacketType::Host), (3, PacketType::OtherHost), (0xff, PacketType::Other(0xff))] {
+            frame[10] = raw;
+            assert_eq!(parse_v2(&frame).unwrap().packet_type, packet_type);
+        }
+    }
+
+    #[test]
+    fn truncated_v2() {
+        assert!(parse_v2(&SENT_V2[.. 20]).unwrap().packet.is_empty());
+        assert_eq!(parse_v2(&SENT_V2[.. 19]), Err(Malformed));
+        // Long enough for SLL, not SLL2
+        assert_eq!(parse_v2(&SENT[.. 16]), Err(Malformed));
+    }
+}
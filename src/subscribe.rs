@@ -0,0 +1,209 @@
+use std::{collections::VecDeque, io::{self, Write}, net::{SocketAddr, TcpStream}, sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc, Mutex}, time::SystemTime};
+
+use serde::Serialize;
+
+use crate::{alert::Kind, coding::{Coder, CodingVec}, filter::Glob, observe::Message};
+
+/// Sent as the first frame, in place of a `Hello`, by a connection that wants to receive
+/// accepted messages rather than report its own.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subscribe {
+    /// Only messages from idents matching one of these; all idents if empty.
+    pub idents: Vec<Glob>,
+    /// Only messages of these kinds; every kind if empty.
+    pub kinds: Vec<Kind>,
+    /// Replay what was accepted after this sequence number, as far back as the collector
+    /// still remembers, before going on to what it accepts next.
+    pub since: Option<u64>,
+}
+
+impl Subscribe {
+    pub fn matches(&self, ident: &str, message: &Message) -> bool {
+        (self.idents.is_empty() || self.idents.iter().any(|glob| glob.matches(ident)))
+            && (self.kinds.is_empty() || self.kinds.contains(&Kind::of(message)))
+    }
+}
+
+/// One accepted message as delivered to subscribers, tagged with the ident that reported it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Envelope {
+    pub ident: String,
+    pub message: Message,
+    /// Where the message falls in everything the collector has published since it started,
+    /// for resuming from with `Subscribe::since`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+/// An envelope ready to send, length and all, shared between every subscriber it goes to.
+pub type Frame = Arc<Vec<u8>>;
+
+struct Subscriber {
+    filter: Subscribe,
+    sender: mpsc::SyncSender<Frame>,
+}
+
+/// A published message, kept for subscribers resuming from before it.
+struct Published {
+    seq: u64,
+    ident: String,
+    message: Message,
+    frame: Frame,
+}
+
+struct Inner {
+    subscribers: Vec<Subscriber>,
+    next: u64,
+    /// The most recent messages published, once anyone has asked to resume.
+    history: Option<VecDeque<Published>>,
+}
+
+/// Fans accepted messages out to every subscriber whose filter matches.
+///
+/// Each subscriber gets its own bounded queue; a subscriber that can't keep up loses messages
+/// (counted in `dropped`) rather than slowing ingest down. Messages are numbered as they're
+/// published, starting from the time the collector started in microseconds so that numbers
+/// keep going up across restarts; once a subscriber has asked to resume from one, the last
+/// `HISTORY` are kept to replay to it when it comes back.
+pub struct Broadcast {
+    inner: Mutex<Inner>,
+    dropped: AtomicU64,
+}
+
+impl Default for Broadcast {
+    fn default() -> Self {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        Self {
+            inner: Mutex::new(Inner { subscribers: Vec::new(), next: started.as_micros() as u64, history: None }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl std::fmt::Debug for Broadcast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Broadcast")
+            .field("subscribers", &self.subscribers())
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl Broadcast {
+    pub const BACKLOG: usize = 1024;
+    pub const HISTORY: usize = 65536;
+
+    /// Register a subscriber; it stays registered until the receiver is dropped. Returns the
+    /// frames to replay to it first if it asked to resume, along with the receiver for
+    /// everything published from here on.
+    pub fn subscribe(&self, filter: Subscribe) -> (Vec<Frame>, mpsc::Receiver<Frame>) {
+        let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
+        let mut inner = self.inner.lock().unwrap();
+        let replay = match filter.since {
+            Some(since) => inner.history.get_or_insert_with(VecDeque::new).iter()
+                .filter(|published| published.seq > since && filter.matches(&published.ident, &published.message))
+                .map(|published| published.frame.clone())
+                .collect(),
+            None => Vec::new(),
+        };
+        inner.subscribers.push(Subscriber { filter, sender });
+        (replay, receiver)
+    }
+
+    /// Whether there's anyone to publish to, now or on resuming.
+    pub fn wanted(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        !inner.subscribers.is_empty() || inner.history.is_some()
+    }
+
+    /// Queue a message for every interested subscriber, as a ready-to-send frame.
+    pub fn publish(&self, ident: &str, message: &Message) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { subscribers, next, history } = &mut *inner;
+        if subscribers.is_empty() && history.is_none() {
+            return;
+        }
+        let seq = *next;
+        *next += 1;
+        let mut payload = Vec::new();
+        Envelope {
+            ident: ident.to_string(),
+            message: message.clone(),
+            seq: Some(seq),
+        }.encode(&mut payload).expect("failed to encode envelope");
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        CodingVec::<u8, u32>::new(payload).encode(&mut frame).expect("failed to encode frame");
+        let frame = Arc::new(frame);
+        subscribers.retain(|subscriber| {
+            if !subscriber.filter.matches(ident, message) {
+                return true;
+            }
+            match subscriber.sender.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                },
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            }
+        });
+        if let Some(history) = history {
+            if history.len() == Self::HISTORY {
+                history.pop_front();
+            }
+            history.push_back(Published { seq, ident: ident.to_string(), message: message.clone(), frame });
+        }
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.inner.lock().unwrap().subscribers.len()
+    }
+
+    /// Messages a subscriber missed because its queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Write a subscriber what it's to be replayed, then its queued frames until it hangs up.
+pub fn serve(mut stream: TcpStream, replay: Vec<Frame>, receiver: mpsc::Receiver<Frame>) -> io::Result<()> {
+    for frame in replay {
+        stream.write_all(&frame)?;
+    }
+    while let Ok(frame) = receiver.recv() {
+        stream.write_all(&frame)?;
+    }
+    Ok(())
+}
+
+/// A live feed of the messages a collector accepts.
+#[derive(Debug)]
+pub struct Subscription {
+    stream: TcpStream,
+}
+
+impl Subscription {
+    pub fn connect(addr: SocketAddr, ident: &str, filter: &Subscribe) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        let mut hello = Vec::new();
+        ident.to_string().encode(&mut hello)?;
+        let mut request = Vec::new();
+        filter.encode(&mut request)?;
+        CodingVec::<u8, u32>::new(request).encode(&mut hello)?;
+        stream.write_all(&hello)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = io::Result<Envelope>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = match CodingVec::<u8, u32>::decode(&mut self.stream) {
+            Ok(frame) => frame.0,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Envelope::decode(&mut frame.as_slice()))
+    }
+}
@@ -1,51 +1,378 @@
-use std::{io::{self, Write}, thread, net::{SocketAddr, TcpStream}, sync::{mpsc, Arc}, time::{Duration, Instant}};
+use std::{collections::BTreeMap, fmt, io::{self, Write}, thread, net::{SocketAddr, TcpStream, ToSocketAddrs}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc, Arc, Mutex, RwLock}, time::{Duration, Instant}};
 
 use crate::coding::{Coder, CodingVec};
+use crate::dns::{self, SrvLookup};
+use crate::metrics::{Exposition, Family, Sample, Type};
+use crate::observe::Message;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Default)]
 pub struct ClientConfig {
     dests: Vec<SocketAddr>,
     ident: String,
+    keepalive: Option<u32>,
+    tags: BTreeMap<String, String>,
+    srv: Option<SrvRemotes>,
+}
+
+/// Collectors to find by SRV record, and what to look them up with.
+struct SrvRemotes {
+    name: String,
+    lookup: Box<dyn SrvLookup>,
+    /// `ClientConfig::SRV_MIN_TTL` and the rest, but for tests that can't wait that long.
+    min_ttl: Duration,
+    max_ttl: Duration,
+    retry: Duration,
+}
+
+impl fmt::Debug for SrvRemotes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SrvRemotes")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+/// Sent as the first frame of every connection, after the ident, so the server knows what's
+/// reporting to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    /// Software and version, like `glosco/0.1.0`.
+    pub agent: String,
+    /// Seconds between keepalives for open connections, if the client sends them.
+    pub keepalive: Option<u32>,
+    /// Deployment labels, like `site=ams1`, that replace whatever the collector had for this
+    /// ident. Clients too old to send any send none.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Hello {
+    pub const AGENT: &'static str = concat!("glosco/", env!("CARGO_PKG_VERSION"));
+    /// Most tags a hello may carry.
+    pub const MAX_TAGS: usize = 32;
+    /// Longest a tag key may be, in bytes; values may be four times this.
+    pub const MAX_TAG_KEY: usize = 64;
+
+    /// Whether `key` and `value` make an acceptable tag: a key of letters, digits, `_`, `-`
+    /// and `.`, and a value of printable characters, each within its length limit.
+    pub fn check_tag(key: &str, value: &str) -> Result<(), String> {
+        if key.is_empty() || key.len() > Self::MAX_TAG_KEY {
+            return Err(format!("tag key {:?} must be 1 to {} bytes", key, Self::MAX_TAG_KEY));
+        }
+        if !key.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)) {
+            return Err(format!("tag key {:?} may only have letters, digits, _, - and .", key));
+        }
+        if value.len() > 4 * Self::MAX_TAG_KEY {
+            return Err(format!("tag {:?} has a value over {} bytes", key, 4 * Self::MAX_TAG_KEY));
+        }
+        if value.chars().any(char::is_control) {
+            return Err(format!("tag {:?} has control characters in its value", key));
+        }
+        Ok(())
+    }
+
+    /// Parse a `key=value` tag, as `--tag` takes it.
+    pub fn parse_tag(tag: &str) -> Result<(String, String), String> {
+        let (key, value) = tag.split_once('=').ok_or_else(|| format!("tag {:?} isn't key=value", tag))?;
+        Self::check_tag(key, value)?;
+        Ok((key.to_string(), value.to_string()))
+    }
+}
+
+/// A message passed on by a relaying collector, carrying the ident of the client that first
+/// reported it.
+///
+/// Relayed messages share the relay's connection (and its ident), so the receiving collector
+/// trusts whatever ident each one claims.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relayed {
+    pub ident: String,
+    pub message: Message,
+}
+
+/// Sent ahead of the frames a client queues wherever their numbers don't follow on from the
+/// last frame sent on the connection: first thing after the hello, and after frames the
+/// backlog had no room for. A client numbers every frame it queues from 0 as it starts, these
+/// and hellos aside, so a collector can tell frames that never arrived from a client that
+/// started over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequence {
+    /// The number of the frame after this one.
+    pub next: u64,
 }
 
 #[derive(Debug)]
 pub struct Client {
-    senders: Vec<mpsc::SyncSender<Arc<Vec<u8>>>>,
+    remotes: Remotes,
+    /// The number the next frame queued gets. Locked while queueing, so every remote gets
+    /// frames in the order they're numbered.
+    next: Mutex<u64>,
+    /// Tells the SRV thread, if there is one, to stop: set the flag, then nudge it.
+    srv: Option<(Arc<AtomicBool>, mpsc::SyncSender<()>)>,
+}
+
+/// A collector being sent to.
+#[derive(Debug)]
+struct Remote {
+    sender: mpsc::SyncSender<Queued>,
+    stats: Arc<RemoteStats>,
+    /// Set once it's no longer wanted, for a thread still trying to connect to notice.
+    retired: Arc<AtomicBool>,
+}
+
+/// Bytes to write to every remote, how many frames they make up, and the number of the first.
+type Queued = (Arc<Vec<u8>>, u64, u64);
+
+/// The collectors a client sends to, which change along with SRV records.
+#[derive(Debug, Clone, Default)]
+pub struct Remotes(Arc<RwLock<Vec<Remote>>>);
+
+/// How sending to one remote is going.
+#[derive(Debug)]
+pub struct RemoteStats {
+    pub addr: SocketAddr,
+    pub connected: AtomicBool,
+    /// Times a connection was made.
+    pub connects: AtomicU64,
+    /// Times connecting failed.
+    pub connect_errors: AtomicU64,
+    /// Frames waiting to be sent or being sent; the backlog holds `ClientConfig::BACKLOG`
+    /// sends, of a frame or a bundle each.
+    pub queued: AtomicU64,
+    pub sent: AtomicU64,
+    /// Frames never sent: dropped because the backlog was full, or lost with the connection.
+    pub dropped: AtomicU64,
+}
+
+const CONNECTED: Family = Family {
+    name: "glosco_remote_connected",
+    help: "Whether the remote is connected.",
+    kind: Type::Gauge,
+};
+const CONNECTS: Family = Family {
+    name: "glosco_remote_connects_total",
+    help: "Connections made to the remote.",
+    kind: Type::Counter,
+};
+const CONNECT_ERRORS: Family = Family {
+    name: "glosco_remote_connect_errors_total",
+    help: "Attempts to connect to the remote that failed.",
+    kind: Type::Counter,
+};
+const QUEUED: Family = Family {
+    name: "glosco_remote_queued",
+    help: "Frames waiting to be sent to the remote.",
+    kind: Type::Gauge,
+};
+const SENT: Family = Family {
+    name: "glosco_remote_sent_total",
+    help: "Frames sent to the remote.",
+    kind: Type::Counter,
+};
+const DROPPED: Family = Family {
+    name: "glosco_remote_dropped_total",
+    help: "Frames never sent, because the remote's backlog was full or its connection was lost.",
+    kind: Type::Counter,
+};
+
+impl RemoteStats {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            connected: AtomicBool::new(false),
+            connects: AtomicU64::new(0),
+            connect_errors: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Add every remote's counts to a scrape.
+    pub fn collect(stats: &[Arc<Self>], out: &mut Exposition) {
+        let sample = |value: fn(&Self) -> f64| -> Vec<Sample> {
+            stats.iter().map(|stat| Sample::new(value(stat)).label("remote", stat.addr)).collect()
+        };
+        out.family(&CONNECTED, sample(|stat| if stat.connected.load(Ordering::Relaxed) { 1.0 } else { 0.0 }));
+        out.family(&CONNECTS, sample(|stat| stat.connects.load(Ordering::Relaxed) as f64));
+        out.family(&CONNECT_ERRORS, sample(|stat| stat.connect_errors.load(Ordering::Relaxed) as f64));
+        out.family(&QUEUED, sample(|stat| stat.queued.load(Ordering::Relaxed) as f64));
+        out.family(&SENT, sample(|stat| stat.sent.load(Ordering::Relaxed) as f64));
+        out.family(&DROPPED, sample(|stat| stat.dropped.load(Ordering::Relaxed) as f64));
+    }
 }
 
 const RETRY_BACKOFF_WIN: (usize, Duration) = (5, Duration::new(10, 0));
-fn client_thread(addr: SocketAddr, receiver: mpsc::Receiver<Arc<Vec<u8>>>, hello: Arc<Vec<u8>>) {
+fn client_thread(addr: SocketAddr, receiver: mpsc::Receiver<Queued>, hello: Arc<Vec<u8>>, stats: Arc<RemoteStats>, retired: Arc<AtomicBool>, lost: Option<mpsc::SyncSender<()>>) {
+    // Let the SRV thread know this one's in trouble, in case the records have moved on
+    let nudge = || if let Some(lost) = &lost {
+        let _ = lost.try_send(());
+    };
     loop {
         let mut tries = 0usize;
         let mut start = Instant::now();
         let mut sock = loop {
             if tries > RETRY_BACKOFF_WIN.0 {
+                nudge();
                 thread::sleep((start + RETRY_BACKOFF_WIN.1).saturating_duration_since(Instant::now()));
                 start = Instant::now();
             }
+            if retired.load(Ordering::SeqCst) {
+                return;
+            }
             println!("Try connect to {:?}", addr);
             match TcpStream::connect(addr) {
                 Ok(sock) => break sock,
                 Err(e) => {
                     println!("Connect error to {:?}: {:?}", addr, e);
+                    stats.connect_errors.fetch_add(1, Ordering::Relaxed);
                     tries += 1;
                 },
             }
         };
-        if let Ok(_) = sock.write_all(&hello) {
-            while let Ok(bytes) = receiver.recv() {
-                if let Err(e) = sock.write_all(&bytes) {
+        stats.connects.fetch_add(1, Ordering::Relaxed);
+        if sock.write_all(&hello).is_ok() {
+            stats.connected.store(true, Ordering::Relaxed);
+            // The number of the frame the collector will count this one's next as
+            let mut next = None;
+            loop {
+                // The client's been dropped, so there's nothing more to send
+                let Ok((bytes, frames, first)) = receiver.recv() else {
+                    return;
+                };
+                let mut written = Ok(());
+                if next != Some(first) {
+                    let mut sequence = Vec::new();
+                    Sequence { next: first }.encode(&mut sequence).unwrap();
+                    let mut frame = Vec::with_capacity(sequence.len() + 4);
+                    CodingVec::<u8, u32>::new(sequence).encode(&mut frame).unwrap();
+                    written = sock.write_all(&frame);
+                }
+                let written = written.and_then(|()| sock.write_all(&bytes));
+                next = Some(first + frames);
+                stats.queued.fetch_sub(frames, Ordering::Relaxed);
+                if let Err(e) = written {
                     println!("Send error: {:?}", e);
+                    stats.dropped.fetch_add(frames, Ordering::Relaxed);
                     break;
                 }
+                stats.sent.fetch_add(frames, Ordering::Relaxed);
             }
         }
+        stats.connected.store(false, Ordering::Relaxed);
         println!("Lost connection to {:?}", addr);
+        nudge();
+    }
+}
+
+impl Remotes {
+    /// How sending to each remote is going, in order.
+    pub fn stats(&self) -> Vec<Arc<RemoteStats>> {
+        self.0.read().unwrap().iter().map(|remote| remote.stats.clone()).collect()
+    }
+
+    /// Start sending to `addr`.
+    fn start(addr: SocketAddr, hello: &Arc<Vec<u8>>, lost: Option<mpsc::SyncSender<()>>) -> Remote {
+        let (sender, receiver) = mpsc::sync_channel(ClientConfig::BACKLOG);
+        let remote = Remote {
+            sender,
+            stats: Arc::new(RemoteStats::new(addr)),
+            retired: Arc::default(),
+        };
+        let hello = hello.clone();
+        let stats = remote.stats.clone();
+        let retired = remote.retired.clone();
+        thread::spawn(move || client_thread(addr, receiver, hello, stats, retired, lost));
+        remote
+    }
+
+    /// Send to `addrs` and only those, in that order: start on the new ones and retire the
+    /// rest, whose threads end once they notice.
+    fn set(&self, addrs: &[SocketAddr], hello: &Arc<Vec<u8>>, lost: &mpsc::SyncSender<()>) {
+        let mut remotes = self.0.write().unwrap();
+        let mut old: Vec<Remote> = remotes.drain(..).collect();
+        for addr in addrs {
+            match old.iter().position(|remote| remote.stats.addr == *addr) {
+                Some(idx) => remotes.push(old.remove(idx)),
+                None => {
+                    println!("Sending to {:?}", addr);
+                    remotes.push(Self::start(*addr, hello, Some(lost.clone())));
+                },
+            }
+        }
+        for remote in old {
+            println!("No longer sending to {:?}", remote.stats.addr);
+            remote.retired.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Keep `remotes` in line with the SRV records, looking again when their TTL runs out or a
+/// remote is lost, and sending to `fallback` while there are no records to be had.
+fn srv_thread(mut srv: SrvRemotes, fallback: Vec<SocketAddr>, remotes: Remotes, hello: Arc<Vec<u8>>, closed: Arc<AtomicBool>, nudge: mpsc::SyncSender<()>, lost: mpsc::Receiver<()>) {
+    loop {
+        let found = match srv.lookup.srv(&srv.name) {
+            Ok(records) => {
+                let best = dns::best_srv(&records);
+                let mut addrs: Vec<SocketAddr> = Vec::new();
+                for record in best.iter() {
+                    match (record.target.as_str(), record.port).to_socket_addrs() {
+                        Ok(found) => for addr in found {
+                            if !addrs.contains(&addr) {
+                                addrs.push(addr);
+                            }
+                        },
+                        Err(e) => println!("Couldn't resolve {}: {:?}", record.target, e),
+                    }
+                }
+                let ttl = best.iter().map(|record| record.ttl).min().unwrap_or(srv.retry);
+                (!addrs.is_empty()).then_some((addrs, ttl.clamp(srv.min_ttl, srv.max_ttl)))
+            },
+            Err(e) => {
+                println!("Couldn't look up {}: {:?}", srv.name, e);
+                None
+            },
+        };
+        let wait = match found {
+            Some((addrs, ttl)) => {
+                remotes.set(&addrs, &hello, &nudge);
+                ttl
+            },
+            None => {
+                println!("No collectors found for {}, falling back to {:?}", srv.name, fallback);
+                remotes.set(&fallback, &hello, &nudge);
+                srv.retry
+            },
+        };
+        let looked = Instant::now();
+        let mut until = looked + wait;
+        loop {
+            let lost_one = match lost.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                Ok(()) => true,
+                Err(mpsc::RecvTimeoutError::Timeout) => false,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            if closed.load(Ordering::SeqCst) {
+                remotes.set(&[], &hello, &nudge);
+                return;
+            }
+            if lost_one {
+                until = until.min(looked + srv.min_ttl);
+            }
+            if Instant::now() >= until {
+                break;
+            }
+        }
     }
 }
 
 impl ClientConfig {
     pub const BACKLOG: usize = 1024;
+    /// Bounds on how long SRV records are trusted for, whatever their TTL; a lost remote has
+    /// them looked up again, but no sooner than the least of these.
+    pub const SRV_MIN_TTL: Duration = Duration::from_secs(30);
+    pub const SRV_MAX_TTL: Duration = Duration::from_secs(3600);
+    /// How soon to look again after finding no records.
+    pub const SRV_RETRY: Duration = Duration::from_secs(60);
 
     pub fn new(ident: String) -> Self {
         Self {
@@ -58,38 +385,398 @@ impl ClientConfig {
         self.dests.push(addr);
     }
 
+    /// Send to the collectors named by the SRV records for `name`, looked up with `lookup`, in
+    /// place of those added; they're only sent to while no usable records can be found.
+    pub fn set_srv(&mut self, name: String, lookup: Box<dyn SrvLookup>) {
+        self.srv = Some(SrvRemotes {
+            name,
+            lookup,
+            min_ttl: Self::SRV_MIN_TTL,
+            max_ttl: Self::SRV_MAX_TTL,
+            retry: Self::SRV_RETRY,
+        });
+    }
+
+    /// Announce how often the observer repeats keepalives for open connections.
+    pub fn set_keepalive(&mut self, secs: u32) {
+        self.keepalive = Some(secs);
+    }
+
+    /// Label this client for the collectors, replacing any labels it had before.
+    pub fn set_tags(&mut self, tags: BTreeMap<String, String>) {
+        self.tags = tags;
+    }
+
     pub fn build(self) -> io::Result<Client> {
-        let mut hello: Vec<u8> = Vec::with_capacity(self.ident.as_bytes().len() + 4);
+        let mut hello: Vec<u8> = Vec::with_capacity(self.ident.len() + 4);
         self.ident.encode(&mut hello).unwrap();
+        let mut announce = Vec::new();
+        Hello {
+            agent: Hello::AGENT.to_string(),
+            keepalive: self.keepalive,
+            tags: self.tags,
+        }.encode(&mut announce).unwrap();
+        CodingVec::<u8, u32>::new(announce).encode(&mut hello).unwrap();
         let hello = Arc::new(hello);
-        let mut senders: Vec<mpsc::SyncSender<Arc<Vec<u8>>>> = Vec::new();
-        for addr in self.dests.into_iter() {
-            let (sender, receiver) = mpsc::sync_channel(Self::BACKLOG);
-            senders.push(sender);
-            let hello = hello.clone();
-            thread::spawn(move || client_thread(addr, receiver, hello));
-        }
-        Ok(Client { senders })
+        let remotes = Remotes::default();
+        let Some(srv) = self.srv else {
+            remotes.0.write().unwrap().extend(self.dests.into_iter().map(|addr| Remotes::start(addr, &hello, None)));
+            return Ok(Client { remotes, next: Mutex::new(0), srv: None });
+        };
+        let closed: Arc<AtomicBool> = Arc::default();
+        let (nudge, lost) = mpsc::sync_channel(1);
+        let (thread_remotes, thread_closed, thread_nudge) = (remotes.clone(), closed.clone(), nudge.clone());
+        thread::spawn(move || srv_thread(srv, self.dests, thread_remotes, hello, thread_closed, thread_nudge, lost));
+        Ok(Client { remotes, next: Mutex::new(0), srv: Some((closed, nudge)) })
     }
 }
 
 impl Client {
+    /// How often `shutdown` checks whether everything's been sent.
+    const FLUSH_POLL: Duration = Duration::from_millis(50);
+
     pub fn send<C: Coder>(&self, object: &C) {
         let mut buffer: Vec<u8> = Vec::new();
         object.encode(&mut buffer).unwrap();
         self.send_frame(&buffer);
     }
 
-    pub fn send_frame(&self, bytes: &Vec<u8>) {
+    pub fn send_frame(&self, bytes: &[u8]) {
         let mut frame = Vec::with_capacity(bytes.len() + 4);
-        CodingVec::<u8, u32>::new(bytes.clone()).encode(&mut frame).unwrap();
-        let message = Arc::new(frame);
-        for sender in self.senders.iter() {
+        CodingVec::<u8, u32>::new(bytes.to_vec()).encode(&mut frame).unwrap();
+        self.enqueue(frame, 1);
+    }
+
+    /// Send messages observed together, each in its own frame as `send` would, but encoded
+    /// into one buffer that's queued (and written) once per remote.
+    pub fn send_bundle(&self, messages: &[Message]) {
+        if messages.is_empty() {
+            return;
+        }
+        let mut frames = Vec::new();
+        for message in messages {
+            // The length goes in front once it's known
+            let at = frames.len();
+            frames.extend_from_slice(&[0; 4]);
+            message.encode(&mut frames).unwrap();
+            let len = (frames.len() - at - 4) as u32;
+            len.encode(&mut &mut frames[at .. at + 4]).unwrap();
+        }
+        self.enqueue(frames, messages.len() as u64);
+    }
+
+    fn enqueue(&self, bytes: Vec<u8>, frames: u64) {
+        let mut next = self.next.lock().unwrap();
+        let queued = (Arc::new(bytes), frames, *next);
+        *next += frames;
+        for Remote { sender, stats, .. } in self.remotes.0.read().unwrap().iter() {
+            // Counted before it's sent, so the sending thread can't take it off first
+            stats.queued.fetch_add(frames, Ordering::Relaxed);
             // If this errors with Full, don't care--we drop the message.
             // If this errors with Disconnected, we should evict the sender, but
             // the architecture isn't good enough yet to do that. It's fairly harmless
             // to keep that handle around.
-            let _ = sender.try_send(message.clone());
+            if sender.try_send(queued.clone()).is_err() {
+                stats.queued.fetch_sub(frames, Ordering::Relaxed);
+                stats.dropped.fetch_add(frames, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for everything queued to be sent, then stop. True if every frame
+    /// was sent, none dropped or still waiting.
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let stats = self.remotes.stats();
+            if stats.iter().all(|stat| stat.queued.load(Ordering::Relaxed) == 0) {
+                return stats.iter().all(|stat| stat.dropped.load(Ordering::Relaxed) == 0);
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Self::FLUSH_POLL);
+        }
+    }
+
+    /// The remotes being sent to, as they change.
+    pub fn remotes(&self) -> Remotes {
+        self.remotes.clone()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some((closed, nudge)) = &self.srv {
+            closed.store(true, Ordering::SeqCst);
+            let _ = nudge.try_send(());
+        }
+        // Others may hold on to the remotes for their stats, but the threads should end now
+        for remote in self.remotes.0.write().unwrap().drain(..) {
+            remote.retired.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener};
+
+    use crate::coding::HELLO_MARK;
+    use crate::dns::SrvRecord;
+    use crate::observe::Protocol;
+    use crate::test_support::{state, unused_addr};
+
+    use super::*;
+
+    /// Everything a client writes to a collector while `send` has it send, once it's gone: the
+    /// ident, then every frame.
+    fn written<F: FnOnce(&Client)>(send: F) -> (String, Vec<Vec<u8>>) {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let mut config = ClientConfig::new("sensor".to_string());
+        config.add(listener.local_addr().unwrap());
+        let client = config.build().unwrap();
+        send(&client);
+        assert!(client.shutdown(Duration::from_secs(5)));
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut bytes = Vec::new();
+        sock.read_to_end(&mut bytes).unwrap();
+        let mut reader = &bytes[..];
+        let ident = String::decode(&mut reader).unwrap();
+        let mut frames = Vec::new();
+        while !reader.is_empty() {
+            frames.push(CodingVec::<u8, u32>::decode(&mut reader).unwrap().0);
+        }
+        (ident, frames)
+    }
+
+    fn messages(count: u16) -> Vec<Message> {
+        (0 .. count).map(|port| Message::Starting(state(&format!("10.0.0.1:{}", 40000 + port), "10.0.0.2:443", Protocol::Tcp))).collect()
+    }
+
+    fn encoded<C: Coder>(object: &C) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        object.encode(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn frames_are_numbered_once_per_connection() {
+        let messages = messages(3);
+        let (ident, frames) = written(|client| {
+            client.send(&messages[0]);
+            client.send_bundle(&messages[1 ..]);
+        });
+        assert_eq!(ident, "sensor");
+        assert_eq!(Hello::decode(&mut &frames[0][..]).unwrap().agent, Hello::AGENT);
+        // Numbered from the start, and following on from there without saying so again
+        assert_eq!(frames[1], encoded(&Sequence { next: 0 }));
+        assert_eq!(frames[2 ..], messages.iter().map(encoded).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_bundle_is_byte_for_byte_its_messages_sent_one_by_one() {
+        let messages = messages(10);
+        let one_by_one = written(|client| for message in messages.iter() {
+            client.send(message);
+        });
+        let bundled = written(|client| {
+            client.send_bundle(&messages[.. 3]);
+            // Nothing to send, and nothing numbered
+            client.send_bundle(&[]);
+            client.send_bundle(&messages[3 .. 4]);
+            client.send_bundle(&messages[4 ..]);
+        });
+        assert_eq!(bundled, one_by_one);
+        // The hello, where the numbering starts, then every message in order
+        let (_, frames) = bundled;
+        assert_eq!(frames.len(), 2 + messages.len());
+        let decoded: Vec<Message> = frames[2 ..].iter().map(|frame| Message::decode(&mut &frame[..]).unwrap()).collect();
+        assert_eq!(decoded, messages);
+    }
+
+    fn hello(tags: &[(&str, &str)]) -> Hello {
+        Hello {
+            agent: Hello::AGENT.to_string(),
+            keepalive: Some(30),
+            tags: tags.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    #[test]
+    fn tags_are_key_equals_value() {
+        assert_eq!(Hello::parse_tag("site=ams1"), Ok(("site".to_string(), "ams1".to_string())));
+        assert_eq!(Hello::parse_tag("rack.row_2-b="), Ok(("rack.row_2-b".to_string(), String::new())));
+        // Only the first = splits
+        assert_eq!(Hello::parse_tag("query=a=b"), Ok(("query".to_string(), "a=b".to_string())));
+        assert_eq!(Hello::parse_tag("env=pre prod"), Ok(("env".to_string(), "pre prod".to_string())));
+        assert!(Hello::parse_tag("site").unwrap_err().contains("isn't key=value"));
+    }
+
+    #[test]
+    fn bad_tags_are_rejected() {
+        let long_key = "k".repeat(Hello::MAX_TAG_KEY + 1);
+        let long_value = "v".repeat(4 * Hello::MAX_TAG_KEY + 1);
+        for (key, value, complaint) in [
+            ("", "ams1", "must be 1 to"),
+            (&long_key[..], "ams1", "must be 1 to"),
+            ("si te", "ams1", "may only have"),
+            ("site/1", "ams1", "may only have"),
+            ("sïte", "ams1", "may only have"),
+            ("site", &long_value[..], "has a value over"),
+            ("site", "ams\n1", "control characters"),
+            ("site", "ams\u{7f}", "control characters"),
+        ] {
+            let e = Hello::check_tag(key, value).unwrap_err();
+            assert!(e.contains(complaint), "{:?}={:?}: {}", key, value, e);
+        }
+        assert_eq!(Hello::check_tag(&"k".repeat(Hello::MAX_TAG_KEY), &"v".repeat(4 * Hello::MAX_TAG_KEY)), Ok(()));
+    }
+
+    #[test]
+    fn hellos_round_trip_with_tags_or_without() {
+        for hello in [hello(&[]), hello(&[("site", "ams1"), ("env", "prod")])] {
+            let bytes = encoded(&hello);
+            let mut reader = &bytes[..];
+            assert_eq!(Hello::decode(&mut reader).unwrap(), hello);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn hellos_from_before_tags_have_none() {
+        // The mark, the agent and the keepalive, and nothing after
+        let mut bytes = vec![HELLO_MARK];
+        "glosco/0.1.0".to_string().encode(&mut bytes).unwrap();
+        Some(30u32).encode(&mut bytes).unwrap();
+        let decoded = Hello::decode(&mut &bytes[..]).unwrap();
+        assert_eq!(decoded, Hello { agent: "glosco/0.1.0".to_string(), keepalive: Some(30), tags: BTreeMap::new() });
+    }
+
+    /// What a `FakeSrv` answers: records, or `None` for the lookup failing.
+    type Answer = Arc<Mutex<Option<Vec<SrvRecord>>>>;
+
+    /// Answers every lookup with whatever its `Answer` holds at the time, and counts them.
+    struct FakeSrv {
+        answer: Answer,
+        lookups: Arc<AtomicU64>,
+    }
+
+    impl SrvLookup for FakeSrv {
+        fn srv(&mut self, name: &str) -> io::Result<Vec<SrvRecord>> {
+            assert_eq!(name, "_glosco._tcp.example.com");
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.answer.lock().unwrap().clone().ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+        }
+    }
+
+    fn record(priority: u16, weight: u16, target: &str, port: u16, ttl: u64) -> SrvRecord {
+        SrvRecord { priority, weight, port, target: target.to_string(), ttl: Duration::from_secs(ttl) }
+    }
+
+    fn listener() -> (TcpListener, u16) {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    fn local(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /// A client finding its remotes through a `FakeSrv` answering `answer`, sending to
+    /// `fallback` when it can't, and trusting what it finds for no less than `min_ttl`.
+    fn srv_client(answer: Option<Vec<SrvRecord>>, fallback: &[SocketAddr], min_ttl: Duration) -> (Client, Answer, Arc<AtomicU64>) {
+        let answer = Arc::new(Mutex::new(answer));
+        let lookups: Arc<AtomicU64> = Arc::default();
+        let mut config = ClientConfig::new("sensor".to_string());
+        for addr in fallback {
+            config.add(*addr);
+        }
+        config.set_srv("_glosco._tcp.example.com".to_string(), Box::new(FakeSrv { answer: answer.clone(), lookups: lookups.clone() }));
+        let srv = config.srv.as_mut().unwrap();
+        srv.min_ttl = min_ttl;
+        srv.retry = min_ttl;
+        (config.build().unwrap(), answer, lookups)
+    }
+
+    fn sending_to(client: &Client) -> Vec<SocketAddr> {
+        client.remotes().stats().iter().map(|stats| stats.addr).collect()
+    }
+
+    fn until<F: FnMut() -> bool>(mut cond: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if cond() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
         }
+        false
+    }
+
+    #[test]
+    fn the_best_priority_is_sent_to_heaviest_first() {
+        let ((_a, a), (_b, b), (_c, c)) = (listener(), listener(), listener());
+        let (client, _, _) = srv_client(Some(vec![
+            record(10, 5, "127.0.0.1", a, 300),
+            record(20, 100, "127.0.0.1", c, 300),
+            record(10, 50, "127.0.0.1", b, 300),
+            // Not offered there, however good its priority
+            record(1, 100, ".", c, 300),
+        ]), &[], ClientConfig::SRV_MIN_TTL);
+        assert!(until(|| !sending_to(&client).is_empty()));
+        assert_eq!(sending_to(&client), [local(b), local(a)]);
+    }
+
+    #[test]
+    fn records_are_looked_up_again_as_their_ttl_runs_out() {
+        let ((_a, a), (_b, b)) = (listener(), listener());
+        let (client, answer, lookups) = srv_client(Some(vec![record(10, 0, "127.0.0.1", a, 0)]), &[], Duration::from_millis(200));
+        assert!(until(|| sending_to(&client) == [local(a)]));
+        let first = client.remotes().stats()[0].clone();
+        assert!(until(|| first.connected.load(Ordering::Relaxed)));
+
+        // The collector moves: the next lookup sends to the new one only
+        *answer.lock().unwrap() = Some(vec![record(10, 0, "127.0.0.1", b, 0)]);
+        assert!(until(|| sending_to(&client) == [local(b)]), "still sending to {:?}", sending_to(&client));
+        assert!(lookups.load(Ordering::SeqCst) >= 2);
+        // A set that hasn't changed keeps the remotes it has, connections and all
+        let second = client.remotes().stats()[0].clone();
+        assert!(until(|| second.connected.load(Ordering::Relaxed)));
+        let looked = lookups.load(Ordering::SeqCst);
+        assert!(until(|| lookups.load(Ordering::SeqCst) > looked + 1));
+        assert!(Arc::ptr_eq(&client.remotes().stats()[0], &second));
+        assert_eq!(second.connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn remotes_added_are_sent_to_until_records_turn_up() {
+        let ((_a, a), (_b, b)) = (listener(), listener());
+        let (client, answer, _) = srv_client(None, &[local(a)], Duration::from_millis(200));
+        assert!(until(|| sending_to(&client) == [local(a)]));
+
+        // Records saying there's no such service are as good as none
+        *answer.lock().unwrap() = Some(vec![record(10, 0, ".", b, 0)]);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(sending_to(&client), [local(a)]);
+
+        *answer.lock().unwrap() = Some(vec![record(10, 0, "127.0.0.1", b, 0)]);
+        assert!(until(|| sending_to(&client) == [local(b)]));
+        // And back again once they're gone
+        *answer.lock().unwrap() = Some(Vec::new());
+        assert!(until(|| sending_to(&client) == [local(a)]));
+    }
+
+    #[test]
+    fn a_remote_that_cant_be_reached_has_the_records_looked_up_again() {
+        let (_b, b) = listener();
+        let gone = unused_addr();
+        // Trusted for an hour, were it not for the remote being unreachable
+        let (client, answer, lookups) = srv_client(Some(vec![record(10, 0, "127.0.0.1", gone.port(), 3600)]), &[], Duration::from_millis(200));
+        assert!(until(|| sending_to(&client) == [gone]));
+        *answer.lock().unwrap() = Some(vec![record(10, 0, "127.0.0.1", b, 3600)]);
+        assert!(until(|| sending_to(&client) == [local(b)]), "still sending to {:?}", sending_to(&client));
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
     }
 }
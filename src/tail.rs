@@ -0,0 +1,542 @@
+use std::net::{IpAddr, ToSocketAddrs};
+#[cfg(feature = "sqlite")]
+use std::{collections::HashMap, path::Path, sync::mpsc, thread, time::{Duration, Instant}};
+
+#[cfg(feature = "sqlite")]
+use serde::Serialize;
+
+use crate::alert::Kind;
+use crate::cli::TailArgs;
+use crate::filter::{Cidr, Glob};
+use crate::observe::Protocol;
+use crate::subscribe::{Subscribe, Subscription};
+#[cfg(feature = "sqlite")]
+use crate::{changes::{Changes, Op, RowChange}, coding::{ACTIVE_MARK, ENDED_MARK, FAILED_MARK, RESET_MARK, START_MARK}, db, partition, query::protocol_name, timefmt};
+
+/// Which messages get printed, whichever end they're followed from.
+#[derive(Debug, Clone)]
+struct Filter {
+    idents: Vec<Glob>,
+    kinds: Vec<Kind>,
+    host: Option<Cidr>,
+    port: Option<u16>,
+}
+
+impl Filter {
+    fn matches(&self, ident: &str, kind: Kind, ends: [(IpAddr, u16); 2]) -> bool {
+        (self.idents.is_empty() || self.idents.iter().any(|glob| glob.matches(ident)))
+            && (self.kinds.is_empty() || self.kinds.contains(&kind))
+            && self.host.is_none_or(|cidr| ends.iter().any(|(addr, _)| cidr.contains(addr)))
+            && self.port.is_none_or(|port| ends.iter().any(|&(_, p)| p == port))
+    }
+}
+
+/// One message as `--text` prints it.
+fn line(time: &str, ident: &str, kind: Kind, proto: &str, src: (IpAddr, u16), dst: (IpAddr, u16)) -> String {
+    format!("{} {} {} {} {}:{} -> {}:{}", time, ident, kind, proto, src.0, src.1, dst.0, dst.1)
+}
+
+/// Entry point for `glosco tail`: print each message as it arrives at a collector, or as it's
+/// stored in a collector's database, until interrupted.
+pub fn run(args: TailArgs) {
+    let filter = filter(&args);
+    match (&args.remote, &args.database) {
+        (Some(remote), _) => follow_remote(remote, &filter, args.text),
+        #[cfg(feature = "sqlite")]
+        (None, Some(database)) => follow_database(database, &filter, args.text, Wake::Every(Duration::from_secs_f64(args.interval))),
+        #[cfg(not(feature = "sqlite"))]
+        (None, Some(_)) => panic!("following a database needs the sqlite feature"),
+        (None, None) => unreachable!("clap requires a source"),
+    }
+}
+
+/// Follow `args.database` like `run`, for a collector running in this process that reports
+/// what it writes to `changes` (see `server::start_with`): each poll waits for it to announce
+/// newly stored state rather than for `--interval` to pass. Returns once the collector's done.
+#[cfg(feature = "sqlite")]
+pub fn run_with_changes(args: TailArgs, changes: &Changes) {
+    let database = args.database.as_deref().expect("following changes needs the collector's database");
+    follow_database(database, &filter(&args), args.text, Wake::Changes(changes.subscribe()));
+}
+
+fn filter(args: &TailArgs) -> Filter {
+    Filter {
+        idents: args.ident.clone(),
+        kinds: args.kind.clone(),
+        host: args.host,
+        port: args.port,
+    }
+}
+
+/// Subscribe to a collector and print what it accepts. Idents and kinds are filtered by the
+/// collector; host and port here.
+fn follow_remote(remote: &str, filter: &Filter, text: bool) {
+    let addr = remote.to_socket_addrs()
+        .expect("failed to resolve remote")
+        .next()
+        .expect("remote resolved to no addresses");
+    let ident = gethostname::gethostname().into_string().expect("couldn't encode hostname");
+    let subscribe = Subscribe {
+        idents: filter.idents.clone(),
+        kinds: filter.kinds.clone(),
+        since: None,
+    };
+    let subscription = Subscription::connect(addr, &ident, &subscribe).expect("failed to subscribe");
+    for envelope in subscription {
+        let envelope = envelope.expect("failed to read from collector");
+        let kind = Kind::of(&envelope.message);
+        let conn = envelope.message.state().connection;
+        let (src, dst) = ((conn.src.addr, conn.src.port), (conn.dst.addr, conn.dst.port));
+        if !filter.matches(&envelope.ident, kind, [src, dst]) {
+            continue;
+        }
+        if text {
+            let proto = match conn.protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            };
+            let time = envelope.message.state().as_of.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map(|since| crate::timefmt::rfc3339(since.as_secs_f64()))
+                .unwrap_or_default();
+            println!("{}", line(&time, &envelope.ident, kind, proto, src, dst));
+        } else {
+            println!("{}", serde_json::to_string(&envelope).expect("failed to encode message"));
+        }
+    }
+}
+
+/// A stored row as `tail` prints it.
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Serialize)]
+struct Row {
+    instime: f64,
+    conntime: f64,
+    ident: String,
+    peer: String,
+    kind: String,
+    srchost: String,
+    srcport: u16,
+    dsthost: String,
+    dstport: u16,
+    proto: &'static str,
+    pkind: Option<u8>,
+    pcode: Option<u8>,
+}
+
+/// The kind of message a state row was stored from.
+#[cfg(feature = "sqlite")]
+fn kind_of(state: u8, close: Option<u8>) -> Kind {
+    match (state, close) {
+        (_, Some(RESET_MARK)) => Kind::Reset,
+        (_, Some(_)) | (ENDED_MARK, _) => Kind::Ended,
+        (START_MARK, _) => Kind::Starting,
+        (ACTIVE_MARK, _) => Kind::Active,
+        (FAILED_MARK, _) => Kind::Failed,
+        _ => Kind::Active,
+    }
+}
+
+/// Rows of state table `table` stored after rowid `after`, oldest first, with their rowids.
+#[cfg(feature = "sqlite")]
+fn poll(db: &rusqlite::Connection, table: &str, after: i64) -> rusqlite::Result<Vec<(i64, Row, Kind)>> {
+    let mut stmt = db.prepare_cached(&format!("
+        SELECT rowid, instime, conntime, ident, peer, state, close, srchost, srcport, dsthost, dstport, proto, pkind, pcode
+        FROM {}
+        WHERE rowid > ?
+        ORDER BY rowid;
+    ", table))?;
+    let rows = stmt.query_map([after], |row| {
+        let kind = kind_of(row.get(5)?, row.get(6)?);
+        Ok((row.get(0)?, Row {
+            instime: row.get(1)?,
+            conntime: row.get(2)?,
+            ident: row.get(3)?,
+            peer: row.get(4)?,
+            kind: kind.to_string(),
+            srchost: row.get(7)?,
+            srcport: row.get(8)?,
+            dsthost: row.get(9)?,
+            dstport: row.get(10)?,
+            proto: protocol_name(row.get(11)?),
+            pkind: row.get(12)?,
+            pcode: row.get(13)?,
+        }, kind))
+    })?;
+    rows.collect()
+}
+
+/// The tables state rows are stored in, oldest first, with the newest rowid in each.
+#[cfg(feature = "sqlite")]
+fn newest_rowids(db: &rusqlite::Connection) -> rusqlite::Result<Vec<(String, i64)>> {
+    let tables = if partition::is_partitioned(db)? {
+        partition::tables(db)?
+    } else {
+        vec!["state".to_string()]
+    };
+    tables.into_iter().map(|table| {
+        let newest = db.query_row(&format!("SELECT coalesce(max(rowid), 0) FROM {};", table), [], |row| row.get(0))?;
+        Ok((table, newest))
+    }).collect()
+}
+
+/// What identifies the file at `path`, so a database renamed into place (after a rotation or
+/// `VACUUM INTO`) is noticed and reopened.
+#[cfg(all(feature = "sqlite", unix))]
+fn identity(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|meta| meta.ino())
+}
+
+#[cfg(all(feature = "sqlite", not(unix)))]
+fn identity(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Follows a collector database, by the newest rowid it has printed from each state table.
+///
+/// A row gets its rowid when its transaction takes the write lock, so rows turn up in rowid
+/// order however late they commit compared to their `instime`, and nothing is printed twice.
+/// Expiry and eviction only delete the oldest rows, which leaves the marks alone. A database
+/// replaced by a copy of itself (made with `VACUUM INTO`, say) carries on from the same marks; a
+/// table whose rows were renumbered under it is picked up again from its newest row.
+#[cfg(feature = "sqlite")]
+struct Follower<'a> {
+    path: &'a Path,
+    filter: &'a Filter,
+    text: bool,
+    db: Option<(rusqlite::Connection, Option<u64>)>,
+    /// The newest rowid seen in each state table; `None` until the database has been read once.
+    marks: Option<HashMap<String, i64>>,
+    failing: bool,
+}
+
+#[cfg(feature = "sqlite")]
+impl<'a> Follower<'a> {
+    fn new(path: &'a Path, filter: &'a Filter, text: bool) -> Self {
+        Self { path, filter, text, db: None, marks: None, failing: false }
+    }
+
+    /// Lines for what was stored since the last call. The first call only takes note of what's
+    /// there already.
+    fn poll(&mut self) -> Vec<String> {
+        if self.db.as_ref().is_some_and(|(_, opened)| *opened != identity(self.path)) {
+            println!("{} was replaced, reopening it", self.path.display());
+            self.db = None;
+        }
+        if self.db.is_none() {
+            match db::open_read_only(self.path) {
+                Ok(opened) => self.db = Some((opened, identity(self.path))),
+                Err(e) => {
+                    self.fail("open", e);
+                    return Vec::new();
+                },
+            }
+        }
+        match self.read() {
+            Ok(lines) => {
+                self.failing = false;
+                lines
+            },
+            Err(e) => {
+                self.fail("read", e);
+                self.db = None;
+                Vec::new()
+            },
+        }
+    }
+
+    /// Whether every table in `newest` has been read up to the rowid it gives.
+    fn caught_up(&self, newest: &HashMap<String, i64>) -> bool {
+        self.marks.as_ref().is_some_and(|marks| {
+            newest.iter().all(|(table, rowid)| marks.get(table).is_some_and(|mark| mark >= rowid))
+        })
+    }
+
+    fn fail(&mut self, what: &str, e: rusqlite::Error) {
+        if !self.failing {
+            println!("couldn't {} {}, retrying: {:?}", what, self.path.display(), e);
+            self.failing = true;
+        }
+    }
+
+    fn read(&mut self) -> rusqlite::Result<Vec<String>> {
+        let db = &self.db.as_ref().expect("just opened").0;
+        let newest = newest_rowids(db)?;
+        let Some(marks) = &mut self.marks else {
+            self.marks = Some(newest.into_iter().collect());
+            return Ok(Vec::new());
+        };
+        // Day tables dropped by expiry or eviction are done with
+        marks.retain(|table, _| newest.iter().any(|(name, _)| name == table));
+        let mut lines = Vec::new();
+        for (table, newest) in newest {
+            // A day table that's new since the last poll is read from its first row
+            let mark = marks.entry(table.clone()).or_insert(0);
+            if newest < *mark {
+                println!("{} in {} was renumbered, following it from its newest row", table, self.path.display());
+                *mark = newest;
+                continue;
+            }
+            for (rowid, row, kind) in poll(db, &table, *mark)? {
+                *mark = rowid;
+                let ends = [
+                    (row.srchost.parse().unwrap_or(IpAddr::from([0, 0, 0, 0])), row.srcport),
+                    (row.dsthost.parse().unwrap_or(IpAddr::from([0, 0, 0, 0])), row.dstport),
+                ];
+                if !self.filter.matches(&row.ident, kind, ends) {
+                    continue;
+                }
+                lines.push(if self.text {
+                    line(&timefmt::rfc3339(row.instime), &row.ident, kind, row.proto, ends[0], ends[1])
+                } else {
+                    let mut value = serde_json::to_value(&row).expect("failed to encode row");
+                    timefmt::readable(&mut value);
+                    value.to_string()
+                });
+            }
+        }
+        Ok(lines)
+    }
+}
+
+/// What `follow_database` waits on between polls.
+#[cfg(feature = "sqlite")]
+enum Wake {
+    Every(Duration),
+    Changes(mpsc::Receiver<RowChange>),
+}
+
+/// How long to keep polling for rows `changes` announced before giving up on them; the feed
+/// announces a row as its transaction commits, a moment before other connections can read it.
+#[cfg(feature = "sqlite")]
+const CATCH_UP: Duration = Duration::from_secs(1);
+
+/// Wait for `changes` to announce stored state, and give the newest rowid announced in each
+/// table; `None` once the collector has gone.
+#[cfg(feature = "sqlite")]
+fn announced(changes: &mpsc::Receiver<RowChange>) -> Option<HashMap<String, i64>> {
+    fn note(newest: &mut HashMap<String, i64>, change: RowChange) {
+        if change.op == Op::Insert && partition::is_state_table(&change.table) {
+            let rowid = newest.entry(change.table).or_insert(change.rowid);
+            *rowid = change.rowid.max(*rowid);
+        }
+    }
+    let mut newest = HashMap::new();
+    while newest.is_empty() {
+        note(&mut newest, changes.recv().ok()?);
+    }
+    while let Ok(change) = changes.try_recv() {
+        note(&mut newest, change);
+    }
+    Some(newest)
+}
+
+/// Print newly stored rows from a collector database, starting from whatever was stored last
+/// when it starts, polling it as `wake` says. The database being replaced, vacuumed or briefly
+/// missing just means reopening it on a later poll.
+#[cfg(feature = "sqlite")]
+fn follow_database(database: &str, filter: &Filter, text: bool, wake: Wake) {
+    let mut follower = Follower::new(Path::new(database), filter, text);
+    let print = |follower: &mut Follower| for line in follower.poll() {
+        println!("{}", line);
+    };
+    print(&mut follower);
+    loop {
+        match &wake {
+            Wake::Every(interval) => {
+                thread::sleep(*interval);
+                print(&mut follower);
+            },
+            Wake::Changes(changes) => {
+                let Some(announced) = announced(changes) else {
+                    return;
+                };
+                let started = Instant::now();
+                loop {
+                    print(&mut follower);
+                    if follower.caught_up(&announced) || started.elapsed() > CATCH_UP {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::{fs, sync::Arc, time::SystemTime};
+
+    use crate::{server, test_support::Scratch};
+
+    use super::*;
+
+    /// A database of the test's own, migrated.
+    fn migrated(name: &str) -> Scratch {
+        let scratch = Scratch::new(name);
+        server::migrate(&mut db::open(scratch.path()).unwrap());
+        scratch
+    }
+
+    fn everything() -> Filter {
+        Filter { idents: Vec::new(), kinds: Vec::new(), host: None, port: None }
+    }
+
+    fn now() -> f64 {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64()
+    }
+
+    /// Store an Active row for the connection from port `srcport`, stamped `instime`.
+    fn store(db: &rusqlite::Connection, srcport: u16, instime: f64) {
+        db::retry(|| db.execute("
+            INSERT INTO state (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state)
+            VALUES (?, ?, 'sensor', '127.0.0.1:40000', '10.0.0.1', ?, '10.0.0.2', 443, 6, ?);
+        ", rusqlite::params![instime, instime, srcport, ACTIVE_MARK])).unwrap();
+    }
+
+    /// The source ports of the rows behind `lines`, in order.
+    fn ports(lines: &[String]) -> Vec<u16> {
+        lines.iter().map(|line| {
+            let row: serde_json::Value = serde_json::from_str(line).unwrap();
+            row["srcport"].as_u64().unwrap() as u16
+        }).collect()
+    }
+
+    #[test]
+    fn rows_come_out_once_each_in_the_order_they_were_stored() {
+        let scratch = migrated("ordered");
+        let db = db::open(scratch.path()).unwrap();
+        for port in 1 ..= 10 {
+            store(&db, port, now());
+        }
+        let filter = everything();
+        let mut follower = Follower::new(scratch.path(), &filter, false);
+        assert_eq!(follower.poll(), Vec::<String>::new(), "what was stored before is skipped");
+
+        // Every other row is stamped as if its writer had waited a minute for the lock, long
+        // after rows stamped later than it had committed
+        let writer = {
+            let path = scratch.path().to_path_buf();
+            thread::spawn(move || {
+                let db = db::open(path).unwrap();
+                for port in 100 .. 400 {
+                    let late = if port % 2 == 0 { 60.0 } else { 0.0 };
+                    store(&db, port, now() - late);
+                }
+            })
+        };
+        let mut seen = Vec::new();
+        let started = Instant::now();
+        while seen.len() < 300 && started.elapsed() < Duration::from_secs(30) {
+            seen.extend(ports(&follower.poll()));
+            thread::sleep(Duration::from_millis(5));
+        }
+        writer.join().unwrap();
+        seen.extend(ports(&follower.poll()));
+        assert_eq!(seen, (100 .. 400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_change_feed_says_when_there_is_more_to_read() {
+        let scratch = migrated("feed");
+        let changes: Arc<Changes> = Arc::default();
+        let feed = changes.subscribe();
+        let filter = everything();
+        let mut follower = Follower::new(scratch.path(), &filter, false);
+        follower.poll();
+        let writer = {
+            let path = scratch.path().to_path_buf();
+            let changes = changes.clone();
+            thread::spawn(move || {
+                let db = db::open(path).unwrap();
+                changes.hook(&db);
+                for port in 1 ..= 200 {
+                    store(&db, port, now());
+                }
+            })
+        };
+        let mut seen = Vec::new();
+        while seen.len() < 200 {
+            let announced = announced(&feed).expect("the writer is still going");
+            let started = Instant::now();
+            while !follower.caught_up(&announced) {
+                assert!(started.elapsed() < CATCH_UP, "announced rows never turned up");
+                seen.extend(ports(&follower.poll()));
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(seen, (1 ..= 200).collect::<Vec<_>>());
+        drop(changes);
+        // Rows can be read before the feed's been drained of them
+        while let Some(announced) = announced(&feed) {
+            assert!(follower.caught_up(&announced), "rows were announced after the writer stopped");
+        }
+    }
+
+    #[test]
+    fn the_filter_applies_to_what_is_followed() {
+        let scratch = migrated("filtered");
+        let db = db::open(scratch.path()).unwrap();
+        let filter = Filter { port: Some(2), ..everything() };
+        let mut follower = Follower::new(scratch.path(), &filter, true);
+        follower.poll();
+        for port in 1 ..= 3 {
+            store(&db, port, 1_749_686_400.0);
+        }
+        assert_eq!(follower.poll(), ["2025-06-12T00:00:00.000000Z sensor active tcp 10.0.0.1:2 -> 10.0.0.2:443"]);
+    }
+
+    #[test]
+    fn following_carries_on_through_the_database_being_replaced() {
+        let scratch = migrated("replaced");
+        let filter = everything();
+        let mut follower = Follower::new(scratch.path(), &filter, false);
+        follower.poll();
+        {
+            let db = db::open(scratch.path()).unwrap();
+            store(&db, 1, now());
+            store(&db, 2, now());
+        }
+        assert_eq!(ports(&follower.poll()), [1, 2]);
+
+        // Rotated the way an operator would: a compacted copy renamed over the original
+        let mut copy = scratch.path().to_path_buf().into_os_string();
+        copy.push(".copy");
+        {
+            let db = db::open(scratch.path()).unwrap();
+            db.execute("VACUUM INTO ?;", [copy.to_str().unwrap()]).unwrap();
+        }
+        fs::rename(&copy, scratch.path()).unwrap();
+        for suffix in ["-wal", "-shm"] {
+            let mut stale = scratch.path().to_path_buf().into_os_string();
+            stale.push(suffix);
+            let _ = fs::remove_file(stale);
+        }
+        {
+            let db = db::open(scratch.path()).unwrap();
+            store(&db, 3, now());
+            store(&db, 4, now());
+        }
+        assert_eq!(ports(&follower.poll()), [3, 4]);
+        assert_eq!(follower.poll(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn new_day_tables_are_followed_from_their_first_row() {
+        let scratch = migrated("partitioned");
+        let mut db = db::open(scratch.path()).unwrap();
+        let partitions = partition::Partitions::open(&mut db, true, now()).unwrap();
+        let filter = everything();
+        let mut follower = Follower::new(scratch.path(), &filter, false);
+        follower.poll();
+        let tomorrow = now() + 86400.0;
+        for (port, instime) in [(1, now()), (2, tomorrow), (3, tomorrow)] {
+            db.execute(&format!("
+                INSERT INTO {} (instime, conntime, ident, peer, srchost, srcport, dsthost, dstport, proto, state)
+                VALUES (?, ?, 'sensor', '127.0.0.1:40000', '10.0.0.1', ?, '10.0.0.2', 443, 6, ?);
+            ", partitions.table(&db, instime).unwrap()), rusqlite::params![instime, instime, port, ACTIVE_MARK]).unwrap();
+        }
+        assert_eq!(ports(&follower.poll()), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,539 @@
+//! An in-process collector on a scratch database, and a client that speaks the wire protocol
+//! frame by frame, for exercising glosco end to end from tests.
+
+use std::{fs, io::{self, Read, Write}, net::{IpAddr, SocketAddr, SocketAddrV4, TcpListener, TcpStream}, path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}, thread, time::{Duration, Instant, SystemTime}};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::{coding::{Coder, CodingVec}, db, observe::{Connection, Endpoint, Protocol, State}, server::{self, EventLogSettings, Reload, ServerHandle, ServerSettings}, sync::Hello};
+
+/// Tells apart the scratch directories of servers spawned by one process.
+static NEXT_SERVER: AtomicU64 = AtomicU64::new(0);
+
+/// A collector on an ephemeral port with a database in a scratch directory of its own, shut
+/// down and cleaned up when dropped.
+#[derive(Debug)]
+pub struct TestServer {
+    handle: Option<ServerHandle>,
+    dir: PathBuf,
+    database: PathBuf,
+    events: Option<PathBuf>,
+}
+
+impl TestServer {
+    /// How often `wait_for_rows` and `logged` look again.
+    const POLL: Duration = Duration::from_millis(20);
+
+    /// Start a collector with the default settings.
+    pub fn spawn() -> Self {
+        Self::spawn_with(|_| ())
+    }
+
+    /// Start a collector with the default settings as `adjust` leaves them. The bind address
+    /// and database are filled in before `adjust` sees them; changing them is on the caller,
+    /// though `db` opens whichever database `adjust` leaves, `:memory:` included.
+    pub fn spawn_with<F: FnOnce(&mut ServerSettings)>(adjust: F) -> Self {
+        Self::launch(adjust, None)
+    }
+
+    /// Start a collector like `spawn_with` that calls `reload` for new settings on SIGHUP.
+    pub fn spawn_reloading<F: FnOnce(&mut ServerSettings)>(adjust: F, reload: Reload) -> Self {
+        Self::launch(adjust, Some(reload))
+    }
+
+    fn launch<F: FnOnce(&mut ServerSettings)>(adjust: F, reload: Option<Reload>) -> Self {
+        let dir = std::env::temp_dir().join(format!("glosco-test-{}-{}", std::process::id(), NEXT_SERVER.fetch_add(1, Ordering::SeqCst)));
+        // Left over from an earlier process that had the same pid
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("failed to create scratch directory");
+        let mut settings = ServerSettings {
+            // The port's taken before `start_with` returns, so tests running at once can't collide
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            database: dir.join("glosco.db").to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        adjust(&mut settings);
+        let database = PathBuf::from(&settings.database);
+        let events = settings.event_log.as_ref().map(|log| log.path.clone());
+        let handle = server::start_with(settings, reload, Default::default()).expect("failed to start test server");
+        Self { handle: Some(handle), dir, database, events }
+    }
+
+    /// Start a collector with the default settings, logging events next to its database for
+    /// `logged` to read.
+    pub fn spawn_logging() -> Self {
+        Self::spawn_with(|settings| settings.event_log = Some(EventLogSettings {
+            path: Path::new(&settings.database).with_file_name("events.ndjson"),
+            max_bytes: None,
+            max_age: None,
+            gzip: false,
+        }))
+    }
+
+    /// Where clients should connect.
+    pub fn addr(&self) -> SocketAddr {
+        self.handle().local_addr()
+    }
+
+    /// The scratch directory the database is in, removed along with it; somewhere for a test's
+    /// own files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The running collector, for what the harness doesn't wrap, like `on_match`.
+    pub fn handle(&self) -> &ServerHandle {
+        self.handle.as_ref().expect("server is running")
+    }
+
+    pub fn database(&self) -> &Path {
+        &self.database
+    }
+
+    /// A fresh connection on the database, to look at what's been stored.
+    pub fn db(&self) -> rusqlite::Connection {
+        db::open(&self.database).expect("failed to open test database")
+    }
+
+    /// Connect a client claiming `ident`.
+    pub fn client(&self, ident: &str) -> TestClient {
+        TestClient::connect(self.addr(), ident).expect("failed to connect to test server")
+    }
+
+    /// Connect a client claiming `ident` from the local address `from`; see
+    /// `TestClient::connect_from`.
+    pub fn client_from(&self, from: IpAddr, ident: &str) -> TestClient {
+        TestClient::connect_from(self.addr(), from, ident).expect("failed to connect to test server")
+    }
+
+    /// Wait until `predicate` holds of the database, or `timeout` passes; true if it held. An
+    /// error from the predicate counts as not holding yet, since the schema or rows it wants
+    /// may still be on their way.
+    pub fn wait_for_rows<F: FnMut(&rusqlite::Connection) -> rusqlite::Result<bool>>(&self, mut predicate: F, timeout: Duration) -> bool {
+        let db = self.db();
+        let deadline = Instant::now() + timeout;
+        loop {
+            if predicate(&db).unwrap_or(false) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Self::POLL);
+        }
+    }
+
+    /// The event log's records, each parsed, once there are at least `count` or `timeout`
+    /// passes. Panics unless the settings asked for an event log.
+    pub fn logged(&self, count: usize, timeout: Duration) -> Vec<serde_json::Value> {
+        let log = self.events.as_ref().expect("no event log");
+        let deadline = Instant::now() + timeout;
+        loop {
+            let contents = fs::read_to_string(log).unwrap_or_default();
+            if contents.lines().count() >= count || Instant::now() >= deadline {
+                return contents.lines().map(|line| serde_json::from_str(line).expect("every line is a record")).collect();
+            }
+            thread::sleep(Self::POLL);
+        }
+    }
+
+    /// Wait until `sql`, a query for one count, gives at least `rows`; true if it did in time.
+    pub fn wait_for_count(&self, sql: &str, rows: i64, timeout: Duration) -> bool {
+        self.wait_for_rows(|db| Ok(db.query_row(sql, [], |row| row.get::<_, i64>(0))? >= rows), timeout)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+            handle.join();
+        }
+        // Worker threads may still hold the database open, which doesn't stop it going on Unix
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            println!("couldn't remove test directory {:?}: {:?}", self.dir, e);
+        }
+    }
+}
+
+/// A client that sends exactly what it's told, frame by frame, the way `sync::Client` would.
+#[derive(Debug)]
+pub struct TestClient {
+    stream: TcpStream,
+    ident: String,
+}
+
+impl TestClient {
+    /// Connect and claim `ident`. Nothing else is sent until asked for; a collector doesn't act
+    /// on the connection until its first frame, which `hello` makes the usual one.
+    pub fn connect(addr: SocketAddr, ident: &str) -> io::Result<Self> {
+        Self::claim(TcpStream::connect(addr)?, ident)
+    }
+
+    /// Connect from the local address `from`, like `127.0.0.2`, rather than whichever the
+    /// system picks, and claim `ident`: as a client on another host would look.
+    pub fn connect_from(addr: SocketAddr, from: IpAddr, ident: &str) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(socket2::Protocol::TCP))?;
+        socket.bind(&SocketAddr::new(from, 0).into())?;
+        socket.connect(&addr.into())?;
+        Self::claim(socket.into(), ident)
+    }
+
+    fn claim(mut stream: TcpStream, ident: &str) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let mut claim = Vec::new();
+        ident.to_string().encode(&mut claim)?;
+        stream.write_all(&claim)?;
+        Ok(Self { stream, ident: ident.to_string() })
+    }
+
+    pub fn ident(&self) -> &str {
+        &self.ident
+    }
+
+    /// Send the hello a real client opens with, announcing `keepalive` and no tags.
+    pub fn hello(&mut self, keepalive: Option<u32>) -> io::Result<()> {
+        self.send(&Hello { agent: Hello::AGENT.to_string(), keepalive, tags: Default::default() })
+    }
+
+    /// Send a message, snapshot, or anything else with a wire encoding, as one frame.
+    pub fn send<C: Coder>(&mut self, object: &C) -> io::Result<()> {
+        let mut body = Vec::new();
+        object.encode(&mut body)?;
+        self.send_frame(&body)
+    }
+
+    /// Send `body` as one frame, however malformed.
+    pub fn send_frame(&mut self, body: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(body.len() + 4);
+        CodingVec::<u8, u32>::new(body.to_vec()).encode(&mut frame)?;
+        self.stream.write_all(&frame)
+    }
+
+    /// Send bytes as they are, with no framing.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes)
+    }
+
+    /// Whether the collector hangs up on this client within `timeout`.
+    pub fn hung_up(&mut self, timeout: Duration) -> bool {
+        if self.stream.set_read_timeout(Some(timeout)).is_err() {
+            return false;
+        }
+        match self.stream.read(&mut [0u8; 1]) {
+            Ok(read) => read == 0,
+            Err(e) => e.kind() == io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    /// Hang up, as a client going away would.
+    pub fn close(self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// A connection from `src` to `dst`, both written `addr:port`, on the first interface, as of
+/// now; what most messages a test sends are about.
+pub fn state(src: &str, dst: &str, protocol: Protocol) -> State {
+    let endpoint = |addr: &str| {
+        let addr: SocketAddr = addr.parse().expect("endpoints are written addr:port");
+        Endpoint { addr: addr.ip(), port: addr.port() }
+    };
+    State {
+        as_of: SystemTime::now(),
+        connection: Connection { interface: 0, src: endpoint(src), dst: endpoint(dst), protocol },
+        rtt_micros: None,
+    }
+}
+
+/// TCP flags, for `tcp_frame`.
+pub const SYN: u8 = 0x02;
+pub const ACK: u8 = 0x10;
+pub const FIN: u8 = 0x01;
+pub const RST: u8 = 0x04;
+
+/// An IPv4 packet from `src` to `dst`, both written `addr:port`, carrying `protocol`'s header
+/// built by `transport` from the two ports, in an Ethernet frame.
+fn frame(src: &str, dst: &str, protocol: u8, transport: impl FnOnce(u16, u16) -> Vec<u8>) -> Vec<u8> {
+    let (src, dst): (SocketAddrV4, SocketAddrV4) = (src.parse().expect("IPv4 addr:port"), dst.parse().expect("IPv4 addr:port"));
+    let body = transport(src.port(), dst.port());
+    let mut frame = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00];
+    frame.extend_from_slice(&[0x45, 0x00]);
+    frame.extend_from_slice(&((20 + body.len()) as u16).to_be_bytes());
+    // Identification, flags and fragment offset, TTL, protocol, checksum
+    frame.extend_from_slice(&[0x00, 0x01, 0x40, 0x00, 0x40, protocol, 0x00, 0x00]);
+    frame.extend_from_slice(&src.ip().octets());
+    frame.extend_from_slice(&dst.ip().octets());
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// An Ethernet frame holding a TCP segment with `flags` and no payload.
+pub fn tcp_frame(src: &str, dst: &str, flags: u8) -> Vec<u8> {
+    frame(src, dst, 6, |src, dst| {
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&src.to_be_bytes());
+        segment.extend_from_slice(&dst.to_be_bytes());
+        // Sequence and acknowledgement numbers, data offset, flags, window, checksum, urgent
+        segment.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        segment
+    })
+}
+
+/// An Ethernet frame holding a UDP datagram carrying `payload`.
+pub fn udp_frame(src: &str, dst: &str, payload: &[u8]) -> Vec<u8> {
+    frame(src, dst, 17, |src, dst| {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&src.to_be_bytes());
+        datagram.extend_from_slice(&dst.to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        datagram
+    })
+}
+
+/// The header of a classic pcap file of Ethernet frames, with microsecond timestamps.
+pub fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&65535u32.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header
+}
+
+/// One frame of a pcap file after `pcap_header`, captured at `time`.
+pub fn pcap_record(time: SystemTime, frame: &[u8]) -> Vec<u8> {
+    let since = time.duration_since(SystemTime::UNIX_EPOCH).expect("captured after the epoch");
+    let mut record = Vec::new();
+    record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&since.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    record.extend_from_slice(frame);
+    record
+}
+
+/// Write a pcap file of `frames`, each with the time it was captured.
+pub fn write_pcap(path: &Path, frames: &[(SystemTime, Vec<u8>)]) -> io::Result<()> {
+    let mut file = pcap_header();
+    for (time, frame) in frames {
+        file.extend_from_slice(&pcap_record(*time, frame));
+    }
+    fs::write(path, file)
+}
+
+/// Write a MaxMind database of IPv4 `networks`, each a prefix like `"192.0.2.0/24"` with the
+/// country code and ASN a lookup inside it finds, standing in for GeoLite2-Country and
+/// GeoLite2-ASN in tests. Prefixes mustn't overlap.
+pub fn write_mmdb(path: &Path, networks: &[(&str, Option<&str>, Option<u32>)]) -> io::Result<()> {
+    fn control(kind: u8, size: usize, out: &mut Vec<u8>) {
+        assert!(size < 29, "too big for a fixture");
+        if kind <= 7 {
+            out.push(kind << 5 | size as u8);
+        } else {
+            out.extend_from_slice(&[size as u8, kind - 7]);
+        }
+    }
+    fn string(value: &str, out: &mut Vec<u8>) {
+        control(2, value.len(), out);
+        out.extend_from_slice(value.as_bytes());
+    }
+    fn uint(kind: u8, bytes: &[u8], out: &mut Vec<u8>) {
+        control(kind, bytes.len(), out);
+        out.extend_from_slice(bytes);
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+    let mut nodes = vec![[Record::Empty; 2]];
+    for (index, (network, _, _)) in networks.iter().enumerate() {
+        let (addr, len) = network.split_once('/').expect("a prefix with a length");
+        let addr = u32::from(addr.parse::<std::net::Ipv4Addr>().expect("an IPv4 prefix"));
+        let len: u32 = len.parse().expect("a prefix length");
+        assert!((1..=32).contains(&len), "a prefix length from 1 to 32");
+        let mut node = 0;
+        for bit in 0..len {
+            let side = (addr >> (31 - bit) & 1) as usize;
+            if bit == len - 1 {
+                nodes[node][side] = Record::Data(index);
+                break;
+            }
+            node = match nodes[node][side] {
+                Record::Node(next) => next,
+                Record::Empty => {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][side] = Record::Node(nodes.len() - 1);
+                    nodes.len() - 1
+                },
+                Record::Data(_) => panic!("overlapping prefixes"),
+            };
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+    for (_, country, asn) in networks {
+        offsets.push(data.len());
+        control(7, country.is_some() as usize + asn.is_some() as usize, &mut data);
+        if let Some(country) = country {
+            string("country", &mut data);
+            control(7, 1, &mut data);
+            string("iso_code", &mut data);
+            string(country, &mut data);
+        }
+        if let Some(asn) = asn {
+            string("autonomous_system_number", &mut data);
+            uint(6, &asn.to_be_bytes(), &mut data);
+        }
+    }
+
+    let count = nodes.len();
+    let mut file = Vec::new();
+    for node in &nodes {
+        for record in node {
+            let value = match *record {
+                Record::Empty => count,
+                Record::Node(next) => next,
+                Record::Data(index) => count + 16 + offsets[index],
+            };
+            file.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+        }
+    }
+    file.extend_from_slice(&[0; 16]);
+    file.extend_from_slice(&data);
+    file.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    control(7, 9, &mut file);
+    string("binary_format_major_version", &mut file);
+    uint(5, &2u16.to_be_bytes(), &mut file);
+    string("binary_format_minor_version", &mut file);
+    uint(5, &0u16.to_be_bytes(), &mut file);
+    string("build_epoch", &mut file);
+    uint(9, &0u64.to_be_bytes(), &mut file);
+    string("database_type", &mut file);
+    string("glosco-test", &mut file);
+    string("description", &mut file);
+    control(7, 0, &mut file);
+    string("ip_version", &mut file);
+    uint(5, &4u16.to_be_bytes(), &mut file);
+    string("languages", &mut file);
+    control(11, 0, &mut file);
+    string("node_count", &mut file);
+    uint(6, &(count as u32).to_be_bytes(), &mut file);
+    string("record_size", &mut file);
+    uint(5, &24u16.to_be_bytes(), &mut file);
+    fs::write(path, file)
+}
+
+/// A loopback address nothing was listening on a moment ago, for a listener that can't be
+/// asked which port it got, like the API's.
+pub fn unused_addr() -> SocketAddr {
+    TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).and_then(|listener| listener.local_addr())
+        .expect("failed to find an unused port")
+}
+
+/// Make an HTTP request of `addr` for `path`, with `headers` and `body`, returning the status
+/// and the body of the response.
+pub fn http(addr: SocketAddr, method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, addr, body.len());
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad response {:?}", response));
+    let status = response.split(' ').nth(1).and_then(|status| status.parse().ok()).ok_or_else(bad)?;
+    let (_, body) = response.split_once("\r\n\r\n").ok_or_else(bad)?;
+    Ok((status, body.to_string()))
+}
+
+/// A xorshift generator, so that a fixture comes out the same every run.
+#[derive(Debug)]
+pub struct Random(u64);
+
+impl Random {
+    /// Seeded with `seed`, which mustn't be 0: xorshift never leaves it.
+    pub fn new(seed: u64) -> Self {
+        assert_ne!(seed, 0, "a xorshift seed can't be 0");
+        Self(seed)
+    }
+
+    /// The next number below `n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// Tells apart the scratch paths of one process, so tests in different modules can use the
+/// same names.
+static NEXT_SCRATCH: AtomicU64 = AtomicU64::new(0);
+
+/// A path of a test's own in the temp directory, removed when dropped: a database with the
+/// journal, WAL and shared memory files SQLite keeps beside it, or a directory with everything
+/// in it.
+#[derive(Debug)]
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// A database path named after `name`, with nothing there yet.
+    pub fn new(name: &str) -> Self {
+        Self::file(&format!("{}.db", name))
+    }
+
+    /// A path for any other file, ending in `name`, with nothing there yet.
+    pub fn file(name: &str) -> Self {
+        let scratch = Self::unique(name);
+        scratch.remove();
+        scratch
+    }
+
+    /// A directory named after `name`, empty.
+    pub fn dir(name: &str) -> Self {
+        let scratch = Self::unique(name);
+        scratch.remove();
+        fs::create_dir_all(&scratch.0).expect("failed to create scratch directory");
+        scratch
+    }
+
+    fn unique(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("glosco-{}-{}-{}", std::process::id(), NEXT_SCRATCH.fetch_add(1, Ordering::SeqCst), name)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The path as a string, for what takes a database by name.
+    pub fn database(&self) -> &str {
+        self.0.to_str().expect("scratch path isn't UTF-8")
+    }
+
+    fn remove(&self) {
+        let _ = fs::remove_dir_all(&self.0);
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
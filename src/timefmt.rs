@@ -0,0 +1,118 @@
+use serde_json::Value;
+
+/// Fields of query results that hold times as seconds since the epoch, as opposed to spans of
+/// seconds (`age`, `duration`, `skew`, ...).
+pub const TIME_FIELDS: &[&str] = &[
+    "instime", "conntime", "connected", "first_seen", "last_seen", "hour", "start", "end", "generated",
+];
+
+/// The proleptic Gregorian (year, month, day) of a day counted from the epoch, after Howard
+/// Hinnant's `civil_from_days`.
+pub fn civil(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m as u32, d as u32)
+}
+
+/// Seconds since the epoch as RFC 3339 UTC, to the microsecond: `2025-06-12T14:12:14.220000Z`.
+pub fn rfc3339(secs: f64) -> String {
+    let micros = (secs * 1e6).round() as i64;
+    let (secs, micros) = (micros.div_euclid(1_000_000), micros.rem_euclid(1_000_000));
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (y, m, d) = civil(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z", y, m, d, secs / 3600, secs / 60 % 60, secs % 60, micros)
+}
+
+/// Rewrite every time field in a query result (an object, or arrays of them) as RFC 3339 text.
+pub fn readable(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(readable),
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                match field.as_f64() {
+                    Some(secs) if TIME_FIELDS.contains(&name.as_str()) => *field = Value::String(rfc3339(secs)),
+                    _ => readable(field),
+                }
+            }
+        },
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn every_day_to_2100_is_the_date_counting_gets_to() {
+        let leap = |y: i64| y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+        let length = |y: i64, m: u32| match m {
+            2 if leap(y) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        // Through 2000, a leap year for being a multiple of 400, and into 2100, which isn't
+        let (mut y, mut m, mut d) = (1970, 1, 1);
+        for days in 0 ..= 47541 {
+            assert_eq!(civil(days), (y, m, d), "{} days", days);
+            d += 1;
+            if d > length(y, m) {
+                (m, d) = (m + 1, 1);
+            }
+            if m > 12 {
+                (y, m) = (y + 1, 1);
+            }
+        }
+        assert_eq!(civil(47540), (2100, 2, 28));
+        assert_eq!(civil(47541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn times_are_utc_whatever_the_local_clocks_did() {
+        assert_eq!(rfc3339(0.0), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(rfc3339(1718201534.22), "2024-06-12T14:12:14.220000Z");
+        assert_eq!(rfc3339(951782400.0), "2000-02-29T00:00:00.000000Z");
+        assert_eq!(rfc3339(-1.0), "1969-12-31T23:59:59.000000Z");
+        // Either side of the hours Europe and the US moved their clocks in 2024: UTC carries on
+        // a second at a time
+        for (before, expected) in [
+            (1711846799.0, ["2024-03-31T00:59:59.000000Z", "2024-03-31T01:00:00.000000Z"]),
+            (1730613599.0, ["2024-11-03T05:59:59.000000Z", "2024-11-03T06:00:00.000000Z"]),
+        ] {
+            assert_eq!([rfc3339(before), rfc3339(before + 1.0)], expected);
+        }
+    }
+
+    #[test]
+    fn fractions_are_kept_to_the_microsecond_and_rounded_past_it() {
+        assert_eq!(rfc3339(1718201534.000001), "2024-06-12T14:12:14.000001Z");
+        assert_eq!(rfc3339(1718201534.999999), "2024-06-12T14:12:14.999999Z");
+        assert_eq!(rfc3339(1718201534.123456), "2024-06-12T14:12:14.123456Z");
+        // Half a microsecond short of the next second rounds up into it, minute and day and all
+        assert_eq!(rfc3339(1718236799.9999996), "2024-06-13T00:00:00.000000Z");
+        assert_eq!(rfc3339(-0.25), "1969-12-31T23:59:59.750000Z");
+    }
+
+    #[test]
+    fn only_time_fields_are_made_readable_however_deep() {
+        let mut value = json!([
+            {"instime": 0.5, "age": 12.5, "ident": "sensor", "port": 443},
+            {"sessions": [{"connected": 1718201534.22, "duration": 60.0}], "last_seen": null},
+        ]);
+        readable(&mut value);
+        assert_eq!(value, json!([
+            {"instime": "1970-01-01T00:00:00.500000Z", "age": 12.5, "ident": "sensor", "port": 443},
+            {"sessions": [{"connected": "2024-06-12T14:12:14.220000Z", "duration": 60.0}], "last_seen": null},
+        ]));
+    }
+}
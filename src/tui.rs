@@ -0,0 +1,15 @@
+//! `glosco client --tui`: what's observed, as a live table in the terminal. The table itself is
+//! always built; drawing it takes the `tui` feature.
+
+#[cfg(feature = "tui")]
+mod screen;
+mod table;
+
+#[cfg(feature = "tui")]
+pub use screen::run;
+pub use table::{Column, Row, Stats, Table};
+
+#[cfg(not(feature = "tui"))]
+pub fn run(_args: crate::cli::ClientArgs) {
+    panic!("--tui given, but glosco was built without the tui feature");
+}
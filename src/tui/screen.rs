@@ -0,0 +1,278 @@
+//! The table drawn in the terminal with ratatui, and the keys that sort and filter it.
+
+use std::{sync::mpsc, thread, time::{Duration, Instant, SystemTime}};
+
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::{layout::{Constraint, Layout}, style::{Color, Modifier, Style}, text::Line, widgets, Frame};
+
+use crate::{alert::Kind, cli::ClientArgs, client, observe::{Message, Protocol, Snapshot}, output::Format, timefmt};
+
+use super::table::{Column, Table};
+
+/// How often the screen is redrawn with nothing else happening.
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// What the filter box is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Browse,
+    Filter,
+}
+
+/// The table, and what the keys pressed so far have made of it.
+#[derive(Debug)]
+struct Screen {
+    table: Table,
+    mode: Mode,
+}
+
+impl Screen {
+    /// Do what `key` says; false once it says to quit.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+        match self.mode {
+            Mode::Browse => match key.code {
+                _ if ctrl_c => return false,
+                KeyCode::Char('q') => return false,
+                KeyCode::Char('s') | KeyCode::Tab => self.table.sort = self.table.sort.next(),
+                KeyCode::Char('r') => self.table.descending = !self.table.descending,
+                KeyCode::Char('/') => self.mode = Mode::Filter,
+                KeyCode::Char(digit @ '1' ..= '5') => self.table.sort_by(Column::ALL[digit as usize - '1' as usize]),
+                _ => (),
+            },
+            Mode::Filter => match key.code {
+                KeyCode::Enter => self.mode = Mode::Browse,
+                _ if ctrl_c => {
+                    self.table.filter.clear();
+                    self.mode = Mode::Browse;
+                },
+                KeyCode::Esc => {
+                    self.table.filter.clear();
+                    self.mode = Mode::Browse;
+                },
+                KeyCode::Backspace => {
+                    self.table.filter.pop();
+                },
+                KeyCode::Char(c) if !c.is_control() => self.table.filter.push(c),
+                _ => (),
+            },
+        }
+        true
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let table = &self.table;
+        let rows = table.rows();
+        let [title, filter, body, counts, keys] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+
+        let sort = format!("{} {}", table.sort.title(), if table.descending { "desc" } else { "asc" });
+        let title_text = format!("glosco: {} connections, {} shown, sorted by {}", table.len(), rows.len(), sort);
+        frame.render_widget(Line::styled(title_text, Style::new().add_modifier(Modifier::BOLD)), title);
+        let filter_text = match self.mode {
+            Mode::Filter => format!("filter: {}_", table.filter),
+            Mode::Browse if table.filter.is_empty() => "filter: (none, / to set)".to_string(),
+            Mode::Browse => format!("filter: {}", table.filter),
+        };
+        frame.render_widget(Line::raw(filter_text), filter);
+
+        let header = widgets::Row::new(["LAST SEEN", "PROTO", "SOURCE", "DESTINATION", "STATE", "NAMES"])
+            .style(Style::new().add_modifier(Modifier::REVERSED));
+        let lines = rows.iter().map(|row| {
+            let time = row.last_seen.duration_since(SystemTime::UNIX_EPOCH)
+                .map(|since| timefmt::rfc3339(since.as_secs_f64()))
+                .unwrap_or_default();
+            let proto = match row.connection.protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            };
+            widgets::Row::new([
+                time.get(11 .. 19).unwrap_or("").to_string(),
+                proto.to_string(),
+                row.connection.src.to_string(),
+                row.connection.dst.to_string(),
+                row.kind.to_string(),
+                row.names.join(" "),
+            ]).style(style(row.kind))
+        });
+        let widths = [
+            Constraint::Length(9),
+            Constraint::Length(5),
+            Constraint::Length(30),
+            Constraint::Length(30),
+            Constraint::Length(8),
+            Constraint::Fill(1),
+        ];
+        frame.render_widget(widgets::Table::new(lines, widths).header(header), body);
+
+        let stats = table.stats;
+        let counts_text = format!(
+            "{} messages: {} started, {} ended, {} failed, {} names, {} scans; {} snapshots",
+            stats.messages, stats.starting, stats.ended, stats.failed, stats.names, stats.scans, stats.snapshots,
+        );
+        frame.render_widget(Line::raw(counts_text), counts);
+        let keys_text = match self.mode {
+            Mode::Browse => "q quit  s/tab sort column  r reverse  / filter  1-5 sort by column",
+            Mode::Filter => "type host or port text  enter keep  esc clear",
+        };
+        frame.render_widget(Line::styled(keys_text, Style::new().add_modifier(Modifier::DIM)), keys);
+    }
+}
+
+fn style(kind: Kind) -> Style {
+    match kind {
+        Kind::Starting => Style::new().fg(Color::Cyan),
+        Kind::Active => Style::new().fg(Color::Green),
+        Kind::Ended | Kind::Reset => Style::new().add_modifier(Modifier::DIM),
+        Kind::Failed => Style::new().fg(Color::Red),
+        Kind::Name => Style::new(),
+        Kind::Scan => Style::new().fg(Color::Magenta),
+    }
+}
+
+/// Everything the TUI waits on.
+#[derive(Debug)]
+enum Event {
+    Message(Message),
+    Snapshot(Snapshot),
+    Key(KeyEvent),
+}
+
+/// Hands what the sensor observes to the TUI instead of printing it.
+struct Feed(mpsc::Sender<Event>);
+
+impl Format for Feed {
+    fn message(&self, message: &Message) -> Option<String> {
+        let _ = self.0.send(Event::Message(message.clone()));
+        None
+    }
+
+    fn snapshot(&self, snapshot: &Snapshot) -> Option<String> {
+        let _ = self.0.send(Event::Snapshot(snapshot.clone()));
+        None
+    }
+}
+
+/// Entry point for `glosco client --tui`: report as usual, but show what's observed as a live
+/// table rather than printing it, until `q` is pressed.
+pub fn run(args: ClientArgs) {
+    let (events, received) = mpsc::channel();
+    let client = client::start_with(args.resolve(), Box::new(Feed(events.clone())))
+        .unwrap_or_else(|e| panic!("failed to start: {}", e));
+    let mut terminal = ratatui::try_init().expect("failed to set up terminal");
+    thread::spawn(move || loop {
+        match event::read() {
+            // Some terminals report releases too
+            Ok(TermEvent::Key(key)) if key.kind == KeyEventKind::Press => if events.send(Event::Key(key)).is_err() {
+                return;
+            },
+            // A resize shows at the next redraw
+            Ok(_) => (),
+            Err(_) => return,
+        }
+    });
+
+    let mut screen = Screen { table: Table::default(), mode: Mode::Browse };
+    let mut next_draw = Instant::now();
+    loop {
+        let event = match received.recv_timeout(next_draw.saturating_duration_since(Instant::now())) {
+            Ok(event) => Some(event),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        match event {
+            Some(Event::Message(message)) => screen.table.apply(&message, Instant::now()),
+            Some(Event::Snapshot(snapshot)) => screen.table.snapshot(&snapshot),
+            Some(Event::Key(key)) => {
+                if !screen.key(key) {
+                    break;
+                }
+                // Show what a key did right away
+                next_draw = Instant::now();
+            },
+            // Time to draw again
+            None => (),
+        }
+        if Instant::now() >= next_draw {
+            screen.table.prune(Instant::now());
+            if let Err(e) = terminal.draw(|frame| screen.draw(frame)) {
+                println!("failed to draw: {}", e);
+                break;
+            }
+            next_draw = Instant::now() + REFRESH;
+        }
+    }
+    ratatui::restore();
+    client.shutdown();
+    client.join();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use crate::observe::{Connection, Endpoint, State};
+
+    use super::*;
+
+    fn press(screen: &mut Screen, keys: &str) -> bool {
+        keys.chars().all(|c| screen.key(KeyEvent::from(KeyCode::Char(c))))
+    }
+
+    fn starting(port: u16) -> Message {
+        let endpoint = |last, port| Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), port };
+        Message::Starting(State {
+            as_of: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            connection: Connection { interface: 0, src: endpoint(1, port), dst: endpoint(2, 443), protocol: Protocol::Tcp },
+            rtt_micros: None,
+        })
+    }
+
+    #[test]
+    fn keys_sort_and_filter() {
+        let mut screen = Screen { table: Table::default(), mode: Mode::Browse };
+        assert!(press(&mut screen, "3"));
+        assert_eq!((screen.table.sort, screen.table.descending), (Column::Src, true));
+        assert!(press(&mut screen, "3r"));
+        assert!(screen.table.descending);
+        assert!(press(&mut screen, "s"));
+        assert_eq!(screen.table.sort, Column::Dst);
+
+        // While filtering, q is just a letter
+        assert!(press(&mut screen, "/:4q"));
+        assert!(screen.key(KeyEvent::from(KeyCode::Backspace)));
+        assert_eq!((screen.table.filter.as_str(), screen.mode), (":4", Mode::Filter));
+        assert!(screen.key(KeyEvent::from(KeyCode::Enter)));
+        assert_eq!((screen.table.filter.as_str(), screen.mode), (":4", Mode::Browse));
+        assert!(press(&mut screen, "/"));
+        assert!(screen.key(KeyEvent::from(KeyCode::Esc)));
+        assert_eq!((screen.table.filter.as_str(), screen.mode), ("", Mode::Browse));
+
+        assert!(!press(&mut screen, "q"));
+        assert!(!screen.key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)));
+    }
+
+    #[test]
+    fn draws_a_line_a_connection() {
+        let mut screen = Screen { table: Table::default(), mode: Mode::Browse };
+        screen.table.apply(&starting(40000), Instant::now());
+        screen.table.apply(&starting(40001), Instant::now());
+        let mut terminal = Terminal::new(TestBackend::new(120, 8)).unwrap();
+        terminal.draw(|frame| screen.draw(frame)).unwrap();
+        let lines: Vec<String> = terminal.backend().buffer().content().chunks(120)
+            .map(|line| line.iter().map(|cell| cell.symbol()).collect::<String>().trim_end().to_string())
+            .collect();
+        assert_eq!(lines[0], "glosco: 2 connections, 2 shown, sorted by last seen desc");
+        assert!(lines[2].starts_with("LAST SEEN"), "{:?}", lines[2]);
+        assert!(lines[3].contains("10.0.0.1:40001") && lines[3].contains("starting"), "{:?}", lines[3]);
+        assert!(lines[4].contains("10.0.0.1:40000"), "{:?}", lines[4]);
+        assert!(lines[6].starts_with("2 messages: 2 started"), "{:?}", lines[6]);
+    }
+}
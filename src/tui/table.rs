@@ -0,0 +1,296 @@
+//! What the TUI shows, apart from the drawing: every connection seen lately, sorted and
+//! filtered as asked.
+
+use std::{cmp::Ordering, collections::HashMap, time::{Duration, Instant, SystemTime}};
+
+use crate::{alert::Kind, observe::{Connection, Message, Snapshot}};
+
+/// How long ended and failed connections stay on screen.
+const LINGER: Duration = Duration::from_secs(30);
+
+/// A column the table can be sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    LastSeen,
+    Protocol,
+    Src,
+    Dst,
+    State,
+}
+
+impl Column {
+    /// In the order they're shown.
+    pub const ALL: [Self; 5] = [Self::LastSeen, Self::Protocol, Self::Src, Self::Dst, Self::State];
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::LastSeen => "last seen",
+            Self::Protocol => "proto",
+            Self::Src => "source",
+            Self::Dst => "destination",
+            Self::State => "state",
+        }
+    }
+
+    /// The one after, going round.
+    pub fn next(self) -> Self {
+        let idx = Self::ALL.iter().position(|column| *column == self).unwrap_or(0);
+        Self::ALL[(idx + 1) % Self::ALL.len()]
+    }
+}
+
+/// One connection as the table shows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub connection: Connection,
+    pub kind: Kind,
+    pub last_seen: SystemTime,
+    /// When it ended or failed, by this host's clock, for lingering.
+    pub closed_at: Option<Instant>,
+    pub names: Vec<String>,
+}
+
+impl Row {
+    fn cmp_by(&self, other: &Self, column: Column) -> Ordering {
+        match column {
+            Column::LastSeen => self.last_seen.cmp(&other.last_seen),
+            Column::Protocol => self.connection.protocol.cmp(&other.connection.protocol),
+            Column::Src => self.connection.src.cmp(&other.connection.src),
+            Column::Dst => self.connection.dst.cmp(&other.connection.dst),
+            Column::State => self.kind.cmp(&other.kind),
+        }.then_with(|| self.connection.cmp(&other.connection))
+    }
+
+    /// Whether either end, written `host:port`, contains `filter`.
+    fn matches(&self, filter: &str) -> bool {
+        filter.is_empty()
+            || self.connection.src.to_string().contains(filter)
+            || self.connection.dst.to_string().contains(filter)
+    }
+}
+
+/// Counts for the footer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub messages: u64,
+    pub starting: u64,
+    pub ended: u64,
+    pub failed: u64,
+    pub names: u64,
+    pub scans: u64,
+    pub snapshots: u64,
+}
+
+/// Every connection seen lately, and how to sort and filter them.
+#[derive(Debug, Clone)]
+pub struct Table {
+    rows: HashMap<Connection, Row>,
+    pub sort: Column,
+    pub descending: bool,
+    pub filter: String,
+    pub stats: Stats,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self {
+            rows: HashMap::new(),
+            sort: Column::LastSeen,
+            descending: true,
+            filter: String::new(),
+            stats: Stats::default(),
+        }
+    }
+}
+
+impl Table {
+    /// Take in a message observed at `now`.
+    pub fn apply(&mut self, message: &Message, now: Instant) {
+        self.stats.messages += 1;
+        let state = message.state();
+        let kind = Kind::of(message);
+        match kind {
+            Kind::Starting => self.stats.starting += 1,
+            Kind::Ended | Kind::Reset => self.stats.ended += 1,
+            Kind::Failed => self.stats.failed += 1,
+            Kind::Name => self.stats.names += 1,
+            Kind::Scan => self.stats.scans += 1,
+            Kind::Active => (),
+        }
+        let row = self.rows.entry(state.connection).or_insert_with(|| Row {
+            connection: state.connection,
+            kind,
+            last_seen: state.as_of,
+            closed_at: None,
+            names: Vec::new(),
+        });
+        row.last_seen = row.last_seen.max(state.as_of);
+        match message {
+            // A name says nothing of the connection's state
+            Message::Name(_, names) => for name in names.iter() {
+                if !row.names.contains(&name.name) {
+                    row.names.push(name.name.clone());
+                }
+            },
+            _ => {
+                row.kind = kind;
+                row.closed_at = matches!(kind, Kind::Ended | Kind::Reset | Kind::Failed | Kind::Scan).then_some(now);
+            },
+        }
+    }
+
+    pub fn snapshot(&mut self, _snapshot: &Snapshot) {
+        self.stats.snapshots += 1;
+    }
+
+    /// Forget connections that closed longer than `LINGER` before `now`.
+    pub fn prune(&mut self, now: Instant) {
+        self.rows.retain(|_, row| row.closed_at.is_none_or(|closed| now.saturating_duration_since(closed) < LINGER));
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The rows matching the filter, in order.
+    pub fn rows(&self) -> Vec<&Row> {
+        let mut rows: Vec<&Row> = self.rows.values().filter(|row| row.matches(&self.filter)).collect();
+        rows.sort_by(|a, b| a.cmp_by(b, self.sort));
+        if self.descending {
+            rows.reverse();
+        }
+        rows
+    }
+
+    /// Sort by `column`, or if it already is, flip the order.
+    pub fn sort_by(&mut self, column: Column) {
+        if self.sort == column {
+            self.descending = !self.descending;
+        } else {
+            self.sort = column;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::observe::{Closed, Endpoint, Name, Problem, Protocol, State};
+
+    use super::*;
+
+    fn connection(src: (u8, u16), dst: (u8, u16), protocol: Protocol) -> Connection {
+        let endpoint = |(last, port)| Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), port };
+        Connection { interface: 0, src: endpoint(src), dst: endpoint(dst), protocol }
+    }
+
+    fn state(secs: u64, connection: Connection) -> State {
+        State { as_of: SystemTime::UNIX_EPOCH + Duration::from_secs(secs), connection, rtt_micros: None }
+    }
+
+    fn ports(table: &Table) -> Vec<u16> {
+        table.rows().iter().map(|row| row.connection.src.port).collect()
+    }
+
+    /// Three connections: 1000 from .1 over TCP, 2000 from .3 over UDP, 3000 from .2 over TCP,
+    /// last seen in the order their ports go.
+    fn three() -> Table {
+        let mut table = Table::default();
+        let now = Instant::now();
+        table.apply(&Message::Starting(state(10, connection((1, 1000), (9, 443), Protocol::Tcp))), now);
+        table.apply(&Message::Ended(state(20, connection((3, 2000), (9, 53), Protocol::Udp)), Closed::Connectionless), now);
+        table.apply(&Message::Active(state(30, connection((2, 3000), (8, 22), Protocol::Tcp))), now);
+        table
+    }
+
+    #[test]
+    fn newest_first_by_default() {
+        assert_eq!(ports(&three()), [3000, 2000, 1000]);
+    }
+
+    #[test]
+    fn sorted_by_each_column() {
+        let mut table = three();
+        table.descending = false;
+        for (column, expected) in [
+            (Column::LastSeen, [1000, 2000, 3000]),
+            (Column::Protocol, [1000, 3000, 2000]),
+            (Column::Src, [1000, 3000, 2000]),
+            (Column::Dst, [3000, 2000, 1000]),
+            (Column::State, [1000, 3000, 2000]),
+        ] {
+            table.sort = column;
+            assert_eq!(ports(&table), expected, "by {}", column.title());
+        }
+    }
+
+    #[test]
+    fn sorting_by_the_same_column_again_flips_it() {
+        let mut table = three();
+        table.sort_by(Column::Src);
+        assert_eq!((table.sort, table.descending), (Column::Src, true));
+        assert_eq!(ports(&table), [2000, 3000, 1000]);
+        table.sort_by(Column::Src);
+        assert_eq!(ports(&table), [1000, 3000, 2000]);
+    }
+
+    #[test]
+    fn next_column_goes_round() {
+        let mut column = Column::LastSeen;
+        for _ in 0 .. Column::ALL.len() {
+            column = column.next();
+        }
+        assert_eq!(column, Column::LastSeen);
+    }
+
+    #[test]
+    fn filtered_on_either_end() {
+        let mut table = three();
+        table.filter = "10.0.0.3".to_string();
+        assert_eq!(ports(&table), [2000]);
+        table.filter = ":22".to_string();
+        assert_eq!(ports(&table), [3000]);
+        table.filter = "192.168.".to_string();
+        assert!(ports(&table).is_empty());
+        table.filter.clear();
+        assert_eq!(table.rows().len(), 3);
+        // Filtering hides rows, it doesn't drop them
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn a_row_follows_its_connection() {
+        let mut table = Table::default();
+        let conn = connection((1, 1000), (9, 53), Protocol::Udp);
+        let now = Instant::now();
+        table.apply(&Message::Failed(state(10, conn), Problem { kind: 3, code: 3, repeats: 0 }), now);
+        table.apply(&Message::Name(state(5, conn), vec![Name { name: "example.com".to_string(), address: None }]), now);
+        table.apply(&Message::Name(state(11, conn), vec![Name { name: "example.com".to_string(), address: None }]), now);
+        let rows = table.rows();
+        assert_eq!(rows.len(), 1);
+        // A name neither changes the state nor brings last seen back
+        assert_eq!(rows[0].kind, Kind::Failed);
+        assert_eq!(rows[0].last_seen, SystemTime::UNIX_EPOCH + Duration::from_secs(11));
+        assert_eq!(rows[0].names, ["example.com"]);
+        assert_eq!(table.stats, Stats { messages: 3, failed: 1, names: 2, ..Stats::default() });
+    }
+
+    #[test]
+    fn closed_connections_linger_then_go() {
+        let mut table = three();
+        let now = Instant::now();
+        table.prune(now + LINGER / 2);
+        assert_eq!(table.len(), 3);
+        table.prune(now + LINGER);
+        // Only the UDP one closed; the others are still open however long it's been
+        assert_eq!(ports(&table), [3000, 1000]);
+        table.apply(&Message::Ended(state(40, connection((1, 1000), (9, 443), Protocol::Tcp)), Closed::Reset), now);
+        table.prune(now + LINGER * 2);
+        assert_eq!(ports(&table), [3000]);
+    }
+}
@@ -0,0 +1,417 @@
+//! Messages read in place from the frame they came in: names and text are borrowed from the
+//! frame rather than copied out of it, so a collector can store a message without allocating
+//! for it. `to_message` makes the owned `Message` for whatever has to keep one.
+//!
+//! What decodes as a view decodes as a `Message` and vice versa, with the same fields.
+
+use std::{borrow::Cow, fmt::{self, Debug, Formatter}, io::{self, ErrorKind}, net::IpAddr, str};
+
+use crate::coding::{Coder, ACTIVE_MARK, ADDR_MARK, ALIAS_MARK, ENDED_MARK, FAILED_MARK, NAME_MARK, REPEATED_MARK, RTT_MARK, SCAN_MARK, START_MARK, SVC_MARK, TEXT_MARK};
+use crate::observe::{Closed, Message, Name, Problem, Resolution, State};
+use crate::scan::Scan;
+
+/// A `Message`, borrowing its names from the frame it was decoded from or the `Message` it
+/// views.
+#[derive(Debug, Clone, Copy)]
+pub enum MessageRef<'a> {
+    Starting(State),
+    Active(State),
+    Ended(State, Closed),
+    Failed(State, Problem),
+    Name(State, Names<'a>),
+    Scan(State, Scan),
+}
+
+impl<'a> MessageRef<'a> {
+    /// Decode a message from the front of `reader`, leaving it at whatever follows.
+    pub fn decode(reader: &mut &'a [u8]) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            START_MARK => Ok(Self::Starting(State::decode(reader)?)),
+            ACTIVE_MARK => Ok(Self::Active(State::decode(reader)?)),
+            RTT_MARK => {
+                let state = State::decode(reader)?;
+                Ok(Self::Active(State { rtt_micros: Some(u32::decode(reader)?), ..state }))
+            },
+            ENDED_MARK => Ok(Self::Ended(State::decode(reader)?, Closed::decode(reader)?)),
+            FAILED_MARK => Ok(Self::Failed(State::decode(reader)?, Problem::decode(reader)?)),
+            REPEATED_MARK => {
+                let (state, problem) = (State::decode(reader)?, Problem::decode(reader)?);
+                Ok(Self::Failed(state, Problem { repeats: u32::decode(reader)?, ..problem }))
+            },
+            NAME_MARK => Ok(Self::Name(State::decode(reader)?, Names::decode(reader)?)),
+            SCAN_MARK => Ok(Self::Scan(State::decode(reader)?, Scan::decode(reader)?)),
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        match self {
+            Self::Starting(state) | Self::Active(state) | Self::Ended(state, _) | Self::Failed(state, _) | Self::Name(state, _) | Self::Scan(state, _) => state,
+        }
+    }
+
+    pub fn state_mut(&mut self) -> &mut State {
+        match self {
+            Self::Starting(state) | Self::Active(state) | Self::Ended(state, _) | Self::Failed(state, _) | Self::Name(state, _) | Self::Scan(state, _) => state,
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        match *self {
+            Self::Starting(state) => Message::Starting(state),
+            Self::Active(state) => Message::Active(state),
+            Self::Ended(state, closed) => Message::Ended(state, closed),
+            Self::Failed(state, problem) => Message::Failed(state, problem),
+            Self::Name(state, names) => Message::Name(state, names.iter().map(|name| name.to_name()).collect()),
+            Self::Scan(state, scan) => Message::Scan(state, scan),
+        }
+    }
+}
+
+impl Message {
+    /// This message as a view, for code that takes one.
+    pub fn view(&self) -> MessageRef<'_> {
+        match self {
+            Self::Starting(state) => MessageRef::Starting(*state),
+            Self::Active(state) => MessageRef::Active(*state),
+            Self::Ended(state, closed) => MessageRef::Ended(*state, *closed),
+            Self::Failed(state, problem) => MessageRef::Failed(*state, *problem),
+            Self::Name(state, names) => MessageRef::Name(*state, Names(Source::Owned(names))),
+            Self::Scan(state, scan) => MessageRef::Scan(*state, *scan),
+        }
+    }
+}
+
+/// Where a list in a view comes from: a frame, as encoded (`count` items in `bytes`, already
+/// checked to decode), or an owned message.
+enum Source<'a, T> {
+    Encoded { count: u8, bytes: &'a [u8] },
+    Owned(&'a [T]),
+}
+
+// Only references, whatever `T` is; derived, these would need `T: Copy`
+impl<T> Clone for Source<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Source<'_, T> {}
+
+impl<'a, T> Source<'a, T> {
+    /// Each item, decoded with `decode` if it's encoded and converted with `of` if it's owned.
+    fn iter<U: 'a>(self, decode: fn(&mut &'a [u8]) -> io::Result<U>, of: fn(&'a T) -> U) -> impl Iterator<Item = U> + 'a {
+        let (count, mut bytes, owned) = match self {
+            Self::Encoded { count, bytes } => (count, bytes, [].iter()),
+            Self::Owned(items) => (0, &[][..], items.iter()),
+        };
+        (0 .. count).map(move |_| decode(&mut bytes).expect("checked when the message was decoded"))
+            .chain(owned.map(of))
+    }
+}
+
+/// The names a Name message carries.
+#[derive(Clone, Copy)]
+pub struct Names<'a>(Source<'a, Name>);
+
+impl<'a> Names<'a> {
+    fn decode(reader: &mut &'a [u8]) -> io::Result<Self> {
+        let count = u8::decode(reader)?;
+        let start = *reader;
+        for _ in 0 .. count {
+            NameRef::decode(reader)?;
+        }
+        Ok(Self(Source::Encoded { count, bytes: &start[.. start.len() - reader.len()] }))
+    }
+
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Source::Encoded { count, .. } => count as usize,
+            Source::Owned(names) => names.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = NameRef<'a>> + 'a {
+        self.0.iter(NameRef::decode, NameRef::of)
+    }
+}
+
+impl Debug for Names<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// A `Name`, borrowing its strings.
+#[derive(Clone, Copy)]
+pub struct NameRef<'a> {
+    pub name: &'a str,
+    pub address: Option<ResolutionRef<'a>>,
+}
+
+impl<'a> NameRef<'a> {
+    fn decode(reader: &mut &'a [u8]) -> io::Result<Self> {
+        let name = decode_str(reader)?;
+        let address = match u8::decode(reader)? {
+            1 => Some(ResolutionRef::decode(reader)?),
+            _ => None,
+        };
+        Ok(Self { name, address })
+    }
+
+    fn of(name: &'a Name) -> Self {
+        Self {
+            name: &name.name,
+            address: name.address.as_ref().map(ResolutionRef::of),
+        }
+    }
+
+    pub fn to_name(&self) -> Name {
+        Name {
+            name: self.name.to_string(),
+            address: self.address.map(|address| address.to_resolution()),
+        }
+    }
+}
+
+impl Debug for NameRef<'_> {
+    // As a `Name` would be, so logs read the same either way
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Name")
+            .field("name", &self.name)
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+/// A `Resolution`, borrowing its strings.
+#[derive(Debug, Clone, Copy)]
+pub enum ResolutionRef<'a> {
+    Address(IpAddr),
+    Alias(&'a str),
+    Service(&'a str, Option<u16>),
+    Text(Texts<'a>),
+}
+
+impl<'a> ResolutionRef<'a> {
+    fn decode(reader: &mut &'a [u8]) -> io::Result<Self> {
+        match u8::decode(reader)? {
+            ADDR_MARK => Ok(Self::Address(IpAddr::decode(reader)?)),
+            ALIAS_MARK => Ok(Self::Alias(decode_str(reader)?)),
+            SVC_MARK => Ok(Self::Service(decode_str(reader)?, Option::<u16>::decode(reader)?)),
+            TEXT_MARK => Ok(Self::Text(Texts::decode(reader)?)),
+            _ => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn of(resolution: &'a Resolution) -> Self {
+        match resolution {
+            Resolution::Address(addr) => Self::Address(*addr),
+            Resolution::Alias(alias) => Self::Alias(alias),
+            Resolution::Service(name, port) => Self::Service(name, *port),
+            Resolution::Text(texts) => Self::Text(Texts(Source::Owned(texts))),
+        }
+    }
+
+    pub fn to_resolution(&self) -> Resolution {
+        match *self {
+            Self::Address(addr) => Resolution::Address(addr),
+            Self::Alias(alias) => Resolution::Alias(alias.to_string()),
+            Self::Service(name, port) => Resolution::Service(name.to_string(), port),
+            Self::Text(texts) => Resolution::Text(texts.iter().map(<[u8]>::to_vec).collect()),
+        }
+    }
+}
+
+/// The strings of a TXT record.
+#[derive(Clone, Copy)]
+pub struct Texts<'a>(Source<'a, Vec<u8>>);
+
+impl<'a> Texts<'a> {
+    fn decode(reader: &mut &'a [u8]) -> io::Result<Self> {
+        let count = u8::decode(reader)?;
+        let start = *reader;
+        for _ in 0 .. count {
+            decode_text(reader)?;
+        }
+        Ok(Self(Source::Encoded { count, bytes: &start[.. start.len() - reader.len()] }))
+    }
+
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Source::Encoded { count, .. } => count as usize,
+            Source::Owned(texts) => texts.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.0.iter(decode_text, Vec::as_slice)
+    }
+
+    /// The strings as a TXT record's data has them, each after a byte giving its length; as
+    /// they're encoded, in fact, so only an owned record has to be copied to get it.
+    pub fn rdata(&self) -> Cow<'a, [u8]> {
+        match self.0 {
+            Source::Encoded { bytes, .. } => Cow::Borrowed(bytes),
+            Source::Owned(texts) => Cow::Owned(texts.iter().fold(Vec::new(), |mut rdata, text| {
+                rdata.push(text.len() as u8);
+                rdata.extend_from_slice(text);
+                rdata
+            })),
+        }
+    }
+}
+
+impl Debug for Texts<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// The next `len` bytes of `reader`, failing as `read_exact` would if there aren't that many.
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if reader.len() < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let (taken, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(taken)
+}
+
+/// A string as `String` encodes it, checked to be UTF-8 but not copied.
+fn decode_str<'a>(reader: &mut &'a [u8]) -> io::Result<&'a str> {
+    let len = u16::decode(reader)?;
+    str::from_utf8(take(reader, len as usize)?).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// One string of a TXT record, as `Vec<u8>` encodes it in a list of them.
+fn decode_text<'a>(reader: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = u8::decode(reader)?;
+    take(reader, len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::{Ipv4Addr, Ipv6Addr}, time::{Duration, SystemTime}};
+
+    use crate::{observe::{Connection, Endpoint, Protocol}, scan::ScanKind};
+
+    use super::*;
+
+    fn state(srcport: u16, rtt_micros: Option<u32>) -> State {
+        State {
+            as_of: SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+            connection: Connection {
+                interface: 1,
+                src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), port: srcport },
+                dst: Endpoint { addr: IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), port: 53 },
+                protocol: Protocol::Udp,
+            },
+            rtt_micros,
+        }
+    }
+
+    fn name(name: &str, address: Option<Resolution>) -> Name {
+        Name { name: name.to_string(), address }
+    }
+
+    /// A message of every kind, and a Name message with every kind of name in it.
+    fn every_kind() -> Vec<Message> {
+        let mut messages = vec![
+            Message::Starting(state(40000, None)),
+            Message::Active(state(40000, None)),
+            Message::Active(state(40000, Some(12_500))),
+            Message::Failed(state(40001, None), Problem { kind: 3, code: 13, repeats: 0 }),
+            Message::Failed(state(40001, None), Problem { kind: 3, code: 13, repeats: 7 }),
+            Message::Scan(state(0, None), Scan { kind: ScanKind::Ports, count: 40, window: Duration::from_millis(1500) }),
+            Message::Scan(state(0, None), Scan { kind: ScanKind::Hosts, count: 9, window: Duration::from_secs(10) }),
+            Message::Name(state(5353, None), Vec::new()),
+            Message::Name(state(5353, None), vec![
+                name("example.com", Some(Resolution::Address(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))))),
+                name("example.com", Some(Resolution::Address(IpAddr::V6(Ipv6Addr::LOCALHOST)))),
+                name("www.example.com", Some(Resolution::Alias("example.com".to_string()))),
+                name("_http._tcp.local", Some(Resolution::Service("printer.local".to_string(), Some(631)))),
+                name("_ipp._tcp.local", Some(Resolution::Service("printer.local".to_string(), None))),
+                name("printer.local", Some(Resolution::Text(vec![b"txtvers=1".to_vec(), Vec::new(), vec![0xff, 0x00]]))),
+                name("printer.local", Some(Resolution::Text(Vec::new()))),
+                name("nothing.example", None),
+                name("", None),
+                name("bücher.example", None),
+            ]),
+        ];
+        for closed in [Closed::Normally, Closed::Reset, Closed::TimedOut, Closed::Connectionless] {
+            messages.push(Message::Ended(state(40002, None), closed));
+        }
+        messages
+    }
+
+    fn frame(message: &Message) -> Vec<u8> {
+        let mut frame = Vec::new();
+        message.encode(&mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn every_frame_decodes_to_the_same_message_either_way() {
+        for message in every_kind() {
+            let frame = frame(&message);
+            let (mut owned, mut viewed) = (&frame[..], &frame[..]);
+            let decoded = Message::decode(&mut owned).unwrap();
+            let view = MessageRef::decode(&mut viewed).unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(view.to_message(), decoded);
+            assert_eq!(view.state(), decoded.state());
+            assert!(owned.is_empty() && viewed.is_empty(), "{:?} left bytes over", message);
+            // Logs read the same whichever was decoded
+            assert_eq!(format!("{:?}", view), format!("{:?}", decoded));
+        }
+    }
+
+    #[test]
+    fn a_view_of_a_message_is_the_view_of_its_frame() {
+        for message in every_kind() {
+            let frame = frame(&message);
+            let from_frame = MessageRef::decode(&mut &frame[..]).unwrap();
+            let of_message = message.view();
+            assert_eq!(of_message.to_message(), message);
+            assert_eq!(format!("{:?}", of_message), format!("{:?}", from_frame));
+            if let (MessageRef::Name(_, from_frame), MessageRef::Name(_, of_message)) = (from_frame, of_message) {
+                assert_eq!(from_frame.len(), of_message.len());
+                for (from_frame, of_message) in from_frame.iter().zip(of_message.iter()) {
+                    if let (Some(ResolutionRef::Text(from_frame)), Some(ResolutionRef::Text(of_message))) = (from_frame.address, of_message.address) {
+                        assert_eq!(from_frame.rdata(), of_message.rdata());
+                        assert!(matches!(from_frame.rdata(), Cow::Borrowed(_)), "copied out of the frame");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn what_one_decoder_refuses_the_other_does_too() {
+        for message in every_kind() {
+            let frame = frame(&message);
+            for len in 0 .. frame.len() {
+                let cut = &frame[.. len];
+                assert!(Message::decode(&mut &cut[..]).is_err(), "{:?} cut to {} bytes decoded", message, len);
+                assert!(MessageRef::decode(&mut &cut[..]).is_err(), "{:?} cut to {} bytes decoded as a view", message, len);
+            }
+        }
+        // A name that isn't UTF-8, and a mark that's nothing
+        let mut frame = frame(&Message::Name(state(5353, None), vec![name("ab", None)]));
+        let at = frame.windows(2).position(|pair| pair == b"ab").unwrap();
+        frame[at] = 0xff;
+        assert!(Message::decode(&mut &frame[..]).is_err());
+        assert_eq!(MessageRef::decode(&mut &frame[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(Message::decode(&mut &[0xee][..]).is_err());
+        assert_eq!(MessageRef::decode(&mut &[0xee][..]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
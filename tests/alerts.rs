@@ -0,0 +1,147 @@
+//! Webhook alerts, as a local listener standing in for the webhook receives them.
+
+use std::{net::SocketAddr, sync::mpsc, thread, time::Duration};
+
+use glosco::{alert::Rule, observe::{Closed, Message, Problem, Protocol}, server::AlertSettings, test_support::{state, TestServer}};
+use serde_json::Value;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// A webhook on an ephemeral port, handing over the body of each POST it takes.
+struct Webhook {
+    url: String,
+    bodies: mpsc::Receiver<Value>,
+}
+
+impl Webhook {
+    fn start() -> Self {
+        let server = tiny_http::Server::http(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let (sender, bodies) = mpsc::channel();
+        thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let _ = request.respond(tiny_http::Response::empty(200));
+                if sender.send(serde_json::from_str(&body).unwrap()).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { url, bodies }
+    }
+
+    fn next(&self) -> Option<Value> {
+        self.bodies.recv_timeout(WAIT).ok()
+    }
+
+    /// Wait a while for anything else to arrive; true if nothing did.
+    fn quiet(&self) -> bool {
+        self.bodies.recv_timeout(Duration::from_millis(500)).is_err()
+    }
+}
+
+fn alerting_server(webhook: &Webhook, rules: Vec<Rule>) -> TestServer {
+    TestServer::spawn_with(|settings| settings.alerts = Some(AlertSettings { webhook: webhook.url.clone(), rules }))
+}
+
+fn unreachable(src: &str) -> Message {
+    Message::Failed(state(src, "10.0.0.2:5432", Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 })
+}
+
+#[test]
+fn a_payload_says_what_matched_and_why() {
+    let webhook = Webhook::start();
+    let server = alerting_server(&webhook, vec!["kind=failed,port=5432".parse().unwrap()]);
+    let mut client = server.client("db-1");
+    client.hello(None).unwrap();
+    let failed = unreachable("10.0.0.1:40000");
+    client.send(&failed).unwrap();
+
+    let payload = webhook.next().expect("no alert");
+    assert_eq!(payload["rule"], "kind=failed,port=5432,window=300");
+    assert_eq!(payload["ident"], "db-1");
+    assert!(payload["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+    assert!(payload["received"].as_f64().unwrap() > 0.0);
+    assert_eq!(payload["message"], serde_json::to_value(&failed).unwrap());
+    assert_eq!(payload["suppressed"], 0);
+    assert_eq!(payload["labels"], serde_json::json!({}));
+}
+
+#[test]
+fn messages_no_rule_matches_send_nothing() {
+    let webhook = Webhook::start();
+    let server = alerting_server(&webhook, vec!["kind=failed,port=22".parse().unwrap()]);
+    let mut client = server.client("db-1");
+    client.hello(None).unwrap();
+    client.send(&unreachable("10.0.0.1:40000")).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:22", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 2, WAIT));
+    assert!(webhook.quiet());
+}
+
+#[test]
+fn matches_within_a_window_are_counted_into_the_next_alert() {
+    let webhook = Webhook::start();
+    let server = alerting_server(&webhook, vec!["kind=failed,window=1".parse().unwrap()]);
+    let mut client = server.client("db-1");
+    client.hello(None).unwrap();
+    for port in 40000 .. 40003 {
+        client.send(&unreachable(&format!("10.0.0.1:{}", port))).unwrap();
+    }
+    assert_eq!(webhook.next().expect("no alert")["suppressed"], 0);
+    assert!(webhook.quiet());
+
+    thread::sleep(Duration::from_secs(1));
+    client.send(&unreachable("10.0.0.1:40003")).unwrap();
+    assert_eq!(webhook.next().expect("no alert after the window")["suppressed"], 2);
+}
+
+#[test]
+fn a_replayed_message_alerts_once() {
+    let webhook = Webhook::start();
+    let server = alerting_server(&webhook, vec!["kind=failed,window=0".parse().unwrap()]);
+    let mut client = server.client("db-1");
+    client.hello(None).unwrap();
+    let failed = unreachable("10.0.0.1:40000");
+    client.send(&failed).unwrap();
+    client.send(&failed).unwrap();
+    assert!(webhook.next().is_some());
+    assert!(webhook.quiet());
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM state_all", [], |row| row.get::<_, i64>(0)).unwrap(), 1);
+}
+
+#[test]
+fn a_connection_timed_out_by_the_collector_alerts() {
+    let webhook = Webhook::start();
+    let server = alerting_server(&webhook, vec!["kind=ended,port=5432".parse().unwrap()]);
+    let mut client = server.client("db-1");
+    client.hello(None).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:5432", Protocol::Tcp))).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:22", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 2, WAIT));
+    assert!(webhook.quiet());
+
+    // Dropping the sensor closes what it had open as timed out
+    client.close();
+    let payload = webhook.next().expect("no alert for the timeout");
+    assert_eq!(payload["rule"], "kind=ended,port=5432,window=300");
+    assert_eq!(payload["ident"], "db-1");
+    let ended = &payload["message"]["Ended"];
+    assert_eq!(ended[1], "TimedOut");
+    assert_eq!(ended[0]["connection"]["dst"]["port"], 5432);
+    assert_eq!(ended[0]["connection"]["src"]["port"], 40000);
+    assert!(webhook.quiet(), "the other connection's port doesn't match");
+}
+
+#[test]
+fn a_timeout_a_sensor_reports_alerts_once() {
+    let webhook = Webhook::start();
+    let server = alerting_server(&webhook, vec!["kind=ended,window=0".parse().unwrap()]);
+    let mut client = server.client("db-1");
+    client.hello(None).unwrap();
+    client.send(&Message::Ended(state("10.0.0.1:40000", "10.0.0.2:5432", Protocol::Tcp), Closed::TimedOut)).unwrap();
+    let payload = webhook.next().expect("no alert for the timeout");
+    assert_eq!(payload["message"]["Ended"][1], "TimedOut");
+    assert!(webhook.quiet());
+}
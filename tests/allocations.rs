@@ -0,0 +1,105 @@
+//! How many allocations decoding a stream of frames costs, owned against viewed in place, as
+//! the collector's insert path reads them. Run it with
+//! `cargo test --release --test allocations -- --nocapture` for the numbers.
+
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell, net::{IpAddr, Ipv4Addr}, time::{Duration, Instant, SystemTime}};
+
+use glosco::{coding::Coder, observe::{Closed, Connection, Endpoint, Message, Name, Problem, Protocol, Resolution, State}, view::{MessageRef, ResolutionRef}};
+
+/// Counts the allocations made on each thread, so other tests running at once don't count.
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+// Safety: passes everything through to the system allocator, only counting on the way.
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+/// Allocations `work` makes, and how long it takes.
+fn counted<T, F: FnOnce() -> T>(work: F) -> (u64, Duration, T) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let started = Instant::now();
+    let result = work();
+    let elapsed = started.elapsed();
+    (ALLOCATIONS.with(Cell::get) - before, elapsed, result)
+}
+
+/// A sensor's worth of frames: mostly connections coming and going, with a resolver's answers
+/// every so often.
+fn frames(count: usize) -> Vec<Vec<u8>> {
+    let state = |n: usize| State {
+        as_of: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + n as u64),
+        connection: Connection {
+            interface: 0,
+            src: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(10, 0, (n >> 8) as u8, n as u8)), port: 40000 + (n % 20000) as u16 },
+            dst: Endpoint { addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, (n % 250) as u8)), port: 443 },
+            protocol: Protocol::Tcp,
+        },
+        rtt_micros: None,
+    };
+    (0 .. count).map(|n| {
+        let message = match n % 10 {
+            0 ..= 3 => Message::Starting(state(n)),
+            4 ..= 5 => Message::Active(state(n)),
+            6 => Message::Ended(state(n), Closed::Normally),
+            7 => Message::Failed(state(n), Problem { kind: 3, code: 1, repeats: 0 }),
+            _ => Message::Name(state(n), vec![
+                Name { name: format!("host{}.example.com", n), address: Some(Resolution::Alias("cdn.example.net".to_string())) },
+                Name { name: "cdn.example.net".to_string(), address: Some(Resolution::Address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)))) },
+                Name { name: "cdn.example.net".to_string(), address: Some(Resolution::Text(vec![b"v=spf1 -all".to_vec()])) },
+            ]),
+        };
+        let mut frame = Vec::new();
+        message.encode(&mut frame).unwrap();
+        frame
+    }).collect()
+}
+
+/// Something of every field, so neither decoder's work can be skipped.
+fn checksum(message: &MessageRef<'_>) -> usize {
+    let names = match message {
+        MessageRef::Name(_, names) => names.iter().map(|name| name.name.len() + match name.address {
+            Some(ResolutionRef::Alias(alias)) => alias.len(),
+            Some(ResolutionRef::Text(texts)) => texts.rdata().len(),
+            _ => 1,
+        }).sum(),
+        _ => 0,
+    };
+    message.state().connection.src.port as usize + names
+}
+
+#[test]
+fn viewing_frames_in_place_allocates_nothing_decoding_them_owned_does() {
+    const FRAMES: usize = 100_000;
+    let frames = frames(FRAMES);
+    let (owned, owned_time, owned_sum) = counted(|| frames.iter().map(|frame| {
+        let message = Message::decode(&mut &frame[..]).unwrap();
+        checksum(&message.view())
+    }).sum::<usize>());
+    let (viewed, viewed_time, viewed_sum) = counted(|| frames.iter().map(|frame| {
+        checksum(&MessageRef::decode(&mut &frame[..]).unwrap())
+    }).sum::<usize>());
+    println!("{} frames: {} allocations in {:?} decoded owned, {} in {:?} viewed", FRAMES, owned, owned_time, viewed, viewed_time);
+    assert_eq!(owned_sum, viewed_sum);
+    assert_eq!(viewed, 0);
+    // Each Name frame alone costs a list, a string per name and some per resolution
+    assert!(owned >= FRAMES as u64 / 10 * 7, "{} allocations decoding owned", owned);
+}
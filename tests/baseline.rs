@@ -0,0 +1,36 @@
+//! Destinations an ident hasn't used before, flagged once the collector has learned its usual
+//! ones.
+
+use std::{thread, time::Duration};
+
+use glosco::{observe::{Message, Protocol}, server::{BaselineSettings, Granularity}, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn starting(srcport: u16, dst: &str) -> Message {
+    Message::Starting(state(&format!("10.0.0.1:{}", srcport), dst, Protocol::Tcp))
+}
+
+#[test]
+fn a_novel_destination_is_flagged_once() {
+    // Learning for as long as it takes the first connection to be stored
+    let server = TestServer::spawn_with(|settings| settings.baseline = Some(BaselineSettings { granularity: Granularity::Port, learning: 0.2 }));
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&starting(40001, "10.0.0.2:443")).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM baseline", 1, WAIT));
+    thread::sleep(Duration::from_millis(300));
+
+    client.send(&starting(40002, "10.0.0.2:443")).unwrap();
+    client.send(&starting(40003, "10.0.0.2:4444")).unwrap();
+    client.send(&starting(40004, "10.0.0.2:4444")).unwrap();
+    // Each message is learned from before the next is stored, so once this one is, the
+    // second to 4444 has been looked at
+    client.send(&starting(40005, "10.0.0.2:443")).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 5, WAIT));
+
+    let anomalies: Vec<(String, u16, u16)> = server.db().prepare("SELECT ident, srcport, dstport FROM anomalies").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(anomalies, [("sensor".to_string(), 40003, 4444)]);
+}
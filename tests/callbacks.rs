@@ -0,0 +1,33 @@
+//! Rust callbacks registered on a running collector, as an embedder would.
+
+use std::{sync::{mpsc, Mutex}, time::Duration};
+
+use glosco::{alert::{Kind, Rule}, observe::{Message, Problem, Protocol}, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[test]
+fn a_callback_hears_of_each_stored_message_its_rule_matches_once() {
+    let server = TestServer::spawn();
+    let (sender, heard) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    server.handle().on_match(Rule::new([Kind::Failed]).ident("db-*").port(5432), move |event| {
+        sender.lock().unwrap().send((event.ident.to_string(), event.message.clone())).unwrap();
+    });
+
+    let failed = Message::Failed(state("10.0.0.1:40000", "10.0.0.2:5432", Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 });
+    let mut db = server.client("db-1");
+    db.hello(None).unwrap();
+    db.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:5432", Protocol::Tcp))).unwrap();
+    db.send(&failed).unwrap();
+    // A replay the database ignores isn't heard of again
+    db.send(&failed).unwrap();
+    let mut web = server.client("web-1");
+    web.hello(None).unwrap();
+    web.send(&Message::Failed(state("10.0.0.5:40000", "10.0.0.2:5432", Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 })).unwrap();
+
+    assert_eq!(heard.recv_timeout(WAIT).unwrap(), ("db-1".to_string(), failed));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 3, WAIT));
+    assert!(heard.recv_timeout(Duration::from_millis(500)).is_err());
+    assert_eq!(server.handle().callbacks_dropped(), 0);
+}
@@ -0,0 +1,77 @@
+//! What client mode sends, as the collector decodes it: the observer's bundles go out through
+//! `sync::Client::send_bundle`, and every message in them has to come out the other end as it
+//! went in, in order.
+
+use std::{net::IpAddr, time::Duration};
+
+use glosco::{observe::{Closed, Message, Name, Problem, Protocol, Resolution}, scan::{Scan, ScanKind}, sync::ClientConfig, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Bundles like an observer yields: one packet's worth of messages apiece.
+fn bundles() -> Vec<Vec<Message>> {
+    let answer: IpAddr = "93.184.216.34".parse().unwrap();
+    let mut timed = state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp);
+    timed.rtt_micros = Some(1500);
+    vec![
+        vec![Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))],
+        vec![
+            Message::Ended(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp), Closed::Reset),
+            Message::Active(timed),
+        ],
+        vec![Message::Failed(state("10.0.0.1:5000", "10.0.0.3:53", Protocol::Udp), Problem { kind: 3, code: 3, repeats: 4 })],
+        vec![
+            Message::Name(state("10.0.0.53:53", "10.0.0.1:5353", Protocol::Udp), vec![
+                Name { name: "example.com".to_string(), address: Some(Resolution::Address(answer)) },
+                Name { name: "www.example.com".to_string(), address: Some(Resolution::Alias("example.com".to_string())) },
+            ]),
+            Message::Ended(state("10.0.0.1:5001", "10.0.0.53:53", Protocol::Udp), Closed::Connectionless),
+        ],
+        vec![Message::Scan(state("10.0.0.9:0", "10.0.0.2:0", Protocol::Tcp), Scan { kind: ScanKind::Ports, count: 40, window: Duration::from_secs(10) })],
+    ]
+}
+
+#[test]
+fn bundles_sent_are_decoded_message_for_message() {
+    let server = TestServer::spawn_logging();
+    let mut config = ClientConfig::new("sensor".to_string());
+    config.add(server.addr());
+    config.set_keepalive(30);
+    let client = config.build().unwrap();
+    let bundles = bundles();
+    for bundle in &bundles {
+        client.send_bundle(bundle);
+    }
+    assert!(client.shutdown(WAIT), "not everything was sent");
+
+    let sent: Vec<Message> = bundles.into_iter().flatten().collect();
+    let decoded: Vec<Message> = server.logged(sent.len(), WAIT).into_iter()
+        .map(|record| {
+            assert_eq!(record["ident"], "sensor");
+            serde_json::from_value(record["message"].clone()).unwrap()
+        })
+        .collect();
+    assert_eq!(decoded, sent);
+}
+
+#[test]
+fn a_bundle_is_what_sending_each_message_alone_would_be() {
+    let bundled = TestServer::spawn_logging();
+    let alone = TestServer::spawn_logging();
+    let bundles = bundles();
+    for (server, bundle_them) in [(&bundled, true), (&alone, false)] {
+        let mut config = ClientConfig::new("sensor".to_string());
+        config.add(server.addr());
+        let client = config.build().unwrap();
+        for bundle in &bundles {
+            match bundle_them {
+                true => client.send_bundle(bundle),
+                false => bundle.iter().for_each(|message| client.send(message)),
+            }
+        }
+        assert!(client.shutdown(WAIT), "not everything was sent");
+    }
+    let count = bundles.iter().map(Vec::len).sum();
+    let messages = |server: &TestServer| server.logged(count, WAIT).into_iter().map(|record| record["message"].clone()).collect::<Vec<_>>();
+    assert_eq!(messages(&bundled), messages(&alone));
+}
@@ -0,0 +1,80 @@
+//! The collector's inventory of clients: one row per ident, and one per connection it made.
+
+use std::{thread, time::Duration};
+
+use glosco::{coding::{ACTIVE_MARK, TMOUT_MARK}, observe::{Closed, Message, Protocol}, query, sync::Hello, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Every session of `ident`, oldest first: connected, disconnected and frames received.
+fn sessions(server: &TestServer, ident: &str) -> Vec<(f64, Option<f64>, Option<i64>)> {
+    server.db().prepare("SELECT connected, disconnected, frames FROM client_sessions WHERE ident = ? ORDER BY rowid").unwrap()
+        .query_map([ident], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+/// First and last seen of `ident`.
+fn seen(server: &TestServer, ident: &str) -> (f64, f64) {
+    server.db().query_row("SELECT first_seen, last_seen FROM clients WHERE ident = ?", [ident], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+}
+
+#[test]
+fn reconnecting_adds_a_session_and_moves_last_seen_on() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    client.close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+    let (first_seen, last_seen) = seen(&server, "sensor");
+
+    // Far enough on for the clock to tell the two apart
+    thread::sleep(Duration::from_millis(50));
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions", 2, WAIT));
+
+    let sessions = sessions(&server, "sensor");
+    let (connected, disconnected, frames) = sessions[0];
+    let disconnected = disconnected.expect("the first session never ended");
+    assert!(connected <= disconnected, "{} > {}", connected, disconnected);
+    // The hello and the one message
+    assert_eq!(frames, Some(2));
+    let (reconnected, still_open, _) = sessions[1];
+    assert!(reconnected >= disconnected, "{} < {}", reconnected, disconnected);
+    assert_eq!(still_open, None);
+
+    let (first_seen_now, last_seen_now) = seen(&server, "sensor");
+    assert_eq!(first_seen_now, first_seen);
+    assert!(last_seen_now > last_seen, "{} not after {}", last_seen_now, last_seen);
+
+    let clients = query::clients(&server.db(), &[], &[]).unwrap();
+    assert_eq!(clients.len(), 1);
+    assert_eq!((clients[0].ident.as_str(), clients[0].agent.as_deref()), ("sensor", Some(Hello::AGENT)));
+    assert!(clients[0].connected);
+    drop(client);
+}
+
+#[test]
+fn a_sensor_that_dies_has_its_connections_closed() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    for src in ["10.0.0.1:40000", "10.0.0.1:40001"] {
+        client.send(&Message::Starting(state(src, "10.0.0.2:443", Protocol::Tcp))).unwrap();
+        client.send(&Message::Active(state(src, "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    }
+    client.send(&Message::Ended(state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp), Closed::Normally)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM active_now", 1, WAIT));
+    // Gone without a word, as a sensor that's killed is
+    drop(client);
+
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+    let (_, disconnected, _) = sessions(&server, "sensor")[0];
+    let closes: Vec<(u16, u8, f64)> = server.db().prepare("SELECT srcport, state, conntime FROM state_all WHERE close = ? ORDER BY srcport").unwrap()
+        .query_map([TMOUT_MARK], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    // Only the one still open, in the state it was last seen in and stamped with when the sensor went
+    assert_eq!(closes, [(40000, ACTIVE_MARK, disconnected.unwrap())]);
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM active_now", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+}
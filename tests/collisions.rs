@@ -0,0 +1,116 @@
+//! Two clients on different addresses claiming the same ident, under each collision policy.
+
+use std::{net::{IpAddr, Ipv4Addr}, time::Duration};
+
+use glosco::{observe::{Message, Protocol}, server::CollisionPolicy, test_support::{state, TestClient, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+const FIRST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const SECOND: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+
+fn spawn(policy: CollisionPolicy) -> TestServer {
+    TestServer::spawn_with(|settings| settings.ident_collision = policy)
+}
+
+/// Connect from `from` as `sensor`, and wait until the collector has seen the hello.
+fn connect(server: &TestServer, from: IpAddr, sessions: i64) -> TestClient {
+    let mut client = server.client_from(from, "sensor");
+    client.hello(None).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions", sessions, WAIT));
+    client
+}
+
+fn starting(port: u16) -> Message {
+    Message::Starting(state(&format!("10.0.0.1:{}", port), "10.0.0.2:443", Protocol::Tcp))
+}
+
+/// Every session, oldest first: ident, the ident claimed if it collided, and whether it did.
+fn sessions(server: &TestServer) -> Vec<(Option<String>, Option<String>, bool)> {
+    server.db().prepare("SELECT ident, claimed, collision FROM client_sessions ORDER BY rowid").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+/// Which ident each source port was stored under.
+fn stored(server: &TestServer) -> Vec<(u16, String)> {
+    server.db().prepare("SELECT srcport, ident FROM state_all ORDER BY srcport").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn warn_accepts_both_under_the_one_ident() {
+    let server = spawn(CollisionPolicy::Warn);
+    let mut first = connect(&server, FIRST, 1);
+    let mut second = connect(&server, SECOND, 2);
+    first.send(&starting(40001)).unwrap();
+    second.send(&starting(40002)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 2, WAIT));
+
+    assert_eq!(stored(&server), [(40001, "sensor".to_string()), (40002, "sensor".to_string())]);
+    assert_eq!(sessions(&server), [
+        (Some("sensor".to_string()), None, false),
+        (Some("sensor".to_string()), Some("sensor".to_string()), true),
+    ]);
+}
+
+#[test]
+fn reject_turns_the_newcomer_away() {
+    let server = spawn(CollisionPolicy::Reject);
+    let mut first = connect(&server, FIRST, 1);
+    let mut second = connect(&server, SECOND, 2);
+    assert!(second.hung_up(WAIT), "the second client wasn't turned away");
+    first.send(&starting(40001)).unwrap();
+    let _ = second.send(&starting(40002));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+
+    assert_eq!(stored(&server), [(40001, "sensor".to_string())]);
+    assert_eq!(sessions(&server), [
+        (Some("sensor".to_string()), None, false),
+        (None, Some("sensor".to_string()), true),
+    ]);
+}
+
+#[test]
+fn suffix_renames_the_newcomer() {
+    let server = spawn(CollisionPolicy::Suffix);
+    let mut first = connect(&server, FIRST, 1);
+    let mut second = connect(&server, SECOND, 2);
+    // And a third, from yet another address, takes the next suffix
+    let mut third = connect(&server, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 3)), 3);
+    first.send(&starting(40001)).unwrap();
+    second.send(&starting(40002)).unwrap();
+    third.send(&starting(40003)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 3, WAIT));
+
+    assert_eq!(stored(&server), [
+        (40001, "sensor".to_string()),
+        (40002, "sensor#2".to_string()),
+        (40003, "sensor#3".to_string()),
+    ]);
+    assert_eq!(sessions(&server)[1 ..], [
+        (Some("sensor#2".to_string()), Some("sensor".to_string()), true),
+        (Some("sensor#3".to_string()), Some("sensor".to_string()), true),
+    ]);
+}
+
+#[test]
+fn the_same_address_twice_is_no_collision() {
+    let server = spawn(CollisionPolicy::Reject);
+    let _first = connect(&server, FIRST, 1);
+    let mut again = connect(&server, FIRST, 2);
+    assert!(!again.hung_up(Duration::from_millis(200)));
+    again.send(&starting(40001)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+    assert!(sessions(&server).iter().all(|(ident, _, collision)| ident.as_deref() == Some("sensor") && !collision));
+}
+
+#[test]
+fn the_ident_is_free_again_once_its_holder_leaves() {
+    let server = spawn(CollisionPolicy::Reject);
+    connect(&server, FIRST, 1).close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+    let mut second = connect(&server, SECOND, 2);
+    assert!(!second.hung_up(Duration::from_millis(200)));
+    assert_eq!(sessions(&server)[1], (Some("sensor".to_string()), None, false));
+}
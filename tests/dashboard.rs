@@ -0,0 +1,110 @@
+//! The dashboard a collector's API serves, and the endpoints its page polls.
+
+use std::{net::SocketAddr, time::{Duration, Instant}};
+
+use glosco::{observe::{Message, Problem, Protocol}, server::ApiSettings, test_support::{http, state, unused_addr, TestClient, TestServer}};
+use serde_json::Value;
+
+const WAIT: Duration = Duration::from_secs(5);
+const TOKEN: (&str, &str) = ("Authorization", "Bearer secret");
+
+/// A collector with the API behind a token, hearing from `east` (two connections open to
+/// 10.0.0.2, and three that failed, one at a time) and `west` (one to 10.0.0.3), both kept
+/// connected.
+fn fixture() -> (TestServer, SocketAddr, [TestClient; 2]) {
+    let bind = unused_addr();
+    let server = TestServer::spawn_with(|settings| settings.api = Some(ApiSettings {
+        bind,
+        token: Some("secret".to_string()),
+        ingest: false,
+        ingest_max_bytes: None,
+        ingest_rate: None,
+    }));
+    let mut east = server.client("east");
+    east.hello(None).unwrap();
+    east.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    east.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:22", Protocol::Tcp))).unwrap();
+    for port in 50000 .. 50003 {
+        east.send(&Message::Failed(state(&format!("10.0.0.1:{}", port), "10.0.0.9:80", Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 })).unwrap();
+        assert!(server.wait_for_count(&format!("SELECT COUNT(*) FROM state_all WHERE srcport = {}", port), 1, WAIT));
+    }
+    let mut west = server.client("west");
+    west.hello(None).unwrap();
+    west.send(&Message::Starting(state("10.0.1.1:40000", "10.0.0.3:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM active_now", 3, WAIT));
+    (server, bind, [east, west])
+}
+
+fn get(bind: SocketAddr, path: &str) -> Value {
+    let (status, body) = http(bind, "GET", path, &[TOKEN], "").unwrap();
+    assert_eq!(status, 200, "{}: {}", path, body);
+    serde_json::from_str(&body).unwrap()
+}
+
+/// Each row's `field`, as a string.
+fn column(rows: &Value, field: &str) -> Vec<String> {
+    rows.as_array().unwrap().iter().map(|row| match &row[field] {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }).collect()
+}
+
+#[test]
+fn the_page_is_served_without_a_token_and_polls_the_endpoints() {
+    let (_server, bind, _clients) = fixture();
+    let (status, page) = http(bind, "GET", "/", &[], "").unwrap();
+    assert_eq!(status, 200, "{}", page);
+    assert!(page.starts_with("<!DOCTYPE html>"), "{}", page);
+    for path in ["/v1/sensors", "/v1/active?", "/v1/failures?limit="] {
+        assert!(page.contains(&format!("get(\"{}", path)), "the page doesn't poll {}", path);
+    }
+    // The tables it fills in
+    for id in ["sensors", "active", "failures"] {
+        assert!(page.contains(&format!("id=\"{}\"", id)), "no #{}", id);
+    }
+    let (status, _) = http(bind, "POST", "/", &[], "").unwrap();
+    assert_eq!(status, 405);
+}
+
+#[test]
+fn what_the_page_polls_needs_the_token() {
+    let (_server, bind, _clients) = fixture();
+    for path in ["/v1/sensors", "/v1/active", "/v1/failures"] {
+        assert_eq!(http(bind, "GET", path, &[], "").unwrap().0, 401, "{}", path);
+        assert_eq!(http(bind, "GET", path, &[("Authorization", "Bearer wrong")], "").unwrap().0, 401, "{}", path);
+    }
+}
+
+#[test]
+fn sensors_and_their_active_connections_with_ages() {
+    let started = Instant::now();
+    let (_server, bind, _clients) = fixture();
+    let sensors = get(bind, "/v1/sensors");
+    let mut idents = column(&sensors, "ident");
+    idents.sort();
+    assert_eq!(idents, ["east", "west"]);
+
+    let active = get(bind, "/v1/active");
+    assert_eq!(column(&active, "ident"), ["east", "east", "west"]);
+    for row in active.as_array().unwrap() {
+        let age = row["age"].as_f64().unwrap();
+        assert!((0.0 ..= started.elapsed().as_secs_f64() + 1.0).contains(&age), "{}", row);
+    }
+    // Filtered by either end's host or port, as the page's boxes do
+    assert_eq!(column(&get(bind, "/v1/active?host=10.0.0.2"), "dstport"), ["443", "22"]);
+    assert_eq!(column(&get(bind, "/v1/active?host=10.0.1.1"), "ident"), ["west"]);
+    assert_eq!(column(&get(bind, "/v1/active?port=443"), "srchost"), ["10.0.0.1", "10.0.1.1"]);
+    assert_eq!(column(&get(bind, "/v1/active?port=40001"), "dstport"), ["22"]);
+    assert_eq!(column(&get(bind, "/v1/active?host=10.0.0.2&port=443"), "srcport"), ["40000"]);
+    assert!(get(bind, "/v1/active?host=10.9.9.9").as_array().unwrap().is_empty());
+}
+
+#[test]
+fn the_failure_tail_is_newest_first_and_as_long_as_asked() {
+    let (_server, bind, _clients) = fixture();
+    assert_eq!(column(&get(bind, "/v1/failures"), "srcport"), ["50002", "50001", "50000"]);
+    let tail = get(bind, "/v1/failures?limit=2");
+    assert_eq!(column(&tail, "srcport"), ["50002", "50001"]);
+    assert_eq!(column(&tail, "dsthost"), ["10.0.0.9", "10.0.0.9"]);
+    assert_eq!(column(&tail, "pkind"), ["3", "3"]);
+}
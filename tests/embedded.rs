@@ -0,0 +1,77 @@
+//! The library's entry points, run in-process as an embedder would: `run_server` accepting
+//! sensors until its handle shuts it down, and `run_client` reporting a capture to it.
+
+use std::{net::{SocketAddr, TcpStream}, thread, time::{Duration, Instant, SystemTime}};
+
+use glosco::{db, observe::{Message, Protocol}, test_support::{state, tcp_frame, write_pcap, Scratch, TestClient, ACK, FIN, SYN}, ClientSettings, ServerHandle, ServerSettings};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn run_server(scratch: &Scratch) -> ServerHandle {
+    glosco::run_server(ServerSettings {
+        bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+        database: scratch.database().to_string(),
+        ..Default::default()
+    }).unwrap()
+}
+
+/// The state rows stored, as (ident, srcport, state, close), once there are at least `count`.
+fn rows(scratch: &Scratch, count: usize) -> Vec<(String, u16, u8, Option<u8>)> {
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let rows: Vec<_> = db::open_read_only(scratch.path()).and_then(|db| {
+            db.prepare("SELECT ident, srcport, state, close FROM state_all ORDER BY srcport, instime")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+                .collect()
+        }).unwrap_or_default();
+        if rows.len() >= count || Instant::now() >= deadline {
+            return rows;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn run_server_accepts_sensors_until_its_handle_shuts_it_down() {
+    let scratch = Scratch::new("server");
+    let server = run_server(&scratch);
+    let addr = server.local_addr();
+    let mut client = TestClient::connect(addr, "sensor").unwrap();
+    client.hello(None).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert_eq!(rows(&scratch, 1), [("sensor".to_string(), 40000, 5, None)]);
+
+    server.shutdown();
+    server.join();
+    assert!(TcpStream::connect(addr).is_err(), "still accepting after shutting down");
+}
+
+#[test]
+fn run_client_reports_a_capture_to_run_server_and_stops_at_its_end() {
+    let scratch = Scratch::new("client");
+    let server = run_server(&scratch);
+    let capture = scratch.path().with_extension("pcap");
+    let captured = SystemTime::now() - Duration::from_secs(60);
+    write_pcap(&capture, &[
+        (captured, tcp_frame("10.0.0.1:40000", "10.0.0.2:443", SYN)),
+        (captured + Duration::from_secs(2), tcp_frame("10.0.0.1:40000", "10.0.0.2:443", FIN | ACK)),
+    ]).unwrap();
+
+    let client = glosco::run_client(ClientSettings {
+        captures: vec![capture.clone()],
+        remotes: vec![server.local_addr()],
+        ident: Some("embedded".to_string()),
+        snapshot_interval: None,
+        ..Default::default()
+    }).unwrap();
+    // A capture file runs out, and the sensor stops by itself once it's delivered everything
+    assert!(client.join(), "not everything reached the collector");
+    assert_eq!(rows(&scratch, 2), [
+        ("embedded".to_string(), 40000, 5, None),
+        ("embedded".to_string(), 40000, 2, Some(1)),
+    ]);
+
+    server.shutdown();
+    server.join();
+    let _ = std::fs::remove_file(capture);
+}
@@ -0,0 +1,101 @@
+//! The event log, as a collector writes it: one line for every message it stores, saying what
+//! the row says.
+
+use std::time::{Duration, SystemTime};
+
+use glosco::{coding::Coder, observe::{Closed, Message, Problem, Protocol}, test_support::{state, TestServer}};
+use serde::Deserialize;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    ident: String,
+    peer: String,
+    received: f64,
+    message: Message,
+}
+
+/// The event log's records once it has `count`, parsed.
+fn records(server: &TestServer, count: usize) -> Vec<Record> {
+    server.logged(count, WAIT).into_iter().map(|record| serde_json::from_value(record).expect("every line is a record")).collect()
+}
+
+/// The columns of a state row that an event says something about.
+#[derive(Debug, PartialEq)]
+struct Row {
+    ident: String,
+    peer: String,
+    instime: f64,
+    conntime: f64,
+    srchost: String,
+    srcport: u16,
+    dsthost: String,
+    dstport: u16,
+    proto: u8,
+}
+
+fn secs(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+#[test]
+fn each_record_matches_its_row() {
+    let server = TestServer::spawn_logging();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    let sent = [
+        Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp)),
+        Message::Ended(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp), Closed::Reset),
+        Message::Failed(state("10.0.0.1:5000", "10.0.0.3:53", Protocol::Udp), Problem { kind: 3, code: 3, repeats: 0 }),
+    ];
+    for message in &sent {
+        client.send(message).unwrap();
+    }
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 3, WAIT));
+
+    let records = records(&server, 3);
+    assert_eq!(records.len(), 3);
+    let db = server.db();
+    let mut rows = db.prepare("
+        SELECT ident, peer, instime, conntime, srchost, srcport, dsthost, dstport, proto
+        FROM state ORDER BY rowid
+    ").unwrap();
+    let rows: Vec<Row> = rows
+        .query_map([], |row| Ok(Row {
+            ident: row.get(0)?, peer: row.get(1)?, instime: row.get(2)?, conntime: row.get(3)?,
+            srchost: row.get(4)?, srcport: row.get(5)?, dsthost: row.get(6)?, dstport: row.get(7)?, proto: row.get(8)?,
+        }))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    for ((record, row), message) in records.iter().zip(rows.iter()).zip(sent.iter()) {
+        assert_eq!(&record.message, message);
+        let conn = record.message.state().connection;
+        // JSON floats needn't read back to the last bit
+        assert!((row.instime - record.received).abs() < 1e-6, "stored at {}, logged as received at {}", row.instime, record.received);
+        assert_eq!(row, &Row {
+            ident: record.ident.clone(), peer: record.peer.clone(), instime: row.instime, conntime: secs(record.message.state().as_of),
+            srchost: conn.src.addr.to_string(), srcport: conn.src.port, dsthost: conn.dst.addr.to_string(), dstport: conn.dst.port, proto: conn.protocol.iana_number(),
+        });
+    }
+}
+
+#[test]
+fn a_replayed_frame_is_stored_and_logged_once() {
+    let server = TestServer::spawn_logging();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    let mut frame = Vec::new();
+    Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp)).encode(&mut frame).unwrap();
+    client.send_frame(&frame).unwrap();
+    client.send_frame(&frame).unwrap();
+    // Something after the replay, so that once it's in, the replay has been dealt with
+    client.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 2, WAIT));
+
+    let records = records(&server, 2);
+    let ports: Vec<u16> = records.iter().map(|record| record.message.state().connection.src.port).collect();
+    assert_eq!(ports, [40000, 40001]);
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM state_all WHERE srcport = 40000", [], |row| row.get::<_, i64>(0)).unwrap(), 1);
+}
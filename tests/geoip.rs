@@ -0,0 +1,50 @@
+//! Destination country and ASN from MaxMind databases, and picking up new ones on SIGHUP.
+#![cfg(all(unix, feature = "geoip"))]
+
+use std::{fs, time::{Duration, Instant}};
+
+use glosco::{observe::{Message, Protocol}, test_support::{state, write_mmdb, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[test]
+fn rows_are_located_with_whatever_database_the_last_sighup_found() {
+    let dir = std::env::temp_dir().join(format!("glosco-geoip-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let country = dir.join("country.mmdb");
+    let asn = dir.join("asn.mmdb");
+    write_mmdb(&country, &[("192.0.2.0/24", Some("NZ"), None)]).unwrap();
+    write_mmdb(&asn, &[("192.0.2.0/24", None, Some(64500))]).unwrap();
+    let server = TestServer::spawn_with(|settings| settings.geoip = vec![country.clone(), asn.clone()]);
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+
+    client.send(&Message::Starting(state("10.0.0.1:40000", "192.0.2.1:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE dstcountry = 'NZ' AND dstasn = 64500", 1, WAIT));
+    client.send(&Message::Starting(state("10.0.0.1:40001", "198.51.100.1:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE srcport = 40001 AND dstcountry IS NULL AND dstasn IS NULL", 1, WAIT));
+
+    // This week's update, which the collector wouldn't look for on its own for a minute yet
+    let partial = dir.join("country.mmdb.partial");
+    write_mmdb(&partial, &[("192.0.2.0/24", Some("AU"), None)]).unwrap();
+    fs::rename(&partial, &country).unwrap();
+    // Safety: the collector has had its handler in place since it started storing rows.
+    unsafe {
+        libc::raise(libc::SIGHUP);
+    }
+    let started = Instant::now();
+    let mut port = 41000;
+    let reopened = loop {
+        client.send(&Message::Starting(state(&format!("10.0.0.1:{}", port), "192.0.2.1:443", Protocol::Tcp))).unwrap();
+        if server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE dstcountry = 'AU' AND dstasn = 64500", 1, Duration::from_millis(200)) {
+            break true;
+        }
+        if started.elapsed() > WAIT {
+            break false;
+        }
+        port += 1;
+    };
+    assert!(reopened, "the new database wasn't picked up");
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,65 @@
+//! The test harness itself: servers spawned at once get ports and databases of their own, and
+//! go away whole when dropped.
+
+use std::{collections::HashSet, net::TcpStream, thread, time::Duration};
+
+use glosco::{observe::{Message, Protocol}, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[test]
+fn servers_spawned_at_once_get_ports_and_databases_of_their_own() {
+    let servers: Vec<TestServer> = (0 .. 8)
+        .map(|_| thread::spawn(TestServer::spawn))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|spawning| spawning.join().expect("spawn panicked"))
+        .collect();
+    let ports: HashSet<u16> = servers.iter().map(|server| server.addr().port()).collect();
+    assert_eq!(ports.len(), servers.len());
+    let databases: HashSet<_> = servers.iter().map(|server| server.database().to_path_buf()).collect();
+    assert_eq!(databases.len(), servers.len());
+
+    // Each stores what its own client sends, and nothing of anyone else's
+    for (idx, server) in servers.iter().enumerate() {
+        let mut client = server.client(&format!("sensor-{}", idx));
+        client.hello(None).unwrap();
+        client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    }
+    for (idx, server) in servers.iter().enumerate() {
+        assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+        let idents: Vec<String> = server.db().prepare("SELECT DISTINCT ident FROM state_all").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(idents, vec![format!("sensor-{}", idx)]);
+    }
+}
+
+#[test]
+fn dropping_a_server_stops_it_and_removes_its_directory() {
+    let server = TestServer::spawn();
+    let addr = server.addr();
+    let dir = server.database().parent().unwrap().to_path_buf();
+    assert!(dir.is_dir());
+    drop(server);
+    assert!(!dir.exists());
+    assert!(TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_err());
+}
+
+#[test]
+fn waiting_for_rows_that_never_come_gives_up() {
+    let server = TestServer::spawn();
+    assert!(!server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, Duration::from_millis(100)));
+}
+
+#[test]
+fn garbage_from_one_client_leaves_the_rest_alone() {
+    let server = TestServer::spawn();
+    let mut bad = server.client("bad");
+    bad.send_frame(&[0xff; 16]).unwrap();
+    bad.send_raw(&[0xff; 3]).unwrap();
+    let mut good = server.client("good");
+    good.hello(None).unwrap();
+    good.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'good'", 1, WAIT));
+}
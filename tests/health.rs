@@ -0,0 +1,70 @@
+//! Sensors reporting how capture's going, and the collector keeping the latest of it.
+
+use std::time::{Duration, SystemTime};
+
+use glosco::{observe::{InterfaceCounts, Stats}, query, server::ApiSettings, test_support::{http, unused_addr, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn counts(name: &str, packets: u64, dropped: u64, if_dropped: u64) -> InterfaceCounts {
+    InterfaceCounts { name: name.to_string(), packets, dropped, if_dropped, unparsed: 1 }
+}
+
+/// Every interface recorded for `ident`: name, received, dropped, dropped by the interface.
+fn health(server: &TestServer, ident: &str) -> Vec<(String, i64, i64, i64)> {
+    server.db().prepare("SELECT interface, received, dropped, if_dropped FROM sensor_health WHERE ident = ? ORDER BY interface").unwrap()
+        .query_map([ident], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn each_report_replaces_the_last() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&Stats { as_of: SystemTime::now(), interfaces: vec![counts("eth0", 900, 50, 50), counts("eth1", 10, 0, 0)] }).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM sensor_health", 2, WAIT));
+    assert_eq!(health(&server, "sensor"), [("eth0".to_string(), 900, 50, 50), ("eth1".to_string(), 10, 0, 0)]);
+
+    // eth1 went away
+    client.send(&Stats { as_of: SystemTime::now(), interfaces: vec![counts("eth0", 1800, 100, 100)] }).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM sensor_health WHERE received = 1800", 1, WAIT));
+    assert_eq!(health(&server, "sensor"), [("eth0".to_string(), 1800, 100, 100)]);
+
+    let clients = query::clients(&server.db(), &[], &[]).unwrap();
+    assert_eq!(clients[0].health.len(), 1);
+    assert_eq!(clients[0].drop_rate, Some(0.1));
+}
+
+#[test]
+fn a_sensor_that_never_reported_has_no_drop_rate() {
+    let server = TestServer::spawn();
+    let mut client = server.client("quiet");
+    client.hello(None).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients", 1, WAIT));
+
+    let clients = query::clients(&server.db(), &[], &[]).unwrap();
+    assert!(clients[0].health.is_empty());
+    assert_eq!(clients[0].drop_rate, None);
+}
+
+#[test]
+fn drop_rate_is_a_gauge_on_the_api() {
+    let bind = unused_addr();
+    let server = TestServer::spawn_with(|settings| settings.api = Some(ApiSettings {
+        bind,
+        token: None,
+        ingest: false,
+        ingest_max_bytes: None,
+        ingest_rate: None,
+    }));
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&Stats { as_of: SystemTime::now(), interfaces: vec![counts("eth0", 900, 50, 50)] }).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM sensor_health", 1, WAIT));
+
+    let (status, body) = http(bind, "GET", "/metrics", &[], "").unwrap();
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.contains("# TYPE glosco_sensor_drop_rate gauge"), "{}", body);
+    assert!(body.lines().any(|line| line == "glosco_sensor_drop_rate{ident=\"sensor\"} 0.1"), "{}", body);
+}
@@ -0,0 +1,108 @@
+//! Liveness probes: `/healthz` on the API, and `glosco healthcheck` for when there's no HTTP,
+//! each healthy and stale.
+
+use std::{process::Command, sync::Arc, thread, time::Duration};
+
+use glosco::{api::{ApiConfig, Heartbeat}, db, observe::{Message, Protocol}, server::ApiSettings, test_support::{http, state, unused_addr, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn healthz(bind: std::net::SocketAddr) -> (u16, String) {
+    http(bind, "GET", "/healthz", &[], "").unwrap()
+}
+
+/// `glosco healthcheck` on `database` with `args`, as exit status and what it printed.
+fn healthcheck(database: &str, args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_glosco"))
+        .args(["healthcheck", "--database", database])
+        .args(args)
+        .output()
+        .unwrap();
+    (output.status.code(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn healthz_is_ok_as_long_as_maintenance_keeps_ticking() {
+    let bind = unused_addr();
+    let _server = TestServer::spawn_with(|settings| {
+        settings.maintenance = 0.1;
+        settings.api = Some(ApiSettings { bind, token: Some("secret".to_string()), ingest: false, ingest_max_bytes: None, ingest_rate: None });
+    });
+    // Well past three periods, which it would be stale after if it weren't ticking; and no
+    // token needed
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(healthz(bind), (200, "ok\n".to_string()));
+}
+
+#[test]
+fn healthz_fails_once_maintenance_misses_three_ticks() {
+    let server = TestServer::spawn();
+    let bind = unused_addr();
+    let heartbeat = Arc::new(Heartbeat::new(Duration::from_millis(100)));
+    let mut api = ApiConfig::new(bind, server.database().to_string_lossy().into_owned());
+    api.set_heartbeat(heartbeat.clone());
+    api.start().unwrap();
+    assert_eq!(healthz(bind).0, 200);
+
+    thread::sleep(Duration::from_millis(400));
+    let (status, body) = healthz(bind);
+    assert_eq!(status, 503);
+    assert!(body.starts_with("maintenance last ticked "), "{}", body);
+
+    heartbeat.beat(Duration::from_millis(100));
+    assert_eq!(healthz(bind).0, 200);
+}
+
+#[test]
+fn healthz_fails_while_nothing_can_write_the_database() {
+    let server = TestServer::spawn();
+    let bind = unused_addr();
+    ApiConfig::new(bind, server.database().to_string_lossy().into_owned()).start().unwrap();
+
+    let holder = db::open(server.database()).unwrap();
+    holder.execute_batch("BEGIN IMMEDIATE;").unwrap();
+    // After waiting out the busy timeout for the lock
+    let (status, body) = healthz(bind);
+    assert_eq!(status, 503);
+    assert_eq!(body, "database not writable: database is locked\n");
+
+    holder.execute_batch("ROLLBACK;").unwrap();
+    assert_eq!(healthz(bind).0, 200);
+}
+
+#[test]
+fn healthcheck_passes_a_fresh_database_and_fails_a_stale_one() {
+    let server = TestServer::spawn();
+    let database = server.database().to_string_lossy().into_owned();
+    let (status, printed) = healthcheck(&database, &[]);
+    assert_eq!((status, printed.as_str()), (Some(1), "stale: nothing written yet\n"));
+
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+    let (status, printed) = healthcheck(&database, &[]);
+    assert_eq!(status, Some(0), "{}", printed);
+    assert!(printed.starts_with("ok: last write "), "{}", printed);
+
+    // Ten minutes without a word, from the client or anything else
+    client.close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+    server.db().execute_batch("
+        UPDATE state SET instime = instime - 600;
+        UPDATE clients SET last_seen = last_seen - 600;
+    ").unwrap();
+    let (status, printed) = healthcheck(&database, &[]);
+    assert_eq!(status, Some(1), "{}", printed);
+    assert_eq!(printed, "stale: last write 600s ago\n");
+    let (status, printed) = healthcheck(&database, &["--max-age", "900"]);
+    assert_eq!(status, Some(0), "{}", printed);
+}
+
+#[test]
+fn healthcheck_fails_a_database_it_cant_read() {
+    let missing = std::env::temp_dir().join(format!("glosco-healthz-{}-missing.db", std::process::id()));
+    let (status, printed) = healthcheck(&missing.to_string_lossy(), &[]);
+    assert_eq!(status, Some(1));
+    assert!(printed.starts_with("unreadable: "), "{}", printed);
+}
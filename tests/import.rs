@@ -0,0 +1,81 @@
+//! `glosco import`: a capture from before there was a sensor, read straight into a database as
+//! of its own timestamps. `data/import.pcap` holds a lookup of example.com answered at
+//! 2025-06-12T10:00:00Z, then a connection to it opened half a second later and closed at
+//! 10:00:02.25.
+
+use std::process::Command;
+
+use glosco::{db, test_support::Scratch};
+
+const CAPTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/import.pcap");
+const CAPTURED: f64 = 1749722400.0;
+
+/// A row as (ident, peer, srchost, srcport, dsthost, dstport, state, close, conntime, instime).
+type Row = (String, String, String, u16, String, u16, u8, Option<u8>, f64, f64);
+
+/// `glosco import` of the bundled capture with `args`: whether it succeeded and what it printed.
+fn import(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_glosco"))
+        .args(["import", "--pcap", CAPTURE])
+        .args(args)
+        .output()
+        .unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn a_capture_is_stored_under_its_ident_as_of_when_it_was_captured() {
+    let scratch = Scratch::new("stored");
+    let (succeeded, printed) = import(&["--ident", "archive", "--database", scratch.database()]);
+    assert!(succeeded, "{}", printed);
+    assert!(printed.ends_with("starting: 1\nended: 2\nname: 1\n4 messages, 4 stored under archive (0 duplicates)\n"), "{}", printed);
+
+    let db = db::open_read_only(scratch.path()).unwrap();
+    // The lookup, ended as soon as seen (connectionless, 3), and the connection's start (5) and
+    // its end (2, normally, 1), each stored as of the packet that said so
+    let rows: Vec<Row> = db.prepare("
+        SELECT ident, peer, srchost, srcport, dsthost, dstport, state, close, conntime, instime FROM state_all
+        ORDER BY conntime, srcport
+    ").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?, row.get(9)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    let row = |srchost: &str, srcport, dsthost: &str, dstport, state, close, at: f64| {
+        ("archive".to_string(), "import".to_string(), srchost.to_string(), srcport, dsthost.to_string(), dstport, state, close, CAPTURED + at, CAPTURED + at)
+    };
+    assert_eq!(rows, [
+        row("10.0.0.53", 53, "10.0.0.1", 5353, 2, Some(3), 0.0),
+        row("10.0.0.1", 40000, "93.184.216.34", 443, 5, None, 0.5),
+        row("10.0.0.1", 40000, "93.184.216.34", 443, 2, Some(1), 2.25),
+    ]);
+
+    // The name, asked after and answered, with whoever asked it
+    let names: Vec<(f64, String, String, String, Option<String>)> = db
+        .prepare("SELECT instime, querier, responder, name, addr FROM names ORDER BY addr IS NOT NULL").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    let name = |addr: Option<&str>| (CAPTURED, "10.0.0.1".to_string(), "10.0.0.53".to_string(), "example.com".to_string(), addr.map(str::to_string));
+    assert_eq!(names, [name(None), name(Some("93.184.216.34"))]);
+
+    // And the ident, as seen over the capture's span
+    let seen: (f64, f64) = db.query_row("SELECT first_seen, last_seen FROM clients WHERE ident = 'archive'", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!(seen, (CAPTURED, CAPTURED + 2.25));
+}
+
+#[test]
+fn a_dry_run_counts_without_touching_the_database() {
+    let scratch = Scratch::new("dry");
+    let (succeeded, printed) = import(&["--ident", "archive", "--database", scratch.database(), "--dry-run"]);
+    assert!(succeeded, "{}", printed);
+    assert!(printed.ends_with("starting: 1\nended: 2\nname: 1\n4 messages (dry run, nothing stored)\n"), "{}", printed);
+    assert!(!scratch.path().exists());
+}
+
+#[test]
+fn a_capture_that_cant_be_read_fails_the_import_before_anything_is_stored() {
+    let scratch = Scratch::new("unreadable");
+    let missing = Scratch::file("missing.pcap");
+    let (succeeded, printed) = import(&["--pcap", missing.path().to_str().unwrap(), "--ident", "archive", "--database", scratch.database()]);
+    assert!(!succeeded, "{}", printed);
+    assert!(!printed.contains("stored"), "{}", printed);
+    assert!(!scratch.path().exists());
+}
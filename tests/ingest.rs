@@ -0,0 +1,216 @@
+//! What a collector makes of what sensors send it, over the wire, as the harness sees it.
+
+use std::{net::{IpAddr, SocketAddr}, thread, time::{Duration, Instant}};
+
+use glosco::{observe::{Closed, Message, Name, Protocol, Resolution}, server::{ApiSettings, CollisionPolicy}, test_support::{http, state, unused_addr, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[test]
+fn a_sensor_that_reconnects_keeps_its_rows() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+    client.close();
+
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'sensor'", 2, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE ident = 'sensor'", 2, WAIT));
+}
+
+#[test]
+fn a_second_connection_from_the_same_address_is_no_collision() {
+    let server = TestServer::spawn_with(|settings| settings.ident_collision = CollisionPolicy::Reject);
+    let mut first = server.client("sensor");
+    first.hello(None).unwrap();
+    first.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+
+    // Still connected, but a sensor restarting comes back from where it was
+    let mut second = server.client("sensor");
+    second.hello(None).unwrap();
+    second.send(&Message::Starting(state("10.0.0.3:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'sensor' AND srchost = '10.0.0.3'", 1, WAIT));
+}
+
+#[test]
+fn every_message_of_a_burst_is_stored() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    for port in 0 .. 500 {
+        let src = format!("10.0.0.1:{}", 20000 + port);
+        client.send(&Message::Starting(state(&src, "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    }
+    assert!(server.wait_for_count("SELECT COUNT(DISTINCT srcport) FROM state_all", 500, WAIT));
+}
+
+#[test]
+fn names_are_stored_querier_first() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    let answer = "93.184.216.34".parse::<IpAddr>().unwrap();
+    client.send(&Message::Name(state("10.0.0.53:53", "10.0.0.1:5353", Protocol::Udp), vec![
+        Name { name: "example.com".to_string(), address: Some(Resolution::Address(answer)) },
+    ])).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM names", 1, WAIT));
+    let (querier, responder, name, addr): (String, String, String, String) = server.db()
+        .query_row("SELECT querier, responder, name, addr FROM names", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+        .unwrap();
+    assert_eq!((querier.as_str(), responder.as_str(), name.as_str(), addr.as_str()), ("10.0.0.1", "10.0.0.53", "example.com", "93.184.216.34"));
+}
+
+#[cfg(feature = "async-server")]
+#[test]
+fn the_async_server_stores_what_the_threaded_one_does() {
+    let server = TestServer::spawn_with(|settings| settings.async_io = true);
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    client.send(&Message::Name(state("10.0.0.53:53", "10.0.0.1:5353", Protocol::Udp), vec![
+        Name { name: "example.com".to_string(), address: None },
+    ])).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'sensor'", 1, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM names WHERE name = 'example.com'", 1, WAIT));
+}
+
+const TOKEN: &str = "s3cret";
+
+/// A collector taking NDJSON on `/v1/ingest` behind `TOKEN`, and where its API listens.
+fn posting_to(max_bytes: Option<usize>, rate: Option<f64>) -> (TestServer, SocketAddr) {
+    let bind = unused_addr();
+    let server = TestServer::spawn_with(|settings| settings.api = Some(ApiSettings {
+        bind,
+        token: Some(TOKEN.to_string()),
+        ingest: true,
+        ingest_max_bytes: max_bytes,
+        ingest_rate: rate,
+    }));
+    // The API comes up on a thread of its own, a moment after the client port
+    let start = Instant::now();
+    while http(bind, "GET", "/healthz", &[], "").is_err() {
+        assert!(start.elapsed() < WAIT, "the API never came up");
+        thread::sleep(Duration::from_millis(20));
+    }
+    (server, bind)
+}
+
+/// POST `lines` to `/v1/ingest`, `query` and all, with the token and `headers`.
+fn post(bind: SocketAddr, query: &str, headers: &[(&str, &str)], lines: &[String]) -> (u16, String) {
+    let auth = format!("Bearer {}", TOKEN);
+    let mut all = vec![("Authorization", auth.as_str()), ("Content-Type", "application/x-ndjson")];
+    all.extend(headers);
+    http(bind, "POST", &format!("/v1/ingest{}", query), &all, &lines.join("\n")).unwrap()
+}
+
+fn ndjson(messages: &[Message]) -> Vec<String> {
+    messages.iter().map(|message| serde_json::to_string(message).unwrap()).collect()
+}
+
+fn stored(server: &TestServer) -> Vec<(String, u16, i64)> {
+    server.db().prepare("SELECT ident, srcport, state FROM state_all ORDER BY instime, srcport").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn posted_messages_are_stored_as_if_sent_over_the_wire() {
+    let (server, bind) = posting_to(None, None);
+    let mut lines = ndjson(&[
+        Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp)),
+        Message::Starting(state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp)),
+    ]);
+    // Blank lines are skipped over
+    lines.push(String::new());
+    lines.extend(ndjson(&[Message::Ended(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp), Closed::Normally)]));
+    let (status, body) = post(bind, "", &[("X-Glosco-Ident", "appliance")], &lines);
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), serde_json::json!({ "received": 3 }));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 3, WAIT));
+
+    // And the same from a sensor over the wire, under an ident of its own
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    client.send(&Message::Ended(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp), Closed::Normally)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 6, WAIT));
+    let rows = stored(&server);
+    let posted: Vec<(u16, i64)> = rows.iter().filter(|(ident, _, _)| ident == "appliance").map(|(_, port, state)| (*port, *state)).collect();
+    let sent: Vec<(u16, i64)> = rows.iter().filter(|(ident, _, _)| ident == "sensor").map(|(_, port, state)| (*port, *state)).collect();
+    assert_eq!(posted, sent);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM latest_state WHERE ident = 'appliance'", 2, WAIT));
+}
+
+#[test]
+fn the_ident_can_be_a_query_parameter() {
+    let (server, bind) = posting_to(None, None);
+    let (status, body) = post(bind, "?ident=lab-box", &[], &ndjson(&[Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))]));
+    assert_eq!(status, 200, "{}", body);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'lab-box'", 1, WAIT));
+}
+
+#[test]
+fn a_malformed_line_refuses_the_whole_request() {
+    let (server, bind) = posting_to(None, None);
+    let good = ndjson(&[Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))]).remove(0);
+    for (bad, complaint) in [
+        ("{\"Starting\":", "line 2: EOF while parsing"),
+        ("{\"Exploded\":{}}", "line 2: unknown variant `Exploded`"),
+        ("[1, 2, 3]", "line 2:"),
+    ] {
+        let (status, body) = post(bind, "", &[("X-Glosco-Ident", "appliance")], &[good.clone(), bad.to_string(), good.clone()]);
+        assert_eq!(status, 400, "{:?}: {}", bad, body);
+        assert!(body.starts_with(complaint), "{:?}: {}", bad, body);
+    }
+    // None of it, the good lines included; a request that's fine afterwards is stored
+    let (status, _) = post(bind, "", &[("X-Glosco-Ident", "appliance")], std::slice::from_ref(&good));
+    assert_eq!(status, 200);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(stored(&server).len(), 1);
+}
+
+#[test]
+fn requests_without_the_token_or_an_ident_are_refused() {
+    let (server, bind) = posting_to(None, None);
+    let lines = ndjson(&[Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))]);
+    let (status, _) = http(bind, "POST", "/v1/ingest?ident=appliance", &[], &lines[0]).unwrap();
+    assert_eq!(status, 401);
+    let (status, _) = http(bind, "POST", "/v1/ingest?ident=appliance", &[("Authorization", "Bearer guess")], &lines[0]).unwrap();
+    assert_eq!(status, 401);
+    let (status, body) = post(bind, "", &[], &lines);
+    assert_eq!((status, body.as_str()), (400, "no ident given\n"));
+    let (status, _) = http(bind, "GET", "/v1/ingest?ident=appliance", &[("Authorization", &format!("Bearer {}", TOKEN))], "").unwrap();
+    assert_eq!(status, 405);
+    thread::sleep(Duration::from_millis(100));
+    assert!(stored(&server).is_empty());
+}
+
+#[test]
+fn bodies_over_the_limit_are_refused() {
+    let (server, bind) = posting_to(Some(256), None);
+    let lines = ndjson(&(0 .. 10).map(|port| Message::Starting(state(&format!("10.0.0.1:{}", 40000 + port), "10.0.0.2:443", Protocol::Tcp))).collect::<Vec<_>>());
+    let (status, body) = post(bind, "", &[("X-Glosco-Ident", "appliance")], &lines);
+    assert_eq!((status, body.as_str()), (413, "body is over 256 bytes\n"));
+    let (status, _) = post(bind, "", &[("X-Glosco-Ident", "appliance")], &lines[.. 1]);
+    assert_eq!(status, 200);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+}
+
+#[test]
+fn a_source_over_its_rate_is_told_to_wait() {
+    let (server, bind) = posting_to(None, Some(2.0));
+    let lines = ndjson(&(0 .. 4).map(|port| Message::Starting(state(&format!("10.0.0.1:{}", 40000 + port), "10.0.0.2:443", Protocol::Tcp))).collect::<Vec<_>>());
+    // A request can overdraw what's left, but the next waits for it to be paid back
+    let (status, _) = post(bind, "", &[("X-Glosco-Ident", "appliance")], &lines);
+    assert_eq!(status, 200);
+    let (status, body) = post(bind, "", &[("X-Glosco-Ident", "appliance")], &lines);
+    assert_eq!((status, body.as_str()), (429, "rate limited\n"));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 4, WAIT));
+}
@@ -0,0 +1,102 @@
+//! The interfaces a sensor announces it captures on, as the collector keeps them.
+
+use std::time::{Duration, SystemTime};
+
+use glosco::{observe::{InterfaceInfo, Namespace}, sync::Sequence, test_support::TestServer};
+use pcap::{Address, Device, DeviceFlags, IfFlags};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// eth0, up and running with an IPv4 address, a link-local IPv6 one, and one with no netmask.
+fn eth0() -> Device {
+    let address = |addr: &str, netmask: Option<&str>| Address {
+        addr: addr.parse().unwrap(),
+        netmask: netmask.map(|mask| mask.parse().unwrap()),
+        broadcast_addr: None,
+        dst_addr: None,
+    };
+    Device {
+        name: "eth0".to_string(),
+        desc: None,
+        addresses: vec![
+            address("10.3.0.9", Some("255.255.255.0")),
+            address("fe80::1", Some("ffff:ffff:ffff:ffff::")),
+            address("192.0.2.1", None),
+        ],
+        flags: DeviceFlags::from((IfFlags::UP | IfFlags::RUNNING).bits()),
+    }
+}
+
+/// An announcement of `devices`, with no link-layer address or MTU whatever the host running
+/// the test has by the same names.
+fn namespace(generation: u32, devices: &[Device]) -> Namespace {
+    let interfaces = devices.iter().map(|dev| InterfaceInfo { mac: None, mtu: None, ..InterfaceInfo::from(dev) }).collect();
+    Namespace { generation, as_of: SystemTime::now(), interfaces }
+}
+
+/// Every interface recorded for `ident`: generation, index, name, flags and addresses.
+fn interfaces(server: &TestServer, ident: &str) -> Vec<(u32, u32, String, u32, String)> {
+    server.db().prepare("SELECT generation, idx, name, flags, addresses FROM interfaces WHERE ident = ? ORDER BY generation, idx").unwrap()
+        .query_map([ident], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn every_address_of_every_interface_is_kept() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    let mut announced = namespace(0, &[eth0(), Device::from("lo")]);
+    announced.interfaces[0].mac = Some("52:54:00:12:34:56".to_string());
+    announced.interfaces[0].mtu = Some(9000);
+    client.send(&announced).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM interfaces", 2, WAIT));
+
+    let flags = (IfFlags::UP | IfFlags::RUNNING).bits();
+    assert_eq!(interfaces(&server, "sensor"), [
+        (0, 0, "eth0".to_string(), flags, "10.3.0.9/24 fe80::1/64 192.0.2.1".to_string()),
+        (0, 1, "lo".to_string(), 0, String::new()),
+    ]);
+    let links: Vec<(String, Option<String>, Option<u32>)> = server.db().prepare("SELECT name, mac, mtu FROM interfaces ORDER BY idx").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(links, [("eth0".to_string(), Some("52:54:00:12:34:56".to_string()), Some(9000)), ("lo".to_string(), None, None)]);
+    // Which sensor interface owns an address is a query away
+    let owner: (String, String) = server.db()
+        .query_row("SELECT ident, name FROM interfaces WHERE ' ' || addresses || ' ' LIKE '% 10.3.0.9/%'", [], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap();
+    assert_eq!(owner, ("sensor".to_string(), "eth0".to_string()));
+}
+
+#[test]
+fn announcing_a_generation_again_replaces_it() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&namespace(0, &[eth0(), Device::from("lo")])).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM interfaces", 2, WAIT));
+    client.close();
+
+    // Restarted with only eth0, then announcing a change
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&namespace(0, &[eth0()])).unwrap();
+    client.send(&namespace(1, &[Device::from("eth1")])).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM interfaces WHERE generation = 1", 1, WAIT));
+    let names: Vec<(u32, String)> = interfaces(&server, "sensor").into_iter().map(|(generation, _, name, _, _)| (generation, name)).collect();
+    assert_eq!(names, [(0, "eth0".to_string()), (1, "eth1".to_string())]);
+}
+
+#[test]
+fn an_announcement_is_a_numbered_frame() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    client.hello(None).unwrap();
+    client.send(&Sequence { next: 0 }).unwrap();
+    client.send(&namespace(0, &[eth0()])).unwrap();
+    // The announcement was frame 0
+    client.send(&Sequence { next: 1 }).unwrap();
+    client.send(&namespace(1, &[eth0()])).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM interfaces", 2, WAIT));
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM gaps", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+}
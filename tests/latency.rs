@@ -0,0 +1,68 @@
+//! How late each sensor's messages arrive: timestamped a known while before they're sent, the
+//! collector keeps their 95th percentile per ident, counts those timestamped after they
+//! arrived apart from it, and reports both in the client listing and on `/metrics`.
+
+use std::time::{Duration, SystemTime};
+
+use glosco::{observe::{Message, Protocol}, query, server::ApiSettings, test_support::{http, state, unused_addr, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// A connection opened `late` seconds before now, or after it if negative.
+fn starting(port: u16, late: f64) -> Message {
+    let mut state = state(&format!("10.0.0.1:{}", port), "10.0.0.2:443", Protocol::Tcp);
+    state.as_of = if late >= 0.0 {
+        SystemTime::now() - Duration::from_secs_f64(late)
+    } else {
+        SystemTime::now() + Duration::from_secs_f64(-late)
+    };
+    Message::Starting(state)
+}
+
+#[test]
+fn latency_is_kept_per_sensor_with_early_messages_counted_apart() {
+    let bind = unused_addr();
+    let server = TestServer::spawn_with(|settings| {
+        settings.maintenance = 0.1;
+        settings.api = Some(ApiSettings { bind, token: None, ingest: false, ingest_max_bytes: None, ingest_rate: None });
+    });
+    // One a second late up to twenty, then two from a clock five seconds fast, last
+    let mut slow = server.client("slow");
+    slow.hello(None).unwrap();
+    for late in 1 ..= 20 {
+        slow.send(&starting(40000 + late, late as f64)).unwrap();
+    }
+    for port in [41000, 41001] {
+        slow.send(&starting(port, -5.0)).unwrap();
+    }
+    // And a sensor that's never behind
+    let mut prompt = server.client("prompt");
+    prompt.hello(None).unwrap();
+    prompt.send(&starting(40000, 0.0)).unwrap();
+    // The tick that counts the last early message has seen every late one before it
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients WHERE ident = 'slow' AND early = 2", 1, WAIT), "the early messages weren't counted");
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients WHERE ident = 'prompt' AND latency_p95 IS NOT NULL", 1, WAIT));
+
+    let clients = query::clients(&server.db(), &[], &[]).unwrap();
+    let client = |ident: &str| clients.iter().find(|client| client.ident == ident).unwrap();
+    // The 19th of 20, give or take however long they took to get here
+    let p95 = client("slow").latency_p95.unwrap();
+    assert!((19.0 .. 19.5).contains(&p95), "{}", p95);
+    assert_eq!(client("slow").early, Some(2));
+    // Neither the early ones' skew nor the late ones' is hidden
+    let skew = client("slow").skew.unwrap();
+    assert!((4.5 .. 5.0).contains(&skew), "{}", skew);
+    let worst = client("slow").max_skew.unwrap();
+    assert!((20.0 .. 20.5).contains(&worst), "{}", worst);
+    let prompt_p95 = client("prompt").latency_p95.unwrap();
+    assert!((0.0 .. 0.5).contains(&prompt_p95), "{}", prompt_p95);
+    assert_eq!(client("prompt").early, Some(0));
+
+    let (status, body) = http(bind, "GET", "/metrics", &[], "").unwrap();
+    assert_eq!(status, 200, "{}", body);
+    assert!(body.contains("# TYPE glosco_sensor_latency_p95_seconds gauge"), "{}", body);
+    assert!(body.lines().any(|line| line == format!("glosco_sensor_latency_p95_seconds{{ident=\"slow\"}} {}", p95)), "{}", body);
+    assert!(body.contains("# TYPE glosco_sensor_early_messages_total counter"), "{}", body);
+    assert!(body.lines().any(|line| line == "glosco_sensor_early_messages_total{ident=\"slow\"} 2"), "{}", body);
+    assert!(body.lines().any(|line| line == "glosco_sensor_early_messages_total{ident=\"prompt\"} 0"), "{}", body);
+}
@@ -0,0 +1,89 @@
+//! Clients from before hellos reporting alongside current ones, and turning them away once the
+//! transition's over.
+
+use std::time::Duration;
+
+use glosco::{observe::{Message, Protocol}, server::ServerSettings, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn starting(srcport: u16) -> Message {
+    Message::Starting(state(&format!("10.0.0.1:{}", srcport), "10.0.0.2:443", Protocol::Tcp))
+}
+
+fn legacy(server: &TestServer, ident: &str) -> Option<bool> {
+    server.db().query_row("SELECT legacy FROM clients WHERE ident = ?", [ident], |row| row.get(0)).unwrap()
+}
+
+fn rows(server: &TestServer, ident: &str) -> i64 {
+    server.db().query_row("SELECT COUNT(*) FROM state_all WHERE ident = ?", [ident], |row| row.get(0)).unwrap()
+}
+
+/// A legacy client and a current one report to one collector at once, taking turns frame by
+/// frame; both are stored, and told apart in the clients table.
+fn both_kinds_at_once(adjust: fn(&mut ServerSettings)) {
+    let server = TestServer::spawn_with(adjust);
+    let mut old = server.client("old-sensor");
+    let mut new = server.client("new-sensor");
+    new.hello(Some(30)).unwrap();
+    for port in 40000 .. 40020 {
+        // The legacy client's first frame is a message, with nothing said beforehand
+        old.send(&starting(port)).unwrap();
+        new.send(&starting(port)).unwrap();
+    }
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'old-sensor'", 20, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'new-sensor'", 20, WAIT));
+    assert_eq!(legacy(&server, "old-sensor"), Some(true));
+    assert_eq!(legacy(&server, "new-sensor"), Some(false));
+
+    // Upgraded: the flag goes with the session, so it clears
+    old.close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE ident = 'old-sensor' AND disconnected IS NOT NULL", 1, WAIT));
+    let mut upgraded = server.client("old-sensor");
+    upgraded.hello(Some(30)).unwrap();
+    upgraded.send(&starting(41000)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'old-sensor' AND srcport = 41000", 1, WAIT));
+    assert_eq!(legacy(&server, "old-sensor"), Some(false));
+}
+
+/// With `reject_legacy`, a client that starts without a hello is hung up on before it claims
+/// its ident, while one that says hello reports as usual.
+fn legacy_clients_turned_away(adjust: fn(&mut ServerSettings)) {
+    let server = TestServer::spawn_with(adjust);
+    let mut old = server.client("old-sensor");
+    let mut new = server.client("new-sensor");
+    old.send(&starting(40000)).unwrap();
+    new.hello(Some(30)).unwrap();
+    new.send(&starting(40000)).unwrap();
+    assert!(old.hung_up(WAIT), "the legacy client is still connected");
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'new-sensor'", 1, WAIT));
+    assert!(!new.hung_up(Duration::from_millis(200)));
+    assert_eq!(rows(&server, "old-sensor"), 0);
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM clients WHERE ident = 'old-sensor'", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM client_sessions WHERE ident = 'old-sensor'", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+}
+
+#[test]
+fn legacy_and_current_clients_report_side_by_side() {
+    both_kinds_at_once(|_| ());
+}
+
+#[test]
+fn reject_legacy_turns_away_only_legacy_clients() {
+    legacy_clients_turned_away(|settings| settings.reject_legacy = true);
+}
+
+#[cfg(feature = "async-server")]
+#[test]
+fn the_async_server_takes_both_kinds_of_client_too() {
+    both_kinds_at_once(|settings| settings.async_io = true);
+}
+
+#[cfg(feature = "async-server")]
+#[test]
+fn the_async_server_turns_legacy_clients_away_too() {
+    legacy_clients_turned_away(|settings| {
+        settings.async_io = true;
+        settings.reject_legacy = true;
+    });
+}
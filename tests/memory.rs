@@ -0,0 +1,84 @@
+//! A collector on the in-memory database: every thread it has sees the one database, however
+//! many clients write to it at once, and a snapshot of it outlives the collector.
+
+use std::{fs, sync::Mutex, thread, time::Duration};
+
+use glosco::{db, observe::{Message, Protocol}, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(30);
+
+/// There's one in-memory database per process, so the tests here take turns with it.
+static MEMORY: Mutex<()> = Mutex::new(());
+
+fn in_memory() -> TestServer {
+    TestServer::spawn_with(|settings| settings.database = db::MEMORY.to_string())
+}
+
+fn starting(port: u16) -> Message {
+    Message::Starting(state(&format!("10.0.0.1:{}", port), "10.0.0.2:443", Protocol::Tcp))
+}
+
+#[test]
+fn what_each_client_thread_stores_every_other_thread_sees() {
+    let _turn = MEMORY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let server = in_memory();
+    const CLIENTS: u16 = 16;
+    const MESSAGES: u16 = 1000;
+    thread::scope(|scope| {
+        for sensor in 0 .. CLIENTS {
+            let server = &server;
+            scope.spawn(move || {
+                let mut client = server.client(&format!("load-{}", sensor));
+                client.hello(None).unwrap();
+                for port in 0 .. MESSAGES {
+                    client.send(&starting(10000 + port)).unwrap();
+                }
+            });
+        }
+        // Read while they write, so readers hold locks against them too
+        scope.spawn(|| server.wait_for_count("SELECT count(*) FROM state_all WHERE ident LIKE 'load-%' AND close IS NULL", (CLIENTS * MESSAGES) as i64, WAIT));
+    });
+    assert!(server.wait_for_count("SELECT count(*) FROM state_all WHERE ident LIKE 'load-%' AND close IS NULL", (CLIENTS * MESSAGES) as i64, WAIT));
+    let per_ident: Vec<(String, i64)> = server.db().prepare("
+        SELECT ident, count(*) FROM state_all WHERE ident LIKE 'load-%' AND close IS NULL GROUP BY ident ORDER BY length(ident), ident;
+    ").unwrap().query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(per_ident, (0 .. CLIENTS).map(|sensor| (format!("load-{}", sensor), MESSAGES as i64)).collect::<Vec<_>>());
+    assert_eq!(server.db().query_row("SELECT count(*) FROM client_sessions WHERE ident LIKE 'load-%'", [], |row| row.get::<_, i64>(0)).unwrap(), CLIENTS as i64);
+}
+
+#[test]
+fn a_restarted_collector_starts_from_its_snapshot() {
+    let _turn = MEMORY.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let dir = std::env::temp_dir().join(format!("glosco-memory-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let snapshot = dir.join("snapshot.db");
+    let snapshotting = || TestServer::spawn_with(|settings| {
+        settings.database = db::MEMORY.to_string();
+        settings.snapshot = Some(snapshot.clone());
+        settings.snapshot_interval = 3600.0;
+    });
+    let count = "SELECT count(*) FROM state_all WHERE ident = 'snapshotted' AND close IS NULL";
+
+    let server = snapshotting();
+    let mut client = server.client("snapshotted");
+    client.hello(None).unwrap();
+    for port in 0 .. 3 {
+        client.send(&starting(20000 + port)).unwrap();
+    }
+    assert!(server.wait_for_count(count, 3, WAIT));
+    client.close();
+    // Shutting down takes the last snapshot
+    drop(server);
+    assert!(snapshot.exists());
+
+    // What the process's in-memory database went through since doesn't matter
+    db::open(db::MEMORY).unwrap().execute("DELETE FROM state WHERE ident = 'snapshotted';", []).unwrap();
+    let server = snapshotting();
+    assert_eq!(server.db().query_row(count, [], |row| row.get::<_, i64>(0)).unwrap(), 3);
+    let ports: Vec<u16> = server.db().prepare("SELECT srcport FROM state_all WHERE ident = 'snapshotted' AND close IS NULL ORDER BY srcport").unwrap()
+        .query_map([], |row| row.get(0)).unwrap().collect::<Result<_, _>>().unwrap();
+    assert_eq!(ports, [20000, 20001, 20002]);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
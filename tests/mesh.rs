@@ -0,0 +1,46 @@
+//! A collector on the mesh storing what sensors publish to it, as though they'd connected.
+#![cfg(feature = "mesh")]
+
+use std::{thread, time::{Duration, Instant}};
+
+use glosco::{coding::Coder, mesh::MeshConfig, observe::{Message, Protocol}, test_support::{state, unused_addr, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn encoded(message: &Message) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    message.encode(&mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn frames_published_to_the_mesh_are_stored_under_their_idents() {
+    let listen = unused_addr();
+    let server = TestServer::spawn_with(|settings| settings.mesh_listen = vec![listen]);
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut config = MeshConfig::default();
+    config.set_listens(Vec::new());
+    config.add_peer(listen);
+    let mesh = runtime.block_on(config.build()).unwrap();
+    let deadline = Instant::now() + WAIT;
+    while mesh.peers().iter().all(|peer| peer.connected_since.is_none()) {
+        assert!(Instant::now() < deadline, "never linked to the collector");
+        thread::sleep(Duration::from_millis(20));
+    }
+    // A link's listed a moment before it starts forwarding
+    thread::sleep(Duration::from_millis(50));
+
+    mesh.publish("sensor", encoded(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))));
+    mesh.publish("other", encoded(&Message::Starting(state("10.0.0.5:40001", "10.0.0.2:443", Protocol::Tcp))));
+    mesh.publish("sensor", encoded(&Message::Starting(state("10.0.0.1:40002", "10.0.0.2:443", Protocol::Tcp))));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 3, WAIT));
+
+    let stored: Vec<(String, u16)> = server.db().prepare("SELECT ident, srcport FROM state_all ORDER BY srcport").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(stored, [("sensor".to_string(), 40000), ("other".to_string(), 40001), ("sensor".to_string(), 40002)]);
+    let clients: Vec<String> = server.db().prepare("SELECT ident FROM clients ORDER BY ident").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(clients, ["other", "sensor"]);
+}
@@ -0,0 +1,95 @@
+//! A few hundred clients at once: a fixed pool of workers sees them all through, queueing
+//! what it can't take yet and turning away what won't fit, without a thread apiece.
+
+use std::{fs, time::Duration};
+
+use glosco::{observe::{Message, Protocol}, test_support::{state, TestClient, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+const CLIENTS: u16 = 300;
+const MESSAGES: u16 = 5;
+
+/// Threads in this process, from `/proc`.
+fn threads() -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    status.lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+fn starting(client: u16, port: u16) -> Message {
+    Message::Starting(state(&format!("10.{}.{}.1:{}", client / 256, client % 256, 40000 + port), "10.255.0.1:443", Protocol::Tcp))
+}
+
+#[test]
+fn a_few_hundred_clients_at_once_are_all_stored_by_a_handful_of_threads() {
+    let server = TestServer::spawn_with(|settings| {
+        settings.workers = 16;
+        settings.pending = CLIENTS as usize;
+    });
+    let before = threads();
+    let mut clients: Vec<TestClient> = (0 .. CLIENTS).map(|n| server.client(&format!("sensor-{}", n))).collect();
+    for (n, client) in clients.iter_mut().enumerate() {
+        client.hello(None).unwrap();
+        for port in 0 .. MESSAGES {
+            client.send(&starting(n as u16, port)).unwrap();
+        }
+    }
+    // Every one of them connected and waiting on, or talking to, a worker
+    let during = threads();
+    assert!(during <= before + 16 + 8, "{} threads with {} clients connected, from {}", during, CLIENTS, before);
+
+    for client in clients {
+        client.close();
+    }
+    let rows = CLIENTS as i64 * MESSAGES as i64;
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE state = 5 AND close IS NULL", rows, 6 * WAIT), "not every message was stored");
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", CLIENTS as i64, WAIT));
+    assert_eq!(server.db().query_row("SELECT COUNT(DISTINCT ident) FROM state_all", [], |row| row.get::<_, i64>(0)).unwrap(), CLIENTS as i64);
+}
+
+#[test]
+fn connections_past_the_queue_are_turned_away_and_those_queued_are_seen_to() {
+    let server = TestServer::spawn_with(|settings| {
+        settings.workers = 2;
+        settings.pending = 4;
+    });
+    let busy: Vec<TestClient> = (0 .. 2).map(|n| {
+        let mut client = server.client(&format!("busy-{}", n));
+        client.hello(None).unwrap();
+        client
+    }).collect();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions", 2, WAIT), "the workers never took the first two");
+
+    let mut waiting: Vec<TestClient> = (0 .. 10).map(|n| {
+        let mut client = server.client(&format!("waiting-{}", n));
+        // Those turned away may be hung up on before they're done sending
+        let _ = client.hello(None);
+        let _ = client.send(&starting(n, 0));
+        client
+    }).collect();
+    let mut refused = waiting.split_off(4);
+    for client in refused.iter_mut() {
+        assert!(client.hung_up(WAIT), "{} wasn't turned away", client.ident());
+    }
+    for client in waiting.iter_mut() {
+        assert!(!client.hung_up(Duration::from_millis(100)), "{} was turned away", client.ident());
+    }
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM state_all", [], |row| row.get::<_, i64>(0)).unwrap(), 0, "the queued were seen to early");
+
+    // The queued ones get their turn as the first two go
+    for client in busy {
+        client.close();
+    }
+    for client in waiting {
+        client.close();
+    }
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE state = 5 AND close IS NULL", 4, WAIT));
+    let idents: Vec<String> = server.db().prepare("SELECT ident FROM state_all WHERE state = 5 AND close IS NULL ORDER BY ident").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(idents, ["waiting-0", "waiting-1", "waiting-2", "waiting-3"]);
+}
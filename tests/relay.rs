@@ -0,0 +1,106 @@
+//! A site collector relaying upstream: what its sensors report is stored there and at the
+//! central collector both, under each sensor's own ident rather than the relay's.
+
+use std::time::Duration;
+
+use glosco::{observe::{Closed, Message, Problem, Protocol}, sync::ClientConfig, test_support::{state, unused_addr, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+/// The retry window a relay backs off to, with room to spare for a loaded machine.
+const BACKOFF: Duration = Duration::from_secs(15);
+
+/// A row as (ident, srchost, srcport, dsthost, dstport, state, close, pkind, conntime).
+type Row = (String, String, u16, String, u16, u8, Option<u8>, Option<u8>, f64);
+
+/// What each sensor reports.
+fn reports() -> Vec<(&'static str, Vec<Message>)> {
+    let web = state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp);
+    let mut later = web;
+    later.as_of += Duration::from_secs(1);
+    let dns = state("10.0.1.1:40000", "10.0.1.53:53", Protocol::Udp);
+    vec![
+        ("sensor-a", vec![
+            Message::Starting(web),
+            Message::Active(later),
+            Message::Ended(later, Closed::Normally),
+        ]),
+        ("sensor-b", vec![
+            Message::Starting(dns),
+            Message::Ended(dns, Closed::Connectionless),
+            Message::Failed(state("10.0.1.1:40001", "10.0.1.3:5432", Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 }),
+        ]),
+    ]
+}
+
+/// Report everything from a client per sensor to `site`, and wait for it to go.
+fn report(site: &TestServer) {
+    let clients: Vec<_> = reports().into_iter().map(|(ident, messages)| {
+        let mut config = ClientConfig::new(ident.to_string());
+        config.add(site.addr());
+        let client = config.build().unwrap();
+        for message in &messages {
+            client.send(message);
+        }
+        client
+    }).collect();
+    for client in clients {
+        assert!(client.shutdown(WAIT), "a sensor didn't get everything out");
+    }
+}
+
+fn rows(server: &TestServer) -> Vec<Row> {
+    server.db().prepare("
+        SELECT ident, srchost, srcport, dsthost, dstport, state, close, pkind, conntime FROM state_all
+        ORDER BY ident, srcport, conntime, state = 2
+    ").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+/// Rows as (ident, srchost, srcport, state, close), leaving out the rest.
+fn brief(rows: &[Row]) -> Vec<(&str, &str, u16, u8, Option<u8>)> {
+    rows.iter().map(|row| (row.0.as_str(), row.1.as_str(), row.2, row.5, row.6)).collect()
+}
+
+fn idents(server: &TestServer) -> Vec<String> {
+    server.db().prepare("SELECT ident FROM clients ORDER BY ident").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn sensor_to_relay_to_central_stores_rows_at_both_under_the_sensors_ident() {
+    let central = TestServer::spawn();
+    let site = TestServer::spawn_with(|settings| settings.relay = vec![central.addr()]);
+    report(&site);
+
+    assert!(site.wait_for_count("SELECT COUNT(*) FROM state_all", 6, WAIT));
+    assert!(central.wait_for_count("SELECT COUNT(*) FROM state_all", 6, WAIT), "nothing was relayed");
+    let stored = rows(&site);
+    assert_eq!(brief(&stored), [
+        ("sensor-a", "10.0.0.1", 40000, 5, None),
+        ("sensor-a", "10.0.0.1", 40000, 1, None),
+        ("sensor-a", "10.0.0.1", 40000, 2, Some(1)),
+        ("sensor-b", "10.0.1.1", 40000, 5, None),
+        ("sensor-b", "10.0.1.1", 40000, 2, Some(3)),
+        ("sensor-b", "10.0.1.1", 40001, 3, None),
+    ]);
+    assert_eq!(stored[5].7, Some(3));
+    // The same rows upstream, down to when each connection was seen
+    assert_eq!(rows(&central), stored);
+    // Though it was only the relay that connected to central, under the host's name
+    assert_eq!(idents(&site), ["sensor-a", "sensor-b"]);
+    assert_eq!(idents(&central), [gethostname::gethostname().into_string().unwrap()]);
+}
+
+#[test]
+fn a_relay_holds_on_to_what_it_stored_until_central_is_up() {
+    let addr = unused_addr();
+    let site = TestServer::spawn_with(|settings| settings.relay = vec![addr]);
+    report(&site);
+    assert!(site.wait_for_count("SELECT COUNT(*) FROM state_all", 6, WAIT));
+
+    let central = TestServer::spawn_with(|settings| settings.bind = addr);
+    assert!(central.wait_for_count("SELECT COUNT(*) FROM state_all", 6, BACKOFF), "nothing was relayed within the backoff");
+    assert_eq!(rows(&central), rows(&site));
+}
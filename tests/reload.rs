@@ -0,0 +1,53 @@
+//! Reloading settings on SIGHUP: a new retention applies from the next maintenance tick, and
+//! the clients connected at the time stay connected.
+#![cfg(unix)]
+
+use std::{fs, thread, time::Duration};
+
+use glosco::{observe::{Message, Protocol}, server::ServerSettings, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+#[test]
+fn a_new_retention_applies_from_the_next_tick_without_dropping_anyone() {
+    let config = std::env::temp_dir().join(format!("glosco-reload-{}.toml", std::process::id()));
+    let reload = {
+        let config = config.clone();
+        Box::new(move || ServerSettings::load(&config))
+    };
+    let server = TestServer::spawn_reloading(|settings| settings.maintenance = 0.2, reload);
+    let write = |retention: Option<f64>| {
+        let mut contents = format!("bind = \"127.0.0.1:0\"\ndatabase = {:?}\nmaintenance = 0.2\n", server.database());
+        if let Some(retention) = retention {
+            contents += &format!("retention = {:?}\n", retention);
+        }
+        fs::write(&config, contents).unwrap();
+    };
+    write(None);
+
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT));
+    // Long enough for a new retention to find the row old, and for a few ticks to leave it be
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM state_all", [], |row| row.get::<_, i64>(0)).unwrap(), 1);
+
+    write(Some(1.0));
+    // Safety: the collector has had its handler in place since it started storing rows.
+    unsafe {
+        libc::raise(libc::SIGHUP);
+    }
+    // The reload's looked for every half second, and the tick after it expires the row
+    assert!(server.wait_for_rows(|db| Ok(db.query_row("SELECT COUNT(*) FROM state_all", [], |row| row.get::<_, i64>(0))? == 0), WAIT), "the new retention never applied");
+
+    // Still connected, on the session it started with
+    client.send(&Message::Starting(state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE srcport = 40001", 1, WAIT));
+    assert!(!client.hung_up(Duration::from_millis(100)));
+    let sessions: Vec<Option<f64>> = server.db().prepare("SELECT disconnected FROM client_sessions WHERE ident = 'sensor'").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(sessions, [None]);
+    let _ = fs::remove_file(&config);
+}
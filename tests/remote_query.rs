@@ -0,0 +1,120 @@
+//! Questions asked of a collector over its client port, as `glosco query --remote` asks them.
+
+use std::time::Duration;
+
+use glosco::{observe::{Message, Protocol}, remote::{self, Query, QueryActive, QueryConnections, QueryRequest, QueryResponse}, test_support::{state, TestClient, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+const TOKEN: &str = "s3cret";
+
+/// A collector taking queries with `TOKEN`, that's been told of four open connections: three
+/// from `web` to port 443, and one from `db` to 5432. They're open for as long as the clients
+/// that reported them stay connected.
+fn server(limit: usize) -> (TestServer, Vec<TestClient>) {
+    let server = TestServer::spawn_with(|settings| {
+        settings.remote_query_token = Some(TOKEN.to_string());
+        settings.remote_query_limit = limit;
+    });
+    let mut clients = Vec::new();
+    for (ident, connections) in [("web", &["10.0.0.1:40001", "10.0.0.1:40002", "10.0.0.1:40003"][..]), ("db", &["10.0.0.3:40004"][..])] {
+        let mut client = server.client(ident);
+        client.hello(Some(30)).unwrap();
+        for src in connections {
+            let dst = if ident == "web" { "10.0.0.2:443" } else { "10.0.0.4:5432" };
+            client.send(&Message::Starting(state(src, dst, Protocol::Tcp))).unwrap();
+            client.send(&Message::Active(state(src, dst, Protocol::Tcp))).unwrap();
+        }
+        clients.push(client);
+    }
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM active_now", 4, WAIT));
+    (server, clients)
+}
+
+fn ask(server: &TestServer, token: &str, query: Query) -> QueryResponse {
+    remote::ask(server.addr(), "operator-query", &QueryRequest { token: token.to_string(), query }).unwrap()
+}
+
+fn active(ident: Option<&str>, port: Option<u16>, limit: u32) -> Query {
+    Query::Active(QueryActive { ident: ident.map(str::to_string), host: None, port, limit })
+}
+
+/// The rows of `response` as JSON, and whether they were cut short.
+fn rows(response: QueryResponse) -> (Vec<serde_json::Value>, bool) {
+    match response {
+        QueryResponse::Rows { rows, truncated } => (rows.iter().map(|row| serde_json::from_str(row).unwrap()).collect(), truncated),
+        QueryResponse::Refused(reason) => panic!("refused: {}", reason),
+    }
+}
+
+fn srcports(rows: &[serde_json::Value]) -> Vec<u64> {
+    let mut ports: Vec<u64> = rows.iter().map(|row| row["srcport"].as_u64().unwrap()).collect();
+    ports.sort();
+    ports
+}
+
+#[test]
+fn active_connections_are_answered_filtered_and_limited() {
+    let (server, _clients) = server(100);
+    let (all, truncated) = rows(ask(&server, TOKEN, active(None, None, 100)));
+    assert_eq!(srcports(&all), [40001, 40002, 40003, 40004]);
+    assert!(!truncated);
+
+    let (web, _) = rows(ask(&server, TOKEN, active(Some("web"), None, 100)));
+    assert_eq!(srcports(&web), [40001, 40002, 40003]);
+    assert!(web.iter().all(|row| row["ident"] == "web"));
+    let (postgres, _) = rows(ask(&server, TOKEN, active(None, Some(5432), 100)));
+    assert_eq!(srcports(&postgres), [40004]);
+
+    // Fewer than there are, and saying so
+    let (some, truncated) = rows(ask(&server, TOKEN, active(None, None, 2)));
+    assert_eq!(some.len(), 2);
+    assert!(truncated);
+}
+
+#[test]
+fn the_collectors_own_limit_wins_when_its_lower() {
+    let (server, _clients) = server(3);
+    let (some, truncated) = rows(ask(&server, TOKEN, active(None, None, 100)));
+    assert_eq!(some.len(), 3);
+    assert!(truncated);
+}
+
+#[test]
+fn sessions_are_answered_for_the_window_asked() {
+    let (server, _clients) = server(100);
+    let connections = |since: f64, until: f64, port: Option<u16>| Query::Connections(QueryConnections {
+        ident: None,
+        host: Some("10.0.0.2/32".parse().unwrap()),
+        port,
+        proto: Some(Protocol::Tcp),
+        since,
+        until,
+        limit: 100,
+    });
+    let (found, truncated) = rows(ask(&server, TOKEN, connections(0.0, f64::MAX, None)));
+    assert_eq!(srcports(&found), [40001, 40002, 40003]);
+    assert!(!truncated);
+    assert!(rows(ask(&server, TOKEN, connections(0.0, f64::MAX, Some(22)))).0.is_empty());
+    // Long before any of them opened
+    assert!(rows(ask(&server, TOKEN, connections(0.0, 1000.0, None))).0.is_empty());
+}
+
+#[test]
+fn queries_are_refused_without_the_token() {
+    let (server, _clients) = server(100);
+    assert_eq!(ask(&server, "guess", active(None, None, 100)), QueryResponse::Refused("wrong token".to_string()));
+    assert_eq!(ask(&server, "", active(None, None, 100)), QueryResponse::Refused("wrong token".to_string()));
+
+    let closed = TestServer::spawn();
+    assert_eq!(ask(&closed, TOKEN, active(None, None, 100)), QueryResponse::Refused("remote queries aren't enabled".to_string()));
+}
+
+#[test]
+fn a_query_isnt_stored_as_a_client() {
+    let (server, _clients) = server(100);
+    rows(ask(&server, TOKEN, active(None, None, 100)));
+    let idents: Vec<String> = server.db().prepare("SELECT ident FROM clients ORDER BY ident").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(idents, ["db", "web"]);
+}
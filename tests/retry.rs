@@ -0,0 +1,49 @@
+//! A client started before its collector: it keeps trying, backs off while it can't connect,
+//! and delivers what it queued in the meantime once, and only once, when it finally can.
+
+use std::{sync::atomic::Ordering, thread, time::{Duration, Instant}};
+
+use glosco::{observe::{Message, Protocol}, sync::ClientConfig, test_support::{state, unused_addr, TestServer}};
+
+/// The retry window the client backs off to, with room to spare for a loaded machine.
+const BACKOFF: Duration = Duration::from_secs(15);
+const MESSAGES: u16 = 20;
+
+#[test]
+fn a_collector_thats_down_then_up_gets_everything_once() {
+    let addr = unused_addr();
+    let mut config = ClientConfig::new("sensor".to_string());
+    config.add(addr);
+    let client = config.build().unwrap();
+    for port in 0 .. MESSAGES {
+        client.send(&Message::Starting(state(&format!("10.0.0.1:{}", 40000 + port), "10.0.0.2:443", Protocol::Tcp)));
+    }
+
+    // Long enough to use up the quick retries, and short of the window they back off to
+    thread::sleep(Duration::from_secs(2));
+    let stats = client.remotes().stats();
+    let failed = stats[0].connect_errors.load(Ordering::Relaxed);
+    // The first try, and five quick retries before it waits
+    assert_eq!(failed, 6);
+    assert_eq!(stats[0].connects.load(Ordering::Relaxed), 0);
+    assert_eq!(stats[0].queued.load(Ordering::Relaxed), MESSAGES as u64);
+
+    let up = Instant::now();
+    let server = TestServer::spawn_with(|settings| settings.bind = addr);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", MESSAGES as i64, BACKOFF), "nothing arrived within the backoff");
+    assert!(up.elapsed() < BACKOFF);
+
+    let ports: Vec<u16> = server.db().prepare("SELECT srcport FROM state_all ORDER BY srcport").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(ports, (40000 .. 40000 + MESSAGES).collect::<Vec<_>>());
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM client_sessions", [], |row| row.get::<_, i64>(0)).unwrap(), 1);
+    assert_eq!(server.db().query_row("SELECT COUNT(*) FROM gaps", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+
+    // One more try at most while it was down and waiting, then the one that got through
+    assert!(stats[0].connect_errors.load(Ordering::Relaxed) <= failed + 1);
+    assert_eq!(stats[0].connects.load(Ordering::Relaxed), 1);
+    assert_eq!(stats[0].sent.load(Ordering::Relaxed), MESSAGES as u64);
+    assert_eq!(stats[0].dropped.load(Ordering::Relaxed), 0);
+    assert!(client.shutdown(Duration::from_secs(1)));
+}
@@ -0,0 +1,364 @@
+//! A sensor reading a capture against a collector, as `glosco client --pcap` runs.
+
+use std::{ffi::CString, fs::OpenOptions, io::Write, net::{SocketAddr, UdpSocket}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, process::{Command, Stdio}, sync::mpsc, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use glosco::{client::{self, ClientSettings}, metrics::TagFormat, observe::{Closed, Message}, test_support::{http, pcap_header, pcap_record, tcp_frame, udp_frame, unused_addr, write_pcap, TestServer, SYN}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn secs(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+#[test]
+fn shutdown_closes_whats_still_open_as_of_then() {
+    let server = TestServer::spawn_logging();
+    // A capture that's still being written, so the sensor is mid-read when it's shut down
+    // rather than stopping at the end of the file
+    let capture = server.dir().join("capture.pcap");
+    let path = CString::new(capture.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0, "failed to create fifo");
+    let (done, finished) = mpsc::channel::<()>();
+    let writer = {
+        let capture = capture.clone();
+        thread::spawn(move || {
+            let mut fifo = OpenOptions::new().write(true).open(capture).unwrap();
+            fifo.write_all(&pcap_header()).unwrap();
+            fifo.write_all(&pcap_record(SystemTime::now(), &tcp_frame("10.0.0.1:40000", "10.0.0.2:443", SYN))).unwrap();
+            fifo.flush().unwrap();
+            let _ = finished.recv();
+        })
+    };
+
+    let sensor = client::start(ClientSettings {
+        captures: vec![capture],
+        remotes: vec![server.addr()],
+        ident: Some("sensor".to_string()),
+        snapshot_interval: None,
+        flush_timeout: 5.0,
+        ..Default::default()
+    }).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE state = 5", 1, WAIT));
+
+    let before = SystemTime::now();
+    sensor.shutdown();
+    assert!(sensor.join(), "not everything reached the collector");
+    let after = SystemTime::now();
+    done.send(()).unwrap();
+    writer.join().unwrap();
+
+    // The close came from the sensor, not from the collector seeing it go, which logs nothing
+    let logged: Vec<Message> = server.logged(2, WAIT).into_iter().map(|record| serde_json::from_value(record["message"].clone()).unwrap()).collect();
+    assert!(matches!(logged[..], [Message::Starting(_), Message::Ended(_, Closed::TimedOut)]), "{:?}", logged);
+    // Ended (2), timed out (4), stamped when the sensor stopped rather than when it last heard
+    let closes: Vec<(String, f64, i64)> = server.db()
+        .prepare("SELECT ident, conntime, srcport FROM state_all WHERE state = 2 AND close = 4").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(closes.len(), 1, "{:?}", closes);
+    let (ident, conntime, srcport) = &closes[0];
+    assert_eq!((ident.as_str(), *srcport), ("sensor", 40000));
+    assert!((secs(before) ..= secs(after)).contains(conntime), "{} not in {:?}", conntime, secs(before) ..= secs(after));
+}
+
+/// A finished capture in `dir`: two connections opened a minute ago and never heard from again.
+fn capture(dir: &Path) -> (PathBuf, SystemTime) {
+    let path = dir.join("once.pcap");
+    let captured = SystemTime::now() - Duration::from_secs(60);
+    write_pcap(&path, &[
+        (captured, tcp_frame("10.0.0.1:40000", "10.0.0.2:443", SYN)),
+        (captured, tcp_frame("10.0.0.1:40001", "10.0.0.2:443", SYN)),
+    ]).unwrap();
+    (path, captured)
+}
+
+/// `glosco client --once` replaying `pcap` to `remote`, with the exit status it stopped with.
+fn once(pcap: &Path, remote: &str) -> Option<i32> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_glosco"))
+        .args(["client", "--ident", "sensor", "--once", "--flush-timeout", "2", "--snapshot-interval", "0", "--remotes", remote, "--pcap"])
+        .arg(pcap)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start glosco client");
+    let deadline = Instant::now() + WAIT * 2;
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status.code();
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            panic!("glosco client --once never exited");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn once_ends_whats_open_as_of_its_last_packet_and_reports_delivery() {
+    let server = TestServer::spawn_logging();
+    let (pcap, captured) = capture(server.dir());
+    let sensor = client::start(ClientSettings {
+        captures: vec![pcap],
+        remotes: vec![server.addr()],
+        ident: Some("sensor".to_string()),
+        snapshot_interval: None,
+        once: true,
+        flush_timeout: 5.0,
+        ..Default::default()
+    }).unwrap();
+    // It stops by itself when the file runs out
+    let deadline = Instant::now() + WAIT;
+    while !sensor.is_finished() {
+        assert!(Instant::now() < deadline, "the sensor kept going after its capture ran out");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(sensor.join(), "not everything reached the collector");
+
+    let logged: Vec<Message> = server.logged(4, WAIT).into_iter().map(|record| serde_json::from_value(record["message"].clone()).unwrap()).collect();
+    assert!(matches!(logged[..], [
+        Message::Starting(_), Message::Starting(_), Message::Ended(_, Closed::TimedOut), Message::Ended(_, Closed::TimedOut),
+    ]), "{:?}", logged);
+    // Stamped when the capture last heard from them, not when the replay stopped
+    let closes: Vec<(i64, f64)> = server.db()
+        .prepare("SELECT srcport, conntime FROM state_all WHERE state = 2 AND close = 4 ORDER BY srcport").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(closes.iter().map(|(port, _)| *port).collect::<Vec<_>>(), [40000, 40001]);
+    for (_, conntime) in closes {
+        assert!((conntime - secs(captured)).abs() < 0.001, "{} isn't {}", conntime, secs(captured));
+    }
+}
+
+#[test]
+fn once_exits_zero_when_everything_was_delivered() {
+    let server = TestServer::spawn();
+    let (pcap, _) = capture(server.dir());
+    assert_eq!(once(&pcap, &server.addr().to_string()), Some(0));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'sensor' AND state = 2 AND close = 4", 2, WAIT));
+}
+
+#[test]
+fn once_exits_nonzero_with_no_collector_to_deliver_to() {
+    let dir = std::env::temp_dir().join(format!("glosco-sensor-nobody-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (pcap, _) = capture(&dir);
+    let nobody = unused_addr();
+    assert_eq!(once(&pcap, &nobody.to_string()), Some(1));
+
+    // Nor does a client given up on that way report success from the library
+    let sensor = client::start(ClientSettings {
+        captures: vec![pcap],
+        remotes: vec![nobody],
+        ident: Some("sensor".to_string()),
+        snapshot_interval: None,
+        once: true,
+        flush_timeout: 1.0,
+        ..Default::default()
+    }).unwrap();
+    assert!(!sensor.join());
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn a_statsd_agent_hears_the_observer_and_remote_counters() {
+    let server = TestServer::spawn();
+    // Still being written, like a live capture, so the sensor keeps its remotes until shut down
+    let capture = server.dir().join("statsd.pcap");
+    let path = CString::new(capture.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0, "failed to create fifo");
+    let writer = {
+        let capture = capture.clone();
+        thread::spawn(move || {
+            let mut fifo = OpenOptions::new().write(true).open(capture).unwrap();
+            fifo.write_all(&pcap_header()).unwrap();
+            for src in ["10.0.0.1:40000", "10.0.0.1:40001"] {
+                fifo.write_all(&pcap_record(SystemTime::now(), &tcp_frame(src, "10.0.0.2:443", SYN))).unwrap();
+            }
+            fifo.flush().unwrap();
+            fifo
+        })
+    };
+    let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    agent.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let sensor = client::start(ClientSettings {
+        captures: vec![capture.clone()],
+        remotes: vec![server.addr()],
+        ident: Some("sensor".to_string()),
+        snapshot_interval: None,
+        statsd: Some(agent.local_addr().unwrap()),
+        statsd_prefix: "edge.".to_string(),
+        statsd_tags: TagFormat::Dogstatsd,
+        statsd_interval: 0.1,
+        ..Default::default()
+    }).unwrap();
+    let fifo = writer.join().unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'sensor'", 2, WAIT));
+
+    // Counters come as what they went up by, so add them up until they've caught up
+    let packets = format!("edge.glosco_observer_packets_total:{{}}|c|#interface:{}", capture.display());
+    let (packets_before, packets_after) = packets.split_once("{}").unwrap();
+    let sent = format!("|c|#remote:{}", server.addr());
+    let connected = format!("edge.glosco_remote_connected:1|g|#remote:{}", server.addr());
+    let (mut captured, mut delivered, mut up) = (0, 0, false);
+    let mut buffer = [0; 65536];
+    let deadline = Instant::now() + WAIT;
+    while captured < 2 || delivered < 3 || !up {
+        assert!(Instant::now() < deadline, "the agent heard {} packets, {} frames sent, connected {}", captured, delivered, up);
+        let Ok(len) = agent.recv(&mut buffer) else {
+            continue;
+        };
+        for line in std::str::from_utf8(&buffer[.. len]).unwrap().lines() {
+            if let Some(value) = line.strip_prefix(packets_before).and_then(|rest| rest.strip_suffix(packets_after)) {
+                captured += value.parse::<u64>().unwrap();
+            }
+            if let Some(value) = line.strip_prefix("edge.glosco_remote_sent_total:").and_then(|rest| rest.strip_suffix(sent.as_str())) {
+                delivered += value.parse::<u64>().unwrap();
+            }
+            up |= line == connected;
+        }
+    }
+    // The namespace and both connections' starts, and nothing more to count
+    assert_eq!((captured, delivered), (2, 3));
+    sensor.shutdown();
+    assert!(sensor.join());
+    drop(fifo);
+}
+
+/// A response from 10.0.0.53 to 10.0.0.1 saying example.com is at 93.184.216.34.
+const EXAMPLE_COM: [u8; 45] = [
+    // ID, flags, one question, one answer, nothing else
+    0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+    // example.com, type A, class IN
+    7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01,
+    // The question's name, type A, class IN, TTL 60, the address
+    0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 93, 184, 216, 34,
+];
+
+#[test]
+fn a_replay_into_a_local_database_stores_what_a_collector_would() {
+    let dir = std::env::temp_dir().join(format!("glosco-sensor-local-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let pcap = dir.join("local.pcap");
+    let captured = SystemTime::now() - Duration::from_secs(60);
+    write_pcap(&pcap, &[
+        (captured, udp_frame("10.0.0.53:53", "10.0.0.1:5353", &EXAMPLE_COM)),
+        (captured, tcp_frame("10.0.0.1:40000", "93.184.216.34:443", SYN)),
+    ]).unwrap();
+    let database = dir.join("local.db");
+    let sensor = client::start(ClientSettings {
+        captures: vec![pcap],
+        local_db: Some(database.to_str().unwrap().to_string()),
+        ident: Some("sensor".to_string()),
+        snapshot_interval: None,
+        once: true,
+        flush_timeout: 1.0,
+        ..Default::default()
+    }).unwrap();
+    assert!(sensor.join(), "with no collectors there's nothing left undelivered");
+
+    let db = rusqlite::Connection::open(&database).unwrap();
+    // The lookup, ended as soon as seen (connectionless, 3), and the connection's start (5) and
+    // its end when the capture ran out (timed out, 4), all stamped with the capture's time
+    let rows: Vec<(String, String, i64, i64, Option<i64>, f64)> = db
+        .prepare("SELECT ident, srchost, srcport, state, close, conntime FROM state_all ORDER BY srcport, state DESC").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    let rows: Vec<_> = rows.into_iter().map(|(ident, host, port, state, close, conntime)| {
+        assert_eq!(ident, "sensor");
+        assert!((conntime - secs(captured)).abs() < 0.001, "{} isn't {}", conntime, secs(captured));
+        (host, port, state, close)
+    }).collect();
+    assert_eq!(rows, [
+        ("10.0.0.53".to_string(), 53, 2, Some(3)),
+        ("10.0.0.1".to_string(), 40000, 5, None),
+        ("10.0.0.1".to_string(), 40000, 2, Some(4)),
+    ]);
+    // The name, asked after and answered
+    let names: Vec<(String, String, String, Option<String>)> = db
+        .prepare("SELECT querier, responder, name, addr FROM names ORDER BY addr IS NOT NULL").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(names, [
+        ("10.0.0.1".to_string(), "10.0.0.53".to_string(), "example.com".to_string(), None),
+        ("10.0.0.1".to_string(), "10.0.0.53".to_string(), "example.com".to_string(), Some("93.184.216.34".to_string())),
+    ]);
+    drop(db);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+/// The value of `name{labels}` in a scrape, if it has one.
+fn sample(body: &str, name: &str, labels: &str) -> Option<f64> {
+    let prefix = format!("{}{{{}}} ", name, labels);
+    body.lines().find_map(|line| line.strip_prefix(prefix.as_str())).map(|value| value.parse().unwrap())
+}
+
+/// Scrape `bind` until `done` is happy with what it finds, and return that.
+fn scrape_until<F: Fn(&str) -> bool>(bind: SocketAddr, done: F) -> String {
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let (status, body) = http(bind, "GET", "/metrics", &[], "").unwrap();
+        assert_eq!(status, 200, "{}", body);
+        if done(&body) {
+            return body;
+        }
+        assert!(Instant::now() < deadline, "never got there:\n{}", body);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn a_scrape_sees_the_observer_and_remote_counters_move() {
+    let server = TestServer::spawn();
+    // Still being written, like a live capture, with a packet at a time
+    let capture = server.dir().join("metrics.pcap");
+    let path = CString::new(capture.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0, "failed to create fifo");
+    let (next, packets) = mpsc::channel::<&'static str>();
+    let writer = {
+        let capture = capture.clone();
+        thread::spawn(move || {
+            let mut fifo = OpenOptions::new().write(true).open(capture).unwrap();
+            fifo.write_all(&pcap_header()).unwrap();
+            fifo.flush().unwrap();
+            for src in packets {
+                fifo.write_all(&pcap_record(SystemTime::now(), &tcp_frame(src, "10.0.0.2:443", SYN))).unwrap();
+                fifo.flush().unwrap();
+            }
+        })
+    };
+    let bind = unused_addr();
+    let sensor = client::start(ClientSettings {
+        captures: vec![capture.clone()],
+        remotes: vec![server.addr()],
+        ident: Some("sensor".to_string()),
+        snapshot_interval: None,
+        metrics_bind: Some(bind),
+        ..Default::default()
+    }).unwrap();
+    let interface = format!("interface=\"{}\"", capture.display());
+    let remote = format!("remote=\"{}\"", server.addr());
+
+    next.send("10.0.0.1:40000").unwrap();
+    let body = scrape_until(bind, |body| {
+        sample(body, "glosco_observer_packets_total", &interface) == Some(1.0)
+            && sample(body, "glosco_remote_sent_total", &remote) == Some(2.0)
+    });
+    assert!(body.contains("# TYPE glosco_observer_packets_total counter\n"), "{}", body);
+    assert_eq!(sample(&body, "glosco_remote_connected", &remote), Some(1.0));
+    assert_eq!(sample(&body, "glosco_remote_dropped_total", &remote), Some(0.0));
+    assert_eq!(sample(&body, "glosco_build_info", &format!("version=\"{}\"", env!("CARGO_PKG_VERSION"))), Some(1.0));
+
+    // Another packet, and another start sent on for it
+    next.send("10.0.0.1:40001").unwrap();
+    scrape_until(bind, |body| {
+        sample(body, "glosco_observer_packets_total", &interface) == Some(2.0)
+            && sample(body, "glosco_remote_sent_total", &remote) == Some(3.0)
+    });
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident = 'sensor'", 2, WAIT));
+    sensor.shutdown();
+    assert!(sensor.join());
+    drop(next);
+    writer.join().unwrap();
+}
@@ -0,0 +1,86 @@
+//! Frames a client numbered that the collector never got, and clients that started numbering
+//! over, as the collector records them.
+
+use std::time::Duration;
+
+use glosco::{observe::{Message, Protocol}, query, sync::Sequence, test_support::{state, TestClient, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn starting(port: u16) -> Message {
+    Message::Starting(state(&format!("10.0.0.1:{}", port), "10.0.0.2:443", Protocol::Tcp))
+}
+
+/// Every gap recorded, oldest first: from, to, and whether it was a reset.
+fn gaps(server: &TestServer) -> Vec<(i64, i64, bool)> {
+    server.db().prepare("SELECT from_seq, to_seq, reset FROM gaps ORDER BY rowid").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+/// Connect as `ident`, say the next frame is numbered `next`, and send `count` messages.
+fn session(server: &TestServer, ident: &str, next: u64, count: u16) -> TestClient {
+    let mut client = server.client(ident);
+    client.hello(None).unwrap();
+    client.send(&Sequence { next }).unwrap();
+    for port in 0 .. count {
+        client.send(&starting(40000 + next as u16 + port)).unwrap();
+    }
+    client
+}
+
+#[test]
+fn skipped_frames_are_a_gap() {
+    let server = TestServer::spawn();
+    let mut client = session(&server, "sensor", 0, 3);
+    // 3 and 4 went missing
+    client.send(&Sequence { next: 5 }).unwrap();
+    client.send(&starting(40005)).unwrap();
+    // Carrying on where it should is nothing to record
+    client.send(&Sequence { next: 6 }).unwrap();
+    client.send(&starting(40006)).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all", 5, WAIT));
+
+    assert_eq!(gaps(&server), [(3, 4, false)]);
+    let clients = query::clients(&server.db(), &[], &[]).unwrap();
+    assert_eq!((clients[0].ident.as_str(), clients[0].lost_frames), ("sensor", 2));
+}
+
+#[test]
+fn numbering_carries_over_from_one_session_to_the_next() {
+    let server = TestServer::spawn();
+    session(&server, "sensor", 0, 3).close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients WHERE next_seq = 3", 1, WAIT));
+
+    // Reconnected, having lost frame 3 with the connection
+    let _client = session(&server, "sensor", 4, 1);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE srcport = 40004", 1, WAIT));
+    assert_eq!(gaps(&server), [(3, 3, false)]);
+}
+
+#[test]
+fn a_restarted_client_is_a_reset_not_a_loss() {
+    let server = TestServer::spawn();
+    session(&server, "sensor", 0, 3).close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients WHERE next_seq = 3", 1, WAIT));
+
+    let _client = session(&server, "sensor", 0, 1);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM gaps", 1, WAIT));
+    assert_eq!(gaps(&server), [(3, 0, true)]);
+    let clients = query::clients(&server.db(), &[], &[]).unwrap();
+    assert_eq!(clients[0].lost_frames, 0);
+}
+
+#[test]
+fn a_client_that_doesnt_number_its_frames_has_no_gaps() {
+    let server = TestServer::spawn();
+    let mut client = server.client("legacy");
+    client.hello(None).unwrap();
+    client.send(&starting(40000)).unwrap();
+    client.close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+
+    assert!(gaps(&server).is_empty());
+    assert_eq!(server.db().query_row("SELECT next_seq FROM clients", [], |row| row.get::<_, Option<i64>>(0)).unwrap(), None);
+}
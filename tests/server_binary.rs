@@ -0,0 +1,114 @@
+//! The glosco_server binary, started as packaging would start it, against a scratch database.
+
+use std::{fs, io::{BufRead, BufReader}, net::SocketAddr, process::{Child, Command, Stdio}, sync::mpsc, thread, time::{Duration, Instant}};
+
+use glosco::{observe::{Message, Protocol}, test_support::{state, TestClient}};
+
+const WAIT: Duration = Duration::from_secs(10);
+
+/// The running binary, killed when dropped.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn a_client_connects_and_its_messages_are_stored() {
+    let dir = std::env::temp_dir().join(format!("glosco-server-binary-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let database = dir.join("glosco.db");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_glosco_server"))
+        .args(["--bind", "127.0.0.1:0", "--database"])
+        .arg(&database)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start glosco_server");
+    // The port's only known once it's bound, which the server says; what it says after that
+    // has to be read too, or it stalls once the pipe fills
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let server = Server(child);
+    let (sender, bound) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines().map_while(Result::ok) {
+            if let Some(addr) = line.strip_prefix("listening for clients on ") {
+                let _ = sender.send(addr.parse::<SocketAddr>().expect("bad address"));
+            }
+        }
+    });
+    let addr = bound.recv_timeout(WAIT).expect("glosco_server never said where it's listening");
+
+    let mut client = TestClient::connect(addr, "smoke").unwrap();
+    client.hello(Some(30)).unwrap();
+    client.send(&Message::Starting(state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+
+    let db = rusqlite::Connection::open(&database).unwrap();
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let stored: rusqlite::Result<i64> = db.query_row(
+            "SELECT COUNT(*) FROM state_all WHERE ident = 'smoke' AND srcport = 40000 AND dstport = 443", [], |row| row.get(0));
+        if stored == Ok(1) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "the message was never stored ({:?})", stored);
+        thread::sleep(Duration::from_millis(20));
+    }
+    let agent: String = db.query_row("SELECT agent FROM clients WHERE ident = 'smoke'", [], |row| row.get(0)).unwrap();
+    assert!(!agent.is_empty());
+
+    client.close();
+    drop(db);
+    drop(server);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Start glosco_server on `database` with `--pidfile`, saying nothing.
+#[cfg(unix)]
+fn with_pidfile(database: &std::path::Path, pidfile: &std::path::Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_glosco_server"))
+        .args(["--bind", "127.0.0.1:0", "--database"])
+        .arg(database)
+        .arg("--pidfile")
+        .arg(pidfile)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start glosco_server")
+}
+
+#[cfg(unix)]
+#[test]
+fn the_pidfile_is_kept_while_running_and_removed_on_sigterm() {
+    let dir = std::env::temp_dir().join(format!("glosco-server-pidfile-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let pidfile = dir.join("glosco.pid");
+
+    let mut server = Server(with_pidfile(&dir.join("glosco.db"), &pidfile));
+    let pid = server.0.id();
+    let deadline = Instant::now() + WAIT;
+    while glosco::daemon::read_pid(&pidfile).unwrap() != Some(pid) {
+        assert!(Instant::now() < deadline, "glosco_server never wrote its pidfile");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // A second one can't take it while the first runs
+    let mut second = with_pidfile(&dir.join("second.db"), &pidfile);
+    assert!(!second.wait().unwrap().success());
+    assert_eq!(glosco::daemon::read_pid(&pidfile).unwrap(), Some(pid));
+
+    // Safety: signals a child this test started and hasn't reaped.
+    assert_eq!(unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) }, 0);
+    let deadline = Instant::now() + WAIT;
+    while server.0.try_wait().unwrap().is_none() {
+        assert!(Instant::now() < deadline, "glosco_server didn't exit on SIGTERM");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!pidfile.exists(), "the pidfile was left behind");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,82 @@
+//! Snapshots of a client's open connections, reconciled with what the collector thought was
+//! open while ordinary messages keep arriving around them: opened after the snapshot was taken,
+//! closed or kept alive right after it, and the same snapshot twice.
+
+use std::time::{Duration, SystemTime};
+
+use glosco::{observe::{Closed, Message, Protocol, Snapshot, State}, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+const ENDED: u8 = 2;
+const TMOUT: u8 = 4;
+
+/// A row as (srcport, state, close).
+type Row = (u16, u8, Option<u8>);
+
+/// The connection from `port`, as of `at` seconds after `base`.
+fn conn(port: u16, base: SystemTime, at: u64) -> State {
+    State { as_of: base + Duration::from_secs(at), ..state(&format!("10.0.0.1:{}", port), "10.0.0.2:443", Protocol::Tcp) }
+}
+
+/// Every row, in the order they arrived by connection.
+fn rows(server: &TestServer) -> Vec<Row> {
+    server.db().prepare("SELECT srcport, state, close FROM state_all ORDER BY srcport, instime").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+/// What `active_now` has open, as (srcport, conntime relative to `base`).
+fn open(server: &TestServer, base: SystemTime) -> Vec<(u16, f64)> {
+    let base = base.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
+    server.db().prepare("SELECT srcport, conntime FROM active_now ORDER BY srcport").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, f64>(1)? - base))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn a_snapshot_reconciles_without_undoing_what_arrives_around_it() {
+    let server = TestServer::spawn();
+    let base = SystemTime::now() - Duration::from_secs(100);
+    let mut client = server.client("sensor");
+    client.hello(Some(30)).unwrap();
+    for port in 1 ..= 3 {
+        client.send(&Message::Starting(conn(port, base, 0))).unwrap();
+    }
+    // Opened after the snapshot was taken, and sent ahead of it
+    client.send(&Message::Starting(conn(4, base, 60))).unwrap();
+    // Without 2, whose end went missing, or 4; with 5, whose start did
+    let snapshot = Snapshot {
+        as_of: base + Duration::from_secs(50),
+        states: [1, 3, 5].into_iter().map(|port| Message::Active(conn(port, base, 50))).collect(),
+    };
+    client.send(&snapshot).unwrap();
+    // Right behind it: 1 closes, 2 opens again on the same tuple, 3 is kept alive
+    client.send(&Message::Ended(conn(1, base, 55), Closed::Normally)).unwrap();
+    client.send(&Message::Starting(conn(2, base, 70))).unwrap();
+    client.send(&Message::Active(conn(3, base, 56))).unwrap();
+    client.send(&Message::Starting(conn(99, base, 80))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE srcport = 99", 1, WAIT));
+
+    let reconciled = vec![
+        (1, 5, None), (1, ENDED, Some(1)),
+        // Timed out by the snapshot, as it was, then opened afresh
+        (2, 5, None), (2, 5, Some(TMOUT)), (2, 5, None),
+        // What the snapshot had open already isn't stored again
+        (3, 5, None), (3, 1, None),
+        (4, 5, None),
+        (5, 1, None),
+        (99, 5, None),
+    ];
+    assert_eq!(rows(&server), reconciled);
+    assert_eq!(open(&server, base), [(2, 70.0), (3, 0.0), (4, 60.0), (5, 50.0), (99, 80.0)]);
+
+    // The same snapshot again, late: nothing it lacks was open as of it any more
+    client.send(&snapshot).unwrap();
+    client.send(&Message::Starting(conn(100, base, 90))).unwrap();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE srcport = 100", 1, WAIT));
+    let mut again = reconciled;
+    again.push((100, 5, None));
+    assert_eq!(rows(&server), again);
+    assert_eq!(open(&server, base), [(2, 70.0), (3, 0.0), (4, 60.0), (5, 50.0), (99, 80.0), (100, 90.0)]);
+    assert!(!client.hung_up(Duration::from_millis(100)));
+}
@@ -0,0 +1,124 @@
+//! A warm standby following its primary: everything a sensor reports to the primary is stored
+//! at the standby too, including what the primary accepted while the link between them was
+//! down, caught up once it comes back.
+
+use std::{io, net::{Shutdown, SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread, time::Duration};
+
+use glosco::{observe::{Closed, Message, Problem, Protocol}, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+/// Long enough for the standby to back off and reconnect, with room to spare.
+const CATCH_UP: Duration = Duration::from_secs(15);
+
+/// A row as (ident, srchost, srcport, dsthost, dstport, state, close, pkind, conntime).
+type Row = (String, String, u16, String, u16, u8, Option<u8>, Option<u8>, f64);
+
+/// A TCP proxy in front of the primary, for cutting the standby off from it.
+struct Proxy {
+    addr: SocketAddr,
+    down: Arc<AtomicBool>,
+    links: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn new(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let down = Arc::new(AtomicBool::new(false));
+        let links = Arc::new(Mutex::new(Vec::new()));
+        let (accepting, linked) = (down.clone(), links.clone());
+        thread::spawn(move || {
+            for downstream in listener.incoming() {
+                let Ok(downstream) = downstream else { continue };
+                // Hung up on at once while down, as if there were nothing there
+                if accepting.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let Ok(upstream) = TcpStream::connect(target) else { continue };
+                let mut linked = linked.lock().unwrap();
+                linked.push(downstream.try_clone().unwrap());
+                linked.push(upstream.try_clone().unwrap());
+                pipe(downstream.try_clone().unwrap(), upstream.try_clone().unwrap());
+                pipe(upstream, downstream);
+            }
+        });
+        Self { addr, down, links }
+    }
+
+    /// Drop every connection through the proxy and refuse new ones until `restore`.
+    fn cut(&self) {
+        self.down.store(true, Ordering::SeqCst);
+        for stream in self.links.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn restore(&self) {
+        self.down.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Copy from `from` to `to` until either end goes, then hang up the other.
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    thread::spawn(move || {
+        let _ = io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Both);
+    });
+}
+
+fn rows(server: &TestServer) -> Vec<Row> {
+    server.db().prepare("
+        SELECT ident, srchost, srcport, dsthost, dstport, state, close, pkind, conntime FROM state_all
+        ORDER BY ident, srcport, conntime, state = 2
+    ").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+fn count(server: &TestServer) -> i64 {
+    server.db().query_row("SELECT COUNT(*) FROM state_all", [], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn a_standby_catches_up_on_what_the_primary_accepted_while_it_was_cut_off() {
+    let primary = TestServer::spawn();
+    let proxy = Proxy::new(primary.addr());
+    let standby = TestServer::spawn_with(|settings| settings.replicate = Some(proxy.addr));
+
+    let web = state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp);
+    let mut later = web;
+    later.as_of += Duration::from_secs(1);
+    let dns = state("10.0.0.1:40001", "10.0.0.53:53", Protocol::Udp);
+    // Kept connected throughout, so that neither end times anything out on it
+    let mut sensor = primary.client("sensor");
+    sensor.hello(Some(30)).unwrap();
+    sensor.send(&Message::Starting(web)).unwrap();
+    assert!(standby.wait_for_count("SELECT COUNT(*) FROM state_all", 1, WAIT), "the standby never started following");
+
+    proxy.cut();
+    for message in [
+        Message::Active(later),
+        Message::Ended(later, Closed::Normally),
+        Message::Starting(dns),
+        Message::Ended(dns, Closed::Connectionless),
+        Message::Failed(state("10.0.0.1:40002", "10.0.0.3:5432", Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 }),
+    ] {
+        sensor.send(&message).unwrap();
+    }
+    assert!(primary.wait_for_count("SELECT COUNT(*) FROM state_all", 6, WAIT));
+    // None of which got through while it was down
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(count(&standby), 1);
+
+    proxy.restore();
+    assert!(standby.wait_for_count("SELECT COUNT(*) FROM state_all", 6, CATCH_UP), "the standby never caught up");
+    // And once caught up, what comes next is followed live as before
+    sensor.send(&Message::Starting(state("10.0.0.1:40003", "10.0.0.2:443", Protocol::Tcp))).unwrap();
+    assert!(primary.wait_for_count("SELECT COUNT(*) FROM state_all", 7, WAIT));
+    assert!(standby.wait_for_count("SELECT COUNT(*) FROM state_all", 7, WAIT));
+    assert!(!sensor.hung_up(Duration::from_millis(100)));
+
+    let stored = rows(&primary);
+    assert_eq!(stored.len(), 7);
+    assert_eq!(rows(&standby), stored);
+}
@@ -0,0 +1,188 @@
+//! Subscribers: a reporting client's messages go through the collector and out to whoever's
+//! subscribed, filtered as each asked, whether that's a `Subscription` or `glosco tail --remote`.
+
+use std::{io::{BufRead, BufReader}, process::{Command, Stdio}, sync::mpsc, thread, time::{Duration, Instant}};
+
+use glosco::{alert::Kind, filter::Glob, observe::{Closed, Message, Problem, Protocol}, subscribe::{Envelope, Subscribe, Subscription}, sync::ClientConfig, test_support::{state, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+const PROBE: &str = "east-probe";
+
+fn failed(src: &str, dst: &str) -> Message {
+    Message::Failed(state(src, dst, Protocol::Tcp), Problem { kind: 3, code: 1, repeats: 0 })
+}
+
+/// What each reporter sends, in order.
+fn reports() -> Vec<(&'static str, Vec<Message>)> {
+    let first = state("10.0.0.1:40000", "10.0.0.2:443", Protocol::Tcp);
+    let mut later = first;
+    later.as_of += Duration::from_secs(1);
+    let second = state("10.0.0.1:40001", "10.0.0.2:443", Protocol::Tcp);
+    let west = state("10.0.1.1:40000", "10.0.1.2:22", Protocol::Tcp);
+    vec![
+        ("east-1", vec![
+            Message::Starting(first),
+            Message::Active(later),
+            Message::Ended(later, Closed::Reset),
+            failed("10.0.0.1:40002", "10.0.0.3:5432"),
+        ]),
+        ("east-2", vec![
+            Message::Starting(second),
+            // Sent again, which the database ignores, and so nobody hears of
+            Message::Starting(second),
+            Message::Ended(second, Closed::Normally),
+        ]),
+        ("west-1", vec![
+            Message::Starting(west),
+            failed("10.0.1.1:40001", "10.0.1.3:5432"),
+        ]),
+    ]
+}
+
+/// Send each of `reports` from its own client, as a sensor would, and wait for it to go.
+fn report(server: &TestServer, reports: &[(&str, Vec<Message>)]) {
+    let clients: Vec<_> = reports.iter().map(|(ident, messages)| {
+        let mut config = ClientConfig::new(ident.to_string());
+        config.add(server.addr());
+        let client = config.build().unwrap();
+        for message in messages {
+            client.send(message);
+        }
+        client
+    }).collect();
+    for client in clients {
+        assert!(client.shutdown(WAIT), "a reporter didn't get everything out");
+    }
+}
+
+/// Envelopes from a subscription, passed on as they're read.
+fn subscribe(server: &TestServer, filter: Subscribe) -> mpsc::Receiver<Envelope> {
+    let subscription = Subscription::connect(server.addr(), "subscriber", &filter).unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for envelope in subscription {
+            if sender.send(envelope.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Wait for the collector to have taken a subscription on: report failures from `PROBE`
+/// until one comes through `feed`, then throw away whatever came through.
+fn settle<T>(server: &TestServer, feed: &mpsc::Receiver<T>) {
+    let deadline = Instant::now() + WAIT;
+    for port in 1 .. {
+        assert!(Instant::now() < deadline, "the subscription never came through");
+        let mut probe = server.client(PROBE);
+        probe.hello(None).unwrap();
+        probe.send(&failed(&format!("10.9.9.9:{}", port), "10.9.9.10:9")).unwrap();
+        if feed.recv_timeout(Duration::from_millis(200)).is_ok() {
+            probe.close();
+            break;
+        }
+        probe.close();
+    }
+    thread::sleep(Duration::from_millis(200));
+    while feed.try_recv().is_ok() {}
+}
+
+/// What came through `feed` once it's gone quiet, leaving out probes.
+fn received(feed: &mpsc::Receiver<Envelope>) -> Vec<Envelope> {
+    let mut received = Vec::new();
+    while let Ok(envelope) = feed.recv_timeout(Duration::from_millis(500)) {
+        if envelope.ident != PROBE {
+            received.push(envelope);
+        }
+    }
+    received
+}
+
+/// `envelopes` from `ident`, in the order they came.
+fn from(envelopes: &[Envelope], ident: &str) -> Vec<Message> {
+    envelopes.iter().filter(|envelope| envelope.ident == ident).map(|envelope| envelope.message.clone()).collect()
+}
+
+#[test]
+fn subscribers_get_what_reporters_send_as_its_accepted_and_only_what_they_asked_for() {
+    let server = TestServer::spawn();
+    let everything = subscribe(&server, Subscribe::default());
+    let east_trouble = subscribe(&server, Subscribe {
+        idents: vec![Glob("east*".to_string())],
+        kinds: vec![Kind::Reset, Kind::Failed],
+        since: None,
+    });
+    settle(&server, &everything);
+    settle(&server, &east_trouble);
+
+    let reports = reports();
+    report(&server, &reports);
+    let everything = received(&everything);
+    let east_trouble = received(&east_trouble);
+
+    assert_eq!(everything.len(), 8, "{:?}", everything);
+    for (ident, messages) in &reports {
+        let mut messages = messages.clone();
+        messages.dedup();
+        assert_eq!(from(&everything, ident), messages, "{}", ident);
+    }
+    assert_eq!(east_trouble.len(), 2);
+    assert_eq!(from(&east_trouble, "east-1"), reports[0].1[2 ..]);
+
+    // What went out is what was stored, and the subscribers aren't taken for clients
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident != 'east-probe'", 8, WAIT));
+    let idents: Vec<String> = server.db().prepare("SELECT ident FROM clients ORDER BY ident").unwrap()
+        .query_map([], |row| row.get(0)).unwrap()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(idents, ["east-1", "east-2", PROBE, "west-1"]);
+}
+
+#[test]
+fn a_subscriber_hanging_up_doesnt_hold_reporting_up() {
+    let server = TestServer::spawn();
+    let gone = subscribe(&server, Subscribe::default());
+    settle(&server, &gone);
+    drop(gone);
+
+    report(&server, &reports());
+    report(&server, &reports());
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM state_all WHERE ident != 'east-probe'", 16, WAIT));
+}
+
+#[test]
+fn tail_prints_what_a_remote_collector_accepts() {
+    let server = TestServer::spawn();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_glosco"))
+        .args(["tail", "--remote", &server.addr().to_string(), "--ident", "east*", "--kind", "failed"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            let Ok(line) = line else { return };
+            if sender.send(serde_json::from_str::<serde_json::Value>(&line).unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    settle(&server, &lines);
+
+    let reports = reports();
+    report(&server, &reports);
+    let mut printed = Vec::new();
+    while let Ok(line) = lines.recv_timeout(Duration::from_millis(500)) {
+        if line["ident"] != PROBE {
+            printed.push(line);
+        }
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(printed.len(), 1, "{:?}", printed);
+    assert_eq!(printed[0]["ident"], "east-1");
+    let expected = serde_json::to_value(&reports[0].1[3]).unwrap();
+    assert_eq!(printed[0]["message"], expected);
+}
@@ -0,0 +1,89 @@
+//! Tags clients announce in their hello, as the collector keeps them and queries slice by them.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use glosco::{query, sync::Hello, test_support::{TestClient, TestServer}};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+fn tags(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+}
+
+fn hello(client: &mut TestClient, pairs: &[(&str, &str)]) {
+    client.send(&Hello { agent: Hello::AGENT.to_string(), keepalive: None, tags: tags(pairs) }).unwrap();
+}
+
+fn stored(server: &TestServer, ident: &str) -> BTreeMap<String, String> {
+    server.db().prepare("SELECT key, value FROM client_tags WHERE ident = ?").unwrap()
+        .query_map([ident], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn each_hello_replaces_the_tags_before() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    hello(&mut client, &[("site", "ams1"), ("rack", "r12")]);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_tags", 2, WAIT));
+    client.close();
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions WHERE disconnected IS NOT NULL", 1, WAIT));
+    // Still there with the client gone
+    assert_eq!(stored(&server, "sensor"), tags(&[("site", "ams1"), ("rack", "r12")]));
+
+    // Moved, and no longer in a rack
+    let mut client = server.client("sensor");
+    hello(&mut client, &[("site", "fra2"), ("env", "prod")]);
+    assert!(server.wait_for_rows(|db| db.query_row("SELECT COUNT(*) FROM client_tags WHERE value = 'fra2'", [], |row| row.get::<_, i64>(0)).map(|n| n == 1), WAIT));
+    assert_eq!(stored(&server, "sensor"), tags(&[("site", "fra2"), ("env", "prod")]));
+
+    // And a hello with none clears them
+    let mut client = server.client("sensor");
+    hello(&mut client, &[]);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_sessions", 3, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_tags", 0, WAIT));
+}
+
+#[test]
+fn bad_tags_are_dropped_and_the_rest_kept() {
+    let server = TestServer::spawn();
+    let mut client = server.client("sensor");
+    hello(&mut client, &[("site", "ams1"), ("bad key", "x"), ("env", "pre\nprod")]);
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients", 1, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_tags", 1, WAIT));
+    assert_eq!(stored(&server, "sensor"), tags(&[("site", "ams1")]));
+}
+
+#[test]
+fn clients_are_filtered_by_every_tag_given() {
+    let server = TestServer::spawn();
+    let mut clients = Vec::new();
+    for (ident, pairs) in [
+        ("ams-a", &[("site", "ams1"), ("env", "prod")][..]),
+        ("ams-b", &[("site", "ams1"), ("env", "test")][..]),
+        ("fra-a", &[("site", "fra2"), ("env", "prod")][..]),
+        ("bare", &[][..]),
+    ] {
+        let mut client = server.client(ident);
+        hello(&mut client, pairs);
+        clients.push(client);
+    }
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM client_tags", 6, WAIT));
+    assert!(server.wait_for_count("SELECT COUNT(*) FROM clients", 4, WAIT));
+
+    let db = server.db();
+    let idents = |filter: &[(&str, &str)]| -> Vec<String> {
+        let filter: Vec<(String, String)> = filter.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        let mut idents: Vec<String> = query::clients(&db, &filter, &[]).unwrap().into_iter().map(|client| client.ident).collect();
+        idents.sort();
+        idents
+    };
+    assert_eq!(idents(&[]), ["ams-a", "ams-b", "bare", "fra-a"]);
+    assert_eq!(idents(&[("site", "ams1")]), ["ams-a", "ams-b"]);
+    assert_eq!(idents(&[("site", "ams1"), ("env", "prod")]), ["ams-a"]);
+    assert_eq!(idents(&[("env", "prod")]), ["ams-a", "fra-a"]);
+    assert!(idents(&[("site", "lhr1")]).is_empty());
+    // Each comes with its tags
+    let tagged = query::clients(&db, &[("site".to_string(), "fra2".to_string())], &[]).unwrap();
+    assert_eq!(tagged[0].tags, tags(&[("site", "fra2"), ("env", "prod")]));
+}